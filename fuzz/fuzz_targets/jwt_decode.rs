@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_oauth2_server::models::Claims;
+
+fuzz_target!(|token: &str| {
+    let _ = Claims::decode(token, "fuzz-secret");
+});
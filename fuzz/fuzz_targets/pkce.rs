@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_oauth2_server::actors::validate_pkce;
+
+fuzz_target!(|input: (&str, &str, &str)| {
+    let (challenge, verifier, method) = input;
+    let valid = validate_pkce(challenge, verifier, method);
+    if method == "plain" {
+        assert_eq!(valid, challenge == verifier);
+    }
+});
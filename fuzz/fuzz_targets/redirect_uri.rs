@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_oauth2_server::models::is_valid_redirect_uri;
+
+fuzz_target!(|uri: &str| {
+    if is_valid_redirect_uri(uri) {
+        // The URL parser trims surrounding whitespace and lowercases the scheme
+        let normalized = uri.trim().to_ascii_lowercase();
+        assert!(normalized.starts_with("http:") || normalized.starts_with("https:"));
+        assert!(!uri.contains('#'));
+    }
+});
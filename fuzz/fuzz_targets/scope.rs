@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_oauth2_server::models::scope::{intersect_scopes, validate_scopes};
+
+fuzz_target!(|input: (&str, &str)| {
+    let (requested, available) = input;
+    let granted = intersect_scopes(requested, available);
+    assert!(validate_scopes(&granted, available));
+    assert!(validate_scopes(&granted, requested));
+});
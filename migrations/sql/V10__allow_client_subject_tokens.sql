@@ -0,0 +1,32 @@
+-- Client credentials tokens are issued to the client itself, so a token's
+-- user_id is not always a row in users. SQLite cannot drop a foreign key in
+-- place, so the table is rebuilt without it.
+CREATE TABLE tokens_new (
+    id TEXT PRIMARY KEY,
+    access_token TEXT NOT NULL UNIQUE,
+    refresh_token TEXT,
+    token_type TEXT NOT NULL,
+    expires_in INTEGER NOT NULL,
+    scope TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    revoked INTEGER NOT NULL DEFAULT 0,
+    refresh_expires_at TEXT,
+    refresh_max_expires_at TEXT,
+    last_used_at TEXT,
+    FOREIGN KEY (client_id) REFERENCES clients(client_id)
+);
+
+INSERT INTO tokens_new (id, access_token, refresh_token, token_type, expires_in, scope, client_id, user_id, created_at, expires_at, revoked, refresh_expires_at, refresh_max_expires_at, last_used_at)
+SELECT id, access_token, refresh_token, token_type, expires_in, scope, client_id, user_id, created_at, expires_at, revoked, refresh_expires_at, refresh_max_expires_at, last_used_at
+FROM tokens;
+
+DROP TABLE tokens;
+ALTER TABLE tokens_new RENAME TO tokens;
+
+CREATE INDEX idx_tokens_access_token ON tokens(access_token);
+CREATE INDEX idx_tokens_refresh_token ON tokens(refresh_token);
+CREATE INDEX idx_tokens_client_id ON tokens(client_id);
+CREATE INDEX idx_tokens_user_id ON tokens(user_id);
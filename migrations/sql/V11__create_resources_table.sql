@@ -0,0 +1,15 @@
+-- Protected APIs tokens can be issued for (RFC 8707 resource indicators).
+-- scopes is a JSON array of the scopes the API exposes; token_format is
+-- `jwt` or `opaque`.
+CREATE TABLE IF NOT EXISTS resources (
+    id TEXT PRIMARY KEY,
+    identifier TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    scopes TEXT NOT NULL,
+    token_format TEXT NOT NULL DEFAULT 'jwt',
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- The resource a token was issued for; NULL means the issuing client
+ALTER TABLE tokens ADD COLUMN audience TEXT;
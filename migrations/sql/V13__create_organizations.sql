@@ -0,0 +1,21 @@
+-- Organizations (teams) that own clients. A client with an owner_org is
+-- managed by every member of that organization through the developer portal;
+-- clients without one stay with the individual owner_id.
+CREATE TABLE IF NOT EXISTS organizations (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL
+);
+
+ALTER TABLE clients ADD COLUMN owner_org TEXT REFERENCES organizations(id);
+ALTER TABLE users ADD COLUMN owner_org TEXT REFERENCES organizations(id);
+
+-- Membership for federated users, who sign in through a social provider and
+-- have no users row. Keyed by the provider:id subject.
+CREATE TABLE IF NOT EXISTS organization_members (
+    subject TEXT PRIMARY KEY,
+    org_id TEXT NOT NULL REFERENCES organizations(id),
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX idx_clients_owner_org ON clients(owner_org);
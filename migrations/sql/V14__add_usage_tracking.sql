@@ -0,0 +1,6 @@
+-- Usage counters for cleaning up credentials. Both are written in batches by
+-- the usage tracker rather than on every request, so they can lag by up to
+-- the flush interval.
+ALTER TABLE tokens ADD COLUMN use_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE clients ADD COLUMN last_used_at TEXT;
+ALTER TABLE clients ADD COLUMN use_count INTEGER NOT NULL DEFAULT 0;
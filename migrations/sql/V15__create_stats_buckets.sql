@@ -0,0 +1,10 @@
+-- Event counts rolled up per hour and per day for dashboards. dimension
+-- breaks a metric down (grant type, login provider, ...); '' when it has none.
+CREATE TABLE IF NOT EXISTS stats_buckets (
+    granularity TEXT NOT NULL,
+    bucket_start TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    dimension TEXT NOT NULL DEFAULT '',
+    count INTEGER NOT NULL,
+    PRIMARY KEY (granularity, bucket_start, metric, dimension)
+);
@@ -0,0 +1,12 @@
+-- Accounts scheduled for erasure. Until erase_after the account is disabled
+-- and the deletion can be cancelled; after it the erasure job anonymizes the
+-- subject everywhere and replaces it here with its pseudonym.
+CREATE TABLE IF NOT EXISTS account_deletions (
+    subject TEXT PRIMARY KEY,
+    requested_by TEXT NOT NULL,
+    requested_at TEXT NOT NULL,
+    erase_after TEXT NOT NULL,
+    erased_at TEXT
+);
+
+CREATE INDEX idx_account_deletions_erase_after ON account_deletions(erase_after);
@@ -0,0 +1,4 @@
+-- When a token was revoked, so the retention job can tell how long it has
+-- been dead. Tokens revoked before this column existed have none and are
+-- aged from when they were issued.
+ALTER TABLE tokens ADD COLUMN revoked_at TEXT;
@@ -0,0 +1,10 @@
+-- Social login `state` values that have already come back from the provider.
+-- Session cookies are stored client-side, so an old cookie still carries the
+-- state; this is what keeps a callback from being accepted twice. Rows are
+-- dropped once the state has expired.
+CREATE TABLE IF NOT EXISTS consumed_login_states (
+    state TEXT PRIMARY KEY,
+    expires_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_consumed_login_states_expires_at ON consumed_login_states(expires_at);
@@ -0,0 +1,5 @@
+-- Who minted an impersonation token: the admin acting as the token's user,
+-- as `user:<id>` or `client:<id>`. NULL for every other token.
+ALTER TABLE tokens ADD COLUMN impersonator TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_tokens_impersonator ON tokens(impersonator);
@@ -0,0 +1,4 @@
+-- Web origins a browser-based client calls the token and revocation
+-- endpoints from, as a JSON array like redirect_uris. CORS on those
+-- endpoints only admits origins some client has registered.
+ALTER TABLE clients ADD COLUMN allowed_origins TEXT NOT NULL DEFAULT '[]';
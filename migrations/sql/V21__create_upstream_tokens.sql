@@ -0,0 +1,14 @@
+-- Access and refresh tokens from a social login provider, kept for users who
+-- connected the provider so the server can call its APIs on their behalf.
+-- Token values are encrypted; see services::upstream_tokens.
+CREATE TABLE IF NOT EXISTS upstream_tokens (
+    subject TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    access_token TEXT NOT NULL,
+    refresh_token TEXT,
+    scope TEXT NOT NULL,
+    expires_at TEXT,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (subject, provider)
+);
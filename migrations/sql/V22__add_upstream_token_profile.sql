@@ -0,0 +1,6 @@
+-- The profile a connected provider last returned, refreshed by the profile
+-- sync job.
+ALTER TABLE upstream_tokens ADD COLUMN email TEXT;
+ALTER TABLE upstream_tokens ADD COLUMN name TEXT;
+ALTER TABLE upstream_tokens ADD COLUMN picture TEXT;
+ALTER TABLE upstream_tokens ADD COLUMN profile_synced_at TEXT;
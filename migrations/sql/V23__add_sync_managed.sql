@@ -0,0 +1,5 @@
+-- Objects created or adopted by the declarative sync API are marked
+-- `sync`; only those are deleted when a manifest stops declaring them.
+ALTER TABLE clients ADD COLUMN managed_by TEXT;
+ALTER TABLE resources ADD COLUMN managed_by TEXT;
+ALTER TABLE users ADD COLUMN managed_by TEXT;
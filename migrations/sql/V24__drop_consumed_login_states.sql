@@ -0,0 +1,4 @@
+-- Social login states that have come back are now marked in the cache
+-- (in process, or Redis shared between replicas), which expires them itself.
+DROP INDEX IF EXISTS idx_consumed_login_states_expires_at;
+DROP TABLE IF EXISTS consumed_login_states;
@@ -0,0 +1,6 @@
+-- RFC 9396 authorization details a token was granted, as a JSON array, and
+-- the key (DPoP JWK thumbprint) or certificate (SHA-256 thumbprint) it is
+-- bound to. Reported by introspection; carried over when tokens refresh.
+ALTER TABLE tokens ADD COLUMN authorization_details TEXT;
+ALTER TABLE tokens ADD COLUMN cnf_jkt TEXT;
+ALTER TABLE tokens ADD COLUMN cnf_x5t_s256 TEXT;
@@ -0,0 +1,4 @@
+-- Redirect URIs with a wildcard host label (https://*.preview.example.com/cb),
+-- as a JSON array like redirect_uris. Kept apart from redirect_uris so a
+-- client only matches patterns it registered as patterns.
+ALTER TABLE clients ADD COLUMN redirect_uri_patterns TEXT NOT NULL DEFAULT '[]';
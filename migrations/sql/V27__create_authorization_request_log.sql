@@ -0,0 +1,25 @@
+-- Every /oauth/authorize request: what was asked for, how it ended, and the
+-- ids of the code it issued and the token that code was exchanged for.
+-- params is a JSON object of the query parameters with state, nonce and the
+-- PKCE challenge fingerprinted.
+CREATE TABLE IF NOT EXISTS authorization_request_log (
+    id TEXT PRIMARY KEY,
+    client_id TEXT,
+    user_id TEXT,
+    response_type TEXT,
+    scope TEXT,
+    granted_scope TEXT,
+    redirect_uri TEXT,
+    params TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    error_description TEXT,
+    status INTEGER NOT NULL,
+    code_id TEXT,
+    token_id TEXT,
+    client_ip TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_authorization_request_log_created_at ON authorization_request_log(created_at);
+CREATE INDEX IF NOT EXISTS idx_authorization_request_log_client_id ON authorization_request_log(client_id);
+CREATE INDEX IF NOT EXISTS idx_authorization_request_log_code_id ON authorization_request_log(code_id);
@@ -0,0 +1,7 @@
+-- When a client's secret stops being accepted; NULL never expires.
+-- secret_expiry_warned_at is set once the expiry warning has been sent, and
+-- cleared whenever the secret or its expiry changes.
+ALTER TABLE clients ADD COLUMN secret_expires_at TEXT;
+ALTER TABLE clients ADD COLUMN secret_expiry_warned_at TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_clients_secret_expires_at ON clients(secret_expires_at);
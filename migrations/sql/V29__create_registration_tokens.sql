@@ -0,0 +1,16 @@
+-- Tokens that let their bearer register clients through /clients/register.
+-- Only a SHA-256 of the token is kept. scope limits the scopes a registration
+-- may ask for, and grant_types (a JSON array) the grant types; NULL and an
+-- empty array allow any.
+CREATE TABLE IF NOT EXISTS registration_tokens (
+    id TEXT PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    description TEXT,
+    scope TEXT,
+    grant_types TEXT NOT NULL DEFAULT '[]',
+    max_uses INTEGER NOT NULL,
+    use_count INTEGER NOT NULL DEFAULT 0,
+    expires_at TEXT,
+    revoked_at TEXT,
+    created_at TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Events written in the same transaction as the change they describe, waiting
+-- to be published to the event plugins. A row is deleted once its event has
+-- been handed to them, so anything left here after a crash is published on
+-- the next start. event is the JSON-encoded event; seq keeps them in order.
+CREATE TABLE IF NOT EXISTS event_outbox (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_id TEXT NOT NULL UNIQUE,
+    event TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
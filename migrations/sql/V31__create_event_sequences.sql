@@ -0,0 +1,7 @@
+-- The last sequence number given to an outbox event about each aggregate:
+-- aggregate is 'user:' or 'client:' followed by the id. Numbers are taken in
+-- the transaction that writes the event, so they have no gaps.
+CREATE TABLE IF NOT EXISTS event_sequences (
+    aggregate TEXT PRIMARY KEY,
+    last_sequence INTEGER NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Hash chain over the admin audit log. seq numbers entries without gaps, and
+-- hash is an HMAC, keyed from the server's signing key, over the entry and
+-- prev_hash, the hash of the entry before it. Entries written before the
+-- chain existed have none of these.
+ALTER TABLE admin_audit_log ADD COLUMN seq INTEGER;
+ALTER TABLE admin_audit_log ADD COLUMN prev_hash TEXT;
+ALTER TABLE admin_audit_log ADD COLUMN hash TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_admin_audit_log_seq ON admin_audit_log(seq);
@@ -0,0 +1,5 @@
+-- A client may refresh for a narrower scope than it was granted (RFC 6749
+-- section 6). The rotated refresh token must still be good for the original
+-- scope, so it is kept here whenever it is wider than the access token's.
+-- NULL means the refresh token's scope is the access token's.
+ALTER TABLE tokens ADD COLUMN refresh_scope TEXT;
@@ -0,0 +1,10 @@
+-- Cache keys deleted on one replica, for the others to delete from their own
+-- in-process caches. Each replica remembers the newest seq it has applied and
+-- polls for later ones; rows are purged an hour after they were written.
+CREATE TABLE IF NOT EXISTS cache_invalidations (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    cache_key TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_cache_invalidations_created_at ON cache_invalidations(created_at);
@@ -0,0 +1,5 @@
+-- OpenID Connect requests carry a nonce the ID token must echo back, and
+-- the ID token reports when the user signed in. Both are captured when the
+-- code is issued, since the token request comes from the client later.
+ALTER TABLE authorization_codes ADD COLUMN nonce TEXT;
+ALTER TABLE authorization_codes ADD COLUMN auth_time TEXT;
@@ -0,0 +1,12 @@
+-- The scope each user last approved for each client on the consent page.
+-- Silent authentication (prompt=none) only issues a code for what the user
+-- has already approved.
+CREATE TABLE IF NOT EXISTS user_consents (
+    subject TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    granted_at TEXT NOT NULL,
+    PRIMARY KEY (subject, client_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_consents_client_id ON user_consents(client_id);
@@ -0,0 +1,13 @@
+-- Access tokens remember the jti of their JWT, and revoking one copies the
+-- jti into revoked_jtis. Replicas validating JWTs without looking them up
+-- only consult this table, which holds nothing past the token's expiry.
+ALTER TABLE tokens ADD COLUMN jti TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_tokens_revoked_at ON tokens(revoked_at);
+
+CREATE TABLE IF NOT EXISTS revoked_jtis (
+    jti TEXT PRIMARY KEY,
+    expires_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_revoked_jtis_expires_at ON revoked_jtis(expires_at);
@@ -0,0 +1,40 @@
+-- The development client and user V5 seeded, with their well-known secret
+-- and unusable password, go; demo mode (OAUTH2_MODE=demo) seeds its own.
+-- Only rows still holding the seeded credentials are removed.
+DELETE FROM authorization_codes WHERE client_id IN (
+    SELECT client_id FROM clients
+    WHERE client_id = 'default_client'
+      AND client_secret = 'INSECURE_DEFAULT_SECRET_REGENERATE_FOR_PRODUCTION'
+);
+DELETE FROM tokens WHERE client_id IN (
+    SELECT client_id FROM clients
+    WHERE client_id = 'default_client'
+      AND client_secret = 'INSECURE_DEFAULT_SECRET_REGENERATE_FOR_PRODUCTION'
+);
+DELETE FROM user_consents WHERE client_id IN (
+    SELECT client_id FROM clients
+    WHERE client_id = 'default_client'
+      AND client_secret = 'INSECURE_DEFAULT_SECRET_REGENERATE_FOR_PRODUCTION'
+);
+DELETE FROM clients
+WHERE client_id = 'default_client'
+  AND client_secret = 'INSECURE_DEFAULT_SECRET_REGENERATE_FOR_PRODUCTION';
+
+DELETE FROM users
+WHERE id = 'test-user-id'
+  AND password_hash = '$argon2id$v=19$m=524288,t=2,p=1$c29tZXNhbHQxMjM0NTY3ODkwMTIzNDU$wA1qkO0rATEtNnS/xPbbgQ1234567890123456789012'
+  AND NOT EXISTS (SELECT 1 FROM tokens WHERE user_id = 'test-user-id')
+  AND NOT EXISTS (SELECT 1 FROM authorization_codes WHERE user_id = 'test-user-id');
+
+-- Facts about the deployment a database belongs to. `mode` is the mode the
+-- server last started in; demo mode refuses a database marked production.
+CREATE TABLE IF NOT EXISTS deployment_settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+-- A database that already holds clients or users is serving someone
+INSERT INTO deployment_settings (key, value, updated_at)
+SELECT 'mode', 'production', CURRENT_TIMESTAMP
+WHERE EXISTS (SELECT 1 FROM clients) OR EXISTS (SELECT 1 FROM users);
@@ -0,0 +1,4 @@
+-- How a client authenticates at the token endpoint (RFC 7591). `none` marks
+-- a public client, which holds no secret and must use PKCE; every existing
+-- client was issued a secret and stays confidential.
+ALTER TABLE clients ADD COLUMN token_endpoint_auth_method TEXT NOT NULL DEFAULT 'client_secret_basic';
@@ -0,0 +1,9 @@
+-- One id per authorization journey: generated at the authorize request and
+-- carried by its log entries, the code it issued, the tokens that code was
+-- exchanged for and every refresh token rotated from them.
+ALTER TABLE authorization_request_log ADD COLUMN flow_id TEXT;
+ALTER TABLE authorization_codes ADD COLUMN flow_id TEXT;
+ALTER TABLE tokens ADD COLUMN flow_id TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_authorization_request_log_flow_id ON authorization_request_log(flow_id);
+CREATE INDEX IF NOT EXISTS idx_tokens_flow_id ON tokens(flow_id);
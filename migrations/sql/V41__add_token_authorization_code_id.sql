@@ -0,0 +1,6 @@
+-- The authorization code each token was exchanged for, carried across
+-- refreshes, so replaying a used code revokes every token issued from it
+-- (RFC 6749 section 4.1.2).
+ALTER TABLE tokens ADD COLUMN authorization_code_id TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_tokens_authorization_code_id ON tokens(authorization_code_id);
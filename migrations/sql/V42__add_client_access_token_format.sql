@@ -0,0 +1,3 @@
+-- Access token format a client registered for: `jwt` or `opaque`. NULL
+-- leaves it to the grant's format and OAUTH2_TOKEN_FORMAT.
+ALTER TABLE clients ADD COLUMN access_token_format TEXT;
@@ -0,0 +1,5 @@
+-- Track which end user owns a client registered through the developer portal.
+-- Clients registered through the admin API or /clients/register have no owner.
+ALTER TABLE clients ADD COLUMN owner_id TEXT;
+
+CREATE INDEX idx_clients_owner_id ON clients(owner_id);
@@ -0,0 +1,22 @@
+-- TOTP second factor, one enrollment per user. `last_used_step` is the
+-- 30-second time step of the last accepted code so a code cannot be replayed.
+CREATE TABLE IF NOT EXISTS mfa_totp (
+    subject TEXT PRIMARY KEY,
+    secret TEXT NOT NULL,
+    last_used_step INTEGER,
+    created_at TEXT NOT NULL
+);
+
+-- Browsers that completed MFA and may skip it until expires_at. The id is
+-- what the signed `trusted_device` cookie carries.
+CREATE TABLE IF NOT EXISTS trusted_devices (
+    id TEXT PRIMARY KEY,
+    subject TEXT NOT NULL,
+    label TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    last_used_at TEXT,
+    revoked INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX idx_trusted_devices_subject ON trusted_devices(subject);
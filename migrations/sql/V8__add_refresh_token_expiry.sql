@@ -0,0 +1,7 @@
+-- Refresh tokens rotate on every use, so each row tracks when its refresh
+-- token expires and the absolute limit inherited from the original grant.
+-- last_used_at is touched whenever the access or refresh token is used and
+-- drives inactivity expiry.
+ALTER TABLE tokens ADD COLUMN refresh_expires_at TEXT;
+ALTER TABLE tokens ADD COLUMN refresh_max_expires_at TEXT;
+ALTER TABLE tokens ADD COLUMN last_used_at TEXT;
@@ -0,0 +1,8 @@
+-- When a user's credentials were last revoked (account disabled, password
+-- changed, signed out everywhere). Browser sessions started before
+-- revoked_at are no longer accepted.
+CREATE TABLE IF NOT EXISTS user_revocations (
+    user_id TEXT PRIMARY KEY,
+    revoked_at TEXT NOT NULL,
+    reason TEXT NOT NULL
+);
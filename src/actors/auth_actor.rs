@@ -1,16 +1,25 @@
+use crate::cache::CacheInvalidator;
+use crate::clock::{SharedClock, SystemClock};
+use crate::config::SecurityProfile;
 use crate::db::Database;
 use crate::events::{
     event_actor::{EmitEvent, EventActor},
     AuthEvent, EventSeverity, EventType,
 };
-use crate::models::{AuthorizationCode, OAuth2Error};
+use crate::models::{AuthorizationCode, Client, ErrorCode, OAuth2Error, User};
+use crate::services::{placeholder_hash, verify_password};
 use actix::prelude::*;
+use chrono::{DateTime, Utc};
 use rand::Rng;
 use std::sync::Arc;
 
 pub struct AuthActor {
     db: Arc<Database>,
     event_actor: Option<Addr<EventActor>>,
+    clock: SharedClock,
+    pkce_required: bool,
+    security_profile: SecurityProfile,
+    invalidator: Option<Arc<CacheInvalidator>>,
 }
 
 impl AuthActor {
@@ -18,6 +27,10 @@ impl AuthActor {
         Self {
             db,
             event_actor: None,
+            clock: Arc::new(SystemClock),
+            pkce_required: false,
+            security_profile: SecurityProfile::default(),
+            invalidator: None,
         }
     }
 
@@ -25,14 +38,47 @@ impl AuthActor {
         Self {
             db,
             event_actor: Some(event_actor),
+            clock: Arc::new(SystemClock),
+            pkce_required: false,
+            security_profile: SecurityProfile::default(),
+            invalidator: None,
         }
     }
+
+    /// Refuse authorization requests without a PKCE `code_challenge` from
+    /// every client, not only public ones
+    pub fn with_pkce_required(mut self, required: bool) -> Self {
+        self.pkce_required = required;
+        self
+    }
+
+    /// Hold authorization requests to `profile`'s PKCE rules
+    pub fn with_security_profile(mut self, profile: SecurityProfile) -> Self {
+        self.security_profile = profile;
+        self
+    }
+
+    /// Issue and expire authorization codes against `clock` instead of the
+    /// system time
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Drop what every replica has cached about the tokens revoked when a
+    /// code is replayed
+    pub fn with_invalidator(mut self, invalidator: Arc<CacheInvalidator>) -> Self {
+        self.invalidator = Some(invalidator);
+        self
+    }
 }
 
 impl Actor for AuthActor {
     type Context = Context<Self>;
 }
 
+impl Supervised for AuthActor {}
+
 #[derive(Message)]
 #[rtype(result = "Result<AuthorizationCode, OAuth2Error>")]
 pub struct CreateAuthorizationCode {
@@ -42,6 +88,12 @@ pub struct CreateAuthorizationCode {
     pub scope: String,
     pub code_challenge: Option<String>,
     pub code_challenge_method: Option<String>,
+    /// OpenID Connect nonce to echo in the ID token
+    pub nonce: Option<String>,
+    /// When the approving user signed in
+    pub auth_time: Option<DateTime<Utc>>,
+    /// Authorization journey the code is issued in
+    pub flow_id: Option<String>,
 }
 
 impl Handler<CreateAuthorizationCode> for AuthActor {
@@ -50,8 +102,23 @@ impl Handler<CreateAuthorizationCode> for AuthActor {
     fn handle(&mut self, msg: CreateAuthorizationCode, _: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
         let event_actor = self.event_actor.clone();
+        let clock = self.clock.clone();
+        let pkce_required = self.pkce_required;
+        let security_profile = self.security_profile;
 
         Box::pin(async move {
+            let client = db
+                .get_client(&msg.client_id)
+                .await?
+                .ok_or_else(|| OAuth2Error::invalid_client("Client not found"))?;
+            check_pkce(
+                pkce_required,
+                security_profile,
+                &client,
+                msg.code_challenge.as_deref(),
+                msg.code_challenge_method.as_deref(),
+            )?;
+
             let code = generate_code();
             let auth_code = AuthorizationCode::new(
                 code,
@@ -61,7 +128,10 @@ impl Handler<CreateAuthorizationCode> for AuthActor {
                 msg.scope.clone(),
                 msg.code_challenge,
                 msg.code_challenge_method,
-            );
+                clock.as_ref(),
+            )
+            .with_id_token_context(msg.nonce, msg.auth_time)
+            .with_flow_id(msg.flow_id);
 
             db.save_authorization_code(&auth_code).await?;
 
@@ -74,7 +144,8 @@ impl Handler<CreateAuthorizationCode> for AuthActor {
                     Some(msg.client_id.clone()),
                 )
                 .with_metadata("scope", msg.scope)
-                .with_metadata("redirect_uri", msg.redirect_uri);
+                .with_metadata("redirect_uri", msg.redirect_uri)
+                .with_flow_id(auth_code.flow_id.as_deref());
 
                 event_actor.do_send(EmitEvent { event });
             }
@@ -84,6 +155,59 @@ impl Handler<CreateAuthorizationCode> for AuthActor {
     }
 }
 
+/// Check an authorization request's PKCE parameters against the policy,
+/// before the user is asked to sign in or approve it
+#[derive(Message)]
+#[rtype(result = "Result<(), OAuth2Error>")]
+pub struct CheckPkce {
+    pub client: Client,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+impl Handler<CheckPkce> for AuthActor {
+    type Result = Result<(), OAuth2Error>;
+
+    fn handle(&mut self, msg: CheckPkce, _: &mut Self::Context) -> Self::Result {
+        check_pkce(
+            self.pkce_required,
+            self.security_profile,
+            &msg.client,
+            msg.code_challenge.as_deref(),
+            msg.code_challenge_method.as_deref(),
+        )
+    }
+}
+
+/// Public clients, and every client when `required` or under a profile that
+/// requires it, must send a `code_challenge`; whoever sends one must use a
+/// method the profile accepts. A challenge without a method is `plain`.
+fn check_pkce(
+    required: bool,
+    profile: SecurityProfile,
+    client: &Client,
+    code_challenge: Option<&str>,
+    code_challenge_method: Option<&str>,
+) -> Result<(), OAuth2Error> {
+    let challenged = code_challenge.is_some_and(|challenge| !challenge.is_empty());
+    let method = code_challenge_method.or(challenged.then_some("plain"));
+    if method.is_some_and(|method| !profile.code_challenge_methods().contains(&method)) {
+        return Err(OAuth2Error::invalid_request(
+            "Unsupported code_challenge_method; use S256",
+        ));
+    }
+    match code_challenge {
+        Some(challenge) if !challenge.is_empty() => Ok(()),
+        _ if client.is_public() => Err(OAuth2Error::invalid_request(
+            "Public clients must send a PKCE code_challenge",
+        )),
+        _ if required || profile.requires_pkce() => Err(OAuth2Error::invalid_request(
+            "A PKCE code_challenge is required",
+        )),
+        _ => Ok(()),
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "Result<AuthorizationCode, OAuth2Error>")]
 pub struct ValidateAuthorizationCode {
@@ -99,6 +223,8 @@ impl Handler<ValidateAuthorizationCode> for AuthActor {
     fn handle(&mut self, msg: ValidateAuthorizationCode, _: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
         let event_actor = self.event_actor.clone();
+        let clock = self.clock.clone();
+        let invalidator = self.invalidator.clone();
 
         Box::pin(async move {
             let auth_code = db
@@ -106,16 +232,51 @@ impl Handler<ValidateAuthorizationCode> for AuthActor {
                 .await?
                 .ok_or_else(|| OAuth2Error::invalid_grant("Authorization code not found"))?;
 
-            if !auth_code.is_valid() {
-                // Emit expired event
-                if let Some(event_actor) = &event_actor {
-                    let event = AuthEvent::new(
-                        EventType::AuthorizationCodeExpired,
-                        EventSeverity::Warning,
-                        Some(auth_code.user_id.clone()),
-                        Some(auth_code.client_id.clone()),
+            if !auth_code.is_valid(clock.as_ref()) {
+                // A used code that tokens came out of is being replayed, by
+                // whoever intercepted it or by the client it was stolen
+                // from; neither can tell which tokens are safe, so they all
+                // go (RFC 6749 section 4.1.2)
+                let revoked = if auth_code.used {
+                    let tokens = db
+                        .revoke_authorization_code_tokens(&auth_code.id, clock.now())
+                        .await?;
+                    if let Some(invalidator) = &invalidator {
+                        invalidator.forget_tokens(&tokens).await;
+                    }
+                    tokens.len()
+                } else {
+                    0
+                };
+                if revoked > 0 {
+                    tracing::warn!(
+                        code_id = %auth_code.id,
+                        client_id = %auth_code.client_id,
+                        tokens = revoked,
+                        "Authorization code replayed; revoked the tokens issued from it"
                     );
-                    event_actor.do_send(EmitEvent { event });
+                }
+                if let Some(event_actor) = &event_actor {
+                    let event = if revoked > 0 {
+                        AuthEvent::new(
+                            EventType::AuthorizationCodeReplayed,
+                            EventSeverity::Critical,
+                            Some(auth_code.user_id.clone()),
+                            Some(auth_code.client_id.clone()),
+                        )
+                        .with_metadata("code_id", auth_code.id.clone())
+                        .with_metadata("tokens_revoked", revoked.to_string())
+                    } else {
+                        AuthEvent::new(
+                            EventType::AuthorizationCodeExpired,
+                            EventSeverity::Warning,
+                            Some(auth_code.user_id.clone()),
+                            Some(auth_code.client_id.clone()),
+                        )
+                    };
+                    event_actor.do_send(EmitEvent {
+                        event: event.with_flow_id(auth_code.flow_id.as_deref()),
+                    });
                 }
 
                 return Err(OAuth2Error::invalid_grant(
@@ -146,9 +307,6 @@ impl Handler<ValidateAuthorizationCode> for AuthActor {
                 }
             }
 
-            // Mark as used
-            db.mark_authorization_code_used(&msg.code).await?;
-
             // Emit validated event
             if let Some(event_actor) = event_actor {
                 let event = AuthEvent::new(
@@ -156,7 +314,8 @@ impl Handler<ValidateAuthorizationCode> for AuthActor {
                     EventSeverity::Info,
                     Some(auth_code.user_id.clone()),
                     Some(auth_code.client_id.clone()),
-                );
+                )
+                .with_flow_id(auth_code.flow_id.as_deref());
                 event_actor.do_send(EmitEvent { event });
             }
 
@@ -165,6 +324,74 @@ impl Handler<ValidateAuthorizationCode> for AuthActor {
     }
 }
 
+/// Check a local user's username and password, for the password grant
+#[derive(Message)]
+#[rtype(result = "Result<User, OAuth2Error>")]
+pub struct AuthenticateUser {
+    pub username: String,
+    pub password: String,
+}
+
+impl Handler<AuthenticateUser> for AuthActor {
+    type Result = ResponseFuture<Result<User, OAuth2Error>>;
+
+    fn handle(&mut self, msg: AuthenticateUser, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let event_actor = self.event_actor.clone();
+
+        Box::pin(async move {
+            let user = db.get_user_by_username(&msg.username).await?;
+
+            // Argon2 is deliberately slow, so it runs off the actor's thread.
+            // Unknown usernames are checked against a placeholder so they
+            // cannot be told apart by how long the answer takes.
+            let hash = user.as_ref().map(|user| user.password_hash.clone());
+            let password = msg.password;
+            let matched = actix_web::rt::task::spawn_blocking(move || match hash {
+                Some(hash) => verify_password(&password, &hash),
+                None => verify_password(&password, placeholder_hash()),
+            })
+            .await
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+
+            let failure = match &user {
+                None => Some("unknown_user"),
+                Some(_) if !matched => Some("invalid_password"),
+                Some(user) if !user.enabled => Some("user_disabled"),
+                Some(_) => None,
+            };
+
+            if let Some(event_actor) = event_actor {
+                let subject = user.as_ref().map(|user| user.id.clone());
+                let event = match failure {
+                    Some(reason) => AuthEvent::new(
+                        EventType::UserAuthenticationFailed,
+                        EventSeverity::Warning,
+                        subject,
+                        None,
+                    )
+                    .with_metadata("reason", reason),
+                    None => AuthEvent::new(
+                        EventType::UserAuthenticated,
+                        EventSeverity::Info,
+                        subject,
+                        None,
+                    ),
+                }
+                .with_metadata("provider", "local")
+                .with_metadata("username", msg.username);
+                event_actor.do_send(EmitEvent { event });
+            }
+
+            match (user, failure) {
+                (Some(user), None) => Ok(user),
+                // The same answer whatever went wrong
+                _ => Err(OAuth2Error::invalid_grant("Invalid username or password")),
+            }
+        })
+    }
+}
+
 fn generate_code() -> String {
     let mut rng = rand::thread_rng();
     let code: String = (0..32)
@@ -180,7 +407,8 @@ fn generate_code() -> String {
     code
 }
 
-fn validate_pkce(challenge: &str, verifier: &str, method: &str) -> bool {
+/// Check a PKCE code verifier against the stored challenge (RFC 7636 §4.6)
+pub fn validate_pkce(challenge: &str, verifier: &str, method: &str) -> bool {
     match method {
         "plain" => challenge == verifier,
         "S256" => {
@@ -1,16 +1,20 @@
+use crate::clock::{SharedClock, SystemClock};
 use crate::db::Database;
 use crate::events::{
     event_actor::{EmitEvent, EventActor},
     AuthEvent, EventSeverity, EventType,
 };
-use crate::models::{Client, ClientRegistration, OAuth2Error};
+use crate::models::{Client, ClientRegistration, ClientState, OAuth2Error, PortableClient};
 use actix::prelude::*;
+use chrono::{DateTime, Duration, Utc};
 use rand::Rng;
 use std::sync::Arc;
 
 pub struct ClientActor {
     db: Arc<Database>,
     event_actor: Option<Addr<EventActor>>,
+    clock: SharedClock,
+    secret_lifetime: Option<Duration>,
 }
 
 impl ClientActor {
@@ -18,6 +22,8 @@ impl ClientActor {
         Self {
             db,
             event_actor: None,
+            clock: Arc::new(SystemClock),
+            secret_lifetime: None,
         }
     }
 
@@ -25,18 +31,43 @@ impl ClientActor {
         Self {
             db,
             event_actor: Some(event_actor),
+            clock: Arc::new(SystemClock),
+            secret_lifetime: None,
         }
     }
+
+    /// Expire secrets against `clock` instead of the system time
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Issue secrets that expire `days` after they are issued; 0 issues
+    /// secrets that never expire
+    pub fn with_secret_lifetime(mut self, days: u32) -> Self {
+        self.secret_lifetime = (days > 0).then(|| Duration::days(i64::from(days)));
+        self
+    }
+
+    /// When a secret issued now expires
+    fn secret_expiry(&self) -> Option<DateTime<Utc>> {
+        self.secret_lifetime
+            .map(|lifetime| self.clock.now() + lifetime)
+    }
 }
 
 impl Actor for ClientActor {
     type Context = Context<Self>;
 }
 
+impl Supervised for ClientActor {}
+
 #[derive(Message)]
 #[rtype(result = "Result<Client, OAuth2Error>")]
 pub struct RegisterClient {
     pub registration: ClientRegistration,
+    pub owner_id: Option<String>,
+    pub owner_org: Option<String>,
 }
 
 impl Handler<RegisterClient> for ClientActor {
@@ -45,20 +76,32 @@ impl Handler<RegisterClient> for ClientActor {
     fn handle(&mut self, msg: RegisterClient, _: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
         let event_actor = self.event_actor.clone();
+        let secret_expires_at = self.secret_expiry();
 
         Box::pin(async move {
             // Generate client credentials
             let client_id = format!("client_{}", uuid::Uuid::new_v4());
             let client_secret = generate_secret();
 
-            let client = Client::new(
+            let mut client = Client::new(
                 client_id.clone(),
                 client_secret,
                 msg.registration.redirect_uris,
                 msg.registration.grant_types,
                 msg.registration.scope.clone(),
                 msg.registration.client_name.clone(),
-            );
+            )
+            .with_allowed_origins(msg.registration.allowed_origins)
+            .with_redirect_uri_patterns(msg.registration.redirect_uri_patterns)
+            .with_secret_expiry(secret_expires_at)
+            .with_token_endpoint_auth_method(msg.registration.token_endpoint_auth_method)
+            .with_access_token_format(msg.registration.access_token_format);
+            if let Some(owner_id) = msg.owner_id {
+                client = client.with_owner(owner_id);
+            }
+            if let Some(owner_org) = msg.owner_org {
+                client = client.with_org(owner_org);
+            }
 
             db.save_client(&client).await?;
 
@@ -81,6 +124,49 @@ impl Handler<RegisterClient> for ClientActor {
     }
 }
 
+/// Register a client brought over from another server under its existing
+/// id. The returned client carries the newly issued plaintext secret.
+#[derive(Message)]
+#[rtype(result = "Result<Client, OAuth2Error>")]
+pub struct ImportClient {
+    pub client: PortableClient,
+}
+
+impl Handler<ImportClient> for ClientActor {
+    type Result = ResponseFuture<Result<Client, OAuth2Error>>;
+
+    fn handle(&mut self, msg: ImportClient, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let event_actor = self.event_actor.clone();
+        let secret_expires_at = self.secret_expiry();
+
+        Box::pin(async move {
+            let public = msg.client.token_endpoint_auth_method.is_public();
+            let client = msg
+                .client
+                .into_client(generate_secret())
+                .with_secret_expiry(secret_expires_at.filter(|_| !public));
+            db.save_client(&client).await?;
+
+            if let Some(event_actor) = event_actor {
+                let event = AuthEvent::new(
+                    EventType::ClientRegistered,
+                    EventSeverity::Info,
+                    None,
+                    Some(client.client_id.clone()),
+                )
+                .with_metadata("client_name", client.name.clone())
+                .with_metadata("scope", client.scope.clone())
+                .with_metadata("source", "import");
+
+                event_actor.do_send(EmitEvent { event });
+            }
+
+            Ok(client)
+        })
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "Result<Client, OAuth2Error>")]
 pub struct GetClient {
@@ -101,6 +187,9 @@ impl Handler<GetClient> for ClientActor {
     }
 }
 
+/// Check a client's secret. An expired secret is refused with
+/// `invalid_client` even when it matches. The token endpoint, which checks
+/// secrets with this, records the outcome of each request itself.
 #[derive(Message)]
 #[rtype(result = "Result<bool, OAuth2Error>")]
 pub struct ValidateClient {
@@ -113,7 +202,7 @@ impl Handler<ValidateClient> for ClientActor {
 
     fn handle(&mut self, msg: ValidateClient, _: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
-        let event_actor = self.event_actor.clone();
+        let now = self.clock.now();
 
         Box::pin(async move {
             let client = db
@@ -123,30 +212,151 @@ impl Handler<ValidateClient> for ClientActor {
 
             // Use constant-time comparison to prevent timing attacks
             use subtle::ConstantTimeEq;
-            let secret_match = client
+            let secret_match: bool = client
                 .client_secret
                 .as_bytes()
                 .ct_eq(msg.client_secret.as_bytes())
                 .into();
+            if secret_match && client.secret_expired(now) {
+                return Err(secret_expired_error());
+            }
+            Ok(secret_match)
+        })
+    }
+}
+
+/// Issue a new secret for a client. The returned client carries the
+/// plaintext secret; it is not retrievable afterwards.
+#[derive(Message)]
+#[rtype(result = "Result<Client, OAuth2Error>")]
+pub struct RotateClientSecret {
+    pub client_id: String,
+}
+
+impl Handler<RotateClientSecret> for ClientActor {
+    type Result = ResponseFuture<Result<Client, OAuth2Error>>;
+
+    fn handle(&mut self, msg: RotateClientSecret, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let event_actor = self.event_actor.clone();
+        let secret_expires_at = self.secret_expiry();
+
+        Box::pin(async move {
+            let mut client = db
+                .get_client(&msg.client_id)
+                .await?
+                .ok_or_else(|| OAuth2Error::invalid_client("Client not found"))?;
+            if client.is_public() {
+                return Err(OAuth2Error::invalid_request(
+                    "Public clients have no secret",
+                ));
+            }
+
+            client.client_secret = generate_secret();
+            client.secret_expires_at = secret_expires_at;
+            db.update_client_secret(
+                &client.client_id,
+                &client.client_secret,
+                client.secret_expires_at,
+            )
+            .await?;
+
+            if let Some(event_actor) = event_actor {
+                let event = AuthEvent::new(
+                    EventType::ClientUpdated,
+                    EventSeverity::Info,
+                    client.owner_id.clone(),
+                    Some(client.client_id.clone()),
+                )
+                .with_metadata("change", "secret_rotated");
+
+                event_actor.do_send(EmitEvent { event });
+            }
+
+            Ok(client)
+        })
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), OAuth2Error>")]
+pub struct UpdateClientRedirectUris {
+    pub client_id: String,
+    pub redirect_uris: Vec<String>,
+}
+
+impl Handler<UpdateClientRedirectUris> for ClientActor {
+    type Result = ResponseFuture<Result<(), OAuth2Error>>;
+
+    fn handle(&mut self, msg: UpdateClientRedirectUris, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let event_actor = self.event_actor.clone();
+
+        Box::pin(async move {
+            db.update_client_redirect_uris(&msg.client_id, &msg.redirect_uris)
+                .await?;
 
-            // Emit event
             if let Some(event_actor) = event_actor {
                 let event = AuthEvent::new(
-                    EventType::ClientValidated,
+                    EventType::ClientUpdated,
                     EventSeverity::Info,
                     None,
                     Some(msg.client_id),
                 )
-                .with_metadata("success", if secret_match { "true" } else { "false" });
+                .with_metadata("change", "redirect_uris_updated")
+                .with_metadata("redirect_uris", msg.redirect_uris.join(" "));
 
                 event_actor.do_send(EmitEvent { event });
             }
 
-            Ok(secret_match)
+            Ok(())
         })
     }
 }
 
+/// Bring a client in line with a declared state. `drift` names the fields
+/// that differ, for the event.
+#[derive(Message)]
+#[rtype(result = "Result<(), OAuth2Error>")]
+pub struct ApplyClientState {
+    pub client_id: String,
+    pub state: ClientState,
+    pub drift: Vec<String>,
+}
+
+impl Handler<ApplyClientState> for ClientActor {
+    type Result = ResponseFuture<Result<(), OAuth2Error>>;
+
+    fn handle(&mut self, msg: ApplyClientState, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let event_actor = self.event_actor.clone();
+
+        Box::pin(async move {
+            db.update_client_state(&msg.client_id, &msg.state).await?;
+
+            if let Some(event_actor) = event_actor {
+                let event = AuthEvent::new(
+                    EventType::ClientUpdated,
+                    EventSeverity::Info,
+                    None,
+                    Some(msg.client_id),
+                )
+                .with_metadata("change", "state_applied")
+                .with_metadata("drift", msg.drift.join(" "));
+
+                event_actor.do_send(EmitEvent { event });
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Refusal of a secret that matched but has expired
+pub fn secret_expired_error() -> OAuth2Error {
+    OAuth2Error::invalid_client("Client secret has expired; rotate it to get a new one")
+}
+
 fn generate_secret() -> String {
     let mut rng = rand::thread_rng();
     let secret: String = (0..32)
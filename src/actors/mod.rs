@@ -1,7 +1,9 @@
 pub mod auth_actor;
 pub mod client_actor;
+pub mod supervisor;
 pub mod token_actor;
 
 pub use auth_actor::*;
 pub use client_actor::*;
+pub use supervisor::supervise;
 pub use token_actor::*;
@@ -0,0 +1,123 @@
+//! Supervision for the actors behind the handlers.
+//!
+//! Handlers hold an actor's address for the life of the server, so an actor
+//! that stopped or panicked would leave them with mailbox errors until a
+//! restart. [`supervise`] runs an actor so that either restarts it on the
+//! same mailbox instead: messages already queued are handled by the restarted
+//! actor, and only the message it failed on, and replies it had in flight,
+//! are lost. Their callers get a `MailboxError`, answered as `503
+//! temporarily_unavailable` with `Retry-After`.
+
+use actix::dev::ContextFut;
+use actix::{Actor, Addr, AsyncContext, Context, Supervised};
+use prometheus::IntCounterVec;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{self, Poll};
+
+/// Start `actor` under supervision, counting its restarts in `restarts`
+/// under the `name` label
+pub fn supervise<A>(name: &'static str, actor: A, restarts: IntCounterVec) -> Addr<A>
+where
+    A: Supervised + Actor<Context = Context<A>>,
+{
+    let ctx = Context::new();
+    let addr = ctx.address();
+    actix_rt::spawn(Supervision {
+        name,
+        restarts,
+        fut: ctx.into_future(actor),
+    });
+    addr
+}
+
+/// Polls an actor's context, restarting it whenever it stops or panics
+/// while a mailbox address is still held
+struct Supervision<A>
+where
+    A: Supervised + Actor<Context = Context<A>>,
+{
+    name: &'static str,
+    restarts: IntCounterVec,
+    fut: ContextFut<A, Context<A>>,
+}
+
+impl<A> Future for Supervision<A>
+where
+    A: Supervised + Actor<Context = Context<A>>,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        loop {
+            match panic::catch_unwind(AssertUnwindSafe(|| Pin::new(&mut this.fut).poll(cx))) {
+                Ok(Poll::Pending) => return Poll::Pending,
+                Ok(Poll::Ready(())) => tracing::warn!("{} actor stopped", this.name),
+                Err(_) => tracing::error!("{} actor panicked", this.name),
+            }
+            if !this.fut.restart() {
+                // Every address is gone; nothing could send to it again
+                return Poll::Ready(());
+            }
+            this.restarts.with_label_values(&[this.name]).inc();
+            tracing::warn!("{} actor restarted", this.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::{ActorContext, Handler, Message};
+    use prometheus::Opts;
+
+    struct Flaky;
+
+    impl Actor for Flaky {
+        type Context = Context<Self>;
+    }
+
+    impl Supervised for Flaky {}
+
+    #[derive(Message)]
+    #[rtype(result = "String")]
+    enum Ask {
+        Echo,
+        Stop,
+        Panic,
+    }
+
+    impl Handler<Ask> for Flaky {
+        type Result = String;
+
+        fn handle(&mut self, msg: Ask, ctx: &mut Self::Context) -> Self::Result {
+            match msg {
+                Ask::Echo => "echo".to_string(),
+                Ask::Stop => {
+                    ctx.stop();
+                    "stopping".to_string()
+                }
+                Ask::Panic => panic!("flaky actor panicked on purpose"),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_restarts_after_stop_and_panic() {
+        let restarts =
+            IntCounterVec::new(Opts::new("restarts", "Actor restarts"), &["actor"]).unwrap();
+        let addr = supervise("flaky", Flaky, restarts.clone());
+
+        assert_eq!(addr.send(Ask::Echo).await.unwrap(), "echo");
+        assert_eq!(addr.send(Ask::Stop).await.unwrap(), "stopping");
+        assert_eq!(addr.send(Ask::Echo).await.unwrap(), "echo");
+
+        // The message it panicked on is lost; the address is not
+        assert!(addr.send(Ask::Panic).await.is_err());
+        assert_eq!(addr.send(Ask::Echo).await.unwrap(), "echo");
+        assert!(addr.connected());
+        assert_eq!(restarts.with_label_values(&["flaky"]).get(), 2);
+    }
+}
@@ -1,51 +1,372 @@
-use crate::db::Database;
+use crate::clock::{Clock, SharedClock, SystemClock};
+use crate::config::GrantTokenConfig;
+use crate::config::RefreshTokenConfig;
+use crate::config::UsageConfig;
+use crate::db::{Database, DbTransaction};
 use crate::events::{
     event_actor::{EmitEvent, EventActor},
     AuthEvent, EventSeverity, EventType,
 };
-use crate::models::{Claims, OAuth2Error, Token};
+use crate::keys::{SharedKeys, SigningKeys};
+use crate::models::{
+    scope::validate_scopes, AuthorizationCode, Claims, ErrorCode, IdTokenClaims, OAuth2Error,
+    Resource, Token, TokenFormat, DEFAULT_ISSUER,
+};
+use crate::services::{
+    publish_recorded_event, AuthorizationPolicy, EventOutbox, JtiDenylist, PolicyInput,
+    RequestOrigin, UsageTracker,
+};
 use actix::prelude::*;
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
 use std::sync::Arc;
 
 pub struct TokenActor {
     db: Arc<Database>,
-    jwt_secret: String,
+    keys: SharedKeys,
     event_actor: Option<Addr<EventActor>>,
+    clock: SharedClock,
+    refresh_policy: RefreshTokenConfig,
+    grant_tokens: GrantTokenConfig,
+    policy: Arc<AuthorizationPolicy>,
+    usage: Arc<UsageTracker>,
+    outbox: Option<Arc<EventOutbox>>,
+    denylist: Option<Arc<JtiDenylist>>,
+    issuer: String,
 }
 
 impl TokenActor {
-    pub fn new(db: Arc<Database>, jwt_secret: String) -> Self {
+    pub fn new(db: Arc<Database>, keys: SharedKeys) -> Self {
         Self {
-            db,
-            jwt_secret,
+            keys,
             event_actor: None,
+            clock: Arc::new(SystemClock),
+            refresh_policy: RefreshTokenConfig::default(),
+            grant_tokens: GrantTokenConfig::default(),
+            policy: Arc::new(AuthorizationPolicy::permit_all()),
+            usage: Arc::new(UsageTracker::new(db.clone(), &UsageConfig::default())),
+            outbox: None,
+            denylist: None,
+            issuer: DEFAULT_ISSUER.to_string(),
+            db,
         }
     }
 
-    pub fn with_events(
-        db: Arc<Database>,
-        jwt_secret: String,
-        event_actor: Addr<EventActor>,
-    ) -> Self {
+    pub fn with_events(db: Arc<Database>, keys: SharedKeys, event_actor: Addr<EventActor>) -> Self {
         Self {
-            db,
-            jwt_secret,
+            keys,
             event_actor: Some(event_actor),
+            clock: Arc::new(SystemClock),
+            refresh_policy: RefreshTokenConfig::default(),
+            grant_tokens: GrantTokenConfig::default(),
+            policy: Arc::new(AuthorizationPolicy::permit_all()),
+            usage: Arc::new(UsageTracker::new(db.clone(), &UsageConfig::default())),
+            outbox: None,
+            denylist: None,
+            issuer: DEFAULT_ISSUER.to_string(),
+            db,
+        }
+    }
+
+    /// Issue and expire tokens against `clock` instead of the system time
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Lifetime, absolute limit and inactivity expiry for refresh tokens
+    pub fn with_refresh_policy(mut self, policy: RefreshTokenConfig) -> Self {
+        self.refresh_policy = policy;
+        self
+    }
+
+    /// Token format and lifetimes by grant type
+    pub fn with_grant_tokens(mut self, grant_tokens: GrantTokenConfig) -> Self {
+        self.grant_tokens = grant_tokens;
+        self
+    }
+
+    /// Authorization policy every token request, refreshes included, must pass
+    pub fn with_policy(mut self, policy: Arc<AuthorizationPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Record token and client usage in `usage`, shared with whoever reports
+    /// on it
+    pub fn with_usage(mut self, usage: Arc<UsageTracker>) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Record token events in `outbox` with the token they describe, rather
+    /// than sending them once it is saved
+    pub fn with_outbox(mut self, outbox: Option<Arc<EventOutbox>>) -> Self {
+        self.outbox = outbox;
+        self
+    }
+
+    /// Accept JWT access tokens that are not on `denylist` without looking
+    /// them up
+    pub fn with_denylist(mut self, denylist: Option<Arc<JtiDenylist>>) -> Self {
+        self.denylist = denylist;
+        self
+    }
+
+    /// `iss` of the tokens issued, the server's public URL
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = issuer.into();
+        self
+    }
+
+    /// The refresh policy, with the lifetime configured for the refresh grant
+    fn rotation_policy(&self) -> RefreshTokenConfig {
+        let mut policy = self.refresh_policy.clone();
+        if let Some(lifetime) = self
+            .grant_tokens
+            .refresh_token_lifetime_for("refresh_token")
+            .filter(|lifetime| *lifetime > 0)
+        {
+            policy.lifetime = lifetime;
         }
+        policy
     }
 }
 
+/// When `token`'s refresh token stops being accepted. Tokens issued before
+/// expiry tracking keep the default lifetime.
+fn refresh_expires_at(token: &Token, policy: &RefreshTokenConfig) -> DateTime<Utc> {
+    token
+        .refresh_expires_at
+        .unwrap_or_else(|| token.created_at + Duration::seconds(policy.lifetime as i64))
+}
+
+/// Reject a refresh token that was revoked, has expired, or went unused for
+/// longer than the idle timeout
+fn check_refreshable(
+    token: &Token,
+    policy: &RefreshTokenConfig,
+    usage: &UsageTracker,
+    now: DateTime<Utc>,
+) -> Result<(), OAuth2Error> {
+    if token.revoked {
+        return Err(OAuth2Error::invalid_grant("Refresh token has been revoked"));
+    }
+    if now > refresh_expires_at(token, policy) {
+        return Err(OAuth2Error::invalid_grant("Refresh token has expired"));
+    }
+    let last_used_at = usage.last_used(token).unwrap_or(token.created_at);
+    if policy.idle_timeout > 0 && now - last_used_at > Duration::seconds(policy.idle_timeout as i64)
+    {
+        return Err(OAuth2Error::invalid_grant(
+            "Refresh token expired after inactivity",
+        ));
+    }
+    Ok(())
+}
+
+/// When a refresh token expires and the limit later rotations inherit
+struct RefreshExpiry {
+    expires_at: DateTime<Utc>,
+    max_expires_at: Option<DateTime<Utc>>,
+    /// Scope the refresh token keeps, when wider than its access token's
+    scope: Option<String>,
+}
+
+impl RefreshExpiry {
+    /// Expiry for the first refresh token of a grant
+    fn for_grant(policy: &RefreshTokenConfig, now: DateTime<Utc>) -> Self {
+        let max_expires_at =
+            (policy.max_lifetime > 0).then(|| now + Duration::seconds(policy.max_lifetime as i64));
+        Self::for_rotation(policy, now, max_expires_at)
+    }
+
+    /// A fresh lifetime, but never past the grant's limit
+    fn for_rotation(
+        policy: &RefreshTokenConfig,
+        now: DateTime<Utc>,
+        max_expires_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        let expires_at = now + Duration::seconds(policy.lifetime as i64);
+        Self {
+            expires_at: max_expires_at.map_or(expires_at, |max| expires_at.min(max)),
+            max_expires_at,
+            scope: None,
+        }
+    }
+}
+
+/// The access token a grant gets: the resource's format if it is for one,
+/// otherwise the client's if it registered one, otherwise the grant's, for
+/// the grant's lifetime
+struct AccessTokenShape {
+    format: TokenFormat,
+    lifetime: i64,
+}
+
+impl AccessTokenShape {
+    fn for_grant(
+        config: &GrantTokenConfig,
+        grant_type: &str,
+        resource: Option<&Resource>,
+        client_format: Option<TokenFormat>,
+    ) -> Self {
+        Self {
+            format: resource
+                .map(Resource::format)
+                .or(client_format)
+                .unwrap_or_else(|| config.format_for(grant_type)),
+            lifetime: i64::try_from(config.access_token_lifetime_for(grant_type))
+                .unwrap_or(i64::MAX),
+        }
+    }
+}
+
+/// Issue an access token, plus a refresh token when `refresh` is set, and
+/// store them in `tx`. With a `resource` the access token is minted for it;
+/// otherwise it is for the client. A token `replacing` another keeps its
+/// binding, authorization details, flow and code; a new one is exchanged for
+/// `code` when there is one. `event` goes into the outbox with it.
+#[allow(clippy::too_many_arguments)]
+async fn issue_token(
+    tx: &mut DbTransaction,
+    keys: &SigningKeys,
+    clock: &dyn Clock,
+    issuer: &str,
+    user_id: &str,
+    client_id: &str,
+    scope: &str,
+    resource: Option<&Resource>,
+    shape: AccessTokenShape,
+    refresh: Option<RefreshExpiry>,
+    replacing: Option<&Token>,
+    code: Option<&AuthorizationCode>,
+    event: Option<&AuthEvent>,
+) -> Result<Token, OAuth2Error> {
+    let claims = |lifetime: i64, audience: Option<&str>| {
+        let claims = Claims::new(
+            user_id.to_string(),
+            client_id.to_string(),
+            scope.to_string(),
+            lifetime,
+            clock,
+        )
+        .with_issuer(issuer);
+        match audience {
+            Some(audience) => claims.with_audience(audience),
+            None => claims,
+        }
+    };
+    let encoding_failed = |e| OAuth2Error::internal(ErrorCode::ServerError, e);
+
+    let audience = resource.map(|resource| resource.identifier.as_str());
+    let access_claims = claims(shape.lifetime, audience);
+    let (access_token, jti) = match shape.format {
+        TokenFormat::Opaque => (opaque_token(), None),
+        TokenFormat::Jwt => (
+            access_claims
+                .encode_access_token(keys)
+                .map_err(encoding_failed)?,
+            Some(access_claims.jti),
+        ),
+    };
+    // Refresh tokens only ever come back to us, so they stay JWTs for the client
+    let refresh_token = match &refresh {
+        Some(refresh) => Some(
+            claims((refresh.expires_at - clock.now()).num_seconds(), None)
+                .encode(keys)
+                .map_err(encoding_failed)?,
+        ),
+        None => None,
+    };
+
+    let mut token = Token::new(
+        access_token,
+        refresh_token,
+        client_id.to_string(),
+        user_id.to_string(),
+        scope.to_string(),
+        shape.lifetime,
+        clock,
+    )
+    .with_audience(audience.map(str::to_string));
+    if let Some(code) = code {
+        token = token.exchanged_for(code);
+    }
+    if let Some(jti) = jti {
+        token = token.with_jti(jti);
+    }
+    if let Some(refresh) = refresh {
+        token = token.with_refresh_expiry(refresh.expires_at, refresh.max_expires_at);
+        token.refresh_scope = refresh.scope;
+    }
+    if let Some(previous) = replacing {
+        token = token.inheriting(previous);
+    }
+
+    tx.save_token(&token, event).await?;
+    Ok(token)
+}
+
+/// The access token format the client registered for, if any
+async fn client_token_format(
+    db: &Database,
+    client_id: &str,
+) -> Result<Option<TokenFormat>, OAuth2Error> {
+    Ok(db
+        .get_client(client_id)
+        .await?
+        .and_then(|client| client.token_format()))
+}
+
+/// A random reference token; everything about it lives in the database
+fn opaque_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
 impl Actor for TokenActor {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.usage.flush_interval(), |actor, ctx| {
+            let usage = actor.usage.clone();
+            ctx.spawn(
+                async move {
+                    if let Err(e) = usage.flush().await {
+                        tracing::warn!("Failed to record token usage: {}", e);
+                    }
+                }
+                .into_actor(actor),
+            );
+        });
+    }
 }
 
+/// Restarted on the same mailbox; `started` schedules the usage flush again
+impl Supervised for TokenActor {}
+
 #[derive(Message)]
 #[rtype(result = "Result<Token, OAuth2Error>")]
 pub struct CreateToken {
     pub user_id: String,
+    /// Roles from the user's directory groups, passed to the authorization
+    /// policy
+    pub roles: Vec<String>,
     pub client_id: String,
     pub scope: String,
     pub include_refresh: bool,
+    /// Grant the token is issued under, passed to the authorization policy
+    pub grant_type: &'static str,
+    /// Resource server the token is for, when the client named one
+    pub resource: Option<Resource>,
+    /// Where the token request came from, recorded on the event
+    pub origin: RequestOrigin,
+    /// Validated code the token is exchanged for. It is consumed in the
+    /// transaction that saves the token, so it is never spent on a token
+    /// that was not issued.
+    pub authorization_code: Option<AuthorizationCode>,
 }
 
 impl Handler<CreateToken> for TokenActor {
@@ -53,61 +374,268 @@ impl Handler<CreateToken> for TokenActor {
 
     fn handle(&mut self, msg: CreateToken, _: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
-        let jwt_secret = self.jwt_secret.clone();
+        let keys = self.keys.clone();
         let event_actor = self.event_actor.clone();
+        let clock = self.clock.clone();
+        let issuer = self.issuer.clone();
+        let mut refresh_policy = self.refresh_policy.clone();
+        let grant_tokens = self.grant_tokens.clone();
+        if let Some(lifetime) = self.grant_tokens.refresh_token_lifetime_for(msg.grant_type) {
+            refresh_policy.lifetime = lifetime;
+        }
+        let policy = self.policy.clone();
+        let usage = self.usage.clone();
+        let outbox = self.outbox.clone();
 
         Box::pin(async move {
-            // Create access token
-            let access_claims = Claims::new(
-                msg.user_id.clone(),
-                msg.client_id.clone(),
-                msg.scope.clone(),
-                3600, // 1 hour
+            let scope = policy
+                .enforce(
+                    &PolicyInput::new("token", &msg.user_id, &msg.client_id, &msg.scope)
+                        .with_grant_type(msg.grant_type)
+                        .with_roles(&msg.roles)
+                        .with_resource(msg.resource.as_ref().map(|r| r.identifier.as_str()))
+                        .with_origin(&msg.origin),
+                )
+                .await?;
+            let refresh = (msg.include_refresh && refresh_policy.lifetime > 0)
+                .then(|| RefreshExpiry::for_grant(&refresh_policy, clock.now()));
+            let shape = AccessTokenShape::for_grant(
+                &grant_tokens,
+                msg.grant_type,
+                msg.resource.as_ref(),
+                client_token_format(&db, &msg.client_id).await?,
             );
-            let access_token = access_claims
-                .encode(&jwt_secret)
-                .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))?;
-
-            // Create refresh token if requested
-            let refresh_token = if msg.include_refresh {
-                let refresh_claims = Claims::new(
-                    msg.user_id.clone(),
-                    msg.client_id.clone(),
-                    msg.scope.clone(),
-                    2592000, // 30 days
-                );
-                Some(
-                    refresh_claims
-                        .encode(&jwt_secret)
-                        .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))?,
+            let flow_id = msg
+                .authorization_code
+                .as_ref()
+                .and_then(|code| code.flow_id.as_deref());
+            let event = event_actor.as_ref().map(|_| {
+                let event = AuthEvent::new(
+                    EventType::TokenCreated,
+                    EventSeverity::Info,
+                    Some(msg.user_id.clone()),
+                    Some(msg.client_id.clone()),
                 )
-            } else {
-                None
+                .with_metadata("scope", scope.clone())
+                .with_metadata("grant_type", msg.grant_type)
+                .with_metadata("has_refresh_token", refresh.is_some().to_string())
+                .with_flow_id(flow_id);
+                let event = match &msg.resource {
+                    Some(resource) => event.with_metadata("audience", resource.identifier.clone()),
+                    None => event,
+                };
+                let event = if msg.roles.is_empty() {
+                    event
+                } else {
+                    event.with_metadata("roles", msg.roles.join(","))
+                };
+                msg.origin.annotate(event)
+            });
+            let mut tx = db.begin().await?;
+            // Another request, possibly on another replica, may have redeemed
+            // the code since it was validated
+            if let Some(code) = &msg.authorization_code {
+                if !tx.consume_authorization_code(&code.code).await? {
+                    return Err(OAuth2Error::invalid_grant(
+                        "Authorization code is expired or used",
+                    ));
+                }
+            }
+            let token = issue_token(
+                &mut tx,
+                &keys,
+                clock.as_ref(),
+                &issuer,
+                &msg.user_id,
+                &msg.client_id,
+                &scope,
+                msg.resource.as_ref(),
+                shape,
+                refresh,
+                None,
+                msg.authorization_code.as_ref(),
+                event.as_ref().filter(|_| outbox.is_some()),
+            )
+            .await?;
+            if let Some(code) = &msg.authorization_code {
+                tx.link_authorization_token(&code.id, &token.id).await?;
+            }
+            tx.commit().await?;
+            usage.record_client(&msg.client_id, clock.now());
+
+            if let Some(event) = event {
+                publish_recorded_event(outbox.as_deref(), event_actor.as_ref(), event);
+            }
+
+            Ok(token)
+        })
+    }
+}
+
+/// Mint an OpenID Connect ID token for the user who approved a code. It is
+/// issued next to the access token, not stored: it only tells the client
+/// who signed in and is never presented back to this server.
+#[derive(Message)]
+#[rtype(result = "Result<String, OAuth2Error>")]
+pub struct CreateIdToken {
+    pub authorization_code: AuthorizationCode,
+    /// Lifetime in seconds, matching the access token issued with it
+    pub expires_in: i64,
+}
+
+impl Handler<CreateIdToken> for TokenActor {
+    type Result = Result<String, OAuth2Error>;
+
+    fn handle(&mut self, msg: CreateIdToken, _: &mut Self::Context) -> Self::Result {
+        IdTokenClaims::new(&msg.authorization_code, msg.expires_in, self.clock.as_ref())
+            .with_issuer(&self.issuer)
+            .encode(&self.keys)
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Token, OAuth2Error>")]
+pub struct RefreshAccessToken {
+    pub refresh_token: String,
+    pub client_id: String,
+    /// Must match the resource the token was first issued for, if given
+    pub resource: Option<String>,
+    /// Narrower scope for the new access token; the refresh token keeps
+    /// the scope it was granted
+    pub scope: Option<String>,
+    /// Where the refresh request came from, recorded on the event
+    pub origin: RequestOrigin,
+}
+
+impl Handler<RefreshAccessToken> for TokenActor {
+    type Result = ResponseFuture<Result<Token, OAuth2Error>>;
+
+    /// Exchange a refresh token for a new access token and a rotated refresh
+    /// token. The old refresh token stops working; its access token does not.
+    fn handle(&mut self, msg: RefreshAccessToken, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let keys = self.keys.clone();
+        let event_actor = self.event_actor.clone();
+        let clock = self.clock.clone();
+        let issuer = self.issuer.clone();
+        let policy = self.rotation_policy();
+        let grant_tokens = self.grant_tokens.clone();
+        let authorization_policy = self.policy.clone();
+        let usage = self.usage.clone();
+        let outbox = self.outbox.clone();
+
+        Box::pin(async move {
+            let now = clock.now();
+            let previous = db
+                .get_token_by_refresh_token(&msg.refresh_token)
+                .await?
+                .ok_or_else(|| OAuth2Error::invalid_grant("Refresh token not found"))?;
+
+            if previous.client_id != msg.client_id {
+                return Err(OAuth2Error::invalid_grant(
+                    "Refresh token was issued to another client",
+                ));
+            }
+            check_refreshable(&previous, &policy, &usage, now)?;
+
+            // Rotated tokens stay with the original resource
+            if msg.resource.is_some() && msg.resource != previous.audience {
+                return Err(OAuth2Error::new(
+                    "invalid_target",
+                    Some("Refresh tokens cannot change resource"),
+                ));
+            }
+            let resource = match &previous.audience {
+                Some(audience) => Some(db.get_resource_by_identifier(audience).await?.ok_or_else(
+                    || OAuth2Error::invalid_grant("Refresh token's resource no longer exists"),
+                )?),
+                None => None,
             };
 
-            let token = Token::new(
-                access_token,
-                refresh_token,
-                msg.client_id.clone(),
-                msg.user_id.clone(),
-                msg.scope.clone(),
-                3600,
-            );
+            // A client may ask for less than it was granted, never more
+            let granted = previous.refreshable_scope();
+            let requested = match msg.scope.as_deref() {
+                Some(requested) => {
+                    let requested = requested.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if requested.is_empty() {
+                        return Err(OAuth2Error::invalid_scope("Requested scope is empty"));
+                    }
+                    if !validate_scopes(&requested, granted) {
+                        return Err(OAuth2Error::invalid_scope(
+                            "Requested scope exceeds what the refresh token was granted",
+                        ));
+                    }
+                    requested
+                }
+                None => granted.to_string(),
+            };
 
-            db.save_token(&token).await?;
+            // The policy may have changed since the grant; a narrowed scope
+            // sticks for later rotations
+            let scope = authorization_policy
+                .enforce(
+                    &PolicyInput::new("token", &previous.user_id, &previous.client_id, &requested)
+                        .with_grant_type("refresh_token")
+                        .with_resource(previous.audience.as_deref())
+                        .with_origin(&msg.origin),
+                )
+                .await?;
 
-            // Emit event
-            if let Some(event_actor) = event_actor {
+            let mut refresh =
+                RefreshExpiry::for_rotation(&policy, now, previous.refresh_max_expires_at);
+            if msg.scope.is_some() && scope != granted {
+                refresh.scope = Some(granted.to_string());
+            }
+            let event = event_actor.as_ref().map(|_| {
                 let event = AuthEvent::new(
                     EventType::TokenCreated,
                     EventSeverity::Info,
-                    Some(msg.user_id),
-                    Some(msg.client_id),
+                    Some(previous.user_id.clone()),
+                    Some(previous.client_id.clone()),
                 )
-                .with_metadata("scope", msg.scope)
-                .with_metadata("has_refresh_token", msg.include_refresh.to_string());
+                .with_metadata("scope", scope.clone())
+                .with_metadata("grant_type", "refresh_token")
+                .with_metadata("has_refresh_token", "true")
+                .with_flow_id(previous.flow_id.as_deref());
+                msg.origin.annotate(event)
+            });
+            // The old refresh token is only spent if its replacement is saved
+            let mut tx = db.begin().await?;
+            if !tx
+                .consume_refresh_token(&previous.id, &msg.refresh_token, now)
+                .await?
+            {
+                return Err(OAuth2Error::invalid_grant(
+                    "Refresh token has already been used",
+                ));
+            }
+            let token = issue_token(
+                &mut tx,
+                &keys,
+                clock.as_ref(),
+                &issuer,
+                &previous.user_id,
+                &previous.client_id,
+                &scope,
+                resource.as_ref(),
+                AccessTokenShape::for_grant(
+                    &grant_tokens,
+                    "refresh_token",
+                    resource.as_ref(),
+                    client_token_format(&db, &previous.client_id).await?,
+                ),
+                Some(refresh),
+                Some(&previous),
+                None,
+                event.as_ref().filter(|_| outbox.is_some()),
+            )
+            .await?;
+            tx.commit().await?;
+            usage.record_token(&previous, now);
 
-                event_actor.do_send(EmitEvent { event });
+            if let Some(event) = event {
+                publish_recorded_event(outbox.as_deref(), event_actor.as_ref(), event);
             }
 
             Ok(token)
@@ -115,6 +643,35 @@ impl Handler<CreateToken> for TokenActor {
     }
 }
 
+/// Look up a refresh token for introspection. Answers `None` if there is
+/// no such refresh token, and an error if there is but it cannot be used.
+#[derive(Message)]
+#[rtype(result = "Result<Option<Token>, OAuth2Error>")]
+pub struct ValidateRefreshToken {
+    pub token: String,
+}
+
+impl Handler<ValidateRefreshToken> for TokenActor {
+    type Result = ResponseFuture<Result<Option<Token>, OAuth2Error>>;
+
+    /// The token comes back with `refresh_expires_at` always set
+    fn handle(&mut self, msg: ValidateRefreshToken, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let clock = self.clock.clone();
+        let policy = self.rotation_policy();
+        let usage = self.usage.clone();
+
+        Box::pin(async move {
+            let Some(mut token) = db.get_token_by_refresh_token(&msg.token).await? else {
+                return Ok(None);
+            };
+            check_refreshable(&token, &policy, &usage, clock.now())?;
+            token.refresh_expires_at = Some(refresh_expires_at(&token, &policy));
+            Ok(Some(token))
+        })
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "Result<Token, OAuth2Error>")]
 pub struct ValidateToken {
@@ -127,14 +684,36 @@ impl Handler<ValidateToken> for TokenActor {
     fn handle(&mut self, msg: ValidateToken, _: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
         let event_actor = self.event_actor.clone();
+        let clock = self.clock.clone();
+        let usage = self.usage.clone();
+        let denylist = self.denylist.clone();
+        let keys = self.keys.clone();
 
         Box::pin(async move {
-            let token = db
-                .get_token_by_access_token(&msg.token)
-                .await?
-                .ok_or_else(|| OAuth2Error::invalid_grant("Token not found"))?;
+            // In stateless mode a JWT access token is taken at its word,
+            // unless its jti has been revoked
+            let verified = denylist
+                .as_ref()
+                .and_then(|denylist| Some((denylist, denylist.decode(&msg.token, &keys)?)));
+            let found = match verified {
+                Some((denylist, claims)) => {
+                    let mut token = Token::from_claims(msg.token.clone(), &claims);
+                    token.revoked = denylist.is_revoked(&claims).await?;
+                    Some(token)
+                }
+                None => db.get_token_by_access_token(&msg.token).await?,
+            };
+            let Some(token) = found else {
+                if let Some(event_actor) = &event_actor {
+                    let event =
+                        AuthEvent::new(EventType::TokenExpired, EventSeverity::Warning, None, None)
+                            .with_metadata("reason", "unknown_token");
+                    event_actor.do_send(EmitEvent { event });
+                }
+                return Err(OAuth2Error::invalid_grant("Token not found"));
+            };
 
-            if !token.is_valid() {
+            if !token.is_valid(clock.as_ref()) {
                 // Emit expired/invalid event
                 if let Some(event_actor) = &event_actor {
                     let event = AuthEvent::new(
@@ -149,6 +728,8 @@ impl Handler<ValidateToken> for TokenActor {
                 return Err(OAuth2Error::invalid_grant("Token is expired or revoked"));
             }
 
+            usage.record_token(&token, clock.now());
+
             // Emit validated event
             if let Some(event_actor) = event_actor {
                 let event = AuthEvent::new(
@@ -174,27 +755,47 @@ pub struct RevokeToken {
 impl Handler<RevokeToken> for TokenActor {
     type Result = ResponseFuture<Result<(), OAuth2Error>>;
 
+    /// Revoke an access or refresh token and the rest of its pair. The
+    /// `token_revoked` event goes through the outbox, so resource servers
+    /// validating JWTs locally are sure to hear of it.
     fn handle(&mut self, msg: RevokeToken, _: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
+        let keys = self.keys.clone();
         let event_actor = self.event_actor.clone();
+        let clock = self.clock.clone();
+        let outbox = self.outbox.clone();
 
         Box::pin(async move {
-            // Get token info before revoking for event
-            let token_info = db.get_token_by_access_token(&msg.token).await?;
-
-            db.revoke_token(&msg.token).await?;
+            let token = match db.get_token_by_access_token(&msg.token).await? {
+                Some(token) => Some(token),
+                None => db.get_token_by_refresh_token(&msg.token).await?,
+            };
 
-            // Emit revoked event
-            if let Some(event_actor) = event_actor {
-                if let Some(token) = token_info {
-                    let event = AuthEvent::new(
+            // Revoking an unknown or already revoked token changes nothing
+            let event = token
+                .filter(|token| !token.revoked)
+                .zip(event_actor.as_ref())
+                .map(|(token, _)| {
+                    AuthEvent::new(
                         EventType::TokenRevoked,
                         EventSeverity::Info,
-                        Some(token.user_id),
-                        Some(token.client_id),
-                    );
-                    event_actor.do_send(EmitEvent { event });
-                }
+                        Some(token.user_id.clone()),
+                        Some(token.client_id.clone()),
+                    )
+                    .with_metadata("token_id", token.id.clone())
+                    .with_metadata("jti_sha256", token.jti_sha256(&keys))
+                    .with_metadata("expires_at", token.expires_at.to_rfc3339())
+                    .with_flow_id(token.flow_id.as_deref())
+                });
+            db.revoke_token(
+                &msg.token,
+                clock.now(),
+                event.as_ref().filter(|_| outbox.is_some()),
+            )
+            .await?;
+
+            if let Some(event) = event {
+                publish_recorded_event(outbox.as_deref(), event_actor.as_ref(), event);
             }
 
             Ok(())
@@ -0,0 +1,108 @@
+//! Operator commands run against the server's database.
+//!
+//! ```text
+//! oauth2ctl migrate-from <keycloak|hydra> <export.json> [--dry-run]
+//! oauth2ctl verify-audit-log
+//! ```
+//!
+//! The database is the one the server is configured with
+//! (`OAUTH2_DATABASE_URL`). The migration report, including the new client
+//! secrets and anything that could not be mapped, is printed as JSON.
+//!
+//! `verify-audit-log` checks the admin audit log's hash chain with the
+//! server's signing key (`OAUTH2_JWT_SECRET`), prints the result as JSON and
+//! exits with status 1 if the chain is broken.
+
+use actix::Actor;
+use rust_oauth2_server::actors::ClientActor;
+use rust_oauth2_server::config::Config;
+use rust_oauth2_server::db::Database;
+use rust_oauth2_server::models::AuditChain;
+use rust_oauth2_server::services::MigrationPlan;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+const USAGE: &str = "usage: oauth2ctl migrate-from <keycloak|hydra> <export.json> [--dry-run]
+       oauth2ctl verify-audit-log";
+
+#[actix_web::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| *arg != "--dry-run")
+        .collect();
+
+    match args.as_slice() {
+        ["migrate-from", source, path] => match migrate_from(source, path, dry_run).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("oauth2ctl: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        ["verify-audit-log"] => match verify_audit_log().await {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::FAILURE,
+            Err(e) => {
+                eprintln!("oauth2ctl: {}", e);
+                ExitCode::FAILURE
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+async fn migrate_from(source: &str, path: &str, dry_run: bool) -> Result<(), String> {
+    let export =
+        std::fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let plan = match source {
+        "keycloak" => MigrationPlan::from_keycloak(&export),
+        "hydra" => MigrationPlan::from_hydra(&export),
+        other => {
+            return Err(format!(
+                "unknown source {}; expected keycloak or hydra",
+                other
+            ))
+        }
+    }
+    .map_err(|e| format!("{} is not a {} export: {}", path, source, e))?;
+
+    let config = Config::default();
+    let db = Arc::new(
+        Database::new(&config.database)
+            .await
+            .map_err(|e| format!("cannot open the database: {}", e))?,
+    );
+    let client_actor = ClientActor::new(db.clone()).start();
+
+    let report = plan
+        .apply(&db, &client_actor, dry_run)
+        .await
+        .map_err(|e| e.to_string())?;
+    let report = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+    println!("{}", report);
+    Ok(())
+}
+
+/// Returns whether the chain is intact
+async fn verify_audit_log() -> Result<bool, String> {
+    let config = Config::default();
+    let db = Database::new(&config.database)
+        .await
+        .map_err(|e| format!("cannot open the database: {}", e))?;
+    let entries = db
+        .list_admin_audit_chain()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let verification = AuditChain::new(&config.jwt.secret).verify(&entries);
+    let report = serde_json::to_string_pretty(&verification).map_err(|e| e.to_string())?;
+    println!("{}", report);
+    Ok(verification.valid)
+}
@@ -0,0 +1,121 @@
+//! Cache invalidations broadcast between replicas.
+//!
+//! An in-process cache is private to its replica, so deleting an entry on the
+//! replica that handled a revocation leaves every other replica serving its
+//! own copy until it expires. [`CacheInvalidator::invalidate`] deletes the key
+//! locally and records it in the shared database's `cache_invalidations`
+//! table; each replica polls the table every `invalidation_poll_ms` and drops
+//! the keys it has not seen yet. Replicas sharing Redis see each other's
+//! deletions directly and leave the table alone.
+
+use super::SharedCache;
+use crate::clock::SharedClock;
+use crate::db::Database;
+use crate::handlers::token::introspection_key;
+use crate::middleware::client_cors_middleware::origin_cache_key;
+use crate::models::{OAuth2Error, Token};
+use crate::services::JtiDenylist;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long invalidations are kept for replicas to see. A replica that has
+/// been away longer than this starts again with an empty cache.
+const RETENTION: chrono::Duration = chrono::Duration::hours(1);
+
+pub struct CacheInvalidator {
+    db: Arc<Database>,
+    cache: SharedCache,
+    clock: SharedClock,
+    poll_interval: Option<Duration>,
+    /// Newest invalidation applied to this replica's cache
+    seen: AtomicI64,
+}
+
+impl CacheInvalidator {
+    /// Invalidations recorded before now cannot concern a cache that has only
+    /// just been opened, so polling starts after them. A `poll_ms` of 0, or a
+    /// shared cache, turns broadcasting off.
+    pub async fn new(
+        db: Arc<Database>,
+        cache: SharedCache,
+        clock: SharedClock,
+        poll_ms: u64,
+    ) -> Result<Self, OAuth2Error> {
+        let poll_interval =
+            (poll_ms > 0 && !cache.shared()).then(|| Duration::from_millis(poll_ms));
+        let seen = match poll_interval {
+            Some(_) => db.latest_cache_invalidation().await?,
+            None => 0,
+        };
+        Ok(Self {
+            db,
+            cache,
+            clock,
+            poll_interval,
+            seen: AtomicI64::new(seen),
+        })
+    }
+
+    /// How often to apply other replicas' invalidations, if they are broadcast
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval
+    }
+
+    /// Delete `key` from the cache on this replica and every other one.
+    /// Failures are logged; the entry still expires in its own time.
+    pub async fn invalidate(&self, key: &str) {
+        if let Err(e) = self.cache.delete(key).await {
+            tracing::warn!("Failed to delete cache entry {}: {}", key, e);
+        }
+        if self.poll_interval.is_some() {
+            if let Err(e) = self
+                .db
+                .record_cache_invalidation(key, self.clock.now())
+                .await
+            {
+                tracing::warn!("Failed to broadcast cache invalidation {}: {}", key, e);
+            }
+        }
+    }
+
+    /// Forget the cached introspection results and jti denylist answers of
+    /// tokens revoked or deleted in bulk
+    pub async fn forget_tokens(&self, tokens: &[Token]) {
+        for token in tokens {
+            self.invalidate(&introspection_key(&token.access_token))
+                .await;
+            if let Some(jti) = &token.jti {
+                self.invalidate(&JtiDenylist::cache_key(jti)).await;
+            }
+        }
+    }
+
+    /// Forget that `origins` belong to some client, once the client that
+    /// registered them has gone
+    pub async fn forget_origins(&self, origins: &[String]) {
+        for origin in origins {
+            self.invalidate(&origin_cache_key(origin)).await;
+        }
+    }
+
+    /// Delete the keys invalidated since the last run, returning how many
+    pub async fn apply(&self) -> Result<usize, OAuth2Error> {
+        let since = self.seen.load(Ordering::Acquire);
+        let invalidations = self.db.list_cache_invalidations(since).await?;
+        let Some((latest, _)) = invalidations.last() else {
+            return Ok(0);
+        };
+        for (_, key) in &invalidations {
+            if let Err(e) = self.cache.delete(key).await {
+                tracing::warn!("Failed to delete cache entry {}: {}", key, e);
+            }
+        }
+        self.seen.store(*latest, Ordering::Release);
+
+        self.db
+            .purge_cache_invalidations(self.clock.now() - RETENTION)
+            .await?;
+        Ok(invalidations.len())
+    }
+}
@@ -0,0 +1,225 @@
+//! Short-lived state shared between requests, and between replicas.
+//!
+//! Subsystems keep lookups worth not repeating and one-time markers in a
+//! [`Cache`] rather than a map of their own. A single node uses the
+//! in-process [`InMemoryCache`]; replicas set `OAUTH2_REDIS_URL` and share a
+//! [`RedisCache`], through the same code paths. The cache holds:
+//!
+//! - origins some client registered, for
+//!   [`ClientCors`](crate::middleware::ClientCors)
+//! - active introspection results, for no longer than resource servers are
+//!   told they may cache them
+//! - social login `state` values that have come back, so a callback cannot
+//!   be replayed
+//! - whether a JWT's `jti` is on the [denylist](crate::services::JtiDenylist),
+//!   in stateless validation mode
+//!
+//! Every entry expires. Losing the cache costs lookups, and lets a login
+//! `state` that has not yet expired be replayed once.
+//!
+//! Replicas that each keep an in-process cache hear of entries deleted
+//! elsewhere through the [`CacheInvalidator`].
+
+pub mod invalidation;
+pub mod redis;
+
+pub use invalidation::CacheInvalidator;
+pub use redis::RedisCache;
+
+use crate::clock::SharedClock;
+use crate::config::CacheConfig;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[async_trait]
+pub trait Cache: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>, String>;
+
+    async fn set(&self, key: &str, value: &str, ttl: Duration) -> Result<(), String>;
+
+    /// Set `key` unless it already holds a value. Returns false if it did.
+    async fn insert(&self, key: &str, value: &str, ttl: Duration) -> Result<bool, String>;
+
+    async fn delete(&self, key: &str) -> Result<(), String>;
+
+    /// Add one to a counter, which expires `ttl` after it was created, and
+    /// return the new count
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, String>;
+
+    fn name(&self) -> &str;
+
+    /// Whether every replica reads and writes the same entries. Deletions
+    /// from a cache that is not shared are broadcast to the other replicas.
+    fn shared(&self) -> bool {
+        false
+    }
+}
+
+/// Shared handle passed to middleware and the HTTP layer
+pub type SharedCache = Arc<dyn Cache>;
+
+/// Redis when `redis_url` is set, otherwise in process
+pub fn open_cache(config: &CacheConfig, clock: SharedClock) -> Result<SharedCache, String> {
+    match &config.redis_url {
+        Some(_) => Ok(Arc::new(RedisCache::new(config)?)),
+        None => Ok(Arc::new(InMemoryCache::new(clock))),
+    }
+}
+
+/// Writes between sweeps of expired entries
+const SWEEP_EVERY: usize = 1024;
+
+struct Entry {
+    value: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// A cache private to this process
+pub struct InMemoryCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    writes: AtomicUsize,
+    clock: SharedClock,
+}
+
+impl InMemoryCache {
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            writes: AtomicUsize::new(0),
+            clock,
+        }
+    }
+
+    fn expiry(&self, now: DateTime<Utc>, ttl: Duration) -> DateTime<Utc> {
+        chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| now.checked_add_signed(ttl))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    /// Lock the entries for a write, dropping expired ones now and then so
+    /// keys that are never read again do not pile up
+    fn entries_for_write(
+        &self,
+        now: DateTime<Utc>,
+    ) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        let mut entries = self.entries.lock().unwrap();
+        if self.writes.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == SWEEP_EVERY - 1 {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        entries
+    }
+}
+
+fn live<'a>(
+    entries: &'a mut HashMap<String, Entry>,
+    key: &str,
+    now: DateTime<Utc>,
+) -> Option<&'a mut Entry> {
+    if entries
+        .get(key)
+        .is_some_and(|entry| entry.expires_at <= now)
+    {
+        entries.remove(key);
+    }
+    entries.get_mut(key)
+}
+
+#[async_trait]
+impl Cache for InMemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<String>, String> {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        Ok(live(&mut entries, key, now).map(|entry| entry.value.clone()))
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Duration) -> Result<(), String> {
+        let now = self.clock.now();
+        let expires_at = self.expiry(now, ttl);
+        self.entries_for_write(now).insert(
+            key.to_string(),
+            Entry {
+                value: value.to_string(),
+                expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    async fn insert(&self, key: &str, value: &str, ttl: Duration) -> Result<bool, String> {
+        let now = self.clock.now();
+        let expires_at = self.expiry(now, ttl);
+        let mut entries = self.entries_for_write(now);
+        if live(&mut entries, key, now).is_some() {
+            return Ok(false);
+        }
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value: value.to_string(),
+                expires_at,
+            },
+        );
+        Ok(true)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, String> {
+        let now = self.clock.now();
+        let expires_at = self.expiry(now, ttl);
+        let mut entries = self.entries_for_write(now);
+        let entry = match live(&mut entries, key, now) {
+            Some(entry) => entry,
+            None => entries.entry(key.to_string()).or_insert(Entry {
+                value: "0".to_string(),
+                expires_at,
+            }),
+        };
+        let count = entry
+            .value
+            .parse::<u64>()
+            .map_err(|_| format!("{} is not a counter", key))?
+            + 1;
+        entry.value = count.to_string();
+        Ok(count)
+    }
+
+    fn name(&self) -> &str {
+        "in_memory"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[tokio::test]
+    async fn test_entries_expire() {
+        let clock = Arc::new(ManualClock::starting_now());
+        let cache = InMemoryCache::new(clock.clone());
+        let ttl = Duration::from_secs(60);
+
+        cache.set("a", "1", ttl).await.unwrap();
+        assert!(!cache.insert("a", "2", ttl).await.unwrap());
+        assert_eq!(cache.get("a").await.unwrap().as_deref(), Some("1"));
+        assert_eq!(cache.increment("n", ttl).await.unwrap(), 1);
+        assert_eq!(cache.increment("n", ttl).await.unwrap(), 2);
+
+        clock.advance(chrono::Duration::seconds(60));
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        assert!(cache.insert("a", "2", ttl).await.unwrap());
+        assert_eq!(cache.increment("n", ttl).await.unwrap(), 1);
+
+        cache.delete("a").await.unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), None);
+    }
+}
@@ -1,59 +1,2101 @@
-use serde::Deserialize;
+use crate::models::{TokenFormat, DEFAULT_ISSUER};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub mode: DeploymentMode,
+    #[serde(default)]
+    pub security_profile: SecurityProfile,
     pub server: ServerConfig,
     pub database: DatabaseConfig,
     pub jwt: JwtConfig,
     pub events: EventConfig,
+    #[serde(default)]
+    pub branding: BrandingConfig,
+    #[serde(default)]
+    pub login: LoginConfig,
+    #[serde(default)]
+    pub introspection: IntrospectionConfig,
+    #[serde(default)]
+    pub risk: RiskConfig,
+    #[serde(default)]
+    pub mfa: MfaConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub refresh_token: RefreshTokenConfig,
+    #[serde(default)]
+    pub grant_tokens: GrantTokenConfig,
+    #[serde(default)]
+    pub workload_identity: WorkloadIdentityConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    #[serde(default)]
+    pub anomaly: AnomalyConfig,
+    #[serde(default)]
+    pub limits: RequestLimitsConfig,
+    #[serde(default)]
+    pub token_concurrency: TokenConcurrencyConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub usage: UsageConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    #[serde(default)]
+    pub account_deletion: AccountDeletionConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub client_secrets: ClientSecretConfig,
+    #[serde(default)]
+    pub registration: RegistrationConfig,
+    #[serde(default)]
+    pub pkce: PkceConfig,
+    #[serde(default)]
+    pub impersonation: ImpersonationConfig,
+    #[serde(default)]
+    pub admin_access: AdminAccessConfig,
+    #[serde(default)]
+    pub log_sampling: LogSamplingConfig,
+    #[serde(default)]
+    pub upstream_tokens: UpstreamTokenConfig,
+    #[serde(default)]
+    pub profile_sync: ProfileSyncConfig,
+    #[serde(default)]
+    pub ldap: LdapConfig,
+    #[serde(default)]
+    pub saml: SamlConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
 }
 
+/// What the server is run for, from `OAUTH2_MODE`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeploymentMode {
+    /// Every secret must be configured and the server starts empty
+    #[default]
+    Production,
+    /// For trying the server out: canned users and clients are seeded,
+    /// consent is approved on the user's behalf, the playground at `/demo`
+    /// is served and insecure defaults stand in for unset secrets. Refuses
+    /// to start on a database that has been used in production.
+    Demo,
+}
+
+impl DeploymentMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "production" => Some(Self::Production),
+            "demo" => Some(Self::Demo),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Production => "production",
+            Self::Demo => "demo",
+        }
+    }
+
+    pub fn is_demo(&self) -> bool {
+        *self == Self::Demo
+    }
+}
+
+/// How much of OAuth 2.0 the server still accepts, from
+/// `OAUTH2_SECURITY_PROFILE`. Each profile accepts less than the one before;
+/// discovery advertises only what the profile accepts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityProfile {
+    /// Any `response_type` is answered with a code, as before profiles
+    /// existed
+    Legacy,
+    /// Only `response_type=code` is accepted
+    #[default]
+    Balanced,
+    /// Balanced, and in the spirit of OAuth 2.1: no password grant, no
+    /// public clients, and PKCE with `S256` on every authorization request
+    Strict,
+}
+
+impl SecurityProfile {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "legacy" => Some(Self::Legacy),
+            "balanced" => Some(Self::Balanced),
+            "strict" => Some(Self::Strict),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Legacy => "legacy",
+            Self::Balanced => "balanced",
+            Self::Strict => "strict",
+        }
+    }
+
+    pub fn is_strict(&self) -> bool {
+        *self == Self::Strict
+    }
+
+    /// Whether `response_type` is one the profile answers
+    pub fn accepts_response_type(&self, response_type: &str) -> bool {
+        *self == Self::Legacy || response_type == "code"
+    }
+
+    pub fn allows_password_grant(&self) -> bool {
+        !self.is_strict()
+    }
+
+    /// Whether clients without a secret may get tokens
+    pub fn allows_public_clients(&self) -> bool {
+        !self.is_strict()
+    }
+
+    /// Whether every authorization request must carry a PKCE challenge
+    pub fn requires_pkce(&self) -> bool {
+        self.is_strict()
+    }
+
+    /// PKCE methods accepted, strongest first
+    pub fn code_challenge_methods(&self) -> &'static [&'static str] {
+        if self.is_strict() {
+            &["S256"]
+        } else {
+            &["S256", "plain"]
+        }
+    }
+}
+
+/// JWT secret demo mode signs with when `OAUTH2_JWT_SECRET` is unset
+pub const DEMO_JWT_SECRET: &str = "demo-mode-jwt-secret-never-use-in-production";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Absolute base for `error_uri` links, e.g. `https://auth.example.com/errors`.
+    /// Links are relative (`/errors/...`) without one.
+    #[serde(default)]
+    pub error_reference_url: Option<String>,
+    /// HTTP worker threads; one per physical CPU when unset
+    #[serde(default)]
+    pub workers: Option<usize>,
+    /// Connections waiting to be accepted before new ones are refused
+    #[serde(default = "default_backlog")]
+    pub backlog: u32,
+    /// Seconds an idle keep-alive connection stays open; 0 closes each
+    /// connection after its response
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    /// Milliseconds a client has to send a request's headers; 0 waits
+    /// indefinitely
+    #[serde(default = "default_client_request_timeout_ms")]
+    pub client_request_timeout_ms: u64,
+    /// Also listen on this Unix domain socket, for a proxy on the same host
+    #[serde(default)]
+    pub unix_socket: Option<String>,
+    /// Address of an internal listener, e.g. `127.0.0.1:9090`. When set,
+    /// `/admin` and `/metrics` are served there and not on the public one.
+    #[serde(default)]
+    pub internal_addr: Option<String>,
+}
+
+fn default_backlog() -> u32 {
+    2048
+}
+
+fn default_keep_alive_secs() -> u64 {
+    5
+}
+
+fn default_client_request_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DatabaseConfig {
+    pub url: String,
+    /// Write-ahead logging, so reads never wait for a write to finish
+    #[serde(default = "default_database_wal")]
+    pub wal: bool,
+    /// Milliseconds a connection waits for a lock before failing with
+    /// `database is locked`
+    #[serde(default = "default_database_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+    /// Connections for reads. Writes always share a single connection.
+    #[serde(default = "default_database_max_connections")]
+    pub max_connections: u32,
+}
+
+fn default_database_wal() -> bool {
+    true
+}
+
+fn default_database_busy_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_database_max_connections() -> u32 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwtConfig {
+    pub secret: String,
+    /// Seconds of clock drift allowed when checking a token's `exp`, `nbf`
+    /// and `iat`
+    #[serde(default = "default_jwt_leeway")]
+    pub leeway_secs: u64,
+    /// Accept JWT access tokens on their signature, claims and the jti
+    /// denylist, without looking each one up
+    #[serde(default)]
+    pub stateless_validation: bool,
+    /// Seconds a jti found not to be revoked is remembered. A token revoked
+    /// through another replica may be accepted for this much longer.
+    #[serde(default = "default_jwt_denylist_cache")]
+    pub denylist_cache_secs: u64,
+    /// `HS256` signs with `secret`; `RS256` and `ES256` with a private key
+    /// whose public half is published as a JWKS
+    #[serde(default = "default_jwt_algorithm")]
+    pub algorithm: jsonwebtoken::Algorithm,
+    /// PEM private key for `RS256` and `ES256`, created if it does not
+    /// exist. Without one a key is generated on every start.
+    #[serde(default)]
+    pub private_key_file: Option<String>,
+    /// `iss` of every token, and the issuer in the discovery document: the
+    /// server's public URL
+    #[serde(default = "default_jwt_issuer")]
+    pub issuer: String,
+}
+
+fn default_jwt_issuer() -> String {
+    DEFAULT_ISSUER.to_string()
+}
+
+fn default_jwt_leeway() -> u64 {
+    60
+}
+
+fn default_jwt_denylist_cache() -> u64 {
+    5
+}
+
+fn default_jwt_algorithm() -> jsonwebtoken::Algorithm {
+    jsonwebtoken::Algorithm::HS256
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventConfig {
+    pub enabled: bool,
+    pub backend: String,
+    pub filter_mode: String,
+    pub event_types: Vec<String>,
+    /// Directory external backends buffer events in while they are
+    /// unhealthy; in memory when unset
+    #[serde(default)]
+    pub buffer_dir: Option<String>,
+    /// Events buffered per backend before new ones are dropped
+    #[serde(default = "default_event_buffer_max_events")]
+    pub buffer_max_events: usize,
+    /// Seconds between backend health checks
+    #[serde(default = "default_event_health_check_interval")]
+    pub health_check_interval_secs: u64,
+    /// Seconds between outbox relay runs, besides the one after each write
+    #[serde(default = "default_outbox_relay_interval")]
+    pub outbox_relay_interval_secs: u64,
+    /// Kafka to publish events to, through a plugin the embedder registers
+    #[serde(default)]
+    pub kafka: Option<KafkaEventConfig>,
+    /// Redis stream to append events to
+    #[serde(default)]
+    pub redis: Option<RedisEventConfig>,
+    /// URLs to POST events to
+    #[serde(default)]
+    pub webhook: Option<WebhookEventConfig>,
+}
+
+fn default_event_buffer_max_events() -> usize {
+    10_000
+}
+
+fn default_event_health_check_interval() -> u64 {
+    30
+}
+
+fn default_outbox_relay_interval() -> u64 {
+    5
+}
+
+/// A secret from the environment: the value of `var`, or else the contents
+/// of the file `{var}_FILE` names, as mounted by Docker and Kubernetes
+/// secrets
+pub fn env_secret(var: &str) -> Option<String> {
+    if let Some(value) = std::env::var(var).ok().filter(|v| !v.is_empty()) {
+        return Some(value);
+    }
+    let path = std::env::var(format!("{}_FILE", var))
+        .ok()
+        .filter(|v| !v.is_empty())?;
+    match std::fs::read_to_string(&path) {
+        Ok(value) => Some(value.trim().to_string()).filter(|v| !v.is_empty()),
+        Err(e) => {
+            tracing::warn!("Cannot read {}_FILE {}: {}", var, path, e);
+            None
+        }
+    }
+}
+
+fn env_list(var: &str) -> Vec<String> {
+    std::env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Acknowledgements a Kafka producer waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaAcks {
+    /// Fire and forget (`acks=0`)
+    None,
+    /// The partition leader has written it (`acks=1`)
+    Leader,
+    /// Every in-sync replica has it (`acks=all`)
+    #[default]
+    All,
+}
+
+impl KafkaAcks {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "0" | "none" => Some(Self::None),
+            "1" | "leader" => Some(Self::Leader),
+            "-1" | "all" => Some(Self::All),
+            _ => None,
+        }
+    }
+
+    /// The value of the producer's `acks` setting
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "0",
+            Self::Leader => "1",
+            Self::All => "all",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KafkaEventConfig {
+    /// Bootstrap servers, as `host:port`
+    pub brokers: Vec<String>,
+    pub topic: String,
+    #[serde(default)]
+    pub acks: KafkaAcks,
+}
+
+impl KafkaEventConfig {
+    /// Set when `OAUTH2_EVENTS_KAFKA_BROKERS` is
+    pub fn from_env() -> Option<Self> {
+        let brokers = env_list("OAUTH2_EVENTS_KAFKA_BROKERS");
+        if brokers.is_empty() {
+            return None;
+        }
+        Some(Self {
+            brokers,
+            topic: std::env::var("OAUTH2_EVENTS_KAFKA_TOPIC")
+                .unwrap_or_else(|_| "oauth2-events".to_string()),
+            acks: std::env::var("OAUTH2_EVENTS_KAFKA_ACKS")
+                .ok()
+                .and_then(|v| KafkaAcks::parse(&v))
+                .unwrap_or_default(),
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(broker) = self.brokers.iter().find(|broker| {
+            broker
+                .rsplit_once(':')
+                .is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err())
+        }) {
+            return Err(format!(
+                "OAUTH2_EVENTS_KAFKA_BROKERS: expected host:port, got {}",
+                broker
+            ));
+        }
+        if self.topic.trim().is_empty() {
+            return Err("OAUTH2_EVENTS_KAFKA_TOPIC must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisEventConfig {
+    /// `redis://[[user]:password@]host[:port][/db]`, or `rediss://` for TLS
+    pub url: String,
+    /// Stream events are appended to with `XADD`
+    pub stream: String,
+}
+
+impl RedisEventConfig {
+    /// Set when `OAUTH2_EVENTS_REDIS_URL`, or the file
+    /// `OAUTH2_EVENTS_REDIS_URL_FILE` names, is; the URL may carry a password
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            url: env_secret("OAUTH2_EVENTS_REDIS_URL")?,
+            stream: std::env::var("OAUTH2_EVENTS_REDIS_STREAM")
+                .unwrap_or_else(|_| "oauth2:events".to_string()),
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let url = oauth2::url::Url::parse(&self.url)
+            .map_err(|e| format!("OAUTH2_EVENTS_REDIS_URL: {}", e))?;
+        if !matches!(url.scheme(), "redis" | "rediss") {
+            return Err(format!(
+                "OAUTH2_EVENTS_REDIS_URL: expected redis:// or rediss://, got {}://",
+                url.scheme()
+            ));
+        }
+        if self.stream.trim().is_empty() {
+            return Err("OAUTH2_EVENTS_REDIS_STREAM must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Shortest webhook signing secret accepted
+const MIN_WEBHOOK_SECRET_LEN: usize = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookEventConfig {
+    pub urls: Vec<String>,
+    /// Key for the `X-Webhook-Signature` HMAC of each delivery; unsigned
+    /// when unset
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+impl WebhookEventConfig {
+    /// Set when `OAUTH2_EVENTS_WEBHOOK_URLS` is. The secret comes from
+    /// `OAUTH2_EVENTS_WEBHOOK_SECRET` or `OAUTH2_EVENTS_WEBHOOK_SECRET_FILE`.
+    pub fn from_env() -> Option<Self> {
+        let urls = env_list("OAUTH2_EVENTS_WEBHOOK_URLS");
+        if urls.is_empty() {
+            return None;
+        }
+        Some(Self {
+            urls,
+            secret: env_secret("OAUTH2_EVENTS_WEBHOOK_SECRET"),
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.urls.is_empty() {
+            return Err("OAUTH2_EVENTS_WEBHOOK_URLS must not be empty".to_string());
+        }
+        for url in &self.urls {
+            let parsed = oauth2::url::Url::parse(url)
+                .map_err(|e| format!("OAUTH2_EVENTS_WEBHOOK_URLS: {}: {}", url, e))?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(format!(
+                    "OAUTH2_EVENTS_WEBHOOK_URLS: {} is not an http(s) URL",
+                    url
+                ));
+            }
+        }
+        if self
+            .secret
+            .as_ref()
+            .is_some_and(|secret| secret.len() < MIN_WEBHOOK_SECRET_LEN)
+        {
+            return Err(format!(
+                "OAUTH2_EVENTS_WEBHOOK_SECRET must be at least {} characters long",
+                MIN_WEBHOOK_SECRET_LEN
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl EventConfig {
+    /// Check the connection settings of the configured external backends
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(kafka) = &self.kafka {
+            kafka.validate()?;
+        }
+        if let Some(redis) = &self.redis {
+            redis.validate()?;
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Sign-in methods offered on the login page besides social providers
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoginConfig {
+    /// Show the username/password form
+    pub local_login_enabled: bool,
+    /// Seconds a social login has to come back from the provider before its
+    /// `state` is no longer accepted
+    pub state_ttl_secs: u64,
+    /// URL prefixes, besides the origins of registered client redirect URIs,
+    /// that users may be sent on to after signing in
+    pub return_to_allowlist: Vec<String>,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            local_login_enabled: true,
+            state_ttl_secs: 600,
+            return_to_allowlist: Vec::new(),
+        }
+    }
+}
+
+impl LoginConfig {
+    pub fn from_env() -> Self {
+        Self {
+            local_login_enabled: std::env::var("OAUTH2_LOCAL_LOGIN_ENABLED")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(true),
+            state_ttl_secs: std::env::var("OAUTH2_LOGIN_STATE_TTL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(600),
+            return_to_allowlist: std::env::var("OAUTH2_RETURN_TO_ALLOWLIST")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        }
+    }
+}
+
+/// How long resource servers may cache introspection results for active tokens.
+/// A max-age of 0 sends `Cache-Control: no-store`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct IntrospectionConfig {
+    pub cache_max_age: u64,
+    /// Overrides keyed by the `client_id` the token was issued to
+    pub client_cache_max_age: HashMap<String, u64>,
+}
+
+impl IntrospectionConfig {
+    pub fn from_env() -> Self {
+        Self {
+            cache_max_age: std::env::var("OAUTH2_INTROSPECTION_CACHE_MAX_AGE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            client_cache_max_age: std::env::var("OAUTH2_INTROSPECTION_CLIENT_CACHE_MAX_AGE")
+                .map(|v| parse_client_max_ages(&v))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Seconds a result for a token issued to `client_id` may be cached
+    pub fn max_age_for(&self, client_id: &str) -> u64 {
+        self.client_cache_max_age
+            .get(client_id)
+            .copied()
+            .unwrap_or(self.cache_max_age)
+    }
+}
+
+/// Parse `client_a=30,client_b=0` into per-client max-ages, skipping malformed entries
+fn parse_client_max_ages(value: &str) -> HashMap<String, u64> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (client_id, max_age) = entry.split_once('=')?;
+            let client_id = client_id.trim();
+            if client_id.is_empty() {
+                return None;
+            }
+            Some((client_id.to_string(), max_age.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Refresh token expiry, in seconds. Refresh tokens rotate on every use and
+/// each new one gets a fresh `lifetime`, so active clients keep a chain alive
+/// until `max_lifetime` after the original grant. 0 turns off `max_lifetime`
+/// and `idle_timeout`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RefreshTokenConfig {
+    pub lifetime: u64,
+    pub max_lifetime: u64,
+    /// Reject a refresh token whose access token and itself have gone unused
+    /// for this long
+    pub idle_timeout: u64,
+}
+
+impl Default for RefreshTokenConfig {
+    fn default() -> Self {
+        Self {
+            lifetime: 2592000,
+            max_lifetime: 7776000,
+            idle_timeout: 0,
+        }
+    }
+}
+
+impl RefreshTokenConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let seconds = |var: &str, default: u64| {
+            std::env::var(var)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+
+        Self {
+            lifetime: seconds("OAUTH2_REFRESH_TOKEN_EXPIRATION", defaults.lifetime),
+            max_lifetime: seconds("OAUTH2_REFRESH_TOKEN_MAX_LIFETIME", defaults.max_lifetime),
+            idle_timeout: seconds("OAUTH2_REFRESH_TOKEN_IDLE_TIMEOUT", defaults.idle_timeout),
+        }
+    }
+}
+
+/// Access token format and lifetimes by grant type, e.g. short opaque tokens
+/// for the password grant and JWTs for `client_credentials`. Grants without
+/// an entry get tokens in `format` for `access_token_lifetime` and refresh
+/// tokens per [`RefreshTokenConfig`]. A resource's own token format wins
+/// over a client's, and a client's over the grant's. Refreshed tokens follow
+/// the `refresh_token` entries.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GrantTokenConfig {
+    /// Access token lifetime in seconds for grants without their own
+    pub access_token_lifetime: u64,
+    /// Access token format for grants without their own
+    pub format: TokenFormat,
+    pub formats: HashMap<String, TokenFormat>,
+    pub access_token_lifetimes: HashMap<String, u64>,
+    /// Refresh token lifetimes in seconds; 0 issues no refresh token under
+    /// that grant
+    pub refresh_token_lifetimes: HashMap<String, u64>,
+}
+
+impl Default for GrantTokenConfig {
+    fn default() -> Self {
+        Self {
+            access_token_lifetime: 3600,
+            format: TokenFormat::Jwt,
+            formats: HashMap::new(),
+            access_token_lifetimes: HashMap::new(),
+            refresh_token_lifetimes: HashMap::new(),
+        }
+    }
+}
+
+impl GrantTokenConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let value = |var: &str| std::env::var(var).ok().filter(|v| !v.is_empty());
+        let lifetimes = |var: &str| {
+            value(var).map_or_else(HashMap::new, |v| parse_grant_values(&v, str::parse))
+        };
+
+        Self {
+            access_token_lifetime: value("OAUTH2_ACCESS_TOKEN_EXPIRATION")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.access_token_lifetime),
+            format: value("OAUTH2_TOKEN_FORMAT")
+                .and_then(|v| TokenFormat::parse(&v))
+                .unwrap_or(defaults.format),
+            formats: value("OAUTH2_GRANT_TOKEN_FORMATS").map_or_else(HashMap::new, |v| {
+                parse_grant_values(&v, |format| TokenFormat::parse(format).ok_or(()))
+            }),
+            access_token_lifetimes: lifetimes("OAUTH2_GRANT_ACCESS_TOKEN_LIFETIMES"),
+            refresh_token_lifetimes: lifetimes("OAUTH2_GRANT_REFRESH_TOKEN_LIFETIMES"),
+        }
+    }
+
+    pub fn format_for(&self, grant_type: &str) -> TokenFormat {
+        self.formats.get(grant_type).copied().unwrap_or(self.format)
+    }
+
+    pub fn access_token_lifetime_for(&self, grant_type: &str) -> u64 {
+        self.access_token_lifetimes
+            .get(grant_type)
+            .copied()
+            .unwrap_or(self.access_token_lifetime)
+    }
+
+    /// The grant's refresh token lifetime, if it overrides the usual one
+    pub fn refresh_token_lifetime_for(&self, grant_type: &str) -> Option<u64> {
+        self.refresh_token_lifetimes.get(grant_type).copied()
+    }
+}
+
+/// `grant=value` pairs separated by commas, skipping entries that do not parse
+fn parse_grant_values<T, E>(
+    value: &str,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> HashMap<String, T> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (grant_type, value) = entry.split_once('=')?;
+            let grant_type = grant_type.trim();
+            if grant_type.is_empty() {
+                return None;
+            }
+            Some((grant_type.to_string(), parse(value.trim()).ok()?))
+        })
+        .collect()
+}
+
+/// Client IP resolution, geolocation and suspicious-login detection
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RiskConfig {
+    /// Take the client IP from `X-Forwarded-For`/`Forwarded`. Only enable
+    /// behind a proxy that overwrites these headers.
+    pub trust_forwarded_for: bool,
+    /// CSV network table or, with the `geoip` feature, a MaxMind `.mmdb` file
+    pub geoip_database: Option<String>,
+    /// Consecutive logins further apart than this speed allows are flagged
+    pub max_travel_speed_kmh: f64,
+    /// Hold flagged logins for step-up authentication instead of completing them
+    pub step_up_on_suspicious_login: bool,
+}
+
+impl Default for RiskConfig {
+    fn default() -> Self {
+        Self {
+            trust_forwarded_for: false,
+            geoip_database: None,
+            // Roughly airliner cruising speed
+            max_travel_speed_kmh: 1000.0,
+            step_up_on_suspicious_login: false,
+        }
+    }
+}
+
+impl RiskConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let flag = |var: &str| {
+            std::env::var(var)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false)
+        };
+
+        Self {
+            trust_forwarded_for: flag("OAUTH2_TRUST_FORWARDED_FOR"),
+            geoip_database: std::env::var("OAUTH2_GEOIP_DATABASE")
+                .ok()
+                .filter(|v| !v.is_empty()),
+            max_travel_speed_kmh: std::env::var("OAUTH2_MAX_TRAVEL_SPEED_KMH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_travel_speed_kmh),
+            step_up_on_suspicious_login: flag("OAUTH2_STEP_UP_ON_SUSPICIOUS_LOGIN"),
+        }
+    }
+}
+
+/// Server-side limits on signed-in sessions, in seconds. 0 turns a limit off.
+/// Also the attributes of the session cookie.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Sign out after this long without a request
+    pub idle_timeout: u64,
+    /// Sign out this long after login, however active the session is
+    pub max_lifetime: u64,
+    pub cookie_name: String,
+    /// Only send the cookie over HTTPS
+    pub cookie_secure: bool,
+    pub cookie_same_site: CookieSameSite,
+    /// Share the cookie with subdomains of this domain. Unset, it is only
+    /// sent back to the host that set it.
+    pub cookie_domain: Option<String>,
+    pub cookie_path: String,
+}
+
+/// The `SameSite` attribute of a cookie
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CookieSameSite {
+    Strict,
+    Lax,
+    /// Sent on cross-site requests too; browsers require `Secure` with it
+    None,
+}
+
+impl CookieSameSite {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "strict" => Some(Self::Strict),
+            "lax" => Some(Self::Lax),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: 3600,
+            max_lifetime: 86400,
+            cookie_name: "id".to_string(),
+            cookie_secure: true,
+            cookie_same_site: CookieSameSite::Lax,
+            cookie_domain: None,
+            cookie_path: "/".to_string(),
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let seconds = |var: &str, default: u64| {
+            std::env::var(var)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+
+        Self {
+            idle_timeout: seconds("OAUTH2_SESSION_TIMEOUT", defaults.idle_timeout),
+            max_lifetime: seconds("OAUTH2_SESSION_MAX_LIFETIME", defaults.max_lifetime),
+            cookie_name: std::env::var("OAUTH2_SESSION_COOKIE_NAME")
+                .ok()
+                .filter(|name| !name.is_empty())
+                .unwrap_or(defaults.cookie_name),
+            cookie_secure: std::env::var("OAUTH2_SESSION_SECURE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.cookie_secure),
+            cookie_same_site: std::env::var("OAUTH2_SESSION_COOKIE_SAME_SITE")
+                .ok()
+                .and_then(|v| CookieSameSite::parse(&v))
+                .unwrap_or(defaults.cookie_same_site),
+            cookie_domain: std::env::var("OAUTH2_SESSION_COOKIE_DOMAIN")
+                .ok()
+                .filter(|domain| !domain.is_empty()),
+            cookie_path: std::env::var("OAUTH2_SESSION_COOKIE_PATH")
+                .ok()
+                .filter(|path| path.starts_with('/'))
+                .unwrap_or(defaults.cookie_path),
+        }
+    }
+}
+
+/// Second-factor settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MfaConfig {
+    /// Days a browser skips the TOTP challenge after the user ticks
+    /// "remember this device". 0 removes the option.
+    pub trusted_device_days: u32,
+}
+
+impl Default for MfaConfig {
+    fn default() -> Self {
+        Self {
+            trusted_device_days: 30,
+        }
+    }
+}
+
+impl MfaConfig {
+    pub fn from_env() -> Self {
+        Self {
+            trusted_device_days: std::env::var("OAUTH2_TRUSTED_DEVICE_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(Self::default().trusted_device_days),
+        }
+    }
+}
+
+/// Client authentication with workload identity tokens instead of secrets
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WorkloadIdentityConfig {
+    /// JSON file listing the trusted issuers, their keys and which workload
+    /// subjects act as which clients. Federation is off without one.
+    pub trust_bundle: Option<String>,
+}
+
+impl WorkloadIdentityConfig {
+    pub fn from_env() -> Self {
+        Self {
+            trust_bundle: std::env::var("OAUTH2_WORKLOAD_TRUST_BUNDLE")
+                .ok()
+                .filter(|v| !v.is_empty()),
+        }
+    }
+}
+
+/// External or embedded authorization policy checked before issuing codes
+/// and tokens. Everything is permitted when neither source is set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// OPA decision endpoint, e.g. `http://opa:8181/v1/data/oauth2/decision`
+    pub opa_url: Option<String>,
+    /// JSON rule file evaluated in-process, used when there is no OPA endpoint
+    pub rules_file: Option<String>,
+    /// Permit requests when the policy engine cannot be reached
+    pub fail_open: bool,
+    /// How long to wait for the OPA endpoint
+    pub timeout_ms: u64,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            opa_url: None,
+            rules_file: None,
+            fail_open: false,
+            timeout_ms: 500,
+        }
+    }
+}
+
+impl PolicyConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let path = |var: &str| std::env::var(var).ok().filter(|v| !v.is_empty());
+
+        Self {
+            opa_url: path("OAUTH2_POLICY_OPA_URL"),
+            rules_file: path("OAUTH2_POLICY_FILE"),
+            fail_open: std::env::var("OAUTH2_POLICY_FAIL_OPEN")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.fail_open),
+            timeout_ms: std::env::var("OAUTH2_POLICY_TIMEOUT_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.timeout_ms),
+        }
+    }
+}
+
+/// Thresholds for the token-abuse signals derived from events
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    /// Length in seconds of the window the signals are computed over
+    pub window_secs: u64,
+    /// Observations a signal needs in the window before it can be anomalous
+    pub min_samples: u64,
+    /// Share of a client's authentications that failed
+    pub failed_auth_ratio: f64,
+    /// Share of introspected tokens that were not active
+    pub introspection_miss_rate: f64,
+    /// Revocations in the window over the per-window baseline
+    pub revocation_spike_factor: f64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 300,
+            min_samples: 20,
+            failed_auth_ratio: 0.5,
+            introspection_miss_rate: 0.5,
+            revocation_spike_factor: 5.0,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            window_secs: var("OAUTH2_ANOMALY_WINDOW_SECS", defaults.window_secs),
+            min_samples: var("OAUTH2_ANOMALY_MIN_SAMPLES", defaults.min_samples),
+            failed_auth_ratio: var(
+                "OAUTH2_ANOMALY_FAILED_AUTH_RATIO",
+                defaults.failed_auth_ratio,
+            ),
+            introspection_miss_rate: var(
+                "OAUTH2_ANOMALY_INTROSPECTION_MISS_RATE",
+                defaults.introspection_miss_rate,
+            ),
+            revocation_spike_factor: var(
+                "OAUTH2_ANOMALY_REVOCATION_SPIKE_FACTOR",
+                defaults.revocation_spike_factor,
+            ),
+        }
+    }
+}
+
+/// Size limits and checks applied to request bodies and OAuth2 parameters
+/// before they reach the handlers
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RequestLimitsConfig {
+    /// Largest form or JSON body accepted, in bytes
+    pub max_body_bytes: usize,
+    pub max_client_id_len: usize,
+    pub max_scope_len: usize,
+    pub max_state_len: usize,
+    pub max_redirect_uri_len: usize,
+    /// Check JSON and form bodies against the OpenAPI schemas before they
+    /// reach the handlers
+    pub validate_schemas: bool,
+}
+
+impl Default for RequestLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 64 * 1024,
+            max_client_id_len: 128,
+            max_scope_len: 1024,
+            max_state_len: 512,
+            max_redirect_uri_len: 2048,
+            validate_schemas: false,
+        }
+    }
+}
+
+impl RequestLimitsConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        fn var(name: &str, default: usize) -> usize {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            max_body_bytes: var("OAUTH2_MAX_BODY_BYTES", defaults.max_body_bytes),
+            max_client_id_len: var("OAUTH2_MAX_CLIENT_ID_LEN", defaults.max_client_id_len),
+            max_scope_len: var("OAUTH2_MAX_SCOPE_LEN", defaults.max_scope_len),
+            max_state_len: var("OAUTH2_MAX_STATE_LEN", defaults.max_state_len),
+            max_redirect_uri_len: var("OAUTH2_MAX_REDIRECT_URI_LEN", defaults.max_redirect_uri_len),
+            validate_schemas: std::env::var("OAUTH2_VALIDATE_SCHEMAS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.validate_schemas),
+        }
+    }
+}
+
+/// How many token requests are served at once. Requests beyond the limits
+/// wait for a slot, and are shed with a 503 once the queue is full or they
+/// have waited `queue_timeout_ms`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TokenConcurrencyConfig {
+    /// Token requests in flight across all clients; 0 for no limit
+    pub max_in_flight: usize,
+    /// Token requests one client may have in flight or queued, so a single
+    /// client cannot take every slot; 0 for no limit
+    pub max_in_flight_per_client: usize,
+    /// Requests waiting for a slot before new ones are shed straight away
+    pub max_queued: usize,
+    /// How long a request may wait for a slot, in milliseconds
+    pub queue_timeout_ms: u64,
+    /// `Retry-After` sent with a shed request, in seconds
+    pub retry_after_secs: u64,
+}
+
+impl Default for TokenConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 256,
+            max_in_flight_per_client: 32,
+            max_queued: 1024,
+            queue_timeout_ms: 2000,
+            retry_after_secs: 1,
+        }
+    }
+}
+
+impl TokenConcurrencyConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            max_in_flight: var("OAUTH2_TOKEN_MAX_IN_FLIGHT", defaults.max_in_flight),
+            max_in_flight_per_client: var(
+                "OAUTH2_TOKEN_MAX_IN_FLIGHT_PER_CLIENT",
+                defaults.max_in_flight_per_client,
+            ),
+            max_queued: var("OAUTH2_TOKEN_MAX_QUEUED", defaults.max_queued),
+            queue_timeout_ms: var("OAUTH2_TOKEN_QUEUE_TIMEOUT_MS", defaults.queue_timeout_ms),
+            retry_after_secs: var("OAUTH2_TOKEN_RETRY_AFTER_SECS", defaults.retry_after_secs),
+        }
+    }
+}
+
+/// Requests allowed per window. Past `soft_limit_percent` of a limit,
+/// responses carry `X-RateLimit-*` headers and a warning event is emitted;
+/// past the limit itself, requests are refused with a 429 until the window
+/// ends.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Length of a window, in seconds
+    pub window_secs: u64,
+    /// Authorization requests per IP address
+    pub authorize_limit: u64,
+    /// Token requests per client
+    pub token_limit: u64,
+    /// Introspection requests per client
+    pub introspect_limit: u64,
+    /// Requests to any other endpoint per IP address
+    pub default_limit: u64,
+    /// Share of a limit, in percent, from which callers are warned
+    pub soft_limit_percent: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_secs: 60,
+            authorize_limit: 10,
+            token_limit: 20,
+            introspect_limit: 100,
+            default_limit: 100,
+            soft_limit_percent: 80,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            enabled: var("OAUTH2_RATE_LIMIT_ENABLED", defaults.enabled),
+            window_secs: var("OAUTH2_RATE_LIMIT_WINDOW_SECS", defaults.window_secs).max(1),
+            authorize_limit: var("OAUTH2_RATE_LIMIT_AUTHORIZE", defaults.authorize_limit),
+            token_limit: var("OAUTH2_RATE_LIMIT_TOKEN", defaults.token_limit),
+            introspect_limit: var("OAUTH2_RATE_LIMIT_INTROSPECT", defaults.introspect_limit),
+            default_limit: var("OAUTH2_RATE_LIMIT_DEFAULT", defaults.default_limit),
+            soft_limit_percent: var(
+                "OAUTH2_RATE_LIMIT_SOFT_PERCENT",
+                defaults.soft_limit_percent,
+            )
+            .min(100),
+        }
+    }
+}
+
+/// Token and client usage tracking
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UsageConfig {
+    /// How often buffered last-used times and counters are written, in seconds
+    pub flush_interval_secs: u64,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval_secs: 30,
+        }
+    }
+}
+
+impl UsageConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            flush_interval_secs: std::env::var("OAUTH2_USAGE_FLUSH_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.flush_interval_secs),
+        }
+    }
+}
+
+/// Dashboard statistics rolled up from events
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatsConfig {
+    /// How often counted events are rolled into the stats tables, in seconds
+    pub rollup_interval_secs: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self {
+            rollup_interval_secs: 60,
+        }
+    }
+}
+
+impl StatsConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            rollup_interval_secs: std::env::var("OAUTH2_STATS_ROLLUP_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.rollup_interval_secs),
+        }
+    }
+}
+
+/// Self-service and admin account deletion
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AccountDeletionConfig {
+    /// Days an account stays disabled, and the deletion can be cancelled,
+    /// before it is erased
+    pub grace_period_days: u32,
+}
+
+impl Default for AccountDeletionConfig {
+    fn default() -> Self {
+        Self {
+            grace_period_days: 30,
+        }
+    }
+}
+
+impl AccountDeletionConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            grace_period_days: std::env::var("OAUTH2_ACCOUNT_DELETION_GRACE_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.grace_period_days),
+        }
+    }
+}
+
+/// Admin impersonation tokens
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ImpersonationConfig {
+    /// Longest an impersonation token lives, in seconds; requests for longer
+    /// are cut down to this
+    pub max_ttl_secs: u64,
+    /// Principals, as `client:<id>` or `user:<subject>`, whose bearer tokens
+    /// may impersonate. The `admin:impersonate` scope alone is not enough;
+    /// signed-in full admins need not be listed.
+    pub admins: Vec<String>,
+}
+
+impl Default for ImpersonationConfig {
+    fn default() -> Self {
+        Self {
+            max_ttl_secs: 900,
+            admins: Vec::new(),
+        }
+    }
+}
+
+impl ImpersonationConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            max_ttl_secs: std::env::var("OAUTH2_IMPERSONATION_MAX_TTL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_ttl_secs),
+            admins: std::env::var("OAUTH2_IMPERSONATION_ADMINS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        }
+    }
+}
+
+/// Who may use the admin API. Without `enforce_roles` it is open to anyone
+/// who can reach it, so keep it on an internal listener.
 #[derive(Debug, Clone, Deserialize)]
-pub struct ServerConfig {
-    pub host: String,
-    pub port: u16,
+#[serde(default)]
+pub struct AdminAccessConfig {
+    /// Require a read-only or full admin role for every `/admin/api` request
+    pub enforce_roles: bool,
+    /// Bearer token scope granting read-only access: listing, viewing and
+    /// exporting
+    pub read_scope: String,
+    /// Bearer token scope granting full access, including revoking, deleting
+    /// and rotating
+    pub write_scope: String,
+    /// Subjects of signed-in users given read-only access
+    pub readers: Vec<String>,
+    /// Subjects of signed-in users given full access
+    pub admins: Vec<String>,
+}
+
+impl AdminAccessConfig {
+    /// Whether `scope` grants admin access, so only an admin may give it to
+    /// a client: `admin`, `admin:*` and the configured role scopes
+    pub fn reserves_scope(&self, scope: &str) -> bool {
+        crate::models::scope::is_admin_scope(scope)
+            || scope == self.read_scope
+            || scope == self.write_scope
+    }
+}
+
+impl Default for AdminAccessConfig {
+    fn default() -> Self {
+        Self {
+            enforce_roles: false,
+            read_scope: "admin:read".to_string(),
+            write_scope: "admin:write".to_string(),
+            readers: Vec::new(),
+            admins: Vec::new(),
+        }
+    }
+}
+
+impl AdminAccessConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        fn subjects(name: &str) -> Vec<String> {
+            std::env::var(name)
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        }
+
+        Self {
+            enforce_roles: std::env::var("OAUTH2_ADMIN_ENFORCE_ROLES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.enforce_roles),
+            read_scope: std::env::var("OAUTH2_ADMIN_READ_SCOPE")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or(defaults.read_scope),
+            write_scope: std::env::var("OAUTH2_ADMIN_WRITE_SCOPE")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or(defaults.write_scope),
+            readers: subjects("OAUTH2_ADMIN_READERS"),
+            admins: subjects("OAUTH2_ADMINS"),
+        }
+    }
 }
 
+/// Which share of access log lines and logged events are written. Routes
+/// and event types without a rate are always logged.
 #[derive(Debug, Clone, Deserialize)]
-pub struct DatabaseConfig {
-    pub url: String,
+#[serde(default)]
+pub struct LogSamplingConfig {
+    /// Fraction, 0.0 to 1.0, of requests logged, keyed by exact path
+    pub routes: HashMap<String, f64>,
+    /// Fraction, 0.0 to 1.0, of events the console logger writes, keyed by
+    /// event type
+    pub event_types: HashMap<String, f64>,
+    /// Longest verbose logging can be turned on for a client, in seconds
+    pub max_debug_ttl_secs: u64,
+}
+
+impl Default for LogSamplingConfig {
+    fn default() -> Self {
+        Self {
+            routes: HashMap::new(),
+            event_types: HashMap::new(),
+            max_debug_ttl_secs: 3600,
+        }
+    }
+}
+
+impl LogSamplingConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            routes: std::env::var("OAUTH2_LOG_SAMPLE_ROUTES")
+                .map(|v| parse_sample_rates(&v))
+                .unwrap_or_default(),
+            event_types: std::env::var("OAUTH2_LOG_SAMPLE_EVENTS")
+                .map(|v| parse_sample_rates(&v))
+                .unwrap_or_default(),
+            max_debug_ttl_secs: std::env::var("OAUTH2_LOG_DEBUG_MAX_TTL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_debug_ttl_secs),
+        }
+    }
+}
+
+/// Parse `/oauth/introspect=0.01,token_validated=0` into sample rates,
+/// clamped to 0.0..=1.0, skipping malformed entries
+fn parse_sample_rates(value: &str) -> HashMap<String, f64> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (key, rate) = entry.split_once('=')?;
+            let key = key.trim();
+            let rate: f64 = rate.trim().parse().ok()?;
+            if key.is_empty() || rate.is_nan() {
+                return None;
+            }
+            Some((key.to_string(), rate.clamp(0.0, 1.0)))
+        })
+        .collect()
+}
+
+/// Storage of social login providers' tokens for users who connect a provider
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UpstreamTokenConfig {
+    /// Base64 of the 32-byte AES-256-GCM key the tokens are encrypted with.
+    /// Without one no provider tokens are stored.
+    pub encryption_key: Option<String>,
+}
+
+impl UpstreamTokenConfig {
+    pub fn from_env() -> Self {
+        Self {
+            encryption_key: std::env::var("OAUTH2_UPSTREAM_TOKEN_KEY")
+                .ok()
+                .filter(|v| !v.is_empty()),
+        }
+    }
+
+    /// The decoded key, if one is set and is 32 bytes
+    pub fn key(&self) -> Option<[u8; 32]> {
+        use base64::{engine::general_purpose, Engine as _};
+
+        let key = general_purpose::STANDARD
+            .decode(self.encryption_key.as_deref()?.trim())
+            .ok()?;
+        key.try_into().ok()
+    }
 }
 
+/// Re-reading connected users' profiles from their provider
 #[derive(Debug, Clone, Deserialize)]
-pub struct JwtConfig {
-    pub secret: String,
+#[serde(default)]
+pub struct ProfileSyncConfig {
+    /// How often each connected profile is re-read, in seconds; 0 turns the
+    /// job off
+    pub interval_secs: u64,
+    /// Most profiles re-read per run; the rest wait for the next one
+    pub batch_size: u32,
+}
+
+impl Default for ProfileSyncConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 86400,
+            batch_size: 100,
+        }
+    }
+}
+
+impl ProfileSyncConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            interval_secs: std::env::var("OAUTH2_PROFILE_SYNC_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.interval_secs),
+            batch_size: std::env::var("OAUTH2_PROFILE_SYNC_BATCH_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.batch_size),
+        }
+    }
 }
 
+/// LDAP or Active Directory server users can sign in against with their
+/// directory password. Not used unless `url` is set.
 #[derive(Debug, Clone, Deserialize)]
-pub struct EventConfig {
-    pub enabled: bool,
-    pub backend: String,
-    pub filter_mode: String,
-    pub event_types: Vec<String>,
+#[serde(default)]
+pub struct LdapConfig {
+    /// `ldap://host:389` or `ldaps://host:636`
+    pub url: Option<String>,
+    /// Account the user search binds as; anonymous when unset
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    /// Where users are searched for, e.g. `ou=people,dc=example,dc=com`
+    pub base_dn: String,
+    /// Attribute the username is matched against: `uid`, or
+    /// `sAMAccountName` on Active Directory
+    pub user_attribute: String,
+    pub email_attribute: String,
+    pub name_attribute: String,
+    /// Attribute listing the DNs of the user's groups
+    pub group_attribute: String,
+    /// Roles granted to members of a group, as (group DN, role) pairs
+    pub group_roles: Vec<(String, String)>,
+    /// How long a sign-in may take, connecting and all
+    pub timeout_ms: u64,
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            bind_dn: None,
+            bind_password: None,
+            base_dn: String::new(),
+            user_attribute: "uid".to_string(),
+            email_attribute: "mail".to_string(),
+            name_attribute: "cn".to_string(),
+            group_attribute: "memberOf".to_string(),
+            group_roles: Vec::new(),
+            timeout_ms: 5000,
+        }
+    }
+}
+
+impl LdapConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let value = |var: &str| std::env::var(var).ok().filter(|v| !v.is_empty());
+
+        Self {
+            url: value("OAUTH2_LDAP_URL"),
+            bind_dn: value("OAUTH2_LDAP_BIND_DN"),
+            bind_password: value("OAUTH2_LDAP_BIND_PASSWORD"),
+            base_dn: value("OAUTH2_LDAP_BASE_DN").unwrap_or(defaults.base_dn),
+            user_attribute: value("OAUTH2_LDAP_USER_ATTRIBUTE").unwrap_or(defaults.user_attribute),
+            email_attribute: value("OAUTH2_LDAP_EMAIL_ATTRIBUTE")
+                .unwrap_or(defaults.email_attribute),
+            name_attribute: value("OAUTH2_LDAP_NAME_ATTRIBUTE").unwrap_or(defaults.name_attribute),
+            group_attribute: value("OAUTH2_LDAP_GROUP_ATTRIBUTE")
+                .unwrap_or(defaults.group_attribute),
+            group_roles: value("OAUTH2_LDAP_GROUP_ROLES")
+                .map(|v| parse_group_roles(&v))
+                .unwrap_or_default(),
+            timeout_ms: value("OAUTH2_LDAP_TIMEOUT_MS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.timeout_ms),
+        }
+    }
+}
+
+/// Where short-lived shared state lives: in process unless `redis_url` is
+/// set. Replicas need Redis to see each other's entries; without it, only
+/// deletions reach the other replicas.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// `redis://[[user]:password@]host[:port][/db]`, or `rediss://` for TLS
+    pub redis_url: Option<String>,
+    /// Prepended to every key, so deployments can share a Redis server
+    pub key_prefix: String,
+    /// How long a Redis command may take, connecting and all
+    pub timeout_ms: u64,
+    /// Seconds an allowed CORS origin is remembered. A deleted client's
+    /// origins stay allowed this long.
+    pub origin_ttl_secs: u64,
+    /// How often a replica without Redis applies entries other replicas
+    /// deleted, such as introspection results for revoked tokens; 0 turns
+    /// broadcasting off for a single node
+    pub invalidation_poll_ms: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            redis_url: None,
+            key_prefix: "oauth2:".to_string(),
+            timeout_ms: 1000,
+            origin_ttl_secs: 60,
+            invalidation_poll_ms: 1000,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let value = |var: &str| std::env::var(var).ok().filter(|v| !v.is_empty());
+
+        Self {
+            redis_url: value("OAUTH2_REDIS_URL"),
+            key_prefix: value("OAUTH2_CACHE_KEY_PREFIX").unwrap_or(defaults.key_prefix),
+            timeout_ms: value("OAUTH2_CACHE_TIMEOUT_MS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.timeout_ms),
+            origin_ttl_secs: value("OAUTH2_CACHE_ORIGIN_TTL_SECS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.origin_ttl_secs),
+            invalidation_poll_ms: value("OAUTH2_CACHE_INVALIDATION_POLL_MS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.invalidation_poll_ms),
+        }
+    }
+}
+
+/// Parse `admin=cn=admins,ou=groups,dc=example,dc=com;support=cn=...` into
+/// (group DN, role) pairs. Entries are split on `;` because DNs contain
+/// commas; malformed ones are skipped.
+fn parse_group_roles(value: &str) -> Vec<(String, String)> {
+    value
+        .split(';')
+        .filter_map(|entry| {
+            let (role, group) = entry.split_once('=')?;
+            let (role, group) = (role.trim(), group.trim());
+            if role.is_empty() || group.is_empty() {
+                return None;
+            }
+            Some((group.to_string(), role.to_string()))
+        })
+        .collect()
+}
+
+/// The SAML 2.0 identity provider for applications that cannot use OIDC.
+/// Off unless the certificate, key and service provider file are all set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SamlConfig {
+    /// Our entity ID; defaults to the metadata URL
+    pub entity_id: Option<String>,
+    /// PEM file with the certificate service providers verify assertions with
+    pub certificate: Option<String>,
+    /// PEM file with the RSA private key assertions are signed with
+    pub private_key: Option<String>,
+    /// JSON file listing the service providers assertions may be issued to
+    pub service_providers: Option<String>,
+    /// How long an assertion may be presented, in seconds
+    pub assertion_lifetime_secs: u64,
+}
+
+impl Default for SamlConfig {
+    fn default() -> Self {
+        Self {
+            entity_id: None,
+            certificate: None,
+            private_key: None,
+            service_providers: None,
+            assertion_lifetime_secs: 300,
+        }
+    }
+}
+
+impl SamlConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        let value = |var: &str| std::env::var(var).ok().filter(|v| !v.is_empty());
+
+        Self {
+            entity_id: value("OAUTH2_SAML_ENTITY_ID"),
+            certificate: value("OAUTH2_SAML_CERTIFICATE"),
+            private_key: value("OAUTH2_SAML_PRIVATE_KEY"),
+            service_providers: value("OAUTH2_SAML_SERVICE_PROVIDERS"),
+            assertion_lifetime_secs: value("OAUTH2_SAML_ASSERTION_LIFETIME_SECS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.assertion_lifetime_secs),
+        }
+    }
+}
+
+/// How long records are kept before the retention job purges them, in days.
+/// 0 keeps a class forever.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Admin audit log and authorization request log entries, by when they
+    /// were recorded
+    pub audit_days: u32,
+    /// Tokens, counted from when they were revoked or stopped being usable
+    pub token_days: u32,
+    /// Authorization codes, counted from when they expired
+    pub authorization_code_days: u32,
+    /// Events kept by event backends that store them
+    pub event_days: u32,
+    /// How often the retention job runs, in seconds
+    pub interval_secs: u64,
+    /// Count what would be purged without deleting anything
+    pub dry_run: bool,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            audit_days: 365,
+            token_days: 30,
+            authorization_code_days: 1,
+            event_days: 7,
+            interval_secs: 3600,
+            dry_run: false,
+        }
+    }
+}
+
+impl RetentionConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            audit_days: var("OAUTH2_RETENTION_AUDIT_DAYS", defaults.audit_days),
+            token_days: var("OAUTH2_RETENTION_TOKEN_DAYS", defaults.token_days),
+            authorization_code_days: var(
+                "OAUTH2_RETENTION_AUTHORIZATION_CODE_DAYS",
+                defaults.authorization_code_days,
+            ),
+            event_days: var("OAUTH2_RETENTION_EVENT_DAYS", defaults.event_days),
+            interval_secs: var("OAUTH2_RETENTION_INTERVAL_SECS", defaults.interval_secs),
+            dry_run: var("OAUTH2_RETENTION_DRY_RUN", defaults.dry_run),
+        }
+    }
+}
+
+/// Client secret expiry and the warnings sent before it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClientSecretConfig {
+    /// Days a newly issued secret is accepted before it must be rotated; 0
+    /// issues secrets that never expire
+    pub lifetime_days: u32,
+    /// Days before expiry that a `client_secret_expiring` event is sent
+    pub warning_days: u32,
+    /// How often secrets are checked for upcoming expiry, in seconds
+    pub check_interval_secs: u64,
+}
+
+impl Default for ClientSecretConfig {
+    fn default() -> Self {
+        Self {
+            lifetime_days: 0,
+            warning_days: 14,
+            check_interval_secs: 3600,
+        }
+    }
+}
+
+impl ClientSecretConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+
+        Self {
+            lifetime_days: var("OAUTH2_CLIENT_SECRET_LIFETIME_DAYS", defaults.lifetime_days),
+            warning_days: var("OAUTH2_CLIENT_SECRET_WARNING_DAYS", defaults.warning_days),
+            check_interval_secs: var(
+                "OAUTH2_CLIENT_SECRET_CHECK_INTERVAL_SECS",
+                defaults.check_interval_secs,
+            ),
+        }
+    }
+}
+
+/// Who may register clients through `/clients/register`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RegistrationConfig {
+    /// Refuse registrations that do not bring a registration token issued
+    /// by an admin; otherwise anyone may register, and a token only applies
+    /// when one is sent
+    pub require_token: bool,
+}
+
+impl RegistrationConfig {
+    pub fn from_env() -> Self {
+        Self {
+            require_token: std::env::var("OAUTH2_REGISTRATION_REQUIRE_TOKEN")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// When authorization requests must carry a PKCE `code_challenge`. Public
+/// clients always must.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PkceConfig {
+    /// Require PKCE from confidential clients as well
+    pub required: bool,
+}
+
+impl PkceConfig {
+    pub fn from_env() -> Self {
+        Self {
+            required: std::env::var("OAUTH2_REQUIRE_PKCE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Look-and-feel settings for the end-user pages (login, consent, error)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BrandingConfig {
+    pub product_name: String,
+    pub logo_url: Option<String>,
+    pub primary_color: String,
+    pub background_color: String,
+    pub terms_url: Option<String>,
+    pub privacy_url: Option<String>,
+    pub footer_links: Vec<FooterLink>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FooterLink {
+    pub label: String,
+    pub url: String,
+}
+
+impl Default for BrandingConfig {
+    fn default() -> Self {
+        Self {
+            product_name: "OAuth2 Server".to_string(),
+            logo_url: None,
+            primary_color: "#4f46e5".to_string(),
+            background_color: "#eef2ff".to_string(),
+            terms_url: Some("/terms".to_string()),
+            privacy_url: Some("/privacy".to_string()),
+            footer_links: vec![FooterLink {
+                label: "API Docs".to_string(),
+                url: "/swagger-ui/".to_string(),
+            }],
+        }
+    }
+}
+
+impl BrandingConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        let color = |var: &str, default: String| match std::env::var(var) {
+            Ok(value) if is_css_color(&value) => value,
+            Ok(value) => {
+                eprintln!(
+                    "WARNING: {} is not a hex color ({}), using default",
+                    var, value
+                );
+                default
+            }
+            Err(_) => default,
+        };
+        let url = |var: &str, default: Option<String>| match std::env::var(var) {
+            Ok(value) if value.is_empty() => None,
+            Ok(value) if is_safe_url(&value) => Some(value),
+            Ok(value) => {
+                eprintln!(
+                    "WARNING: {} must be an http(s) or relative URL ({}), ignoring",
+                    var, value
+                );
+                default
+            }
+            Err(_) => default,
+        };
+
+        Self {
+            product_name: std::env::var("OAUTH2_BRAND_PRODUCT_NAME")
+                .unwrap_or(defaults.product_name),
+            logo_url: url("OAUTH2_BRAND_LOGO_URL", defaults.logo_url),
+            primary_color: color("OAUTH2_BRAND_PRIMARY_COLOR", defaults.primary_color),
+            background_color: color("OAUTH2_BRAND_BACKGROUND_COLOR", defaults.background_color),
+            terms_url: url("OAUTH2_BRAND_TERMS_URL", defaults.terms_url),
+            privacy_url: url("OAUTH2_BRAND_PRIVACY_URL", defaults.privacy_url),
+            footer_links: std::env::var("OAUTH2_BRAND_FOOTER_LINKS")
+                .map(|v| parse_footer_links(&v))
+                .unwrap_or(defaults.footer_links),
+        }
+    }
+}
+
+/// Accept only `#rgb`, `#rrggbb` or `#rrggbbaa` so values are safe to emit into CSS
+fn is_css_color(value: &str) -> bool {
+    value
+        .strip_prefix('#')
+        .map(|hex| matches!(hex.len(), 3 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .unwrap_or(false)
+}
+
+fn is_safe_url(value: &str) -> bool {
+    value.starts_with("https://")
+        || value.starts_with("http://")
+        || (value.starts_with('/') && !value.starts_with("//"))
+}
+
+/// Parse `Label=url,Other=url` into footer links, skipping malformed entries
+fn parse_footer_links(value: &str) -> Vec<FooterLink> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (label, url) = entry.split_once('=')?;
+            let (label, url) = (label.trim(), url.trim());
+            if label.is_empty() || !is_safe_url(url) {
+                return None;
+            }
+            Some(FooterLink {
+                label: label.to_string(),
+                url: url.to_string(),
+            })
+        })
+        .collect()
 }
 
 impl Default for Config {
     fn default() -> Self {
+        let mode = match std::env::var("OAUTH2_MODE") {
+            Ok(value) => DeploymentMode::parse(&value).unwrap_or_else(|| {
+                eprintln!(
+                    "WARNING: Unknown OAUTH2_MODE {:?}; running in production mode",
+                    value
+                );
+                DeploymentMode::Production
+            }),
+            Err(_) => DeploymentMode::Production,
+        };
+        let security_profile = match std::env::var("OAUTH2_SECURITY_PROFILE") {
+            Ok(value) => SecurityProfile::parse(&value).unwrap_or_else(|| {
+                eprintln!(
+                    "WARNING: Unknown OAUTH2_SECURITY_PROFILE {:?}; using the balanced profile",
+                    value
+                );
+                SecurityProfile::Balanced
+            }),
+            Err(_) => SecurityProfile::Balanced,
+        };
+        let mut session = SessionConfig::from_env();
+        // Demo servers are usually reached over plain HTTP
+        if mode.is_demo() && std::env::var("OAUTH2_SESSION_SECURE").is_err() {
+            session.cookie_secure = false;
+        }
+
         Self {
+            mode,
+            security_profile,
             server: ServerConfig {
-                host: std::env::var("OAUTH2_SERVER_HOST").unwrap_or_else(|_| "127.0.0.1".to_string()),
+                host: std::env::var("OAUTH2_SERVER_HOST")
+                    .unwrap_or_else(|_| "127.0.0.1".to_string()),
                 port: std::env::var("OAUTH2_SERVER_PORT")
                     .ok()
                     .and_then(|p| p.parse().ok())
                     .unwrap_or(8080),
+                error_reference_url: std::env::var("OAUTH2_ERROR_REFERENCE_URL")
+                    .ok()
+                    .filter(|v| !v.is_empty()),
+                workers: std::env::var("OAUTH2_SERVER_WORKERS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|workers| *workers > 0),
+                backlog: std::env::var("OAUTH2_SERVER_BACKLOG")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(default_backlog),
+                keep_alive_secs: std::env::var("OAUTH2_SERVER_KEEP_ALIVE_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(default_keep_alive_secs),
+                client_request_timeout_ms: std::env::var("OAUTH2_SERVER_CLIENT_REQUEST_TIMEOUT_MS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(default_client_request_timeout_ms),
+                unix_socket: std::env::var("OAUTH2_SERVER_UNIX_SOCKET")
+                    .ok()
+                    .filter(|v| !v.is_empty()),
+                internal_addr: std::env::var("OAUTH2_SERVER_INTERNAL_ADDR")
+                    .ok()
+                    .filter(|v| !v.is_empty()),
             },
             database: DatabaseConfig {
-                url: std::env::var("OAUTH2_DATABASE_URL").unwrap_or_else(|_| "sqlite:oauth2.db".to_string()),
+                url: std::env::var("OAUTH2_DATABASE_URL")
+                    .unwrap_or_else(|_| "sqlite:oauth2.db".to_string()),
+                wal: std::env::var("OAUTH2_DATABASE_WAL")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(default_database_wal),
+                busy_timeout_ms: std::env::var("OAUTH2_DATABASE_BUSY_TIMEOUT_MS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(default_database_busy_timeout_ms),
+                max_connections: std::env::var("OAUTH2_DATABASE_MAX_CONNECTIONS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .filter(|connections| *connections > 0)
+                    .unwrap_or_else(default_database_max_connections),
             },
             jwt: JwtConfig {
-                // Use environment variable or fail-safe default for testing
-                // Production deployments MUST set OAUTH2_JWT_SECRET
-                secret: std::env::var("OAUTH2_JWT_SECRET")
-                    .unwrap_or_else(|_| {
-                        eprintln!("WARNING: OAUTH2_JWT_SECRET not set. Using insecure default for testing only!");
-                        eprintln!("NEVER use this in production! Set OAUTH2_JWT_SECRET environment variable.");
-                        "insecure-default-for-testing-only-change-in-production".to_string()
-                    }),
+                // Only demo mode has a default; production refuses to start
+                // without one
+                secret: std::env::var("OAUTH2_JWT_SECRET").unwrap_or_else(|_| match mode {
+                    DeploymentMode::Demo => DEMO_JWT_SECRET.to_string(),
+                    DeploymentMode::Production => String::new(),
+                }),
+                leeway_secs: std::env::var("OAUTH2_JWT_LEEWAY_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(default_jwt_leeway),
+                stateless_validation: std::env::var("OAUTH2_JWT_STATELESS_VALIDATION")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(false),
+                denylist_cache_secs: std::env::var("OAUTH2_JWT_DENYLIST_CACHE_SECS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(default_jwt_denylist_cache),
+                algorithm: std::env::var("OAUTH2_JWT_ALGORITHM")
+                    .ok()
+                    .and_then(|v| v.to_uppercase().parse().ok())
+                    .unwrap_or_else(default_jwt_algorithm),
+                private_key_file: std::env::var("OAUTH2_JWT_PRIVATE_KEY_FILE").ok(),
+                issuer: std::env::var("OAUTH2_JWT_ISSUER")
+                    .map(|issuer| issuer.trim_end_matches('/').to_string())
+                    .unwrap_or_else(|_| default_jwt_issuer()),
             },
             events: EventConfig {
                 enabled: std::env::var("OAUTH2_EVENTS_ENABLED")
@@ -64,13 +2106,61 @@ impl Default for Config {
                     .unwrap_or_else(|_| "in_memory".to_string()),
                 filter_mode: std::env::var("OAUTH2_EVENTS_FILTER_MODE")
                     .unwrap_or_else(|_| "allow_all".to_string()),
-                event_types: std::env::var("OAUTH2_EVENTS_TYPES")
-                    .unwrap_or_default()
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect(),
+                event_types: env_list("OAUTH2_EVENTS_TYPES"),
+                buffer_dir: std::env::var("OAUTH2_EVENTS_BUFFER_DIR")
+                    .ok()
+                    .filter(|v| !v.is_empty()),
+                buffer_max_events: std::env::var("OAUTH2_EVENTS_BUFFER_MAX_EVENTS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_else(default_event_buffer_max_events),
+                health_check_interval_secs: std::env::var(
+                    "OAUTH2_EVENTS_HEALTH_CHECK_INTERVAL_SECS",
+                )
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|secs| *secs > 0)
+                .unwrap_or_else(default_event_health_check_interval),
+                outbox_relay_interval_secs: std::env::var(
+                    "OAUTH2_EVENTS_OUTBOX_RELAY_INTERVAL_SECS",
+                )
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|secs| *secs > 0)
+                .unwrap_or_else(default_outbox_relay_interval),
+                kafka: KafkaEventConfig::from_env(),
+                redis: RedisEventConfig::from_env(),
+                webhook: WebhookEventConfig::from_env(),
             },
+            branding: BrandingConfig::from_env(),
+            login: LoginConfig::from_env(),
+            introspection: IntrospectionConfig::from_env(),
+            risk: RiskConfig::from_env(),
+            mfa: MfaConfig::from_env(),
+            session,
+            refresh_token: RefreshTokenConfig::from_env(),
+            grant_tokens: GrantTokenConfig::from_env(),
+            workload_identity: WorkloadIdentityConfig::from_env(),
+            policy: PolicyConfig::from_env(),
+            anomaly: AnomalyConfig::from_env(),
+            limits: RequestLimitsConfig::from_env(),
+            token_concurrency: TokenConcurrencyConfig::from_env(),
+            rate_limit: RateLimitConfig::from_env(),
+            usage: UsageConfig::from_env(),
+            stats: StatsConfig::from_env(),
+            account_deletion: AccountDeletionConfig::from_env(),
+            retention: RetentionConfig::from_env(),
+            client_secrets: ClientSecretConfig::from_env(),
+            registration: RegistrationConfig::from_env(),
+            pkce: PkceConfig::from_env(),
+            impersonation: ImpersonationConfig::from_env(),
+            admin_access: AdminAccessConfig::from_env(),
+            log_sampling: LogSamplingConfig::from_env(),
+            upstream_tokens: UpstreamTokenConfig::from_env(),
+            profile_sync: ProfileSyncConfig::from_env(),
+            ldap: LdapConfig::from_env(),
+            saml: SamlConfig::from_env(),
+            cache: CacheConfig::from_env(),
         }
     }
 }
@@ -87,9 +2177,11 @@ impl Config {
 
     /// Validate configuration for production use
     pub fn validate_for_production(&self) -> Result<(), String> {
-        // Check JWT secret is not the default
-        if self.jwt.secret == "insecure-default-for-testing-only-change-in-production" {
-            return Err("OAUTH2_JWT_SECRET must be explicitly set for production. Generate a secure random string (minimum 32 characters).".to_string());
+        if self.jwt.secret.is_empty() {
+            return Err("OAUTH2_JWT_SECRET must be set. Generate a secure random string (minimum 32 characters), or set OAUTH2_MODE=demo to try the server out.".to_string());
+        }
+        if self.jwt.secret == DEMO_JWT_SECRET {
+            return Err("OAUTH2_JWT_SECRET is the demo mode secret. Generate a secure random string (minimum 32 characters).".to_string());
         }
 
         // Check JWT secret length
@@ -100,6 +2192,312 @@ impl Config {
             ));
         }
 
+        // Browsers drop SameSite=None cookies that are not also Secure
+        if self.session.cookie_same_site == CookieSameSite::None && !self.session.cookie_secure {
+            return Err(
+                "OAUTH2_SESSION_COOKIE_SAME_SITE=none requires OAUTH2_SESSION_SECURE=true"
+                    .to_string(),
+            );
+        }
+
+        if self.upstream_tokens.encryption_key.is_some() && self.upstream_tokens.key().is_none() {
+            return Err(
+                "OAUTH2_UPSTREAM_TOKEN_KEY must be the base64 encoding of 32 random bytes"
+                    .to_string(),
+            );
+        }
+
+        self.events.validate()?;
+
         Ok(())
     }
+
+    /// Settings that are allowed but probably not what a deployment wants
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if !self.session.cookie_secure {
+            warnings.push(
+                "OAUTH2_SESSION_SECURE=false sends the session cookie over plain HTTP".to_string(),
+            );
+        }
+        if self.jwt.algorithm != jsonwebtoken::Algorithm::HS256
+            && self.jwt.private_key_file.is_none()
+        {
+            warnings.push(format!(
+                "{:?} without OAUTH2_JWT_PRIVATE_KEY_FILE generates a new key on every start; tokens signed before a restart stop validating",
+                self.jwt.algorithm
+            ));
+        }
+        if !self.events.enabled {
+            warnings.push("OAUTH2_EVENTS_ENABLED=false records no audit events".to_string());
+        }
+        if self.security_profile == SecurityProfile::Legacy {
+            warnings.push(
+                "OAUTH2_SECURITY_PROFILE=legacy answers implicit-style authorization requests with a code"
+                    .to_string(),
+            );
+        }
+        if let Some(webhook) = &self.events.webhook {
+            if webhook.secret.is_none() {
+                warnings.push(
+                    "OAUTH2_EVENTS_WEBHOOK_SECRET is not set; webhook receivers cannot tell events from forgeries"
+                        .to_string(),
+                );
+            }
+            if webhook.urls.iter().any(|url| url.starts_with("http://")) {
+                warnings.push("A webhook URL is plain HTTP; events travel unencrypted".to_string());
+            }
+        }
+        if self.database.url.contains(":memory:") {
+            warnings.push("The database is in memory; everything is lost on restart".to_string());
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_css_color() {
+        assert!(is_css_color("#fff"));
+        assert!(is_css_color("#4f46e5"));
+        assert!(is_css_color("#4f46e5cc"));
+        assert!(!is_css_color("red"));
+        assert!(!is_css_color("#12345"));
+        assert!(!is_css_color("#000;} body{display:none"));
+    }
+
+    #[test]
+    fn test_parse_footer_links() {
+        let links = parse_footer_links(
+            "Status=https://status.example.com, Help=/help,bad,Evil=javascript:alert(1)",
+        );
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].label, "Status");
+        assert_eq!(links[0].url, "https://status.example.com");
+        assert_eq!(links[1].url, "/help");
+    }
+
+    #[test]
+    fn test_parse_sample_rates() {
+        let rates = parse_sample_rates("/oauth/introspect=0.01, token_validated=0,big=7,bad,x=nan");
+        assert_eq!(rates.len(), 3);
+        assert_eq!(rates["/oauth/introspect"], 0.01);
+        assert_eq!(rates["token_validated"], 0.0);
+        assert_eq!(rates["big"], 1.0);
+    }
+
+    #[test]
+    fn test_same_site_none_requires_secure_cookie() {
+        let mut config = Config::default();
+        config.jwt.secret = "a".repeat(32);
+        config.session.cookie_same_site = CookieSameSite::None;
+        config.session.cookie_secure = false;
+        assert!(config.validate_for_production().is_err());
+        config.session.cookie_secure = true;
+        assert!(config.validate_for_production().is_ok());
+        assert_eq!(
+            CookieSameSite::parse("Strict"),
+            Some(CookieSameSite::Strict)
+        );
+        assert_eq!(CookieSameSite::parse("sometimes"), None);
+    }
+
+    #[test]
+    fn test_production_needs_a_secret_of_its_own() {
+        let mut config = Config::default();
+        config.jwt.secret = String::new();
+        assert!(config.validate_for_production().is_err());
+        config.jwt.secret = DEMO_JWT_SECRET.to_string();
+        assert!(config.validate_for_production().is_err());
+        assert_eq!(DeploymentMode::parse("Demo"), Some(DeploymentMode::Demo));
+        assert_eq!(DeploymentMode::parse("staging"), None);
+    }
+
+    #[test]
+    fn test_warnings_name_risky_settings() {
+        let mut config = Config::default();
+        config.session.cookie_secure = true;
+        config.events.enabled = true;
+        config.database.url = "sqlite:oauth2.db".to_string();
+        config.jwt.algorithm = jsonwebtoken::Algorithm::HS256;
+        config.security_profile = SecurityProfile::Balanced;
+        assert!(config.warnings().is_empty());
+
+        config.session.cookie_secure = false;
+        config.jwt.algorithm = jsonwebtoken::Algorithm::RS256;
+        config.jwt.private_key_file = None;
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("OAUTH2_SESSION_SECURE"));
+        assert!(warnings[1].contains("OAUTH2_JWT_PRIVATE_KEY_FILE"));
+    }
+
+    #[test]
+    fn test_security_profiles_accept_less_and_less() {
+        assert_eq!(
+            SecurityProfile::parse("STRICT"),
+            Some(SecurityProfile::Strict)
+        );
+        assert_eq!(SecurityProfile::parse("lax"), None);
+
+        let legacy = SecurityProfile::Legacy;
+        assert!(legacy.accepts_response_type("token"));
+
+        let balanced = SecurityProfile::default();
+        assert!(!balanced.accepts_response_type("token"));
+        assert!(balanced.accepts_response_type("code"));
+        assert!(balanced.allows_password_grant());
+        assert_eq!(balanced.code_challenge_methods(), ["S256", "plain"]);
+
+        let strict = SecurityProfile::Strict;
+        assert!(!strict.allows_password_grant());
+        assert!(!strict.allows_public_clients());
+        assert!(strict.requires_pkce());
+        assert_eq!(strict.code_challenge_methods(), ["S256"]);
+    }
+
+    #[test]
+    fn test_event_backend_settings_are_validated() {
+        let mut config = Config::default();
+        config.jwt.secret = "a".repeat(32);
+        config.events.kafka = Some(KafkaEventConfig {
+            brokers: vec!["kafka-1:9092".to_string(), "kafka-2:9092".to_string()],
+            topic: "auth".to_string(),
+            acks: KafkaAcks::parse("1").unwrap(),
+        });
+        config.events.redis = Some(RedisEventConfig {
+            url: "rediss://:pw@events.internal/1".to_string(),
+            stream: "oauth2:events".to_string(),
+        });
+        config.events.webhook = Some(WebhookEventConfig {
+            urls: vec!["https://hooks.example.com/auth".to_string()],
+            secret: Some("s".repeat(16)),
+        });
+        assert!(config.validate_for_production().is_ok());
+
+        let mut bad = config.clone();
+        bad.events.kafka.as_mut().unwrap().brokers = vec!["kafka-1".to_string()];
+        assert!(bad
+            .validate_for_production()
+            .unwrap_err()
+            .contains("KAFKA_BROKERS"));
+        let mut bad = config.clone();
+        bad.events.redis.as_mut().unwrap().url = "http://events.internal".to_string();
+        assert!(bad
+            .validate_for_production()
+            .unwrap_err()
+            .contains("REDIS_URL"));
+        let mut bad = config.clone();
+        bad.events.webhook.as_mut().unwrap().secret = Some("short".to_string());
+        assert!(bad
+            .validate_for_production()
+            .unwrap_err()
+            .contains("WEBHOOK_SECRET"));
+
+        let webhook = config.events.webhook.as_mut().unwrap();
+        webhook.secret = None;
+        webhook.urls.push("http://hooks.internal".to_string());
+        let warnings = config.warnings();
+        assert!(warnings.iter().any(|w| w.contains("WEBHOOK_SECRET")));
+        assert!(warnings.iter().any(|w| w.contains("plain HTTP")));
+        assert_eq!(KafkaAcks::parse("all"), Some(KafkaAcks::All));
+        assert_eq!(KafkaAcks::parse("2"), None);
+    }
+
+    #[test]
+    fn test_secrets_are_read_from_files() {
+        let path = std::env::temp_dir().join(format!("oauth2-secret-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "from-a-file\n").unwrap();
+        std::env::set_var("OAUTH2_TEST_FILE_SECRET_FILE", &path);
+        assert_eq!(
+            env_secret("OAUTH2_TEST_FILE_SECRET").as_deref(),
+            Some("from-a-file")
+        );
+        std::env::set_var("OAUTH2_TEST_FILE_SECRET", "from-the-env");
+        assert_eq!(
+            env_secret("OAUTH2_TEST_FILE_SECRET").as_deref(),
+            Some("from-the-env")
+        );
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(env_secret("OAUTH2_TEST_UNSET_SECRET"), None);
+    }
+
+    #[test]
+    fn test_upstream_token_key_must_be_32_bytes() {
+        let mut config = Config::default();
+        config.jwt.secret = "a".repeat(32);
+        config.upstream_tokens.encryption_key = Some("c2hvcnQ=".to_string());
+        assert!(config.validate_for_production().is_err());
+        config.upstream_tokens.encryption_key = Some(base64::Engine::encode(
+            &base64::engine::general_purpose::STANDARD,
+            [7u8; 32],
+        ));
+        assert!(config.validate_for_production().is_ok());
+        assert_eq!(config.upstream_tokens.key(), Some([7u8; 32]));
+    }
+
+    #[test]
+    fn test_parse_group_roles() {
+        let roles = parse_group_roles(
+            "admin=cn=admins,ou=groups,dc=example,dc=com; support = cn=support,dc=example,dc=com;bad;=x",
+        );
+        assert_eq!(
+            roles,
+            vec![
+                (
+                    "cn=admins,ou=groups,dc=example,dc=com".to_string(),
+                    "admin".to_string()
+                ),
+                (
+                    "cn=support,dc=example,dc=com".to_string(),
+                    "support".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_introspection_max_age_per_client() {
+        let config = IntrospectionConfig {
+            cache_max_age: 60,
+            client_cache_max_age: parse_client_max_ages("banking=0, reports = 300,bad,x=-1"),
+        };
+        assert_eq!(config.client_cache_max_age.len(), 2);
+        assert_eq!(config.max_age_for("banking"), 0);
+        assert_eq!(config.max_age_for("reports"), 300);
+        assert_eq!(config.max_age_for("anyone-else"), 60);
+    }
+
+    #[test]
+    fn test_grant_token_settings() {
+        let config = GrantTokenConfig {
+            formats: parse_grant_values("password=opaque, client_credentials=jwt,x=bad", |f| {
+                TokenFormat::parse(f).ok_or(())
+            }),
+            access_token_lifetimes: parse_grant_values("password=300,bad", str::parse),
+            refresh_token_lifetimes: parse_grant_values("password=0,=5", str::parse),
+            ..GrantTokenConfig::default()
+        };
+        assert_eq!(config.formats.len(), 2);
+        assert_eq!(config.format_for("password"), TokenFormat::Opaque);
+        assert_eq!(config.format_for("authorization_code"), TokenFormat::Jwt);
+        assert_eq!(config.access_token_lifetime_for("password"), 300);
+        assert_eq!(config.access_token_lifetime_for("client_credentials"), 3600);
+        assert_eq!(config.refresh_token_lifetime_for("password"), Some(0));
+        assert_eq!(
+            config.refresh_token_lifetime_for("authorization_code"),
+            None
+        );
+
+        // Grants without an entry follow the server-wide format
+        let config = GrantTokenConfig {
+            format: TokenFormat::Opaque,
+            ..config
+        };
+        assert_eq!(config.format_for("authorization_code"), TokenFormat::Opaque);
+        assert_eq!(config.format_for("client_credentials"), TokenFormat::Jwt);
+    }
 }
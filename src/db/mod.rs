@@ -1,16 +1,60 @@
 #![allow(dead_code)]
 
-use crate::models::{AuthorizationCode, Client, OAuth2Error, Token, User};
-use sqlx::{Pool, Sqlite, SqlitePool};
+mod transaction;
+
+pub use transaction::DbTransaction;
+
+use crate::config::DatabaseConfig;
+use crate::events::AuthEvent;
+use crate::models::{
+    stored_access_token, AccountDeletion, AdminAuditEntry, AuditChain, AuthorizationCode,
+    AuthorizationRequestEntry, Client, ClientState, ErasureReport, ErrorCode, ExpiringSecret,
+    OAuth2Error, Organization, RegistrationToken, Resource, RevokedCredentials, SocialUserInfo,
+    StaleClient, StatsBucket, StatsCounts, StatsGranularity, SyncKind, Token, TotpEnrollment,
+    TrustedDevice, UnusedToken, UpstreamToken, UsageCount, User,
+};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{Pool, Sqlite, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
 
 pub struct Database {
+    /// Connections for reads
     pool: Pool<Sqlite>,
+    /// The one connection every write goes through. SQLite only lets one
+    /// connection write at a time; queueing for this one instead means
+    /// concurrent writes wait their turn rather than fail with `database is
+    /// locked`.
+    writer: Pool<Sqlite>,
 }
 
 impl Database {
-    pub async fn new(database_url: &str) -> Result<Self, sqlx::Error> {
-        let pool = SqlitePool::connect(database_url).await?;
-        Ok(Self { pool })
+    pub async fn new(config: &DatabaseConfig) -> Result<Self, sqlx::Error> {
+        let mut options = SqliteConnectOptions::from_str(&config.url)?
+            .busy_timeout(std::time::Duration::from_millis(config.busy_timeout_ms));
+        if config.wal {
+            // Readers see the last commit while a write is in progress, and
+            // commits only need a full sync at checkpoints
+            options = options
+                .journal_mode(SqliteJournalMode::Wal)
+                .synchronous(SqliteSynchronous::Normal);
+        }
+
+        let writer = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options.clone())
+            .await?;
+        let pool = SqlitePoolOptions::new()
+            .max_connections(config.max_connections)
+            .connect_with(options)
+            .await?;
+        Ok(Self { pool, writer })
+    }
+
+    /// Start a transaction for changes that must be applied together
+    pub async fn begin(&self) -> Result<DbTransaction, OAuth2Error> {
+        Ok(DbTransaction::new(self.writer.begin().await?))
     }
 
     pub async fn init(&self) -> Result<(), sqlx::Error> {
@@ -20,12 +64,66 @@ impl Database {
         Ok(())
     }
 
+    /// Newest migration Flyway recorded as applied; `None` when the schema
+    /// was not applied with Flyway
+    pub async fn schema_version(&self) -> Result<Option<u32>, OAuth2Error> {
+        let has_history: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'flyway_schema_history')",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if !has_history {
+            return Ok(None);
+        }
+        let versions: Vec<Option<String>> =
+            sqlx::query_scalar("SELECT version FROM flyway_schema_history WHERE success = 1")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(versions
+            .iter()
+            .flatten()
+            .filter_map(|version| version.parse().ok())
+            .max())
+    }
+
+    /// A fact recorded about the deployment this database belongs to
+    pub async fn get_deployment_setting(&self, key: &str) -> Result<Option<String>, OAuth2Error> {
+        let value = sqlx::query_scalar("SELECT value FROM deployment_settings WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(value)
+    }
+
+    pub async fn set_deployment_setting(
+        &self,
+        key: &str,
+        value: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO deployment_settings (key, value, updated_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(key) DO UPDATE SET
+                value = excluded.value,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(key)
+        .bind(value)
+        .bind(now)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
     // Client operations
     pub async fn save_client(&self, client: &Client) -> Result<(), OAuth2Error> {
         sqlx::query(
             r#"
-            INSERT INTO clients (id, client_id, client_secret, redirect_uris, grant_types, scope, name, created_at, updated_at)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO clients (id, client_id, client_secret, redirect_uris, grant_types, scope, name, created_at, updated_at, owner_id, owner_org, allowed_origins, redirect_uri_patterns, secret_expires_at, token_endpoint_auth_method, access_token_format)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             "#,
         )
         .bind(&client.id)
@@ -37,7 +135,14 @@ impl Database {
         .bind(&client.name)
         .bind(client.created_at)
         .bind(client.updated_at)
-        .execute(&self.pool)
+        .bind(&client.owner_id)
+        .bind(&client.owner_org)
+        .bind(&client.allowed_origins)
+        .bind(&client.redirect_uri_patterns)
+        .bind(client.secret_expires_at)
+        .bind(&client.token_endpoint_auth_method)
+        .bind(&client.access_token_format)
+        .execute(&self.writer)
         .await?;
         Ok(())
     }
@@ -50,123 +155,2077 @@ impl Database {
         Ok(client)
     }
 
-    // User operations
-    pub async fn save_user(&self, user: &User) -> Result<(), OAuth2Error> {
+    pub async fn list_clients_by_owner(&self, owner_id: &str) -> Result<Vec<Client>, OAuth2Error> {
+        let clients = sqlx::query_as::<_, Client>(
+            "SELECT * FROM clients WHERE owner_id = ? ORDER BY created_at DESC",
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(clients)
+    }
+
+    /// Every client, or only those in `org_id`
+    pub async fn list_clients(&self, org_id: Option<&str>) -> Result<Vec<Client>, OAuth2Error> {
+        let clients = sqlx::query_as::<_, Client>(
+            "SELECT * FROM clients WHERE ? IS NULL OR owner_org = ? ORDER BY created_at DESC",
+        )
+        .bind(org_id)
+        .bind(org_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(clients)
+    }
+
+    /// Clients a portal user manages: their organization's, plus ones they
+    /// registered outside any organization
+    pub async fn list_manageable_clients(
+        &self,
+        subject: &str,
+        org_id: Option<&str>,
+    ) -> Result<Vec<Client>, OAuth2Error> {
+        let clients = sqlx::query_as::<_, Client>(
+            r#"
+            SELECT * FROM clients
+            WHERE (owner_org IS NOT NULL AND owner_org = ?)
+               OR (owner_org IS NULL AND owner_id = ?)
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(org_id)
+        .bind(subject)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(clients)
+    }
+
+    /// Returns false if there is no such client
+    pub async fn set_client_org(
+        &self,
+        client_id: &str,
+        org_id: Option<&str>,
+    ) -> Result<bool, OAuth2Error> {
+        let result =
+            sqlx::query("UPDATE clients SET owner_org = ?, updated_at = ? WHERE client_id = ?")
+                .bind(org_id)
+                .bind(Utc::now())
+                .bind(client_id)
+                .execute(&self.writer)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn update_client_secret(
+        &self,
+        client_id: &str,
+        client_secret: &str,
+        secret_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(), OAuth2Error> {
         sqlx::query(
             r#"
-            INSERT INTO users (id, username, password_hash, email, enabled, created_at, updated_at)
-            VALUES (?, ?, ?, ?, ?, ?, ?)
+            UPDATE clients
+            SET client_secret = ?, secret_expires_at = ?, secret_expiry_warned_at = NULL, updated_at = ?
+            WHERE client_id = ?
             "#,
         )
-        .bind(&user.id)
-        .bind(&user.username)
-        .bind(&user.password_hash)
-        .bind(&user.email)
-        .bind(user.enabled)
-        .bind(user.created_at)
-        .bind(user.updated_at)
-        .execute(&self.pool)
+        .bind(client_secret)
+        .bind(secret_expires_at)
+        .bind(Utc::now())
+        .bind(client_id)
+        .execute(&self.writer)
         .await?;
         Ok(())
     }
 
-    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<User>, OAuth2Error> {
-        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = ?")
-            .bind(username)
-            .fetch_optional(&self.pool)
+    /// Set or clear when the secret expires, so the warning is sent again
+    /// for the new date. Returns false if there is no such client.
+    pub async fn set_client_secret_expiry(
+        &self,
+        client_id: &str,
+        secret_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, OAuth2Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE clients
+            SET secret_expires_at = ?, secret_expiry_warned_at = NULL, updated_at = ?
+            WHERE client_id = ?
+            "#,
+        )
+        .bind(secret_expires_at)
+        .bind(Utc::now())
+        .bind(client_id)
+        .execute(&self.writer)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Clients whose secret expires before `before`, soonest first. With
+    /// `unwarned`, only those not yet warned about.
+    pub async fn list_expiring_client_secrets(
+        &self,
+        before: DateTime<Utc>,
+        now: DateTime<Utc>,
+        unwarned: bool,
+    ) -> Result<Vec<ExpiringSecret>, OAuth2Error> {
+        let clients = sqlx::query_as::<_, ExpiringSecret>(
+            r#"
+            SELECT client_id, name, owner_id, owner_org, secret_expires_at,
+                secret_expires_at <= ?2 AS expired
+            FROM clients
+            WHERE secret_expires_at < ?1 AND (NOT ?3 OR secret_expiry_warned_at IS NULL)
+            ORDER BY secret_expires_at
+            "#,
+        )
+        .bind(before)
+        .bind(now)
+        .bind(unwarned)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(clients)
+    }
+
+    /// Record that the expiry warning went out for the client's current
+    /// secret. Returns false if it already had, so replicas warn only once.
+    pub async fn mark_secret_expiry_warned(
+        &self,
+        client_id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, OAuth2Error> {
+        let result = sqlx::query(
+            "UPDATE clients SET secret_expiry_warned_at = ? WHERE client_id = ? AND secret_expiry_warned_at IS NULL",
+        )
+        .bind(now)
+        .bind(client_id)
+        .execute(&self.writer)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn update_client_redirect_uris(
+        &self,
+        client_id: &str,
+        redirect_uris: &[String],
+    ) -> Result<(), OAuth2Error> {
+        let redirect_uris =
+            serde_json::to_string(redirect_uris).unwrap_or_else(|_| "[]".to_string());
+        sqlx::query("UPDATE clients SET redirect_uris = ?, updated_at = ? WHERE client_id = ?")
+            .bind(redirect_uris)
+            .bind(Utc::now())
+            .bind(client_id)
+            .execute(&self.writer)
             .await?;
-        Ok(user)
+        Ok(())
     }
 
-    // Token operations
-    pub async fn save_token(&self, token: &Token) -> Result<(), OAuth2Error> {
+    /// Whether any client has registered `origin`, which must be normalized
+    pub async fn is_allowed_origin(&self, origin: &str) -> Result<bool, OAuth2Error> {
+        let lists: Vec<String> = sqlx::query_scalar(
+            "SELECT allowed_origins FROM clients WHERE instr(allowed_origins, ?) > 0",
+        )
+        .bind(format!("\"{}\"", origin))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(lists.iter().any(|list| {
+            serde_json::from_str::<Vec<String>>(list)
+                .unwrap_or_default()
+                .iter()
+                .any(|allowed| allowed == origin)
+        }))
+    }
+
+    pub async fn list_clients_by_name(&self, name: &str) -> Result<Vec<Client>, OAuth2Error> {
+        let clients =
+            sqlx::query_as::<_, Client>("SELECT * FROM clients WHERE name = ? ORDER BY created_at")
+                .bind(name)
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(clients)
+    }
+
+    /// Overwrite a client's redirect URIs, grant types and scope
+    pub async fn update_client_state(
+        &self,
+        client_id: &str,
+        state: &ClientState,
+    ) -> Result<(), OAuth2Error> {
+        let to_json =
+            |items: &[String]| serde_json::to_string(items).unwrap_or_else(|_| "[]".to_string());
+        sqlx::query(
+            "UPDATE clients SET redirect_uris = ?, grant_types = ?, scope = ?, updated_at = ? WHERE client_id = ?",
+        )
+        .bind(to_json(&state.redirect_uris))
+        .bind(to_json(&state.grant_types))
+        .bind(&state.scope)
+        .bind(Utc::now())
+        .bind(client_id)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    /// Delete a client together with its tokens and authorization codes,
+    /// recording `event` in the outbox with the deletion. Returns the tokens
+    /// that were still live.
+    pub async fn delete_client(
+        &self,
+        client_id: &str,
+        event: Option<&AuthEvent>,
+    ) -> Result<Vec<Token>, OAuth2Error> {
+        let mut tx = self.begin().await?;
+        let tokens = tx.delete_client(client_id, event).await?;
+        tx.commit().await?;
+        Ok(tokens)
+    }
+
+    // Registration token operations
+    pub async fn save_registration_token(
+        &self,
+        token: &RegistrationToken,
+    ) -> Result<(), OAuth2Error> {
         sqlx::query(
             r#"
-            INSERT INTO tokens (id, access_token, refresh_token, token_type, expires_in, scope, client_id, user_id, created_at, expires_at, revoked)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO registration_tokens (id, token_hash, description, scope, grant_types, max_uses, use_count, expires_at, revoked_at, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             "#,
         )
         .bind(&token.id)
-        .bind(&token.access_token)
-        .bind(&token.refresh_token)
-        .bind(&token.token_type)
-        .bind(token.expires_in)
+        .bind(&token.token_hash)
+        .bind(&token.description)
         .bind(&token.scope)
-        .bind(&token.client_id)
-        .bind(&token.user_id)
-        .bind(token.created_at)
+        .bind(&token.grant_types)
+        .bind(token.max_uses)
+        .bind(token.use_count)
         .bind(token.expires_at)
-        .bind(token.revoked)
-        .execute(&self.pool)
+        .bind(token.revoked_at)
+        .bind(token.created_at)
+        .execute(&self.writer)
         .await?;
         Ok(())
     }
 
-    pub async fn get_token_by_access_token(
+    pub async fn get_registration_token_by_hash(
         &self,
-        access_token: &str,
-    ) -> Result<Option<Token>, OAuth2Error> {
-        let token = sqlx::query_as::<_, Token>("SELECT * FROM tokens WHERE access_token = ?")
-            .bind(access_token)
-            .fetch_optional(&self.pool)
-            .await?;
+        token_hash: &str,
+    ) -> Result<Option<RegistrationToken>, OAuth2Error> {
+        let token = sqlx::query_as::<_, RegistrationToken>(
+            "SELECT * FROM registration_tokens WHERE token_hash = ?",
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
         Ok(token)
     }
 
-    pub async fn revoke_token(&self, token: &str) -> Result<(), OAuth2Error> {
-        sqlx::query("UPDATE tokens SET revoked = 1 WHERE access_token = ? OR refresh_token = ?")
-            .bind(token)
-            .bind(token)
-            .execute(&self.pool)
+    /// Newest first
+    pub async fn list_registration_tokens(&self) -> Result<Vec<RegistrationToken>, OAuth2Error> {
+        let tokens = sqlx::query_as::<_, RegistrationToken>(
+            "SELECT * FROM registration_tokens ORDER BY created_at DESC",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    /// Count one use, unless the token is used up or revoked by now. Returns
+    /// whether the use was counted, so concurrent registrations cannot
+    /// exceed `max_uses`.
+    pub async fn use_registration_token(&self, id: &str) -> Result<bool, OAuth2Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE registration_tokens SET use_count = use_count + 1
+            WHERE id = ? AND use_count < max_uses AND revoked_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .execute(&self.writer)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns false if there is no such token or it was already revoked
+    pub async fn revoke_registration_token(
+        &self,
+        id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, OAuth2Error> {
+        let result = sqlx::query(
+            "UPDATE registration_tokens SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL",
+        )
+        .bind(now)
+        .bind(id)
+        .execute(&self.writer)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Event outbox operations
+    /// The oldest `limit` events waiting to be published, as their outbox
+    /// sequence number and JSON
+    pub async fn list_outbox_events(&self, limit: i64) -> Result<Vec<(i64, String)>, OAuth2Error> {
+        let events = sqlx::query_as::<_, (i64, String)>(
+            "SELECT seq, event FROM event_outbox ORDER BY seq LIMIT ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(events)
+    }
+
+    pub async fn delete_outbox_event(&self, seq: i64) -> Result<(), OAuth2Error> {
+        sqlx::query("DELETE FROM event_outbox WHERE seq = ?")
+            .bind(seq)
+            .execute(&self.writer)
             .await?;
         Ok(())
     }
 
-    // Authorization code operations
-    pub async fn save_authorization_code(
+    // Cache invalidation operations
+    pub async fn record_cache_invalidation(
         &self,
-        auth_code: &AuthorizationCode,
+        key: &str,
+        now: DateTime<Utc>,
     ) -> Result<(), OAuth2Error> {
+        sqlx::query("INSERT INTO cache_invalidations (cache_key, created_at) VALUES (?, ?)")
+            .bind(key)
+            .bind(now)
+            .execute(&self.writer)
+            .await?;
+        Ok(())
+    }
+
+    /// Sequence number of the newest invalidation, 0 if there are none
+    pub async fn latest_cache_invalidation(&self) -> Result<i64, OAuth2Error> {
+        let latest: Option<i64> = sqlx::query_scalar("SELECT MAX(seq) FROM cache_invalidations")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(latest.unwrap_or(0))
+    }
+
+    /// Invalidations recorded after `seq`, oldest first
+    pub async fn list_cache_invalidations(
+        &self,
+        seq: i64,
+    ) -> Result<Vec<(i64, String)>, OAuth2Error> {
+        let invalidations = sqlx::query_as::<_, (i64, String)>(
+            "SELECT seq, cache_key FROM cache_invalidations WHERE seq > ? ORDER BY seq",
+        )
+        .bind(seq)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(invalidations)
+    }
+
+    pub async fn purge_cache_invalidations(
+        &self,
+        before: DateTime<Utc>,
+    ) -> Result<u64, OAuth2Error> {
+        let result = sqlx::query("DELETE FROM cache_invalidations WHERE created_at < ?")
+            .bind(before)
+            .execute(&self.writer)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Resource operations
+    pub async fn save_resource(&self, resource: &Resource) -> Result<(), OAuth2Error> {
         sqlx::query(
             r#"
-            INSERT INTO authorization_codes (id, code, client_id, user_id, redirect_uri, scope, created_at, expires_at, used, code_challenge, code_challenge_method)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO resources (id, identifier, name, scopes, token_format, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
             "#,
         )
-        .bind(&auth_code.id)
-        .bind(&auth_code.code)
-        .bind(&auth_code.client_id)
-        .bind(&auth_code.user_id)
-        .bind(&auth_code.redirect_uri)
-        .bind(&auth_code.scope)
-        .bind(auth_code.created_at)
-        .bind(auth_code.expires_at)
-        .bind(auth_code.used)
-        .bind(&auth_code.code_challenge)
-        .bind(&auth_code.code_challenge_method)
-        .execute(&self.pool)
+        .bind(&resource.id)
+        .bind(&resource.identifier)
+        .bind(&resource.name)
+        .bind(&resource.scopes)
+        .bind(&resource.token_format)
+        .bind(resource.created_at)
+        .bind(resource.updated_at)
+        .execute(&self.writer)
         .await?;
         Ok(())
     }
 
-    pub async fn get_authorization_code(
+    pub async fn get_resource_by_identifier(
         &self,
-        code: &str,
-    ) -> Result<Option<AuthorizationCode>, OAuth2Error> {
-        let auth_code = sqlx::query_as::<_, AuthorizationCode>(
-            "SELECT * FROM authorization_codes WHERE code = ?",
+        identifier: &str,
+    ) -> Result<Option<Resource>, OAuth2Error> {
+        let resource =
+            sqlx::query_as::<_, Resource>("SELECT * FROM resources WHERE identifier = ?")
+                .bind(identifier)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(resource)
+    }
+
+    pub async fn list_resources(&self) -> Result<Vec<Resource>, OAuth2Error> {
+        let resources =
+            sqlx::query_as::<_, Resource>("SELECT * FROM resources ORDER BY identifier")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(resources)
+    }
+
+    /// Returns false if there is no such resource
+    pub async fn delete_resource(&self, id: &str) -> Result<bool, OAuth2Error> {
+        let result = sqlx::query("DELETE FROM resources WHERE id = ?")
+            .bind(id)
+            .execute(&self.writer)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn update_resource(&self, resource: &Resource) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            "UPDATE resources SET name = ?, scopes = ?, token_format = ?, updated_at = ? WHERE id = ?",
         )
-        .bind(code)
-        .fetch_optional(&self.pool)
+        .bind(&resource.name)
+        .bind(&resource.scopes)
+        .bind(&resource.token_format)
+        .bind(resource.updated_at)
+        .bind(&resource.id)
+        .execute(&self.writer)
         .await?;
-        Ok(auth_code)
+        Ok(())
+    }
+
+    // Declarative sync operations
+
+    /// Keys of every object created or adopted by sync
+    pub async fn list_sync_managed(&self) -> Result<HashSet<(SyncKind, String)>, OAuth2Error> {
+        let mut managed = HashSet::new();
+        for kind in [SyncKind::Client, SyncKind::Resource, SyncKind::User] {
+            let (table, key) = kind.table();
+            let ids: Vec<String> = sqlx::query_scalar(&format!(
+                "SELECT {} FROM {} WHERE managed_by = 'sync'",
+                key, table
+            ))
+            .fetch_all(&self.pool)
+            .await?;
+            managed.extend(ids.into_iter().map(|id| (kind, id)));
+        }
+        Ok(managed)
+    }
+
+    pub async fn set_sync_managed(&self, kind: SyncKind, id: &str) -> Result<(), OAuth2Error> {
+        let (table, key) = kind.table();
+        sqlx::query(&format!(
+            "UPDATE {} SET managed_by = 'sync' WHERE {} = ?",
+            table, key
+        ))
+        .bind(id)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
     }
 
-    pub async fn mark_authorization_code_used(&self, code: &str) -> Result<(), OAuth2Error> {
-        sqlx::query("UPDATE authorization_codes SET used = 1 WHERE code = ?")
-            .bind(code)
-            .execute(&self.pool)
+    // Admin audit operations
+    /// Append `entry` to the audit log, sealed into the hash chain after the
+    /// last entry
+    pub async fn save_admin_audit_entry(
+        &self,
+        entry: &AdminAuditEntry,
+        chain: &AuditChain,
+    ) -> Result<(), OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        // Inserting first takes the write lock, so no other entry can claim
+        // the same place in the chain
+        let (seq, prev_hash): (i64, String) = sqlx::query_as(
+            r#"
+            INSERT INTO admin_audit_log (id, actor, method, path, params, status, client_ip, created_at, seq, prev_hash)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?,
+                (SELECT COALESCE(MAX(seq), 0) + 1 FROM admin_audit_log),
+                (SELECT COALESCE((SELECT hash FROM admin_audit_log WHERE seq IS NOT NULL ORDER BY seq DESC LIMIT 1), '')))
+            RETURNING seq, prev_hash
+            "#,
+        )
+        .bind(&entry.id)
+        .bind(&entry.actor)
+        .bind(&entry.method)
+        .bind(&entry.path)
+        .bind(&entry.params)
+        .bind(entry.status)
+        .bind(&entry.client_ip)
+        .bind(entry.created_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        let sealed = AdminAuditEntry {
+            seq: Some(seq),
+            ..entry.clone()
+        };
+        sqlx::query("UPDATE admin_audit_log SET hash = ? WHERE id = ?")
+            .bind(chain.seal(&prev_hash, &sealed))
+            .bind(&entry.id)
+            .execute(&mut *tx)
             .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Every audit entry in chain order, those from before the chain first
+    pub async fn list_admin_audit_chain(&self) -> Result<Vec<AdminAuditEntry>, OAuth2Error> {
+        let entries = sqlx::query_as::<_, AdminAuditEntry>(
+            "SELECT * FROM admin_audit_log ORDER BY seq, created_at",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(entries)
+    }
+
+    /// Newest first, optionally only paths starting with `path_prefix`
+    pub async fn list_admin_audit_entries(
+        &self,
+        path_prefix: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<AdminAuditEntry>, OAuth2Error> {
+        let entries = sqlx::query_as::<_, AdminAuditEntry>(
+            r#"
+            SELECT * FROM admin_audit_log
+            WHERE ? IS NULL OR substr(path, 1, length(?)) = ?
+            ORDER BY created_at DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(path_prefix)
+        .bind(path_prefix)
+        .bind(path_prefix)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(entries)
+    }
+
+    pub async fn save_authorization_request(
+        &self,
+        entry: &AuthorizationRequestEntry,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO authorization_request_log (id, client_id, user_id, response_type, scope, granted_scope, redirect_uri, params, outcome, error_description, status, code_id, token_id, client_ip, created_at, flow_id)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&entry.id)
+        .bind(&entry.client_id)
+        .bind(&entry.user_id)
+        .bind(&entry.response_type)
+        .bind(&entry.scope)
+        .bind(&entry.granted_scope)
+        .bind(&entry.redirect_uri)
+        .bind(&entry.params)
+        .bind(&entry.outcome)
+        .bind(&entry.error_description)
+        .bind(entry.status)
+        .bind(&entry.code_id)
+        .bind(&entry.token_id)
+        .bind(&entry.client_ip)
+        .bind(entry.created_at)
+        .bind(&entry.flow_id)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    /// Remember the scope `subject` approved for `client_id`, replacing
+    /// what they approved before
+    pub async fn record_consent(
+        &self,
+        subject: &str,
+        client_id: &str,
+        scope: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO user_consents (subject, client_id, scope, granted_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(subject, client_id) DO UPDATE SET
+                scope = excluded.scope,
+                granted_at = excluded.granted_at
+            "#,
+        )
+        .bind(subject)
+        .bind(client_id)
+        .bind(scope)
+        .bind(now)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    /// The scope `subject` last approved for `client_id`, if they ever did
+    pub async fn get_consented_scope(
+        &self,
+        subject: &str,
+        client_id: &str,
+    ) -> Result<Option<String>, OAuth2Error> {
+        let scope = sqlx::query_scalar::<_, String>(
+            "SELECT scope FROM user_consents WHERE subject = ? AND client_id = ?",
+        )
+        .bind(subject)
+        .bind(client_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(scope)
+    }
+
+    /// Newest first, optionally only those of one client, user or flow
+    pub async fn list_authorization_requests(
+        &self,
+        client_id: Option<&str>,
+        user_id: Option<&str>,
+        flow_id: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<AuthorizationRequestEntry>, OAuth2Error> {
+        let entries = sqlx::query_as::<_, AuthorizationRequestEntry>(
+            r#"
+            SELECT * FROM authorization_request_log
+            WHERE (?1 IS NULL OR client_id = ?1) AND (?2 IS NULL OR user_id = ?2)
+                AND (?3 IS NULL OR flow_id = ?3)
+            ORDER BY created_at DESC
+            LIMIT ?4
+            "#,
+        )
+        .bind(client_id)
+        .bind(user_id)
+        .bind(flow_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(entries)
+    }
+
+    // Stats operations
+    /// Add counts, keyed by bucket start, metric and dimension, to the
+    /// buckets of each granularity, all in one transaction
+    pub async fn add_stats_rollup(
+        &self,
+        rollup: &[(StatsGranularity, &StatsCounts)],
+    ) -> Result<(), OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        for (granularity, counts) in rollup {
+            for ((bucket_start, metric, dimension), count) in counts.iter() {
+                sqlx::query(
+                    r#"
+                    INSERT INTO stats_buckets (granularity, bucket_start, metric, dimension, count)
+                    VALUES (?, ?, ?, ?, ?)
+                    ON CONFLICT (granularity, bucket_start, metric, dimension)
+                    DO UPDATE SET count = count + excluded.count
+                    "#,
+                )
+                .bind(granularity.as_str())
+                .bind(bucket_start)
+                .bind(metric)
+                .bind(dimension)
+                .bind(count)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
         Ok(())
     }
+
+    /// Buckets starting in `[from, to)`, oldest first, optionally for one metric
+    pub async fn list_stats(
+        &self,
+        granularity: StatsGranularity,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        metric: Option<&str>,
+    ) -> Result<Vec<StatsBucket>, OAuth2Error> {
+        let buckets = sqlx::query_as::<_, StatsBucket>(
+            r#"
+            SELECT bucket_start, metric, dimension, count FROM stats_buckets
+            WHERE granularity = ? AND bucket_start >= ? AND bucket_start < ?
+                AND (? IS NULL OR metric = ?)
+            ORDER BY bucket_start, metric, dimension
+            "#,
+        )
+        .bind(granularity.as_str())
+        .bind(from)
+        .bind(to)
+        .bind(metric)
+        .bind(metric)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(buckets)
+    }
+
+    // Organization operations
+    pub async fn save_organization(&self, org: &Organization) -> Result<(), OAuth2Error> {
+        sqlx::query("INSERT INTO organizations (id, name, created_at) VALUES (?, ?, ?)")
+            .bind(&org.id)
+            .bind(&org.name)
+            .bind(org.created_at)
+            .execute(&self.writer)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_organization(&self, id: &str) -> Result<Option<Organization>, OAuth2Error> {
+        let org = sqlx::query_as::<_, Organization>("SELECT * FROM organizations WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(org)
+    }
+
+    pub async fn get_organization_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Option<Organization>, OAuth2Error> {
+        let org = sqlx::query_as::<_, Organization>("SELECT * FROM organizations WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(org)
+    }
+
+    pub async fn list_organizations(&self) -> Result<Vec<Organization>, OAuth2Error> {
+        let orgs = sqlx::query_as::<_, Organization>("SELECT * FROM organizations ORDER BY name")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(orgs)
+    }
+
+    /// The organization of a local user (by id) or federated user (by
+    /// `provider:id` subject)
+    pub async fn get_member_org(&self, subject: &str) -> Result<Option<String>, OAuth2Error> {
+        let org = sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT owner_org FROM users WHERE id = ? AND owner_org IS NOT NULL
+            UNION ALL
+            SELECT org_id FROM organization_members WHERE subject = ?
+            LIMIT 1
+            "#,
+        )
+        .bind(subject)
+        .bind(subject)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(org)
+    }
+
+    /// Put a user in an organization, or take them out of it with `None`.
+    /// Local users are recorded on their users row, federated ones in
+    /// organization_members.
+    pub async fn set_member_org(
+        &self,
+        subject: &str,
+        org_id: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<(), OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        let local = sqlx::query("UPDATE users SET owner_org = ?, updated_at = ? WHERE id = ?")
+            .bind(org_id)
+            .bind(now)
+            .bind(subject)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+
+        sqlx::query("DELETE FROM organization_members WHERE subject = ?")
+            .bind(subject)
+            .execute(&mut *tx)
+            .await?;
+        if let (false, Some(org_id)) = (local, org_id) {
+            sqlx::query(
+                "INSERT INTO organization_members (subject, org_id, created_at) VALUES (?, ?, ?)",
+            )
+            .bind(subject)
+            .bind(org_id)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // User operations
+    pub async fn save_user(&self, user: &User) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username, password_hash, email, enabled, created_at, updated_at, owner_org)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&user.id)
+        .bind(&user.username)
+        .bind(&user.password_hash)
+        .bind(&user.email)
+        .bind(user.enabled)
+        .bind(user.created_at)
+        .bind(user.updated_at)
+        .bind(&user.owner_org)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    /// Create a user row for `id` unless there is one. Codes and tokens
+    /// reference users, so a federated subject needs a row before it can be
+    /// issued any; the row has no usable password.
+    pub async fn ensure_user(
+        &self,
+        id: &str,
+        email: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, username, password_hash, email, enabled, created_at, updated_at)
+            VALUES (?, ?, '', ?, 1, ?, ?)
+            ON CONFLICT(id) DO NOTHING
+            "#,
+        )
+        .bind(id)
+        .bind(id)
+        .bind(email)
+        .bind(now)
+        .bind(now)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<User>, OAuth2Error> {
+        let users = sqlx::query_as::<_, User>("SELECT * FROM users ORDER BY username")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(users)
+    }
+
+    /// Set a user's email and whether they may sign in. Disabling through
+    /// here revokes nothing; use [`Database::disable_user`] for that.
+    pub async fn update_user_state(
+        &self,
+        id: &str,
+        email: &str,
+        enabled: bool,
+        now: DateTime<Utc>,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query("UPDATE users SET email = ?, enabled = ?, updated_at = ? WHERE id = ?")
+            .bind(email)
+            .bind(enabled)
+            .bind(now)
+            .bind(id)
+            .execute(&self.writer)
+            .await?;
+        Ok(())
+    }
+
+    /// Delete a local user, revoking everything they hold first. Token rows
+    /// stay, revoked, for their counts. Returns `None` if there is no such
+    /// user.
+    pub async fn delete_user(
+        &self,
+        id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<RevokedCredentials>, OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        let revoked = revoke_credentials(&mut tx, id, "user_deleted", now).await?;
+        // Codes reference users(id)
+        sqlx::query("DELETE FROM authorization_codes WHERE user_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let result = sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        tx.commit().await?;
+        Ok(Some(revoked))
+    }
+
+    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<User>, OAuth2Error> {
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = ?")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(user)
+    }
+
+    pub async fn get_user(&self, id: &str) -> Result<Option<User>, OAuth2Error> {
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(user)
+    }
+
+    /// Disable a user and revoke everything they hold. Returns `None` if
+    /// there is no such user.
+    pub async fn disable_user(
+        &self,
+        id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<RevokedCredentials>, OAuth2Error> {
+        let mut tx = self.begin().await?;
+        let revoked = tx.disable_user(id, now).await?;
+        tx.commit().await?;
+        Ok(revoked)
+    }
+
+    /// Replace a user's password hash and revoke everything issued under the
+    /// old password. Returns `None` if there is no such user.
+    pub async fn update_user_password(
+        &self,
+        id: &str,
+        password_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<RevokedCredentials>, OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        let result = sqlx::query("UPDATE users SET password_hash = ?, updated_at = ? WHERE id = ?")
+            .bind(password_hash)
+            .bind(now)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let revoked = revoke_credentials(&mut tx, id, "password_changed", now).await?;
+        tx.commit().await?;
+        Ok(Some(revoked))
+    }
+
+    /// Revoke everything a user holds without changing their account
+    pub async fn revoke_user_credentials(
+        &self,
+        user_id: &str,
+        reason: &str,
+        now: DateTime<Utc>,
+    ) -> Result<RevokedCredentials, OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        let revoked = revoke_credentials(&mut tx, user_id, reason, now).await?;
+        tx.commit().await?;
+        Ok(revoked)
+    }
+
+    /// When the user's sessions were last revoked, if ever
+    pub async fn get_user_revoked_at(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<DateTime<Utc>>, OAuth2Error> {
+        let revoked_at =
+            sqlx::query_scalar("SELECT revoked_at FROM user_revocations WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(revoked_at)
+    }
+
+    // Account deletion operations
+    /// Schedule an erasure. A deletion already pending is kept as it is and
+    /// returned instead.
+    pub async fn save_account_deletion(
+        &self,
+        deletion: &AccountDeletion,
+    ) -> Result<AccountDeletion, OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO account_deletions (subject, requested_by, requested_at, erase_after, erased_at)
+            VALUES (?, ?, ?, ?, NULL)
+            ON CONFLICT (subject) DO NOTHING
+            "#,
+        )
+        .bind(&deletion.subject)
+        .bind(&deletion.requested_by)
+        .bind(deletion.requested_at)
+        .bind(deletion.erase_after)
+        .execute(&self.writer)
+        .await?;
+        self.get_account_deletion(&deletion.subject)
+            .await?
+            .ok_or_else(|| OAuth2Error::internal(ErrorCode::ServerError, "deletion not saved"))
+    }
+
+    /// The subject's pending deletion, if any
+    pub async fn get_account_deletion(
+        &self,
+        subject: &str,
+    ) -> Result<Option<AccountDeletion>, OAuth2Error> {
+        let deletion = sqlx::query_as::<_, AccountDeletion>(
+            "SELECT * FROM account_deletions WHERE subject = ? AND erased_at IS NULL",
+        )
+        .bind(subject)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(deletion)
+    }
+
+    /// Call off a pending deletion and re-enable the account. Returns false
+    /// if none was pending.
+    pub async fn cancel_account_deletion(
+        &self,
+        subject: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        let cancelled =
+            sqlx::query("DELETE FROM account_deletions WHERE subject = ? AND erased_at IS NULL")
+                .bind(subject)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                > 0;
+        if cancelled {
+            sqlx::query("UPDATE users SET enabled = 1, updated_at = ? WHERE id = ?")
+                .bind(now)
+                .bind(subject)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(cancelled)
+    }
+
+    /// Subjects whose grace period is over
+    pub async fn list_due_account_deletions(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, OAuth2Error> {
+        let subjects = sqlx::query_scalar(
+            "SELECT subject FROM account_deletions WHERE erased_at IS NULL AND erase_after <= ?",
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(subjects)
+    }
+
+    /// Whether anything is held for a local user id or federated subject
+    pub async fn subject_exists(&self, subject: &str) -> Result<bool, OAuth2Error> {
+        let exists = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (SELECT 1 FROM users WHERE id = ?1)
+                OR EXISTS (SELECT 1 FROM tokens WHERE user_id = ?1)
+                OR EXISTS (SELECT 1 FROM mfa_totp WHERE subject = ?1)
+                OR EXISTS (SELECT 1 FROM organization_members WHERE subject = ?1)
+                OR EXISTS (SELECT 1 FROM clients WHERE owner_id = ?1)
+            "#,
+        )
+        .bind(subject)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(exists)
+    }
+
+    /// Anonymize everything held about `subject`, replacing it with
+    /// `pseudonym` where a record has to stay for its counts. Profile data,
+    /// second factors, devices, memberships and consents are deleted; token
+    /// rows, audit entries and authorization requests are kept with the
+    /// identity and token values removed.
+    /// The subject stays on the session revocation list so sessions signed
+    /// in before the erasure stay signed out.
+    /// Audit entries changed are sealed again into `chain`.
+    pub async fn erase_subject(
+        &self,
+        subject: &str,
+        pseudonym: &str,
+        now: DateTime<Utc>,
+        chain: &AuditChain,
+    ) -> Result<ErasureReport, OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        let mut report = ErasureReport {
+            pseudonym: pseudonym.to_string(),
+            ..Default::default()
+        };
+
+        // Codes reference users(id), so they go first
+        report.authorization_codes =
+            sqlx::query("DELETE FROM authorization_codes WHERE user_id = ?")
+                .bind(subject)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        // JWT access and refresh tokens name their subject, so the values go
+        report.tokens = sqlx::query(
+            r#"
+            UPDATE tokens SET user_id = ?, access_token = 'erased:' || id, refresh_token = NULL,
+                revoked = 1, revoked_at = COALESCE(revoked_at, ?)
+            WHERE user_id = ?
+            "#,
+        )
+        .bind(pseudonym)
+        .bind(now)
+        .bind(subject)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        deny_revoked_jtis(&mut tx, now).await?;
+        report.users = sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(subject)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        report.owned_clients = sqlx::query("UPDATE clients SET owner_id = ? WHERE owner_id = ?")
+            .bind(pseudonym)
+            .bind(subject)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        for table in [
+            "mfa_totp",
+            "trusted_devices",
+            "organization_members",
+            "upstream_tokens",
+            "user_consents",
+        ] {
+            report.deleted_records +=
+                sqlx::query(&format!("DELETE FROM {table} WHERE subject = ?"))
+                    .bind(subject)
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+        }
+        sqlx::query("UPDATE user_revocations SET reason = 'account_erased' WHERE user_id = ?")
+            .bind(subject)
+            .execute(&mut *tx)
+            .await?;
+
+        // Audit entries by the subject, or naming it in their path or
+        // parameters, keep the action but lose the identity and IP address
+        let actor = format!("user:{}", subject);
+        let entries = sqlx::query_as::<_, AdminAuditEntry>(
+            "SELECT * FROM admin_audit_log WHERE actor = ? OR instr(path, ?) > 0 OR instr(params, ?) > 0",
+        )
+        .bind(&actor)
+        .bind(subject)
+        .bind(subject)
+        .fetch_all(&mut *tx)
+        .await?;
+        let reseal_from = entries.iter().filter_map(|entry| entry.seq).min();
+        if let Some(from) = reseal_from {
+            check_audit_chain_from(&mut tx, chain, from).await?;
+        }
+        for entry in entries {
+            let by_subject = entry.actor == actor;
+            let params = match serde_json::from_str::<serde_json::Value>(&entry.params) {
+                Ok(mut params) => {
+                    replace_json_strings(&mut params, subject, pseudonym);
+                    params.to_string()
+                }
+                Err(_) => entry.params,
+            };
+            sqlx::query(
+                "UPDATE admin_audit_log SET actor = ?, path = ?, params = ?, client_ip = ? WHERE id = ?",
+            )
+            .bind(if by_subject {
+                format!("user:{}", pseudonym)
+            } else {
+                entry.actor
+            })
+            .bind(replace_path_segment(&entry.path, subject, pseudonym))
+            .bind(params)
+            .bind(if by_subject { None } else { entry.client_ip })
+            .bind(&entry.id)
+            .execute(&mut *tx)
+            .await?;
+            report.audit_entries += 1;
+        }
+        if let Some(from) = reseal_from {
+            reseal_audit_chain_from(&mut tx, chain, from).await?;
+        }
+        report.audit_entries += sqlx::query(
+            "UPDATE authorization_request_log SET user_id = ?, client_ip = NULL WHERE user_id = ?",
+        )
+        .bind(pseudonym)
+        .bind(subject)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        // Events not yet published get anonymized before they go out, and
+        // the subject's event numbering carries on under the pseudonym
+        let pending = sqlx::query_as::<_, (i64, String)>(
+            "SELECT seq, event FROM event_outbox WHERE instr(event, ?) > 0",
+        )
+        .bind(subject)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (seq, json) in pending {
+            let Ok(mut event) = serde_json::from_str::<AuthEvent>(&json) else {
+                continue;
+            };
+            event.anonymize(subject, pseudonym);
+            sqlx::query("UPDATE event_outbox SET event = ? WHERE seq = ?")
+                .bind(
+                    serde_json::to_string(&event)
+                        .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?,
+                )
+                .bind(seq)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query("UPDATE event_sequences SET aggregate = ? WHERE aggregate = ?")
+            .bind(format!("user:{}", pseudonym))
+            .bind(format!("user:{}", subject))
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(
+            "UPDATE account_deletions SET subject = ?, erased_at = ? WHERE subject = ? AND erased_at IS NULL",
+        )
+        .bind(pseudonym)
+        .bind(now)
+        .bind(subject)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    // Token operations
+    pub async fn save_token(&self, token: &Token) -> Result<(), OAuth2Error> {
+        self.save_token_with_event(token, None).await
+    }
+
+    /// Save a token, recording `event` in the outbox in the same transaction
+    pub async fn save_token_with_event(
+        &self,
+        token: &Token,
+        event: Option<&AuthEvent>,
+    ) -> Result<(), OAuth2Error> {
+        let mut tx = self.begin().await?;
+        tx.save_token(token, event).await?;
+        tx.commit().await
+    }
+
+    pub async fn get_token_by_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<Token>, OAuth2Error> {
+        let token = sqlx::query_as::<_, Token>("SELECT * FROM tokens WHERE refresh_token = ?")
+            .bind(refresh_token)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(token)
+    }
+
+    /// Add batched uses to tokens (by id) and clients (by client_id).
+    /// `last_used_at` only ever moves forward.
+    pub async fn save_usage(
+        &self,
+        tokens: &HashMap<String, UsageCount>,
+        clients: &HashMap<String, UsageCount>,
+    ) -> Result<(), OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        for (table, key, usage) in [("tokens", "id", tokens), ("clients", "client_id", clients)] {
+            let query = format!(
+                r#"
+                UPDATE {table} SET
+                    use_count = use_count + ?,
+                    last_used_at = CASE
+                        WHEN last_used_at IS NULL OR last_used_at < ? THEN ?
+                        ELSE last_used_at
+                    END
+                WHERE {key} = ?
+                "#
+            );
+            for (id, usage) in usage {
+                sqlx::query(&query)
+                    .bind(usage.count)
+                    .bind(usage.last_used_at)
+                    .bind(usage.last_used_at)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Clients unused since `cutoff`, including ones created before it and
+    /// never used, least recently used first
+    pub async fn list_stale_clients(
+        &self,
+        cutoff: DateTime<Utc>,
+    ) -> Result<Vec<StaleClient>, OAuth2Error> {
+        let clients = sqlx::query_as::<_, StaleClient>(
+            r#"
+            SELECT client_id, name, owner_id, owner_org, created_at, last_used_at, use_count
+            FROM clients
+            WHERE COALESCE(last_used_at, created_at) < ?
+            ORDER BY COALESCE(last_used_at, created_at)
+            "#,
+        )
+        .bind(cutoff)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(clients)
+    }
+
+    /// Unrevoked tokens, still usable or refreshable at `now`, that have not
+    /// been used since `cutoff`
+    pub async fn list_unused_tokens(
+        &self,
+        cutoff: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<UnusedToken>, OAuth2Error> {
+        let tokens = sqlx::query_as::<_, UnusedToken>(
+            r#"
+            SELECT id, client_id, user_id, scope, created_at, expires_at, last_used_at, use_count,
+                refresh_token IS NOT NULL
+                    AND (refresh_expires_at IS NULL OR refresh_expires_at > ?1) AS refreshable
+            FROM tokens
+            WHERE revoked = 0
+                AND COALESCE(last_used_at, created_at) < ?2
+                AND (expires_at > ?1 OR (
+                    refresh_token IS NOT NULL
+                    AND (refresh_expires_at IS NULL OR refresh_expires_at > ?1)
+                ))
+            ORDER BY COALESCE(last_used_at, created_at)
+            "#,
+        )
+        .bind(now)
+        .bind(cutoff)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    pub async fn get_token_by_access_token(
+        &self,
+        access_token: &str,
+    ) -> Result<Option<Token>, OAuth2Error> {
+        let token = sqlx::query_as::<_, Token>("SELECT * FROM tokens WHERE access_token = ?")
+            .bind(stored_access_token(access_token))
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(token)
+    }
+
+    pub async fn list_recent_tokens_for_client(
+        &self,
+        client_id: &str,
+        limit: i64,
+    ) -> Result<Vec<Token>, OAuth2Error> {
+        let tokens = sqlx::query_as::<_, Token>(
+            "SELECT * FROM tokens WHERE client_id = ? ORDER BY created_at DESC LIMIT ?",
+        )
+        .bind(client_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    /// Revoke the access or refresh token `token` together with the rest of
+    /// its pair, recording `event` in the outbox with the revocation
+    pub async fn revoke_token(
+        &self,
+        token: &str,
+        now: DateTime<Utc>,
+        event: Option<&AuthEvent>,
+    ) -> Result<(), OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        sqlx::query(
+            r#"
+            UPDATE tokens SET revoked = 1, revoked_at = COALESCE(revoked_at, ?)
+            WHERE access_token = ? OR refresh_token = ?
+            "#,
+        )
+        .bind(now)
+        .bind(stored_access_token(token))
+        .bind(token)
+        .execute(&mut *tx)
+        .await?;
+        deny_revoked_jtis(&mut tx, now).await?;
+        if let Some(event) = event {
+            enqueue_event(&mut tx, event).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Whether the access token with this `jti` has been revoked
+    pub async fn is_jti_revoked(&self, jti: &str) -> Result<bool, OAuth2Error> {
+        let revoked: Option<i64> = sqlx::query_scalar("SELECT 1 FROM revoked_jtis WHERE jti = ?")
+            .bind(jti)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(revoked.is_some())
+    }
+
+    /// Live impersonation tokens, newest first
+    pub async fn list_impersonation_tokens(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Token>, OAuth2Error> {
+        let tokens = sqlx::query_as::<_, Token>(
+            r#"
+            SELECT * FROM tokens
+            WHERE impersonator IS NOT NULL AND revoked = 0 AND expires_at > ?
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    /// Revoke every impersonation token, or only those minted by
+    /// `impersonator`. Returns the ones that were still live.
+    pub async fn revoke_impersonation_tokens(
+        &self,
+        impersonator: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Token>, OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        let tokens = sqlx::query_as::<_, Token>(
+            r#"
+            UPDATE tokens SET revoked = 1, revoked_at = ?1
+            WHERE impersonator IS NOT NULL AND revoked = 0
+              AND (?2 IS NULL OR impersonator = ?2)
+            RETURNING *
+            "#,
+        )
+        .bind(now)
+        .bind(impersonator)
+        .fetch_all(&mut *tx)
+        .await?;
+        deny_revoked_jtis(&mut tx, now).await?;
+        tx.commit().await?;
+        Ok(tokens)
+    }
+
+    /// Revoke every token exchanged for the authorization code `code_id`,
+    /// and every token rotated from them, because the code was replayed.
+    /// Returns every token that came from the code, revoked before or not.
+    pub async fn revoke_authorization_code_tokens(
+        &self,
+        code_id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Token>, OAuth2Error> {
+        let mut tx = self.writer.begin().await?;
+        let tokens = sqlx::query_as::<_, Token>(
+            r#"
+            UPDATE tokens SET revoked = 1, revoked_at = COALESCE(revoked_at, ?)
+            WHERE authorization_code_id = ?
+            RETURNING *
+            "#,
+        )
+        .bind(now)
+        .bind(code_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        deny_revoked_jtis(&mut tx, now).await?;
+        tx.commit().await?;
+        Ok(tokens)
+    }
+
+    // Authorization code operations
+    pub async fn save_authorization_code(
+        &self,
+        auth_code: &AuthorizationCode,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO authorization_codes (id, code, client_id, user_id, redirect_uri, scope, created_at, expires_at, used, code_challenge, code_challenge_method, nonce, auth_time, flow_id)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&auth_code.id)
+        .bind(&auth_code.code)
+        .bind(&auth_code.client_id)
+        .bind(&auth_code.user_id)
+        .bind(&auth_code.redirect_uri)
+        .bind(&auth_code.scope)
+        .bind(auth_code.created_at)
+        .bind(auth_code.expires_at)
+        .bind(auth_code.used)
+        .bind(&auth_code.code_challenge)
+        .bind(&auth_code.code_challenge_method)
+        .bind(&auth_code.nonce)
+        .bind(auth_code.auth_time)
+        .bind(&auth_code.flow_id)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_authorization_code(
+        &self,
+        code: &str,
+    ) -> Result<Option<AuthorizationCode>, OAuth2Error> {
+        let auth_code = sqlx::query_as::<_, AuthorizationCode>(
+            "SELECT * FROM authorization_codes WHERE code = ?",
+        )
+        .bind(code)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(auth_code)
+    }
+
+    // MFA operations
+    pub async fn save_totp_enrollment(
+        &self,
+        enrollment: &TotpEnrollment,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO mfa_totp (subject, secret, last_used_step, created_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(subject) DO UPDATE SET
+                secret = excluded.secret,
+                last_used_step = excluded.last_used_step,
+                created_at = excluded.created_at
+            "#,
+        )
+        .bind(&enrollment.subject)
+        .bind(&enrollment.secret)
+        .bind(enrollment.last_used_step)
+        .bind(enrollment.created_at)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_totp_enrollment(
+        &self,
+        subject: &str,
+    ) -> Result<Option<TotpEnrollment>, OAuth2Error> {
+        let enrollment =
+            sqlx::query_as::<_, TotpEnrollment>("SELECT * FROM mfa_totp WHERE subject = ?")
+                .bind(subject)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(enrollment)
+    }
+
+    /// Record an accepted code's time step. Returns false when the same or a
+    /// later step was already used, i.e. the code is being replayed.
+    pub async fn advance_totp_step(&self, subject: &str, step: i64) -> Result<bool, OAuth2Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE mfa_totp SET last_used_step = ?
+            WHERE subject = ? AND (last_used_step IS NULL OR last_used_step < ?)
+            "#,
+        )
+        .bind(step)
+        .bind(subject)
+        .bind(step)
+        .execute(&self.writer)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn delete_totp_enrollment(&self, subject: &str) -> Result<(), OAuth2Error> {
+        sqlx::query("DELETE FROM mfa_totp WHERE subject = ?")
+            .bind(subject)
+            .execute(&self.writer)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn save_trusted_device(&self, device: &TrustedDevice) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO trusted_devices (id, subject, label, created_at, expires_at, last_used_at, revoked)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&device.id)
+        .bind(&device.subject)
+        .bind(&device.label)
+        .bind(device.created_at)
+        .bind(device.expires_at)
+        .bind(device.last_used_at)
+        .bind(device.revoked)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_trusted_device(&self, id: &str) -> Result<Option<TrustedDevice>, OAuth2Error> {
+        let device =
+            sqlx::query_as::<_, TrustedDevice>("SELECT * FROM trusted_devices WHERE id = ?")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(device)
+    }
+
+    /// Unrevoked devices, newest first. Expired ones are included.
+    pub async fn list_trusted_devices(
+        &self,
+        subject: &str,
+    ) -> Result<Vec<TrustedDevice>, OAuth2Error> {
+        let devices = sqlx::query_as::<_, TrustedDevice>(
+            "SELECT * FROM trusted_devices WHERE subject = ? AND revoked = 0 ORDER BY created_at DESC",
+        )
+        .bind(subject)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(devices)
+    }
+
+    pub async fn touch_trusted_device(
+        &self,
+        id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query("UPDATE trusted_devices SET last_used_at = ? WHERE id = ?")
+            .bind(now)
+            .bind(id)
+            .execute(&self.writer)
+            .await?;
+        Ok(())
+    }
+
+    /// Revoke one of a user's devices. Returns false if the user has no such device.
+    pub async fn revoke_trusted_device(
+        &self,
+        subject: &str,
+        id: &str,
+    ) -> Result<bool, OAuth2Error> {
+        let result =
+            sqlx::query("UPDATE trusted_devices SET revoked = 1 WHERE id = ? AND subject = ?")
+                .bind(id)
+                .bind(subject)
+                .execute(&self.writer)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn revoke_all_trusted_devices(&self, subject: &str) -> Result<(), OAuth2Error> {
+        sqlx::query("UPDATE trusted_devices SET revoked = 1 WHERE subject = ?")
+            .bind(subject)
+            .execute(&self.writer)
+            .await?;
+        Ok(())
+    }
+
+    // Upstream token operations
+
+    /// Store a user's tokens for a provider, replacing earlier ones. A
+    /// provider that sends no new refresh token leaves the old one in place.
+    pub async fn save_upstream_token(&self, token: &UpstreamToken) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO upstream_tokens
+                (subject, provider, access_token, refresh_token, scope, expires_at, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (subject, provider) DO UPDATE SET
+                access_token = excluded.access_token,
+                refresh_token = COALESCE(excluded.refresh_token, upstream_tokens.refresh_token),
+                scope = excluded.scope,
+                expires_at = excluded.expires_at,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(&token.subject)
+        .bind(&token.provider)
+        .bind(&token.access_token)
+        .bind(&token.refresh_token)
+        .bind(&token.scope)
+        .bind(token.expires_at)
+        .bind(token.created_at)
+        .bind(token.updated_at)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_upstream_token(
+        &self,
+        subject: &str,
+        provider: &str,
+    ) -> Result<Option<UpstreamToken>, OAuth2Error> {
+        let token = sqlx::query_as::<_, UpstreamToken>(
+            "SELECT * FROM upstream_tokens WHERE subject = ? AND provider = ?",
+        )
+        .bind(subject)
+        .bind(provider)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(token)
+    }
+
+    pub async fn list_upstream_tokens(
+        &self,
+        subject: &str,
+    ) -> Result<Vec<UpstreamToken>, OAuth2Error> {
+        let tokens = sqlx::query_as::<_, UpstreamToken>(
+            "SELECT * FROM upstream_tokens WHERE subject = ? ORDER BY provider",
+        )
+        .bind(subject)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    /// Record the profile a connected provider returned
+    pub async fn save_upstream_profile(
+        &self,
+        profile: &SocialUserInfo,
+        synced_at: DateTime<Utc>,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            UPDATE upstream_tokens SET email = ?, name = ?, picture = ?, profile_synced_at = ?
+            WHERE subject = ? AND provider = ?
+            "#,
+        )
+        .bind(&profile.email)
+        .bind(&profile.name)
+        .bind(&profile.picture)
+        .bind(synced_at)
+        .bind(profile.subject())
+        .bind(&profile.provider)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    /// Mark a connection's profile as checked without changing it, so one
+    /// that keeps failing waits its turn like the rest
+    pub async fn touch_upstream_profile(
+        &self,
+        subject: &str,
+        provider: &str,
+        synced_at: DateTime<Utc>,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            "UPDATE upstream_tokens SET profile_synced_at = ? WHERE subject = ? AND provider = ?",
+        )
+        .bind(synced_at)
+        .bind(subject)
+        .bind(provider)
+        .execute(&self.writer)
+        .await?;
+        Ok(())
+    }
+
+    /// Connections whose profile was last synced before `cutoff`, or never,
+    /// least recently synced first
+    pub async fn list_upstream_profiles_due(
+        &self,
+        cutoff: DateTime<Utc>,
+        limit: u32,
+    ) -> Result<Vec<UpstreamToken>, OAuth2Error> {
+        let tokens = sqlx::query_as::<_, UpstreamToken>(
+            r#"
+            SELECT * FROM upstream_tokens
+            WHERE profile_synced_at IS NULL OR profile_synced_at < ?
+            ORDER BY profile_synced_at IS NOT NULL, profile_synced_at
+            LIMIT ?
+            "#,
+        )
+        .bind(cutoff)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tokens)
+    }
+
+    /// Delete a user's tokens for one provider, or for all of them. Returns
+    /// how many were deleted.
+    pub async fn delete_upstream_tokens(
+        &self,
+        subject: &str,
+        provider: Option<&str>,
+    ) -> Result<u64, OAuth2Error> {
+        let result = sqlx::query(
+            "DELETE FROM upstream_tokens WHERE subject = ?1 AND (?2 IS NULL OR provider = ?2)",
+        )
+        .bind(subject)
+        .bind(provider)
+        .execute(&self.writer)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Retention operations
+
+    /// Admin audit entries recorded before `cutoff`
+    pub async fn purge_admin_audit_entries(
+        &self,
+        cutoff: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<u64, OAuth2Error> {
+        self.purge("admin_audit_log", "created_at < ?1", cutoff, dry_run)
+            .await
+    }
+
+    /// Authorization request log entries recorded before `cutoff`
+    pub async fn purge_authorization_requests(
+        &self,
+        cutoff: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<u64, OAuth2Error> {
+        self.purge(
+            "authorization_request_log",
+            "created_at < ?1",
+            cutoff,
+            dry_run,
+        )
+        .await
+    }
+
+    /// Tokens revoked before `cutoff`, or that could no longer be used or
+    /// refreshed by then. Refresh tokens from before expiry tracking have no
+    /// recorded expiry and are kept until revoked.
+    pub async fn purge_tokens(
+        &self,
+        cutoff: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<u64, OAuth2Error> {
+        self.purge(
+            "tokens",
+            r#"(revoked = 1 AND COALESCE(revoked_at, created_at) < ?1)
+                OR (expires_at < ?1 AND (refresh_token IS NULL OR refresh_expires_at < ?1))"#,
+            cutoff,
+            dry_run,
+        )
+        .await
+    }
+
+    /// Authorization codes, used or not, that expired before `cutoff`
+    pub async fn purge_authorization_codes(
+        &self,
+        cutoff: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<u64, OAuth2Error> {
+        self.purge("authorization_codes", "expires_at < ?1", cutoff, dry_run)
+            .await
+    }
+
+    /// Delete the rows of `table` matching `condition`, whose one parameter
+    /// is the cutoff, or on a dry run just count them
+    async fn purge(
+        &self,
+        table: &str,
+        condition: &str,
+        cutoff: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<u64, OAuth2Error> {
+        if dry_run {
+            let count: i64 =
+                sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table} WHERE {condition}"))
+                    .bind(cutoff)
+                    .fetch_one(&self.pool)
+                    .await?;
+            return Ok(count as u64);
+        }
+        let purged = sqlx::query(&format!("DELETE FROM {table} WHERE {condition}"))
+            .bind(cutoff)
+            .execute(&self.writer)
+            .await?
+            .rows_affected();
+        Ok(purged)
+    }
+}
+
+/// Audit entries from `seq` on, in chain order, with the hash of the entry
+/// before them
+async fn audit_chain_from(
+    tx: &mut Transaction<'_, Sqlite>,
+    seq: i64,
+) -> Result<(String, Vec<AdminAuditEntry>), OAuth2Error> {
+    let prev_hash: Option<String> =
+        sqlx::query_scalar("SELECT hash FROM admin_audit_log WHERE seq = ?")
+            .bind(seq - 1)
+            .fetch_optional(&mut **tx)
+            .await?
+            .flatten();
+    let entries = sqlx::query_as::<_, AdminAuditEntry>(
+        "SELECT * FROM admin_audit_log WHERE seq >= ? ORDER BY seq",
+    )
+    .bind(seq)
+    .fetch_all(&mut **tx)
+    .await?;
+    // Without the entry before, as after retention, the first one's link
+    // is kept as it is
+    let prev_hash = prev_hash
+        .or_else(|| entries.first().and_then(|entry| entry.prev_hash.clone()))
+        .unwrap_or_default();
+    Ok((prev_hash, entries))
+}
+
+/// Report tampering with the entries an erasure is about to seal again,
+/// since resealing would hide it from later checks
+async fn check_audit_chain_from(
+    tx: &mut Transaction<'_, Sqlite>,
+    chain: &AuditChain,
+    seq: i64,
+) -> Result<(), OAuth2Error> {
+    let (_, entries) = audit_chain_from(tx, seq).await?;
+    let verification = chain.verify(&entries);
+    if !verification.valid {
+        tracing::error!(
+            "Admin audit log was tampered with before an erasure resealed it: entry {:?} {}",
+            verification.broken_at,
+            verification.problem.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Seal the audit entries from `seq` on again, after some were changed
+async fn reseal_audit_chain_from(
+    tx: &mut Transaction<'_, Sqlite>,
+    chain: &AuditChain,
+    seq: i64,
+) -> Result<(), OAuth2Error> {
+    let (mut prev_hash, entries) = audit_chain_from(tx, seq).await?;
+    for entry in entries {
+        let hash = chain.seal(&prev_hash, &entry);
+        sqlx::query("UPDATE admin_audit_log SET prev_hash = ?, hash = ? WHERE id = ?")
+            .bind(&prev_hash)
+            .bind(&hash)
+            .bind(&entry.id)
+            .execute(&mut **tx)
+            .await?;
+        prev_hash = hash;
+    }
+    Ok(())
+}
+
+/// Record `event` in the outbox, to be published once the transaction
+/// commits, numbering it among the events about its user and its client
+async fn enqueue_event(
+    tx: &mut Transaction<'_, Sqlite>,
+    event: &AuthEvent,
+) -> Result<(), OAuth2Error> {
+    let mut event = event.clone();
+    if let Some(user_id) = &event.user_id {
+        event.user_sequence = Some(next_event_sequence(tx, &format!("user:{}", user_id)).await?);
+    }
+    if let Some(client_id) = &event.client_id {
+        event.client_sequence =
+            Some(next_event_sequence(tx, &format!("client:{}", client_id)).await?);
+    }
+    let json = serde_json::to_string(&event)
+        .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+    sqlx::query("INSERT INTO event_outbox (event_id, event, created_at) VALUES (?, ?, ?)")
+        .bind(&event.id)
+        .bind(json)
+        .bind(event.timestamp)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+async fn next_event_sequence(
+    tx: &mut Transaction<'_, Sqlite>,
+    aggregate: &str,
+) -> Result<i64, OAuth2Error> {
+    let sequence = sqlx::query_scalar(
+        r#"
+        INSERT INTO event_sequences (aggregate, last_sequence) VALUES (?, 1)
+        ON CONFLICT (aggregate) DO UPDATE SET last_sequence = last_sequence + 1
+        RETURNING last_sequence
+        "#,
+    )
+    .bind(aggregate)
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(sequence)
+}
+
+/// Revoke a user's tokens and unused authorization codes and record the
+/// revocation so their existing browser sessions end too
+async fn revoke_credentials(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: &str,
+    reason: &str,
+    now: DateTime<Utc>,
+) -> Result<RevokedCredentials, OAuth2Error> {
+    let revoked_tokens = sqlx::query_as::<_, Token>(
+        r#"
+        UPDATE tokens SET revoked = 1, revoked_at = ? WHERE user_id = ? AND revoked = 0
+        RETURNING *
+        "#,
+    )
+    .bind(now)
+    .bind(user_id)
+    .fetch_all(&mut **tx)
+    .await?;
+    deny_revoked_jtis(tx, now).await?;
+    let authorization_codes =
+        sqlx::query("UPDATE authorization_codes SET used = 1 WHERE user_id = ? AND used = 0")
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await?
+            .rows_affected();
+    sqlx::query(
+        r#"
+        INSERT INTO user_revocations (user_id, revoked_at, reason)
+        VALUES (?, ?, ?)
+        ON CONFLICT (user_id) DO UPDATE SET revoked_at = excluded.revoked_at, reason = excluded.reason
+        "#,
+    )
+    .bind(user_id)
+    .bind(now)
+    .bind(reason)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(RevokedCredentials {
+        tokens: revoked_tokens.len() as u64,
+        authorization_codes,
+        revoked_tokens,
+    })
+}
+
+/// Add the JWT access tokens revoked at `now` to the jti denylist, and drop
+/// entries for tokens that have expired, which nobody would accept anyway
+async fn deny_revoked_jtis(
+    tx: &mut Transaction<'_, Sqlite>,
+    now: DateTime<Utc>,
+) -> Result<(), OAuth2Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO revoked_jtis (jti, expires_at)
+        SELECT jti, expires_at FROM tokens
+        WHERE revoked_at = ?1 AND jti IS NOT NULL AND expires_at > ?1
+        ON CONFLICT (jti) DO NOTHING
+        "#,
+    )
+    .bind(now)
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query("DELETE FROM revoked_jtis WHERE expires_at <= ?")
+        .bind(now)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// `path` with every segment equal to `from` replaced by `to`
+fn replace_path_segment(path: &str, from: &str, to: &str) -> String {
+    path.split('/')
+        .map(|segment| if segment == from { to } else { segment })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Replace JSON strings equal to `from`, or to `user:` followed by it
+fn replace_json_strings(value: &mut serde_json::Value, from: &str, to: &str) {
+    match value {
+        serde_json::Value::String(s) if s == from => *s = to.to_string(),
+        serde_json::Value::String(s) if s.strip_prefix("user:") == Some(from) => {
+            *s = format!("user:{}", to)
+        }
+        serde_json::Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| replace_json_strings(item, from, to)),
+        serde_json::Value::Object(fields) => fields
+            .values_mut()
+            .for_each(|field| replace_json_strings(field, from, to)),
+        _ => {}
+    }
 }
@@ -0,0 +1,193 @@
+//! Storage operations that have to succeed or fail together.
+//!
+//! [`Database::begin`](super::Database::begin) opens a [`DbTransaction`];
+//! each operation on it is applied inside the transaction and nothing is
+//! visible to other connections until it is committed. Dropping it without
+//! committing, for instance by returning early with `?`, rolls everything
+//! back, so a failure halfway through a multi-step change never leaves half
+//! of it behind.
+
+use super::{enqueue_event, revoke_credentials};
+use crate::events::AuthEvent;
+use crate::models::{stored_access_token, OAuth2Error, RevokedCredentials, Token};
+use chrono::{DateTime, Utc};
+use sqlx::{Sqlite, Transaction};
+
+pub struct DbTransaction {
+    tx: Transaction<'static, Sqlite>,
+}
+
+impl DbTransaction {
+    pub(super) fn new(tx: Transaction<'static, Sqlite>) -> Self {
+        Self { tx }
+    }
+
+    /// Apply everything done in the transaction
+    pub async fn commit(self) -> Result<(), OAuth2Error> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+
+    /// Discard everything done in the transaction. Dropping it does the same;
+    /// this only makes the intent explicit.
+    pub async fn rollback(self) -> Result<(), OAuth2Error> {
+        self.tx.rollback().await?;
+        Ok(())
+    }
+
+    /// Save a token, recording `event` in the outbox with it
+    pub async fn save_token(
+        &mut self,
+        token: &Token,
+        event: Option<&AuthEvent>,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query(
+            r#"
+            INSERT INTO tokens (id, access_token, refresh_token, token_type, expires_in, scope, client_id, user_id, created_at, expires_at, revoked, refresh_expires_at, refresh_max_expires_at, last_used_at, audience, impersonator, authorization_details, cnf_jkt, cnf_x5t_s256, refresh_scope, jti, flow_id, authorization_code_id)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&token.id)
+        .bind(stored_access_token(&token.access_token))
+        .bind(&token.refresh_token)
+        .bind(&token.token_type)
+        .bind(token.expires_in)
+        .bind(&token.scope)
+        .bind(&token.client_id)
+        .bind(&token.user_id)
+        .bind(token.created_at)
+        .bind(token.expires_at)
+        .bind(token.revoked)
+        .bind(token.refresh_expires_at)
+        .bind(token.refresh_max_expires_at)
+        .bind(token.last_used_at)
+        .bind(&token.audience)
+        .bind(&token.impersonator)
+        .bind(&token.authorization_details)
+        .bind(&token.cnf_jkt)
+        .bind(&token.cnf_x5t_s256)
+        .bind(&token.refresh_scope)
+        .bind(&token.jti)
+        .bind(&token.flow_id)
+        .bind(&token.authorization_code_id)
+        .execute(&mut *self.tx)
+        .await?;
+        if let Some(event) = event {
+            enqueue_event(&mut self.tx, event).await?;
+        }
+        Ok(())
+    }
+
+    /// Detach a refresh token from its row once it has been rotated. Returns
+    /// false if another request consumed it first.
+    pub async fn consume_refresh_token(
+        &mut self,
+        id: &str,
+        refresh_token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, OAuth2Error> {
+        let result = sqlx::query(
+            "UPDATE tokens SET refresh_token = NULL, last_used_at = ? WHERE id = ? AND refresh_token = ?",
+        )
+        .bind(now)
+        .bind(id)
+        .bind(refresh_token)
+        .execute(&mut *self.tx)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Mark a code used, returning false if it already was. The check and
+    /// the update are one statement, so when replicas race on the same code
+    /// exactly one of them consumes it; the loser waits for the winner's
+    /// transaction to finish before seeing the code used.
+    pub async fn consume_authorization_code(&mut self, code: &str) -> Result<bool, OAuth2Error> {
+        let result =
+            sqlx::query("UPDATE authorization_codes SET used = 1 WHERE code = ? AND used = 0")
+                .bind(code)
+                .execute(&mut *self.tx)
+                .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Record the token an authorization code was exchanged for against the
+    /// request that issued the code
+    pub async fn link_authorization_token(
+        &mut self,
+        code_id: &str,
+        token_id: &str,
+    ) -> Result<(), OAuth2Error> {
+        sqlx::query("UPDATE authorization_request_log SET token_id = ? WHERE code_id = ?")
+            .bind(token_id)
+            .bind(code_id)
+            .execute(&mut *self.tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Delete a client together with its tokens, authorization codes and
+    /// the consents users gave it, recording `event` in the outbox with the
+    /// deletion. Its live JWT access tokens go on the jti denylist. Returns
+    /// the tokens that were still live, for caches to forget.
+    pub async fn delete_client(
+        &mut self,
+        client_id: &str,
+        event: Option<&AuthEvent>,
+    ) -> Result<Vec<Token>, OAuth2Error> {
+        sqlx::query("DELETE FROM authorization_codes WHERE client_id = ?")
+            .bind(client_id)
+            .execute(&mut *self.tx)
+            .await?;
+        // Its JWTs stay verifiable after the rows are gone
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_jtis (jti, expires_at)
+            SELECT jti, expires_at FROM tokens
+            WHERE client_id = ? AND revoked = 0 AND jti IS NOT NULL
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+        )
+        .bind(client_id)
+        .execute(&mut *self.tx)
+        .await?;
+        let live = sqlx::query_as::<_, Token>("DELETE FROM tokens WHERE client_id = ? RETURNING *")
+            .bind(client_id)
+            .fetch_all(&mut *self.tx)
+            .await?
+            .into_iter()
+            .filter(|token| !token.revoked)
+            .collect();
+        sqlx::query("DELETE FROM user_consents WHERE client_id = ?")
+            .bind(client_id)
+            .execute(&mut *self.tx)
+            .await?;
+        sqlx::query("DELETE FROM clients WHERE client_id = ?")
+            .bind(client_id)
+            .execute(&mut *self.tx)
+            .await?;
+        if let Some(event) = event {
+            enqueue_event(&mut self.tx, event).await?;
+        }
+        Ok(live)
+    }
+
+    /// Disable a user and revoke everything they hold. Returns `None` if
+    /// there is no such user.
+    pub async fn disable_user(
+        &mut self,
+        id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<RevokedCredentials>, OAuth2Error> {
+        let result = sqlx::query("UPDATE users SET enabled = 0, updated_at = ? WHERE id = ?")
+            .bind(now)
+            .bind(id)
+            .execute(&mut *self.tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let revoked = revoke_credentials(&mut self.tx, id, "user_disabled", now).await?;
+        Ok(Some(revoked))
+    }
+}
@@ -0,0 +1,336 @@
+//! Token-abuse signals derived from the event stream.
+//!
+//! [`AnomalyDetector`] is an event plugin that remembers recent client
+//! authentication outcomes, introspection results and revocations. From them
+//! it derives a failed-auth ratio per client, the introspection miss rate and
+//! whether revocations are spiking against their recent baseline, and reports
+//! whichever of those crosses its threshold.
+//!
+//! History is kept in memory, so a restart starts the baseline over.
+
+use crate::clock::SharedClock;
+use crate::config::AnomalyConfig;
+use crate::events::{AuthEvent, EventPlugin, EventType};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use utoipa::ToSchema;
+
+/// Earlier windows averaged into the revocation baseline
+const BASELINE_WINDOWS: i64 = 12;
+
+pub const ANOMALY_KINDS: [&str; 3] = [
+    "failed_auth_ratio",
+    "introspection_miss_rate",
+    "revocation_spike",
+];
+
+/// Upper bound on remembered observations, whatever the traffic
+const MAX_OBSERVATIONS: usize = 100_000;
+
+#[derive(Debug, Clone)]
+enum Observation {
+    ClientAuth { client_id: String, success: bool },
+    Introspection { active: bool },
+    Revocation { tokens: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Anomaly {
+    /// `failed_auth_ratio`, `introspection_miss_rate` or `revocation_spike`
+    pub kind: &'static str,
+    /// Set for per-client anomalies
+    pub client_id: Option<String>,
+    pub value: f64,
+    pub threshold: f64,
+    /// Observations the value is based on
+    pub sample_size: u64,
+    pub description: String,
+}
+
+/// The derived metrics over the current window
+#[derive(Debug, Clone, Default)]
+pub struct AnomalySnapshot {
+    /// Failed authentications over attempts, by client
+    pub failed_auth_ratios: BTreeMap<String, f64>,
+    pub introspection_miss_rate: f64,
+    pub revocations: u64,
+    /// Mean revocations per window over the baseline windows
+    pub revocation_baseline: f64,
+    pub anomalies: Vec<Anomaly>,
+}
+
+pub struct AnomalyDetector {
+    config: AnomalyConfig,
+    clock: SharedClock,
+    observations: Mutex<VecDeque<(DateTime<Utc>, Observation)>>,
+}
+
+impl AnomalyDetector {
+    pub fn new(config: AnomalyConfig, clock: SharedClock) -> Self {
+        Self {
+            config,
+            clock,
+            observations: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::seconds(self.config.window_secs.max(1) as i64)
+    }
+
+    /// What an event tells us, if anything
+    fn observe(event: &AuthEvent) -> Option<Observation> {
+        let metadata = |key: &str| event.metadata.get(key).map(String::as_str);
+        match event.event_type {
+            EventType::ClientValidated => Some(Observation::ClientAuth {
+                client_id: event.client_id.clone()?,
+                success: metadata("success") == Some("true"),
+            }),
+            EventType::TokenCreated => Some(Observation::ClientAuth {
+                client_id: event.client_id.clone()?,
+                success: true,
+            }),
+            EventType::TokenValidated => Some(Observation::Introspection { active: true }),
+            EventType::TokenExpired => Some(Observation::Introspection { active: false }),
+            EventType::TokenRevoked => Some(Observation::Revocation {
+                // Bulk revocations say how many tokens they covered
+                tokens: metadata("tokens")
+                    .and_then(|tokens| tokens.parse().ok())
+                    .unwrap_or(1),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Observations are stamped on arrival by our clock; event timestamps come
+    /// from the system time, which tests may not be using
+    fn record(&self, observation: Observation) {
+        let at = self.clock.now();
+        let horizon = at - self.window() * (BASELINE_WINDOWS as i32 + 1);
+        let mut observations = self.observations.lock().unwrap();
+        observations.push_back((at, observation));
+        while observations
+            .front()
+            .is_some_and(|(at, _)| *at < horizon || observations.len() > MAX_OBSERVATIONS)
+        {
+            observations.pop_front();
+        }
+    }
+
+    /// Derive the metrics for the window ending now
+    pub fn snapshot(&self) -> AnomalySnapshot {
+        let now = self.clock.now();
+        let window_start = now - self.window();
+        let baseline_start = window_start - self.window() * BASELINE_WINDOWS as i32;
+        let observations = self.observations.lock().unwrap();
+
+        let mut client_auth: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        let (mut introspections, mut misses) = (0u64, 0u64);
+        let (mut revocations, mut baseline_revocations) = (0u64, 0u64);
+        for (at, observation) in observations.iter() {
+            if *at <= baseline_start {
+                continue;
+            }
+            let current = *at > window_start;
+            match observation {
+                Observation::Revocation { tokens } if current => revocations += tokens,
+                Observation::Revocation { tokens } => baseline_revocations += tokens,
+                _ if !current => {}
+                Observation::ClientAuth { client_id, success } => {
+                    let (attempts, failures) = client_auth.entry(client_id.clone()).or_default();
+                    *attempts += 1;
+                    if !success {
+                        *failures += 1;
+                    }
+                }
+                Observation::Introspection { active } => {
+                    introspections += 1;
+                    if !active {
+                        misses += 1;
+                    }
+                }
+            }
+        }
+        drop(observations);
+
+        let ratio = |part: u64, whole: u64| {
+            if whole == 0 {
+                0.0
+            } else {
+                part as f64 / whole as f64
+            }
+        };
+        let config = &self.config;
+        let mut snapshot = AnomalySnapshot {
+            introspection_miss_rate: ratio(misses, introspections),
+            revocations,
+            revocation_baseline: baseline_revocations as f64 / BASELINE_WINDOWS as f64,
+            ..Default::default()
+        };
+
+        for (client_id, (attempts, failures)) in client_auth {
+            let value = ratio(failures, attempts);
+            if attempts >= config.min_samples && value >= config.failed_auth_ratio {
+                snapshot.anomalies.push(Anomaly {
+                    kind: "failed_auth_ratio",
+                    client_id: Some(client_id.clone()),
+                    value,
+                    threshold: config.failed_auth_ratio,
+                    sample_size: attempts,
+                    description: format!(
+                        "{} of {} authentication attempts failed",
+                        failures, attempts
+                    ),
+                });
+            }
+            snapshot.failed_auth_ratios.insert(client_id, value);
+        }
+
+        if introspections >= config.min_samples
+            && snapshot.introspection_miss_rate >= config.introspection_miss_rate
+        {
+            snapshot.anomalies.push(Anomaly {
+                kind: "introspection_miss_rate",
+                client_id: None,
+                value: snapshot.introspection_miss_rate,
+                threshold: config.introspection_miss_rate,
+                sample_size: introspections,
+                description: format!(
+                    "{} of {} introspected tokens were unknown, expired or revoked",
+                    misses, introspections
+                ),
+            });
+        }
+
+        // Compare against at least one revocation per window, so a quiet
+        // baseline does not turn a handful of revocations into a spike
+        let spike = revocations as f64 / snapshot.revocation_baseline.max(1.0);
+        if revocations >= config.min_samples && spike >= config.revocation_spike_factor {
+            snapshot.anomalies.push(Anomaly {
+                kind: "revocation_spike",
+                client_id: None,
+                value: spike,
+                threshold: config.revocation_spike_factor,
+                sample_size: revocations,
+                description: format!(
+                    "{} tokens revoked against a baseline of {:.1} per window",
+                    revocations, snapshot.revocation_baseline
+                ),
+            });
+        }
+
+        snapshot
+    }
+}
+
+#[async_trait]
+impl EventPlugin for AnomalyDetector {
+    async fn emit(&self, event: &AuthEvent) -> Result<(), String> {
+        if let Some(observation) = Self::observe(event) {
+            self.record(observation);
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "anomaly_detector"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::events::EventSeverity;
+    use std::sync::Arc;
+
+    fn detector(clock: Arc<ManualClock>) -> AnomalyDetector {
+        AnomalyDetector::new(
+            AnomalyConfig {
+                window_secs: 300,
+                min_samples: 4,
+                failed_auth_ratio: 0.5,
+                introspection_miss_rate: 0.5,
+                revocation_spike_factor: 3.0,
+            },
+            clock,
+        )
+    }
+
+    fn event(event_type: EventType) -> AuthEvent {
+        AuthEvent::new(
+            event_type,
+            EventSeverity::Info,
+            None,
+            Some("app".to_string()),
+        )
+    }
+
+    async fn client_auth(detector: &AnomalyDetector, client_id: &str, success: bool) {
+        let mut event =
+            event(EventType::ClientValidated).with_metadata("success", success.to_string());
+        event.client_id = Some(client_id.to_string());
+        detector.emit(&event).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_auth_ratio_per_client() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let detector = detector(clock.clone());
+
+        for success in [false, false, false, true] {
+            client_auth(&detector, "noisy", success).await;
+        }
+        // Too few attempts to judge
+        client_auth(&detector, "quiet", false).await;
+
+        let snapshot = detector.snapshot();
+        assert_eq!(snapshot.failed_auth_ratios["noisy"], 0.75);
+        assert_eq!(snapshot.failed_auth_ratios["quiet"], 1.0);
+        assert_eq!(snapshot.anomalies.len(), 1);
+        assert_eq!(snapshot.anomalies[0].kind, "failed_auth_ratio");
+        assert_eq!(snapshot.anomalies[0].client_id.as_deref(), Some("noisy"));
+
+        // Attempts age out of the window
+        clock.advance(Duration::seconds(301));
+        assert!(detector.snapshot().anomalies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_introspection_misses_and_revocation_spikes() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let detector = detector(clock.clone());
+
+        // A steady baseline of two revocations per window
+        for _ in 0..BASELINE_WINDOWS {
+            for _ in 0..2 {
+                detector
+                    .emit(&event(EventType::TokenRevoked))
+                    .await
+                    .unwrap();
+            }
+            clock.advance(Duration::seconds(300));
+        }
+        assert!(detector.snapshot().anomalies.is_empty());
+
+        let bulk = event(EventType::TokenRevoked).with_metadata("tokens", "8");
+        detector.emit(&bulk).await.unwrap();
+        for event_type in [
+            EventType::TokenValidated,
+            EventType::TokenExpired,
+            EventType::TokenExpired,
+            EventType::TokenExpired,
+        ] {
+            detector.emit(&event(event_type)).await.unwrap();
+        }
+
+        let snapshot = detector.snapshot();
+        assert_eq!(snapshot.introspection_miss_rate, 0.75);
+        assert_eq!(snapshot.revocations, 8);
+        let kinds: Vec<_> = snapshot.anomalies.iter().map(|a| a.kind).collect();
+        assert_eq!(kinds, ["introspection_miss_rate", "revocation_spike"]);
+    }
+}
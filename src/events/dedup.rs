@@ -0,0 +1,77 @@
+use crate::events::AuthEvent;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Recognizes events delivered more than once. The outbox publishes each
+/// event at least once, under the same id every time, so a consumer that
+/// must count each event once can drop the ones this has already seen.
+pub struct EventDeduplicator {
+    capacity: usize,
+    seen: Mutex<SeenIds>,
+}
+
+#[derive(Default)]
+struct SeenIds {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl EventDeduplicator {
+    /// Remember the ids of the last `capacity` events
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            seen: Mutex::new(SeenIds::default()),
+        }
+    }
+
+    /// Whether `event` is the first delivery of its id among those
+    /// remembered. Records it either way.
+    pub fn accept(&self, event: &AuthEvent) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.ids.insert(event.id.clone()) {
+            return false;
+        }
+        seen.order.push_back(event.id.clone());
+        if seen.order.len() > self.capacity {
+            if let Some(oldest) = seen.order.pop_front() {
+                seen.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventSeverity, EventType};
+
+    fn event() -> AuthEvent {
+        AuthEvent::new(
+            EventType::TokenCreated,
+            EventSeverity::Info,
+            Some("user_123".to_string()),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_repeated_delivery_is_rejected() {
+        let dedup = EventDeduplicator::new(10);
+        let first = event();
+        assert!(dedup.accept(&first));
+        assert!(!dedup.accept(&first.clone()));
+        assert!(dedup.accept(&event()));
+    }
+
+    #[test]
+    fn test_only_recent_ids_are_remembered() {
+        let dedup = EventDeduplicator::new(2);
+        let first = event();
+        assert!(dedup.accept(&first));
+        assert!(dedup.accept(&event()));
+        assert!(dedup.accept(&event()));
+        assert!(dedup.accept(&first));
+    }
+}
@@ -0,0 +1,539 @@
+//! Keeping events when an external backend is down.
+//!
+//! Backends that ship events elsewhere (Kafka, Redis, a webhook) are wrapped
+//! in a [`DegradablePlugin`]. The first failed emit marks the backend
+//! degraded, and from then on its events are buffered instead: in a JSON
+//! lines file under `OAUTH2_EVENTS_BUFFER_DIR`, or in memory when that is
+//! unset. [`EventBackends::check`] runs on an interval; once a degraded
+//! backend passes its health check the buffer is replayed in order and
+//! delivery carries on directly. A file buffer left over from a previous run
+//! is replayed the same way. Backends can also be attached and detached
+//! while the server runs, at `/admin/api/events/plugins`.
+
+use crate::clock::SharedClock;
+use crate::events::{AuthEvent, EventPlugin};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Where a degraded backend's events wait
+enum Buffer {
+    Memory(VecDeque<AuthEvent>),
+    /// One JSON event per line
+    Disk(PathBuf),
+}
+
+impl Buffer {
+    fn read_disk(path: &PathBuf) -> Vec<AuthEvent> {
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            return Vec::new();
+        };
+        contents
+            .lines()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(event) => Some(event),
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable buffered event: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn write_disk(path: &PathBuf, events: &[AuthEvent]) -> std::io::Result<()> {
+        let mut contents = String::new();
+        for event in events {
+            contents.push_str(&serde_json::to_string(event)?);
+            contents.push('\n');
+        }
+        std::fs::write(path, contents)
+    }
+
+    fn push(&mut self, event: &AuthEvent) -> std::io::Result<()> {
+        match self {
+            Buffer::Memory(events) => events.push_back(event.clone()),
+            Buffer::Disk(path) => {
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                writeln!(file, "{}", serde_json::to_string(event)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn take_all(&mut self) -> Vec<AuthEvent> {
+        match self {
+            Buffer::Memory(events) => events.drain(..).collect(),
+            Buffer::Disk(path) => {
+                let events = Self::read_disk(path);
+                if let Err(e) = std::fs::remove_file(&*path) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        tracing::warn!("Failed to clear event buffer {}: {}", path.display(), e);
+                    }
+                }
+                events
+            }
+        }
+    }
+
+    /// Put undelivered events back ahead of any buffered since they were taken
+    fn restore(&mut self, mut undelivered: Vec<AuthEvent>) {
+        match self {
+            Buffer::Memory(events) => {
+                undelivered.extend(events.drain(..));
+                *events = undelivered.into();
+            }
+            Buffer::Disk(path) => {
+                undelivered.extend(Self::read_disk(path));
+                if let Err(e) = Self::write_disk(path, &undelivered) {
+                    tracing::error!("Failed to restore event buffer {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    fn erase_subject(&mut self, subject: &str, pseudonym: &str) {
+        match self {
+            Buffer::Memory(events) => {
+                for event in events.iter_mut() {
+                    event.anonymize(subject, pseudonym);
+                }
+            }
+            Buffer::Disk(path) => {
+                if !path.exists() {
+                    return;
+                }
+                let mut events = Self::read_disk(path);
+                for event in events.iter_mut() {
+                    event.anonymize(subject, pseudonym);
+                }
+                if let Err(e) = Self::write_disk(path, &events) {
+                    tracing::error!(
+                        "Failed to erase from event buffer {}: {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+struct State {
+    buffer: Buffer,
+    buffered: usize,
+    dropped: u64,
+    degraded_since: Option<DateTime<Utc>>,
+}
+
+/// How an external event backend is doing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BackendStatus {
+    pub plugin: String,
+    pub degraded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub degraded_since: Option<DateTime<Utc>>,
+    /// Events waiting for the backend to recover
+    pub buffered: usize,
+    /// Events lost because the buffer was full
+    pub dropped: u64,
+}
+
+/// An external backend that buffers its events while it is unhealthy
+pub struct DegradablePlugin {
+    inner: Arc<dyn EventPlugin>,
+    degraded: AtomicBool,
+    state: Mutex<State>,
+    max_buffered: usize,
+    /// Held while the buffer is replayed, so replays do not overlap
+    replaying: tokio::sync::Mutex<()>,
+    clock: SharedClock,
+}
+
+impl DegradablePlugin {
+    /// Wrap `inner`, buffering on disk under `buffer_dir` or else in memory.
+    /// Events left in the buffer by a previous run start it degraded.
+    pub fn new(
+        inner: Arc<dyn EventPlugin>,
+        buffer_dir: Option<&str>,
+        max_buffered: usize,
+        clock: SharedClock,
+    ) -> std::io::Result<Self> {
+        let (buffer, buffered) = match buffer_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                let path = PathBuf::from(dir).join(format!("{}.jsonl", inner.name()));
+                let left_over = Buffer::read_disk(&path).len();
+                (Buffer::Disk(path), left_over)
+            }
+            None => (Buffer::Memory(VecDeque::new()), 0),
+        };
+        let degraded_since = (buffered > 0).then(|| clock.now());
+        if buffered > 0 {
+            tracing::warn!(
+                "Event backend {} has {} buffered events from a previous run",
+                inner.name(),
+                buffered
+            );
+        }
+
+        Ok(Self {
+            degraded: AtomicBool::new(degraded_since.is_some()),
+            state: Mutex::new(State {
+                buffer,
+                buffered,
+                dropped: 0,
+                degraded_since,
+            }),
+            inner,
+            max_buffered,
+            replaying: tokio::sync::Mutex::new(()),
+            clock,
+        })
+    }
+
+    pub fn status(&self) -> BackendStatus {
+        let state = self.state.lock().unwrap();
+        BackendStatus {
+            plugin: self.inner.name().to_string(),
+            degraded: state.degraded_since.is_some(),
+            degraded_since: state.degraded_since,
+            buffered: state.buffered,
+            dropped: state.dropped,
+        }
+    }
+
+    fn mark_degraded(&self, state: &mut State, reason: &str) {
+        if state.degraded_since.is_none() {
+            tracing::warn!(
+                "Event backend {} is degraded ({}); buffering its events",
+                self.inner.name(),
+                reason
+            );
+            state.degraded_since = Some(self.clock.now());
+            self.degraded.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Buffer `event`, or `None` if the backend recovered in the meantime
+    /// and it should be delivered after all
+    fn buffer(&self, event: &AuthEvent, reason: Option<&str>) -> Option<()> {
+        let mut state = self.state.lock().unwrap();
+        match reason {
+            Some(reason) => self.mark_degraded(&mut state, reason),
+            None if state.degraded_since.is_none() => return None,
+            None => {}
+        }
+        if state.buffered >= self.max_buffered {
+            state.dropped += 1;
+            return Some(());
+        }
+        match state.buffer.push(event) {
+            Ok(()) => state.buffered += 1,
+            Err(e) => {
+                tracing::error!("Failed to buffer event for {}: {}", self.inner.name(), e);
+                state.dropped += 1;
+            }
+        }
+        Some(())
+    }
+
+    /// Check the backend's health. A healthy backend that was degraded gets
+    /// its buffer replayed; a backend failing its health check is marked
+    /// degraded. Returns whether the backend is healthy afterwards.
+    pub async fn check(&self) -> bool {
+        let healthy = self.inner.health_check().await;
+        if !healthy {
+            self.mark_degraded(&mut self.state.lock().unwrap(), "health check failed");
+            return false;
+        }
+        if !self.degraded.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        let _replaying = self.replaying.lock().await;
+        let mut delivered = 0;
+        loop {
+            let events = {
+                let mut state = self.state.lock().unwrap();
+                if state.buffered == 0 {
+                    // Emits now go straight to the backend again
+                    state.degraded_since = None;
+                    self.degraded.store(false, Ordering::SeqCst);
+                    break;
+                }
+                state.buffered = 0;
+                state.buffer.take_all()
+            };
+
+            for (i, event) in events.iter().enumerate() {
+                if let Err(e) = self.inner.emit(event).await {
+                    tracing::warn!(
+                        "Event backend {} failed again after {} replayed events: {}",
+                        self.inner.name(),
+                        delivered,
+                        e
+                    );
+                    let undelivered = events[i..].to_vec();
+                    let mut state = self.state.lock().unwrap();
+                    state.buffered += undelivered.len();
+                    state.buffer.restore(undelivered);
+                    return false;
+                }
+                delivered += 1;
+            }
+        }
+        tracing::info!(
+            "Event backend {} recovered; replayed {} buffered events",
+            self.inner.name(),
+            delivered
+        );
+        true
+    }
+}
+
+#[async_trait]
+impl EventPlugin for DegradablePlugin {
+    async fn emit(&self, event: &AuthEvent) -> Result<(), String> {
+        if self.degraded.load(Ordering::SeqCst) && self.buffer(event, None).is_some() {
+            return Ok(());
+        }
+        if let Err(e) = self.inner.emit(event).await {
+            self.buffer(event, Some(&e));
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn health_check(&self) -> bool {
+        !self.degraded.load(Ordering::SeqCst)
+    }
+
+    async fn erase_subject(&self, subject: &str, pseudonym: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .buffer
+            .erase_subject(subject, pseudonym);
+        self.inner.erase_subject(subject, pseudonym).await;
+    }
+
+    async fn purge_events(&self, cutoff: DateTime<Utc>, dry_run: bool) -> usize {
+        self.inner.purge_events(cutoff, dry_run).await
+    }
+}
+
+/// The external backends events are delivered to, for health checks,
+/// readiness and metrics. Backends can be added and removed while the
+/// server runs; the event actor is told separately.
+pub struct EventBackends {
+    backends: RwLock<Vec<Arc<DegradablePlugin>>>,
+    buffer_dir: Option<String>,
+    max_buffered: usize,
+    clock: SharedClock,
+}
+
+impl EventBackends {
+    /// No backends yet; each [`add`](Self::add)ed one buffers under
+    /// `buffer_dir`, or in memory, up to `max_buffered` events
+    pub fn new(buffer_dir: Option<String>, max_buffered: usize, clock: SharedClock) -> Self {
+        Self {
+            backends: RwLock::new(Vec::new()),
+            buffer_dir,
+            max_buffered,
+            clock,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backends.read().unwrap().is_empty()
+    }
+
+    pub fn backends(&self) -> Vec<Arc<DegradablePlugin>> {
+        self.backends.read().unwrap().clone()
+    }
+
+    pub fn statuses(&self) -> Vec<BackendStatus> {
+        self.backends()
+            .iter()
+            .map(|backend| backend.status())
+            .collect()
+    }
+
+    /// Wrap `plugin` to buffer while it is unhealthy and add it. Names
+    /// must be unique, as they name the disk buffers.
+    pub fn add(&self, plugin: Arc<dyn EventPlugin>) -> std::io::Result<Arc<DegradablePlugin>> {
+        let mut backends = self.backends.write().unwrap();
+        if backends
+            .iter()
+            .any(|backend| backend.name() == plugin.name())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("An event backend named {} already exists", plugin.name()),
+            ));
+        }
+        let backend = Arc::new(DegradablePlugin::new(
+            plugin,
+            self.buffer_dir.as_deref(),
+            self.max_buffered,
+            self.clock.clone(),
+        )?);
+        backends.push(backend.clone());
+        Ok(backend)
+    }
+
+    /// Remove the backend named `name`. Events it still buffers on disk
+    /// are replayed if a backend of that name is added again.
+    pub fn remove(&self, name: &str) -> Option<Arc<DegradablePlugin>> {
+        let mut backends = self.backends.write().unwrap();
+        let index = backends.iter().position(|backend| backend.name() == name)?;
+        Some(backends.remove(index))
+    }
+
+    /// Health-check every backend, replaying the buffers of any that recovered
+    pub async fn check(&self) {
+        for backend in self.backends() {
+            backend.check().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::events::{EventSeverity, EventType, InMemoryEventLogger};
+
+    /// Delivers to an in-memory logger while `up`
+    struct Flaky {
+        up: AtomicBool,
+        delivered: InMemoryEventLogger,
+    }
+
+    #[async_trait]
+    impl EventPlugin for Flaky {
+        async fn emit(&self, event: &AuthEvent) -> Result<(), String> {
+            if !self.up.load(Ordering::SeqCst) {
+                return Err("connection refused".to_string());
+            }
+            self.delivered.emit(event).await
+        }
+
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        async fn health_check(&self) -> bool {
+            self.up.load(Ordering::SeqCst)
+        }
+    }
+
+    fn event(user: &str) -> AuthEvent {
+        AuthEvent::new(
+            EventType::TokenCreated,
+            EventSeverity::Info,
+            Some(user.to_string()),
+            None,
+        )
+    }
+
+    fn delivered_users(flaky: &Flaky) -> Vec<String> {
+        flaky
+            .delivered
+            .get_events()
+            .into_iter()
+            .filter_map(|event| event.user_id)
+            .collect()
+    }
+
+    async fn run_outage(buffer_dir: Option<&str>) {
+        let flaky = Arc::new(Flaky {
+            up: AtomicBool::new(true),
+            delivered: InMemoryEventLogger::new(100),
+        });
+        let plugin =
+            DegradablePlugin::new(flaky.clone(), buffer_dir, 3, Arc::new(SystemClock)).unwrap();
+
+        plugin.emit(&event("a")).await.unwrap();
+        flaky.up.store(false, Ordering::SeqCst);
+        for user in ["b", "c", "d", "e"] {
+            plugin.emit(&event(user)).await.unwrap();
+        }
+        let status = plugin.status();
+        assert!(status.degraded);
+        assert_eq!((status.buffered, status.dropped), (3, 1));
+        assert!(!plugin.check().await);
+
+        flaky.up.store(true, Ordering::SeqCst);
+        // Still degraded until the health check, so order is kept
+        plugin.emit(&event("f")).await.unwrap();
+        assert_eq!(delivered_users(&flaky), vec!["a"]);
+
+        assert!(plugin.check().await);
+        plugin.emit(&event("g")).await.unwrap();
+        assert_eq!(delivered_users(&flaky), vec!["a", "b", "c", "d", "g"]);
+        let status = plugin.status();
+        assert!(!status.degraded);
+        assert_eq!(status.buffered, 0);
+    }
+
+    #[tokio::test]
+    async fn test_outage_buffers_in_memory_and_replays() {
+        run_outage(None).await;
+    }
+
+    #[tokio::test]
+    async fn test_outage_buffers_on_disk_and_replays() {
+        let dir = std::env::temp_dir().join(format!("oauth2-events-{}", uuid::Uuid::new_v4()));
+        run_outage(Some(dir.to_str().unwrap())).await;
+
+        // Events left on disk are picked up by the next run
+        let flaky = Arc::new(Flaky {
+            up: AtomicBool::new(false),
+            delivered: InMemoryEventLogger::new(100),
+        });
+        let plugin =
+            DegradablePlugin::new(flaky.clone(), dir.to_str(), 10, Arc::new(SystemClock)).unwrap();
+        plugin.emit(&event("h")).await.unwrap();
+        drop(plugin);
+
+        let plugin =
+            DegradablePlugin::new(flaky.clone(), dir.to_str(), 10, Arc::new(SystemClock)).unwrap();
+        assert_eq!(plugin.status().buffered, 1);
+        flaky.up.store(true, Ordering::SeqCst);
+        assert!(plugin.check().await);
+        assert_eq!(delivered_users(&flaky), vec!["h"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backends_are_added_and_removed_by_name() {
+        let backends = EventBackends::new(None, 10, Arc::new(SystemClock));
+        let flaky = || {
+            Arc::new(Flaky {
+                up: AtomicBool::new(true),
+                delivered: InMemoryEventLogger::new(10),
+            })
+        };
+
+        backends.add(flaky()).unwrap();
+        let duplicate = backends.add(flaky()).err().unwrap();
+        assert_eq!(duplicate.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(backends.statuses().len(), 1);
+
+        assert!(backends.remove("flaky").is_some());
+        assert!(backends.remove("flaky").is_none());
+        assert!(backends.is_empty());
+    }
+}
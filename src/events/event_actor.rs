@@ -1,5 +1,6 @@
 use crate::events::{AuthEvent, EventFilter, EventPlugin};
 use actix::prelude::*;
+use chrono::{DateTime, Utc};
 use std::sync::Arc;
 
 /// Event actor that processes and distributes events to plugins
@@ -77,6 +78,94 @@ impl Handler<EmitEvent> for EventActor {
     }
 }
 
+/// Message to anonymize an erased user in every plugin. Sent through the
+/// actor so it is ordered with the events emitted before it.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EraseSubject {
+    pub subject: String,
+    pub pseudonym: String,
+}
+
+impl Handler<EraseSubject> for EventActor {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: EraseSubject, _: &mut Self::Context) -> Self::Result {
+        let plugins = self.plugins.clone();
+
+        Box::pin(async move {
+            for plugin in plugins.iter() {
+                plugin.erase_subject(&msg.subject, &msg.pseudonym).await;
+            }
+        })
+    }
+}
+
+/// Message to drop stored events from before `cutoff` in every plugin.
+/// Returns how many were dropped, or on a dry run would have been.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct PurgeEvents {
+    pub cutoff: DateTime<Utc>,
+    pub dry_run: bool,
+}
+
+impl Handler<PurgeEvents> for EventActor {
+    type Result = ResponseFuture<usize>;
+
+    fn handle(&mut self, msg: PurgeEvents, _: &mut Self::Context) -> Self::Result {
+        let plugins = self.plugins.clone();
+
+        Box::pin(async move {
+            let mut purged = 0;
+            for plugin in plugins.iter() {
+                purged += plugin.purge_events(msg.cutoff, msg.dry_run).await;
+            }
+            purged
+        })
+    }
+}
+
+/// Message to start delivering events to another plugin
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AddPlugin {
+    pub plugin: Arc<dyn EventPlugin>,
+}
+
+impl Handler<AddPlugin> for EventActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: AddPlugin, _: &mut Self::Context) {
+        tracing::info!("Event plugin {} attached", msg.plugin.name());
+        self.plugins.push(msg.plugin);
+    }
+}
+
+/// Message to stop delivering events to a plugin. The plugin is matched by
+/// identity, not name, so a built-in plugin of the same name stays.
+/// Returns whether it was attached.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct RemovePlugin {
+    pub plugin: Arc<dyn EventPlugin>,
+}
+
+impl Handler<RemovePlugin> for EventActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: RemovePlugin, _: &mut Self::Context) -> bool {
+        let before = self.plugins.len();
+        self.plugins
+            .retain(|plugin| !Arc::ptr_eq(plugin, &msg.plugin));
+        let removed = self.plugins.len() < before;
+        if removed {
+            tracing::info!("Event plugin {} detached", msg.plugin.name());
+        }
+        removed
+    }
+}
+
 /// Message to get health status of all plugins
 #[derive(Message)]
 #[rtype(result = "Vec<(String, bool)>")]
@@ -178,4 +267,46 @@ mod tests {
         assert_eq!(health[0].0, "in_memory");
         assert!(health[0].1);
     }
+
+    #[actix::test]
+    async fn test_plugins_are_attached_and_detached() {
+        let builtin = Arc::new(InMemoryEventLogger::new(10));
+        let attached = Arc::new(InMemoryEventLogger::new(10));
+        let plugins: Vec<Arc<dyn EventPlugin>> = vec![builtin.clone()];
+        let actor = EventActor::new(plugins, EventFilter::allow_all()).start();
+        let event = || {
+            AuthEvent::new(
+                EventType::TokenCreated,
+                EventSeverity::Info,
+                Some("user_123".to_string()),
+                None,
+            )
+        };
+
+        actor
+            .send(AddPlugin {
+                plugin: attached.clone(),
+            })
+            .await
+            .unwrap();
+        actor.send(EmitEvent { event: event() }).await.unwrap();
+
+        // Same name as the built-in logger, but only the attached one goes
+        assert!(actor
+            .send(RemovePlugin {
+                plugin: attached.clone(),
+            })
+            .await
+            .unwrap());
+        assert!(!actor
+            .send(RemovePlugin {
+                plugin: attached.clone(),
+            })
+            .await
+            .unwrap());
+        actor.send(EmitEvent { event: event() }).await.unwrap();
+
+        assert_eq!(builtin.get_events().len(), 2);
+        assert_eq!(attached.get_events().len(), 1);
+    }
 }
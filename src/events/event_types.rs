@@ -10,6 +10,7 @@ pub enum EventType {
     AuthorizationCodeCreated,
     AuthorizationCodeValidated,
     AuthorizationCodeExpired,
+    AuthorizationCodeReplayed,
 
     // Token events
     TokenCreated,
@@ -20,12 +21,23 @@ pub enum EventType {
     // Client events
     ClientRegistered,
     ClientValidated,
+    ClientUpdated,
     ClientDeleted,
+    ClientSecretExpiring,
+    RateLimitWarning,
 
     // User events
     UserAuthenticated,
     UserAuthenticationFailed,
     UserLogout,
+    SuspiciousLogin,
+    UserDeletionRequested,
+    UserErased,
+    UserProfileUpdated,
+
+    // Admin events
+    AdminAction,
+    ImpersonationStarted,
 }
 
 impl EventType {
@@ -36,16 +48,26 @@ impl EventType {
             EventType::AuthorizationCodeCreated => "authorization_code_created",
             EventType::AuthorizationCodeValidated => "authorization_code_validated",
             EventType::AuthorizationCodeExpired => "authorization_code_expired",
+            EventType::AuthorizationCodeReplayed => "authorization_code_replayed",
             EventType::TokenCreated => "token_created",
             EventType::TokenValidated => "token_validated",
             EventType::TokenRevoked => "token_revoked",
             EventType::TokenExpired => "token_expired",
             EventType::ClientRegistered => "client_registered",
             EventType::ClientValidated => "client_validated",
+            EventType::ClientUpdated => "client_updated",
             EventType::ClientDeleted => "client_deleted",
+            EventType::ClientSecretExpiring => "client_secret_expiring",
+            EventType::RateLimitWarning => "rate_limit_warning",
             EventType::UserAuthenticated => "user_authenticated",
             EventType::UserAuthenticationFailed => "user_authentication_failed",
             EventType::UserLogout => "user_logout",
+            EventType::SuspiciousLogin => "suspicious_login",
+            EventType::UserDeletionRequested => "user_deletion_requested",
+            EventType::UserErased => "user_erased",
+            EventType::UserProfileUpdated => "user_profile_updated",
+            EventType::AdminAction => "admin_action",
+            EventType::ImpersonationStarted => "impersonation_started",
         }
     }
 }
@@ -57,6 +79,7 @@ pub enum EventSeverity {
     Info,
     Warning,
     Error,
+    Critical,
 }
 
 /// Authentication event
@@ -85,6 +108,16 @@ pub struct AuthEvent {
 
     /// Optional error message
     pub error: Option<String>,
+
+    /// Position among the events about `user_id`, counting from 1. Set on
+    /// events published through the outbox, so a consumer can order them
+    /// and notice one that is missing or repeated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_sequence: Option<i64>,
+
+    /// Position among the events about `client_id`, as for `user_sequence`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_sequence: Option<i64>,
 }
 
 impl AuthEvent {
@@ -104,6 +137,8 @@ impl AuthEvent {
             client_id,
             metadata: HashMap::new(),
             error: None,
+            user_sequence: None,
+            client_sequence: None,
         }
     }
 
@@ -113,6 +148,14 @@ impl AuthEvent {
         self
     }
 
+    /// Record the authorization journey the event is part of, if any
+    pub fn with_flow_id(self, flow_id: Option<&str>) -> Self {
+        match flow_id {
+            Some(flow_id) => self.with_metadata("flow_id", flow_id),
+            None => self,
+        }
+    }
+
     /// Add an error message to the event
     #[allow(dead_code)]
     pub fn with_error(mut self, error: impl Into<String>) -> Self {
@@ -120,6 +163,22 @@ impl AuthEvent {
         self
     }
 
+    /// Replace `subject` with `pseudonym`, and drop where the request came
+    /// from if it was the subject's
+    pub fn anonymize(&mut self, subject: &str, pseudonym: &str) {
+        if self.user_id.as_deref() == Some(subject) {
+            self.user_id = Some(pseudonym.to_string());
+            for key in ["client_ip", "geo_country", "geo_city"] {
+                self.metadata.remove(key);
+            }
+        }
+        for value in self.metadata.values_mut() {
+            if value == subject {
+                *value = pseudonym.to_string();
+            }
+        }
+    }
+
     /// Convert event to JSON string
     pub fn to_json(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
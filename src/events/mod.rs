@@ -1,6 +1,18 @@
+pub mod anomaly;
+pub mod dedup;
+pub mod degradation;
 pub mod event_actor;
 pub mod event_types;
 pub mod plugins;
+pub mod redis_stream;
+pub mod stats;
+pub mod webhook;
 
+pub use anomaly::*;
+pub use dedup::*;
+pub use degradation::*;
 pub use event_types::*;
 pub use plugins::*;
+pub use redis_stream::*;
+pub use stats::*;
+pub use webhook::*;
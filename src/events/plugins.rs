@@ -1,5 +1,7 @@
-use crate::events::{AuthEvent, EventType};
+use crate::events::{AuthEvent, EventDeduplicator, EventType};
+use crate::telemetry::LogSampler;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use std::collections::HashSet;
 use std::sync::{Arc, RwLock};
 
@@ -16,6 +18,16 @@ pub trait EventPlugin: Send + Sync {
     async fn health_check(&self) -> bool {
         true
     }
+
+    /// Anonymize whatever the plugin keeps about an erased user. Backends
+    /// that only forward events have nothing to do.
+    async fn erase_subject(&self, _subject: &str, _pseudonym: &str) {}
+
+    /// Drop stored events from before `cutoff`, returning how many there
+    /// were. On a dry run they are only counted.
+    async fn purge_events(&self, _cutoff: DateTime<Utc>, _dry_run: bool) -> usize {
+        0
+    }
 }
 
 /// Configuration for event filtering
@@ -70,10 +82,12 @@ impl EventFilter {
     }
 }
 
-/// In-memory event logger (default plugin)
+/// In-memory event logger (default plugin). An event delivered again, as the
+/// outbox may after a restart, is kept once.
 pub struct InMemoryEventLogger {
     events: Arc<RwLock<Vec<AuthEvent>>>,
     max_events: usize,
+    dedup: EventDeduplicator,
 }
 
 impl InMemoryEventLogger {
@@ -82,6 +96,7 @@ impl InMemoryEventLogger {
         Self {
             events: Arc::new(RwLock::new(Vec::new())),
             max_events,
+            dedup: EventDeduplicator::new(max_events),
         }
     }
 
@@ -113,6 +128,10 @@ impl InMemoryEventLogger {
 #[async_trait]
 impl EventPlugin for InMemoryEventLogger {
     async fn emit(&self, event: &AuthEvent) -> Result<(), String> {
+        if !self.dedup.accept(event) {
+            tracing::debug!("Event {} already logged", event.id);
+            return Ok(());
+        }
         let mut events = self.events.write().unwrap();
 
         // Add event
@@ -131,21 +150,56 @@ impl EventPlugin for InMemoryEventLogger {
     fn name(&self) -> &str {
         "in_memory"
     }
+
+    async fn erase_subject(&self, subject: &str, pseudonym: &str) {
+        for event in self.events.write().unwrap().iter_mut() {
+            event.anonymize(subject, pseudonym);
+        }
+    }
+
+    async fn purge_events(&self, cutoff: DateTime<Utc>, dry_run: bool) -> usize {
+        let mut events = self.events.write().unwrap();
+        // Events are kept in the order they were emitted
+        let expired = events.partition_point(|event| event.timestamp < cutoff);
+        if !dry_run {
+            events.drain(..expired);
+        }
+        expired
+    }
 }
 
 /// Console event logger (logs to stdout)
 #[derive(Default)]
-pub struct ConsoleEventLogger;
+pub struct ConsoleEventLogger {
+    sampler: Option<Arc<LogSampler>>,
+}
 
 impl ConsoleEventLogger {
     pub fn new() -> Self {
-        Self
+        Self::default()
+    }
+
+    /// Only log a sample of high-volume event types, but every event about
+    /// a client in verbose mode
+    pub fn with_sampler(sampler: Arc<LogSampler>) -> Self {
+        Self {
+            sampler: Some(sampler),
+        }
     }
 }
 
 #[async_trait]
 impl EventPlugin for ConsoleEventLogger {
     async fn emit(&self, event: &AuthEvent) -> Result<(), String> {
+        if let Some(sampler) = &self.sampler {
+            let verbose = event
+                .client_id
+                .as_deref()
+                .is_some_and(|client_id| sampler.is_debug(client_id));
+            if !verbose && !sampler.sample_event(event.event_type.as_str()) {
+                return Ok(());
+            }
+        }
         match event.to_json() {
             Ok(json) => {
                 tracing::info!("Event: {}", json);
@@ -209,6 +263,54 @@ mod tests {
         assert_eq!(events[0].event_type, EventType::TokenCreated);
     }
 
+    #[tokio::test]
+    async fn test_in_memory_logger_erases_subject() {
+        let logger = InMemoryEventLogger::new(10);
+        for user in ["user_1", "user_2"] {
+            let event = AuthEvent::new(
+                EventType::UserAuthenticated,
+                EventSeverity::Info,
+                Some(user.to_string()),
+                None,
+            )
+            .with_metadata("client_ip", "203.0.113.7");
+            logger.emit(&event).await.unwrap();
+        }
+
+        logger.erase_subject("user_1", "erased:abc").await;
+
+        let events = logger.get_events();
+        assert_eq!(events[0].user_id.as_deref(), Some("erased:abc"));
+        assert!(!events[0].metadata.contains_key("client_ip"));
+        assert_eq!(events[1].user_id.as_deref(), Some("user_2"));
+        assert!(events[1].metadata.contains_key("client_ip"));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_logger_purges_old_events() {
+        let logger = InMemoryEventLogger::new(10);
+        let now = Utc::now();
+        for age in [10, 8, 1] {
+            let mut event = AuthEvent::new(
+                EventType::TokenCreated,
+                EventSeverity::Info,
+                Some("user_123".to_string()),
+                None,
+            );
+            event.timestamp = now - chrono::Duration::days(age);
+            logger.emit(&event).await.unwrap();
+        }
+        let cutoff = now - chrono::Duration::days(7);
+
+        assert_eq!(logger.purge_events(cutoff, true).await, 2);
+        assert_eq!(logger.get_events().len(), 3);
+
+        assert_eq!(logger.purge_events(cutoff, false).await, 2);
+        let events = logger.get_events();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].timestamp > cutoff);
+    }
+
     #[tokio::test]
     async fn test_in_memory_logger_max_events() {
         let logger = InMemoryEventLogger::new(3);
@@ -0,0 +1,65 @@
+//! Appending events to a Redis stream.
+//!
+//! Each event is added to `events.redis.stream` with `XADD`, as an entry
+//! with its `type` and the whole event as JSON under `event`. Consumers read
+//! the stream with `XREAD` or a consumer group; entries are never trimmed
+//! here, so size the stream with a policy of its own.
+
+use crate::cache::redis::{RedisConnection, Reply};
+use crate::config::RedisEventConfig;
+use crate::events::{AuthEvent, EventPlugin};
+use async_trait::async_trait;
+use std::time::Duration;
+
+/// How long a Redis command may take, connecting and all
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct RedisStreamEventPlugin {
+    name: String,
+    redis: RedisConnection,
+    stream: String,
+}
+
+impl RedisStreamEventPlugin {
+    pub fn new(config: &RedisEventConfig) -> Result<Self, String> {
+        Ok(Self {
+            name: "redis".to_string(),
+            redis: RedisConnection::new(&config.url, "OAUTH2_EVENTS_REDIS_URL", COMMAND_TIMEOUT)?,
+            stream: config.stream.clone(),
+        })
+    }
+
+    /// Name it something other than `redis`, to run more than one
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+}
+
+#[async_trait]
+impl EventPlugin for RedisStreamEventPlugin {
+    async fn emit(&self, event: &AuthEvent) -> Result<(), String> {
+        let json = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        let args: [&[u8]; 7] = [
+            b"XADD",
+            self.stream.as_bytes(),
+            b"*",
+            b"type",
+            event.event_type.as_str().as_bytes(),
+            b"event",
+            &json,
+        ];
+        match self.redis.command(&args).await? {
+            Reply::Bulk(Some(_)) => Ok(()),
+            reply => Err(format!("unexpected Redis reply {:?}", reply)),
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn health_check(&self) -> bool {
+        matches!(self.redis.command(&[b"PING"]).await, Ok(Reply::Status(_)))
+    }
+}
@@ -0,0 +1,180 @@
+//! Delivering events to HTTP endpoints.
+//!
+//! Each event is POSTed as JSON to every URL in `events.webhook.urls`. With
+//! a secret configured the body is signed, and receivers check
+//! `X-Webhook-Signature: sha256=<hex HMAC-SHA256 of the body>` before
+//! trusting it. A delivery fails if any URL does not answer with a success
+//! status; the event is then buffered and replayed to every URL, so
+//! receivers should drop repeats by `X-Webhook-Id`.
+
+use crate::config::WebhookEventConfig;
+use crate::events::{AuthEvent, EventPlugin};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+
+/// How long one delivery may take
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+pub struct WebhookEventPlugin {
+    name: String,
+    urls: Vec<String>,
+    secret: Option<String>,
+    http: reqwest::Client,
+}
+
+impl WebhookEventPlugin {
+    pub fn new(config: &WebhookEventConfig) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            name: "webhook".to_string(),
+            urls: config.urls.clone(),
+            secret: config.secret.clone(),
+            http,
+        })
+    }
+
+    /// Name it something other than `webhook`, to run more than one
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+}
+
+/// The `X-Webhook-Signature` value for `body`
+pub fn webhook_signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[async_trait]
+impl EventPlugin for WebhookEventPlugin {
+    async fn emit(&self, event: &AuthEvent) -> Result<(), String> {
+        let body = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        let signature = self
+            .secret
+            .as_deref()
+            .map(|secret| webhook_signature(secret, &body));
+
+        for url in &self.urls {
+            let mut request = self
+                .http
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header("X-Webhook-Id", event.id.as_str())
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+            request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|e| format!("Webhook {}: {}", url, e))?;
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventSeverity, EventType};
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::{Arc, Mutex};
+
+    type Received = Arc<Mutex<Vec<(Option<String>, Vec<u8>)>>>;
+
+    async fn receiver(status: u16) -> (String, Received) {
+        let received = Received::default();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = HttpServer::new({
+            let received = received.clone();
+            move || {
+                let received = received.clone();
+                App::new().route(
+                    "/hook",
+                    web::post().to(move |req: HttpRequest, body: web::Bytes| {
+                        let received = received.clone();
+                        async move {
+                            let signature = req
+                                .headers()
+                                .get(SIGNATURE_HEADER)
+                                .and_then(|v| v.to_str().ok())
+                                .map(str::to_string);
+                            received.lock().unwrap().push((signature, body.to_vec()));
+                            HttpResponse::build(
+                                actix_web::http::StatusCode::from_u16(status).unwrap(),
+                            )
+                            .finish()
+                        }
+                    }),
+                )
+            }
+        })
+        .listen(listener)
+        .unwrap()
+        .workers(1)
+        .run();
+        actix_web::rt::spawn(server);
+        (url, received)
+    }
+
+    fn event() -> AuthEvent {
+        AuthEvent::new(
+            EventType::TokenCreated,
+            EventSeverity::Info,
+            Some("user_1".to_string()),
+            Some("client_1".to_string()),
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_deliveries_are_signed() {
+        let (url, received) = receiver(204).await;
+        let secret = "0123456789abcdef";
+        let plugin = WebhookEventPlugin::new(&WebhookEventConfig {
+            urls: vec![url],
+            secret: Some(secret.to_string()),
+        })
+        .unwrap();
+
+        let event = event();
+        plugin.emit(&event).await.unwrap();
+
+        let received = received.lock().unwrap();
+        let (signature, body) = &received[0];
+        assert_eq!(
+            signature.as_deref(),
+            Some(webhook_signature(secret, body).as_str())
+        );
+        let delivered: AuthEvent = serde_json::from_slice(body).unwrap();
+        assert_eq!(delivered.id, event.id);
+    }
+
+    #[actix_web::test]
+    async fn test_a_failing_receiver_fails_the_delivery() {
+        let (url, received) = receiver(500).await;
+        let plugin = WebhookEventPlugin::new(&WebhookEventConfig {
+            urls: vec![url],
+            secret: None,
+        })
+        .unwrap();
+
+        assert!(plugin.emit(&event()).await.is_err());
+        assert_eq!(received.lock().unwrap()[0].0, None);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::handlers::auth::session_user;
+use crate::handlers::portal::login_redirect;
+use crate::middleware::FormCsrfToken;
+use crate::models::{ErrorCode, OAuth2Error, SocialUserInfo, TrustedDevice, UpstreamConnection};
+use crate::services::{AccountEraser, MfaService, UpstreamTokenVault};
+use crate::templates::{
+    AccountConnectionsPage, AccountDeletePage, AccountSecurityPage, ConnectionView, Templates,
+    TotpSetupView, TrustedDeviceView,
+};
+use actix_session::Session;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+
+const INVALID_CODE: &str = "That code didn't work. Check your authenticator app and try again.";
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeForm {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccountForm {
+    code: Option<String>,
+}
+
+fn device_view(device: TrustedDevice) -> TrustedDeviceView {
+    TrustedDeviceView {
+        id: device.id,
+        label: device.label,
+        last_used_at: device
+            .last_used_at
+            .map(|at| at.format("%Y-%m-%d %H:%M UTC").to_string()),
+        expires_at: device.expires_at.format("%Y-%m-%d").to_string(),
+    }
+}
+
+async fn render_security(
+    session: &Session,
+    mfa: &MfaService,
+    templates: &Templates,
+    csrf: &FormCsrfToken,
+    user: &SocialUserInfo,
+    notice: Option<&str>,
+    error: Option<&str>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let subject = user.subject();
+    let mfa_enrolled = mfa.is_enrolled(&subject).await?;
+
+    let setup = if mfa_enrolled {
+        None
+    } else {
+        // Keep offering the same secret until a code confirms it
+        let secret = match session.get::<String>("totp_setup_secret").unwrap_or(None) {
+            Some(secret) => secret,
+            None => {
+                let secret = MfaService::generate_secret();
+                session
+                    .insert("totp_setup_secret", &secret)
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+                secret
+            }
+        };
+        Some(TotpSetupView {
+            uri: mfa.provisioning_uri(&secret, &user.email)?,
+            secret,
+        })
+    };
+
+    let page = AccountSecurityPage {
+        user_email: user.email.clone(),
+        mfa_enrolled,
+        setup,
+        devices: mfa
+            .list_devices(&subject)
+            .await?
+            .into_iter()
+            .map(device_view)
+            .collect(),
+        trusted_device_days: mfa.trusted_device_days(),
+        notice: notice.map(str::to_string),
+        error: error.map(str::to_string),
+    };
+    Ok(templates.render_form_response("account_security.html", &page, csrf.as_str()))
+}
+
+/// Second-factor status and remembered devices for the signed-in user
+pub async fn security(
+    session: Session,
+    mfa: web::Data<Arc<MfaService>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+
+    render_security(&session, &mfa, &templates, &csrf, &user, None, None).await
+}
+
+/// Confirm the pending TOTP secret with a code from the user's app
+pub async fn enroll_totp(
+    session: Session,
+    form: web::Form<TotpCodeForm>,
+    mfa: web::Data<Arc<MfaService>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+    let Some(secret) = session.get::<String>("totp_setup_secret").unwrap_or(None) else {
+        return render_security(&session, &mfa, &templates, &csrf, &user, None, None).await;
+    };
+
+    if !mfa.enroll(&user.subject(), &secret, &form.code).await? {
+        return render_security(
+            &session,
+            &mfa,
+            &templates,
+            &csrf,
+            &user,
+            None,
+            Some(INVALID_CODE),
+        )
+        .await;
+    }
+
+    tracing::info!("TOTP enabled for {}", user.subject());
+    session.remove("totp_setup_secret");
+    let notice = Some("Two-step verification is on.");
+    render_security(&session, &mfa, &templates, &csrf, &user, notice, None).await
+}
+
+/// Turn off TOTP, which also forgets every remembered device. Needs a
+/// current code so a hijacked session cannot quietly remove the factor.
+pub async fn disable_totp(
+    session: Session,
+    form: web::Form<TotpCodeForm>,
+    mfa: web::Data<Arc<MfaService>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+    let subject = user.subject();
+
+    if !mfa.verify(&subject, &form.code).await? {
+        return render_security(
+            &session,
+            &mfa,
+            &templates,
+            &csrf,
+            &user,
+            None,
+            Some(INVALID_CODE),
+        )
+        .await;
+    }
+
+    tracing::info!("TOTP disabled for {}", subject);
+    mfa.unenroll(&subject).await?;
+    let notice = Some("Two-step verification is off.");
+    render_security(&session, &mfa, &templates, &csrf, &user, notice, None).await
+}
+
+/// Forget one remembered device; its next sign-in is challenged again
+pub async fn revoke_device(
+    session: Session,
+    id: web::Path<String>,
+    mfa: web::Data<Arc<MfaService>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+
+    if !mfa.revoke_device(&user.subject(), &id).await? {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let notice = Some("The device will be asked for a code next time.");
+    render_security(&session, &mfa, &templates, &csrf, &user, notice, None).await
+}
+
+pub async fn revoke_all_devices(
+    session: Session,
+    mfa: web::Data<Arc<MfaService>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+
+    mfa.revoke_all_devices(&user.subject()).await?;
+    let notice = Some("All devices will be asked for a code next time.");
+    render_security(&session, &mfa, &templates, &csrf, &user, notice, None).await
+}
+
+async fn render_delete(
+    mfa: &MfaService,
+    eraser: &AccountEraser,
+    templates: &Templates,
+    csrf: &FormCsrfToken,
+    user: &SocialUserInfo,
+    error: Option<&str>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let page = AccountDeletePage {
+        user_email: user.email.clone(),
+        mfa_required: mfa.is_enrolled(&user.subject()).await?,
+        grace_period_days: eraser.grace_period_days(),
+        erase_after: None,
+        error: error.map(str::to_string),
+    };
+    Ok(templates.render_form_response("account_delete.html", &page, csrf.as_str()))
+}
+
+/// Ask the signed-in user to confirm deleting their account
+pub async fn delete_account_confirm(
+    session: Session,
+    mfa: web::Data<Arc<MfaService>>,
+    eraser: web::Data<Arc<AccountEraser>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+
+    render_delete(&mfa, &eraser, &templates, &csrf, &user, None).await
+}
+
+/// Disable the account and schedule its erasure, ending this session. Like
+/// turning off TOTP, needs a current code from users who have one.
+pub async fn delete_account(
+    session: Session,
+    form: web::Form<DeleteAccountForm>,
+    mfa: web::Data<Arc<MfaService>>,
+    eraser: web::Data<Arc<AccountEraser>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+    let subject = user.subject();
+
+    if mfa.is_enrolled(&subject).await? {
+        let code = form.code.as_deref().unwrap_or_default();
+        if !mfa.verify(&subject, code).await? {
+            return render_delete(&mfa, &eraser, &templates, &csrf, &user, Some(INVALID_CODE))
+                .await;
+        }
+    }
+
+    let (deletion, _) = eraser.request_deletion(&subject, "self").await?;
+    session.purge();
+
+    let page = AccountDeletePage {
+        user_email: user.email,
+        mfa_required: false,
+        grace_period_days: eraser.grace_period_days(),
+        erase_after: Some(deletion.erase_after.format("%Y-%m-%d").to_string()),
+        error: None,
+    };
+    Ok(templates.render_response("account_delete.html", &page))
+}
+
+fn connection_view(connection: UpstreamConnection) -> ConnectionView {
+    ConnectionView {
+        provider: connection.provider,
+        scopes: connection
+            .scope
+            .split_whitespace()
+            .map(str::to_string)
+            .collect(),
+        connected_at: connection.connected_at.format("%Y-%m-%d").to_string(),
+        offline: connection.has_refresh_token,
+    }
+}
+
+async fn render_connections(
+    vault: &UpstreamTokenVault,
+    templates: &Templates,
+    csrf: &FormCsrfToken,
+    user: &SocialUserInfo,
+    notice: Option<&str>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let connections = vault.connections(&user.subject()).await?;
+    // Identities are per provider, so the only provider a user can connect
+    // is the one they signed in with
+    let connectable = (vault.can_store(&user.provider)
+        && !connections.iter().any(|c| c.provider == user.provider))
+    .then(|| user.provider.clone());
+
+    let page = AccountConnectionsPage {
+        user_email: user.email.clone(),
+        connectable,
+        connections: connections.into_iter().map(connection_view).collect(),
+        notice: notice.map(str::to_string),
+    };
+    Ok(templates.render_form_response("account_connections.html", &page, csrf.as_str()))
+}
+
+/// Providers the signed-in user has let the server call on their behalf
+pub async fn connections(
+    session: Session,
+    vault: web::Data<Arc<UpstreamTokenVault>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+
+    render_connections(&vault, &templates, &csrf, &user, None).await
+}
+
+/// Disconnect a provider, deleting the tokens kept for it
+pub async fn disconnect(
+    session: Session,
+    provider: web::Path<String>,
+    vault: web::Data<Arc<UpstreamTokenVault>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+
+    if vault.purge(&user.subject(), Some(&provider)).await? == 0 {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let notice = Some("Disconnected. Its tokens have been deleted.");
+    render_connections(&vault, &templates, &csrf, &user, notice).await
+}
@@ -1,7 +1,38 @@
+use crate::actors::{
+    ApplyClientState, AuthActor, ClientActor, ImportClient, RegisterClient, TokenActor,
+};
+use crate::cache::CacheInvalidator;
+use crate::clock::Clock;
+use crate::config::{JwtConfig, RedisEventConfig, WebhookEventConfig};
 use crate::db::Database;
+use crate::events::{
+    event_actor::{AddPlugin, EmitEvent, EventActor, RemovePlugin},
+    AnomalyDetector, AuthEvent, EventBackends, EventPlugin, EventSeverity, EventType,
+    RedisStreamEventPlugin, StatsAggregator, WebhookEventPlugin, STATS_METRICS,
+};
+use crate::handlers::token::revoke_and_forget;
+use crate::keys::SharedKeys;
 use crate::metrics::Metrics;
-use actix_web::{web, HttpResponse, Result};
-use serde::Serialize;
+use crate::models::{
+    AuditChain, ClientCredentials, ClientExport, ClientImportReport, ClientProvisioning,
+    ClientRegistration, ClientState, ErrorCode, OAuth2Error, Organization, OrganizationAssignment,
+    OrganizationRegistration, RegistrationTokenRequest, Resource, ResourceRegistration,
+    RevokedCredentials, SecretExpiryAssignment, StatsGranularity, SyncAction, SyncChange,
+    SyncError, SyncKind, SyncManifest, SyncReport, SyncSnapshot, TokenDecodeRequest, TokenFormat,
+    User, CLIENT_EXPORT_VERSION, UNUSABLE_PASSWORD_HASH,
+};
+use crate::services::{
+    hash_password, inspect_token, publish_recorded_event, AccountEraser, ConformanceChecker,
+    Diagnostics, ErasedSubject, EventOutbox, Impersonator, ProfileSync, RegistrationGate,
+    RetentionEnforcer, SecretExpiryMonitor, UpstreamTokenVault, UsageTracker,
+};
+use crate::telemetry::{LogLevels, LogSampler};
+use actix::Addr;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 
 #[derive(Serialize)]
@@ -17,6 +48,14 @@ pub struct ClientInfo {
     pub client_id: String,
     pub name: String,
     pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_org: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClientListQuery {
+    /// Only clients owned by this organization
+    org: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -29,6 +68,54 @@ pub struct TokenInfo {
     pub revoked: bool,
 }
 
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub password: String,
+}
+
+/// Emit the events for a user's credentials being revoked: `token_revoked`
+/// with the counts, and `user_logout` with `scope=all` for their sessions
+pub(crate) fn emit_credentials_revoked(
+    event_actor: Option<&web::Data<Addr<EventActor>>>,
+    user_id: &str,
+    reason: &str,
+    revoked: &RevokedCredentials,
+) {
+    let Some(event_actor) = event_actor else {
+        return;
+    };
+
+    let event = AuthEvent::new(
+        EventType::TokenRevoked,
+        EventSeverity::Warning,
+        Some(user_id.to_string()),
+        None,
+    )
+    .with_metadata("reason", reason)
+    .with_metadata("tokens", revoked.tokens.to_string())
+    .with_metadata(
+        "authorization_codes",
+        revoked.authorization_codes.to_string(),
+    );
+    event_actor.do_send(EmitEvent { event });
+
+    let event = AuthEvent::new(
+        EventType::UserLogout,
+        EventSeverity::Info,
+        Some(user_id.to_string()),
+        None,
+    )
+    .with_metadata("scope", "all")
+    .with_metadata("reason", reason);
+    event_actor.do_send(EmitEvent { event });
+}
+
+fn user_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({
+        "message": "User not found"
+    }))
+}
+
 /// Admin dashboard - shows overview statistics
 pub async fn dashboard(_db: web::Data<Arc<Database>>) -> Result<HttpResponse> {
     // In a real implementation, fetch actual stats from database
@@ -42,13 +129,508 @@ pub async fn dashboard(_db: web::Data<Arc<Database>>) -> Result<HttpResponse> {
     Ok(HttpResponse::Ok().json(data))
 }
 
-/// List all registered clients
-pub async fn list_clients(_db: web::Data<Arc<Database>>) -> Result<HttpResponse> {
-    // In a real implementation, fetch from database
-    let clients: Vec<ClientInfo> = vec![];
+/// List registered clients, optionally only one organization's
+pub async fn list_clients(
+    query: web::Query<ClientListQuery>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let clients: Vec<ClientInfo> = db
+        .list_clients(query.org.as_deref())
+        .await?
+        .into_iter()
+        .map(|client| ClientInfo {
+            client_id: client.client_id,
+            name: client.name,
+            created_at: client.created_at.to_rfc3339(),
+            owner_org: client.owner_org,
+        })
+        .collect();
     Ok(HttpResponse::Ok().json(clients))
 }
 
+/// Export clients, optionally only one organization's, for import into
+/// another server. Secrets are left out.
+pub async fn export_clients(
+    query: web::Query<ClientListQuery>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let clients = db.list_clients(query.org.as_deref()).await?;
+    Ok(HttpResponse::Ok().json(ClientExport {
+        version: CLIENT_EXPORT_VERSION,
+        clients: clients.iter().map(Into::into).collect(),
+    }))
+}
+
+/// Register exported clients under their existing ids, issuing each a new
+/// secret. Nothing is imported unless every client is valid; clients whose
+/// id is already registered are skipped.
+pub async fn import_clients(
+    body: web::Json<ClientExport>,
+    db: web::Data<Arc<Database>>,
+    client_actor: web::Data<Addr<ClientActor>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let export = body.into_inner();
+    if export.version != CLIENT_EXPORT_VERSION {
+        return Err(OAuth2Error::invalid_request(&format!(
+            "Unsupported export version {}",
+            export.version
+        )));
+    }
+    let mut seen = std::collections::HashSet::new();
+    for client in &export.clients {
+        client
+            .validate()
+            .map_err(|reason| OAuth2Error::invalid_request(&reason))?;
+        if !seen.insert(client.client_id.as_str()) {
+            return Err(OAuth2Error::invalid_request(&format!(
+                "{} is listed more than once",
+                client.client_id
+            )));
+        }
+    }
+
+    let mut report = ClientImportReport::default();
+    for client in export.clients {
+        if db.get_client(&client.client_id).await?.is_some() {
+            report.skipped.push(client.client_id);
+            continue;
+        }
+        let client = client_actor.send(ImportClient { client }).await??;
+        report.imported.push(ClientCredentials {
+            token_endpoint_auth_method: client.auth_method(),
+            client_id: client.client_id,
+            client_secret: client.client_secret,
+            secret_expires_at: client.secret_expires_at,
+            warnings: vec![],
+        });
+    }
+    tracing::info!(
+        "Imported {} clients, skipped {} already registered",
+        report.imported.len(),
+        report.skipped.len()
+    );
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Move a client into an organization, or out of one
+pub async fn set_client_organization(
+    client_id: web::Path<String>,
+    body: web::Json<OrganizationAssignment>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    if let Some(org_id) = &body.org_id {
+        if db.get_organization(org_id).await?.is_none() {
+            return Ok(organization_not_found());
+        }
+    }
+    if !db
+        .set_client_org(&client_id, body.org_id.as_deref())
+        .await?
+    {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "message": "Client not found"
+        })));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "client_id": client_id.into_inner(),
+        "owner_org": body.org_id
+    })))
+}
+
+/// Set or clear when a client's secret expires. A date in the past refuses
+/// the current secret straight away.
+pub async fn set_client_secret_expiry(
+    client_id: web::Path<String>,
+    body: web::Json<SecretExpiryAssignment>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    if !db
+        .set_client_secret_expiry(&client_id, body.expires_at)
+        .await?
+    {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "message": "Client not found"
+        })));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "client_id": client_id.into_inner(),
+        "secret_expires_at": body.expires_at
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExpiringSecretsQuery {
+    /// Defaults to the configured warning window
+    days: Option<u32>,
+}
+
+/// Clients whose secret expires within `days`, or already has, soonest first
+pub async fn expiring_secrets(
+    query: web::Query<ExpiringSecretsQuery>,
+    secret_expiry: web::Data<Arc<SecretExpiryMonitor>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let days = query.days.unwrap_or_else(|| secret_expiry.warning_days());
+    Ok(HttpResponse::Ok().json(secret_expiry.expiring(days).await?))
+}
+
+fn organization_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({
+        "message": "Organization not found"
+    }))
+}
+
+pub async fn list_organizations(db: web::Data<Arc<Database>>) -> Result<HttpResponse, OAuth2Error> {
+    Ok(HttpResponse::Ok().json(db.list_organizations().await?))
+}
+
+pub async fn create_organization(
+    body: web::Json<OrganizationRegistration>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err(OAuth2Error::invalid_request("name is required"));
+    }
+    if db.get_organization_by_name(name).await?.is_some() {
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "message": "An organization with this name already exists"
+        })));
+    }
+
+    let org = Organization::new(name.to_string());
+    db.save_organization(&org).await?;
+    tracing::info!("Created organization {}", org.name);
+
+    Ok(HttpResponse::Created().json(org))
+}
+
+/// Add a user to an organization, moving them out of any other. `subject` is
+/// a local user id or a federated `provider:id` subject.
+pub async fn add_organization_member(
+    path: web::Path<(String, String)>,
+    db: web::Data<Arc<Database>>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let (org_id, subject) = path.into_inner();
+    if db.get_organization(&org_id).await?.is_none() {
+        return Ok(organization_not_found());
+    }
+
+    db.set_member_org(&subject, Some(&org_id), clock.now())
+        .await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "org_id": org_id,
+        "subject": subject
+    })))
+}
+
+pub async fn remove_organization_member(
+    path: web::Path<(String, String)>,
+    db: web::Data<Arc<Database>>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let (org_id, subject) = path.into_inner();
+    if db.get_member_org(&subject).await?.as_deref() != Some(org_id.as_str()) {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "message": "Not a member of this organization"
+        })));
+    }
+
+    db.set_member_org(&subject, None, clock.now()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Create or update the client with this name so it matches the declared
+/// state. Repeating the same request changes nothing, which is what
+/// declarative tooling expects.
+pub async fn provision_client(
+    name: web::Path<String>,
+    body: web::Json<ClientState>,
+    db: web::Data<Arc<Database>>,
+    client_actor: web::Data<Addr<ClientActor>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let name = name.into_inner();
+    let state = body.into_inner();
+    if name.trim().is_empty() {
+        return Err(OAuth2Error::invalid_request("name is required"));
+    }
+    state
+        .validate()
+        .map_err(|reason| OAuth2Error::invalid_request(&reason))?;
+
+    let mut existing = db.list_clients_by_name(&name).await?;
+    if existing.len() > 1 {
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "message": format!(
+                "{} clients are named '{}'; delete or rename all but one",
+                existing.len(),
+                name
+            )
+        })));
+    }
+
+    let Some(client) = existing.pop() else {
+        let client = client_actor
+            .send(RegisterClient {
+                registration: ClientRegistration {
+                    client_name: name,
+                    redirect_uris: state.redirect_uris,
+                    grant_types: state.grant_types,
+                    scope: state.scope,
+                    allowed_origins: vec![],
+                    redirect_uri_patterns: vec![],
+                    token_endpoint_auth_method: Default::default(),
+                    access_token_format: None,
+                },
+                owner_id: None,
+                owner_org: None,
+            })
+            .await??;
+        tracing::info!("Provisioned new client {}", client.client_id);
+        return Ok(HttpResponse::Created().json(ClientProvisioning::new(client, true, vec![])));
+    };
+
+    let drift = state.drift(&client);
+    if drift.is_empty() {
+        return Ok(HttpResponse::Ok().json(ClientProvisioning::new(client, false, drift)));
+    }
+
+    tracing::info!(
+        "Client {} drifted in {}; applying declared state",
+        client.client_id,
+        drift.join(", ")
+    );
+    client_actor
+        .send(ApplyClientState {
+            client_id: client.client_id.clone(),
+            state,
+            drift: drift.clone(),
+        })
+        .await??;
+    let client = db
+        .get_client(&client.client_id)
+        .await?
+        .ok_or_else(|| OAuth2Error::invalid_client("Client not found"))?;
+
+    Ok(HttpResponse::Ok().json(ClientProvisioning::new(client, false, drift)))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SyncQuery {
+    /// Report the changes without making them
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Converge clients, APIs and users on a declared manifest, for Kubernetes
+/// operators and GitOps pipelines. Sending the same manifest twice changes
+/// nothing the second time; objects sync created that the manifest no longer
+/// declares are deleted.
+#[allow(clippy::too_many_arguments)]
+pub async fn sync(
+    query: web::Query<SyncQuery>,
+    body: web::Json<SyncManifest>,
+    db: web::Data<Arc<Database>>,
+    client_actor: web::Data<Addr<ClientActor>>,
+    clock: web::Data<dyn Clock>,
+    event_actor: Option<web::Data<Addr<EventActor>>>,
+    outbox: Option<web::Data<Arc<EventOutbox>>>,
+    invalidator: web::Data<Arc<CacheInvalidator>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let manifest = body.into_inner();
+    let snapshot = SyncSnapshot {
+        clients: db.list_clients(None).await?,
+        resources: db.list_resources().await?,
+        users: db.list_users().await?,
+        managed: db.list_sync_managed().await?,
+    };
+    let mut changes = match manifest.plan(&snapshot) {
+        Ok(changes) => changes,
+        Err(SyncError::Invalid(reason)) => return Err(OAuth2Error::invalid_request(&reason)),
+        Err(SyncError::Ambiguous(reason)) => {
+            return Ok(HttpResponse::Conflict().json(serde_json::json!({ "message": reason })))
+        }
+    };
+
+    if !query.dry_run {
+        for change in &mut changes {
+            apply_sync_change(
+                change,
+                &manifest,
+                &db,
+                &client_actor,
+                clock.now(),
+                event_actor.as_ref(),
+                outbox.as_ref().map(|outbox| outbox.get_ref().as_ref()),
+                &invalidator,
+            )
+            .await?;
+        }
+        if !changes.is_empty() {
+            tracing::info!("Sync applied {} changes", changes.len());
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(SyncReport {
+        dry_run: query.dry_run,
+        changes,
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn apply_sync_change(
+    change: &mut SyncChange,
+    manifest: &SyncManifest,
+    db: &Database,
+    client_actor: &Addr<ClientActor>,
+    now: DateTime<Utc>,
+    event_actor: Option<&web::Data<Addr<EventActor>>>,
+    outbox: Option<&EventOutbox>,
+    invalidator: &CacheInvalidator,
+) -> Result<(), OAuth2Error> {
+    let id = change.id.clone().unwrap_or_default();
+    match (change.kind, change.action) {
+        (SyncKind::Client, SyncAction::Delete) => {
+            let event = event_actor.map(|_| {
+                AuthEvent::new(
+                    EventType::ClientDeleted,
+                    EventSeverity::Info,
+                    None,
+                    Some(id.clone()),
+                )
+                .with_metadata("reason", "sync")
+            });
+            let origins = db
+                .get_client(&id)
+                .await?
+                .map(|client| client.get_allowed_origins())
+                .unwrap_or_default();
+            let tokens = db
+                .delete_client(&id, event.as_ref().filter(|_| outbox.is_some()))
+                .await?;
+            invalidator.forget_tokens(&tokens).await;
+            invalidator.forget_origins(&origins).await;
+            if let Some(event) = event {
+                publish_recorded_event(outbox, event_actor.map(|actor| actor.get_ref()), event);
+            }
+        }
+        (SyncKind::Resource, SyncAction::Delete) => {
+            db.delete_resource(&id).await?;
+        }
+        (SyncKind::User, SyncAction::Delete) => {
+            if let Some(revoked) = db.delete_user(&id, now).await? {
+                invalidator.forget_tokens(&revoked.revoked_tokens).await;
+                emit_credentials_revoked(event_actor, &id, "user_deleted", &revoked);
+            }
+        }
+        (SyncKind::Client, action) => {
+            let declared = manifest
+                .clients
+                .iter()
+                .find(|client| client.name == change.name)
+                .expect("planned from the manifest");
+            if action == SyncAction::Create {
+                let client = client_actor
+                    .send(RegisterClient {
+                        registration: ClientRegistration {
+                            client_name: declared.name.clone(),
+                            redirect_uris: declared.state.redirect_uris.clone(),
+                            grant_types: declared.state.grant_types.clone(),
+                            scope: declared.state.scope.clone(),
+                            allowed_origins: vec![],
+                            redirect_uri_patterns: vec![],
+                            token_endpoint_auth_method: Default::default(),
+                            access_token_format: None,
+                        },
+                        owner_id: None,
+                        owner_org: None,
+                    })
+                    .await??;
+                change.id = Some(client.client_id.clone());
+                change.client_secret = Some(client.client_secret);
+            } else if !change.fields.is_empty() {
+                client_actor
+                    .send(ApplyClientState {
+                        client_id: id,
+                        state: declared.state.clone(),
+                        drift: change.fields.clone(),
+                    })
+                    .await??;
+            }
+        }
+        (SyncKind::Resource, action) => {
+            let declared = manifest
+                .resources
+                .iter()
+                .find(|resource| resource.identifier == change.name)
+                .expect("planned from the manifest");
+            let mut resource = Resource::new(
+                declared.identifier.clone(),
+                declared.name.clone(),
+                declared.scopes.clone(),
+                declared.token_format,
+            );
+            if action == SyncAction::Create {
+                db.save_resource(&resource).await?;
+                change.id = Some(resource.id);
+            } else if !change.fields.is_empty() {
+                resource.id = id;
+                resource.updated_at = now;
+                db.update_resource(&resource).await?;
+            }
+        }
+        (SyncKind::User, action) => {
+            let declared = manifest
+                .users
+                .iter()
+                .find(|user| user.username == change.name)
+                .expect("planned from the manifest");
+            if action == SyncAction::Create {
+                let mut user = User::new(
+                    declared.username.clone(),
+                    declared
+                        .password_hash
+                        .clone()
+                        .unwrap_or_else(|| UNUSABLE_PASSWORD_HASH.to_string()),
+                    declared.email.clone(),
+                );
+                user.enabled = declared.enabled;
+                db.save_user(&user).await?;
+                change.id = Some(user.id);
+            } else {
+                let changed = |field: &str| change.fields.iter().any(|f| f == field);
+                // Disabling or a new password revokes what the user holds
+                if changed("enabled") && !declared.enabled {
+                    if let Some(revoked) = db.disable_user(&id, now).await? {
+                        invalidator.forget_tokens(&revoked.revoked_tokens).await;
+                        emit_credentials_revoked(event_actor, &id, "user_disabled", &revoked);
+                    }
+                }
+                if let (true, Some(hash)) = (changed("password_hash"), &declared.password_hash) {
+                    if let Some(revoked) = db.update_user_password(&id, hash, now).await? {
+                        invalidator.forget_tokens(&revoked.revoked_tokens).await;
+                        emit_credentials_revoked(event_actor, &id, "password_changed", &revoked);
+                    }
+                }
+                if changed("email") || changed("enabled") {
+                    db.update_user_state(&id, &declared.email, declared.enabled, now)
+                        .await?;
+                }
+            }
+        }
+    }
+
+    if change.action != SyncAction::Delete {
+        if let Some(id) = &change.id {
+            db.set_sync_managed(change.kind, id).await?;
+        }
+    }
+    Ok(())
+}
+
 /// List all active tokens
 pub async fn list_tokens(_db: web::Data<Arc<Database>>) -> Result<HttpResponse> {
     // In a real implementation, fetch from database
@@ -59,18 +641,663 @@ pub async fn list_tokens(_db: web::Data<Arc<Database>>) -> Result<HttpResponse>
 /// Revoke a token by ID (admin function)
 pub async fn admin_revoke_token(
     token_id: web::Path<String>,
+    token_actor: web::Data<Addr<TokenActor>>,
     db: web::Data<Arc<Database>>,
-) -> Result<HttpResponse> {
-    // Revoke token
-    db.revoke_token(&token_id)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+    invalidator: web::Data<Arc<CacheInvalidator>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    // Through the actor, so the revocation is published like any other
+    revoke_and_forget(token_id.into_inner(), &token_actor, &db, &invalidator).await?;
 
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "message": "Token revoked successfully"
     })))
 }
 
+/// Decode a token and report every check it would fail, so support need not
+/// piece it together from jwt.io and the database
+pub async fn decode_token(
+    body: web::Json<TokenDecodeRequest>,
+    db: web::Data<Arc<Database>>,
+    jwt: web::Data<Arc<JwtConfig>>,
+    keys: web::Data<SharedKeys>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let inspection = inspect_token(
+        &db,
+        &jwt,
+        &keys,
+        clock.get_ref(),
+        &body.token,
+        body.audience.as_deref(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(inspection))
+}
+
+/// Disable a user, revoking their tokens, authorization codes and sessions
+pub async fn disable_user(
+    user_id: web::Path<String>,
+    db: web::Data<Arc<Database>>,
+    clock: web::Data<dyn Clock>,
+    event_actor: Option<web::Data<Addr<EventActor>>>,
+    invalidator: web::Data<Arc<CacheInvalidator>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(revoked) = db.disable_user(&user_id, clock.now()).await? else {
+        return Ok(user_not_found());
+    };
+    invalidator.forget_tokens(&revoked.revoked_tokens).await;
+
+    tracing::info!(
+        "Disabled user {}, revoking {} tokens and {} authorization codes",
+        user_id,
+        revoked.tokens,
+        revoked.authorization_codes
+    );
+    emit_credentials_revoked(event_actor.as_ref(), &user_id, "user_disabled", &revoked);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "User disabled successfully",
+        "revoked": revoked
+    })))
+}
+
+/// Set a user's password, revoking everything issued under the old one
+pub async fn change_user_password(
+    user_id: web::Path<String>,
+    body: web::Json<ChangePasswordRequest>,
+    db: web::Data<Arc<Database>>,
+    clock: web::Data<dyn Clock>,
+    event_actor: Option<web::Data<Addr<EventActor>>>,
+    invalidator: web::Data<Arc<CacheInvalidator>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let password_hash = hash_password(&body.password)?;
+    let Some(revoked) = db
+        .update_user_password(&user_id, &password_hash, clock.now())
+        .await?
+    else {
+        return Ok(user_not_found());
+    };
+    invalidator.forget_tokens(&revoked.revoked_tokens).await;
+
+    tracing::info!(
+        "Changed password for user {}, revoking {} tokens and {} authorization codes",
+        user_id,
+        revoked.tokens,
+        revoked.authorization_codes
+    );
+    emit_credentials_revoked(event_actor.as_ref(), &user_id, "password_changed", &revoked);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Password changed successfully",
+        "revoked": revoked
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EraseUserQuery {
+    /// Skip the grace period and erase now
+    #[serde(default)]
+    immediate: bool,
+}
+
+/// Delete a user's account: disable it and erase it once the grace period is
+/// over, or straight away with `immediate=true`. Works for local user ids
+/// and federated `provider:id` subjects alike.
+pub async fn erase_user(
+    subject: web::Path<String>,
+    query: web::Query<EraseUserQuery>,
+    db: web::Data<Arc<Database>>,
+    eraser: web::Data<Arc<AccountEraser>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let subject = subject.into_inner();
+    if !db.subject_exists(&subject).await? && !eraser.is_pending(&subject).await? {
+        return Ok(user_not_found());
+    }
+
+    if query.immediate {
+        let report = eraser.erase(&subject).await?;
+        let mut response = HttpResponse::Ok().json(serde_json::json!({
+            "message": "User erased",
+            "erased": report
+        }));
+        response.extensions_mut().insert(ErasedSubject {
+            subject,
+            pseudonym: report.pseudonym,
+        });
+        return Ok(response);
+    }
+
+    let (deletion, revoked) = eraser.request_deletion(&subject, "admin").await?;
+    Ok(HttpResponse::Accepted().json(serde_json::json!({
+        "message": "User scheduled for erasure",
+        "deletion": deletion,
+        "revoked": revoked
+    })))
+}
+
+/// Call off a pending erasure and re-enable the account
+pub async fn cancel_user_erasure(
+    subject: web::Path<String>,
+    eraser: web::Data<Arc<AccountEraser>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    if !eraser.cancel(&subject).await? {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "message": "No erasure pending for this user"
+        })));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Providers a user has connected, without the stored tokens
+pub async fn list_upstream_tokens(
+    subject: web::Path<String>,
+    vault: web::Data<Arc<UpstreamTokenVault>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    Ok(HttpResponse::Ok().json(vault.connections(&subject).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeUpstreamTokensQuery {
+    /// Only this provider's tokens; all of them if absent
+    provider: Option<String>,
+}
+
+/// Delete the provider tokens stored for a user
+pub async fn purge_upstream_tokens(
+    subject: web::Path<String>,
+    query: web::Query<PurgeUpstreamTokensQuery>,
+    vault: web::Data<Arc<UpstreamTokenVault>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let purged = vault.purge(&subject, query.provider.as_deref()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "purged": purged })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImpersonateRequest {
+    /// Client the token is issued at
+    client_id: String,
+    /// Defaults to the client's scopes, bar admin ones
+    scope: Option<String>,
+    /// Capped at the configured maximum
+    ttl_secs: Option<u64>,
+}
+
+/// Mint a short-lived access token for a user, held by the calling admin:
+/// a signed-in full admin, or a listed one whose bearer token has the
+/// `admin:impersonate` scope.
+pub async fn impersonate_user(
+    req: HttpRequest,
+    subject: web::Path<String>,
+    body: web::Json<ImpersonateRequest>,
+    impersonator: web::Data<Arc<Impersonator>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let admin = impersonator.authorize(&req).await?;
+    let Some(token) = impersonator
+        .impersonate(
+            &admin,
+            &subject,
+            &body.client_id,
+            body.scope.as_deref(),
+            body.ttl_secs,
+        )
+        .await?
+    else {
+        return Ok(user_not_found());
+    };
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "access_token": token.access_token,
+        "token_type": token.token_type,
+        "expires_in": token.expires_in,
+        "scope": token.scope,
+        "act": { "sub": admin }
+    })))
+}
+
+/// Live impersonation tokens, newest first, without the tokens themselves
+pub async fn list_impersonation_tokens(
+    req: HttpRequest,
+    impersonator: web::Data<Arc<Impersonator>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    impersonator.authorize(&req).await?;
+    let tokens: Vec<_> = impersonator
+        .active()
+        .await?
+        .into_iter()
+        .map(|token| {
+            serde_json::json!({
+                "id": token.id,
+                "user_id": token.user_id,
+                "client_id": token.client_id,
+                "impersonator": token.impersonator,
+                "scope": token.scope,
+                "created_at": token.created_at,
+                "expires_at": token.expires_at
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RevokeImpersonationRequest {
+    /// Only tokens minted by this admin, e.g. `user:alice`; all of them if absent
+    impersonator: Option<String>,
+}
+
+/// Revoke live impersonation tokens in bulk
+pub async fn revoke_impersonation_tokens(
+    req: HttpRequest,
+    body: Option<web::Json<RevokeImpersonationRequest>>,
+    impersonator: web::Data<Arc<Impersonator>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    impersonator.authorize(&req).await?;
+    let body = body.map(web::Json::into_inner).unwrap_or_default();
+    let revoked = impersonator
+        .revoke_all(body.impersonator.as_deref())
+        .await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "revoked": revoked })))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DebugLoggingRequest {
+    /// Capped at the configured maximum, which is also the default
+    ttl_secs: Option<u64>,
+}
+
+/// Log every request and event about a client in full for a while
+pub async fn enable_client_debug_logging(
+    client_id: web::Path<String>,
+    body: Option<web::Json<DebugLoggingRequest>>,
+    db: web::Data<Arc<Database>>,
+    sampler: web::Data<Arc<LogSampler>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    if db.get_client(&client_id).await?.is_none() {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "message": "Client not found"
+        })));
+    }
+    let ttl = body
+        .and_then(|body| body.ttl_secs)
+        .map(|secs| chrono::Duration::seconds(i64::try_from(secs).unwrap_or(i64::MAX)));
+    let until = sampler.enable_debug(&client_id, ttl);
+    tracing::info!(
+        "Verbose logging on for client {} until {}",
+        client_id,
+        until
+    );
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "client_id": client_id.into_inner(),
+        "until": until
+    })))
+}
+
+/// Turn a client's verbose logging off before it runs out
+pub async fn disable_client_debug_logging(
+    client_id: web::Path<String>,
+    sampler: web::Data<Arc<LogSampler>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    if !sampler.disable_debug(&client_id) {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "message": "Verbose logging is not on for this client"
+        })));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Clients with verbose logging on, and when it ends
+pub async fn list_debug_logging(sampler: web::Data<Arc<LogSampler>>) -> Result<HttpResponse> {
+    let clients: Vec<_> = sampler
+        .debug_clients()
+        .into_iter()
+        .map(|(client_id, until)| serde_json::json!({ "client_id": client_id, "until": until }))
+        .collect();
+    Ok(HttpResponse::Ok().json(clients))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogLevelRequest {
+    /// Level for every target not listed in `targets`
+    pub level: String,
+    /// Levels for individual targets, such as `rust_oauth2_server::db`
+    #[serde(default)]
+    pub targets: BTreeMap<String, String>,
+}
+
+fn log_levels_unavailable() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(serde_json::json!({
+        "message": "The log filter cannot be changed on this server"
+    }))
+}
+
+fn log_levels_body(levels: &LogLevels) -> serde_json::Value {
+    serde_json::json!({
+        "filter": levels.current(),
+        "startup_filter": levels.startup()
+    })
+}
+
+/// The log filter in effect, and the one set at startup
+pub async fn log_levels(levels: web::Data<Option<Arc<LogLevels>>>) -> Result<HttpResponse> {
+    Ok(match levels.as_deref() {
+        Some(levels) => HttpResponse::Ok().json(log_levels_body(levels)),
+        None => log_levels_unavailable(),
+    })
+}
+
+/// Change log levels without a restart, overall and per target
+pub async fn set_log_levels(
+    body: web::Json<LogLevelRequest>,
+    levels: web::Data<Option<Arc<LogLevels>>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(levels) = levels.as_deref() else {
+        return Ok(log_levels_unavailable());
+    };
+    let directives = LogLevels::directives(&body.level, &body.targets)
+        .map_err(|reason| OAuth2Error::invalid_request(&reason))?;
+    levels
+        .set(&directives)
+        .map_err(|reason| OAuth2Error::invalid_request(&reason))?;
+    tracing::warn!("Log filter changed to {}", directives);
+
+    Ok(HttpResponse::Ok().json(log_levels_body(levels)))
+}
+
+/// Go back to the log filter set at startup
+pub async fn reset_log_levels(
+    levels: web::Data<Option<Arc<LogLevels>>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(levels) = levels.as_deref() else {
+        return Ok(log_levels_unavailable());
+    };
+    levels
+        .reset()
+        .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+    tracing::warn!("Log filter reset to {}", levels.startup());
+
+    Ok(HttpResponse::Ok().json(log_levels_body(levels)))
+}
+
+/// Token-abuse signals currently over their thresholds
+pub async fn anomalies(anomaly_detector: web::Data<Arc<AnomalyDetector>>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(anomaly_detector.snapshot().anomalies))
+}
+
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+const MAX_AUDIT_LIMIT: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    /// Only entries whose path starts with this, e.g. `/admin/api/users/u1`
+    path: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Recorded admin API mutations, newest first
+pub async fn audit_log(
+    query: web::Query<AuditQuery>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .clamp(1, MAX_AUDIT_LIMIT);
+    let entries = db
+        .list_admin_audit_entries(query.path.as_deref(), limit)
+        .await?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// Check the admin audit log's hash chain for entries changed, inserted or
+/// removed outside the server
+pub async fn verify_audit_log(
+    db: web::Data<Arc<Database>>,
+    chain: web::Data<Arc<AuditChain>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let verification = chain.verify(&db.list_admin_audit_chain().await?);
+    if !verification.valid {
+        tracing::error!(
+            "Admin audit log breaks at entry {:?}: {}",
+            verification.broken_at,
+            verification.problem.as_deref().unwrap_or_default()
+        );
+    }
+    Ok(HttpResponse::Ok().json(verification))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthorizationLogQuery {
+    client_id: Option<String>,
+    user_id: Option<String>,
+    /// Only the requests of one authorization journey
+    flow_id: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Recorded `/oauth/authorize` requests, newest first
+pub async fn authorization_log(
+    query: web::Query<AuthorizationLogQuery>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .clamp(1, MAX_AUDIT_LIMIT);
+    let entries = db
+        .list_authorization_requests(
+            query.client_id.as_deref(),
+            query.user_id.as_deref(),
+            query.flow_id.as_deref(),
+            limit,
+        )
+        .await?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// Buckets returned when no range is given
+const DEFAULT_STATS_BUCKETS: i32 = 24;
+
+#[derive(Debug, Deserialize)]
+pub struct StatsQuery {
+    granularity: Option<StatsGranularity>,
+    /// Only this metric
+    metric: Option<String>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Event counts per hour or day, oldest first. Defaults to the last 24
+/// buckets, the current one included.
+pub async fn stats(
+    query: web::Query<StatsQuery>,
+    db: web::Data<Arc<Database>>,
+    stats: web::Data<Arc<StatsAggregator>>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let query = query.into_inner();
+    let granularity = query.granularity.unwrap_or(StatsGranularity::Hour);
+    if let Some(metric) = &query.metric {
+        if !STATS_METRICS.contains(&metric.as_str()) {
+            return Err(OAuth2Error::invalid_request(&format!(
+                "Unknown metric {}; expected one of {}",
+                metric,
+                STATS_METRICS.join(", ")
+            )));
+        }
+    }
+
+    let to = query
+        .to
+        .unwrap_or_else(|| granularity.bucket_start(clock.now()) + granularity.duration());
+    let from = query
+        .from
+        .unwrap_or_else(|| to - granularity.duration() * DEFAULT_STATS_BUCKETS);
+
+    stats.flush().await?;
+    let buckets = db
+        .list_stats(granularity, from, to, query.metric.as_deref())
+        .await?;
+    Ok(HttpResponse::Ok().json(buckets))
+}
+
+const DEFAULT_STALE_DAYS: i64 = 90;
+
+#[derive(Debug, Deserialize)]
+pub struct UsageReportQuery {
+    /// Report what has gone unused for at least this many days
+    days: Option<i64>,
+}
+
+impl UsageReportQuery {
+    fn cutoff(&self, clock: &dyn Clock) -> chrono::DateTime<chrono::Utc> {
+        let days = self.days.unwrap_or(DEFAULT_STALE_DAYS).max(0);
+        clock.now() - chrono::Duration::days(days)
+    }
+}
+
+/// Clients not used for `days`, least recently used first
+pub async fn stale_clients(
+    query: web::Query<UsageReportQuery>,
+    db: web::Data<Arc<Database>>,
+    usage: web::Data<Arc<UsageTracker>>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    usage.flush().await?;
+    let clients = db.list_stale_clients(query.cutoff(clock.as_ref())).await?;
+    Ok(HttpResponse::Ok().json(clients))
+}
+
+/// Live tokens not used for `days`, least recently used first
+pub async fn unused_tokens(
+    query: web::Query<UsageReportQuery>,
+    db: web::Data<Arc<Database>>,
+    usage: web::Data<Arc<UsageTracker>>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    usage.flush().await?;
+    let tokens = db
+        .list_unused_tokens(query.cutoff(clock.as_ref()), clock.now())
+        .await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetentionRunQuery {
+    /// Defaults to the configured mode
+    dry_run: Option<bool>,
+}
+
+/// Run the retention job now rather than waiting for its interval
+pub async fn run_retention(
+    query: web::Query<RetentionRunQuery>,
+    retention: web::Data<Arc<RetentionEnforcer>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let dry_run = query.dry_run.unwrap_or_else(|| retention.dry_run());
+    Ok(HttpResponse::Ok().json(retention.run(dry_run).await?))
+}
+
+/// Re-read connected profiles that are due now, instead of waiting for the
+/// next scheduled run
+pub async fn run_profile_sync(
+    profile_sync: web::Data<Arc<ProfileSync>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    Ok(HttpResponse::Ok().json(profile_sync.run().await?))
+}
+
+/// List the registered protected APIs
+pub async fn list_resources(db: web::Data<Arc<Database>>) -> Result<HttpResponse, OAuth2Error> {
+    Ok(HttpResponse::Ok().json(db.list_resources().await?))
+}
+
+/// Register a protected API that clients can request tokens for
+pub async fn create_resource(
+    body: web::Json<ResourceRegistration>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let registration = body.into_inner();
+    let identifier = registration.identifier.trim();
+    if identifier.is_empty() {
+        return Err(OAuth2Error::invalid_request("identifier is required"));
+    }
+    if registration.scopes.is_empty() || registration.scopes.iter().any(|s| s.trim().is_empty()) {
+        return Err(OAuth2Error::invalid_request(
+            "scopes must list at least one non-empty scope",
+        ));
+    }
+    if db.get_resource_by_identifier(identifier).await?.is_some() {
+        return Ok(HttpResponse::Conflict().json(serde_json::json!({
+            "message": "A resource with this identifier already exists"
+        })));
+    }
+
+    let resource = Resource::new(
+        identifier.to_string(),
+        registration.name,
+        registration.scopes,
+        registration.token_format.unwrap_or(TokenFormat::Jwt),
+    );
+    db.save_resource(&resource).await?;
+    tracing::info!("Registered resource {}", resource.identifier);
+
+    Ok(HttpResponse::Created().json(resource))
+}
+
+/// Remove a protected API. Tokens already issued for it stay valid until
+/// they expire, but cannot be refreshed.
+pub async fn delete_resource(
+    resource_id: web::Path<String>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    if !db.delete_resource(&resource_id).await? {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "message": "Resource not found"
+        })));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Resource deleted successfully"
+    })))
+}
+
+/// Registration tokens, newest first. The tokens themselves are not shown.
+pub async fn list_registration_tokens(
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    Ok(HttpResponse::Ok().json(db.list_registration_tokens().await?))
+}
+
+/// Issue a token that lets its bearer register clients within its limits
+pub async fn create_registration_token(
+    body: web::Json<RegistrationTokenRequest>,
+    gate: web::Data<Arc<RegistrationGate>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let issued = gate.issue(body.into_inner()).await?;
+    tracing::info!(
+        "Issued registration token {} for {} registrations",
+        issued.registration_token.id,
+        issued.registration_token.max_uses
+    );
+    Ok(HttpResponse::Created().json(issued))
+}
+
+/// Stop a registration token from being used again. Clients it registered
+/// are kept.
+pub async fn revoke_registration_token(
+    token_id: web::Path<String>,
+    db: web::Data<Arc<Database>>,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    if !db.revoke_registration_token(&token_id, clock.now()).await? {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "message": "Registration token not found"
+        })));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": "Registration token revoked"
+    })))
+}
+
 /// Delete a client (admin function)
 pub async fn delete_client(
     _client_id: web::Path<String>,
@@ -82,9 +1309,184 @@ pub async fn delete_client(
     })))
 }
 
+/// Run the protocol conformance self-test against this server
+pub async fn conformance(
+    req: HttpRequest,
+    db: web::Data<Arc<Database>>,
+    auth_actor: web::Data<Addr<AuthActor>>,
+    invalidator: web::Data<Arc<CacheInvalidator>>,
+) -> Result<HttpResponse> {
+    // Call back into the listener that took this request rather than trusting
+    // the Host header
+    let mut addr = req.app_config().local_addr();
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+
+    let report = ConformanceChecker::new(
+        format!("http://{}", addr),
+        db.get_ref().clone(),
+        auth_actor.get_ref().clone(),
+        invalidator.get_ref().clone(),
+    )
+    .run()
+    .await;
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Run the startup diagnostics again: database, schema, signing keys, event
+/// backends, clock and configuration
+pub async fn diagnostics(diagnostics: web::Data<Arc<Diagnostics>>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(diagnostics.run().await))
+}
+
+/// An external event backend to attach while the server runs. `type` is
+/// `webhook` or `redis`; the rest is its `events.webhook` or
+/// `events.redis` settings.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventBackendRequest {
+    Webhook {
+        /// Defaults to `webhook`
+        name: Option<String>,
+        #[serde(flatten)]
+        config: WebhookEventConfig,
+    },
+    Redis {
+        /// Defaults to `redis`
+        name: Option<String>,
+        #[serde(flatten)]
+        config: RedisEventConfig,
+    },
+}
+
+impl EventBackendRequest {
+    fn plugin(self) -> Result<Arc<dyn EventPlugin>, OAuth2Error> {
+        let (name, plugin): (Option<String>, Result<Arc<dyn EventPlugin>, String>) = match self {
+            Self::Webhook { name, config } => {
+                config
+                    .validate()
+                    .map_err(|reason| OAuth2Error::invalid_request(&reason))?;
+                let plugin = WebhookEventPlugin::new(&config).map(|plugin| match &name {
+                    Some(name) => plugin.with_name(name),
+                    None => plugin,
+                });
+                (name, plugin.map(|plugin| Arc::new(plugin) as _))
+            }
+            Self::Redis { name, config } => {
+                config
+                    .validate()
+                    .map_err(|reason| OAuth2Error::invalid_request(&reason))?;
+                let plugin = RedisStreamEventPlugin::new(&config).map(|plugin| match &name {
+                    Some(name) => plugin.with_name(name),
+                    None => plugin,
+                });
+                (name, plugin.map(|plugin| Arc::new(plugin) as _))
+            }
+        };
+        // Names become buffer file names under `events.buffer_dir`
+        if let Some(name) = name {
+            if name.is_empty()
+                || name.len() > 64
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(OAuth2Error::invalid_request(
+                    "name must be 1 to 64 letters, digits, '-' or '_'",
+                ));
+            }
+        }
+        plugin.map_err(|reason| OAuth2Error::invalid_request(&reason))
+    }
+}
+
+fn events_disabled() -> HttpResponse {
+    HttpResponse::ServiceUnavailable().json(serde_json::json!({
+        "message": "Events are disabled on this server"
+    }))
+}
+
+/// The external event backends and how each is doing
+pub async fn list_event_plugins(
+    event_backends: web::Data<Arc<EventBackends>>,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(event_backends.statuses()))
+}
+
+/// Attach an external event backend without a restart, such as a webhook
+/// for the length of an incident. It is not kept across restarts.
+pub async fn attach_event_plugin(
+    body: web::Json<EventBackendRequest>,
+    event_backends: web::Data<Arc<EventBackends>>,
+    event_actor: Option<web::Data<Addr<EventActor>>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(event_actor) = event_actor else {
+        return Ok(events_disabled());
+    };
+    let backend = match event_backends.add(body.into_inner().plugin()?) {
+        Ok(backend) => backend,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Ok(HttpResponse::Conflict().json(serde_json::json!({
+                "message": e.to_string()
+            })));
+        }
+        Err(e) => return Err(OAuth2Error::internal(ErrorCode::ServerError, e)),
+    };
+    if let Err(e) = event_actor
+        .send(AddPlugin {
+            plugin: backend.clone(),
+        })
+        .await
+    {
+        event_backends.remove(backend.name());
+        return Err(e.into());
+    }
+    tracing::warn!("Event backend {} attached", backend.name());
+
+    Ok(HttpResponse::Created().json(backend.status()))
+}
+
+/// Detach an external event backend, configured or attached, without a
+/// restart. Answers with its last status, which shows any events it still
+/// had buffered.
+pub async fn detach_event_plugin(
+    name: web::Path<String>,
+    event_backends: web::Data<Arc<EventBackends>>,
+    event_actor: Option<web::Data<Addr<EventActor>>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(event_actor) = event_actor else {
+        return Ok(events_disabled());
+    };
+    let Some(backend) = event_backends.remove(&name) else {
+        return Ok(HttpResponse::NotFound().json(serde_json::json!({
+            "message": "Event backend not found"
+        })));
+    };
+    event_actor
+        .send(RemovePlugin {
+            plugin: backend.clone(),
+        })
+        .await?;
+    tracing::warn!("Event backend {} detached", backend.name());
+
+    Ok(HttpResponse::Ok().json(backend.status()))
+}
+
 /// Get system metrics
-pub async fn system_metrics(metrics: web::Data<Metrics>) -> Result<HttpResponse> {
+pub async fn system_metrics(
+    metrics: web::Data<Metrics>,
+    anomaly_detector: web::Data<Arc<AnomalyDetector>>,
+    event_backends: web::Data<Arc<EventBackends>>,
+) -> Result<HttpResponse> {
     use prometheus::Encoder;
+    metrics.record_anomalies(&anomaly_detector.snapshot());
+    metrics.record_event_backends(&event_backends.statuses());
+    metrics.record_process();
     let encoder = prometheus::TextEncoder::new();
     let metric_families = metrics.registry.gather();
     let mut buffer = vec![];
@@ -105,14 +1507,23 @@ pub async fn health() -> Result<HttpResponse> {
 }
 
 /// Readiness check endpoint
-pub async fn readiness(_db: web::Data<Arc<Database>>) -> Result<HttpResponse> {
+pub async fn readiness(
+    _db: web::Data<Arc<Database>>,
+    event_backends: web::Data<Arc<EventBackends>>,
+) -> Result<HttpResponse> {
     // Check database connectivity
     // In a real implementation, execute a simple query
 
+    // A degraded event backend buffers rather than losing events, so the
+    // server stays ready while it recovers
+    let backends = event_backends.statuses();
+    let degraded = backends.iter().any(|backend| backend.degraded);
+
     Ok(HttpResponse::Ok().json(serde_json::json!({
-        "status": "ready",
+        "status": if degraded { "degraded" } else { "ready" },
         "checks": {
-            "database": "ok"
+            "database": "ok",
+            "event_backends": backends,
         }
     })))
 }
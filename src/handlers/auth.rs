@@ -1,12 +1,40 @@
-use crate::models::{OAuth2Error, SocialLoginConfig, SocialUserInfo};
-use crate::services::SocialLoginService;
+use crate::cache::{Cache, CacheInvalidator};
+use crate::clock::Clock;
+use crate::config::{DeploymentMode, LoginConfig, RiskConfig};
+use crate::db::Database;
+use crate::events::{
+    event_actor::{EmitEvent, EventActor},
+    AuthEvent, EventSeverity, EventType,
+};
+use crate::handlers::admin::emit_credentials_revoked;
+use crate::handlers::portal::login_redirect;
+use crate::middleware::{start_session, FormCsrfToken};
+use crate::models::{ErrorCode, OAuth2Error, SocialLoginConfig, SocialUserInfo};
+use crate::services::{
+    apply_claim_mappings, collect_common_claims, AccountEraser, LoginRiskDetector, MfaService,
+    OriginResolver, ProviderClient, ReturnToValidator, RiskSignal, SocialLoginService,
+    UpstreamGrant, UpstreamTokenVault, DEFAULT_RETURN_TO,
+};
+use crate::telemetry::TraceContext;
+use crate::templates::{
+    AuthSuccessPage, LoginPage, LogoutAllPage, ProviderButton, StepUpPage, Templates,
+};
+use actix::Addr;
 use actix_session::Session;
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Duration, Utc};
 use oauth2::{
-    AuthorizationCode, CsrfToken, PkceCodeChallenge, Scope, TokenResponse as OAuth2TokenResponse,
+    basic::BasicTokenResponse, AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier,
+    Scope, TokenResponse as OAuth2TokenResponse,
 };
 use serde::Deserialize;
 use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+/// Wrong TOTP codes a held login may submit before it is discarded. The
+/// count lives in the session, so this slows guessing rather than capping it;
+/// the per-step replay check is what stops a code being reused.
+const MAX_MFA_ATTEMPTS: u32 = 5;
 
 #[derive(Deserialize)]
 pub struct AuthCallbackQuery {
@@ -14,9 +42,167 @@ pub struct AuthCallbackQuery {
     state: Option<String>,
 }
 
+/// Session keys of a social login waiting for the provider to send the user
+/// back
+const LOGIN_STATE: &str = "csrf_token";
+const LOGIN_STATE_EXPIRES_AT: &str = "csrf_expires_at";
+const LOGIN_PKCE_VERIFIER: &str = "pkce_verifier";
+const LOGIN_PROVIDER: &str = "provider";
+const LOGIN_CONNECT: &str = "connect_provider";
+
+/// A social login sent to the provider, as remembered in the session
+struct PendingLogin {
+    state: String,
+    expires_at: DateTime<Utc>,
+    provider: String,
+    pkce_verifier: Option<String>,
+    /// The user asked to connect the provider, keeping its tokens
+    connect: bool,
+}
+
+impl PendingLogin {
+    /// Remember the login until its state expires, replacing any earlier one
+    fn begin(
+        session: &Session,
+        provider: &str,
+        state: &CsrfToken,
+        pkce_verifier: Option<&PkceCodeVerifier>,
+        connect: bool,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), OAuth2Error> {
+        let insert = |key: &str, value: &str| {
+            session
+                .insert(key, value)
+                .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))
+        };
+        insert(LOGIN_STATE, state.secret())?;
+        insert(LOGIN_PROVIDER, provider)?;
+        session
+            .insert(LOGIN_STATE_EXPIRES_AT, expires_at.timestamp())
+            .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+        session
+            .insert(LOGIN_CONNECT, connect)
+            .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+        match pkce_verifier {
+            Some(verifier) => insert(LOGIN_PKCE_VERIFIER, verifier.secret())?,
+            None => {
+                session.remove(LOGIN_PKCE_VERIFIER);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the login out of the session, so whatever the callback makes of
+    /// it, it cannot be attempted again with this session
+    fn take(session: &Session) -> Option<Self> {
+        let state: Option<String> = session.get(LOGIN_STATE).unwrap_or(None);
+        let expires_at: Option<i64> = session.get(LOGIN_STATE_EXPIRES_AT).unwrap_or(None);
+        let provider: Option<String> = session.get(LOGIN_PROVIDER).unwrap_or(None);
+        let pkce_verifier: Option<String> = session.get(LOGIN_PKCE_VERIFIER).unwrap_or(None);
+        let connect: Option<bool> = session.get(LOGIN_CONNECT).unwrap_or(None);
+        for key in [
+            LOGIN_STATE,
+            LOGIN_STATE_EXPIRES_AT,
+            LOGIN_PROVIDER,
+            LOGIN_PKCE_VERIFIER,
+            LOGIN_CONNECT,
+        ] {
+            session.remove(key);
+        }
+
+        Some(Self {
+            state: state?,
+            expires_at: DateTime::from_timestamp(expires_at?, 0)?,
+            provider: provider?,
+            pkce_verifier,
+            connect: connect.unwrap_or(false),
+        })
+    }
+
+    fn matches_state(&self, state: &str) -> bool {
+        bool::from(self.state.as_bytes().ct_eq(state.as_bytes()))
+    }
+}
+
+/// Session key of where to send the user once the login completes
+const RETURN_TO: &str = "return_to";
+
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    return_to: Option<String>,
+    /// Connect the provider: ask for its API scopes and keep its tokens
+    #[serde(default)]
+    connect: bool,
+}
+
+/// Refuse to connect a provider whose tokens cannot be stored
+fn check_connect(
+    vault: &UpstreamTokenVault,
+    provider: &str,
+    connect: bool,
+) -> Result<(), OAuth2Error> {
+    if connect && !vault.can_store(provider) {
+        return Err(OAuth2Error::invalid_request(&format!(
+            "Connecting {} is not enabled",
+            provider
+        )));
+    }
+    Ok(())
+}
+
+/// Remember where the login was started from, if the user may be sent back
+/// there. Without a target, one remembered earlier (by the login page) stands.
+async fn remember_return_to(
+    session: &Session,
+    validator: &ReturnToValidator,
+    target: Option<&str>,
+) -> Result<(), OAuth2Error> {
+    let Some(target) = target else {
+        return Ok(());
+    };
+    if validator.is_allowed(target) {
+        session
+            .insert(RETURN_TO, target)
+            .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+    } else {
+        tracing::warn!("Ignoring return_to target that is not allowed: {}", target);
+        session.remove(RETURN_TO);
+    }
+    Ok(())
+}
+
+/// Where a completed login continues to, forgetting it for the next one
+fn take_return_to(session: &Session) -> String {
+    take_return_to_or(session, DEFAULT_RETURN_TO)
+}
+
+/// Like `take_return_to`, continuing to `default` when the login was not
+/// started from anywhere in particular
+pub(crate) fn take_return_to_or(session: &Session, default: &str) -> String {
+    let target: Option<String> = session.get(RETURN_TO).unwrap_or(None);
+    session.remove(RETURN_TO);
+    target.unwrap_or_else(|| default.to_string())
+}
+
+/// When a login started now must be back from the provider
+fn state_expiry(config: &LoginConfig, clock: &dyn Clock) -> DateTime<Utc> {
+    clock.now() + Duration::seconds(i64::try_from(config.state_ttl_secs).unwrap_or(i64::MAX))
+}
+
+#[derive(Deserialize)]
+pub struct StepUpForm {
+    code: String,
+    remember_device: Option<String>,
+}
+
 /// Initiate Google login
 pub async fn google_login(
+    query: web::Query<LoginQuery>,
     config: web::Data<Arc<SocialLoginConfig>>,
+    login_config: web::Data<Arc<LoginConfig>>,
+    return_to: web::Data<Arc<ReturnToValidator>>,
+    vault: web::Data<Arc<UpstreamTokenVault>>,
+    clock: web::Data<dyn Clock>,
     session: Session,
 ) -> Result<HttpResponse, OAuth2Error> {
     let provider_config = config.google.as_ref().ok_or_else(|| {
@@ -26,28 +212,35 @@ pub async fn google_login(
         )
     })?;
 
+    check_connect(&vault, "google", query.connect)?;
     let client = SocialLoginService::get_google_client(provider_config)?;
 
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
-    let (auth_url, csrf_token) = client
+    let scopes = SocialLoginService::login_scopes("google", provider_config, query.connect);
+    let mut request = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("openid".to_string()))
-        .add_scope(Scope::new("email".to_string()))
-        .add_scope(Scope::new("profile".to_string()))
-        .set_pkce_challenge(pkce_challenge)
-        .url();
+        .add_scopes(scopes.into_iter().map(Scope::new))
+        .set_pkce_challenge(pkce_challenge);
+    if query.connect {
+        // Google only issues a refresh token for offline access, and only
+        // when the user is asked for consent
+        request = request
+            .add_extra_param("access_type", "offline")
+            .add_extra_param("prompt", "consent");
+    }
+    let (auth_url, csrf_token) = request.url();
 
     // Store CSRF token and PKCE verifier in session
-    session
-        .insert("csrf_token", csrf_token.secret())
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
-    session
-        .insert("pkce_verifier", pkce_verifier.secret())
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
-    session
-        .insert("provider", "google")
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
+    remember_return_to(&session, &return_to, query.return_to.as_deref()).await?;
+    PendingLogin::begin(
+        &session,
+        "google",
+        &csrf_token,
+        Some(&pkce_verifier),
+        query.connect,
+        state_expiry(&login_config, clock.as_ref()),
+    )?;
 
     Ok(HttpResponse::Found()
         .append_header(("Location", auth_url.to_string()))
@@ -56,7 +249,12 @@ pub async fn google_login(
 
 /// Initiate Microsoft login
 pub async fn microsoft_login(
+    query: web::Query<LoginQuery>,
     config: web::Data<Arc<SocialLoginConfig>>,
+    login_config: web::Data<Arc<LoginConfig>>,
+    return_to: web::Data<Arc<ReturnToValidator>>,
+    vault: web::Data<Arc<UpstreamTokenVault>>,
+    clock: web::Data<dyn Clock>,
     session: Session,
 ) -> Result<HttpResponse, OAuth2Error> {
     let provider_config = config.microsoft.as_ref().ok_or_else(|| {
@@ -66,21 +264,24 @@ pub async fn microsoft_login(
         )
     })?;
 
+    check_connect(&vault, "microsoft", query.connect)?;
     let client = SocialLoginService::get_microsoft_client(provider_config)?;
 
+    let scopes = SocialLoginService::login_scopes("microsoft", provider_config, query.connect);
     let (auth_url, csrf_token) = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("openid".to_string()))
-        .add_scope(Scope::new("email".to_string()))
-        .add_scope(Scope::new("profile".to_string()))
+        .add_scopes(scopes.into_iter().map(Scope::new))
         .url();
 
-    session
-        .insert("csrf_token", csrf_token.secret())
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
-    session
-        .insert("provider", "microsoft")
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
+    remember_return_to(&session, &return_to, query.return_to.as_deref()).await?;
+    PendingLogin::begin(
+        &session,
+        "microsoft",
+        &csrf_token,
+        None,
+        query.connect,
+        state_expiry(&login_config, clock.as_ref()),
+    )?;
 
     Ok(HttpResponse::Found()
         .append_header(("Location", auth_url.to_string()))
@@ -89,7 +290,12 @@ pub async fn microsoft_login(
 
 /// Initiate GitHub login
 pub async fn github_login(
+    query: web::Query<LoginQuery>,
     config: web::Data<Arc<SocialLoginConfig>>,
+    login_config: web::Data<Arc<LoginConfig>>,
+    return_to: web::Data<Arc<ReturnToValidator>>,
+    vault: web::Data<Arc<UpstreamTokenVault>>,
+    clock: web::Data<dyn Clock>,
     session: Session,
 ) -> Result<HttpResponse, OAuth2Error> {
     let provider_config = config.github.as_ref().ok_or_else(|| {
@@ -99,19 +305,24 @@ pub async fn github_login(
         )
     })?;
 
+    check_connect(&vault, "github", query.connect)?;
     let client = SocialLoginService::get_github_client(provider_config)?;
 
+    let scopes = SocialLoginService::login_scopes("github", provider_config, query.connect);
     let (auth_url, csrf_token) = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("user:email".to_string()))
+        .add_scopes(scopes.into_iter().map(Scope::new))
         .url();
 
-    session
-        .insert("csrf_token", csrf_token.secret())
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
-    session
-        .insert("provider", "github")
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
+    remember_return_to(&session, &return_to, query.return_to.as_deref()).await?;
+    PendingLogin::begin(
+        &session,
+        "github",
+        &csrf_token,
+        None,
+        query.connect,
+        state_expiry(&login_config, clock.as_ref()),
+    )?;
 
     Ok(HttpResponse::Found()
         .append_header(("Location", auth_url.to_string()))
@@ -119,135 +330,344 @@ pub async fn github_login(
 }
 
 /// Handle OAuth callback from providers
+#[allow(clippy::too_many_arguments)]
 pub async fn auth_callback(
+    req: HttpRequest,
     query: web::Query<AuthCallbackQuery>,
     provider: web::Path<String>,
     config: web::Data<Arc<SocialLoginConfig>>,
     session: Session,
+    origin_resolver: web::Data<Arc<OriginResolver>>,
+    risk_detector: web::Data<Arc<LoginRiskDetector>>,
+    risk_config: web::Data<Arc<RiskConfig>>,
+    mfa: web::Data<Arc<MfaService>>,
+    eraser: web::Data<Arc<AccountEraser>>,
+    vault: web::Data<Arc<UpstreamTokenVault>>,
+    cache: web::Data<dyn Cache>,
+    clock: web::Data<dyn Clock>,
+    event_actor: Option<web::Data<Addr<EventActor>>>,
 ) -> Result<HttpResponse, OAuth2Error> {
-    // Verify CSRF token
-    let stored_csrf: Option<String> = session
-        .get("csrf_token")
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
-
-    if let Some(state) = &query.state {
-        if Some(state.clone()) != stored_csrf {
-            return Err(OAuth2Error::access_denied("CSRF token mismatch"));
-        }
+    // Verify CSRF token. The pending login is used up by this attempt
+    // whether or not it succeeds.
+    let pending = PendingLogin::take(&session);
+    let state = query
+        .state
+        .as_deref()
+        .ok_or_else(|| OAuth2Error::access_denied("Missing state parameter"))?;
+    let pending = pending.ok_or_else(|| OAuth2Error::access_denied("No login in progress"))?;
+    if !pending.matches_state(state) {
+        return Err(OAuth2Error::access_denied("CSRF token mismatch"));
     }
-
-    let stored_provider: Option<String> = session
-        .get("provider")
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
-
-    if stored_provider.as_deref() != Some(provider.as_str()) {
+    let now = clock.now();
+    if now > pending.expires_at {
+        return Err(OAuth2Error::access_denied(
+            "Login took too long, please sign in again",
+        ));
+    }
+    if pending.provider != provider.as_str() {
         return Err(OAuth2Error::invalid_request("Provider mismatch"));
     }
 
-    // Exchange code for token based on provider
-    let user_info = match provider.as_str() {
-        "google" => handle_google_callback(&query.code, config.as_ref(), &session).await?,
-        "microsoft" => handle_microsoft_callback(&query.code, config.as_ref(), &session).await?,
-        "github" => handle_github_callback(&query.code, config.as_ref(), &session).await?,
+    // The session is a cookie, so an old copy of it still holds the state;
+    // the server-side record is what makes the callback single-use. It need
+    // only outlive the state itself.
+    let ttl = (pending.expires_at - now).to_std().unwrap_or_default();
+    if !cache
+        .insert(&format!("login_state:{}", pending.state), "1", ttl)
+        .await
+        .map_err(|e| OAuth2Error::internal(ErrorCode::TemporarilyUnavailable, e))?
+    {
+        tracing::warn!("Rejected replayed {} login callback", provider);
+        return Err(OAuth2Error::access_denied("Login state already used"));
+    }
+
+    // Exchange code for token based on provider, in the caller's trace
+    let provider_client = ProviderClient::new(&provider, TraceContext::from_request(&req));
+    let (mut user_info, token_response) = match provider.as_str() {
+        "google" => {
+            handle_google_callback(
+                &query.code,
+                config.as_ref(),
+                &provider_client,
+                pending.pkce_verifier,
+            )
+            .await?
+        }
+        "microsoft" => {
+            handle_microsoft_callback(&query.code, config.as_ref(), &provider_client).await?
+        }
+        "github" => handle_github_callback(&query.code, config.as_ref(), &provider_client).await?,
         _ => return Err(OAuth2Error::invalid_request("Unsupported provider")),
     };
+    collect_common_claims(&mut user_info);
+    if let Some(provider_config) = config.provider(&provider) {
+        apply_claim_mappings(&provider_config.claim_mappings, &mut user_info);
+    }
+    if !user_info.roles.is_empty() || !user_info.groups.is_empty() {
+        tracing::info!(
+            "{} mapped to roles [{}] and groups [{}]",
+            user_info.subject(),
+            user_info.roles.join(", "),
+            user_info.groups.join(", ")
+        );
+    }
+
+    // Compare where this login came from with the user's previous logins
+    let origin = origin_resolver.resolve(&req);
+    let subject = user_info.subject();
+    if eraser.is_pending(&subject).await? {
+        tracing::warn!("Refusing login by {}, which is pending erasure", subject);
+        return Err(OAuth2Error::access_denied(
+            "This account is scheduled for deletion",
+        ));
+    }
+    if pending.connect {
+        let requested = config
+            .provider(&provider)
+            .map(|provider_config| {
+                SocialLoginService::login_scopes(&provider, provider_config, true)
+            })
+            .unwrap_or_default();
+        let grant = UpstreamGrant::from_response(&token_response, &requested, now);
+        vault.store(&subject, &provider, &grant).await?;
+        vault.record_profile(&user_info).await?;
+    }
+    let signals = match &origin.location {
+        Some(location) => risk_detector.assess(&subject, location, clock.now()),
+        None => Vec::new(),
+    };
+    let step_up_required = !signals.is_empty() && risk_config.step_up_on_suspicious_login;
+
+    // Users with a second factor are challenged unless this browser is
+    // remembered; a suspicious login is challenged even on a remembered one
+    let mfa_required = mfa.is_enrolled(&subject).await?
+        && (step_up_required || !mfa.is_trusted_device(&req, &subject).await?);
+
+    if let Some(event_actor) = &event_actor {
+        let event = AuthEvent::new(
+            EventType::UserAuthenticated,
+            EventSeverity::Info,
+            Some(subject.clone()),
+            None,
+        )
+        .with_metadata("provider", provider.as_str());
+        let event = if user_info.roles.is_empty() {
+            event
+        } else {
+            event.with_metadata("roles", user_info.roles.join(","))
+        };
+        let event = if user_info.groups.is_empty() {
+            event
+        } else {
+            event.with_metadata("groups", user_info.groups.join(","))
+        };
+        event_actor.do_send(EmitEvent {
+            event: origin.annotate(event),
+        });
+
+        for signal in &signals {
+            let event = AuthEvent::new(
+                EventType::SuspiciousLogin,
+                EventSeverity::Critical,
+                Some(subject.clone()),
+                None,
+            )
+            .with_metadata("provider", provider.as_str())
+            .with_metadata("signal", signal.as_str())
+            .with_metadata("detail", signal.describe())
+            .with_metadata("step_up_required", step_up_required.to_string());
+            event_actor.do_send(EmitEvent {
+                event: origin.annotate(event),
+            });
+        }
+    }
 
     // Store user info in session
     session
         .insert("user_info", serde_json::to_string(&user_info).unwrap())
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
+        .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+    start_session(&session, clock.now())
+        .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+
+    if step_up_required || mfa_required {
+        if step_up_required {
+            tracing::warn!("Holding suspicious login by {} for step-up", subject);
+        }
+        let reasons: Vec<String> = if step_up_required {
+            signals.iter().map(RiskSignal::describe).collect()
+        } else {
+            Vec::new()
+        };
+        session.remove("authenticated");
+        session.remove("mfa_attempts");
+        session
+            .insert("step_up_reasons", reasons)
+            .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/auth/step-up"))
+            .finish());
+    }
+
     session
         .insert("authenticated", true)
-        .map_err(|e| OAuth2Error::new("session_error", Some(&e.to_string())))?;
+        .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
 
-    // Redirect to success page
+    // Back to where the login started, or the success page
     Ok(HttpResponse::Found()
-        .append_header(("Location", "/auth/success"))
+        .append_header(("Location", take_return_to(&session)))
         .finish())
 }
 
 async fn handle_google_callback(
     code: &str,
     config: &SocialLoginConfig,
-    _session: &Session,
-) -> Result<SocialUserInfo, OAuth2Error> {
+    provider_client: &ProviderClient,
+    pkce_verifier: Option<String>,
+) -> Result<(SocialUserInfo, BasicTokenResponse), OAuth2Error> {
     let provider_config = config.google.as_ref().ok_or_else(|| {
         OAuth2Error::new("provider_not_configured", Some("Google not configured"))
     })?;
 
     let client = SocialLoginService::get_google_client(provider_config)?;
 
-    // TODO: Reuse a shared reqwest::Client instance for better performance
-    // HTTP clients maintain connection pools and should be created once and reused
-    let http_client = reqwest::Client::new();
+    let pkce_verifier =
+        pkce_verifier.ok_or_else(|| OAuth2Error::invalid_request("Missing PKCE verifier"))?;
+
     let token_result = client
         .exchange_code(AuthorizationCode::new(code.to_string()))
-        .request_async(&http_client)
+        .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
+        .request_async(&provider_client.oauth2("token_exchange"))
         .await
-        .map_err(|e| OAuth2Error::new("token_exchange_failed", Some(&e.to_string())))?;
+        .map_err(|e| OAuth2Error::internal(ErrorCode::TokenExchangeFailed, e))?;
 
     let access_token = token_result.access_token().secret();
-    SocialLoginService::fetch_google_user_info(access_token).await
+    let user_info =
+        SocialLoginService::fetch_google_user_info(provider_client, provider_config, access_token)
+            .await?;
+    Ok((user_info, token_result))
 }
 
 async fn handle_microsoft_callback(
     code: &str,
     config: &SocialLoginConfig,
-    _session: &Session,
-) -> Result<SocialUserInfo, OAuth2Error> {
+    provider_client: &ProviderClient,
+) -> Result<(SocialUserInfo, BasicTokenResponse), OAuth2Error> {
     let provider_config = config.microsoft.as_ref().ok_or_else(|| {
         OAuth2Error::new("provider_not_configured", Some("Microsoft not configured"))
     })?;
 
     let client = SocialLoginService::get_microsoft_client(provider_config)?;
 
-    // TODO: Reuse a shared reqwest::Client instance for better performance
-    let http_client = reqwest::Client::new();
     let token_result = client
         .exchange_code(AuthorizationCode::new(code.to_string()))
-        .request_async(&http_client)
+        .request_async(&provider_client.oauth2("token_exchange"))
         .await
-        .map_err(|e| OAuth2Error::new("token_exchange_failed", Some(&e.to_string())))?;
+        .map_err(|e| OAuth2Error::internal(ErrorCode::TokenExchangeFailed, e))?;
 
     let access_token = token_result.access_token().secret();
-    SocialLoginService::fetch_microsoft_user_info(access_token).await
+    let user_info = SocialLoginService::fetch_microsoft_user_info(
+        provider_client,
+        provider_config,
+        access_token,
+    )
+    .await?;
+    Ok((user_info, token_result))
 }
 
 async fn handle_github_callback(
     code: &str,
     config: &SocialLoginConfig,
-    _session: &Session,
-) -> Result<SocialUserInfo, OAuth2Error> {
+    provider_client: &ProviderClient,
+) -> Result<(SocialUserInfo, BasicTokenResponse), OAuth2Error> {
     let provider_config = config.github.as_ref().ok_or_else(|| {
         OAuth2Error::new("provider_not_configured", Some("GitHub not configured"))
     })?;
 
     let client = SocialLoginService::get_github_client(provider_config)?;
 
-    // TODO: Reuse a shared reqwest::Client instance for better performance
-    let http_client = reqwest::Client::new();
     let token_result = client
         .exchange_code(AuthorizationCode::new(code.to_string()))
-        .request_async(&http_client)
+        .request_async(&provider_client.oauth2("token_exchange"))
         .await
-        .map_err(|e| OAuth2Error::new("token_exchange_failed", Some(&e.to_string())))?;
+        .map_err(|e| OAuth2Error::internal(ErrorCode::TokenExchangeFailed, e))?;
 
     let access_token = token_result.access_token().secret();
-    SocialLoginService::fetch_github_user_info(access_token).await
+    let user_info =
+        SocialLoginService::fetch_github_user_info(provider_client, provider_config, access_token)
+            .await?;
+    Ok((user_info, token_result))
+}
+
+/// Providers with a login handler, in display order. Azure AD signs in
+/// through the Microsoft endpoint, and Okta/Auth0 have no handler yet, so
+/// none of them get a button of their own.
+const LOGIN_PROVIDERS: &[ProviderButton] = &[
+    ProviderButton {
+        id: "google",
+        label: "Google",
+    },
+    ProviderButton {
+        id: "microsoft",
+        label: "Microsoft",
+    },
+    ProviderButton {
+        id: "github",
+        label: "GitHub",
+    },
+];
+
+/// Login buttons for the providers that are actually configured
+fn configured_providers(config: &SocialLoginConfig) -> Vec<ProviderButton> {
+    LOGIN_PROVIDERS
+        .iter()
+        .filter(|provider| config.is_configured(provider.id))
+        .cloned()
+        .collect()
 }
 
 /// Display login page
-pub async fn login_page() -> Result<HttpResponse> {
-    let html = std::fs::read_to_string("templates/login.html")
-        .unwrap_or_else(|_| include_str!("../../templates/login.html").to_string());
+#[allow(clippy::too_many_arguments)]
+pub async fn login_page(
+    query: web::Query<LoginQuery>,
+    config: web::Data<Arc<SocialLoginConfig>>,
+    login_config: web::Data<Arc<LoginConfig>>,
+    return_to: web::Data<Arc<ReturnToValidator>>,
+    mode: web::Data<DeploymentMode>,
+    session: Session,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse> {
+    remember_return_to(&session, &return_to, query.return_to.as_deref()).await?;
+    let page = LoginPage {
+        providers: configured_providers(&config),
+        local_login_enabled: login_config.local_login_enabled,
+        demo: mode.is_demo(),
+    };
 
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(html))
+    Ok(templates.render_form_response("login.html", &page, csrf.as_str()))
+}
+
+/// Return the signed-in user from the session, if any
+pub fn session_user(session: &Session) -> Option<SocialUserInfo> {
+    let authenticated: Option<bool> = session.get("authenticated").unwrap_or(None);
+    if !authenticated.unwrap_or(false) {
+        return None;
+    }
+
+    pending_user(session)
+}
+
+/// The user whose login is in progress, whether or not it has completed
+fn pending_user(session: &Session) -> Option<SocialUserInfo> {
+    let user_info: Option<String> = session.get("user_info").unwrap_or(None);
+    user_info.and_then(|json| serde_json::from_str(&json).ok())
 }
 
 /// Authentication success page
-pub async fn auth_success(session: Session) -> Result<HttpResponse> {
+pub async fn auth_success(
+    session: Session,
+    templates: web::Data<Arc<Templates>>,
+) -> Result<HttpResponse> {
     let authenticated: Option<bool> = session.get("authenticated").unwrap_or(None);
 
     if !authenticated.unwrap_or(false) {
@@ -256,39 +676,184 @@ pub async fn auth_success(session: Session) -> Result<HttpResponse> {
             .finish());
     }
 
-    let user_info: Option<String> = session.get("user_info").unwrap_or(None);
+    let page = AuthSuccessPage {
+        user: session_user(&session),
+    };
 
-    let html = format!(
-        r#"
-        <!DOCTYPE html>
-        <html>
-        <head>
-            <title>Login Success</title>
-            <link rel="stylesheet" href="/static/css/admin.css">
-        </head>
-        <body>
-            <div class="container">
-                <h1>Login Successful!</h1>
-                <p>You have been authenticated successfully.</p>
-                <pre>{}</pre>
-                <a href="/admin">Go to Dashboard</a>
-            </div>
-        </body>
-        </html>
-        "#,
-        user_info.unwrap_or_else(|| "No user info".to_string())
-    );
+    Ok(templates.render_response("auth_success.html", &page))
+}
+
+/// Shown while a login is held for a second factor, either because the user
+/// has TOTP enabled or because the login was flagged as suspicious. The
+/// session holds the user but is not marked authenticated.
+pub async fn step_up(
+    session: Session,
+    mfa: web::Data<Arc<MfaService>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let reasons: Option<Vec<String>> = session.get("step_up_reasons").unwrap_or(None);
+    let (Some(reasons), Some(user)) = (reasons, pending_user(&session)) else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/auth/login"))
+            .finish());
+    };
+
+    render_step_up(&templates, &csrf, &mfa, &user.subject(), reasons, None).await
+}
+
+/// Complete a held login with a TOTP code, optionally remembering the device
+pub async fn verify_step_up(
+    req: HttpRequest,
+    form: web::Form<StepUpForm>,
+    session: Session,
+    mfa: web::Data<Arc<MfaService>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+    event_actor: Option<web::Data<Addr<EventActor>>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let reasons: Option<Vec<String>> = session.get("step_up_reasons").unwrap_or(None);
+    let (Some(reasons), Some(user)) = (reasons, pending_user(&session)) else {
+        return Ok(HttpResponse::Found()
+            .append_header(("Location", "/auth/login"))
+            .finish());
+    };
+    let subject = user.subject();
+
+    if !mfa.verify(&subject, &form.code).await? {
+        let attempts = session
+            .get::<u32>("mfa_attempts")
+            .unwrap_or(None)
+            .unwrap_or(0)
+            + 1;
+        if let Some(event_actor) = &event_actor {
+            let event = AuthEvent::new(
+                EventType::UserAuthenticationFailed,
+                EventSeverity::Warning,
+                Some(subject.clone()),
+                None,
+            )
+            .with_metadata("reason", "invalid_mfa_code")
+            .with_metadata("attempts", attempts.to_string());
+            event_actor.do_send(EmitEvent { event });
+        }
 
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(html))
+        if attempts >= MAX_MFA_ATTEMPTS {
+            tracing::warn!(
+                "Discarding login by {} after {} bad codes",
+                subject,
+                attempts
+            );
+            session.purge();
+            return Ok(HttpResponse::Found()
+                .append_header(("Location", "/auth/login"))
+                .finish());
+        }
+        session
+            .insert("mfa_attempts", attempts)
+            .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+        let error = Some("That code didn't work. Check your authenticator app and try again.");
+        return render_step_up(&templates, &csrf, &mfa, &subject, reasons, error).await;
+    }
+
+    session.remove("step_up_reasons");
+    session.remove("mfa_attempts");
+    session
+        .insert("authenticated", true)
+        .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+
+    let mut response = HttpResponse::Found();
+    response.append_header(("Location", take_return_to(&session)));
+    if form.remember_device.is_some() {
+        if let Some(cookie) = mfa.trust_device(&req, &subject).await? {
+            response.cookie(cookie);
+        }
+    }
+    Ok(response.finish())
+}
+
+async fn render_step_up(
+    templates: &Templates,
+    csrf: &FormCsrfToken,
+    mfa: &MfaService,
+    subject: &str,
+    reasons: Vec<String>,
+    error: Option<&str>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let page = StepUpPage {
+        reasons,
+        mfa_enrolled: mfa.is_enrolled(subject).await?,
+        trusted_device_days: mfa.trusted_device_days(),
+        error: error.map(str::to_string),
+    };
+    Ok(templates.render_form_response("step_up.html", &page, csrf.as_str()))
 }
 
 /// Logout handler
-pub async fn logout(session: Session) -> Result<HttpResponse> {
+pub async fn logout(
+    session: Session,
+    config: web::Data<Arc<SocialLoginConfig>>,
+) -> Result<HttpResponse> {
+    // Through the provider's logout if it is set up for it, so its session
+    // does not sign the user straight back in
+    let upstream = pending_user(&session).and_then(|user| {
+        let provider_config = config.provider(&user.provider)?;
+        SocialLoginService::logout_url(&user.provider, provider_config)
+    });
     session.purge();
 
     Ok(HttpResponse::Found()
-        .append_header(("Location", "/auth/login"))
+        .append_header(("Location", upstream.as_deref().unwrap_or("/auth/login")))
         .finish())
 }
+
+/// Ask the signed-in user to confirm signing out everywhere
+pub async fn logout_all_confirm(
+    session: Session,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> HttpResponse {
+    let Some(user) = session_user(&session) else {
+        return login_redirect();
+    };
+
+    let page = LogoutAllPage {
+        user_email: user.email,
+        tokens_revoked: None,
+    };
+    templates.render_form_response("logout_all.html", &page, csrf.as_str())
+}
+
+/// Revoke every token the user holds across clients and end all of their
+/// sessions, this one included
+pub async fn logout_all(
+    session: Session,
+    db: web::Data<Arc<Database>>,
+    clock: web::Data<dyn Clock>,
+    templates: web::Data<Arc<Templates>>,
+    event_actor: Option<web::Data<Addr<EventActor>>>,
+    invalidator: web::Data<Arc<CacheInvalidator>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect());
+    };
+    let subject = user.subject();
+
+    let revoked = db
+        .revoke_user_credentials(&subject, "logout_all", clock.now())
+        .await?;
+    invalidator.forget_tokens(&revoked.revoked_tokens).await;
+    tracing::info!(
+        "Signed {} out everywhere, revoking {} tokens",
+        subject,
+        revoked.tokens
+    );
+    emit_credentials_revoked(event_actor.as_ref(), &subject, "logout_all", &revoked);
+    session.purge();
+
+    let page = LogoutAllPage {
+        user_email: user.email,
+        tokens_revoked: Some(revoked.tokens),
+    };
+    Ok(templates.render_response("logout_all.html", &page))
+}
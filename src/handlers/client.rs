@@ -1,23 +1,121 @@
 use crate::actors::{ClientActor, RegisterClient};
-use crate::models::{ClientCredentials, ClientRegistration, OAuth2Error};
+use crate::config::{AdminAccessConfig, SecurityProfile};
+use crate::models::{
+    normalize_origin, validate_redirect_uri_pattern, ClientCredentials, ClientRegistration,
+    OAuth2Error, TokenEndpointAuthMethod,
+};
+use crate::services::RegistrationGate;
 use actix::Addr;
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Result};
+use std::sync::Arc;
 
-/// Register a new OAuth2 client
+/// Register a new OAuth2 client, with a registration token as the bearer
+/// token when registration is gated
 pub async fn register_client(
+    req: HttpRequest,
     registration: web::Json<ClientRegistration>,
     client_actor: web::Data<Addr<ClientActor>>,
+    gate: web::Data<Arc<RegistrationGate>>,
+    profile: web::Data<SecurityProfile>,
+    admin_access: web::Data<Arc<AdminAccessConfig>>,
 ) -> Result<HttpResponse, OAuth2Error> {
+    let mut registration = registration.into_inner();
+    if !profile.allows_public_clients()
+        && registration.token_endpoint_auth_method == TokenEndpointAuthMethod::None
+    {
+        return Err(OAuth2Error::invalid_request(
+            "Public clients cannot be registered under the strict security profile",
+        ));
+    }
+    if !profile.allows_password_grant()
+        && registration
+            .grant_types
+            .iter()
+            .any(|grant| grant == "password")
+    {
+        return Err(OAuth2Error::invalid_request(
+            "The password grant is disabled under the strict security profile",
+        ));
+    }
+    registration.allowed_origins = registration
+        .allowed_origins
+        .iter()
+        .map(|origin| {
+            normalize_origin(origin).ok_or_else(|| {
+                OAuth2Error::invalid_request(&format!("Invalid allowed origin: {}", origin))
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    if let Some(uri) = registration
+        .redirect_uris
+        .iter()
+        .find(|uri| uri.contains('*'))
+    {
+        return Err(OAuth2Error::invalid_request(&format!(
+            "Redirect URI {} has a wildcard; register it in redirect_uri_patterns to opt in",
+            uri
+        )));
+    }
+    registration
+        .token_endpoint_auth_method
+        .check_grant_types(&registration.grant_types)
+        .map_err(|reason| OAuth2Error::invalid_request(&reason))?;
+    let mut warnings = Vec::new();
+    for pattern in &registration.redirect_uri_patterns {
+        warnings.extend(
+            validate_redirect_uri_pattern(pattern)
+                .map_err(|reason| OAuth2Error::invalid_request(&reason))?,
+        );
+    }
+
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Only an admin hands out admin scopes, through a registration token
+    if bearer.is_none()
+        && registration
+            .scope
+            .split_whitespace()
+            .any(|scope| admin_access.reserves_scope(scope))
+    {
+        return Err(OAuth2Error::invalid_scope(
+            "Admin scopes need a registration token from an admin",
+        ));
+    }
+    let registration_token = gate.admit(bearer, &registration).await?;
+
     let client = client_actor
         .send(RegisterClient {
-            registration: registration.into_inner(),
+            registration,
+            owner_id: None,
+            owner_org: None,
         })
-        .await
-        .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))??;
+        .await??;
 
+    if let Some(token) = registration_token {
+        tracing::info!(
+            "Client {} registered with registration token {}",
+            client.client_id,
+            token.id
+        );
+    }
+    let patterns = client.get_redirect_uri_patterns();
+    if !patterns.is_empty() {
+        tracing::warn!(
+            "Client {} registered wildcard redirect URI patterns: {}",
+            client.client_id,
+            patterns.join(" ")
+        );
+    }
     let credentials = ClientCredentials {
+        token_endpoint_auth_method: client.auth_method(),
         client_id: client.client_id,
         client_secret: client.client_secret,
+        secret_expires_at: client.secret_expires_at,
+        warnings,
     };
 
     Ok(HttpResponse::Created().json(credentials))
@@ -0,0 +1,156 @@
+//! The demo mode playground: sign in as the demo user and run an
+//! authorization code flow with PKCE as the demo web client. Only routed
+//! with `OAUTH2_MODE=demo`.
+
+use crate::clock::Clock;
+use crate::handlers::auth::{session_user, take_return_to_or};
+use crate::middleware::{start_session, FormCsrfToken};
+use crate::models::{ErrorCode, OAuth2Error};
+use crate::services::{
+    demo_user, DEMO_CALLBACK_PATH, DEMO_CLIENTS, DEMO_PASSWORD, DEMO_USERNAME, DEMO_WEB_CLIENT,
+};
+use crate::templates::{DemoCallbackPage, DemoPage, Templates};
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse};
+use oauth2::{CsrfToken, PkceCodeChallenge};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Session key of the flow the playground started
+const DEMO_FLOW: &str = "demo_flow";
+
+/// Scope the playground asks for
+const DEMO_SCOPE: &str = "openid profile email read";
+
+/// What the callback needs from the start of the flow
+#[derive(Debug, Serialize, Deserialize)]
+struct DemoFlow {
+    state: String,
+    code_verifier: String,
+    redirect_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// The playground: the demo credentials and a button to start a flow
+pub async fn playground(
+    session: Session,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> HttpResponse {
+    let page = DemoPage {
+        user_email: session_user(&session).map(|user| user.email),
+        username: DEMO_USERNAME,
+        password: DEMO_PASSWORD,
+        clients: DEMO_CLIENTS,
+    };
+    templates.render_form_response("demo.html", &page, csrf.as_str())
+}
+
+/// Sign in as the demo user, then carry on with the login that was
+/// interrupted, if any
+pub async fn login(
+    session: Session,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let user = serde_json::to_string(&demo_user())
+        .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+    session
+        .insert("user_info", user)
+        .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+    start_session(&session, clock.now())
+        .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+    session
+        .insert("authenticated", true)
+        .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", take_return_to_or(&session, "/demo")))
+        .finish())
+}
+
+/// Start an authorization code flow as the demo web client, coming back to
+/// the callback on the host the browser used
+pub async fn start(req: HttpRequest, session: Session) -> Result<HttpResponse, OAuth2Error> {
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+    let redirect_uri = {
+        let info = req.connection_info();
+        format!("{}://{}{}", info.scheme(), info.host(), DEMO_CALLBACK_PATH)
+    };
+    let flow = DemoFlow {
+        state: CsrfToken::new_random().secret().clone(),
+        code_verifier: verifier.secret().clone(),
+        redirect_uri,
+    };
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", DEMO_WEB_CLIENT.client_id),
+        ("redirect_uri", &flow.redirect_uri),
+        ("scope", DEMO_SCOPE),
+        ("state", &flow.state),
+        ("code_challenge", challenge.as_str()),
+        ("code_challenge_method", "S256"),
+    ])
+    .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+    session
+        .insert(DEMO_FLOW, &flow)
+        .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+
+    Ok(HttpResponse::Found()
+        .append_header(("Location", format!("/oauth/authorize?{}", query)))
+        .finish())
+}
+
+/// Where the flow comes back to. The page exchanges the code and calls the
+/// userinfo endpoint from the browser, as a client would.
+pub async fn callback(
+    query: web::Query<CallbackQuery>,
+    session: Session,
+    templates: web::Data<Arc<Templates>>,
+) -> HttpResponse {
+    let flow: Option<DemoFlow> = session.get(DEMO_FLOW).unwrap_or(None);
+    session.remove(DEMO_FLOW);
+
+    let outcome = match (flow, &query.code) {
+        _ if query.error.is_some() => Err(format!(
+            "{}: {}",
+            query.error.as_deref().unwrap_or_default(),
+            query
+                .error_description
+                .as_deref()
+                .unwrap_or("no description")
+        )),
+        (None, _) => Err("No flow was started from the playground".to_string()),
+        (Some(flow), _) if query.state.as_deref() != Some(flow.state.as_str()) => {
+            Err("The state does not match the flow the playground started".to_string())
+        }
+        (Some(_), None) => Err("The response carries no code".to_string()),
+        (Some(flow), Some(code)) => Ok((flow, code.clone())),
+    };
+
+    let page = match outcome {
+        Ok((flow, code)) => DemoCallbackPage {
+            error: None,
+            code: Some(code),
+            code_verifier: Some(flow.code_verifier),
+            redirect_uri: Some(flow.redirect_uri),
+            client_id: DEMO_WEB_CLIENT.client_id,
+            client_secret: DEMO_WEB_CLIENT.client_secret,
+        },
+        Err(error) => DemoCallbackPage {
+            error: Some(error),
+            code: None,
+            code_verifier: None,
+            redirect_uri: None,
+            client_id: DEMO_WEB_CLIENT.client_id,
+            client_secret: DEMO_WEB_CLIENT.client_secret,
+        },
+    };
+    templates.render_response("demo_callback.html", &page)
+}
@@ -1,6 +1,10 @@
+pub mod account;
 pub mod admin;
 pub mod auth;
 pub mod client;
+pub mod demo;
 pub mod oauth;
+pub mod portal;
+pub mod saml;
 pub mod token;
 pub mod wellknown;
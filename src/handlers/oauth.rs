@@ -1,12 +1,44 @@
-use crate::actors::{AuthActor, CreateAuthorizationCode, CreateToken, TokenActor};
-use crate::models::{OAuth2Error, TokenResponse};
+use crate::actors::{
+    AuthActor, AuthenticateUser, CheckPkce, ClientActor, CreateAuthorizationCode, CreateIdToken,
+    CreateToken, GetClient, RefreshAccessToken, TokenActor, ValidateClient, ValidateToken,
+};
+use crate::clock::Clock;
+use crate::config::{DeploymentMode, SecurityProfile};
+use crate::db::Database;
+use crate::events::{
+    event_actor::{EmitEvent, EventActor},
+    AuthEvent, EventSeverity, EventType,
+};
+use crate::handlers::auth::session_user;
+use crate::handlers::portal::login_redirect_to;
+use crate::middleware::{
+    browser_state, redirect_uri_origin, session_started_at, session_state, FormCsrfToken,
+    BROWSER_STATE_COOKIE,
+};
+use crate::models::{
+    scope::validate_scopes, Client, DeniedAuthorization, ErrorCode, FlowId, IssuedAuthorization,
+    OAuth2Error, Resource, SocialUserInfo, TokenResponse, UserInfo, FLOW_ID_PARAM,
+    SUPPORTED_GRANT_TYPES,
+};
+use crate::services::{
+    AuthorizationPolicy, OriginResolver, PolicyInput, RequestOrigin, RequestValidator,
+    TokenAdmission, UserAuthenticator, WorkloadIdentityVerifier, JWT_BEARER_ASSERTION_TYPE,
+};
+use crate::telemetry::{OAuthSpan, TOKEN_ISSUED};
+use crate::templates::{CheckSessionPage, ConsentPage, Templates, WebMessagePage};
 use actix::Addr;
-use actix_web::{web, HttpResponse, Result};
+use actix_session::Session;
+use actix_web::{http::header, web, HttpMessage, HttpRequest, HttpResponse, Result};
+use base64::{engine::general_purpose, Engine as _};
+use oauth2::url::form_urlencoded;
+use percent_encoding::percent_decode_str;
 use serde::Deserialize;
+use std::sync::Arc;
+use tracing_actix_web::RootSpan;
+use utoipa::ToSchema;
 
 #[derive(Debug, Deserialize)]
 pub struct AuthorizeQuery {
-    #[allow(dead_code)] // OAuth2 spec field, will be validated in future
     response_type: String,
     client_id: String,
     redirect_uri: String,
@@ -14,20 +46,362 @@ pub struct AuthorizeQuery {
     state: Option<String>,
     code_challenge: Option<String>,
     code_challenge_method: Option<String>,
+    /// OpenID Connect nonce, echoed in the ID token
+    nonce: Option<String>,
+    /// `none` asks for silent authentication: an answer without any page
+    /// being shown to the user
+    prompt: Option<String>,
+    /// `web_message` posts the response to the page that opened or framed
+    /// the request instead of redirecting
+    response_mode: Option<String>,
+}
+
+impl AuthorizeQuery {
+    fn validate(&self, validator: &RequestValidator) -> Result<(), OAuth2Error> {
+        validator.client_id(&self.client_id)?;
+        validator.redirect_uri(Some(&self.redirect_uri))?;
+        validator.scope(self.scope.as_deref())?;
+        validator.state(self.state.as_deref())?;
+        validator.nonce(self.nonce.as_deref())?;
+        match self.response_mode.as_deref() {
+            None | Some("query") | Some("web_message") => Ok(()),
+            Some(_) => Err(OAuth2Error::invalid_request("Unsupported response_mode")),
+        }
+    }
+
+    /// Only codes are issued here; the legacy profile answers any
+    /// `response_type` with one, as the server always did
+    fn check_response_type(&self, profile: SecurityProfile) -> Result<(), OAuth2Error> {
+        if profile.accepts_response_type(&self.response_type) {
+            return Ok(());
+        }
+        Err(OAuth2Error::unsupported_response_type(
+            "Only response_type=code is supported",
+        ))
+    }
+
+    fn silent(&self) -> bool {
+        self.prompt
+            .as_deref()
+            .is_some_and(|prompt| prompt.split(' ').any(|value| value == "none"))
+    }
+}
+
+impl AuthorizeQuery {
+    /// The client the request is from. It must have registered the redirect
+    /// URI before anything is sent there, and may only ask for scopes it was
+    /// registered with.
+    async fn client(&self, client_actor: &Addr<ClientActor>) -> Result<Client, OAuth2Error> {
+        let client = client_actor
+            .send(GetClient {
+                client_id: self.client_id.clone(),
+            })
+            .await??;
+        if !client.validate_redirect_uri(&self.redirect_uri) {
+            return Err(OAuth2Error::invalid_request(
+                "redirect_uri is not registered for this client",
+            ));
+        }
+        if !validate_scopes(&self.requested_scope(), &client.scope) {
+            return Err(OAuth2Error::invalid_scope(
+                "Requested scope exceeds what the client is registered for",
+            ));
+        }
+        Ok(client)
+    }
+
+    /// Refuse a request without the PKCE parameters `client` must send
+    async fn check_pkce(
+        &self,
+        client: Client,
+        auth_actor: &Addr<AuthActor>,
+    ) -> Result<(), OAuth2Error> {
+        auth_actor
+            .send(CheckPkce {
+                client,
+                code_challenge: self.code_challenge.clone(),
+                code_challenge_method: self.code_challenge_method.clone(),
+            })
+            .await?
+    }
+
+    fn requested_scope(&self) -> String {
+        self.scope.clone().unwrap_or_else(|| "read".to_string())
+    }
+
+    /// The scope `user` may grant, after the authorization policy
+    async fn granted_scope(
+        &self,
+        req: &HttpRequest,
+        user_id: &str,
+        origin_resolver: &OriginResolver,
+        policy: &AuthorizationPolicy,
+    ) -> Result<String, OAuth2Error> {
+        policy
+            .enforce(
+                &PolicyInput::new(
+                    "authorize",
+                    user_id,
+                    &self.client_id,
+                    &self.requested_scope(),
+                )
+                .with_origin(&origin_resolver.resolve(req)),
+            )
+            .await
+    }
+
+    /// Send the user back to the client with `params`, and `state` if the
+    /// client sent one: by redirect, or posted to the client's page with
+    /// `response_mode=web_message`
+    fn redirect_back(&self, params: &[(&str, &str)], templates: &Templates) -> HttpResponse {
+        if self.response_mode.as_deref() == Some("web_message") {
+            let mut response: serde_json::Map<String, serde_json::Value> = params
+                .iter()
+                .map(|(name, value)| (name.to_string(), (*value).into()))
+                .collect();
+            if let Some(state) = &self.state {
+                response.insert("state".to_string(), state.as_str().into());
+            }
+            let page = WebMessagePage {
+                origin: redirect_uri_origin(&self.redirect_uri),
+                response: serde_json::Value::Object(response).to_string(),
+            };
+            return templates.render_response("web_message.html", &page);
+        }
+
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.extend_pairs(params);
+        if let Some(state) = &self.state {
+            query.append_pair("state", state);
+        }
+        let separator = if self.redirect_uri.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        HttpResponse::Found()
+            .append_header((
+                "Location",
+                format!("{}{}{}", self.redirect_uri, separator, query.finish()),
+            ))
+            .finish()
+    }
+}
+
+/// The flow the authorize request was put in by the authorization request log
+fn flow_id(req: &HttpRequest) -> FlowId {
+    req.extensions()
+        .get::<FlowId>()
+        .cloned()
+        .unwrap_or_else(FlowId::generate)
+}
+
+/// This authorize request again, in the same flow: where the user comes
+/// back to after signing in, and where the consent page posts to
+fn continuation(req: &HttpRequest, flow_id: &FlowId) -> String {
+    let prefix = format!("{}=", FLOW_ID_PARAM);
+    let mut query: Vec<&str> = req
+        .query_string()
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !pair.starts_with(&prefix))
+        .collect();
+    let flow = format!("{}{}", prefix, flow_id.as_str());
+    query.push(&flow);
+    format!("{}?{}", req.path(), query.join("&"))
+}
+
+/// Answer from the consent page
+#[derive(Debug, Deserialize)]
+pub struct ConsentForm {
+    /// `approve`; anything else denies the request
+    decision: String,
 }
 
 /// OAuth2 authorize endpoint
-/// Initiates the authorization code flow
+/// Initiates the authorization code flow: asks the signed-in user to
+/// approve the client's request, sending them to log in first if needed.
+/// With `prompt=none` nothing is shown: a code is issued straight away if
+/// the user is signed in and has approved this scope for the client before,
+/// and the client is told `login_required` or `consent_required` otherwise.
+/// In demo mode the request is approved without asking.
+#[allow(clippy::too_many_arguments)]
 pub async fn authorize(
+    req: HttpRequest,
+    query: web::Query<AuthorizeQuery>,
+    session: Session,
+    db: web::Data<Arc<Database>>,
+    client_actor: web::Data<Addr<ClientActor>>,
+    auth_actor: web::Data<Addr<AuthActor>>,
+    clock: web::Data<dyn Clock>,
+    origin_resolver: web::Data<Arc<OriginResolver>>,
+    policy: web::Data<Arc<AuthorizationPolicy>>,
+    validator: web::Data<Arc<RequestValidator>>,
+    templates: web::Data<Arc<Templates>>,
+    mode: web::Data<DeploymentMode>,
+    profile: web::Data<SecurityProfile>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    query.validate(&validator)?;
+    query.check_response_type(**profile)?;
+    let client = query.client(&client_actor).await?;
+    let client_name = client.name.clone();
+    query.check_pkce(client, &auth_actor).await?;
+    let flow_id = flow_id(&req);
+
+    let Some(user) = session_user(&session) else {
+        if query.silent() {
+            return Ok(query.redirect_back(
+                &[
+                    ("error", "login_required"),
+                    ("error_description", "The user is not signed in"),
+                ],
+                &templates,
+            ));
+        }
+        return Ok(login_redirect_to(&continuation(&req, &flow_id)));
+    };
+    // Refused by policy before the user is asked, not after
+    let scope = query
+        .granted_scope(&req, &user.subject(), &origin_resolver, &policy)
+        .await?;
+
+    if query.silent() {
+        let consented = db
+            .get_consented_scope(&user.subject(), &query.client_id)
+            .await?;
+        if !consented.is_some_and(|consented| validate_scopes(&scope, &consented)) {
+            return Ok(query.redirect_back(
+                &[
+                    ("error", "consent_required"),
+                    (
+                        "error_description",
+                        "The user has not approved this request for the client",
+                    ),
+                ],
+                &templates,
+            ));
+        }
+        return issue_code(
+            &query,
+            &flow_id,
+            &session,
+            &user,
+            scope,
+            &db,
+            &auth_actor,
+            &clock,
+            &templates,
+        )
+        .await;
+    }
+
+    // Demo mode approves on the user's behalf
+    if mode.is_demo() {
+        db.record_consent(&user.subject(), &query.client_id, &scope, clock.now())
+            .await?;
+        return issue_code(
+            &query,
+            &flow_id,
+            &session,
+            &user,
+            scope,
+            &db,
+            &auth_actor,
+            &clock,
+            &templates,
+        )
+        .await;
+    }
+
+    let page = ConsentPage {
+        client_name,
+        user_email: user.email,
+        scopes: scope.split_whitespace().map(str::to_string).collect(),
+        action: continuation(&req, &flow_id),
+    };
+    Ok(templates.render_form_response("consent.html", &page, csrf.as_str()))
+}
+
+/// The user's answer to the consent page, posted back with the request's
+/// parameters. Approval issues the code and is remembered for silent
+/// authentication; denial sends the user back to the client with
+/// `error=access_denied`.
+#[allow(clippy::too_many_arguments)]
+pub async fn authorize_decision(
+    req: HttpRequest,
     query: web::Query<AuthorizeQuery>,
+    form: web::Form<ConsentForm>,
+    session: Session,
+    db: web::Data<Arc<Database>>,
+    client_actor: web::Data<Addr<ClientActor>>,
     auth_actor: web::Data<Addr<AuthActor>>,
+    clock: web::Data<dyn Clock>,
+    origin_resolver: web::Data<Arc<OriginResolver>>,
+    policy: web::Data<Arc<AuthorizationPolicy>>,
+    validator: web::Data<Arc<RequestValidator>>,
+    templates: web::Data<Arc<Templates>>,
+    profile: web::Data<SecurityProfile>,
 ) -> Result<HttpResponse, OAuth2Error> {
-    // In a real implementation, this would show a consent page
-    // For now, we'll auto-approve with a mock user
-    let user_id = "user_123".to_string(); // Mock user
+    query.validate(&validator)?;
+    query.check_response_type(**profile)?;
+    let client = query.client(&client_actor).await?;
+    query.check_pkce(client, &auth_actor).await?;
+    let flow_id = flow_id(&req);
 
-    let scope = query.scope.clone().unwrap_or_else(|| "read".to_string());
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect_to(&continuation(&req, &flow_id)));
+    };
+    let user_id = user.subject();
+
+    if form.decision != "approve" {
+        let mut response = query.redirect_back(
+            &[
+                ("error", "access_denied"),
+                ("error_description", "The user denied the request"),
+            ],
+            &templates,
+        );
+        response
+            .extensions_mut()
+            .insert(DeniedAuthorization { user_id });
+        return Ok(response);
+    }
+
+    let scope = query
+        .granted_scope(&req, &user_id, &origin_resolver, &policy)
+        .await?;
+    db.record_consent(&user_id, &query.client_id, &scope, clock.now())
+        .await?;
+    issue_code(
+        &query,
+        &flow_id,
+        &session,
+        &user,
+        scope,
+        &db,
+        &auth_actor,
+        &clock,
+        &templates,
+    )
+    .await
+}
 
+/// Issue a code for `scope` to the signed-in user and send it back to the
+/// client, with the session state for the check session iframe
+#[allow(clippy::too_many_arguments)]
+async fn issue_code(
+    query: &AuthorizeQuery,
+    flow_id: &FlowId,
+    session: &Session,
+    user: &SocialUserInfo,
+    scope: String,
+    db: &Database,
+    auth_actor: &Addr<AuthActor>,
+    clock: &web::Data<dyn Clock>,
+    templates: &Templates,
+) -> Result<HttpResponse, OAuth2Error> {
+    let user_id = user.subject();
+    db.ensure_user(&user_id, &user.email, clock.now()).await?;
     let auth_code = auth_actor
         .send(CreateAuthorizationCode {
             client_id: query.client_id.clone(),
@@ -36,52 +410,433 @@ pub async fn authorize(
             scope,
             code_challenge: query.code_challenge.clone(),
             code_challenge_method: query.code_challenge_method.clone(),
+            nonce: query.nonce.clone(),
+            auth_time: session_started_at(session),
+            flow_id: Some(flow_id.as_str().to_string()),
         })
-        .await
-        .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))??;
+        .await??;
 
-    // Redirect back to client with code
-    let mut redirect_url = format!("{}?code={}", query.redirect_uri, auth_code.code);
-    if let Some(state) = &query.state {
-        redirect_url.push_str(&format!("&state={}", state));
+    let session_state = browser_state(session)
+        .map(|state| session_state(&query.client_id, &query.redirect_uri, &state));
+    let mut params = vec![("code", auth_code.code.as_str())];
+    if let Some(session_state) = &session_state {
+        params.push(("session_state", session_state));
     }
+    let mut response = query.redirect_back(&params, templates);
+    response.extensions_mut().insert(IssuedAuthorization {
+        code_id: auth_code.id,
+        user_id: auth_code.user_id,
+        scope: auth_code.scope,
+    });
+    Ok(response)
+}
 
-    Ok(HttpResponse::Found()
-        .append_header(("Location", redirect_url))
-        .finish())
+/// OIDC Session Management `check_session_iframe`. Clients embed it and
+/// post it `client_id session_state`; it answers `changed`, `unchanged` or
+/// `error` from the browser state cookie, without a request to the server.
+pub async fn check_session(templates: web::Data<Arc<Templates>>) -> HttpResponse {
+    let page = CheckSessionPage {
+        cookie_name: BROWSER_STATE_COOKIE,
+    };
+    templates.render_response("check_session.html", &page)
 }
 
-#[derive(Debug, Deserialize)]
+/// OpenID Connect UserInfo endpoint. The bearer token must carry the
+/// `openid` scope; `profile` and `email` add the claims they cover.
+pub async fn userinfo(
+    req: HttpRequest,
+    token_actor: web::Data<Addr<TokenActor>>,
+    db: web::Data<Arc<Database>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(bearer) = bearer else {
+        return Ok(bearer_challenge(
+            HttpResponse::Unauthorized(),
+            "Bearer",
+            "The userinfo endpoint needs a bearer access token",
+        ));
+    };
+    let invalid_token = || {
+        bearer_challenge(
+            HttpResponse::Unauthorized(),
+            r#"Bearer error="invalid_token""#,
+            "The access token is invalid, expired or revoked",
+        )
+    };
+
+    let Ok(token) = token_actor
+        .send(ValidateToken {
+            token: bearer.to_string(),
+        })
+        .await?
+    else {
+        return Ok(invalid_token());
+    };
+    let scopes: Vec<&str> = token.scope.split_whitespace().collect();
+    if !scopes.contains(&"openid") {
+        return Ok(bearer_challenge(
+            HttpResponse::Forbidden(),
+            r#"Bearer error="insufficient_scope", scope="openid""#,
+            "The access token was not granted the openid scope",
+        ));
+    }
+    // Tokens a client was issued for itself name no user
+    let user = match db.get_user(&token.user_id).await? {
+        Some(user) if user.enabled && token.user_id != token.client_id => user,
+        _ => return Ok(invalid_token()),
+    };
+
+    let mut info = UserInfo {
+        sub: user.id.clone(),
+        ..UserInfo::default()
+    };
+    if scopes.contains(&"profile") {
+        // The most recently updated identity provider profile
+        let profile = db
+            .list_upstream_tokens(&user.id)
+            .await?
+            .into_iter()
+            .max_by_key(|upstream| upstream.updated_at);
+        if let Some(profile) = profile {
+            info.name = profile.name;
+            info.picture = profile.picture;
+        }
+        // Users created by a social login are named by their id
+        info.preferred_username = (user.username != user.id).then_some(user.username);
+    }
+    if scopes.contains(&"email") && !user.email.is_empty() {
+        info.email = Some(user.email);
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoStore]))
+        .json(info))
+}
+
+/// An RFC 6750 error response, with `challenge` as its `WWW-Authenticate`
+fn bearer_challenge(
+    mut response: actix_web::HttpResponseBuilder,
+    challenge: &'static str,
+    description: &str,
+) -> HttpResponse {
+    response
+        .insert_header((header::WWW_AUTHENTICATE, challenge))
+        .json(OAuth2Error::access_denied(description))
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct TokenRequest {
     grant_type: String,
     code: Option<String>,
     redirect_uri: Option<String>,
+    /// May instead be sent with HTTP Basic authentication
+    #[serde(default)]
     client_id: String,
     client_secret: Option<String>,
-    #[allow(dead_code)] // OAuth2 refresh token grant, planned for future
     refresh_token: Option<String>,
     username: Option<String>,
     password: Option<String>,
     scope: Option<String>,
     code_verifier: Option<String>,
+    client_assertion_type: Option<String>,
+    client_assertion: Option<String>,
+    /// RFC 8707 resource indicator naming the API the token is for
+    resource: Option<String>,
+}
+
+impl TokenRequest {
+    /// Take the client's credentials from HTTP Basic authentication when the
+    /// request was sent with it. A client uses one method per request
+    /// (RFC 6749 §2.3), so Basic credentials alongside a secret or assertion
+    /// in the body are refused.
+    fn apply_basic_credentials(
+        &mut self,
+        basic: Option<(String, String)>,
+    ) -> Result<(), OAuth2Error> {
+        let Some((client_id, client_secret)) = basic else {
+            if self.client_id.is_empty() {
+                return Err(OAuth2Error::invalid_request("Missing client_id"));
+            }
+            return Ok(());
+        };
+        if self.client_secret.is_some() || self.client_assertion.is_some() {
+            return Err(OAuth2Error::invalid_request(
+                "Use only one client authentication method",
+            ));
+        }
+        if !self.client_id.is_empty() && self.client_id != client_id {
+            return Err(OAuth2Error::invalid_client(
+                "client_id does not match the Basic credentials",
+            ));
+        }
+        self.client_id = client_id;
+        self.client_secret = Some(client_secret);
+        Ok(())
+    }
+
+    fn validate(&self, validator: &RequestValidator) -> Result<(), OAuth2Error> {
+        validator.client_id(&self.client_id)?;
+        validator.redirect_uri(self.redirect_uri.as_deref())?;
+        validator.scope(self.scope.as_deref())
+    }
+}
+
+/// Challenge sent with `invalid_client`, naming the authentication scheme
+/// the token endpoint accepts
+const CLIENT_AUTH_CHALLENGE: &str = "Basic realm=\"oauth2\"";
+
+/// Client credentials sent with HTTP Basic authentication, if the request
+/// carries them. Both halves are form-encoded before being joined
+/// (RFC 6749 §2.3.1).
+fn basic_credentials(req: &HttpRequest) -> Result<Option<(String, String)>, OAuth2Error> {
+    let Some(encoded) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+    else {
+        return Ok(None);
+    };
+    let malformed = || OAuth2Error::invalid_client("Malformed Basic credentials");
+    let decoded = general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(malformed)?;
+    let (client_id, client_secret) = decoded.split_once(':').ok_or_else(malformed)?;
+    let form_decode = |value: &str| {
+        percent_decode_str(&value.replace('+', " "))
+            .decode_utf8()
+            .map(|value| value.into_owned())
+            .map_err(|_| malformed())
+    };
+    Ok(Some((form_decode(client_id)?, form_decode(client_secret)?)))
+}
+
+/// `invalid_client` is answered with 401 and a challenge (RFC 6749 §5.2);
+/// other errors pass through
+fn challenge_client(error: OAuth2Error) -> Result<HttpResponse, OAuth2Error> {
+    if error.code() != Some(ErrorCode::InvalidClient) {
+        return Err(error);
+    }
+    Ok(HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, CLIENT_AUTH_CHALLENGE))
+        .json(error))
 }
 
 /// OAuth2 token endpoint
 /// Exchanges authorization code for access token
+#[allow(clippy::too_many_arguments)]
 pub async fn token(
+    req: HttpRequest,
+    root_span: RootSpan,
     form: web::Form<TokenRequest>,
     token_actor: web::Data<Addr<TokenActor>>,
     auth_actor: web::Data<Addr<AuthActor>>,
+    client_actor: web::Data<Addr<ClientActor>>,
+    origin_resolver: web::Data<Arc<OriginResolver>>,
+    workload_identity: web::Data<Arc<WorkloadIdentityVerifier>>,
+    db: web::Data<Arc<Database>>,
+    directory: web::Data<Option<Arc<dyn UserAuthenticator>>>,
+    event_actor: Option<web::Data<Addr<EventActor>>>,
+    validator: web::Data<Arc<RequestValidator>>,
+    admission: web::Data<Arc<TokenAdmission>>,
+    profile: web::Data<SecurityProfile>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let span = OAuthSpan::new(root_span);
+    let mut form = form.into_inner();
+    if let Err(error) =
+        basic_credentials(&req).and_then(|basic| form.apply_basic_credentials(basic))
+    {
+        return challenge_client(span.failed(error));
+    }
+    span.client(&form.client_id, Some(&form.grant_type));
+    if let Some(scope) = &form.scope {
+        span.scope(scope);
+    }
+    form.validate(&validator).map_err(|e| span.failed(e))?;
+
+    let client_id = form.client_id.clone();
+    // Held until the response is built
+    let _admitted = match admission.admit(&client_id).await {
+        Ok(admitted) => admitted,
+        Err(shed) => {
+            span.outcome(ErrorCode::TemporarilyUnavailable.as_str());
+            return Ok(HttpResponse::ServiceUnavailable()
+                .insert_header((
+                    header::RETRY_AFTER,
+                    admission.retry_after_secs().to_string(),
+                ))
+                .json(OAuth2Error::new(
+                    ErrorCode::TemporarilyUnavailable.as_str(),
+                    Some(shed.description()),
+                )));
+        }
+    };
+    let origin = origin_resolver.resolve(&req);
+    let result = dispatch_grant(
+        form,
+        token_actor,
+        auth_actor,
+        &client_actor,
+        &workload_identity,
+        db,
+        directory,
+        **profile,
+        origin,
+        &span,
+    )
+    .await;
+    match &result {
+        Ok(_) => span.outcome(TOKEN_ISSUED),
+        Err(error) => span.outcome(&error.error),
+    }
+
+    // Failed client and grant authentication feed the per-client failure ratio
+    if let (Err(error), Some(event_actor)) = (&result, &event_actor) {
+        if matches!(error.error.as_str(), "invalid_client" | "invalid_grant") {
+            let event = AuthEvent::new(
+                EventType::ClientValidated,
+                EventSeverity::Warning,
+                None,
+                Some(client_id),
+            )
+            .with_metadata("success", "false")
+            .with_metadata("error", error.error.clone());
+            event_actor.do_send(EmitEvent { event });
+        }
+    }
+    result.or_else(challenge_client)
+}
+
+/// Authenticate the client making a token request: with its secret, or a
+/// workload identity token standing in for one. Public clients send neither,
+/// unless the profile refuses them.
+async fn authenticate_client(
+    req: &TokenRequest,
+    client_actor: &Addr<ClientActor>,
+    workload_identity: &WorkloadIdentityVerifier,
+    db: &Database,
+    profile: SecurityProfile,
+) -> Result<(), OAuth2Error> {
+    match (req.client_assertion_type.as_deref(), &req.client_assertion) {
+        (Some(JWT_BEARER_ASSERTION_TYPE), Some(assertion)) => {
+            let client_id = workload_identity.verify(assertion)?;
+            if client_id != req.client_id || db.get_client(&client_id).await?.is_none() {
+                return Err(OAuth2Error::invalid_client(
+                    "Client assertion does not match client_id",
+                ));
+            }
+            Ok(())
+        }
+        (Some(_), _) => Err(OAuth2Error::invalid_client(
+            "Unsupported client_assertion_type",
+        )),
+        (None, _) => {
+            // Public clients hold no secret; PKCE stands in for one
+            let Some(client_secret) = req.client_secret.clone().filter(|s| !s.is_empty()) else {
+                let client = client_actor
+                    .send(GetClient {
+                        client_id: req.client_id.clone(),
+                    })
+                    .await??;
+                if !client.is_public() {
+                    return Err(OAuth2Error::invalid_client("Missing client_secret"));
+                }
+                if !profile.allows_public_clients() {
+                    return Err(OAuth2Error::invalid_client(
+                        "Client authentication is required under the strict security profile",
+                    ));
+                }
+                // Nothing but a secret authenticates a client acting for itself
+                if req.grant_type == "client_credentials" {
+                    return Err(OAuth2Error::unauthorized_client(
+                        "Public clients cannot use the client_credentials grant",
+                    ));
+                }
+                return Ok(());
+            };
+            let valid = client_actor
+                .send(ValidateClient {
+                    client_id: req.client_id.clone(),
+                    client_secret,
+                })
+                .await??;
+            if !valid {
+                return Err(OAuth2Error::invalid_client("Invalid client credentials"));
+            }
+            Ok(())
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn dispatch_grant(
+    form: TokenRequest,
+    token_actor: web::Data<Addr<TokenActor>>,
+    auth_actor: web::Data<Addr<AuthActor>>,
+    client_actor: &Addr<ClientActor>,
+    workload_identity: &WorkloadIdentityVerifier,
+    db: web::Data<Arc<Database>>,
+    directory: web::Data<Option<Arc<dyn UserAuthenticator>>>,
+    profile: SecurityProfile,
+    origin: RequestOrigin,
+    span: &OAuthSpan,
 ) -> Result<HttpResponse, OAuth2Error> {
+    if form.grant_type == "password" && !profile.allows_password_grant() {
+        return Err(OAuth2Error::unsupported_grant_type(
+            "The password grant is disabled under the strict security profile",
+        ));
+    }
+    authenticate_client(&form, client_actor, workload_identity, &db, profile).await?;
+
+    // A client may only use the grants it registered for
+    let client = db
+        .get_client(&form.client_id)
+        .await?
+        .ok_or_else(|| OAuth2Error::invalid_client("Client not found"))?;
+    if SUPPORTED_GRANT_TYPES.contains(&form.grant_type.as_str())
+        && !client.supports_grant_type(&form.grant_type)
+    {
+        return Err(OAuth2Error::unauthorized_client(&format!(
+            "Client is not registered for the {} grant",
+            form.grant_type
+        )));
+    }
+
+    let resource = match form.grant_type.as_str() {
+        // Checked against the token being refreshed instead
+        "refresh_token" => None,
+        _ => requested_resource(&db, form.resource.as_deref()).await?,
+    };
+
     match form.grant_type.as_str() {
         "authorization_code" => {
-            handle_authorization_code_grant(form.into_inner(), token_actor, auth_actor).await
+            handle_authorization_code_grant(form, token_actor, auth_actor, resource, origin, span)
+                .await
         }
         "client_credentials" => {
-            handle_client_credentials_grant(form.into_inner(), token_actor).await
+            handle_client_credentials_grant(form, &client, token_actor, resource, origin, span)
+                .await
         }
-        "password" => handle_password_grant(form.into_inner(), token_actor).await,
-        "refresh_token" => handle_refresh_token_grant(form.into_inner(), token_actor).await,
+        "password" => {
+            handle_password_grant(
+                form,
+                &client,
+                token_actor,
+                auth_actor,
+                directory.get_ref().as_deref(),
+                resource,
+                origin,
+                span,
+            )
+            .await
+        }
+        "refresh_token" => handle_refresh_token_grant(form, token_actor, origin, span).await,
         _ => Err(OAuth2Error::unsupported_grant_type(&format!(
             "Grant type '{}' not supported",
             form.grant_type
@@ -89,10 +844,51 @@ pub async fn token(
     }
 }
 
+/// Look up the registered resource a token request names, if it names one
+async fn requested_resource(
+    db: &Database,
+    identifier: Option<&str>,
+) -> Result<Option<Resource>, OAuth2Error> {
+    let Some(identifier) = identifier else {
+        return Ok(None);
+    };
+    match db.get_resource_by_identifier(identifier).await? {
+        Some(resource) => Ok(Some(resource)),
+        None => Err(OAuth2Error::new(
+            "invalid_target",
+            Some(&format!("Unknown resource '{}'", identifier)),
+        )),
+    }
+}
+
+/// A token may only carry scopes its client registered for
+fn check_client_scope(client: &Client, scope: &str) -> Result<(), OAuth2Error> {
+    if validate_scopes(scope, &client.scope) {
+        Ok(())
+    } else {
+        Err(OAuth2Error::invalid_scope(
+            "Scope exceeds what the client is registered for",
+        ))
+    }
+}
+
+/// A token for a resource may only carry scopes that resource exposes
+fn check_resource_scope(resource: Option<&Resource>, scope: &str) -> Result<(), OAuth2Error> {
+    match resource {
+        Some(resource) if !resource.exposes_scopes(scope) => Err(OAuth2Error::invalid_scope(
+            &format!("Scope is not offered by {}", resource.identifier),
+        )),
+        _ => Ok(()),
+    }
+}
+
 async fn handle_authorization_code_grant(
     req: TokenRequest,
     token_actor: web::Data<Addr<TokenActor>>,
     auth_actor: web::Data<Addr<AuthActor>>,
+    resource: Option<Resource>,
+    origin: RequestOrigin,
+    span: &OAuthSpan,
 ) -> Result<HttpResponse, OAuth2Error> {
     let code = req
         .code
@@ -109,81 +905,169 @@ async fn handle_authorization_code_grant(
             redirect_uri,
             code_verifier: req.code_verifier,
         })
-        .await
-        .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))??;
+        .await??;
+    check_resource_scope(resource.as_ref(), &auth_code.scope)?;
 
-    // Create token
+    // Create token, redeeming the code with it
     let token = token_actor
         .send(CreateToken {
-            user_id: auth_code.user_id,
-            client_id: auth_code.client_id,
-            scope: auth_code.scope,
+            user_id: auth_code.user_id.clone(),
+            roles: Vec::new(),
+            client_id: auth_code.client_id.clone(),
+            scope: auth_code.scope.clone(),
             include_refresh: true,
+            grant_type: "authorization_code",
+            resource,
+            origin,
+            authorization_code: Some(auth_code.clone()),
         })
-        .await
-        .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))??;
+        .await??;
+    span.user(&token.user_id);
+    span.scope(&token.scope);
+    if let Some(flow_id) = &token.flow_id {
+        span.flow(flow_id);
+    }
 
-    Ok(HttpResponse::Ok().json(TokenResponse::from(token)))
+    // Going by the granted scope, which the policy may have narrowed
+    let id_token = if token.scope.split(' ').any(|scope| scope == "openid") {
+        Some(
+            token_actor
+                .send(CreateIdToken {
+                    authorization_code: auth_code,
+                    expires_in: token.expires_in,
+                })
+                .await??,
+        )
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(TokenResponse::from(token).with_id_token(id_token)))
 }
 
 async fn handle_client_credentials_grant(
     req: TokenRequest,
+    client: &Client,
     token_actor: web::Data<Addr<TokenActor>>,
+    resource: Option<Resource>,
+    origin: RequestOrigin,
+    span: &OAuthSpan,
 ) -> Result<HttpResponse, OAuth2Error> {
-    // Validate client credentials
-    let _client_secret = req
-        .client_secret
-        .ok_or_else(|| OAuth2Error::invalid_client("Missing client_secret"))?;
-
-    let scope = req.scope.unwrap_or_else(|| "read".to_string());
+    // Without a scope, a token for a resource gets everything it exposes
+    let scope = match (req.scope, &resource) {
+        (Some(scope), _) => scope,
+        (None, Some(resource)) => resource.get_scopes().join(" "),
+        (None, None) => "read".to_string(),
+    };
+    check_client_scope(client, &scope)?;
+    check_resource_scope(resource.as_ref(), &scope)?;
 
     // Create token (no user, client-only)
     let token = token_actor
         .send(CreateToken {
             user_id: req.client_id.clone(), // Use client_id as user_id
+            roles: Vec::new(),
             client_id: req.client_id,
             scope,
             include_refresh: false,
+            grant_type: "client_credentials",
+            resource,
+            origin,
+            authorization_code: None,
         })
-        .await
-        .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))??;
+        .await??;
+    // No user to record: the token is the client's own
+    span.scope(&token.scope);
 
     Ok(HttpResponse::Ok().json(TokenResponse::from(token)))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_password_grant(
     req: TokenRequest,
+    client: &Client,
     token_actor: web::Data<Addr<TokenActor>>,
+    auth_actor: web::Data<Addr<AuthActor>>,
+    directory: Option<&dyn UserAuthenticator>,
+    resource: Option<Resource>,
+    origin: RequestOrigin,
+    span: &OAuthSpan,
 ) -> Result<HttpResponse, OAuth2Error> {
     let username = req
         .username
         .ok_or_else(|| OAuth2Error::invalid_request("Missing username"))?;
-    let _password = req
+    let password = req
         .password
         .ok_or_else(|| OAuth2Error::invalid_request("Missing password"))?;
-
-    // In real implementation, validate username/password
     let scope = req.scope.unwrap_or_else(|| "read".to_string());
+    check_client_scope(client, &scope)?;
+    check_resource_scope(resource.as_ref(), &scope)?;
+
+    let (user_id, roles) = match directory {
+        Some(directory) => {
+            let user = directory
+                .authenticate(&username, &password)
+                .await?
+                .ok_or_else(|| OAuth2Error::invalid_grant("Invalid username or password"))?;
+            tracing::info!(
+                "{} signed in through {} with roles [{}]",
+                user.subject,
+                directory.name(),
+                user.roles.join(", ")
+            );
+            (user.subject, user.roles)
+        }
+        None => {
+            let user = auth_actor
+                .send(AuthenticateUser { username, password })
+                .await??;
+            (user.id, Vec::new())
+        }
+    };
 
     let token = token_actor
         .send(CreateToken {
-            user_id: username,
+            user_id,
+            roles,
             client_id: req.client_id,
             scope,
             include_refresh: true,
+            grant_type: "password",
+            resource,
+            origin,
+            authorization_code: None,
         })
-        .await
-        .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))??;
+        .await??;
+    span.user(&token.user_id);
+    span.scope(&token.scope);
 
     Ok(HttpResponse::Ok().json(TokenResponse::from(token)))
 }
 
 async fn handle_refresh_token_grant(
-    _req: TokenRequest,
-    _token_actor: web::Data<Addr<TokenActor>>,
+    req: TokenRequest,
+    token_actor: web::Data<Addr<TokenActor>>,
+    origin: RequestOrigin,
+    span: &OAuthSpan,
 ) -> Result<HttpResponse, OAuth2Error> {
-    // Simplified refresh token handling
-    Err(OAuth2Error::unsupported_grant_type(
-        "Refresh token grant not yet implemented",
-    ))
+    let refresh_token = req
+        .refresh_token
+        .ok_or_else(|| OAuth2Error::invalid_request("Missing refresh_token"))?;
+
+    let token = token_actor
+        .send(RefreshAccessToken {
+            refresh_token,
+            client_id: req.client_id,
+            resource: req.resource,
+            scope: req.scope,
+            origin,
+        })
+        .await??;
+    span.user(&token.user_id);
+    span.scope(&token.scope);
+    if let Some(flow_id) = &token.flow_id {
+        span.flow(flow_id);
+    }
+
+    Ok(HttpResponse::Ok().json(TokenResponse::from(token)))
 }
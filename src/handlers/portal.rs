@@ -0,0 +1,397 @@
+use crate::actors::{ClientActor, RegisterClient, RotateClientSecret, UpdateClientRedirectUris};
+use crate::clock::Clock;
+use crate::config::AdminAccessConfig;
+use crate::db::Database;
+use crate::handlers::auth::session_user;
+use crate::middleware::FormCsrfToken;
+use crate::models::{is_valid_redirect_uri, Client, ClientRegistration, OAuth2Error, Token};
+use crate::templates::{
+    PortalClientPage, PortalClientSummary, PortalClientView, PortalPage, PortalTokenView, Templates,
+};
+use actix::Addr;
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use oauth2::url::form_urlencoded;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Number of recent tokens shown on a client's portal page
+const RECENT_TOKEN_LIMIT: i64 = 20;
+
+/// Grant types available to clients registered through the portal
+const PORTAL_GRANT_TYPES: &[&str] = &["authorization_code", "refresh_token"];
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterClientForm {
+    client_name: String,
+    redirect_uris: String,
+    scope: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RedirectUrisForm {
+    redirect_uris: String,
+}
+
+pub(crate) fn login_redirect() -> HttpResponse {
+    HttpResponse::Found()
+        .append_header(("Location", "/auth/login"))
+        .finish()
+}
+
+/// Send the user to the login page, to come back to this request once they
+/// are signed in
+pub(crate) fn login_redirect_back(req: &HttpRequest) -> HttpResponse {
+    login_redirect_to(&format!("{}?{}", req.path(), req.query_string()))
+}
+
+/// Send the user to the login page, to come back to `return_to` once they
+/// are signed in
+pub(crate) fn login_redirect_to(return_to: &str) -> HttpResponse {
+    let location = format!(
+        "/auth/login?return_to={}",
+        form_urlencoded::byte_serialize(return_to.as_bytes()).collect::<String>()
+    );
+    HttpResponse::Found()
+        .append_header(("Location", location))
+        .finish()
+}
+
+/// Split a textarea of redirect URIs into a validated list
+fn parse_redirect_uris(input: &str) -> Result<Vec<String>, String> {
+    let uris: Vec<String> = input
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    if uris.is_empty() {
+        return Err("At least one redirect URI is required".to_string());
+    }
+    if let Some(invalid) = uris.iter().find(|uri| !is_valid_redirect_uri(uri)) {
+        return Err(format!("Invalid redirect URI: {}", invalid));
+    }
+
+    Ok(uris)
+}
+
+/// The signed-in user and the organization they belong to, if any
+struct PortalUser {
+    subject: String,
+    email: String,
+    org_id: Option<String>,
+}
+
+async fn portal_user(session: &Session, db: &Database) -> Result<Option<PortalUser>, OAuth2Error> {
+    let Some(user) = session_user(session) else {
+        return Ok(None);
+    };
+    let subject = user.subject();
+    let org_id = db.get_member_org(&subject).await?;
+    Ok(Some(PortalUser {
+        subject,
+        email: user.email,
+        org_id,
+    }))
+}
+
+/// Load a client, treating clients the user may not manage as missing
+async fn load_owned_client(
+    db: &Database,
+    client_id: &str,
+    user: &PortalUser,
+) -> Result<Option<Client>, OAuth2Error> {
+    Ok(db
+        .get_client(client_id)
+        .await?
+        .filter(|client| client.is_manageable_by(&user.subject, user.org_id.as_deref())))
+}
+
+async fn render_portal(
+    db: &Database,
+    templates: &Templates,
+    csrf: &FormCsrfToken,
+    user: PortalUser,
+    error: Option<String>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let clients = db
+        .list_manageable_clients(&user.subject, user.org_id.as_deref())
+        .await?
+        .into_iter()
+        .map(|client| PortalClientSummary {
+            client_id: client.client_id,
+            name: client.name,
+            created_at: client.created_at.format("%Y-%m-%d").to_string(),
+        })
+        .collect();
+
+    let page = PortalPage {
+        user_email: user.email,
+        clients,
+        error,
+    };
+    Ok(templates.render_form_response("portal.html", &page, csrf.as_str()))
+}
+
+async fn render_client(
+    db: &Database,
+    templates: &Templates,
+    csrf: &FormCsrfToken,
+    clock: &dyn Clock,
+    client: &Client,
+    new_secret: Option<String>,
+    error: Option<String>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let tokens = db
+        .list_recent_tokens_for_client(&client.client_id, RECENT_TOKEN_LIMIT)
+        .await?
+        .iter()
+        .map(|token| token_view(token, clock))
+        .collect();
+
+    let page = PortalClientPage {
+        client: PortalClientView {
+            client_id: client.client_id.clone(),
+            name: client.name.clone(),
+            scope: client.scope.clone(),
+            grant_types: client.get_grant_types(),
+            redirect_uris: client.get_redirect_uris(),
+            secret_expires_at: client
+                .secret_expires_at
+                .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+            secret_expired: client.secret_expired(clock.now()),
+        },
+        new_secret,
+        tokens,
+        error,
+    };
+    Ok(templates.render_form_response("portal_client.html", &page, csrf.as_str()))
+}
+
+fn token_view(token: &Token, clock: &dyn Clock) -> PortalTokenView {
+    let status = if token.revoked {
+        "revoked"
+    } else if token.is_expired(clock) {
+        "expired"
+    } else {
+        "active"
+    };
+
+    PortalTokenView {
+        created_at: token.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        user_id: token.user_id.clone(),
+        scope: token.scope.clone(),
+        status,
+    }
+}
+
+/// List the signed-in user's clients with a registration form
+pub async fn index(
+    session: Session,
+    db: web::Data<Arc<Database>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = portal_user(&session, &db).await? else {
+        return Ok(login_redirect());
+    };
+
+    render_portal(&db, &templates, &csrf, user, None).await
+}
+
+/// Register a client owned by the signed-in user
+#[allow(clippy::too_many_arguments)]
+pub async fn create_client(
+    session: Session,
+    form: web::Form<RegisterClientForm>,
+    db: web::Data<Arc<Database>>,
+    client_actor: web::Data<Addr<ClientActor>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+    clock: web::Data<dyn Clock>,
+    admin_access: web::Data<Arc<AdminAccessConfig>>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = portal_user(&session, &db).await? else {
+        return Ok(login_redirect());
+    };
+    let form = form.into_inner();
+
+    let client_name = form.client_name.trim().to_string();
+    if client_name.is_empty() {
+        let error = Some("Application name is required".to_string());
+        return render_portal(&db, &templates, &csrf, user, error).await;
+    }
+    let redirect_uris = match parse_redirect_uris(&form.redirect_uris) {
+        Ok(uris) => uris,
+        Err(error) => return render_portal(&db, &templates, &csrf, user, Some(error)).await,
+    };
+    let scope = form
+        .scope
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "read".to_string());
+    if scope
+        .split_whitespace()
+        .any(|scope| admin_access.reserves_scope(scope))
+    {
+        let error = Some("Admin scopes can only be given by an admin".to_string());
+        return render_portal(&db, &templates, &csrf, user, error).await;
+    }
+
+    let client = client_actor
+        .send(RegisterClient {
+            registration: ClientRegistration {
+                client_name,
+                redirect_uris,
+                grant_types: PORTAL_GRANT_TYPES.iter().map(|g| g.to_string()).collect(),
+                scope,
+                allowed_origins: vec![],
+                redirect_uri_patterns: vec![],
+                token_endpoint_auth_method: Default::default(),
+                access_token_format: None,
+            },
+            owner_id: Some(user.subject),
+            owner_org: user.org_id,
+        })
+        .await??;
+
+    let secret = client.client_secret.clone();
+    render_client(
+        &db,
+        &templates,
+        &csrf,
+        clock.get_ref(),
+        &client,
+        Some(secret),
+        None,
+    )
+    .await
+}
+
+/// Show a client's settings and recent token issuance
+pub async fn show_client(
+    session: Session,
+    client_id: web::Path<String>,
+    db: web::Data<Arc<Database>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = portal_user(&session, &db).await? else {
+        return Ok(login_redirect());
+    };
+
+    match load_owned_client(&db, &client_id, &user).await? {
+        Some(client) => {
+            render_client(&db, &templates, &csrf, clock.get_ref(), &client, None, None).await
+        }
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+/// Issue a new secret and display it once
+pub async fn rotate_secret(
+    session: Session,
+    client_id: web::Path<String>,
+    db: web::Data<Arc<Database>>,
+    client_actor: web::Data<Addr<ClientActor>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = portal_user(&session, &db).await? else {
+        return Ok(login_redirect());
+    };
+    if load_owned_client(&db, &client_id, &user).await?.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let client = client_actor
+        .send(RotateClientSecret {
+            client_id: client_id.into_inner(),
+        })
+        .await??;
+
+    let secret = client.client_secret.clone();
+    render_client(
+        &db,
+        &templates,
+        &csrf,
+        clock.get_ref(),
+        &client,
+        Some(secret),
+        None,
+    )
+    .await
+}
+
+/// Replace a client's registered redirect URIs
+#[allow(clippy::too_many_arguments)]
+pub async fn update_redirect_uris(
+    session: Session,
+    client_id: web::Path<String>,
+    form: web::Form<RedirectUrisForm>,
+    db: web::Data<Arc<Database>>,
+    client_actor: web::Data<Addr<ClientActor>>,
+    templates: web::Data<Arc<Templates>>,
+    csrf: FormCsrfToken,
+    clock: web::Data<dyn Clock>,
+) -> Result<HttpResponse, OAuth2Error> {
+    let Some(user) = portal_user(&session, &db).await? else {
+        return Ok(login_redirect());
+    };
+    let Some(client) = load_owned_client(&db, &client_id, &user).await? else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let redirect_uris = match parse_redirect_uris(&form.redirect_uris) {
+        Ok(uris) => uris,
+        Err(error) => {
+            return render_client(
+                &db,
+                &templates,
+                &csrf,
+                clock.get_ref(),
+                &client,
+                None,
+                Some(error),
+            )
+            .await
+        }
+    };
+
+    client_actor
+        .send(UpdateClientRedirectUris {
+            client_id: client.client_id.clone(),
+            redirect_uris,
+        })
+        .await??;
+
+    Ok(HttpResponse::SeeOther()
+        .append_header(("Location", format!("/portal/clients/{}", client.client_id)))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_redirect_uris() {
+        let uris =
+            parse_redirect_uris("https://app.example.com/cb\n\n  http://localhost:3000/cb \n")
+                .unwrap();
+        assert_eq!(
+            uris,
+            vec!["https://app.example.com/cb", "http://localhost:3000/cb"]
+        );
+    }
+
+    #[test]
+    fn test_parse_redirect_uris_rejects_invalid() {
+        assert!(parse_redirect_uris("").is_err());
+        assert!(parse_redirect_uris("javascript:alert(1)").is_err());
+        assert!(parse_redirect_uris("https://app.example.com/cb#frag").is_err());
+    }
+}
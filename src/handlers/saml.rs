@@ -0,0 +1,75 @@
+use crate::handlers::auth::session_user;
+use crate::handlers::portal::login_redirect_back;
+use crate::models::OAuth2Error;
+use crate::services::{AuthnRequest, SamlIdp};
+use crate::templates::{SamlPostPage, Templates};
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct SsoQuery {
+    #[serde(rename = "SAMLRequest")]
+    saml_request: String,
+    #[serde(rename = "RelayState")]
+    relay_state: Option<String>,
+}
+
+/// The URL this server is being reached at
+fn base_url(req: &HttpRequest) -> String {
+    let info = req.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+fn configured(idp: &Option<Arc<SamlIdp>>) -> Result<&SamlIdp, OAuth2Error> {
+    idp.as_deref()
+        .ok_or_else(|| OAuth2Error::invalid_request("SAML is not configured"))
+}
+
+/// IdP metadata for configuring service providers
+pub async fn metadata(
+    req: HttpRequest,
+    idp: web::Data<Option<Arc<SamlIdp>>>,
+) -> Result<HttpResponse> {
+    let idp = configured(&idp)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/samlmetadata+xml")
+        .body(idp.metadata(&base_url(&req))))
+}
+
+/// Single sign-on with the HTTP-Redirect binding. Users who are not signed in
+/// are sent to the login page and come back here afterwards; the response
+/// goes to the service provider with the HTTP-POST binding.
+pub async fn sso(
+    req: HttpRequest,
+    query: web::Query<SsoQuery>,
+    idp: web::Data<Option<Arc<SamlIdp>>>,
+    session: Session,
+    templates: web::Data<Arc<Templates>>,
+) -> Result<HttpResponse> {
+    let idp = configured(&idp)?;
+    let request = AuthnRequest::from_redirect(&query.saml_request)?;
+    let (provider, acs_url) = idp.service_provider(&request)?;
+
+    let Some(user) = session_user(&session) else {
+        return Ok(login_redirect_back(&req));
+    };
+
+    let base_url = base_url(&req);
+    let response = idp.response(&base_url, &request, provider, &acs_url, &user)?;
+    tracing::info!(
+        service_provider = %provider.entity_id,
+        subject = %user.subject(),
+        "Issued SAML assertion"
+    );
+
+    let page = SamlPostPage {
+        service_provider: provider.entity_id.clone(),
+        acs_url,
+        saml_response: general_purpose::STANDARD.encode(response),
+        relay_state: query.relay_state.clone(),
+    };
+    Ok(templates.render_response("saml_post.html", &page))
+}
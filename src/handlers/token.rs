@@ -1,67 +1,282 @@
-use crate::actors::{RevokeToken, TokenActor, ValidateToken};
-use crate::models::{Claims, IntrospectionResponse, OAuth2Error};
+use crate::actors::{RevokeToken, TokenActor, ValidateRefreshToken, ValidateToken};
+use crate::cache::{Cache, CacheInvalidator};
+use crate::clock::Clock;
+use crate::config::{IntrospectionConfig, JwtConfig};
+use crate::db::Database;
+use crate::keys::SharedKeys;
+use crate::models::{
+    stored_access_token, ActClaim, Claims, IntrospectionResponse, OAuth2Error, Token,
+};
 use actix::Addr;
-use actix_web::{web, HttpResponse, Result};
-use serde::Deserialize;
+use actix_web::http::header::{
+    CacheControl, CacheDirective, ContentType, ETag, EntityTag, IfNoneMatch,
+};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Result};
+use base64::{engine::general_purpose, Engine as _};
+use jsonwebtoken::errors::ErrorKind;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::ToSchema;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct IntrospectRequest {
     token: String,
-    #[allow(dead_code)] // OAuth2 spec field, can be used for optimization
+    /// `refresh_token` to introspect a refresh token; anything else is
+    /// taken as an access token
     token_type_hint: Option<String>,
 }
 
+/// An active introspection result, kept no longer than resource servers
+/// were told they may cache it
+#[derive(Serialize, Deserialize)]
+struct CachedIntrospection {
+    response: IntrospectionResponse,
+    /// Unix time the result stops being served from the cache
+    until: i64,
+}
+
+/// Keyed by the access token as stored, which is all a revocation through
+/// the refresh token has of an opaque one
+pub(crate) fn introspection_key(stored_access_token: &str) -> String {
+    format!(
+        "introspection:{}",
+        hex::encode(Sha256::digest(stored_access_token))
+    )
+}
+
+/// Revoke an access or refresh token and the rest of its pair, dropping the
+/// cached introspection result and jti denylist answer on every replica.
+/// Both are cached by access token, whichever of the pair is revoked.
+pub(crate) async fn revoke_and_forget(
+    token: String,
+    token_actor: &Addr<TokenActor>,
+    db: &Database,
+    invalidator: &CacheInvalidator,
+) -> Result<(), OAuth2Error> {
+    let stored = match db.get_token_by_access_token(&token).await? {
+        Some(token) => Some(token),
+        None => db.get_token_by_refresh_token(&token).await?,
+    };
+
+    token_actor.send(RevokeToken { token }).await??;
+
+    if let Some(stored) = stored {
+        invalidator.forget_tokens(&[stored]).await;
+    }
+    Ok(())
+}
+
 /// Token introspection endpoint
 /// Returns information about a token
+#[allow(clippy::too_many_arguments)]
 pub async fn introspect(
+    req: HttpRequest,
     form: web::Form<IntrospectRequest>,
     token_actor: web::Data<Addr<TokenActor>>,
-    jwt_secret: web::Data<String>,
+    jwt: web::Data<Arc<JwtConfig>>,
+    keys: web::Data<SharedKeys>,
+    clock: web::Data<dyn Clock>,
+    cache_config: web::Data<Arc<IntrospectionConfig>>,
+    cache: web::Data<dyn Cache>,
 ) -> Result<HttpResponse, OAuth2Error> {
+    let key = introspection_key(&stored_access_token(&form.token));
+    match cache.get(&key).await {
+        Ok(Some(cached)) => {
+            if let Ok(cached) = serde_json::from_str::<CachedIntrospection>(&cached) {
+                let remaining = cached.until - clock.now().timestamp();
+                if remaining > 0 {
+                    return Ok(introspection_response(
+                        &req,
+                        &cached.response,
+                        remaining as u64,
+                    ));
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Failed to read cached introspection: {}", e),
+    }
+
+    // Refresh tokens are only looked up when asked for, so a resource
+    // server introspecting a bearer token never sees one as active
+    if form.token_type_hint.as_deref() == Some("refresh_token") {
+        match token_actor
+            .send(ValidateRefreshToken {
+                token: form.token.clone(),
+            })
+            .await?
+        {
+            Ok(Some(token)) => {
+                return Ok(introspection_response(
+                    &req,
+                    &refresh_introspection(token),
+                    0,
+                ))
+            }
+            // Not a refresh token, so try it as an access token
+            Ok(None) => {}
+            Err(_) => {
+                return Ok(introspection_response(
+                    &req,
+                    &IntrospectionResponse::inactive(),
+                    0,
+                ))
+            }
+        }
+    }
+
     // Try to validate the token
     let token_result = token_actor
         .send(ValidateToken {
             token: form.token.clone(),
         })
-        .await
-        .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))?;
+        .await?;
 
     match token_result {
         Ok(token) => {
             // Decode JWT to get claims
-            let claims = Claims::decode(&token.access_token, &jwt_secret).ok();
+            let claims = match Claims::decode(
+                &token.access_token,
+                &keys,
+                clock.get_ref(),
+                jwt.leeway_secs,
+            ) {
+                Ok(claims) => Some(claims),
+                // Issued for later, even allowing for clock drift
+                Err(e) if *e.kind() == ErrorKind::ImmatureSignature => {
+                    return Ok(introspection_response(
+                        &req,
+                        &IntrospectionResponse::inactive(),
+                        0,
+                    ));
+                }
+                Err(_) => None,
+            };
 
+            // Never let a cached result outlive the token itself
+            let remaining = (token.expires_at - clock.now()).num_seconds().max(0) as u64;
+            let max_age = cache_config.max_age_for(&token.client_id).min(remaining);
+
+            let cnf = token.confirmation();
+            let jti = claims.as_ref().map_or(token.id.clone(), |c| c.jti.clone());
             let response = IntrospectionResponse {
-                active: token.is_valid(),
+                active: token.is_valid(clock.get_ref()),
                 scope: Some(token.scope),
                 client_id: Some(token.client_id),
                 username: Some(token.user_id.clone()),
                 token_type: Some(token.token_type),
-                exp: claims.as_ref().map(|c| c.exp),
-                iat: claims.as_ref().map(|c| c.iat),
+                // Opaque tokens have no claims; the stored row says the same
+                exp: Some(
+                    claims
+                        .as_ref()
+                        .map_or(token.expires_at.timestamp(), |c| c.exp),
+                ),
+                iat: Some(
+                    claims
+                        .as_ref()
+                        .map_or(token.created_at.timestamp(), |c| c.iat),
+                ),
+                nbf: claims.as_ref().and_then(|c| c.nbf),
                 sub: Some(token.user_id),
+                aud: token.audience,
+                // Opaque tokens are identified by their stored id instead
+                jti: Some(jti),
+                act: token.impersonator.map(|sub| ActClaim { sub }),
+                authorization_details: token
+                    .authorization_details
+                    .and_then(|details| serde_json::from_str(&details).ok()),
+                cnf,
             };
 
-            Ok(HttpResponse::Ok().json(response))
-        }
-        Err(_) => {
-            // Token is invalid
-            let response = IntrospectionResponse {
-                active: false,
-                scope: None,
-                client_id: None,
-                username: None,
-                token_type: None,
-                exp: None,
-                iat: None,
-                sub: None,
-            };
-            Ok(HttpResponse::Ok().json(response))
+            if response.active && max_age > 0 {
+                let cached = CachedIntrospection {
+                    until: clock.now().timestamp() + max_age as i64,
+                    response,
+                };
+                let stored = match serde_json::to_string(&cached) {
+                    Ok(json) => cache.set(&key, &json, Duration::from_secs(max_age)).await,
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(e) = stored {
+                    tracing::warn!("Failed to cache introspection: {}", e);
+                }
+                return Ok(introspection_response(&req, &cached.response, max_age));
+            }
+
+            Ok(introspection_response(&req, &response, max_age))
         }
+        // Token is invalid
+        Err(_) => Ok(introspection_response(
+            &req,
+            &IntrospectionResponse::inactive(),
+            0,
+        )),
+    }
+}
+
+/// What introspection tells about an active refresh token: who it was
+/// issued to and for, what it may be refreshed for, and when it expires
+fn refresh_introspection(token: Token) -> IntrospectionResponse {
+    IntrospectionResponse {
+        active: true,
+        scope: Some(token.refreshable_scope().to_string()),
+        client_id: Some(token.client_id),
+        username: Some(token.user_id.clone()),
+        token_type: Some("refresh_token".to_string()),
+        exp: token
+            .refresh_expires_at
+            .map(|expires_at| expires_at.timestamp()),
+        iat: Some(token.created_at.timestamp()),
+        sub: Some(token.user_id),
+        ..IntrospectionResponse::inactive()
+    }
+}
+
+/// Attach cache headers to an introspection result. With a non-zero
+/// `max_age` the body gets an ETag, and a matching `If-None-Match` is
+/// answered with 304 Not Modified.
+fn introspection_response(
+    req: &HttpRequest,
+    response: &IntrospectionResponse,
+    max_age: u64,
+) -> HttpResponse {
+    if max_age == 0 {
+        return HttpResponse::Ok()
+            .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+            .json(response);
+    }
+
+    let body = serde_json::to_vec(response).unwrap_or_default();
+    let etag = EntityTag::new_strong(
+        general_purpose::URL_SAFE_NO_PAD.encode(&Sha256::digest(&body)[..16]),
+    );
+    let cache_control = CacheControl(vec![
+        CacheDirective::Private,
+        CacheDirective::MaxAge(max_age as u32),
+    ]);
+
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if not_modified {
+        return HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .insert_header(cache_control)
+            .finish();
     }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .insert_header(ETag(etag))
+        .insert_header(cache_control)
+        .body(body)
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct RevokeRequest {
     token: String,
     #[allow(dead_code)] // OAuth2 spec field, can be used for optimization
@@ -73,13 +288,9 @@ pub struct RevokeRequest {
 pub async fn revoke(
     form: web::Form<RevokeRequest>,
     token_actor: web::Data<Addr<TokenActor>>,
+    db: web::Data<Arc<Database>>,
+    invalidator: web::Data<Arc<CacheInvalidator>>,
 ) -> Result<HttpResponse, OAuth2Error> {
-    token_actor
-        .send(RevokeToken {
-            token: form.token.clone(),
-        })
-        .await
-        .map_err(|e| OAuth2Error::new("server_error", Some(&e.to_string())))??;
-
+    revoke_and_forget(form.into_inner().token, &token_actor, &db, &invalidator).await?;
     Ok(HttpResponse::Ok().finish())
 }
@@ -1,31 +1,56 @@
-use actix_web::{HttpResponse, Result};
+use crate::config::{JwtConfig, SecurityProfile};
+use crate::keys::SharedKeys;
+use actix_web::{web, HttpResponse, Result};
 use serde_json::json;
+use std::sync::Arc;
 
 /// OAuth2 discovery endpoint
 /// Returns server metadata according to RFC 8414
-pub async fn openid_configuration() -> Result<HttpResponse> {
+pub async fn openid_configuration(
+    keys: web::Data<SharedKeys>,
+    profile: web::Data<SecurityProfile>,
+    jwt: web::Data<Arc<JwtConfig>>,
+) -> Result<HttpResponse> {
+    let issuer = jwt.issuer.as_str();
+    let endpoint = |path: &str| format!("{}{}", issuer, path);
+    let mut grant_types = vec!["authorization_code", "client_credentials"];
+    if profile.allows_password_grant() {
+        grant_types.push("password");
+    }
+    grant_types.push("refresh_token");
+    let mut auth_methods = vec!["client_secret_basic", "client_secret_post"];
+    if profile.allows_public_clients() {
+        auth_methods.push("none");
+    }
     let config = json!({
-        "issuer": "http://localhost:8080",
-        "authorization_endpoint": "http://localhost:8080/oauth/authorize",
-        "token_endpoint": "http://localhost:8080/oauth/token",
-        "token_introspection_endpoint": "http://localhost:8080/oauth/introspect",
-        "token_revocation_endpoint": "http://localhost:8080/oauth/revoke",
-        "registration_endpoint": "http://localhost:8080/clients/register",
-        "scopes_supported": ["read", "write", "admin"],
-        "response_types_supported": ["code", "token"],
-        "grant_types_supported": [
-            "authorization_code",
-            "client_credentials",
-            "password",
-            "refresh_token"
-        ],
-        "token_endpoint_auth_methods_supported": [
-            "client_secret_basic",
-            "client_secret_post"
-        ],
-        "code_challenge_methods_supported": ["plain", "S256"],
-        "service_documentation": "http://localhost:8080/docs"
+        "issuer": issuer,
+        "jwks_uri": endpoint("/.well-known/jwks.json"),
+        "authorization_endpoint": endpoint("/oauth/authorize"),
+        "token_endpoint": endpoint("/oauth/token"),
+        "token_introspection_endpoint": endpoint("/oauth/introspect"),
+        "token_revocation_endpoint": endpoint("/oauth/revoke"),
+        "registration_endpoint": endpoint("/clients/register"),
+        "check_session_iframe": endpoint("/oauth/check_session"),
+        "userinfo_endpoint": endpoint("/oauth/userinfo"),
+        "scopes_supported": ["openid", "profile", "email", "read", "write", "admin"],
+        "response_types_supported": ["code"],
+        "response_modes_supported": ["query", "web_message"],
+        "grant_types_supported": grant_types,
+        "token_endpoint_auth_methods_supported": auth_methods,
+        "code_challenge_methods_supported": profile.code_challenge_methods(),
+        "security_profile": profile.as_str(),
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": [keys.algorithm()],
+        "claims_supported": ["sub", "name", "preferred_username", "picture", "email"],
+        "service_documentation": endpoint("/docs")
     });
 
     Ok(HttpResponse::Ok().json(config))
 }
+
+/// JSON Web Key Set endpoint (RFC 7517)
+/// Returns the public keys tokens are signed with, for verifying them
+/// offline. Empty while tokens are signed with the HMAC secret.
+pub async fn jwks(keys: web::Data<SharedKeys>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(keys.jwks()))
+}
@@ -0,0 +1,468 @@
+//! Keys tokens are signed and verified with.
+//!
+//! By default every JWT is signed with HS256 and `OAUTH2_JWT_SECRET`, so only
+//! holders of the secret can check one. With `OAUTH2_JWT_ALGORITHM` set to
+//! `RS256` or `ES256` they are signed with a private key instead, and
+//! resource servers verify them offline against the public key published at
+//! `/.well-known/jwks.json`. Such tokens name their key in the `kid` header:
+//! the key's RFC 7638 thumbprint, so a new key always gets a new `kid`.
+//!
+//! The private key is read from `OAUTH2_JWT_PRIVATE_KEY_FILE`, as PKCS#8 PEM
+//! (or PKCS#1 for RSA). A file that does not exist yet is created with a new
+//! key. Without a file the key is generated at startup and lives only in
+//! this process: its tokens stop verifying on restart, and replicas each
+//! have their own.
+//!
+//! For keys that must never be in process memory, a [`TokenSigner`] hands
+//! the signing itself to an HSM over PKCS#11 or to a cloud KMS sign API.
+//! Only its public key is held here, to verify tokens and publish them.
+
+use crate::config::JwtConfig;
+use base64::{engine::general_purpose, Engine as _};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey};
+use rsa::pkcs8::der::Decode;
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, SubjectPublicKeyInfoRef};
+use rsa::traits::PublicKeyParts;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Size of generated RSA keys
+const RSA_KEY_BITS: usize = 2048;
+
+/// Signs tokens with a private key held outside the process, such as in an
+/// HSM reached over PKCS#11 or a cloud KMS. `sign` blocks until the
+/// signature comes back.
+pub trait TokenSigner: Send + Sync {
+    /// `RS256` or `ES256`
+    fn algorithm(&self) -> Algorithm;
+
+    /// The public key, as DER SubjectPublicKeyInfo: what PKCS#11 exports and
+    /// KMS `GetPublicKey` calls return
+    fn public_key(&self) -> Result<Vec<u8>, String>;
+
+    /// Signature over `message` as JWS wants it: PKCS#1 v1.5 with SHA-256
+    /// for `RS256`, and for `ES256` the 64 bytes of `r` then `s`, not the
+    /// DER that most KMS APIs return
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String>;
+
+    fn name(&self) -> &str;
+}
+
+/// What signs: a key in memory, or a signer holding it elsewhere
+enum Signer {
+    Local(EncodingKey),
+    External(Arc<dyn TokenSigner>),
+}
+
+pub struct SigningKeys {
+    algorithm: Algorithm,
+    signer: Signer,
+    decoding: DecodingKey,
+    /// Public key as a JWK, for asymmetric algorithms
+    jwk: Option<Value>,
+}
+
+/// Shared handle passed to actors and the HTTP layer
+pub type SharedKeys = Arc<SigningKeys>;
+
+impl SigningKeys {
+    /// HS256 with a shared secret
+    pub fn hmac(secret: &str) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            signer: Signer::Local(EncodingKey::from_secret(secret.as_bytes())),
+            decoding: DecodingKey::from_secret(secret.as_bytes()),
+            jwk: None,
+        }
+    }
+
+    /// The keys `config` asks for, loading, creating or generating the
+    /// private key as described above
+    pub fn from_config(config: &JwtConfig) -> Result<Self, String> {
+        if config.algorithm == Algorithm::HS256 {
+            return Ok(Self::hmac(&config.secret));
+        }
+
+        let Some(path) = &config.private_key_file else {
+            tracing::warn!(
+                "OAUTH2_JWT_PRIVATE_KEY_FILE is not set; tokens are signed with a key that only lasts until restart"
+            );
+            return Self::generate(config.algorithm);
+        };
+        match std::fs::read_to_string(path) {
+            Ok(pem) => Self::from_pem(config.algorithm, &pem)
+                .map_err(|e| format!("Invalid JWT signing key in {}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let pem = generate_pem(config.algorithm)?;
+                write_private(path, &pem)
+                    .map_err(|e| format!("Failed to write JWT signing key to {}: {}", path, e))?;
+                tracing::info!(
+                    "Generated a new {:?} signing key in {}",
+                    config.algorithm,
+                    path
+                );
+                Self::from_pem(config.algorithm, &pem)
+            }
+            Err(e) => Err(format!("Failed to read JWT signing key {}: {}", path, e)),
+        }
+    }
+
+    /// A new key for `algorithm`, `RS256` or `ES256`
+    pub fn generate(algorithm: Algorithm) -> Result<Self, String> {
+        Self::from_pem(algorithm, &generate_pem(algorithm)?)
+    }
+
+    /// Keys for `algorithm` from a PEM private key
+    pub fn from_pem(algorithm: Algorithm, pem: &str) -> Result<Self, String> {
+        let pem = pem::parse(pem).map_err(|e| e.to_string())?;
+        match algorithm {
+            Algorithm::RS256 => {
+                let key = match pem.tag() {
+                    "RSA PRIVATE KEY" => RsaPrivateKey::from_pkcs1_der(pem.contents()),
+                    _ => RsaPrivateKey::from_pkcs8_der(pem.contents())
+                        .map_err(rsa::pkcs1::Error::Pkcs8),
+                }
+                .map_err(|e| format!("not an RSA private key: {}", e))?;
+                Self::rsa(&key)
+            }
+            Algorithm::ES256 => Self::ec(pem.contents()),
+            other => Err(format!("{:?} is not an asymmetric algorithm", other)),
+        }
+    }
+
+    /// Keys that sign through `signer`, verifying with the public key it
+    /// reports
+    pub fn from_signer(signer: Arc<dyn TokenSigner>) -> Result<Self, String> {
+        let der = signer.public_key()?;
+        let (algorithm, decoding, jwk) = match signer.algorithm() {
+            Algorithm::RS256 => {
+                let key = RsaPublicKey::from_public_key_der(&der)
+                    .map_err(|e| format!("not an RSA public key: {}", e))?;
+                rsa_public(&key)?
+            }
+            Algorithm::ES256 => {
+                let spki = SubjectPublicKeyInfoRef::from_der(&der)
+                    .map_err(|e| format!("not a public key: {}", e))?;
+                ec_public(spki.subject_public_key.raw_bytes())?
+            }
+            other => return Err(format!("{:?} is not an asymmetric algorithm", other)),
+        };
+        Ok(Self {
+            algorithm,
+            signer: Signer::External(signer),
+            decoding,
+            jwk: Some(jwk),
+        })
+    }
+
+    fn rsa(key: &RsaPrivateKey) -> Result<Self, String> {
+        let der = key.to_pkcs1_der().map_err(|e| e.to_string())?;
+        let (algorithm, decoding, jwk) = rsa_public(&key.to_public_key())?;
+        Ok(Self {
+            algorithm,
+            signer: Signer::Local(EncodingKey::from_rsa_der(der.as_bytes())),
+            decoding,
+            jwk: Some(jwk),
+        })
+    }
+
+    fn ec(pkcs8: &[u8]) -> Result<Self, String> {
+        let rng = ring::rand::SystemRandom::new();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng)
+            .map_err(|e| format!("not a P-256 PKCS#8 private key: {}", e))?;
+        let (algorithm, decoding, jwk) = ec_public(pair.public_key().as_ref())?;
+        Ok(Self {
+            algorithm,
+            signer: Signer::Local(EncodingKey::from_ec_der(pkcs8)),
+            decoding,
+            jwk: Some(jwk),
+        })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// `kid` of the signing key; HMAC keys are not published, so have none
+    pub fn kid(&self) -> Option<&str> {
+        self.jwk.as_ref().and_then(|jwk| jwk["kid"].as_str())
+    }
+
+    /// Name of the external signer, if tokens are signed by one
+    pub fn external_signer(&self) -> Option<&str> {
+        match &self.signer {
+            Signer::Local(_) => None,
+            Signer::External(signer) => Some(signer.name()),
+        }
+    }
+
+    /// Sign `claims`, with `typ` in the header if given
+    pub fn encode<T: Serialize>(
+        &self,
+        claims: &T,
+        typ: Option<&str>,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid().map(str::to_string);
+        if let Some(typ) = typ {
+            header.typ = Some(typ.to_string());
+        }
+        let signer = match &self.signer {
+            Signer::Local(key) => return jsonwebtoken::encode(&header, claims, key),
+            Signer::External(signer) => signer,
+        };
+
+        let header = serde_json::to_vec(&header)?;
+        let claims = serde_json::to_vec(claims)?;
+        let message = format!("{}.{}", base64url(&header), base64url(&claims));
+        let signature = signer.sign(message.as_bytes()).map_err(|e| {
+            tracing::error!("Signer {} failed to sign a token: {}", signer.name(), e);
+            jsonwebtoken::errors::ErrorKind::Crypto(ring::error::Unspecified)
+        })?;
+        Ok(format!("{}.{}", message, base64url(&signature)))
+    }
+
+    /// Checks for tokens signed with these keys. Only this algorithm is
+    /// accepted, so a token cannot pick a weaker one for itself.
+    pub fn validation(&self) -> Validation {
+        Validation::new(self.algorithm)
+    }
+
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding
+    }
+
+    /// The public keys as a JWK Set; empty for HMAC
+    pub fn jwks(&self) -> Value {
+        json!({ "keys": self.jwk.iter().collect::<Vec<_>>() })
+    }
+}
+
+/// `RS256` verification key and JWK for an RSA public key
+fn rsa_public(key: &RsaPublicKey) -> Result<(Algorithm, DecodingKey, Value), String> {
+    let n = base64url(&key.n().to_bytes_be());
+    let e = base64url(&key.e().to_bytes_be());
+    let decoding = DecodingKey::from_rsa_components(&n, &e).map_err(|e| e.to_string())?;
+    let thumbprint = json!({"e": e, "kty": "RSA", "n": n});
+    let jwk = json!({
+        "kty": "RSA",
+        "use": "sig",
+        "alg": "RS256",
+        "kid": thumbprint_of(&thumbprint),
+        "n": n,
+        "e": e,
+    });
+    Ok((Algorithm::RS256, decoding, jwk))
+}
+
+/// `ES256` verification key and JWK for an uncompressed P-256 point: 0x04,
+/// then x and y
+fn ec_public(point: &[u8]) -> Result<(Algorithm, DecodingKey, Value), String> {
+    if point.len() != 65 || point[0] != 4 {
+        return Err("not an uncompressed P-256 public key".to_string());
+    }
+    let x = base64url(&point[1..33]);
+    let y = base64url(&point[33..]);
+    let thumbprint = json!({"crv": "P-256", "kty": "EC", "x": x, "y": y});
+    let jwk = json!({
+        "kty": "EC",
+        "use": "sig",
+        "alg": "ES256",
+        "kid": thumbprint_of(&thumbprint),
+        "crv": "P-256",
+        "x": x,
+        "y": y,
+    });
+    Ok((Algorithm::ES256, DecodingKey::from_ec_der(point), jwk))
+}
+
+/// A PEM private key for `algorithm`, PKCS#8 encoded
+fn generate_pem(algorithm: Algorithm) -> Result<String, String> {
+    let der = match algorithm {
+        Algorithm::RS256 => RsaPrivateKey::new(&mut rand::rngs::OsRng, RSA_KEY_BITS)
+            .and_then(|key| Ok(key.to_pkcs8_der()?.as_bytes().to_vec()))
+            .map_err(|e| e.to_string())?,
+        Algorithm::ES256 => EcdsaKeyPair::generate_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &ring::rand::SystemRandom::new(),
+        )
+        .map_err(|e| e.to_string())?
+        .as_ref()
+        .to_vec(),
+        other => return Err(format!("{:?} is not an asymmetric algorithm", other)),
+    };
+    Ok(pem::encode(&pem::Pem::new("PRIVATE KEY", der)))
+}
+
+/// Write a new file only its owner may read
+fn write_private(path: &str, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// RFC 7638 thumbprint of a JWK's required members. `serde_json` keeps
+/// object keys sorted, which is the order the thumbprint needs.
+fn thumbprint_of(members: &Value) -> String {
+    base64url(&Sha256::digest(members.to_string().as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Claims;
+
+    const RSA_KEY_PEM: &str = include_str!("test_support/mock_idp_key.pem");
+
+    fn round_trip(keys: &SigningKeys) {
+        let token = keys.encode(&json!({"sub": "user"}), None).unwrap();
+        let header = jsonwebtoken::decode_header(&token).unwrap();
+        assert_eq!(header.alg, keys.algorithm());
+        assert_eq!(header.kid.as_deref(), keys.kid());
+
+        let mut validation = keys.validation();
+        validation.required_spec_claims.clear();
+        let claims =
+            jsonwebtoken::decode::<Value>(&token, keys.decoding_key(), &validation).unwrap();
+        assert_eq!(claims.claims["sub"], "user");
+
+        // Verifiable from the published JWK alone
+        if let Some(jwk) = keys.jwks()["keys"].get(0) {
+            let jwk: jsonwebtoken::jwk::Jwk = serde_json::from_value(jwk.clone()).unwrap();
+            let key = DecodingKey::from_jwk(&jwk).unwrap();
+            assert!(jsonwebtoken::decode::<Value>(&token, &key, &validation).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_keys_sign_what_they_verify() {
+        round_trip(&SigningKeys::hmac("secret"));
+        round_trip(&SigningKeys::from_pem(Algorithm::RS256, RSA_KEY_PEM).unwrap());
+        round_trip(&SigningKeys::generate(Algorithm::ES256).unwrap());
+        assert_eq!(SigningKeys::hmac("secret").jwks(), json!({"keys": []}));
+    }
+
+    /// Stands in for an HSM: signs with a key the keys under test never see
+    struct RemoteSigner {
+        algorithm: Algorithm,
+        key: EncodingKey,
+        public_key: Vec<u8>,
+    }
+
+    impl TokenSigner for RemoteSigner {
+        fn algorithm(&self) -> Algorithm {
+            self.algorithm
+        }
+
+        fn public_key(&self) -> Result<Vec<u8>, String> {
+            Ok(self.public_key.clone())
+        }
+
+        fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+            let signature = jsonwebtoken::crypto::sign(message, &self.key, self.algorithm)
+                .map_err(|e| e.to_string())?;
+            general_purpose::URL_SAFE_NO_PAD
+                .decode(signature)
+                .map_err(|e| e.to_string())
+        }
+
+        fn name(&self) -> &str {
+            "remote"
+        }
+    }
+
+    #[test]
+    fn test_external_signers_sign_what_the_keys_verify() {
+        use rsa::pkcs8::EncodePublicKey;
+
+        let rsa = RsaPrivateKey::from_pkcs8_pem(RSA_KEY_PEM)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(RSA_KEY_PEM))
+            .unwrap();
+        let signer = RemoteSigner {
+            algorithm: Algorithm::RS256,
+            key: EncodingKey::from_rsa_pem(RSA_KEY_PEM.as_bytes()).unwrap(),
+            public_key: rsa.to_public_key().to_public_key_der().unwrap().into_vec(),
+        };
+        let keys = SigningKeys::from_signer(Arc::new(signer)).unwrap();
+        round_trip(&keys);
+        assert_eq!(keys.external_signer(), Some("remote"));
+        // The same key in memory gets the same kid
+        let local = SigningKeys::from_pem(Algorithm::RS256, RSA_KEY_PEM).unwrap();
+        assert_eq!(keys.kid(), local.kid());
+        assert_eq!(local.external_signer(), None);
+
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        // SubjectPublicKeyInfo header for a P-256 key, then the point
+        let mut spki = hex::decode("3059301306072a8648ce3d020106082a8648ce3d030107034200").unwrap();
+        spki.extend_from_slice(pair.public_key().as_ref());
+        let signer = RemoteSigner {
+            algorithm: Algorithm::ES256,
+            key: EncodingKey::from_ec_der(pkcs8.as_ref()),
+            public_key: spki,
+        };
+        round_trip(&SigningKeys::from_signer(Arc::new(signer)).unwrap());
+
+        let mismatched = RemoteSigner {
+            algorithm: Algorithm::ES256,
+            key: EncodingKey::from_secret(b"unused"),
+            public_key: rsa.to_public_key().to_public_key_der().unwrap().into_vec(),
+        };
+        assert!(SigningKeys::from_signer(Arc::new(mismatched)).is_err());
+    }
+
+    #[test]
+    fn test_kid_is_the_rfc7638_thumbprint() {
+        // RFC 7638 §3.1
+        let members = json!({
+            "e": "AQAB",
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+        });
+        assert_eq!(
+            thumbprint_of(&members),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+    }
+
+    #[test]
+    fn test_tokens_from_other_algorithms_are_rejected() {
+        let hmac = SigningKeys::hmac("secret");
+        let ec = SigningKeys::generate(Algorithm::ES256).unwrap();
+        let clock = crate::clock::SystemClock;
+        let claims = Claims::new("user".into(), "client".into(), "read".into(), 60, &clock);
+        assert!(Claims::decode(&claims.encode(&ec).unwrap(), &hmac, &clock, 0).is_err());
+        assert!(Claims::decode(&claims.encode(&hmac).unwrap(), &ec, &clock, 0).is_err());
+        assert!(Claims::decode(&claims.encode(&ec).unwrap(), &ec, &clock, 0).is_ok());
+    }
+
+    #[test]
+    fn test_missing_key_file_is_created_and_reused() {
+        let path = std::env::temp_dir().join(format!("jwt_key_{}.pem", uuid::Uuid::new_v4()));
+        let mut config = crate::config::Config::default().jwt;
+        config.algorithm = Algorithm::ES256;
+        config.private_key_file = Some(path.display().to_string());
+        let first = SigningKeys::from_config(&config).unwrap();
+        let second = SigningKeys::from_config(&config).unwrap();
+        assert!(first.kid().is_some());
+        assert_eq!(first.kid(), second.kid());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,2 +1,17 @@
-// Library exports for testing
+pub mod actors;
+pub mod cache;
+pub mod clock;
+pub mod config;
+pub mod db;
 pub mod events;
+pub mod handlers;
+pub mod keys;
+pub mod metrics;
+pub mod middleware;
+pub mod models;
+pub mod server;
+pub mod services;
+pub mod telemetry;
+pub mod templates;
+#[cfg(feature = "test-support")]
+pub mod test_support;
@@ -1,5 +1,12 @@
-use prometheus::{Counter, Histogram, HistogramOpts, IntCounter, IntGauge, Opts, Registry};
-use std::sync::Arc;
+use crate::events::{AnomalySnapshot, BackendStatus, ANOMALY_KINDS};
+use crate::models::RetentionReport;
+use prometheus::{
+    Counter, Gauge, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry,
+};
+use std::sync::{Arc, Mutex};
+use std::thread::ThreadId;
+use tokio::runtime::Handle;
 
 #[derive(Clone)]
 pub struct Metrics {
@@ -30,6 +37,49 @@ pub struct Metrics {
     pub db_queries_total: Counter,
     #[allow(dead_code)] // Planned for observability implementation
     pub db_query_duration_seconds: Histogram,
+
+    // Anomaly metrics, refreshed from the detector on scrape
+    pub client_failed_auth_ratio: GaugeVec,
+    pub introspection_miss_rate: Gauge,
+    pub token_revocations_window: IntGauge,
+    pub token_revocations_baseline: Gauge,
+    pub anomalies_active: IntGaugeVec,
+
+    // Retention metrics, by data class
+    pub retention_purged_total: IntCounterVec,
+    pub retention_dry_run_records: IntGaugeVec,
+
+    // Token requests turned away by admission control, by reason
+    pub token_requests_shed_total: IntCounterVec,
+
+    // Actors restarted by their supervisor, by actor
+    pub actor_restarts_total: IntCounterVec,
+
+    // External event backends, refreshed on scrape
+    pub event_backend_degraded: IntGaugeVec,
+    pub event_backend_buffered_events: IntGaugeVec,
+    pub event_backend_dropped_events: IntGaugeVec,
+
+    // Process metrics, read from /proc on scrape
+    pub process_cpu_seconds_total: Counter,
+    pub process_resident_memory_bytes: IntGauge,
+    pub process_virtual_memory_bytes: IntGauge,
+    pub process_open_fds: IntGauge,
+    pub process_max_fds: IntGauge,
+    pub process_threads: IntGauge,
+
+    // Async runtimes: the actor system's and one per HTTP worker
+    pub http_workers: IntGauge,
+    pub runtime_worker_threads: IntGaugeVec,
+    pub runtime_alive_tasks: IntGaugeVec,
+    pub runtime_queued_tasks: IntGaugeVec,
+    runtimes: Arc<Mutex<Vec<WatchedRuntime>>>,
+}
+
+struct WatchedRuntime {
+    name: String,
+    handle: Handle,
+    thread: ThreadId,
 }
 
 impl Metrics {
@@ -110,6 +160,196 @@ impl Metrics {
         )?;
         registry.register(Box::new(db_query_duration_seconds.clone()))?;
 
+        let client_failed_auth_ratio = GaugeVec::new(
+            Opts::new(
+                "client_failed_auth_ratio",
+                "Share of a client's authentications that failed in the anomaly window",
+            )
+            .namespace("oauth2_server"),
+            &["client_id"],
+        )?;
+        registry.register(Box::new(client_failed_auth_ratio.clone()))?;
+
+        let introspection_miss_rate = Gauge::with_opts(
+            Opts::new(
+                "introspection_miss_rate",
+                "Share of introspected tokens that were not active in the anomaly window",
+            )
+            .namespace("oauth2_server"),
+        )?;
+        registry.register(Box::new(introspection_miss_rate.clone()))?;
+
+        let token_revocations_window = IntGauge::with_opts(
+            Opts::new(
+                "token_revocations_window",
+                "Tokens revoked in the anomaly window",
+            )
+            .namespace("oauth2_server"),
+        )?;
+        registry.register(Box::new(token_revocations_window.clone()))?;
+
+        let token_revocations_baseline = Gauge::with_opts(
+            Opts::new(
+                "token_revocations_baseline",
+                "Mean tokens revoked per anomaly window over the preceding windows",
+            )
+            .namespace("oauth2_server"),
+        )?;
+        registry.register(Box::new(token_revocations_baseline.clone()))?;
+
+        let anomalies_active = IntGaugeVec::new(
+            Opts::new("anomalies_active", "Anomalies currently detected, by kind")
+                .namespace("oauth2_server"),
+            &["kind"],
+        )?;
+        registry.register(Box::new(anomalies_active.clone()))?;
+
+        let retention_purged_total = IntCounterVec::new(
+            Opts::new(
+                "retention_purged_total",
+                "Records purged by the retention job, by data class",
+            )
+            .namespace("oauth2_server"),
+            &["class"],
+        )?;
+        registry.register(Box::new(retention_purged_total.clone()))?;
+
+        let retention_dry_run_records = IntGaugeVec::new(
+            Opts::new(
+                "retention_dry_run_records",
+                "Records the last dry run found past retention, by data class",
+            )
+            .namespace("oauth2_server"),
+            &["class"],
+        )?;
+        registry.register(Box::new(retention_dry_run_records.clone()))?;
+
+        let token_requests_shed_total = IntCounterVec::new(
+            Opts::new(
+                "token_requests_shed_total",
+                "Token requests shed with 503 under load, by reason",
+            )
+            .namespace("oauth2_server"),
+            &["reason"],
+        )?;
+        registry.register(Box::new(token_requests_shed_total.clone()))?;
+
+        let actor_restarts_total = IntCounterVec::new(
+            Opts::new(
+                "actor_restarts_total",
+                "Actors restarted after stopping or panicking, by actor",
+            )
+            .namespace("oauth2_server"),
+            &["actor"],
+        )?;
+        registry.register(Box::new(actor_restarts_total.clone()))?;
+
+        let event_backend_degraded = IntGaugeVec::new(
+            Opts::new(
+                "event_backend_degraded",
+                "Whether an external event backend is degraded and buffering, by plugin",
+            )
+            .namespace("oauth2_server"),
+            &["plugin"],
+        )?;
+        registry.register(Box::new(event_backend_degraded.clone()))?;
+
+        let event_backend_buffered_events = IntGaugeVec::new(
+            Opts::new(
+                "event_backend_buffered_events",
+                "Events waiting for a degraded event backend to recover, by plugin",
+            )
+            .namespace("oauth2_server"),
+            &["plugin"],
+        )?;
+        registry.register(Box::new(event_backend_buffered_events.clone()))?;
+
+        let event_backend_dropped_events = IntGaugeVec::new(
+            Opts::new(
+                "event_backend_dropped_events",
+                "Events dropped because an event backend's buffer was full, by plugin",
+            )
+            .namespace("oauth2_server"),
+            &["plugin"],
+        )?;
+        registry.register(Box::new(event_backend_dropped_events.clone()))?;
+
+        // Process metrics keep their conventional unprefixed names
+        let process_cpu_seconds_total = Counter::with_opts(Opts::new(
+            "process_cpu_seconds_total",
+            "Total user and system CPU time spent in seconds",
+        ))?;
+        registry.register(Box::new(process_cpu_seconds_total.clone()))?;
+
+        let process_resident_memory_bytes = IntGauge::with_opts(Opts::new(
+            "process_resident_memory_bytes",
+            "Resident memory size in bytes",
+        ))?;
+        registry.register(Box::new(process_resident_memory_bytes.clone()))?;
+
+        let process_virtual_memory_bytes = IntGauge::with_opts(Opts::new(
+            "process_virtual_memory_bytes",
+            "Virtual memory size in bytes",
+        ))?;
+        registry.register(Box::new(process_virtual_memory_bytes.clone()))?;
+
+        let process_open_fds = IntGauge::with_opts(Opts::new(
+            "process_open_fds",
+            "Number of open file descriptors",
+        ))?;
+        registry.register(Box::new(process_open_fds.clone()))?;
+
+        let process_max_fds = IntGauge::with_opts(Opts::new(
+            "process_max_fds",
+            "Maximum number of open file descriptors",
+        ))?;
+        registry.register(Box::new(process_max_fds.clone()))?;
+
+        let process_threads = IntGauge::with_opts(Opts::new(
+            "process_threads",
+            "Number of OS threads in the process",
+        ))?;
+        registry.register(Box::new(process_threads.clone()))?;
+
+        let http_workers = IntGauge::with_opts(
+            Opts::new(
+                "http_workers",
+                "Number of HTTP worker threads serving requests",
+            )
+            .namespace("oauth2_server"),
+        )?;
+        registry.register(Box::new(http_workers.clone()))?;
+
+        let runtime_worker_threads = IntGaugeVec::new(
+            Opts::new(
+                "runtime_worker_threads",
+                "Worker threads driving an async runtime, by runtime",
+            )
+            .namespace("oauth2_server"),
+            &["runtime"],
+        )?;
+        registry.register(Box::new(runtime_worker_threads.clone()))?;
+
+        let runtime_alive_tasks = IntGaugeVec::new(
+            Opts::new(
+                "runtime_alive_tasks",
+                "Tasks spawned on an async runtime that have not finished, by runtime",
+            )
+            .namespace("oauth2_server"),
+            &["runtime"],
+        )?;
+        registry.register(Box::new(runtime_alive_tasks.clone()))?;
+
+        let runtime_queued_tasks = IntGaugeVec::new(
+            Opts::new(
+                "runtime_queued_tasks",
+                "Tasks waiting in an async runtime's global queue, by runtime",
+            )
+            .namespace("oauth2_server"),
+            &["runtime"],
+        )?;
+        registry.register(Box::new(runtime_queued_tasks.clone()))?;
+
         Ok(Self {
             registry: Arc::new(registry),
             http_requests_total,
@@ -122,6 +362,197 @@ impl Metrics {
             oauth_active_tokens,
             db_queries_total,
             db_query_duration_seconds,
+            client_failed_auth_ratio,
+            introspection_miss_rate,
+            token_revocations_window,
+            token_revocations_baseline,
+            anomalies_active,
+            retention_purged_total,
+            retention_dry_run_records,
+            token_requests_shed_total,
+            actor_restarts_total,
+            event_backend_degraded,
+            event_backend_buffered_events,
+            event_backend_dropped_events,
+            process_cpu_seconds_total,
+            process_resident_memory_bytes,
+            process_virtual_memory_bytes,
+            process_open_fds,
+            process_max_fds,
+            process_threads,
+            http_workers,
+            runtime_worker_threads,
+            runtime_alive_tasks,
+            runtime_queued_tasks,
+            runtimes: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Report on the async runtime this is called from, as `name`. Returns
+    /// false if it is already watched.
+    pub fn watch_runtime(&self, name: &str) -> bool {
+        let Ok(handle) = Handle::try_current() else {
+            return false;
+        };
+        let thread = std::thread::current().id();
+        let mut runtimes = self.runtimes.lock().unwrap();
+        if runtimes.iter().any(|runtime| runtime.thread == thread) {
+            return false;
+        }
+        runtimes.push(WatchedRuntime {
+            name: name.to_string(),
+            handle,
+            thread,
+        });
+        true
+    }
+
+    /// Report on the runtime of the HTTP worker this is called from. Each
+    /// worker thread runs its own runtime, and builds the app once for
+    /// every address it listens on.
+    pub fn watch_http_worker(&self) {
+        let workers = self.http_workers.get();
+        if self.watch_runtime(&format!("http-worker-{}", workers)) {
+            self.http_workers.inc();
+        }
+    }
+
+    /// Publish what the process and its runtimes are doing right now
+    pub fn record_process(&self) {
+        if let Some(stats) = ProcessStats::read() {
+            let cpu = stats.cpu_seconds - self.process_cpu_seconds_total.get();
+            if cpu > 0.0 {
+                self.process_cpu_seconds_total.inc_by(cpu);
+            }
+            self.process_resident_memory_bytes.set(stats.resident_bytes);
+            self.process_virtual_memory_bytes.set(stats.virtual_bytes);
+            self.process_open_fds.set(stats.open_fds);
+            self.process_max_fds.set(stats.max_fds);
+            self.process_threads.set(stats.threads);
+        }
+
+        for runtime in self.runtimes.lock().unwrap().iter() {
+            let metrics = runtime.handle.metrics();
+            let name = [runtime.name.as_str()];
+            let count = |n: usize| i64::try_from(n).unwrap_or(i64::MAX);
+            self.runtime_worker_threads
+                .with_label_values(&name)
+                .set(count(metrics.num_workers()));
+            self.runtime_alive_tasks
+                .with_label_values(&name)
+                .set(count(metrics.num_alive_tasks()));
+            self.runtime_queued_tasks
+                .with_label_values(&name)
+                .set(count(metrics.global_queue_depth()));
+        }
+    }
+
+    /// Publish the detector's current view
+    pub fn record_anomalies(&self, snapshot: &AnomalySnapshot) {
+        // Clients that went quiet drop out rather than keeping a stale ratio
+        self.client_failed_auth_ratio.reset();
+        for (client_id, ratio) in &snapshot.failed_auth_ratios {
+            self.client_failed_auth_ratio
+                .with_label_values(&[client_id])
+                .set(*ratio);
+        }
+        self.introspection_miss_rate
+            .set(snapshot.introspection_miss_rate);
+        self.token_revocations_window
+            .set(i64::try_from(snapshot.revocations).unwrap_or(i64::MAX));
+        self.token_revocations_baseline
+            .set(snapshot.revocation_baseline);
+
+        for kind in ANOMALY_KINDS {
+            let active = snapshot.anomalies.iter().filter(|a| a.kind == kind).count();
+            self.anomalies_active
+                .with_label_values(&[kind])
+                .set(active as i64);
+        }
+    }
+
+    /// Publish how the external event backends are doing
+    pub fn record_event_backends(&self, statuses: &[BackendStatus]) {
+        for status in statuses {
+            let plugin = [status.plugin.as_str()];
+            self.event_backend_degraded
+                .with_label_values(&plugin)
+                .set(i64::from(status.degraded));
+            self.event_backend_buffered_events
+                .with_label_values(&plugin)
+                .set(i64::try_from(status.buffered).unwrap_or(i64::MAX));
+            self.event_backend_dropped_events
+                .with_label_values(&plugin)
+                .set(i64::try_from(status.dropped).unwrap_or(i64::MAX));
+        }
+    }
+
+    /// Count what a retention run purged, or found on a dry run
+    pub fn record_retention(&self, report: &RetentionReport) {
+        for (class, records) in report.classes() {
+            if report.dry_run {
+                self.retention_dry_run_records
+                    .with_label_values(&[class])
+                    .set(i64::try_from(records).unwrap_or(i64::MAX));
+            } else {
+                self.retention_purged_total
+                    .with_label_values(&[class])
+                    .inc_by(records);
+            }
+        }
+    }
+}
+
+/// Resource use of this process, from `/proc/self`
+#[derive(Debug)]
+struct ProcessStats {
+    cpu_seconds: f64,
+    resident_bytes: i64,
+    virtual_bytes: i64,
+    open_fds: i64,
+    max_fds: i64,
+    threads: i64,
+}
+
+impl ProcessStats {
+    /// Clock ticks per second in `/proc/self/stat`; `USER_HZ` is 100 on
+    /// every architecture Linux exposes to user space
+    const TICKS_PER_SECOND: f64 = 100.0;
+
+    /// `None` where there is no `/proc`, as on macOS
+    fn read() -> Option<Self> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+        let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+
+        let status_field = |name: &str| -> Option<i64> {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(name))?
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()
+        };
+        // Fields after the parenthesised command name, which may hold spaces;
+        // utime and stime are the 14th and 15th fields overall
+        let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+        let utime: f64 = fields.next()?.parse().ok()?;
+        let stime: f64 = fields.next()?.parse().ok()?;
+        let max_fds = limits
+            .lines()
+            .find_map(|line| line.strip_prefix("Max open files"))
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|soft| soft.parse().ok())
+            .unwrap_or(-1);
+
+        Some(Self {
+            cpu_seconds: (utime + stime) / Self::TICKS_PER_SECOND,
+            resident_bytes: status_field("VmRSS:")? * 1024,
+            virtual_bytes: status_field("VmSize:")? * 1024,
+            open_fds: std::fs::read_dir("/proc/self/fd").ok()?.count() as i64,
+            max_fds,
+            threads: status_field("Threads:")?,
         })
     }
 }
@@ -131,3 +562,54 @@ impl Default for Metrics {
         Self::new().expect("Failed to create metrics")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_stats() {
+        let stats = ProcessStats::read().unwrap();
+        assert!(stats.resident_bytes > 0);
+        assert!(stats.open_fds > 0);
+        assert!(stats.max_fds >= stats.open_fds);
+        assert!(stats.threads >= 1);
+    }
+
+    #[actix_web::test]
+    async fn test_runtime_metrics() {
+        let metrics = Metrics::new().unwrap();
+        metrics.watch_runtime("main");
+        // Another app on a watched thread is not another worker
+        metrics.watch_http_worker();
+        assert_eq!(metrics.http_workers.get(), 0);
+
+        std::thread::spawn({
+            let metrics = metrics.clone();
+            move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .build()
+                    .unwrap();
+                runtime.block_on(async {
+                    metrics.watch_http_worker();
+                    metrics.watch_http_worker();
+                });
+            }
+        })
+        .join()
+        .unwrap();
+        metrics.record_process();
+
+        assert_eq!(metrics.http_workers.get(), 1);
+        for runtime in ["main", "http-worker-0"] {
+            assert_eq!(
+                metrics
+                    .runtime_worker_threads
+                    .with_label_values(&[runtime])
+                    .get(),
+                1
+            );
+        }
+    }
+}
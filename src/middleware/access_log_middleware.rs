@@ -0,0 +1,209 @@
+//! Access logging with per-route sampling and per-client verbose mode.
+//!
+//! Each request is logged once it completes, unless its route is sampled
+//! and this one was not picked (see [`LogSampler`]); server errors are always
+//! logged. Requests from a client in verbose mode are always logged, along
+//! with their query and form parameters, secrets redacted as in the admin
+//! audit log.
+
+use crate::middleware::admin_audit_middleware::{redact, token_fingerprint};
+use crate::telemetry::LogSampler;
+use actix_web::{
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web, Error,
+};
+use base64::{engine::general_purpose, Engine as _};
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub struct AccessLog {
+    sampler: Arc<LogSampler>,
+}
+
+impl AccessLog {
+    pub fn new(sampler: Arc<LogSampler>) -> Self {
+        Self { sampler }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AccessLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessLogService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AccessLogService {
+            service: Rc::new(service),
+            sampler: self.sampler.clone(),
+        }))
+    }
+}
+
+pub struct AccessLogService<S> {
+    service: Rc<S>,
+    sampler: Arc<LogSampler>,
+}
+
+/// Parameters as `name=value` pairs with secrets redacted; authorization
+/// codes are fingerprinted like tokens
+fn loggable_params(params: Vec<(String, String)>) -> String {
+    params
+        .into_iter()
+        .map(|(name, value)| {
+            let value = if name == "code" {
+                token_fingerprint(&value)
+            } else {
+                redact(&name, &value).unwrap_or(value)
+            };
+            format!("{}={}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The client a request is for: a `client_id` parameter, else the user of
+/// HTTP Basic credentials
+pub(crate) fn client_id(req: &ServiceRequest, params: &[(String, String)]) -> Option<String> {
+    if let Some((_, client_id)) = params.iter().find(|(name, _)| name == "client_id") {
+        return Some(client_id.clone());
+    }
+    let basic = req
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(general_purpose::STANDARD.decode(basic).ok()?).ok()?;
+    decoded.split_once(':').map(|(user, _)| user.to_string())
+}
+
+pub(crate) fn is_form(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"))
+}
+
+impl<S, B> Service<ServiceRequest> for AccessLogService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let svc = self.service.clone();
+        let sampler = self.sampler.clone();
+
+        Box::pin(async move {
+            let sampled = sampler.sample_route(req.path());
+
+            // Only work out the client when one might be in verbose mode
+            let mut verbose = None;
+            if sampler.has_debug_clients() {
+                let mut params: Vec<(String, String)> =
+                    serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+                if is_form(&req) {
+                    // Read the form, then hand the body on
+                    let body = req.extract::<web::Bytes>().await?;
+                    params.extend(
+                        serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+                            .unwrap_or_default(),
+                    );
+                    req.set_payload(Payload::from(body));
+                }
+                verbose = client_id(&req, &params)
+                    .filter(|client_id| sampler.is_debug(client_id))
+                    .map(|client_id| (client_id, loggable_params(params)));
+            }
+
+            let method = req.method().to_string();
+            let path = req.path().to_string();
+            let peer = req
+                .connection_info()
+                .realip_remote_addr()
+                .unwrap_or("-")
+                .to_string();
+            let user_agent = req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("-")
+                .to_string();
+
+            let res = svc.call(req).await?;
+            let status = res.status();
+            let duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+            if let Some((client_id, params)) = verbose {
+                tracing::info!(
+                    peer,
+                    method,
+                    path,
+                    status = status.as_u16(),
+                    duration_ms,
+                    user_agent,
+                    client_id,
+                    params,
+                    verbose = true,
+                    "{} {} {}",
+                    method,
+                    path,
+                    status.as_u16()
+                );
+            } else if sampled || status.is_server_error() {
+                tracing::info!(
+                    peer,
+                    method,
+                    path,
+                    status = status.as_u16(),
+                    duration_ms,
+                    user_agent,
+                    "{} {} {}",
+                    method,
+                    path,
+                    status.as_u16()
+                );
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loggable_params_redact_secrets() {
+        let params = vec![
+            ("grant_type".to_string(), "authorization_code".to_string()),
+            ("code".to_string(), "abc".to_string()),
+            ("client_secret".to_string(), "hunter2".to_string()),
+        ];
+        assert_eq!(
+            loggable_params(params),
+            format!(
+                "grant_type=authorization_code&code={}&client_secret=[REDACTED]",
+                token_fingerprint("abc")
+            )
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! Audit trail for the admin API.
+//!
+//! Every state-changing request under `/admin` (anything but GET, HEAD and
+//! OPTIONS) is recorded in `admin_audit_log` and emitted as an `admin_action`
+//! event once the handler has answered: who made it, the path, its
+//! parameters and the response status.
+//!
+//! Parameters named like credentials are redacted. Tokens are replaced by a
+//! SHA-256 fingerprint rather than dropped, so "who revoked this token" can
+//! still be answered by fingerprinting the token in question.
+
+use crate::clock::SharedClock;
+use crate::db::Database;
+use crate::events::{
+    event_actor::{EmitEvent, EventActor},
+    AuthEvent, EventSeverity, EventType,
+};
+use crate::models::{AdminAuditEntry, AuditChain, SocialUserInfo};
+use crate::services::{ErasedSubject, OriginResolver};
+use actix::Addr;
+use actix_session::SessionExt;
+use actix_web::{
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    web, Error, HttpRequest,
+};
+use futures::future::LocalBoxFuture;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Parameter names whose values are never recorded
+const SECRET_PARAMS: [&str; 4] = ["password", "secret", "assertion", "code_verifier"];
+
+/// Fingerprint of a token, as recorded in place of the token itself
+pub fn token_fingerprint(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    format!("sha256:{}", &hex::encode(digest)[..16])
+}
+
+/// What to record in place of a parameter's value, if not the value itself
+pub(crate) fn redact(name: &str, value: &str) -> Option<String> {
+    let name = name.to_ascii_lowercase();
+    if name.contains("token") {
+        Some(token_fingerprint(value))
+    } else if SECRET_PARAMS.iter().any(|secret| name.contains(secret)) {
+        Some(REDACTED.to_string())
+    } else {
+        None
+    }
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                let replacement = match &*value {
+                    Value::String(s) => redact(name, s),
+                    Value::Object(_) | Value::Array(_) => None,
+                    other => redact(name, &other.to_string()),
+                };
+                match replacement {
+                    Some(replacement) => *value = Value::String(replacement),
+                    None => redact_json(value),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+pub struct AdminAudit {
+    db: Arc<Database>,
+    clock: SharedClock,
+    origin_resolver: Arc<OriginResolver>,
+    event_actor: Option<Addr<EventActor>>,
+    chain: Arc<AuditChain>,
+}
+
+impl AdminAudit {
+    pub fn new(
+        db: Arc<Database>,
+        clock: SharedClock,
+        origin_resolver: Arc<OriginResolver>,
+        event_actor: Option<Addr<EventActor>>,
+        chain: Arc<AuditChain>,
+    ) -> Self {
+        Self {
+            db,
+            clock,
+            origin_resolver,
+            event_actor,
+            chain,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AdminAudit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AdminAuditService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminAuditService {
+            service: Rc::new(service),
+            recorder: Rc::new(Recorder {
+                db: self.db.clone(),
+                clock: self.clock.clone(),
+                origin_resolver: self.origin_resolver.clone(),
+                event_actor: self.event_actor.clone(),
+                chain: self.chain.clone(),
+            }),
+        }))
+    }
+}
+
+pub struct AdminAuditService<S> {
+    service: Rc<S>,
+    recorder: Rc<Recorder>,
+}
+
+struct Recorder {
+    db: Arc<Database>,
+    clock: SharedClock,
+    origin_resolver: Arc<OriginResolver>,
+    event_actor: Option<Addr<EventActor>>,
+    chain: Arc<AuditChain>,
+}
+
+impl Recorder {
+    /// Who is making the request: the signed-in user, else the bearer token's
+    /// user or client
+    async fn actor(&self, req: &HttpRequest) -> String {
+        let user_info: Option<String> = req.get_session().get("user_info").unwrap_or(None);
+        if let Some(user) =
+            user_info.and_then(|json| serde_json::from_str::<SocialUserInfo>(&json).ok())
+        {
+            return format!("user:{}", user.subject());
+        }
+
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let Some(bearer) = bearer else {
+            return "anonymous".to_string();
+        };
+        match self.db.get_token_by_access_token(bearer).await {
+            Ok(Some(token)) if token.is_valid(self.clock.as_ref()) => token.principal(),
+            Ok(_) => "anonymous".to_string(),
+            Err(e) => {
+                tracing::warn!("Failed to resolve admin audit actor: {}", e);
+                "anonymous".to_string()
+            }
+        }
+    }
+
+    /// The path with any redacted path parameters substituted, and every
+    /// parameter as a JSON object. A subject erased by the request itself is
+    /// recorded as its pseudonym.
+    fn describe(req: &HttpRequest, body: &[u8], erased: Option<&ErasedSubject>) -> (String, Value) {
+        let mut params = Map::new();
+        let mut path = req.path().to_string();
+
+        for (name, value) in req.match_info().iter() {
+            let recorded = redact(name, value).or_else(|| {
+                erased
+                    .filter(|erased| erased.subject == value)
+                    .map(|erased| erased.pseudonym.clone())
+            });
+            if let Some(recorded) = &recorded {
+                path = path.replace(value, recorded);
+            }
+            params.insert(
+                name.to_string(),
+                Value::String(recorded.unwrap_or_else(|| value.to_string())),
+            );
+        }
+
+        if let Ok(query) = web::Query::<HashMap<String, String>>::from_query(req.query_string()) {
+            for (name, value) in query.into_inner() {
+                let recorded = redact(&name, &value).unwrap_or(value);
+                params.insert(name, Value::String(recorded));
+            }
+        }
+
+        if !body.is_empty() {
+            let body = match serde_json::from_slice::<Value>(body) {
+                Ok(mut json) => {
+                    redact_json(&mut json);
+                    json
+                }
+                // Only the size of bodies we cannot redact field by field
+                Err(_) => serde_json::json!({ "bytes": body.len() }),
+            };
+            params.insert("body".to_string(), body);
+        }
+
+        (path, Value::Object(params))
+    }
+
+    async fn record(
+        &self,
+        actor: String,
+        req: &HttpRequest,
+        body: &[u8],
+        status: u16,
+        erased: Option<ErasedSubject>,
+    ) {
+        let (path, params) = Self::describe(req, body, erased.as_ref());
+        let origin = self.origin_resolver.resolve(req);
+        let entry = AdminAuditEntry {
+            id: uuid::Uuid::new_v4().to_string(),
+            actor,
+            method: req.method().to_string(),
+            path,
+            params: params.to_string(),
+            status: i64::from(status),
+            client_ip: origin.ip.map(|ip| ip.to_string()),
+            created_at: self.clock.now(),
+            seq: None,
+            prev_hash: None,
+            hash: None,
+        };
+
+        tracing::info!(
+            actor = %entry.actor,
+            status = entry.status,
+            "Admin {} {}",
+            entry.method,
+            entry.path
+        );
+        if let Err(e) = self.db.save_admin_audit_entry(&entry, &self.chain).await {
+            tracing::error!("Failed to record admin audit entry: {}", e);
+        }
+
+        if let Some(event_actor) = &self.event_actor {
+            let severity = if status < 400 {
+                EventSeverity::Info
+            } else {
+                EventSeverity::Warning
+            };
+            let mut event = AuthEvent::new(EventType::AdminAction, severity, None, None)
+                .with_metadata("actor", entry.actor)
+                .with_metadata("method", entry.method)
+                .with_metadata("path", entry.path)
+                .with_metadata("params", entry.params)
+                .with_metadata("status", status.to_string());
+            event = origin.annotate(event);
+            event_actor.do_send(EmitEvent { event });
+        }
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for AdminAuditService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let recorder = self.recorder.clone();
+
+        Box::pin(async move {
+            if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+                return svc.call(req).await;
+            }
+
+            // Identify the caller before the handler runs, in case it revokes
+            // the caller's own token
+            let actor = recorder.actor(req.request()).await;
+
+            // Read the body for the record, then hand it on to the handler
+            let body = req.extract::<web::Bytes>().await?;
+            req.set_payload(Payload::from(body.clone()));
+
+            let res = svc.call(req).await?;
+            let erased = res.response().extensions().get::<ErasedSubject>().cloned();
+            recorder
+                .record(actor, res.request(), &body, res.status().as_u16(), erased)
+                .await;
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redaction() {
+        assert_eq!(redact("id", "client_1"), None);
+        assert_eq!(redact("password", "hunter2").as_deref(), Some(REDACTED));
+        assert_eq!(redact("client_secret", "s").as_deref(), Some(REDACTED));
+        assert_eq!(redact("token", "abc"), Some(token_fingerprint("abc")));
+
+        let mut body = serde_json::json!({
+            "name": "Reports",
+            "credentials": { "new_password": "hunter2", "refresh_token": "rt" },
+        });
+        redact_json(&mut body);
+        assert_eq!(body["name"], "Reports");
+        assert_eq!(body["credentials"]["new_password"], REDACTED);
+        assert_eq!(
+            body["credentials"]["refresh_token"],
+            token_fingerprint("rt")
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! Read-only and full admin roles for the admin API.
+//!
+//! With `admin_access.enforce_roles` on, every `/admin/api` request needs a
+//! role. A read-only admin may use the safe methods (GET, HEAD, OPTIONS),
+//! which list, view and export, and the few POSTs in [`READ_ONLY_POSTS`];
+//! anything that changes state, such as revoking, deleting or rotating,
+//! needs a full admin.
+//!
+//! A bearer token's role comes from its scopes (`admin_access.read_scope`
+//! and `admin_access.write_scope`), counting only those its client is still
+//! registered for; a signed-in user's from being listed in
+//! `admin_access.readers` or `admin_access.admins` by subject. Whichever of
+//! the two grants more is used. Open registrations cannot ask for admin
+//! scopes, so only clients an admin set up can hold a role.
+
+use crate::clock::SharedClock;
+use crate::config::AdminAccessConfig;
+use crate::db::Database;
+use crate::models::{scope::intersect_scopes, OAuth2Error, SocialUserInfo};
+use actix_session::SessionExt;
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    Error, HttpMessage, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// POSTs that change nothing, taking a body only to keep secrets out of URLs
+pub const READ_ONLY_POSTS: &[&str] = &["/admin/api/tokens/decode"];
+
+/// What an admin may do; `Full` includes everything `ReadOnly` may
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AdminRole {
+    ReadOnly,
+    Full,
+}
+
+impl AdminRole {
+    /// The role a request with this method and path needs
+    pub fn required_for(method: &Method, path: &str) -> Self {
+        if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+            || (*method == Method::POST && READ_ONLY_POSTS.contains(&path))
+        {
+            AdminRole::ReadOnly
+        } else {
+            AdminRole::Full
+        }
+    }
+
+    /// The role a bearer token's space-separated scopes grant
+    pub fn from_scopes(scope: &str, config: &AdminAccessConfig) -> Option<Self> {
+        let scopes: Vec<&str> = scope.split_whitespace().collect();
+        if scopes.contains(&config.write_scope.as_str()) {
+            Some(AdminRole::Full)
+        } else if scopes.contains(&config.read_scope.as_str()) {
+            Some(AdminRole::ReadOnly)
+        } else {
+            None
+        }
+    }
+
+    /// The role a signed-in user is listed with
+    pub fn from_subject(subject: &str, config: &AdminAccessConfig) -> Option<Self> {
+        if config.admins.iter().any(|admin| admin == subject) {
+            Some(AdminRole::Full)
+        } else if config.readers.iter().any(|reader| reader == subject) {
+            Some(AdminRole::ReadOnly)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct AdminRoles {
+    config: Arc<AdminAccessConfig>,
+    db: Arc<Database>,
+    clock: SharedClock,
+}
+
+impl AdminRoles {
+    pub fn new(config: Arc<AdminAccessConfig>, db: Arc<Database>, clock: SharedClock) -> Self {
+        Self { config, db, clock }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AdminRoles
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AdminRolesService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AdminRolesService {
+            service: Rc::new(service),
+            config: self.config.clone(),
+            db: self.db.clone(),
+            clock: self.clock.clone(),
+        }))
+    }
+}
+
+pub struct AdminRolesService<S> {
+    service: Rc<S>,
+    config: Arc<AdminAccessConfig>,
+    db: Arc<Database>,
+    clock: SharedClock,
+}
+
+impl<S> AdminRolesService<S> {
+    async fn role(
+        req: &ServiceRequest,
+        config: &AdminAccessConfig,
+        db: &Database,
+        clock: &SharedClock,
+    ) -> Option<AdminRole> {
+        let user_info: Option<String> = req.get_session().get("user_info").unwrap_or(None);
+        let session_role = user_info
+            .and_then(|json| serde_json::from_str::<SocialUserInfo>(&json).ok())
+            .and_then(|user| AdminRole::from_subject(&user.subject(), config));
+
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let token_role = match bearer {
+            Some(bearer) => match db.get_token_by_access_token(bearer).await {
+                Ok(Some(token)) if token.is_valid(clock.as_ref()) => {
+                    match db.get_client(&token.client_id).await {
+                        Ok(Some(client)) => AdminRole::from_scopes(
+                            &intersect_scopes(&token.scope, &client.scope),
+                            config,
+                        ),
+                        Ok(None) => None,
+                        Err(e) => {
+                            tracing::warn!("Failed to look up admin bearer token's client: {}", e);
+                            None
+                        }
+                    }
+                }
+                Ok(_) => None,
+                Err(e) => {
+                    tracing::warn!("Failed to look up admin bearer token: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        session_role.max(token_role)
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for AdminRolesService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let config = self.config.clone();
+        let db = self.db.clone();
+        let clock = self.clock.clone();
+
+        Box::pin(async move {
+            if !config.enforce_roles {
+                return Ok(svc.call(req).await?.map_into_left_body());
+            }
+
+            let required = AdminRole::required_for(req.method(), req.path());
+            let response = match Self::role(&req, &config, &db, &clock).await {
+                Some(role) if role >= required => {
+                    req.extensions_mut().insert(role);
+                    return Ok(svc.call(req).await?.map_into_left_body());
+                }
+                Some(_) => HttpResponse::Forbidden().json(OAuth2Error::access_denied(
+                    "This admin API request needs a full admin; read-only admins may only view",
+                )),
+                None => HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                    .json(OAuth2Error::access_denied(&format!(
+                        "The admin API needs a token with the {} or {} scope, or a signed-in admin",
+                        config.read_scope, config.write_scope
+                    ))),
+            };
+            tracing::warn!(
+                "Refused admin API request {} {} needing {:?} access",
+                req.method(),
+                req.path(),
+                required
+            );
+            Ok(req.into_response(response.map_into_right_body()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_from_scopes_and_subjects() {
+        let config = AdminAccessConfig {
+            readers: vec!["google:auditor".to_string()],
+            admins: vec!["github:ops".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            AdminRole::from_scopes("read admin:read", &config),
+            Some(AdminRole::ReadOnly)
+        );
+        assert_eq!(
+            AdminRole::from_scopes("admin:read admin:write", &config),
+            Some(AdminRole::Full)
+        );
+        assert_eq!(AdminRole::from_scopes("admin:readers", &config), None);
+
+        assert_eq!(
+            AdminRole::from_subject("google:auditor", &config),
+            Some(AdminRole::ReadOnly)
+        );
+        assert_eq!(
+            AdminRole::from_subject("github:ops", &config),
+            Some(AdminRole::Full)
+        );
+        assert_eq!(AdminRole::from_subject("github:someone", &config), None);
+
+        let required = |method, path| AdminRole::required_for(&method, path);
+        assert_eq!(
+            required(Method::GET, "/admin/api/clients"),
+            AdminRole::ReadOnly
+        );
+        assert_eq!(
+            required(Method::POST, "/admin/api/tokens/decode"),
+            AdminRole::ReadOnly
+        );
+        assert_eq!(
+            required(Method::DELETE, "/admin/api/clients/app"),
+            AdminRole::Full
+        );
+        assert_eq!(required(Method::POST, "/admin/api/sync"), AdminRole::Full);
+    }
+}
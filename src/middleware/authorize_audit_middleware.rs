@@ -0,0 +1,257 @@
+//! Log of every `/oauth/authorize` request, for incident forensics.
+//!
+//! Each request is recorded in `authorization_request_log` once it has been
+//! answered, whether a code was issued or not: the client, redirect URI,
+//! scopes asked for and granted, the response type, and the outcome, which
+//! is `code_issued`, `access_denied` when the user turned it down on the
+//! consent page, or the error the request was refused with. The handler
+//! leaves [`IssuedAuthorization`] in the response's extensions naming the
+//! code it issued, or [`DeniedAuthorization`] naming the user who denied
+//! it; when an issued code is exchanged, the token's id is added. The client,
+//! scope count, user hash and outcome also go on the request's trace span.
+//!
+//! Each request belongs to a flow: the [`FlowId`] passed along in the
+//! `flow_id` parameter, or a new one for a request arriving without it. It is
+//! left in the request's extensions for the handler to carry on to the code,
+//! and recorded on the entry and the span.
+//!
+//! Parameters are redacted as in the admin audit log. `state`, `nonce` and
+//! the PKCE challenge are fingerprinted: they are the client's, and only
+//! need to be matched against what the client logged.
+
+use crate::clock::SharedClock;
+use crate::db::Database;
+use crate::middleware::admin_audit_middleware::{redact, token_fingerprint};
+use crate::models::{
+    AuthorizationRequestEntry, DeniedAuthorization, FlowId, IssuedAuthorization, OAuth2Error,
+    FLOW_ID_PARAM,
+};
+use crate::services::OriginResolver;
+use crate::telemetry::OAuthSpan;
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, HttpMessage,
+};
+use futures::future::LocalBoxFuture;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use tracing_actix_web::RootSpan;
+
+/// Parameters recorded only as a fingerprint
+const FINGERPRINTED_PARAMS: [&str; 3] = ["state", "nonce", "code_challenge"];
+
+/// Outcome recorded when a code was issued
+pub const CODE_ISSUED: &str = "code_issued";
+
+pub struct AuthorizeAudit {
+    db: Arc<Database>,
+    clock: SharedClock,
+    origin_resolver: Arc<OriginResolver>,
+}
+
+impl AuthorizeAudit {
+    pub fn new(
+        db: Arc<Database>,
+        clock: SharedClock,
+        origin_resolver: Arc<OriginResolver>,
+    ) -> Self {
+        Self {
+            db,
+            clock,
+            origin_resolver,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AuthorizeAudit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthorizeAuditService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthorizeAuditService {
+            service: Rc::new(service),
+            db: self.db.clone(),
+            clock: self.clock.clone(),
+            origin_resolver: self.origin_resolver.clone(),
+        }))
+    }
+}
+
+pub struct AuthorizeAuditService<S> {
+    service: Rc<S>,
+    db: Arc<Database>,
+    clock: SharedClock,
+    origin_resolver: Arc<OriginResolver>,
+}
+
+/// The query parameters as recorded, redacted
+fn recorded_params(query: &str) -> Map<String, Value> {
+    let Ok(query) = web::Query::<HashMap<String, String>>::from_query(query) else {
+        return Map::new();
+    };
+    query
+        .into_inner()
+        .into_iter()
+        .map(|(name, value)| {
+            let recorded = if FINGERPRINTED_PARAMS.contains(&name.as_str()) {
+                token_fingerprint(&value)
+            } else {
+                redact(&name, &value).unwrap_or(value)
+            };
+            (name, Value::String(recorded))
+        })
+        .collect()
+}
+
+/// The request's OAuth attributes, on its trace span
+fn record_span(span: &OAuthSpan, entry: &AuthorizationRequestEntry) {
+    if let Some(client_id) = &entry.client_id {
+        let grant_type =
+            (entry.response_type.as_deref() == Some("code")).then_some("authorization_code");
+        span.client(client_id, grant_type);
+    }
+    if let Some(scope) = entry.granted_scope.as_ref().or(entry.scope.as_ref()) {
+        span.scope(scope);
+    }
+    if let Some(user_id) = &entry.user_id {
+        span.user(user_id);
+    }
+    if let Some(flow_id) = &entry.flow_id {
+        span.flow(flow_id);
+    }
+    span.outcome(&entry.outcome);
+}
+
+impl<S, B> Service<ServiceRequest> for AuthorizeAuditService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let db = self.db.clone();
+        let clock = self.clock.clone();
+        let origin_resolver = self.origin_resolver.clone();
+
+        Box::pin(async move {
+            let params = recorded_params(req.query_string());
+            let flow_id = params
+                .get(FLOW_ID_PARAM)
+                .and_then(Value::as_str)
+                .and_then(FlowId::parse)
+                .unwrap_or_else(FlowId::generate);
+            req.extensions_mut().insert(flow_id.clone());
+            let client_ip = origin_resolver
+                .resolve(req.request())
+                .ip
+                .map(|ip| ip.to_string());
+            let span = req
+                .extensions()
+                .get::<RootSpan>()
+                .cloned()
+                .map(OAuthSpan::new);
+
+            let res = svc.call(req).await?;
+
+            let issued = res
+                .response()
+                .extensions()
+                .get::<IssuedAuthorization>()
+                .cloned();
+            let denied = res
+                .response()
+                .extensions()
+                .get::<DeniedAuthorization>()
+                .cloned();
+            let status = res.status();
+            let (outcome, error_description) = match res.response().error() {
+                Some(err) => match err.as_error::<OAuth2Error>() {
+                    Some(err) => (err.error.clone(), err.error_description.clone()),
+                    // Refused before the handler ran, e.g. a missing parameter
+                    None if status.is_client_error() => {
+                        ("invalid_request".to_string(), Some(err.to_string()))
+                    }
+                    None => ("server_error".to_string(), None),
+                },
+                None if issued.is_some() => (CODE_ISSUED.to_string(), None),
+                None if denied.is_some() => (
+                    "access_denied".to_string(),
+                    Some("The user denied the request".to_string()),
+                ),
+                None => (status.as_str().to_string(), None),
+            };
+
+            let param = |name: &str| params.get(name).and_then(Value::as_str).map(String::from);
+            let entry = AuthorizationRequestEntry {
+                id: uuid::Uuid::new_v4().to_string(),
+                client_id: param("client_id"),
+                user_id: issued
+                    .as_ref()
+                    .map(|issued| issued.user_id.clone())
+                    .or(denied.map(|denied| denied.user_id)),
+                response_type: param("response_type"),
+                scope: param("scope"),
+                granted_scope: issued.as_ref().map(|issued| issued.scope.clone()),
+                redirect_uri: param("redirect_uri"),
+                params: Value::Object(params.clone()).to_string(),
+                outcome,
+                error_description,
+                status: i64::from(status.as_u16()),
+                code_id: issued.map(|issued| issued.code_id),
+                token_id: None,
+                client_ip,
+                created_at: clock.now(),
+                flow_id: Some(flow_id.as_str().to_string()),
+            };
+
+            if let Some(span) = span {
+                record_span(&span, &entry);
+            }
+            tracing::info!(
+                client_id = entry.client_id.as_deref().unwrap_or(""),
+                flow_id = flow_id.as_str(),
+                outcome = %entry.outcome,
+                "Authorization request"
+            );
+            if let Err(e) = db.save_authorization_request(&entry).await {
+                tracing::error!("Failed to record authorization request: {}", e);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_values_are_fingerprinted() {
+        let params = recorded_params(
+            "client_id=app&state=xyz&code_challenge=abc&client_secret=s&scope=read",
+        );
+        assert_eq!(params["client_id"], "app");
+        assert_eq!(params["scope"], "read");
+        assert_eq!(params["state"], token_fingerprint("xyz"));
+        assert_eq!(params["code_challenge"], token_fingerprint("abc"));
+        assert_eq!(params["client_secret"], "[REDACTED]");
+    }
+}
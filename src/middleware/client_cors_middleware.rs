@@ -0,0 +1,185 @@
+//! CORS for the endpoints browser-based clients call directly.
+//!
+//! Single-page apps exchange codes at `/oauth/token` and revoke tokens at
+//! `/oauth/revoke` from their own origin. Rather than opening those endpoints
+//! to every origin, [`ClientCors`] admits only origins some client registered
+//! in `allowed_origins`. Allowed origins are remembered in the cache for
+//! `cache.origin_ttl_secs`; others are looked up per request so new
+//! registrations apply at once. A preflight does not say which client is
+//! asking, so an origin registered by any client is allowed.
+//!
+//! It must wrap the app's general CORS middleware, which leaves these paths
+//! alone (see [`ClientCors::covers`]).
+
+use crate::cache::SharedCache;
+use crate::db::Database;
+use crate::models::normalize_origin;
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{
+        header::{self, HeaderValue},
+        Method,
+    },
+    Error, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Paths whose CORS policy comes from registered client origins
+const CLIENT_CORS_PATHS: [&str; 2] = ["/oauth/token", "/oauth/revoke"];
+
+const ALLOWED_HEADERS: &str = "authorization, content-type";
+const PREFLIGHT_MAX_AGE_SECS: &str = "3600";
+
+pub struct ClientCors {
+    origins: Arc<OriginLookup>,
+}
+
+impl ClientCors {
+    pub fn new(db: Arc<Database>, cache: SharedCache, origin_ttl: Duration) -> Self {
+        Self {
+            origins: Arc::new(OriginLookup {
+                db,
+                cache,
+                ttl: origin_ttl,
+            }),
+        }
+    }
+
+    /// Whether CORS for `path` is decided here rather than by the app's
+    /// general policy
+    pub fn covers(path: &str) -> bool {
+        CLIENT_CORS_PATHS.contains(&path)
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ClientCors
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ClientCorsService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ClientCorsService {
+            service: Rc::new(service),
+            origins: self.origins.clone(),
+        }))
+    }
+}
+
+pub struct ClientCorsService<S> {
+    service: Rc<S>,
+    origins: Arc<OriginLookup>,
+}
+
+struct OriginLookup {
+    db: Arc<Database>,
+    cache: SharedCache,
+    ttl: Duration,
+}
+
+impl OriginLookup {
+    /// The request's origin, if a client registered it
+    async fn allowed_origin(&self, req: &ServiceRequest) -> Option<HeaderValue> {
+        let origin = req.headers().get(header::ORIGIN)?;
+        let normalized = normalize_origin(origin.to_str().ok()?)?;
+        let key = origin_cache_key(&normalized);
+        match self.cache.get(&key).await {
+            Ok(Some(_)) => return Some(origin.clone()),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to read cached origin {}: {}", normalized, e),
+        }
+
+        match self.db.is_allowed_origin(&normalized).await {
+            Ok(true) => {
+                if let Err(e) = self.cache.set(&key, "1", self.ttl).await {
+                    tracing::warn!("Failed to cache origin {}: {}", normalized, e);
+                }
+                Some(origin.clone())
+            }
+            Ok(false) => None,
+            Err(e) => {
+                tracing::warn!("Failed to check allowed origin {}: {}", normalized, e);
+                None
+            }
+        }
+    }
+}
+
+/// Cache key of the answer that the normalized `origin` is allowed
+pub(crate) fn origin_cache_key(origin: &str) -> String {
+    format!("client_origin:{}", origin)
+}
+
+fn is_preflight(req: &ServiceRequest) -> bool {
+    req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+impl<S, B> Service<ServiceRequest> for ClientCorsService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let origins = self.origins.clone();
+
+        Box::pin(async move {
+            if !ClientCors::covers(req.path()) || !req.headers().contains_key(header::ORIGIN) {
+                return Ok(svc.call(req).await?.map_into_left_body());
+            }
+            let origin = origins.allowed_origin(&req).await;
+
+            if is_preflight(&req) {
+                let post_requested = req
+                    .headers()
+                    .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+                    .is_some_and(|method| method == "POST");
+                let response = match origin {
+                    Some(origin) if post_requested => HttpResponse::Ok()
+                        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin))
+                        .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "POST"))
+                        .insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, ALLOWED_HEADERS))
+                        .insert_header((header::ACCESS_CONTROL_MAX_AGE, PREFLIGHT_MAX_AGE_SECS))
+                        .insert_header((header::VARY, "Origin"))
+                        .finish(),
+                    _ => {
+                        tracing::debug!("Rejected CORS preflight for {}", req.path());
+                        HttpResponse::Forbidden()
+                            .insert_header((header::VARY, "Origin"))
+                            .finish()
+                    }
+                };
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let mut res = svc.call(req).await?;
+            let headers = res.headers_mut();
+            if let Some(origin) = origin {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            }
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            Ok(res.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,164 @@
+//! CSRF protection for the server's own HTML forms.
+//!
+//! Each session gets a random token, handed to templates through the
+//! [`FormCsrfToken`] extractor and rendered into forms as a hidden `csrf_token`
+//! field. [`CsrfProtection`] rejects state-changing requests to the scopes it
+//! wraps unless they carry the session's token, in that field or in an
+//! `X-CSRF-Token` header for scripts.
+//!
+//! This is separate from the OAuth `state` parameter, which protects the
+//! redirect back from an upstream identity provider rather than our forms.
+
+use crate::models::{ErrorCode, OAuth2Error};
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    web, Error, FromRequest, HttpRequest, ResponseError,
+};
+use futures::future::LocalBoxFuture;
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use subtle::ConstantTimeEq;
+
+const CSRF_SESSION_KEY: &str = "form_csrf_token";
+
+/// Form field carrying the token
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// Header carrying the token, for requests made from scripts
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// The session's CSRF token, created on first use
+#[derive(Debug, Clone)]
+pub struct FormCsrfToken(String);
+
+impl FormCsrfToken {
+    pub fn from_session(session: &Session) -> Result<Self, OAuth2Error> {
+        if let Some(token) = session.get::<String>(CSRF_SESSION_KEY).unwrap_or(None) {
+            return Ok(Self(token));
+        }
+        let bytes: [u8; 32] = rand::thread_rng().gen();
+        let token = hex::encode(bytes);
+        session
+            .insert(CSRF_SESSION_KEY, &token)
+            .map_err(|e| OAuth2Error::internal(ErrorCode::SessionError, e))?;
+        Ok(Self(token))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromRequest for FormCsrfToken {
+    type Error = OAuth2Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::from_session(&req.get_session()))
+    }
+}
+
+/// Whether `submitted` is the token stored in the session
+fn is_valid(session: &Session, submitted: Option<&str>) -> bool {
+    let expected: Option<String> = session.get(CSRF_SESSION_KEY).unwrap_or(None);
+    match (expected, submitted) {
+        (Some(expected), Some(submitted)) => {
+            bool::from(expected.as_bytes().ct_eq(submitted.as_bytes()))
+        }
+        _ => false,
+    }
+}
+
+fn is_form(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"))
+}
+
+#[derive(Default)]
+pub struct CsrfProtection;
+
+impl CsrfProtection {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<actix_web::body::EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfProtectionService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfProtectionService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CsrfProtectionService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfProtectionService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<actix_web::body::EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+
+        Box::pin(async move {
+            if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+                return Ok(svc.call(req).await?.map_into_left_body());
+            }
+
+            let mut submitted = req
+                .headers()
+                .get(CSRF_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            if submitted.is_none() && is_form(&req) {
+                // Read the token from the form, then hand the body on
+                let body = req.extract::<web::Bytes>().await?;
+                submitted = serde_urlencoded::from_bytes::<HashMap<String, String>>(&body)
+                    .ok()
+                    .and_then(|mut fields| fields.remove(CSRF_FIELD));
+                req.set_payload(Payload::from(body));
+            }
+
+            if !is_valid(&req.get_session(), submitted.as_deref()) {
+                tracing::warn!(
+                    "Rejected {} {} without a valid CSRF token",
+                    req.method(),
+                    req.path()
+                );
+                let response = OAuth2Error::access_denied("Missing or invalid CSRF token")
+                    .error_response()
+                    .map_into_right_body();
+                return Ok(req.into_response(response));
+            }
+
+            Ok(svc.call(req).await?.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,126 @@
+//! Splits the server between a public and an internal listener.
+//!
+//! With an internal listener configured, the admin surface and metrics are
+//! only served there, and the internal listener serves nothing else but
+//! health checks and the static assets the admin dashboard loads. Each side
+//! answers the other's paths with a plain 404, as if they did not exist, so a
+//! misconfigured ingress in front of the public listener cannot expose them.
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+/// Served on the internal listener only
+const INTERNAL_ONLY: &[&str] = &["/admin", "/metrics"];
+
+/// Served on both listeners
+const SHARED: &[&str] = &["/health", "/ready", "/static"];
+
+pub(crate) fn under(path: &str, prefixes: &[&str]) -> bool {
+    prefixes.iter().any(|prefix| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Whether `path` is served on the listener a request arrived on
+pub fn is_served(path: &str, internal: bool) -> bool {
+    if under(path, SHARED) {
+        return true;
+    }
+    internal == under(path, INTERNAL_ONLY)
+}
+
+pub struct ListenerSplit {
+    internal_addr: Option<SocketAddr>,
+}
+
+impl ListenerSplit {
+    /// Split requests by whether they arrived on `internal_addr`; with none,
+    /// every path is served on every listener
+    pub fn new(internal_addr: Option<SocketAddr>) -> Self {
+        Self { internal_addr }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ListenerSplit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ListenerSplitService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ListenerSplitService {
+            service: Rc::new(service),
+            internal_addr: self.internal_addr,
+        }))
+    }
+}
+
+pub struct ListenerSplitService<S> {
+    service: Rc<S>,
+    internal_addr: Option<SocketAddr>,
+}
+
+impl<S, B> Service<ServiceRequest> for ListenerSplitService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let internal_addr = self.internal_addr;
+
+        Box::pin(async move {
+            if let Some(internal_addr) = internal_addr {
+                let internal = req.app_config().local_addr() == internal_addr;
+                if !is_served(req.path(), internal) {
+                    let response = HttpResponse::NotFound().finish().map_into_right_body();
+                    return Ok(req.into_response(response));
+                }
+            }
+
+            Ok(svc.call(req).await?.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_split_between_listeners() {
+        for path in ["/admin", "/admin/api/clients", "/metrics"] {
+            assert!(is_served(path, true), "{}", path);
+            assert!(!is_served(path, false), "{}", path);
+        }
+        for path in ["/oauth/token", "/", "/administrator", "/swagger-ui/"] {
+            assert!(!is_served(path, true), "{}", path);
+            assert!(is_served(path, false), "{}", path);
+        }
+        for path in ["/health", "/ready", "/static/css/admin.css"] {
+            assert!(is_served(path, true), "{}", path);
+            assert!(is_served(path, false), "{}", path);
+        }
+    }
+}
@@ -1,4 +1,26 @@
+pub mod access_log_middleware;
+pub mod admin_audit_middleware;
+pub mod admin_roles_middleware;
 pub mod auth_middleware;
+pub mod authorize_audit_middleware;
+pub mod client_cors_middleware;
+pub mod csrf_middleware;
+pub mod listener_middleware;
 pub mod metrics_middleware;
+pub mod rate_limit_middleware;
+pub mod schema_validation_middleware;
+pub mod session_state_middleware;
+pub mod session_timeout_middleware;
 
+pub use access_log_middleware::*;
+pub use admin_audit_middleware::*;
+pub use admin_roles_middleware::*;
+pub use authorize_audit_middleware::*;
+pub use client_cors_middleware::*;
+pub use csrf_middleware::*;
+pub use listener_middleware::*;
 pub use metrics_middleware::*;
+pub use rate_limit_middleware::*;
+pub use schema_validation_middleware::*;
+pub use session_state_middleware::*;
+pub use session_timeout_middleware::*;
@@ -0,0 +1,150 @@
+//! Applies the [`RateLimiter`] to incoming requests.
+//!
+//! Token and introspection requests are counted against the client named by
+//! a `client_id` parameter or HTTP Basic credentials, falling back to the
+//! caller's address when there is none; everything else against the address.
+//! Responses past the soft limit carry `X-RateLimit-Limit`,
+//! `X-RateLimit-Remaining` and `X-RateLimit-Reset` (seconds until the window
+//! ends); refused requests get the same headers, `Retry-After` and a
+//! `rate_limit_exceeded` error.
+
+use crate::middleware::access_log_middleware::{client_id, is_form};
+use crate::models::{ErrorCode, OAuth2Error};
+use crate::services::{OriginResolver, RateLimitBucket, RateLimitStatus, RateLimiter};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{self, HeaderMap, HeaderName, HeaderValue},
+    web, Error, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+const LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+fn insert_headers(headers: &mut HeaderMap, status: &RateLimitStatus) {
+    for (name, value) in [
+        (LIMIT, status.limit),
+        (REMAINING, status.remaining),
+        (RESET, status.reset_secs),
+    ] {
+        headers.insert(name, HeaderValue::from(value));
+    }
+}
+
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+    origin_resolver: Arc<OriginResolver>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: Arc<RateLimiter>, origin_resolver: Arc<OriginResolver>) -> Self {
+        Self {
+            limiter,
+            origin_resolver,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitService {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+            origin_resolver: self.origin_resolver.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitService<S> {
+    service: Rc<S>,
+    limiter: Arc<RateLimiter>,
+    origin_resolver: Arc<OriginResolver>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let limiter = self.limiter.clone();
+        let origin_resolver = self.origin_resolver.clone();
+
+        Box::pin(async move {
+            let bucket = match RateLimitBucket::for_path(req.path()) {
+                Some(bucket) if limiter.enabled() => bucket,
+                _ => return Ok(svc.call(req).await?.map_into_left_body()),
+            };
+
+            let origin = origin_resolver.resolve(req.request());
+            let mut client = None;
+            if bucket.per_client() {
+                let mut params: Vec<(String, String)> =
+                    serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+                if is_form(&req) {
+                    // Read the form, then hand the body on
+                    let body = req.extract::<web::Bytes>().await?;
+                    params.extend(
+                        serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+                            .unwrap_or_default(),
+                    );
+                    req.set_payload(Payload::from(body));
+                }
+                client = client_id(&req, &params);
+            }
+            let caller = match (&client, origin.ip) {
+                (Some(client_id), _) => format!("client:{}", client_id),
+                (None, Some(ip)) => format!("ip:{}", ip),
+                (None, None) => "ip:unknown".to_string(),
+            };
+
+            let status = limiter
+                .check(bucket, &caller, client.as_deref(), &origin)
+                .await;
+            let Some(status) = status else {
+                return Ok(svc.call(req).await?.map_into_left_body());
+            };
+
+            if status.exceeded {
+                let mut response = HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, status.reset_secs.to_string()))
+                    .json(OAuth2Error::new(
+                        ErrorCode::RateLimitExceeded.as_str(),
+                        Some("Too many requests. Please try again later."),
+                    ));
+                insert_headers(response.headers_mut(), &status);
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+
+            let mut res = svc.call(req).await?;
+            if status.warn {
+                insert_headers(res.headers_mut(), &status);
+            }
+            Ok(res.map_into_left_body())
+        })
+    }
+}
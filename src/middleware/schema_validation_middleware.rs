@@ -0,0 +1,148 @@
+//! Rejects request bodies that do not match their OpenAPI schema.
+//!
+//! Only routes listed in [`REQUEST_BODIES`](crate::services::REQUEST_BODIES)
+//! with a JSON or form body are checked; everything else passes through to
+//! the handlers untouched. A failing body gets a 400 `invalid_request` whose
+//! `violations` list every problem found.
+
+use crate::models::OAuth2Error;
+use crate::services::{SchemaValidator, SchemaViolation};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web, Error, HttpResponse,
+};
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+pub struct SchemaValidation {
+    validator: Option<Arc<SchemaValidator>>,
+}
+
+impl SchemaValidation {
+    /// Check bodies with `validator`; with none, nothing is checked
+    pub fn new(validator: Option<Arc<SchemaValidator>>) -> Self {
+        Self { validator }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SchemaValidation
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SchemaValidationService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SchemaValidationService {
+            service: Rc::new(service),
+            validator: self.validator.clone(),
+        }))
+    }
+}
+
+pub struct SchemaValidationService<S> {
+    service: Rc<S>,
+    validator: Option<Arc<SchemaValidator>>,
+}
+
+enum BodyKind {
+    Json,
+    Form,
+}
+
+fn body_kind(req: &ServiceRequest) -> Option<BodyKind> {
+    let content_type = req.headers().get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let mime = content_type.split(';').next()?.trim();
+    if mime.eq_ignore_ascii_case("application/json") {
+        Some(BodyKind::Json)
+    } else if mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        Some(BodyKind::Form)
+    } else {
+        None
+    }
+}
+
+fn rejection(schema: &str, violations: &[SchemaViolation]) -> HttpResponse {
+    let summary: Vec<String> = violations
+        .iter()
+        .map(|violation| {
+            if violation.pointer.is_empty() {
+                format!("body {}", violation.message)
+            } else {
+                format!("{} {}", violation.pointer, violation.message)
+            }
+        })
+        .collect();
+    let error = OAuth2Error::invalid_request(&format!(
+        "Request body does not match the {} schema: {}",
+        schema,
+        summary.join("; ")
+    ));
+    let mut body = serde_json::to_value(&error).unwrap_or_default();
+    body["violations"] = serde_json::to_value(violations).unwrap_or_default();
+    HttpResponse::BadRequest().json(body)
+}
+
+impl<S, B> Service<ServiceRequest> for SchemaValidationService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let svc = self.service.clone();
+        let validator = self.validator.clone();
+
+        Box::pin(async move {
+            let checked = validator.and_then(|validator| {
+                let schema = SchemaValidator::schema_for(req.method(), &req.match_pattern()?)?;
+                Some((validator, schema, body_kind(&req)?))
+            });
+            let Some((validator, schema, kind)) = checked else {
+                return Ok(svc.call(req).await?.map_into_left_body());
+            };
+
+            // Read the body to check it, then hand it on
+            let body = req.extract::<web::Bytes>().await?;
+            let parsed = match kind {
+                BodyKind::Json => serde_json::from_slice(&body).map_err(|e| SchemaViolation {
+                    pointer: String::new(),
+                    message: format!("is not valid JSON: {}", e),
+                }),
+                BodyKind::Form => SchemaValidator::form_to_json(&body),
+            };
+            let violations = match parsed {
+                Ok(value) => validator.validate(schema, &value),
+                Err(violation) => vec![violation],
+            };
+            if !violations.is_empty() {
+                tracing::debug!(
+                    "Rejected {} {}: {} schema violation(s)",
+                    req.method(),
+                    req.path(),
+                    violations.len()
+                );
+                let response = rejection(schema, &violations).map_into_right_body();
+                return Ok(req.into_response(response));
+            }
+
+            req.set_payload(Payload::from(body));
+            Ok(svc.call(req).await?.map_into_left_body())
+        })
+    }
+}
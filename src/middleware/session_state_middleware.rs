@@ -0,0 +1,239 @@
+//! Browser state for OpenID Connect Session Management.
+//!
+//! Every signed-in session is given a random browser state, which is
+//! mirrored into a cookie scripts on this origin can read. Authorization
+//! responses carry a `session_state` derived from it, and the
+//! `check_session_iframe` recomputes that value from the cookie when a
+//! client asks: once the user signs out, or signs in again as someone else,
+//! the cookie no longer matches and the client knows to end its own session.
+//!
+//! The cookie is kept in step here, after the handler has run, so logins,
+//! logouts and sessions cleared on expiry all update it the same way.
+
+use crate::config::SessionConfig;
+use crate::handlers::auth::session_user;
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    cookie::{time::Duration, Cookie, SameSite},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use futures::future::LocalBoxFuture;
+use oauth2::url::Url;
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+/// Cookie the check session iframe reads the browser state from
+pub const BROWSER_STATE_COOKIE: &str = "op_browser_state";
+
+const BROWSER_STATE: &str = "browser_state";
+
+/// The browser state of a signed-in session, assigned on first use. `None`
+/// while nobody is signed in.
+pub fn browser_state(session: &Session) -> Option<String> {
+    session_user(session)?;
+    if let Some(state) = session.get::<String>(BROWSER_STATE).unwrap_or(None) {
+        return Some(state);
+    }
+
+    let state = random_token(32);
+    if let Err(e) = session.insert(BROWSER_STATE, &state) {
+        tracing::warn!("Failed to store browser state: {}", e);
+        return None;
+    }
+    Some(state)
+}
+
+/// Drop the browser state so the next sign-in in this session gets a new one
+pub(crate) fn forget_browser_state(session: &Session) {
+    session.remove(BROWSER_STATE);
+}
+
+/// The `session_state` for an authorization response to `redirect_uri`:
+/// a salted hash of the client, the origin it runs on and the browser state
+/// (OIDC Session Management §3)
+pub fn session_state(client_id: &str, redirect_uri: &str, browser_state: &str) -> String {
+    let salt = random_token(16);
+    format!(
+        "{}.{}",
+        session_state_hash(
+            client_id,
+            &redirect_uri_origin(redirect_uri),
+            browser_state,
+            &salt
+        ),
+        salt
+    )
+}
+
+/// Hex SHA-256 of the space-separated inputs, as the iframe computes it
+pub fn session_state_hash(
+    client_id: &str,
+    origin: &str,
+    browser_state: &str,
+    salt: &str,
+) -> String {
+    let input = format!("{} {} {} {}", client_id, origin, browser_state, salt);
+    hex::encode(Sha256::digest(input.as_bytes()))
+}
+
+/// The origin the client's page is served from, which is what the iframe
+/// sees as the sender of its messages
+pub(crate) fn redirect_uri_origin(redirect_uri: &str) -> String {
+    Url::parse(redirect_uri)
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_default()
+}
+
+fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Attributes of the browser state cookie
+#[derive(Debug, Clone)]
+struct CookieSettings {
+    secure: bool,
+    domain: Option<String>,
+}
+
+impl CookieSettings {
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::build(BROWSER_STATE_COOKIE, value)
+            .path("/")
+            // Read by script in the iframe, which runs inside the client's
+            // pages: a cross-site frame only sees cookies marked `None`,
+            // and browsers only accept that over HTTPS
+            .http_only(false)
+            .secure(self.secure)
+            .same_site(if self.secure {
+                SameSite::None
+            } else {
+                SameSite::Lax
+            })
+            .finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+pub struct BrowserState {
+    settings: CookieSettings,
+}
+
+impl BrowserState {
+    pub fn new(config: &SessionConfig) -> Self {
+        Self {
+            settings: CookieSettings {
+                secure: config.cookie_secure,
+                domain: config.cookie_domain.clone(),
+            },
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for BrowserState
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = BrowserStateService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(BrowserStateService {
+            service: Rc::new(service),
+            settings: self.settings.clone(),
+        }))
+    }
+}
+
+pub struct BrowserStateService<S> {
+    service: Rc<S>,
+    settings: CookieSettings,
+}
+
+impl<S, B> Service<ServiceRequest> for BrowserStateService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let sent = req
+            .cookie(BROWSER_STATE_COOKIE)
+            .map(|cookie| cookie.value().to_string());
+        let svc = self.service.clone();
+        let settings = self.settings.clone();
+
+        Box::pin(async move {
+            let mut res = svc.call(req).await?;
+            let state = browser_state(&res.request().get_session());
+            if state == sent {
+                return Ok(res);
+            }
+
+            let result = match state {
+                Some(state) => res.response_mut().add_cookie(&settings.cookie(state)),
+                None => {
+                    let mut cookie = settings.cookie(String::new());
+                    cookie.set_max_age(Duration::ZERO);
+                    res.response_mut().add_cookie(&cookie)
+                }
+            };
+            if let Err(e) = result {
+                tracing::warn!("Failed to set the browser state cookie: {}", e);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_state_is_salted_hash_of_client_origin_and_state() {
+        let value = session_state("client-1", "https://app.example.com/cb?x=1", "abc");
+        let (hash, salt) = value.split_once('.').unwrap();
+        assert_eq!(salt.len(), 16);
+        assert_eq!(
+            hash,
+            session_state_hash("client-1", "https://app.example.com", "abc", salt)
+        );
+        assert_ne!(
+            hash,
+            session_state_hash("client-1", "https://app.example.com", "abd", salt)
+        );
+    }
+
+    #[test]
+    fn test_origin_keeps_non_default_ports() {
+        assert_eq!(
+            redirect_uri_origin("http://localhost:3000/cb"),
+            "http://localhost:3000"
+        );
+        assert_eq!(
+            redirect_uri_origin("https://app.example.com:443/cb"),
+            "https://app.example.com"
+        );
+    }
+}
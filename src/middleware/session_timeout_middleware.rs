@@ -0,0 +1,222 @@
+//! Server-side expiry for cookie sessions.
+//!
+//! A cookie session is valid for as long as the browser keeps the cookie, so
+//! signed-in sessions are stamped with when they started and when they were
+//! last used, and cleared here once either limit has passed. The request then
+//! carries on anonymously and the handler sends the user back to the login.
+//!
+//! Sessions started before the user's credentials were last revoked (see
+//! `user_revocations`) are cleared the same way.
+
+use crate::clock::SharedClock;
+use crate::config::SessionConfig;
+use crate::db::Database;
+use crate::middleware::forget_browser_state;
+use crate::models::SocialUserInfo;
+use actix_session::{Session, SessionExt, SessionInsertError};
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    Error,
+};
+use chrono::{DateTime, Utc};
+use futures::future::LocalBoxFuture;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+const SESSION_STARTED_AT: &str = "session_started_at";
+const SESSION_LAST_SEEN: &str = "session_last_seen";
+
+/// Start the idle and absolute clocks for a session that just signed in,
+/// which also gets new browser state
+pub fn start_session(session: &Session, now: DateTime<Utc>) -> Result<(), SessionInsertError> {
+    forget_browser_state(session);
+    session.insert(SESSION_STARTED_AT, now.timestamp())?;
+    session.insert(SESSION_LAST_SEEN, now.timestamp())
+}
+
+/// When the session's user signed in, if it has one
+pub fn session_started_at(session: &Session) -> Option<DateTime<Utc>> {
+    let started_at: i64 = session.get(SESSION_STARTED_AT).unwrap_or(None)?;
+    DateTime::from_timestamp(started_at, 0)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TimeoutPolicy {
+    idle_secs: Option<i64>,
+    absolute_secs: Option<i64>,
+}
+
+impl TimeoutPolicy {
+    fn new(config: &SessionConfig) -> Self {
+        let limit = |secs: u64| (secs > 0).then(|| i64::try_from(secs).unwrap_or(i64::MAX));
+        Self {
+            idle_secs: limit(config.idle_timeout),
+            absolute_secs: limit(config.max_lifetime),
+        }
+    }
+
+    /// Why a session with these stamps has expired at `now`, if it has
+    fn expired(&self, started_at: i64, last_seen: i64, now: i64) -> Option<&'static str> {
+        if self
+            .absolute_secs
+            .is_some_and(|limit| now - started_at > limit)
+        {
+            return Some("maximum lifetime");
+        }
+        if self.idle_secs.is_some_and(|limit| now - last_seen > limit) {
+            return Some("idle timeout");
+        }
+        None
+    }
+}
+
+pub struct SessionTimeout {
+    policy: TimeoutPolicy,
+    clock: SharedClock,
+    db: Arc<Database>,
+}
+
+impl SessionTimeout {
+    pub fn new(config: &SessionConfig, clock: SharedClock, db: Arc<Database>) -> Self {
+        Self {
+            policy: TimeoutPolicy::new(config),
+            clock,
+            db,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SessionTimeout
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SessionTimeoutService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SessionTimeoutService {
+            service: Rc::new(service),
+            policy: self.policy,
+            clock: self.clock.clone(),
+            db: self.db.clone(),
+        }))
+    }
+}
+
+pub struct SessionTimeoutService<S> {
+    service: Rc<S>,
+    policy: TimeoutPolicy,
+    clock: SharedClock,
+    db: Arc<Database>,
+}
+
+impl<S> SessionTimeoutService<S> {
+    fn enforce(&self, session: &Session) {
+        // Sessions that never signed in have nothing to expire
+        let signed_in = session.get::<String>("user_info").unwrap_or(None).is_some();
+        let started_at: Option<i64> = session.get(SESSION_STARTED_AT).unwrap_or(None);
+        let last_seen: Option<i64> = session.get(SESSION_LAST_SEEN).unwrap_or(None);
+        let now = self.clock.now().timestamp();
+
+        let expired = match (started_at, last_seen) {
+            (Some(started_at), Some(last_seen)) => self.policy.expired(started_at, last_seen, now),
+            // Signed in before sessions were stamped
+            _ if signed_in => Some("missing timestamps"),
+            _ => return,
+        };
+
+        match expired {
+            Some(reason) => {
+                tracing::info!("Clearing session after {}", reason);
+                session.clear();
+            }
+            None if last_seen != Some(now) => {
+                if let Err(e) = session.insert(SESSION_LAST_SEEN, now) {
+                    tracing::warn!("Failed to update session activity: {}", e);
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// Clear the session if the user's credentials were revoked after it started
+async fn enforce_revocation(db: &Database, session: &Session) {
+    let Some(started_at) = session.get::<i64>(SESSION_STARTED_AT).unwrap_or(None) else {
+        return;
+    };
+    let user_info: Option<String> = session.get("user_info").unwrap_or(None);
+    let Some(user) = user_info.and_then(|json| serde_json::from_str::<SocialUserInfo>(&json).ok())
+    else {
+        return;
+    };
+
+    match db.get_user_revoked_at(&user.subject()).await {
+        Ok(Some(revoked_at)) if started_at <= revoked_at.timestamp() => {
+            tracing::info!("Clearing session started before its credentials were revoked");
+            session.clear();
+        }
+        Ok(_) => {}
+        Err(e) => tracing::warn!("Failed to check session revocation: {}", e),
+    }
+}
+
+impl<S, B> Service<ServiceRequest> for SessionTimeoutService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let session = req.get_session();
+        self.enforce(&session);
+        let svc = self.service.clone();
+        let db = self.db.clone();
+
+        Box::pin(async move {
+            enforce_revocation(&db, &session).await;
+            svc.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeout_policy() {
+        let policy = TimeoutPolicy::new(&SessionConfig {
+            idle_timeout: 600,
+            max_lifetime: 3600,
+            ..Default::default()
+        });
+
+        assert_eq!(policy.expired(0, 0, 600), None);
+        assert_eq!(policy.expired(0, 0, 601), Some("idle timeout"));
+        assert_eq!(policy.expired(0, 3000, 3600), None);
+        assert_eq!(policy.expired(0, 3500, 3601), Some("maximum lifetime"));
+    }
+
+    #[test]
+    fn test_zero_disables_a_limit() {
+        let policy = TimeoutPolicy::new(&SessionConfig {
+            idle_timeout: 0,
+            max_lifetime: 0,
+            ..Default::default()
+        });
+        assert_eq!(policy.expired(0, 0, i64::MAX / 2), None);
+    }
+}
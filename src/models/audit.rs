@@ -0,0 +1,244 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+/// One state-changing call to the admin API
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct AdminAuditEntry {
+    pub id: String,
+    /// `user:<subject>`, `client:<client_id>` or `anonymous`
+    pub actor: String,
+    pub method: String,
+    /// Request path, with credentials in it replaced by a fingerprint
+    pub path: String,
+    /// Path, query and body parameters as a JSON object, secrets redacted
+    pub params: String,
+    /// HTTP status of the response
+    pub status: i64,
+    pub client_ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Position in the audit hash chain; absent on entries from before it
+    #[serde(default)]
+    pub seq: Option<i64>,
+    /// `hash` of the entry before this one in the chain
+    #[serde(default)]
+    pub prev_hash: Option<String>,
+    /// HMAC over this entry and `prev_hash`
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+/// Seals admin audit entries into a hash chain, and checks one. Each entry's
+/// hash covers its contents and the hash of the entry before it, so changing,
+/// inserting or removing an entry breaks the chain from there on. The hashes
+/// are HMACs keyed from the server's signing key, so someone with access to
+/// the database alone cannot recompute them.
+pub struct AuditChain {
+    key: Vec<u8>,
+}
+
+impl AuditChain {
+    pub fn new(signing_key: &str) -> Self {
+        // A key of its own, so audit hashes never double as token signatures
+        let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes())
+            .expect("HMAC takes keys of any length");
+        mac.update(b"admin-audit-log");
+        Self {
+            key: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    /// Hash of `entry`, whose `seq` must be set, following `prev_hash`
+    pub fn seal(&self, prev_hash: &str, entry: &AdminAuditEntry) -> String {
+        let contents = serde_json::json!([
+            entry.seq,
+            prev_hash,
+            entry.id,
+            entry.actor,
+            entry.method,
+            entry.path,
+            entry.params,
+            entry.status,
+            entry.client_ip,
+            entry
+                .created_at
+                .to_rfc3339_opts(SecondsFormat::Micros, true),
+        ]);
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(contents.to_string().as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Check `entries`, in chain order. The first sealed entry is taken on
+    /// trust, since those before it may have been purged by retention.
+    pub fn verify(&self, entries: &[AdminAuditEntry]) -> AuditVerification {
+        let mut report = AuditVerification {
+            valid: true,
+            ..Default::default()
+        };
+        let mut previous: Option<(i64, &str)> = None;
+        for entry in entries {
+            let (Some(seq), Some(prev_hash), Some(hash)) =
+                (entry.seq, entry.prev_hash.as_deref(), entry.hash.as_deref())
+            else {
+                report.unsealed += 1;
+                continue;
+            };
+            report.entries += 1;
+            report.first_seq.get_or_insert(seq);
+            report.last_seq = Some(seq);
+
+            let problem = match previous {
+                Some((previous_seq, _)) if seq != previous_seq + 1 => Some(format!(
+                    "entries {} to {} are missing",
+                    previous_seq + 1,
+                    seq - 1
+                )),
+                Some((_, previous_hash)) if prev_hash != previous_hash => {
+                    Some("does not follow the entry before it".to_string())
+                }
+                _ if self.seal(prev_hash, entry) != hash => {
+                    Some("contents do not match its hash".to_string())
+                }
+                _ => None,
+            };
+            if let Some(problem) = problem {
+                report.valid = false;
+                report.broken_at = Some(seq);
+                report.problem = Some(problem);
+                return report;
+            }
+            previous = Some((seq, hash));
+        }
+        report
+    }
+}
+
+/// Outcome of checking the admin audit hash chain
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct AuditVerification {
+    pub valid: bool,
+    /// Sealed entries checked
+    pub entries: u64,
+    /// Entries from before the chain existed, which cannot be checked
+    pub unsealed: u64,
+    pub first_seq: Option<i64>,
+    pub last_seq: Option<i64>,
+    /// First entry the chain breaks at
+    pub broken_at: Option<i64>,
+    pub problem: Option<String>,
+}
+
+/// One request to `/oauth/authorize` and how it ended
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct AuthorizationRequestEntry {
+    pub id: String,
+    pub client_id: Option<String>,
+    /// User the code was issued to; absent when the request was refused
+    /// before a user was known
+    pub user_id: Option<String>,
+    pub response_type: Option<String>,
+    /// Scope as requested
+    pub scope: Option<String>,
+    /// Scope the code carries, once policy has had its say
+    pub granted_scope: Option<String>,
+    pub redirect_uri: Option<String>,
+    /// Every query parameter as a JSON object, with `state`, `nonce` and the
+    /// PKCE challenge fingerprinted
+    pub params: String,
+    /// `code_issued`, or the OAuth2 error code the request was refused with
+    pub outcome: String,
+    pub error_description: Option<String>,
+    /// HTTP status of the response
+    pub status: i64,
+    /// Id of the authorization code issued, never the code itself
+    pub code_id: Option<String>,
+    /// Id of the token the code was exchanged for, once it has been
+    pub token_id: Option<String>,
+    pub client_ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Authorization journey the request was part of
+    pub flow_id: Option<String>,
+}
+
+/// What an authorize request issued, left in the response's extensions for
+/// the authorization request log
+#[derive(Debug, Clone)]
+pub struct IssuedAuthorization {
+    pub code_id: String,
+    pub user_id: String,
+    pub scope: String,
+}
+
+/// An authorize request the user denied on the consent page, left in the
+/// response's extensions like `IssuedAuthorization`
+#[derive(Debug, Clone)]
+pub struct DeniedAuthorization {
+    pub user_id: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed_log(chain: &AuditChain, count: i64) -> Vec<AdminAuditEntry> {
+        let mut prev_hash = String::new();
+        (1..=count)
+            .map(|seq| {
+                let mut entry = AdminAuditEntry {
+                    id: format!("entry-{}", seq),
+                    actor: "user:admin".to_string(),
+                    method: "POST".to_string(),
+                    path: "/admin/api/users".to_string(),
+                    params: "{}".to_string(),
+                    status: 201,
+                    client_ip: Some("203.0.113.7".to_string()),
+                    created_at: Utc::now(),
+                    seq: Some(seq),
+                    prev_hash: Some(prev_hash.clone()),
+                    hash: None,
+                };
+                let hash = chain.seal(&prev_hash, &entry);
+                entry.hash = Some(hash.clone());
+                prev_hash = hash;
+                entry
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_intact_chain_verifies() {
+        let chain = AuditChain::new("signing-key");
+        let log = sealed_log(&chain, 3);
+
+        let verification = chain.verify(&log);
+        assert!(verification.valid);
+        assert_eq!(verification.entries, 3);
+        assert_eq!(verification.last_seq, Some(3));
+
+        // Retention removes the oldest entries, which is not tampering
+        assert!(chain.verify(&log[1..]).valid);
+        assert!(!AuditChain::new("another-key").verify(&log).valid);
+    }
+
+    #[test]
+    fn test_tampering_breaks_the_chain() {
+        let chain = AuditChain::new("signing-key");
+
+        let mut log = sealed_log(&chain, 3);
+        log[1].actor = "user:someone-else".to_string();
+        let verification = chain.verify(&log);
+        assert!(!verification.valid);
+        assert_eq!(verification.broken_at, Some(2));
+
+        let mut log = sealed_log(&chain, 3);
+        log.remove(1);
+        let verification = chain.verify(&log);
+        assert!(!verification.valid);
+        assert_eq!(verification.broken_at, Some(3));
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 
+use crate::clock::Clock;
 use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
@@ -20,9 +21,19 @@ pub struct AuthorizationCode {
     pub code_challenge: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub code_challenge_method: Option<String>,
+    /// OpenID Connect nonce, echoed in the ID token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// When the user signed in, for the ID token's `auth_time`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<DateTime<Utc>>,
+    /// Authorization journey the code was issued in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_id: Option<String>,
 }
 
 impl AuthorizationCode {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         code: String,
         client_id: String,
@@ -31,8 +42,9 @@ impl AuthorizationCode {
         scope: String,
         code_challenge: Option<String>,
         code_challenge_method: Option<String>,
+        clock: &dyn Clock,
     ) -> Self {
-        let now = Utc::now();
+        let now = clock.now();
         let expires_at = now + Duration::minutes(10); // Authorization codes expire in 10 minutes
 
         Self {
@@ -47,15 +59,62 @@ impl AuthorizationCode {
             used: false,
             code_challenge,
             code_challenge_method,
+            nonce: None,
+            auth_time: None,
+            flow_id: None,
         }
     }
 
-    pub fn is_expired(&self) -> bool {
-        Utc::now() > self.expires_at
+    /// Carry what an OpenID Connect request needs in its ID token
+    pub fn with_id_token_context(
+        mut self,
+        nonce: Option<String>,
+        auth_time: Option<DateTime<Utc>>,
+    ) -> Self {
+        self.nonce = nonce;
+        self.auth_time = auth_time;
+        self
+    }
+
+    /// Issue the code as part of the authorization journey `flow_id`
+    pub fn with_flow_id(mut self, flow_id: Option<String>) -> Self {
+        self.flow_id = flow_id;
+        self
+    }
+
+    pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+        clock.now() > self.expires_at
+    }
+
+    pub fn is_valid(&self, clock: &dyn Clock) -> bool {
+        !self.used && !self.is_expired(clock)
+    }
+}
+
+/// Query parameter carrying the flow id from one authorize request to the
+/// next, through the login redirect and the consent form
+pub const FLOW_ID_PARAM: &str = "flow_id";
+
+/// Ties together one user's authorization journey: the authorize requests,
+/// the code issued, the tokens it was exchanged for and their refreshes,
+/// and the events about all of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowId(String);
+
+impl FlowId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4().to_string())
+    }
+
+    /// A flow id passed back in, if it is one this server could have issued
+    pub fn parse(value: &str) -> Option<Self> {
+        Uuid::parse_str(value)
+            .ok()
+            .map(|uuid| Self(uuid.hyphenated().to_string()))
     }
 
-    pub fn is_valid(&self) -> bool {
-        !self.used && !self.is_expired()
+    pub fn as_str(&self) -> &str {
+        &self.0
     }
 }
 
@@ -1,8 +1,10 @@
 #![allow(dead_code)]
 
+use crate::models::TokenFormat;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use std::collections::BTreeSet;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
@@ -18,6 +20,76 @@ pub struct Client {
     pub name: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// End user who registered the client through the developer portal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_id: Option<String>,
+    /// Organization whose members manage the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_org: Option<String>,
+    /// Web origins allowed to call the token and revocation endpoints from a
+    /// browser; JSON array stored as string
+    pub allowed_origins: String,
+    /// Redirect URIs with a wildcard host label, for clients that opted in
+    /// at registration; JSON array stored as string
+    pub redirect_uri_patterns: String,
+    /// When the secret stops being accepted; `None` never expires
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_expires_at: Option<DateTime<Utc>>,
+    /// How the client authenticates at the token endpoint; `none` for a
+    /// public client
+    pub token_endpoint_auth_method: String,
+    /// `jwt` or `opaque` access tokens for this client, over the grant's
+    /// and the server's; `None` leaves it to them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub access_token_format: Option<String>,
+}
+
+/// How a client authenticates at the token endpoint (RFC 7591 §2).
+/// Confidential clients may send their secret either way, whichever they
+/// registered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenEndpointAuthMethod {
+    /// The secret in HTTP Basic authentication
+    #[default]
+    ClientSecretBasic,
+    /// The secret in the request body
+    ClientSecretPost,
+    /// A public client, such as a single-page or native app: it holds no
+    /// secret and must prove possession of its codes with PKCE
+    None,
+}
+
+impl TokenEndpointAuthMethod {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "client_secret_basic" => Some(Self::ClientSecretBasic),
+            "client_secret_post" => Some(Self::ClientSecretPost),
+            "none" => Some(Self::None),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ClientSecretBasic => "client_secret_basic",
+            Self::ClientSecretPost => "client_secret_post",
+            Self::None => "none",
+        }
+    }
+
+    pub fn is_public(self) -> bool {
+        self == Self::None
+    }
+
+    /// Public clients cannot keep a secret, so they cannot use the grants
+    /// that only a secret authenticates
+    pub fn check_grant_types(self, grant_types: &[String]) -> Result<(), String> {
+        if self.is_public() && grant_types.iter().any(|g| g == "client_credentials") {
+            return Err("Public clients cannot use the client_credentials grant".to_string());
+        }
+        Ok(())
+    }
 }
 
 impl Client {
@@ -41,6 +113,93 @@ impl Client {
             name,
             created_at: now,
             updated_at: now,
+            owner_id: None,
+            owner_org: None,
+            allowed_origins: "[]".to_string(),
+            redirect_uri_patterns: "[]".to_string(),
+            secret_expires_at: None,
+            token_endpoint_auth_method: TokenEndpointAuthMethod::default().as_str().to_string(),
+            access_token_format: None,
+        }
+    }
+
+    pub fn with_owner(mut self, owner_id: impl Into<String>) -> Self {
+        self.owner_id = Some(owner_id.into());
+        self
+    }
+
+    pub fn with_org(mut self, org_id: impl Into<String>) -> Self {
+        self.owner_org = Some(org_id.into());
+        self
+    }
+
+    /// Origins must already be normalized with [`normalize_origin`]
+    pub fn with_allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.allowed_origins = serde_json::to_string(&origins).unwrap_or_else(|_| "[]".to_string());
+        self
+    }
+
+    /// Patterns must already be checked with [`validate_redirect_uri_pattern`]
+    pub fn with_redirect_uri_patterns(mut self, patterns: Vec<String>) -> Self {
+        self.redirect_uri_patterns =
+            serde_json::to_string(&patterns).unwrap_or_else(|_| "[]".to_string());
+        self
+    }
+
+    pub fn with_secret_expiry(mut self, expires_at: Option<DateTime<Utc>>) -> Self {
+        self.secret_expires_at = expires_at;
+        self
+    }
+
+    /// A public client holds no secret, so one issued to it is dropped
+    pub fn with_token_endpoint_auth_method(mut self, method: TokenEndpointAuthMethod) -> Self {
+        self.token_endpoint_auth_method = method.as_str().to_string();
+        if method.is_public() {
+            self.client_secret = String::new();
+            self.secret_expires_at = None;
+        }
+        self
+    }
+
+    pub fn with_access_token_format(mut self, format: Option<TokenFormat>) -> Self {
+        self.access_token_format = format.map(|format| format.as_str().to_string());
+        self
+    }
+
+    /// The access token format the client registered for, if any
+    pub fn token_format(&self) -> Option<TokenFormat> {
+        self.access_token_format
+            .as_deref()
+            .and_then(TokenFormat::parse)
+    }
+
+    pub fn auth_method(&self) -> TokenEndpointAuthMethod {
+        TokenEndpointAuthMethod::parse(&self.token_endpoint_auth_method).unwrap_or_default()
+    }
+
+    /// Whether the client is public rather than confidential: it cannot
+    /// keep a secret, and must use PKCE
+    pub fn is_public(&self) -> bool {
+        self.auth_method().is_public()
+    }
+
+    /// Whether the secret has expired by `now` and must be rotated
+    pub fn secret_expired(&self, now: DateTime<Utc>) -> bool {
+        self.secret_expires_at
+            .is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn is_owned_by(&self, owner_id: &str) -> bool {
+        self.owner_id.as_deref() == Some(owner_id)
+    }
+
+    /// Whether a portal user may manage the client. Organization clients
+    /// belong to the organization's current members, whoever created them;
+    /// other clients only to their owner.
+    pub fn is_manageable_by(&self, subject: &str, org_id: Option<&str>) -> bool {
+        match &self.owner_org {
+            Some(owner_org) => org_id == Some(owner_org.as_str()),
+            None => self.is_owned_by(subject),
         }
     }
 
@@ -48,6 +207,14 @@ impl Client {
         serde_json::from_str(&self.redirect_uris).unwrap_or_default()
     }
 
+    pub fn get_redirect_uri_patterns(&self) -> Vec<String> {
+        serde_json::from_str(&self.redirect_uri_patterns).unwrap_or_default()
+    }
+
+    pub fn get_allowed_origins(&self) -> Vec<String> {
+        serde_json::from_str(&self.allowed_origins).unwrap_or_default()
+    }
+
     pub fn get_grant_types(&self) -> Vec<String> {
         serde_json::from_str(&self.grant_types).unwrap_or_default()
     }
@@ -56,21 +223,399 @@ impl Client {
         self.get_grant_types().contains(&grant_type.to_string())
     }
 
+    /// Whether `redirect_uri` is registered exactly, or matches one of the
+    /// client's patterns
     pub fn validate_redirect_uri(&self, redirect_uri: &str) -> bool {
         self.get_redirect_uris().contains(&redirect_uri.to_string())
+            || self
+                .get_redirect_uri_patterns()
+                .iter()
+                .any(|pattern| redirect_uri_matches(pattern, redirect_uri))
+    }
+}
+
+/// Stands in for the wildcard when a pattern is parsed as a URI
+const WILDCARD_SAMPLE_LABEL: &str = "wildcard";
+
+/// Check a redirect URI pattern. The only wildcard allowed is the leftmost
+/// label of an https host, as in `https://*.preview.example.com/callback`;
+/// it matches exactly one DNS label, and the rest must match exactly. At
+/// least two fixed labels must follow it. Returns warnings about a valid
+/// pattern, or why it is refused.
+pub fn validate_redirect_uri_pattern(pattern: &str) -> Result<Vec<String>, String> {
+    let Some(rest) = pattern.strip_prefix("https://*.") else {
+        return Err(format!(
+            "{}: the wildcard must be the leftmost label of an https host, as in https://*.preview.example.com/callback",
+            pattern
+        ));
+    };
+    if rest.contains('*') {
+        return Err(format!("{}: only one wildcard is allowed", pattern));
+    }
+
+    let sample = format!("https://{}.{}", WILDCARD_SAMPLE_LABEL, rest);
+    let url = match oauth2::url::Url::parse(&sample) {
+        Ok(url) if is_valid_redirect_uri(&sample) => url,
+        _ => return Err(format!("{}: not a valid redirect URI", pattern)),
+    };
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(format!("{}: credentials are not allowed", pattern));
+    }
+    let Some(domain) = url.domain() else {
+        return Err(format!("{}: the host must be a domain name", pattern));
+    };
+    // Matching compares text, so the pattern must be written as it parses
+    if url.as_str() != sample {
+        return Err(format!(
+            "{}: write it as {}",
+            pattern,
+            url.as_str()
+                .replacen(&format!("{}.", WILDCARD_SAMPLE_LABEL), "*.", 1)
+        ));
+    }
+
+    let fixed: Vec<&str> = domain.split('.').skip(1).collect();
+    if fixed.len() < 2 {
+        return Err(format!(
+            "{}: the wildcard must sit under a domain of at least two labels",
+            pattern
+        ));
+    }
+    let mut warnings = Vec::new();
+    if fixed.len() == 2 {
+        warnings.push(format!(
+            "{} matches every subdomain of {}; a dedicated subdomain such as *.preview.{} is safer",
+            pattern,
+            fixed.join("."),
+            fixed.join(".")
+        ));
+    }
+    Ok(warnings)
+}
+
+/// Whether `uri` matches a pattern checked with
+/// [`validate_redirect_uri_pattern`]
+pub fn redirect_uri_matches(pattern: &str, uri: &str) -> bool {
+    let Some((prefix, suffix)) = pattern.split_once('*') else {
+        return pattern == uri;
+    };
+    uri.strip_prefix(prefix)
+        .and_then(|rest| rest.strip_suffix(suffix))
+        .is_some_and(is_dns_label)
+}
+
+/// A single lowercase DNS label: letters, digits and inner hyphens
+fn is_dns_label(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+/// Check that a redirect URI is absolute, uses http(s) and has no fragment
+/// (RFC 6749 §3.1.2)
+pub fn is_valid_redirect_uri(uri: &str) -> bool {
+    match oauth2::url::Url::parse(uri) {
+        Ok(url) => {
+            matches!(url.scheme(), "http" | "https")
+                && url.host_str().is_some()
+                && url.fragment().is_none()
+        }
+        Err(_) => false,
+    }
+}
+
+/// Reduce a web origin to its serialized form (`scheme://host[:port]`), or
+/// `None` if it is not an http(s) origin. A trailing slash is accepted, but
+/// not a path, query, fragment or credentials.
+pub fn normalize_origin(origin: &str) -> Option<String> {
+    let url = oauth2::url::Url::parse(origin).ok()?;
+    let bare = matches!(url.path(), "" | "/")
+        && url.query().is_none()
+        && url.fragment().is_none()
+        && url.username().is_empty()
+        && url.password().is_none();
+    (matches!(url.scheme(), "http" | "https") && url.host_str().is_some() && bare)
+        .then(|| url.origin().ascii_serialization())
+}
+
+/// Grant types a client can be registered for
+pub const SUPPORTED_GRANT_TYPES: [&str; 4] = [
+    "authorization_code",
+    "client_credentials",
+    "password",
+    "refresh_token",
+];
+
+/// The complete desired configuration of a client, for declarative
+/// provisioning. Lists and scopes compare as sets.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ClientState {
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub scope: String,
+}
+
+impl ClientState {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(invalid) = self
+            .redirect_uris
+            .iter()
+            .find(|uri| !is_valid_redirect_uri(uri))
+        {
+            return Err(format!("Invalid redirect URI: {}", invalid));
+        }
+        if self.grant_types.is_empty() {
+            return Err("grant_types must list at least one grant type".to_string());
+        }
+        if let Some(unsupported) = self
+            .grant_types
+            .iter()
+            .find(|grant| !SUPPORTED_GRANT_TYPES.contains(&grant.as_str()))
+        {
+            return Err(format!("Unsupported grant type: {}", unsupported));
+        }
+        if self.grant_types.iter().any(|g| g == "authorization_code")
+            && self.redirect_uris.is_empty()
+        {
+            return Err("authorization_code clients need at least one redirect URI".to_string());
+        }
+        if self.scope.split_whitespace().next().is_none() {
+            return Err("scope must name at least one scope".to_string());
+        }
+        Ok(())
+    }
+
+    /// Names of the fields where `client` differs from this state
+    pub fn drift(&self, client: &Client) -> Vec<String> {
+        fn set<'a>(items: impl IntoIterator<Item = &'a str>) -> BTreeSet<&'a str> {
+            items.into_iter().collect()
+        }
+
+        let redirect_uris = client.get_redirect_uris();
+        let grant_types = client.get_grant_types();
+        let mut drift = Vec::new();
+        if set(redirect_uris.iter().map(String::as_str))
+            != set(self.redirect_uris.iter().map(String::as_str))
+        {
+            drift.push("redirect_uris".to_string());
+        }
+        if set(grant_types.iter().map(String::as_str))
+            != set(self.grant_types.iter().map(String::as_str))
+        {
+            drift.push("grant_types".to_string());
+        }
+        if set(client.scope.split_whitespace()) != set(self.scope.split_whitespace()) {
+            drift.push("scope".to_string());
+        }
+        drift
     }
 }
 
+/// Outcome of provisioning a client by name
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClientProvisioning {
+    pub client_id: String,
+    pub client_name: String,
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub scope: String,
+    /// True when no client had the name and one was registered
+    pub created: bool,
+    /// Fields that differed from the desired state and were changed; empty
+    /// when the client already matched
+    pub drift: Vec<String>,
+    /// Only returned when the client was created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ClientProvisioning {
+    pub fn new(client: Client, created: bool, drift: Vec<String>) -> Self {
+        Self {
+            redirect_uris: client.get_redirect_uris(),
+            grant_types: client.get_grant_types(),
+            client_secret: created.then_some(client.client_secret),
+            client_id: client.client_id,
+            client_name: client.name,
+            scope: client.scope,
+            created,
+            drift,
+            updated_at: client.updated_at,
+        }
+    }
+}
+
+/// Version of the client export format this server reads and writes
+pub const CLIENT_EXPORT_VERSION: u32 = 1;
+
+/// Clients exported from one server for import into another. Secrets are
+/// never exported; the importing server issues new ones.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ClientExport {
+    pub version: u32,
+    pub clients: Vec<PortableClient>,
+}
+
+/// A client's registration without its secret or anything tied to the
+/// server it came from
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PortableClient {
+    pub client_id: String,
+    pub client_name: String,
+    pub redirect_uris: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub scope: String,
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub redirect_uri_patterns: Vec<String>,
+    #[serde(default)]
+    pub token_endpoint_auth_method: TokenEndpointAuthMethod,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token_format: Option<TokenFormat>,
+}
+
+impl PortableClient {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.client_id.trim().is_empty() {
+            return Err("client_id is required".to_string());
+        }
+        if self.client_name.trim().is_empty() {
+            return Err(format!("{}: client_name is required", self.client_id));
+        }
+        if let Some(invalid) = self
+            .allowed_origins
+            .iter()
+            .find(|origin| normalize_origin(origin).is_none())
+        {
+            return Err(format!("{}: invalid origin: {}", self.client_id, invalid));
+        }
+        if let Some(reason) = self
+            .redirect_uri_patterns
+            .iter()
+            .find_map(|pattern| validate_redirect_uri_pattern(pattern).err())
+        {
+            return Err(format!("{}: {}", self.client_id, reason));
+        }
+        self.token_endpoint_auth_method
+            .check_grant_types(&self.grant_types)
+            .map_err(|reason| format!("{}: {}", self.client_id, reason))?;
+        ClientState {
+            redirect_uris: self.redirect_uris.clone(),
+            grant_types: self.grant_types.clone(),
+            scope: self.scope.clone(),
+        }
+        .validate()
+        .map_err(|reason| format!("{}: {}", self.client_id, reason))
+    }
+
+    /// The client to store, with a newly issued secret
+    pub fn into_client(self, client_secret: String) -> Client {
+        let allowed_origins = self
+            .allowed_origins
+            .iter()
+            .filter_map(|origin| normalize_origin(origin))
+            .collect();
+        Client::new(
+            self.client_id,
+            client_secret,
+            self.redirect_uris,
+            self.grant_types,
+            self.scope,
+            self.client_name,
+        )
+        .with_allowed_origins(allowed_origins)
+        .with_redirect_uri_patterns(self.redirect_uri_patterns)
+        .with_token_endpoint_auth_method(self.token_endpoint_auth_method)
+        .with_access_token_format(self.access_token_format)
+    }
+}
+
+impl From<&Client> for PortableClient {
+    fn from(client: &Client) -> Self {
+        Self {
+            client_id: client.client_id.clone(),
+            client_name: client.name.clone(),
+            redirect_uris: client.get_redirect_uris(),
+            grant_types: client.get_grant_types(),
+            scope: client.scope.clone(),
+            allowed_origins: client.get_allowed_origins(),
+            redirect_uri_patterns: client.get_redirect_uri_patterns(),
+            token_endpoint_auth_method: client.auth_method(),
+            access_token_format: client.token_format(),
+        }
+    }
+}
+
+/// Outcome of importing clients. Clients whose id is already taken are
+/// skipped, so an interrupted import can be sent again.
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ClientImportReport {
+    /// New clients with the secrets issued to them, which are not
+    /// retrievable afterwards
+    pub imported: Vec<ClientCredentials>,
+    /// Ids of clients that already existed and were left alone
+    pub skipped: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ClientRegistration {
     pub client_name: String,
     pub redirect_uris: Vec<String>,
     pub grant_types: Vec<String>,
     pub scope: String,
+    /// Web origins of a browser-based client, e.g. `https://app.example.com`
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Opt-in redirect URIs with a wildcard host label, e.g.
+    /// `https://*.preview.example.com/callback` for preview environments
+    #[serde(default)]
+    pub redirect_uri_patterns: Vec<String>,
+    /// `none` registers a public client, which is issued no secret
+    #[serde(default)]
+    pub token_endpoint_auth_method: TokenEndpointAuthMethod,
+    /// `opaque` for reference tokens resolved through introspection, `jwt`
+    /// for self-contained ones; by default the server's setting decides
+    #[serde(default)]
+    pub access_token_format: Option<TokenFormat>,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ClientCredentials {
     pub client_id: String,
+    /// Absent for a public client
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub client_secret: String,
+    #[serde(default)]
+    pub token_endpoint_auth_method: TokenEndpointAuthMethod,
+    /// When the secret stops being accepted, if it expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_expires_at: Option<DateTime<Utc>>,
+    /// Things about the registration worth a second look, such as broad
+    /// redirect URI patterns
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// Set or clear when a client's secret expires, e.g. to force a rotation by
+/// a deadline
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SecretExpiryAssignment {
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A client whose secret expires soon, or already has
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct ExpiringSecret {
+    pub client_id: String,
+    pub name: String,
+    pub owner_id: Option<String>,
+    pub owner_org: Option<String>,
+    pub secret_expires_at: DateTime<Utc>,
+    /// Whether the secret is already refused
+    pub expired: bool,
 }
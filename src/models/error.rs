@@ -1,10 +1,195 @@
 #![allow(dead_code)]
 
-use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
+use actix_web::{
+    error::{InternalError, JsonPayloadError, ResponseError, UrlencodedError},
+    http::{header, StatusCode},
+    HttpRequest, HttpResponse,
+};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::sync::OnceLock;
 use utoipa::ToSchema;
 
+/// `Retry-After` sent with `temporarily_unavailable`, in seconds. A crashed
+/// actor is back well within it (see [`crate::actors::supervise`]).
+const UNAVAILABLE_RETRY_AFTER_SECS: u64 = 1;
+
+/// Where `error_uri` points; see [`set_error_reference_base`]
+static ERROR_REFERENCE_BASE: OnceLock<String> = OnceLock::new();
+
+/// Serve `error_uri` links under `base` (e.g. `https://auth.example.com/errors`)
+/// instead of the relative `/errors`. Only the first call has any effect.
+pub fn set_error_reference_base(base: &str) {
+    let _ = ERROR_REFERENCE_BASE.set(base.trim_end_matches('/').to_string());
+}
+
+/// Every error code the server returns. The string forms are stable and each
+/// has a reference page at `/errors/{code}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    InvalidRequest,
+    InvalidClient,
+    InvalidGrant,
+    UnauthorizedClient,
+    UnsupportedGrantType,
+    UnsupportedResponseType,
+    InvalidScope,
+    InvalidTarget,
+    AccessDenied,
+    ServerError,
+    TemporarilyUnavailable,
+    RateLimitExceeded,
+    SessionError,
+    InvalidConfiguration,
+    ProviderNotConfigured,
+    ProviderError,
+    TokenExchangeFailed,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 17] = [
+        ErrorCode::InvalidRequest,
+        ErrorCode::InvalidClient,
+        ErrorCode::InvalidGrant,
+        ErrorCode::UnauthorizedClient,
+        ErrorCode::UnsupportedGrantType,
+        ErrorCode::UnsupportedResponseType,
+        ErrorCode::InvalidScope,
+        ErrorCode::InvalidTarget,
+        ErrorCode::AccessDenied,
+        ErrorCode::ServerError,
+        ErrorCode::TemporarilyUnavailable,
+        ErrorCode::RateLimitExceeded,
+        ErrorCode::SessionError,
+        ErrorCode::InvalidConfiguration,
+        ErrorCode::ProviderNotConfigured,
+        ErrorCode::ProviderError,
+        ErrorCode::TokenExchangeFailed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::InvalidClient => "invalid_client",
+            ErrorCode::InvalidGrant => "invalid_grant",
+            ErrorCode::UnauthorizedClient => "unauthorized_client",
+            ErrorCode::UnsupportedGrantType => "unsupported_grant_type",
+            ErrorCode::UnsupportedResponseType => "unsupported_response_type",
+            ErrorCode::InvalidScope => "invalid_scope",
+            ErrorCode::InvalidTarget => "invalid_target",
+            ErrorCode::AccessDenied => "access_denied",
+            ErrorCode::ServerError => "server_error",
+            ErrorCode::TemporarilyUnavailable => "temporarily_unavailable",
+            ErrorCode::RateLimitExceeded => "rate_limit_exceeded",
+            ErrorCode::SessionError => "session_error",
+            ErrorCode::InvalidConfiguration => "invalid_configuration",
+            ErrorCode::ProviderNotConfigured => "provider_not_configured",
+            ErrorCode::ProviderError => "provider_error",
+            ErrorCode::TokenExchangeFailed => "token_exchange_failed",
+        }
+    }
+
+    pub fn parse(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.as_str() == code)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidClient => StatusCode::UNAUTHORIZED,
+            ErrorCode::AccessDenied => StatusCode::FORBIDDEN,
+            ErrorCode::ServerError | ErrorCode::SessionError | ErrorCode::InvalidConfiguration => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ErrorCode::ProviderError => StatusCode::BAD_GATEWAY,
+            ErrorCode::TemporarilyUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::RateLimitExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::ProviderNotConfigured => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// One-line summary, also the description when an internal failure is
+    /// hidden from the caller
+    pub fn summary(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "The request is missing a parameter or is malformed",
+            ErrorCode::InvalidClient => "Client authentication failed",
+            ErrorCode::InvalidGrant => {
+                "The authorization grant or refresh token is invalid, expired or revoked"
+            }
+            ErrorCode::UnauthorizedClient => "The client may not use this grant type",
+            ErrorCode::UnsupportedGrantType => "The grant type is not supported",
+            ErrorCode::UnsupportedResponseType => "The response type is not supported",
+            ErrorCode::InvalidScope => "The requested scope is invalid or not allowed",
+            ErrorCode::InvalidTarget => "The requested resource is unknown or not allowed",
+            ErrorCode::AccessDenied => "The request was denied",
+            ErrorCode::ServerError => "The server could not complete the request",
+            ErrorCode::TemporarilyUnavailable => "The server is temporarily unable to respond",
+            ErrorCode::RateLimitExceeded => "Too many requests",
+            ErrorCode::SessionError => "The browser session could not be read or updated",
+            ErrorCode::InvalidConfiguration => "The server is misconfigured",
+            ErrorCode::ProviderNotConfigured => "The sign-in provider is not configured",
+            ErrorCode::ProviderError => "The sign-in provider returned an error",
+            ErrorCode::TokenExchangeFailed => "The sign-in provider rejected the login",
+        }
+    }
+
+    /// What to do about it, for the reference page
+    pub fn remedy(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => {
+                "Check the request against the endpoint documentation; error_description names the offending parameter."
+            }
+            ErrorCode::InvalidClient => {
+                "Check the client_id and client secret or assertion. Secrets are shown once at registration and on rotation."
+            }
+            ErrorCode::InvalidGrant => {
+                "Start the authorization again. Codes are single-use and short-lived; refresh tokens are single-use and expire."
+            }
+            ErrorCode::UnauthorizedClient => {
+                "Register the grant type on the client, or use one it is registered for."
+            }
+            ErrorCode::UnsupportedGrantType => {
+                "Use a grant type listed in grant_types_supported in the discovery document."
+            }
+            ErrorCode::UnsupportedResponseType => {
+                "Send response_type=code and exchange the code at the token endpoint."
+            }
+            ErrorCode::InvalidScope => {
+                "Request only scopes the client is registered for and, with a resource, that the resource exposes."
+            }
+            ErrorCode::InvalidTarget => {
+                "Send a resource identifier registered on the server, and keep it unchanged when refreshing."
+            }
+            ErrorCode::AccessDenied => {
+                "The user or an authorization policy refused the request; error_description says which."
+            }
+            ErrorCode::ServerError => {
+                "Retry later. If it persists, contact the operator; the failure is logged on the server."
+            }
+            ErrorCode::TemporarilyUnavailable => "Retry with backoff.",
+            ErrorCode::RateLimitExceeded => {
+                "Wait for the Retry-After seconds, and slow down once X-RateLimit-Remaining appears on responses."
+            }
+            ErrorCode::SessionError => "Clear the site's cookies and sign in again.",
+            ErrorCode::InvalidConfiguration => "Contact the operator.",
+            ErrorCode::ProviderNotConfigured => "Choose another sign-in option.",
+            ErrorCode::ProviderError | ErrorCode::TokenExchangeFailed => {
+                "Sign in again. If it persists, the provider may be having problems."
+            }
+        }
+    }
+
+    /// Link to this code's reference page
+    pub fn uri(&self) -> String {
+        let base = ERROR_REFERENCE_BASE
+            .get()
+            .map(String::as_str)
+            .unwrap_or("/errors");
+        format!("{}/{}", base, self.as_str())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct OAuth2Error {
     pub error: String,
@@ -17,10 +202,24 @@ impl OAuth2Error {
         Self {
             error: error.to_string(),
             error_description: description.map(|s| s.to_string()),
-            error_uri: None,
+            error_uri: ErrorCode::parse(error).map(|code| code.uri()),
         }
     }
 
+    /// A failure whose detail is for our logs, not the caller: `detail` is
+    /// logged under a fresh correlation id, and the response carries only the
+    /// code's summary and that id
+    pub fn internal(code: ErrorCode, detail: impl fmt::Display) -> Self {
+        let error_id = uuid::Uuid::new_v4().simple().to_string();
+        tracing::error!(error_id = %error_id, "{}: {}", code.as_str(), detail);
+        let description = format!("{} (reference {})", code.summary(), error_id);
+        Self::new(code.as_str(), Some(&description))
+    }
+
+    pub fn code(&self) -> Option<ErrorCode> {
+        ErrorCode::parse(&self.error)
+    }
+
     pub fn invalid_request(description: &str) -> Self {
         Self::new("invalid_request", Some(description))
     }
@@ -41,6 +240,10 @@ impl OAuth2Error {
         Self::new("unsupported_grant_type", Some(description))
     }
 
+    pub fn unsupported_response_type(description: &str) -> Self {
+        Self::new("unsupported_response_type", Some(description))
+    }
+
     pub fn invalid_scope(description: &str) -> Self {
         Self::new("invalid_scope", Some(description))
     }
@@ -58,20 +261,124 @@ impl fmt::Display for OAuth2Error {
 
 impl ResponseError for OAuth2Error {
     fn status_code(&self) -> StatusCode {
-        match self.error.as_str() {
-            "invalid_client" => StatusCode::UNAUTHORIZED,
-            "access_denied" => StatusCode::FORBIDDEN,
-            _ => StatusCode::BAD_REQUEST,
-        }
+        self.code()
+            .map_or(StatusCode::BAD_REQUEST, |code| code.status())
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(self)
+        let mut response = HttpResponse::build(self.status_code());
+        if self.code() == Some(ErrorCode::TemporarilyUnavailable) {
+            response.insert_header((
+                header::RETRY_AFTER,
+                UNAVAILABLE_RETRY_AFTER_SECS.to_string(),
+            ));
+        }
+        response.json(self)
     }
 }
 
+/// An extractor failure answered as `invalid_request`, keeping the status
+/// actix would have sent (413 for an oversized body, 415 for a form sent
+/// as something else)
+fn extractor_error<E>(err: E, description: &str) -> actix_web::Error
+where
+    E: ResponseError + fmt::Debug + fmt::Display + 'static,
+{
+    let response =
+        HttpResponse::build(err.status_code()).json(OAuth2Error::invalid_request(description));
+    InternalError::from_response(err, response).into()
+}
+
+/// `JsonConfig` error handler: a malformed or mistyped JSON body gets an
+/// `invalid_request` naming the problem and the field serde stopped at
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let description = match &err {
+        JsonPayloadError::OverflowKnownLength { limit, .. }
+        | JsonPayloadError::Overflow { limit } => {
+            format!("Request body is larger than {} bytes", limit)
+        }
+        JsonPayloadError::ContentType => "Content-Type must be application/json".to_string(),
+        JsonPayloadError::Deserialize(e) if e.is_data() => format!("Invalid request body: {}", e),
+        JsonPayloadError::Deserialize(e) => format!("Request body is not valid JSON: {}", e),
+        other => other.to_string(),
+    };
+    extractor_error(err, &description)
+}
+
+/// `FormConfig` error handler, the form counterpart of [`json_error_handler`]
+pub fn form_error_handler(err: UrlencodedError, _req: &HttpRequest) -> actix_web::Error {
+    let description = match &err {
+        UrlencodedError::Overflow { limit, .. } => {
+            format!("Request body is larger than {} bytes", limit)
+        }
+        UrlencodedError::ContentType => {
+            "Content-Type must be application/x-www-form-urlencoded".to_string()
+        }
+        UrlencodedError::Parse(e) => format!("Invalid form body: {}", e),
+        other => other.to_string(),
+    };
+    extractor_error(err, &description)
+}
+
+/// Database errors name tables and columns, so they never reach the caller
 impl From<sqlx::Error> for OAuth2Error {
     fn from(err: sqlx::Error) -> Self {
-        Self::new("server_error", Some(&err.to_string()))
+        Self::internal(
+            ErrorCode::ServerError,
+            format_args!("database error: {}", err),
+        )
+    }
+}
+
+/// The actor behind a handler dropped the message: it stopped or panicked
+/// while handling it, and is being restarted
+impl From<actix::MailboxError> for OAuth2Error {
+    fn from(err: actix::MailboxError) -> Self {
+        Self::internal(ErrorCode::TemporarilyUnavailable, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalogue_codes_round_trip() {
+        for code in ErrorCode::ALL {
+            assert_eq!(ErrorCode::parse(code.as_str()), Some(code));
+            assert!(code.uri().ends_with(&format!("/{}", code.as_str())));
+        }
+        assert_eq!(ErrorCode::parse("made_up"), None);
+    }
+
+    #[test]
+    fn test_internal_errors_hide_detail() {
+        let err = OAuth2Error::internal(ErrorCode::ServerError, "no such table: secrets");
+        assert_eq!(err.error, "server_error");
+        let description = err.error_description.as_deref().unwrap();
+        assert!(description.starts_with(ErrorCode::ServerError.summary()));
+        assert!(description.contains("(reference "));
+        assert!(!description.contains("secrets"));
+        assert_eq!(err.error_uri.as_deref(), Some("/errors/server_error"));
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_database_errors_get_distinct_references() {
+        let first = OAuth2Error::from(sqlx::Error::Protocol("SELECT * FROM clients".into()));
+        let second = OAuth2Error::from(sqlx::Error::RowNotFound);
+        assert_eq!(first.error, "server_error");
+        assert!(!first.to_string().contains("SELECT"));
+        assert_ne!(first.error_description, second.error_description);
+    }
+
+    #[test]
+    fn test_dropped_messages_ask_to_retry() {
+        let response = OAuth2Error::from(actix::MailboxError::Closed).error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+
+        let response = OAuth2Error::invalid_request("bad").error_response();
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
     }
 }
@@ -0,0 +1,48 @@
+use crate::clock::Clock;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+/// A user's TOTP enrollment
+#[derive(Debug, Clone, FromRow)]
+pub struct TotpEnrollment {
+    pub subject: String,
+    /// Base32 shared secret
+    pub secret: String,
+    /// Time step of the last accepted code
+    pub last_used_step: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A browser that completed MFA and may skip the challenge until it expires
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct TrustedDevice {
+    pub id: String,
+    pub subject: String,
+    /// Shown on the device list, taken from the User-Agent
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked: bool,
+}
+
+impl TrustedDevice {
+    pub fn new(subject: String, label: String, lifetime: Duration, clock: &dyn Clock) -> Self {
+        let now = clock.now();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            subject,
+            label,
+            created_at: now,
+            expires_at: now + lifetime,
+            last_used_at: None,
+            revoked: false,
+        }
+    }
+
+    pub fn is_valid(&self, clock: &dyn Clock) -> bool {
+        !self.revoked && clock.now() <= self.expires_at
+    }
+}
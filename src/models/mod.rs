@@ -1,14 +1,32 @@
+pub mod audit;
 pub mod authorization;
 pub mod client;
 pub mod error;
+pub mod mfa;
+pub mod organization;
+pub mod registration;
+pub mod resource;
+pub mod retention;
 pub mod scope;
 pub mod social;
+pub mod stats;
+pub mod sync;
 pub mod token;
+pub mod usage;
 pub mod user;
 
+pub use audit::*;
 pub use authorization::*;
 pub use client::*;
 pub use error::*;
+pub use mfa::*;
+pub use organization::*;
+pub use registration::*;
+pub use resource::*;
+pub use retention::*;
 pub use social::*;
+pub use stats::*;
+pub use sync::*;
 pub use token::*;
+pub use usage::*;
 pub use user::*;
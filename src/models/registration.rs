@@ -0,0 +1,137 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+/// Lets its bearer register clients, a limited number of times and only
+/// within the scopes and grant types it allows. The token itself is only
+/// shown when issued.
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct RegistrationToken {
+    pub id: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub description: Option<String>,
+    /// Most a registration may ask for; `None` allows any scope
+    pub scope: Option<String>,
+    /// Grant types a registration may ask for; empty allows any. JSON array
+    /// stored as string
+    pub grant_types: String,
+    pub max_uses: i64,
+    pub use_count: i64,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl RegistrationToken {
+    pub fn get_grant_types(&self) -> Vec<String> {
+        serde_json::from_str(&self.grant_types).unwrap_or_default()
+    }
+
+    /// Whether it can still be used at `now`
+    pub fn is_usable(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none()
+            && self.use_count < self.max_uses
+            && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    /// Why a registration asking for `scope` and `grant_types` is not
+    /// allowed, if it is not
+    pub fn refuses(&self, scope: &str, grant_types: &[String]) -> Option<String> {
+        if let Some(allowed) = &self.scope {
+            let allowed: Vec<&str> = allowed.split_whitespace().collect();
+            if let Some(scope) = scope.split_whitespace().find(|s| !allowed.contains(s)) {
+                return Some(format!(
+                    "The registration token does not allow the {} scope",
+                    scope
+                ));
+            }
+        }
+        let allowed = self.get_grant_types();
+        if !allowed.is_empty() {
+            if let Some(grant) = grant_types.iter().find(|g| !allowed.contains(g)) {
+                return Some(format!(
+                    "The registration token does not allow the {} grant type",
+                    grant
+                ));
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RegistrationTokenRequest {
+    /// What the token is for, e.g. the team it was handed to
+    pub description: Option<String>,
+    /// Registrations it allows; defaults to one
+    pub max_uses: Option<u32>,
+    /// Most a registration may ask for; any scope when absent
+    pub scope: Option<String>,
+    /// Grant types a registration may ask for; any when absent or empty
+    #[serde(default)]
+    pub grant_types: Vec<String>,
+    /// Seconds until it stops working; never when absent
+    pub expires_in_secs: Option<u64>,
+}
+
+/// A newly issued registration token, the only time it is shown
+#[derive(Debug, Serialize, ToSchema)]
+pub struct IssuedRegistrationToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub registration_token: RegistrationToken,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(scope: Option<&str>, grant_types: &[&str]) -> RegistrationToken {
+        RegistrationToken {
+            id: "rt".to_string(),
+            token_hash: String::new(),
+            description: None,
+            scope: scope.map(String::from),
+            grant_types: serde_json::to_string(grant_types).unwrap(),
+            max_uses: 1,
+            use_count: 0,
+            expires_at: None,
+            revoked_at: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_registration_must_fit_the_token() {
+        let grants = |grants: &[&str]| grants.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+        let limited = token(Some("read write"), &["client_credentials"]);
+        assert!(limited
+            .refuses("read", &grants(&["client_credentials"]))
+            .is_none());
+        assert!(limited
+            .refuses("read admin", &grants(&["client_credentials"]))
+            .unwrap()
+            .contains("admin"));
+        assert!(limited
+            .refuses("read", &grants(&["authorization_code"]))
+            .unwrap()
+            .contains("authorization_code"));
+
+        let open = token(None, &[]);
+        assert!(open.refuses("admin", &grants(&["password"])).is_none());
+    }
+
+    #[test]
+    fn test_used_up_tokens_are_unusable() {
+        let now = Utc::now();
+        let mut token = token(None, &[]);
+        assert!(token.is_usable(now));
+        token.use_count = 1;
+        assert!(!token.is_usable(now));
+        token.use_count = 0;
+        token.expires_at = Some(now);
+        assert!(!token.is_usable(now));
+    }
+}
@@ -0,0 +1,110 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// How access tokens for a resource are represented
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenFormat {
+    /// Signed JWT the resource can verify itself
+    Jwt,
+    /// Random reference the resource must introspect
+    Opaque,
+}
+
+impl TokenFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenFormat::Jwt => "jwt",
+            TokenFormat::Opaque => "opaque",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "jwt" => Some(TokenFormat::Jwt),
+            "opaque" => Some(TokenFormat::Opaque),
+            _ => None,
+        }
+    }
+}
+
+/// A protected API that tokens can be issued for
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct Resource {
+    pub id: String,
+    /// Resource indicator clients send as `resource`; becomes the token audience
+    pub identifier: String,
+    pub name: String,
+    pub scopes: String, // JSON array stored as string
+    pub token_format: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Resource {
+    pub fn new(
+        identifier: String,
+        name: String,
+        scopes: Vec<String>,
+        token_format: TokenFormat,
+    ) -> Self {
+        let now = Utc::now();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            identifier,
+            name,
+            scopes: serde_json::to_string(&scopes).unwrap_or_else(|_| "[]".to_string()),
+            token_format: token_format.as_str().to_string(),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn get_scopes(&self) -> Vec<String> {
+        serde_json::from_str(&self.scopes).unwrap_or_default()
+    }
+
+    /// Whether every scope in the space-separated `scope` is exposed here
+    pub fn exposes_scopes(&self, scope: &str) -> bool {
+        let scopes = self.get_scopes();
+        scope
+            .split_whitespace()
+            .all(|requested| scopes.iter().any(|exposed| exposed == requested))
+    }
+
+    pub fn format(&self) -> TokenFormat {
+        TokenFormat::parse(&self.token_format).unwrap_or(TokenFormat::Jwt)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ResourceRegistration {
+    pub identifier: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// `jwt` (default) or `opaque`
+    pub token_format: Option<TokenFormat>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposes_scopes() {
+        let resource = Resource::new(
+            "https://api.example.com".to_string(),
+            "API".to_string(),
+            vec!["orders:read".to_string(), "orders:write".to_string()],
+            TokenFormat::Opaque,
+        );
+
+        assert!(resource.exposes_scopes("orders:read"));
+        assert!(resource.exposes_scopes("orders:read orders:write"));
+        assert!(!resource.exposes_scopes("orders:read admin"));
+        assert_eq!(resource.format(), TokenFormat::Opaque);
+    }
+}
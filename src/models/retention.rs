@@ -0,0 +1,28 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Records purged by one retention run, or on a dry run the records that
+/// would have been. Classes kept forever report 0.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    pub audit_entries: u64,
+    pub authorization_requests: u64,
+    pub tokens: u64,
+    pub authorization_codes: u64,
+    /// Events dropped from event backends that store them
+    pub events: u64,
+}
+
+impl RetentionReport {
+    /// Each data class with its count, as labelled in metrics
+    pub fn classes(&self) -> [(&'static str, u64); 5] {
+        [
+            ("audit_entries", self.audit_entries),
+            ("authorization_requests", self.authorization_requests),
+            ("tokens", self.tokens),
+            ("authorization_codes", self.authorization_codes),
+            ("events", self.events),
+        ]
+    }
+}
@@ -21,6 +21,12 @@ impl Scope {
     }
 }
 
+/// Scopes that administer the server, `admin` and `admin:*`. Only an admin
+/// may give them to a client.
+pub fn is_admin_scope(scope: &str) -> bool {
+    scope == "admin" || scope.starts_with("admin:")
+}
+
 pub fn validate_scopes(requested: &str, available: &str) -> bool {
     let requested_scopes: Vec<&str> = requested.split_whitespace().collect();
     let available_scopes: Vec<&str> = available.split_whitespace().collect();
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct SocialLoginConfig {
@@ -22,6 +25,103 @@ pub struct ProviderConfig {
     pub tenant_id: Option<String>, // For Azure/Microsoft
     #[serde(skip_serializing_if = "Option::is_none")]
     pub domain: Option<String>, // For Auth0/Okta
+    /// Endpoint overrides, e.g. for a regional deployment or a mock provider in tests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub userinfo_url: Option<String>,
+    /// Send users through the provider's logout when they sign out, so the
+    /// next login asks for their credentials again
+    #[serde(default)]
+    pub upstream_logout: bool,
+    /// Override of the provider's logout endpoint. GitHub has none, so it
+    /// needs one for `upstream_logout`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logout_url: Option<String>,
+    /// Where the provider sends users after its logout. Defaults to our login
+    /// page, which must be registered with the provider.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_logout_redirect_uri: Option<String>,
+    /// Let users connect the provider, keeping its tokens (encrypted) so the
+    /// server can call its APIs for them. Needs an upstream token key.
+    #[serde(default)]
+    pub store_tokens: bool,
+    /// Provider API scopes asked for, on top of the login ones, when a user
+    /// connects the provider
+    #[serde(default)]
+    pub api_scopes: Vec<String>,
+    /// Rules granting local roles, groups and claims from what the provider
+    /// says about a user, in the order they apply
+    #[serde(default)]
+    pub claim_mappings: Vec<ClaimMappingRule>,
+}
+
+/// Grants `target` to users whose upstream `claim` has `value`, or any
+/// value if it is `*`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ClaimMappingRule {
+    pub claim: String,
+    pub value: String,
+    pub target: MappingTarget,
+}
+
+/// What a claim mapping rule grants
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingTarget {
+    Role(String),
+    Group(String),
+    Claim { name: String, value: String },
+}
+
+impl ClaimMappingRule {
+    /// Rules in the form `claim:value=role:name`, `claim:value=group:name`
+    /// or `claim:value=claim:name=value`, separated by `;`. Malformed rules
+    /// are skipped.
+    pub fn parse_list(value: &str) -> Vec<Self> {
+        value.split(';').filter_map(Self::parse).collect()
+    }
+
+    fn parse(rule: &str) -> Option<Self> {
+        let (source, target) = rule.split_once('=')?;
+        let (claim, value) = source.split_once(':')?;
+        let (kind, granted) = target.split_once(':')?;
+        let (claim, value, granted) = (claim.trim(), value.trim(), granted.trim());
+        if claim.is_empty() || value.is_empty() || granted.is_empty() {
+            return None;
+        }
+        let target = match kind.trim() {
+            "role" => MappingTarget::Role(granted.to_string()),
+            "group" => MappingTarget::Group(granted.to_string()),
+            "claim" => {
+                let (name, value) = granted.split_once('=')?;
+                let name = name.trim();
+                if name.is_empty() {
+                    return None;
+                }
+                MappingTarget::Claim {
+                    name: name.to_string(),
+                    value: value.trim().to_string(),
+                }
+            }
+            _ => return None,
+        };
+        Some(Self {
+            claim: claim.to_string(),
+            value: value.to_string(),
+            target,
+        })
+    }
+}
+
+impl ProviderConfig {
+    /// Whether any mapping rule looks at `claim`, so it is worth asking the
+    /// provider for
+    pub fn maps_claim(&self, claim: &str) -> bool {
+        self.claim_mappings.iter().any(|rule| rule.claim == claim)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +131,105 @@ pub struct SocialUserInfo {
     pub email: String,
     pub name: Option<String>,
     pub picture: Option<String>,
+    /// What the provider said about the user that mapping rules can match,
+    /// by claim name. Only needed at login, so not kept in the session.
+    #[serde(skip)]
+    pub upstream_claims: BTreeMap<String, Vec<String>>,
+    /// Local roles, groups and claims granted by the provider's mapping rules
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub claims: BTreeMap<String, String>,
+}
+
+impl SocialUserInfo {
+    /// Stable identifier for the federated user, unique across providers
+    pub fn subject(&self) -> String {
+        format!("{}:{}", self.provider, self.provider_user_id)
+    }
+}
+
+/// A provider's tokens for a user who connected it. The token values are
+/// encrypted at rest (see `services::upstream_tokens`).
+#[derive(Debug, Clone, FromRow)]
+pub struct UpstreamToken {
+    pub subject: String,
+    pub provider: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Scopes the provider granted, space separated
+    pub scope: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Profile the provider last returned
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+    pub profile_synced_at: Option<DateTime<Utc>>,
+}
+
+impl UpstreamToken {
+    /// Profile attributes that differ from `profile`, by name
+    pub fn profile_changes(&self, profile: &SocialUserInfo) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.email.as_deref() != Some(profile.email.as_str()) {
+            changed.push("email");
+        }
+        if self.name != profile.name {
+            changed.push("name");
+        }
+        if self.picture != profile.picture {
+            changed.push("picture");
+        }
+        changed
+    }
+}
+
+/// A connected provider as shown to users and admins, without its tokens
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamConnection {
+    pub provider: String,
+    pub scope: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub has_refresh_token: bool,
+    pub connected_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// Profile the provider last returned
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+    pub profile_synced_at: Option<DateTime<Utc>>,
+}
+
+/// Outcome of one profile sync run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProfileSyncReport {
+    /// Connected profiles that were due and re-read
+    pub checked: u64,
+    /// Profiles that had changed
+    pub updated: u64,
+    /// Profiles the provider could not be asked for; retried next interval
+    pub failed: u64,
+}
+
+impl From<UpstreamToken> for UpstreamConnection {
+    fn from(token: UpstreamToken) -> Self {
+        Self {
+            provider: token.provider,
+            scope: token.scope,
+            expires_at: token.expires_at,
+            has_refresh_token: token.refresh_token.is_some(),
+            connected_at: token.created_at,
+            updated_at: token.updated_at,
+            email: token.email,
+            name: token.name,
+            picture: token.picture,
+            profile_synced_at: token.profile_synced_at,
+        }
+    }
 }
 
 impl SocialLoginConfig {
@@ -45,6 +244,24 @@ impl SocialLoginConfig {
         }
     }
 
+    /// The configuration of a provider, by the name users record it under
+    pub fn provider(&self, provider: &str) -> Option<&ProviderConfig> {
+        match provider {
+            "google" => self.google.as_ref(),
+            "microsoft" => self.microsoft.as_ref(),
+            "github" => self.github.as_ref(),
+            "azure" => self.azure.as_ref(),
+            "okta" => self.okta.as_ref(),
+            "auth0" => self.auth0.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Whether a provider has credentials configured
+    pub fn is_configured(&self, provider: &str) -> bool {
+        self.provider(provider).is_some()
+    }
+
     fn provider_from_env(prefix: &str) -> Option<ProviderConfig> {
         let client_id = std::env::var(format!("OAUTH2_{}_CLIENT_ID", prefix)).ok()?;
         let client_secret = std::env::var(format!("OAUTH2_{}_CLIENT_SECRET", prefix)).ok()?;
@@ -62,6 +279,31 @@ impl SocialLoginConfig {
             redirect_uri,
             tenant_id: std::env::var(format!("OAUTH2_{}_TENANT_ID", prefix)).ok(),
             domain: std::env::var(format!("OAUTH2_{}_DOMAIN", prefix)).ok(),
+            auth_url: std::env::var(format!("OAUTH2_{}_AUTH_URL", prefix)).ok(),
+            token_url: std::env::var(format!("OAUTH2_{}_TOKEN_URL", prefix)).ok(),
+            userinfo_url: std::env::var(format!("OAUTH2_{}_USERINFO_URL", prefix)).ok(),
+            upstream_logout: std::env::var(format!("OAUTH2_{}_UPSTREAM_LOGOUT", prefix))
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            logout_url: std::env::var(format!("OAUTH2_{}_LOGOUT_URL", prefix)).ok(),
+            post_logout_redirect_uri: std::env::var(format!(
+                "OAUTH2_{}_POST_LOGOUT_REDIRECT_URI",
+                prefix
+            ))
+            .ok(),
+            store_tokens: std::env::var(format!("OAUTH2_{}_STORE_TOKENS", prefix))
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false),
+            api_scopes: std::env::var(format!("OAUTH2_{}_API_SCOPES", prefix))
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            claim_mappings: std::env::var(format!("OAUTH2_{}_CLAIM_MAPPINGS", prefix))
+                .map(|v| ClaimMappingRule::parse_list(&v))
+                .unwrap_or_default(),
         })
     }
 }
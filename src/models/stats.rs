@@ -0,0 +1,71 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::collections::HashMap;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsGranularity {
+    Hour,
+    Day,
+}
+
+impl StatsGranularity {
+    pub const ALL: [StatsGranularity; 2] = [StatsGranularity::Hour, StatsGranularity::Day];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatsGranularity::Hour => "hour",
+            StatsGranularity::Day => "day",
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        match self {
+            StatsGranularity::Hour => Duration::hours(1),
+            StatsGranularity::Day => Duration::days(1),
+        }
+    }
+
+    /// Start of the bucket `at` falls in, in UTC
+    pub fn bucket_start(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        let secs = self.duration().num_seconds();
+        let timestamp = at.timestamp();
+        DateTime::from_timestamp(timestamp - timestamp.rem_euclid(secs), 0).unwrap_or(at)
+    }
+}
+
+/// Counts keyed by bucket start, metric and dimension
+pub type StatsCounts = HashMap<(DateTime<Utc>, String, String), i64>;
+
+/// How many times `metric` happened in one hour or day
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct StatsBucket {
+    pub bucket_start: DateTime<Utc>,
+    /// `tokens_issued`, `auth_failures` or `logins`
+    pub metric: String,
+    /// Grant type, failure kind or provider; empty when not broken down
+    pub dimension: String,
+    pub count: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_start() {
+        let at = DateTime::parse_from_rfc3339("2024-03-05T17:42:09Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            StatsGranularity::Hour.bucket_start(at).to_rfc3339(),
+            "2024-03-05T17:00:00+00:00"
+        );
+        assert_eq!(
+            StatsGranularity::Day.bucket_start(at).to_rfc3339(),
+            "2024-03-05T00:00:00+00:00"
+        );
+    }
+}
@@ -0,0 +1,432 @@
+use crate::models::{Client, ClientState, Resource, TokenFormat, User};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use utoipa::ToSchema;
+
+/// The complete desired set of clients, APIs and users, as an operator or
+/// GitOps pipeline declares it. Objects are matched by name: clients by
+/// `name`, APIs by `identifier`, users by `username`.
+#[derive(Debug, Clone, Default, Deserialize, ToSchema)]
+pub struct SyncManifest {
+    #[serde(default)]
+    pub clients: Vec<DeclaredClient>,
+    /// Protected APIs and the scopes they expose
+    #[serde(default)]
+    pub resources: Vec<DeclaredResource>,
+    #[serde(default)]
+    pub users: Vec<DeclaredUser>,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct DeclaredClient {
+    pub name: String,
+    #[serde(flatten)]
+    pub state: ClientState,
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct DeclaredResource {
+    pub identifier: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    #[serde(default = "default_token_format")]
+    pub token_format: TokenFormat,
+}
+
+fn default_token_format() -> TokenFormat {
+    TokenFormat::Jwt
+}
+
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct DeclaredUser {
+    pub username: String,
+    pub email: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Argon2 PHC string; left alone when omitted, and new users without one
+    /// cannot sign in with a password
+    pub password_hash: Option<String>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Password hash stored for declared users without one. It is not a PHC
+/// string, so no password verifies against it.
+pub const UNUSABLE_PASSWORD_HASH: &str = "!";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncKind {
+    Client,
+    Resource,
+    User,
+}
+
+impl SyncKind {
+    /// Table and key column of the objects of this kind
+    pub fn table(&self) -> (&'static str, &'static str) {
+        match self {
+            SyncKind::Client => ("clients", "client_id"),
+            SyncKind::Resource => ("resources", "id"),
+            SyncKind::User => ("users", "id"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncAction {
+    Create,
+    /// A matching object that was not created by sync is taken over, and
+    /// from then on is deleted when no longer declared
+    Adopt,
+    Update,
+    Delete,
+}
+
+/// One step of converging on a manifest
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SyncChange {
+    pub kind: SyncKind,
+    pub action: SyncAction,
+    /// The client name, API identifier or username
+    pub name: String,
+    /// The client_id, or the API's or user's id; not known for creations
+    /// until they are applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Fields that differ from the manifest
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+    /// Only returned for clients created by this request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+/// Outcome of a sync request
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SyncReport {
+    /// True when nothing was changed
+    pub dry_run: bool,
+    pub changes: Vec<SyncChange>,
+}
+
+/// Why a manifest cannot be applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncError {
+    Invalid(String),
+    /// Several existing objects match one declared name
+    Ambiguous(String),
+}
+
+/// The database state a manifest is compared against
+#[derive(Debug, Default)]
+pub struct SyncSnapshot {
+    pub clients: Vec<Client>,
+    pub resources: Vec<Resource>,
+    pub users: Vec<User>,
+    /// Keys (see [`SyncKind::table`]) of objects created or adopted by sync
+    pub managed: HashSet<(SyncKind, String)>,
+}
+
+fn set<'a>(items: impl IntoIterator<Item = &'a str>) -> BTreeSet<&'a str> {
+    items.into_iter().collect()
+}
+
+impl DeclaredResource {
+    fn drift(&self, resource: &Resource) -> Vec<String> {
+        let scopes = resource.get_scopes();
+        let mut drift = Vec::new();
+        if resource.name != self.name {
+            drift.push("name".to_string());
+        }
+        if set(scopes.iter().map(String::as_str)) != set(self.scopes.iter().map(String::as_str)) {
+            drift.push("scopes".to_string());
+        }
+        if resource.token_format != self.token_format.as_str() {
+            drift.push("token_format".to_string());
+        }
+        drift
+    }
+}
+
+impl DeclaredUser {
+    fn drift(&self, user: &User) -> Vec<String> {
+        let mut drift = Vec::new();
+        if user.email != self.email {
+            drift.push("email".to_string());
+        }
+        if user.enabled != self.enabled {
+            drift.push("enabled".to_string());
+        }
+        if let Some(hash) = &self.password_hash {
+            if &user.password_hash != hash {
+                drift.push("password_hash".to_string());
+            }
+        }
+        drift
+    }
+}
+
+impl SyncManifest {
+    pub fn validate(&self) -> Result<(), SyncError> {
+        let invalid = |reason: String| Err(SyncError::Invalid(reason));
+        fn duplicate<'a>(names: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+            let mut seen = HashSet::new();
+            names.into_iter().find(|name| !seen.insert(*name))
+        }
+
+        for client in &self.clients {
+            if client.name.trim().is_empty() {
+                return invalid("client name is required".to_string());
+            }
+            client.state.validate().map_err(|reason| {
+                SyncError::Invalid(format!("client '{}': {}", client.name, reason))
+            })?;
+        }
+        for resource in &self.resources {
+            if resource.identifier.trim().is_empty() {
+                return invalid("resource identifier is required".to_string());
+            }
+            if resource.scopes.is_empty() || resource.scopes.iter().any(|s| s.trim().is_empty()) {
+                return invalid(format!(
+                    "resource '{}': scopes must list at least one non-empty scope",
+                    resource.identifier
+                ));
+            }
+        }
+        for user in &self.users {
+            if user.username.trim().is_empty() || user.email.trim().is_empty() {
+                return invalid("users need a username and email".to_string());
+            }
+        }
+
+        if let Some(name) = duplicate(self.clients.iter().map(|c| c.name.as_str())) {
+            return invalid(format!("client '{}' is declared more than once", name));
+        }
+        if let Some(name) = duplicate(self.resources.iter().map(|r| r.identifier.as_str())) {
+            return invalid(format!("resource '{}' is declared more than once", name));
+        }
+        if let Some(name) = duplicate(self.users.iter().map(|u| u.username.as_str())) {
+            return invalid(format!("user '{}' is declared more than once", name));
+        }
+        Ok(())
+    }
+
+    /// The changes that make `snapshot` match this manifest: creations,
+    /// adoptions and updates in manifest order, then deletions of managed
+    /// objects that are no longer declared. Objects sync never managed are
+    /// left alone unless declared.
+    pub fn plan(&self, snapshot: &SyncSnapshot) -> Result<Vec<SyncChange>, SyncError> {
+        self.validate()?;
+        let mut changes = Vec::new();
+        changes.extend(reconcile(
+            SyncKind::Resource,
+            &self.resources,
+            &snapshot.resources,
+            &snapshot.managed,
+            |declared| &declared.identifier,
+            |resource| (&resource.identifier, &resource.id),
+            DeclaredResource::drift,
+        )?);
+        changes.extend(reconcile(
+            SyncKind::Client,
+            &self.clients,
+            &snapshot.clients,
+            &snapshot.managed,
+            |declared| &declared.name,
+            |client| (&client.name, &client.client_id),
+            |declared, client| declared.state.drift(client),
+        )?);
+        changes.extend(reconcile(
+            SyncKind::User,
+            &self.users,
+            &snapshot.users,
+            &snapshot.managed,
+            |declared| &declared.username,
+            |user| (&user.username, &user.id),
+            DeclaredUser::drift,
+        )?);
+        changes.sort_by_key(|change| change.action == SyncAction::Delete);
+        Ok(changes)
+    }
+}
+
+fn reconcile<D, E>(
+    kind: SyncKind,
+    declared: &[D],
+    existing: &[E],
+    managed: &HashSet<(SyncKind, String)>,
+    declared_name: impl Fn(&D) -> &String,
+    existing_key: impl Fn(&E) -> (&String, &String),
+    drift: impl Fn(&D, &E) -> Vec<String>,
+) -> Result<Vec<SyncChange>, SyncError> {
+    let is_managed = |id: &String| managed.contains(&(kind, id.clone()));
+    let change = |action, name: &String, id: Option<&String>, fields| SyncChange {
+        kind,
+        action,
+        name: name.clone(),
+        id: id.cloned(),
+        fields,
+        client_secret: None,
+    };
+
+    let mut changes = Vec::new();
+    for wanted in declared {
+        let name = declared_name(wanted);
+        let matches: Vec<&E> = existing
+            .iter()
+            .filter(|object| existing_key(object).0 == name)
+            .collect();
+        match matches.as_slice() {
+            [] => changes.push(change(SyncAction::Create, name, None, Vec::new())),
+            [object] => {
+                let id = existing_key(object).1;
+                let fields = drift(wanted, object);
+                if !is_managed(id) {
+                    changes.push(change(SyncAction::Adopt, name, Some(id), fields));
+                } else if !fields.is_empty() {
+                    changes.push(change(SyncAction::Update, name, Some(id), fields));
+                }
+            }
+            _ => {
+                return Err(SyncError::Ambiguous(format!(
+                    "{} existing {:?} objects are named '{}'",
+                    matches.len(),
+                    kind,
+                    name
+                )))
+            }
+        }
+    }
+
+    for object in existing {
+        let (name, id) = existing_key(object);
+        if is_managed(id) && !declared.iter().any(|wanted| declared_name(wanted) == name) {
+            changes.push(change(SyncAction::Delete, name, Some(id), Vec::new()));
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> SyncManifest {
+        serde_json::from_value(serde_json::json!({
+            "clients": [{
+                "name": "reports",
+                "redirect_uris": ["https://reports.example.com/callback"],
+                "grant_types": ["authorization_code"],
+                "scope": "read",
+            }],
+            "resources": [{
+                "identifier": "https://api.example.com",
+                "name": "API",
+                "scopes": ["read", "write"],
+            }],
+            "users": [{ "username": "alice", "email": "alice@example.com" }],
+        }))
+        .unwrap()
+    }
+
+    fn client(name: &str) -> Client {
+        Client::new(
+            format!("{}_id", name),
+            "secret".to_string(),
+            vec!["https://reports.example.com/callback".to_string()],
+            vec!["authorization_code".to_string()],
+            "read".to_string(),
+            name.to_string(),
+        )
+    }
+
+    fn actions(changes: &[SyncChange]) -> Vec<(SyncKind, SyncAction, &str, Vec<&str>)> {
+        changes
+            .iter()
+            .map(|c| {
+                let fields = c.fields.iter().map(String::as_str).collect();
+                (c.kind, c.action, c.name.as_str(), fields)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_converges_on_manifest() {
+        let manifest = manifest();
+        let plan = manifest.plan(&SyncSnapshot::default()).unwrap();
+        assert_eq!(
+            actions(&plan),
+            vec![
+                (
+                    SyncKind::Resource,
+                    SyncAction::Create,
+                    "https://api.example.com",
+                    vec![]
+                ),
+                (SyncKind::Client, SyncAction::Create, "reports", vec![]),
+                (SyncKind::User, SyncAction::Create, "alice", vec![]),
+            ]
+        );
+
+        // Unmanaged matches are adopted; managed ones only change on drift;
+        // managed objects no longer declared are deleted; others are left
+        let mut alice = User::new("alice".into(), "!".into(), "old@example.com".into());
+        alice.enabled = false;
+        let resource = Resource::new(
+            "https://api.example.com".into(),
+            "API".into(),
+            vec!["write".into(), "read".into()],
+            TokenFormat::Jwt,
+        );
+        let snapshot = SyncSnapshot {
+            managed: HashSet::from([
+                (SyncKind::Client, "old_id".to_string()),
+                (SyncKind::User, alice.id.clone()),
+                (SyncKind::Resource, resource.id.clone()),
+            ]),
+            clients: vec![client("reports"), client("old"), client("manual")],
+            resources: vec![resource],
+            users: vec![alice],
+        };
+        assert_eq!(
+            actions(&manifest.plan(&snapshot).unwrap()),
+            vec![
+                (SyncKind::Client, SyncAction::Adopt, "reports", vec![]),
+                (
+                    SyncKind::User,
+                    SyncAction::Update,
+                    "alice",
+                    vec!["email", "enabled"]
+                ),
+                (SyncKind::Client, SyncAction::Delete, "old", vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_rejects_invalid_manifests() {
+        let mut twice = manifest();
+        twice.users.push(twice.users[0].clone());
+        assert!(matches!(
+            twice.plan(&SyncSnapshot::default()),
+            Err(SyncError::Invalid(_))
+        ));
+
+        let mut bad_client = manifest();
+        bad_client.clients[0].state.grant_types = vec!["implicit".to_string()];
+        assert!(matches!(bad_client.validate(), Err(SyncError::Invalid(_))));
+
+        let snapshot = SyncSnapshot {
+            clients: vec![client("reports"), client("reports")],
+            ..SyncSnapshot::default()
+        };
+        assert!(matches!(
+            manifest().plan(&snapshot),
+            Err(SyncError::Ambiguous(_))
+        ));
+    }
+}
@@ -1,57 +1,197 @@
 #![allow(dead_code)]
 
+use crate::clock::Clock;
+use crate::keys::SigningKeys;
+use crate::models::{AuthorizationCode, TokenFormat};
 use chrono::{DateTime, Duration, Utc};
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::errors::ErrorKind;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::FromRow;
+use std::borrow::Cow;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
+/// JWT `typ` of access tokens (RFC 9068)
+pub const ACCESS_TOKEN_TYPE: &str = "at+jwt";
+
+/// `iss` of tokens until `OAUTH2_JWT_ISSUER` names the server's public URL;
+/// it matches the issuer in the discovery document
+pub const DEFAULT_ISSUER: &str = "http://localhost:8080";
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Claims {
-    pub sub: String,   // Subject (user ID)
-    pub iss: String,   // Issuer
-    pub aud: String,   // Audience (client ID)
-    pub exp: i64,      // Expiration time
-    pub iat: i64,      // Issued at
-    pub scope: String, // Scopes
-    pub jti: String,   // JWT ID
+    pub sub: String, // Subject (user ID)
+    pub iss: String, // Issuer
+    pub aud: String, // Audience (resource server, or the client without one)
+    pub exp: i64,    // Expiration time
+    pub iat: i64,    // Issued at
+    /// Space-separated scopes; left out when none were granted
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub scope: String,
+    pub jti: String, // JWT ID
+    /// Not valid before this time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub client_id: Option<String>,
+    /// Who is acting as the subject, on impersonation tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<ActClaim>,
+}
+
+/// What a sender-constrained token is bound to (RFC 7800 `cnf` claim)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Confirmation {
+    /// JWK SHA-256 thumbprint of the DPoP key (RFC 9449)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jkt: Option<String>,
+    /// SHA-256 thumbprint of the client certificate (RFC 8705)
+    #[serde(rename = "x5t#S256", skip_serializing_if = "Option::is_none")]
+    pub x5t_s256: Option<String>,
+}
+
+/// The party acting on the subject's behalf (RFC 8693 `act` claim)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ActClaim {
+    pub sub: String,
 }
 
 impl Claims {
-    pub fn new(user_id: String, client_id: String, scope: String, duration_seconds: i64) -> Self {
-        let now = Utc::now();
+    pub fn new(
+        user_id: String,
+        client_id: String,
+        scope: String,
+        duration_seconds: i64,
+        clock: &dyn Clock,
+    ) -> Self {
+        let now = clock.now();
         let exp = now + Duration::seconds(duration_seconds);
 
         Self {
             sub: user_id,
-            iss: "rust_oauth2_server".to_string(),
+            iss: DEFAULT_ISSUER.to_string(),
             aud: client_id.clone(),
             exp: exp.timestamp(),
             iat: now.timestamp(),
             scope,
             jti: Uuid::new_v4().to_string(),
+            nbf: None,
             client_id: Some(client_id),
+            act: None,
+        }
+    }
+
+    /// Mark the token as held by `actor` acting as the subject
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.act = Some(ActClaim { sub: actor.into() });
+        self
+    }
+
+    /// Issue for a resource server instead of the client
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.aud = audience.into();
+        self
+    }
+
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.iss = issuer.into();
+        self
+    }
+
+    pub fn encode(&self, keys: &SigningKeys) -> Result<String, jsonwebtoken::errors::Error> {
+        keys.encode(self, None)
+    }
+
+    /// Encode as an access token, typed `at+jwt` so it can be told apart
+    /// from the refresh tokens carrying the same claims
+    pub fn encode_access_token(
+        &self,
+        keys: &SigningKeys,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        keys.encode(self, Some(ACCESS_TOKEN_TYPE))
+    }
+
+    /// Whether `token` is typed as an access token. The signature is not
+    /// checked.
+    pub fn is_access_token(token: &str) -> bool {
+        jsonwebtoken::decode_header(token)
+            .is_ok_and(|header| header.typ.as_deref() == Some(ACCESS_TOKEN_TYPE))
+    }
+
+    /// Verify `token` and check its `exp`, `nbf` and `iat` against `clock`,
+    /// allowing `leeway_secs` of drift from the clock that issued it
+    pub fn decode(
+        token: &str,
+        keys: &SigningKeys,
+        clock: &dyn Clock,
+        leeway_secs: u64,
+    ) -> Result<Self, jsonwebtoken::errors::Error> {
+        // `aud` is the issuing client; callers compare it against the client
+        // they expect, so the library's audience check is left off. Times
+        // are checked below against our clock rather than the system's.
+        let mut validation = keys.validation();
+        validation.validate_aud = false;
+        validation.validate_exp = false;
+
+        let claims =
+            jsonwebtoken::decode::<Claims>(token, keys.decoding_key(), &validation)?.claims;
+
+        let now = clock.now().timestamp();
+        let leeway = i64::try_from(leeway_secs).unwrap_or(i64::MAX);
+        if claims.exp.saturating_add(leeway) < now {
+            return Err(ErrorKind::ExpiredSignature.into());
+        }
+        // Not yet usable, or issued in the future
+        let not_before = claims.nbf.unwrap_or(claims.iat).max(claims.iat);
+        if not_before.saturating_sub(leeway) > now {
+            return Err(ErrorKind::ImmatureSignature.into());
+        }
+        Ok(claims)
+    }
+}
+
+/// Claims of an OpenID Connect ID token, which tells the client who signed
+/// in. It is for the client alone: it carries no `scope` or `jti`, so it
+/// never decodes as an access token.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    /// The client the ID token was issued to
+    pub aud: String,
+    pub exp: i64,
+    pub iat: i64,
+    /// When the user signed in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<i64>,
+    /// The nonce from the authorization request, for the client to match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+impl IdTokenClaims {
+    /// Claims for the user who approved `code`, valid for `duration_seconds`
+    pub fn new(code: &AuthorizationCode, duration_seconds: i64, clock: &dyn Clock) -> Self {
+        let now = clock.now();
+        Self {
+            iss: DEFAULT_ISSUER.to_string(),
+            sub: code.user_id.clone(),
+            aud: code.client_id.clone(),
+            exp: (now + Duration::seconds(duration_seconds)).timestamp(),
+            iat: now.timestamp(),
+            auth_time: code.auth_time.map(|auth_time| auth_time.timestamp()),
+            nonce: code.nonce.clone(),
         }
     }
 
-    pub fn encode(&self, secret: &str) -> Result<String, jsonwebtoken::errors::Error> {
-        jsonwebtoken::encode(
-            &Header::default(),
-            self,
-            &EncodingKey::from_secret(secret.as_ref()),
-        )
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.iss = issuer.into();
+        self
     }
 
-    pub fn decode(token: &str, secret: &str) -> Result<Self, jsonwebtoken::errors::Error> {
-        let token_data = jsonwebtoken::decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(secret.as_ref()),
-            &Validation::default(),
-        )?;
-        Ok(token_data.claims)
+    pub fn encode(&self, keys: &SigningKeys) -> Result<String, jsonwebtoken::errors::Error> {
+        keys.encode(self, None)
     }
 }
 
@@ -68,6 +208,44 @@ pub struct Token {
     pub created_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
     pub revoked: bool,
+    /// When the refresh token stops being accepted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_expires_at: Option<DateTime<Utc>>,
+    /// Limit carried across rotations from the original grant
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_max_expires_at: Option<DateTime<Utc>>,
+    /// Last time the access or refresh token was used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// Resource the token was issued for; `None` means the client itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    /// Admin acting as the user, on impersonation tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<String>,
+    /// RFC 9396 authorization details granted, as a JSON array
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorization_details: Option<String>,
+    /// Thumbprint of the DPoP key the token is bound to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf_jkt: Option<String>,
+    /// SHA-256 thumbprint of the client certificate the token is bound to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf_x5t_s256: Option<String>,
+    /// Scope the refresh token may ask for, when a narrowing refresh left
+    /// it wider than the access token's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_scope: Option<String>,
+    /// `jti` of a JWT access token, which revoking it adds to the denylist
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// Authorization journey the token came out of, kept across refreshes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_id: Option<String>,
+    /// Authorization code the token was exchanged for, kept across
+    /// refreshes so a replay of the code can revoke them all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authorization_code_id: Option<String>,
 }
 
 impl Token {
@@ -78,8 +256,9 @@ impl Token {
         user_id: String,
         scope: String,
         expires_in: i64,
+        clock: &dyn Clock,
     ) -> Self {
-        let now = Utc::now();
+        let now = clock.now();
         let expires_at = now + Duration::seconds(expires_in);
 
         Self {
@@ -94,15 +273,165 @@ impl Token {
             created_at: now,
             expires_at,
             revoked: false,
+            refresh_expires_at: None,
+            refresh_max_expires_at: None,
+            last_used_at: None,
+            audience: None,
+            impersonator: None,
+            authorization_details: None,
+            cnf_jkt: None,
+            cnf_x5t_s256: None,
+            refresh_scope: None,
+            jti: None,
+            flow_id: None,
+            authorization_code_id: None,
+        }
+    }
+
+    /// A JWT access token as its claims describe it, for accepting it
+    /// without looking it up. Only what the claims carry is filled in.
+    pub fn from_claims(access_token: String, claims: &Claims) -> Self {
+        let client_id = claims
+            .client_id
+            .clone()
+            .unwrap_or_else(|| claims.aud.clone());
+        let created_at = DateTime::from_timestamp(claims.iat, 0).unwrap_or_default();
+        let expires_at = DateTime::from_timestamp(claims.exp, 0).unwrap_or_default();
+        Self {
+            id: claims.jti.clone(),
+            access_token,
+            refresh_token: None,
+            token_type: "Bearer".to_string(),
+            expires_in: claims.exp - claims.iat,
+            scope: claims.scope.clone(),
+            audience: (claims.aud != client_id).then(|| claims.aud.clone()),
+            client_id,
+            user_id: claims.sub.clone(),
+            created_at,
+            expires_at,
+            revoked: false,
+            refresh_expires_at: None,
+            refresh_max_expires_at: None,
+            last_used_at: None,
+            impersonator: claims.act.as_ref().map(|act| act.sub.clone()),
+            authorization_details: None,
+            cnf_jkt: None,
+            cnf_x5t_s256: None,
+            refresh_scope: None,
+            jti: Some(claims.jti.clone()),
+            flow_id: None,
+            authorization_code_id: None,
         }
     }
 
-    pub fn is_expired(&self) -> bool {
-        Utc::now() > self.expires_at
+    /// Record the `jti` of the JWT access token
+    pub fn with_jti(mut self, jti: impl Into<String>) -> Self {
+        self.jti = Some(jti.into());
+        self
+    }
+
+    /// Set the refresh token's expiry and the limit its rotations inherit
+    pub fn with_refresh_expiry(
+        mut self,
+        expires_at: DateTime<Utc>,
+        max_expires_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        self.refresh_expires_at = Some(expires_at);
+        self.refresh_max_expires_at = max_expires_at;
+        self
+    }
+
+    /// Scope a refresh may ask for: what was granted, not what the last
+    /// refresh narrowed the access token to
+    pub fn refreshable_scope(&self) -> &str {
+        self.refresh_scope.as_deref().unwrap_or(&self.scope)
+    }
+
+    pub fn with_audience(mut self, audience: Option<String>) -> Self {
+        self.audience = audience;
+        self
+    }
+
+    pub fn with_impersonator(mut self, impersonator: impl Into<String>) -> Self {
+        self.impersonator = Some(impersonator.into());
+        self
+    }
+
+    /// Issue the token in exchange for `code`, as part of its journey
+    pub fn exchanged_for(mut self, code: &AuthorizationCode) -> Self {
+        self.flow_id = code.flow_id.clone();
+        self.authorization_code_id = Some(code.id.clone());
+        self
     }
 
-    pub fn is_valid(&self) -> bool {
-        !self.revoked && !self.is_expired()
+    /// Keep what `previous` was bound to and granted in detail, and the
+    /// journey and code it came out of, for the token replacing it
+    pub fn inheriting(mut self, previous: &Token) -> Self {
+        self.authorization_details = previous.authorization_details.clone();
+        self.flow_id = previous.flow_id.clone();
+        self.authorization_code_id = previous.authorization_code_id.clone();
+        self.cnf_jkt = previous.cnf_jkt.clone();
+        self.cnf_x5t_s256 = previous.cnf_x5t_s256.clone();
+        self
+    }
+
+    /// The key or certificate the token is bound to, if any
+    pub fn confirmation(&self) -> Option<Confirmation> {
+        if self.cnf_jkt.is_none() && self.cnf_x5t_s256.is_none() {
+            return None;
+        }
+        Some(Confirmation {
+            jkt: self.cnf_jkt.clone(),
+            x5t_s256: self.cnf_x5t_s256.clone(),
+        })
+    }
+
+    /// Who holds the token, as `user:<id>`, or `client:<id>` for tokens a
+    /// client was issued for itself
+    pub fn principal(&self) -> String {
+        if self.user_id.is_empty() || self.user_id == self.client_id {
+            format!("client:{}", self.client_id)
+        } else {
+            format!("user:{}", self.user_id)
+        }
+    }
+
+    /// The access token's `jti`, or its stored id for opaque tokens, as
+    /// introspection reports it. Expiry is not checked.
+    pub fn jti(&self, keys: &SigningKeys) -> String {
+        if let Some(jti) = &self.jti {
+            return jti.clone();
+        }
+        let mut validation = keys.validation();
+        validation.validate_aud = false;
+        validation.validate_exp = false;
+        jsonwebtoken::decode::<Claims>(&self.access_token, keys.decoding_key(), &validation)
+            .map_or_else(|_| self.id.clone(), |data| data.claims.jti)
+    }
+
+    /// Hex SHA-256 of `jti`, as carried on `token_revoked` events for
+    /// resource servers keeping a denylist
+    pub fn jti_sha256(&self, keys: &SigningKeys) -> String {
+        hex::encode(Sha256::digest(self.jti(keys).as_bytes()))
+    }
+
+    pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+        clock.now() > self.expires_at
+    }
+
+    pub fn is_valid(&self, clock: &dyn Clock) -> bool {
+        !self.revoked && !self.is_expired(clock)
+    }
+}
+
+/// How an access token is kept in the `tokens` table. JWTs are stored as
+/// issued. An opaque token means nothing without its row, so only its hex
+/// SHA-256 is stored and a copy of the table holds no usable tokens.
+pub fn stored_access_token(access_token: &str) -> Cow<'_, str> {
+    if access_token.split('.').count() == 3 {
+        Cow::Borrowed(access_token)
+    } else {
+        Cow::Owned(hex::encode(Sha256::digest(access_token.as_bytes())))
     }
 }
 
@@ -115,6 +444,16 @@ pub struct TokenResponse {
     pub expires_in: i64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub scope: Option<String>,
+    /// OpenID Connect ID token, when the `openid` scope was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+impl TokenResponse {
+    pub fn with_id_token(mut self, id_token: Option<String>) -> Self {
+        self.id_token = id_token;
+        self
+    }
 }
 
 impl From<Token> for TokenResponse {
@@ -125,6 +464,7 @@ impl From<Token> for TokenResponse {
             token_type: token.token_type,
             expires_in: token.expires_in,
             scope: Some(token.scope),
+            id_token: None,
         }
     }
 }
@@ -145,5 +485,222 @@ pub struct IntrospectionResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub iat: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<ActClaim>,
+    /// RFC 9396 authorization details the token was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<Object>>)]
+    pub authorization_details: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+impl IntrospectionResponse {
+    /// The answer for a token that is not active, which says nothing else
+    pub fn inactive() -> Self {
+        Self {
+            active: false,
+            scope: None,
+            client_id: None,
+            username: None,
+            token_type: None,
+            exp: None,
+            iat: None,
+            nbf: None,
+            sub: None,
+            aud: None,
+            jti: None,
+            act: None,
+            authorization_details: None,
+            cnf: None,
+        }
+    }
+}
+
+/// A token to decode for support, and optionally the API it was sent to
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct TokenDecodeRequest {
+    pub token: String,
+    /// Check the token is meant for this audience, e.g. a resource identifier
+    #[serde(default)]
+    pub audience: Option<String>,
+}
+
+/// A check a token can fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenCheck {
+    /// Not a JWT this server could have produced
+    Malformed,
+    Signature,
+    Expired,
+    NotYetValid,
+    Audience,
+    /// No record of the token; it was not issued here or has been purged
+    Unknown,
+    Revoked,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenFailure {
+    pub check: TokenCheck,
+    pub reason: String,
+}
+
+/// The stored record behind a token, without the token values themselves
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenRecordStatus {
+    pub id: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<String>,
+}
+
+impl From<&Token> for TokenRecordStatus {
+    fn from(token: &Token) -> Self {
+        Self {
+            id: token.id.clone(),
+            client_id: token.client_id.clone(),
+            user_id: token.user_id.clone(),
+            scope: token.scope.clone(),
+            audience: token.audience.clone(),
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+            revoked: token.revoked,
+            last_used_at: token.last_used_at,
+            impersonator: token.impersonator.clone(),
+        }
+    }
+}
+
+/// Everything known about a token, and every reason it would be refused
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TokenInspection {
+    pub format: TokenFormat,
+    /// JWT header, as sent
+    #[schema(value_type = Option<Object>)]
+    pub header: Option<serde_json::Value>,
+    /// JWT claims as sent, whether or not the signature holds
+    #[schema(value_type = Option<Object>)]
+    pub claims: Option<serde_json::Value>,
+    pub record: Option<TokenRecordStatus>,
+    /// True when there are no failures
+    pub valid: bool,
+    pub failures: Vec<TokenFailure>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_time_claims_allow_leeway() {
+        let secret = &SigningKeys::hmac("test-secret");
+        let clock = ManualClock::starting_now();
+        let mut claims = Claims::new("user".into(), "client".into(), "read".into(), 60, &clock);
+        claims.nbf = Some(claims.iat + 30);
+        let token = claims.encode(secret).unwrap();
+        let kind = |result: Result<Claims, jsonwebtoken::errors::Error>| {
+            result.map(|_| ()).map_err(|e| e.into_kind())
+        };
+
+        // Not before is 30s away: too early without leeway, fine with it
+        assert_eq!(
+            kind(Claims::decode(&token, secret, &clock, 0)),
+            Err(ErrorKind::ImmatureSignature)
+        );
+        assert!(Claims::decode(&token, secret, &clock, 30).is_ok());
+
+        // Expired 5s ago
+        clock.advance(Duration::seconds(65));
+        assert_eq!(
+            kind(Claims::decode(&token, secret, &clock, 0)),
+            Err(ErrorKind::ExpiredSignature)
+        );
+        assert_eq!(
+            Claims::decode(&token, secret, &clock, 5).unwrap().jti,
+            claims.jti
+        );
+
+        // Issued by a clock running ahead of ours
+        let ahead = ManualClock::new(clock.now() + Duration::seconds(20));
+        let token = Claims::new("user".into(), "client".into(), "read".into(), 60, &ahead)
+            .encode(secret)
+            .unwrap();
+        assert!(Claims::decode(&token, secret, &clock, 10).is_err());
+        assert!(Claims::decode(&token, secret, &clock, 20).is_ok());
+    }
+
+    #[test]
+    fn test_access_tokens_are_typed_and_read_back_from_claims() {
+        let secret = &SigningKeys::hmac("test-secret");
+        let clock = ManualClock::starting_now();
+        let claims = Claims::new("user".into(), "client".into(), "read".into(), 60, &clock)
+            .with_audience("https://api.example.com")
+            .with_actor("admin");
+        let access_token = claims.encode_access_token(secret).unwrap();
+        assert!(Claims::is_access_token(&access_token));
+        assert!(!Claims::is_access_token(&claims.encode(secret).unwrap()));
+        assert!(!Claims::is_access_token("opaque"));
+
+        let token = Token::from_claims(access_token, &claims);
+        assert_eq!(token.client_id, "client");
+        assert_eq!(token.user_id, "user");
+        assert_eq!(token.audience.as_deref(), Some("https://api.example.com"));
+        assert_eq!(token.impersonator.as_deref(), Some("admin"));
+        assert_eq!(token.expires_at.timestamp(), claims.exp);
+        assert_eq!(token.jti(secret), claims.jti);
+        assert!(token.is_valid(&clock));
+    }
+
+    #[test]
+    fn test_jti_falls_back_to_id_for_opaque_tokens() {
+        let secret = &SigningKeys::hmac("test-secret");
+        let clock = ManualClock::starting_now();
+        let claims = Claims::new("user".into(), "client".into(), "read".into(), 60, &clock);
+        let jwt = Token::new(
+            claims.encode(secret).unwrap(),
+            None,
+            "client".into(),
+            "user".into(),
+            "read".into(),
+            60,
+            &clock,
+        );
+        // Still known once expired, so late revocations can be published
+        clock.advance(Duration::seconds(120));
+        assert_eq!(jwt.jti(secret), claims.jti);
+        assert_eq!(
+            jwt.jti_sha256(secret),
+            hex::encode(Sha256::digest(claims.jti.as_bytes()))
+        );
+
+        let opaque = Token::new(
+            "opaque".into(),
+            None,
+            "client".into(),
+            "user".into(),
+            "read".into(),
+            60,
+            &clock,
+        );
+        assert_eq!(opaque.jti(secret), opaque.id);
+    }
 }
@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+
+/// Uses of a token or client since the last flush
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsageCount {
+    pub last_used_at: DateTime<Utc>,
+    pub count: i64,
+}
+
+/// A client nobody has used recently
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct StaleClient {
+    pub client_id: String,
+    pub name: String,
+    pub owner_id: Option<String>,
+    pub owner_org: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// `None` if the client has never been used
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub use_count: i64,
+}
+
+/// A live token that has not been used recently. The token itself is never
+/// included.
+#[derive(Debug, Clone, Serialize, FromRow, ToSchema)]
+pub struct UnusedToken {
+    pub id: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub scope: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Whether it can still be refreshed
+    pub refreshable: bool,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub use_count: i64,
+}
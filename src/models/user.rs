@@ -1,8 +1,10 @@
 #![allow(dead_code)]
 
-use chrono::{DateTime, Utc};
+use crate::models::Token;
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -14,6 +16,7 @@ pub struct User {
     pub enabled: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub owner_org: Option<String>,
 }
 
 impl User {
@@ -27,10 +30,85 @@ impl User {
             enabled: true,
             created_at: now,
             updated_at: now,
+            owner_org: None,
         }
     }
 }
 
+/// OpenID Connect UserInfo response: standard claims about the user a token
+/// was issued for, as far as its scope allows
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct UserInfo {
+    pub sub: String,
+    /// With the `profile` scope, from the user's identity provider
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// With the `profile` scope, for users with a username of their own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    /// With the `profile` scope, from the user's identity provider
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub picture: Option<String>,
+    /// With the `email` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+/// What was revoked when a user's credentials were
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RevokedCredentials {
+    pub tokens: u64,
+    pub authorization_codes: u64,
+    /// The token rows revoked, for caches to forget
+    #[serde(skip)]
+    pub revoked_tokens: Vec<Token>,
+}
+
+/// An account scheduled for erasure, or already erased
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct AccountDeletion {
+    /// The user's id or `provider:id` subject; the pseudonym once erased
+    pub subject: String,
+    /// `self` or `admin`
+    pub requested_by: String,
+    pub requested_at: DateTime<Utc>,
+    /// When the erasure job anonymizes the account
+    pub erase_after: DateTime<Utc>,
+    pub erased_at: Option<DateTime<Utc>>,
+}
+
+impl AccountDeletion {
+    pub fn new(
+        subject: String,
+        requested_by: &str,
+        now: DateTime<Utc>,
+        grace_period: Duration,
+    ) -> Self {
+        Self {
+            subject,
+            requested_by: requested_by.to_string(),
+            requested_at: now,
+            erase_after: now + grace_period,
+            erased_at: None,
+        }
+    }
+}
+
+/// What an erasure anonymized. Counts are kept, identities are not.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct ErasureReport {
+    /// Stands in for the subject wherever a record of it was kept
+    pub pseudonym: String,
+    pub users: u64,
+    pub tokens: u64,
+    pub authorization_codes: u64,
+    pub owned_clients: u64,
+    pub audit_entries: u64,
+    /// TOTP enrollments, remembered devices, organization memberships,
+    /// stored upstream tokens and consents, which are deleted outright
+    pub deleted_records: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserCredentials {
     pub username: String,
@@ -0,0 +1,1496 @@
+//! Application wiring shared by the binary and the end-to-end tests.
+//!
+//! [`ServerBuilder`] connects the database, starts the actors and assembles
+//! the Actix app; `main` only adds telemetry and waits on the result.
+
+use crate::cache::SharedCache;
+use crate::clock::{SharedClock, SystemClock};
+use crate::config::{Config, CookieSameSite, SessionConfig};
+use crate::{
+    actors, cache, db, events, handlers, keys, metrics, middleware, models, services, telemetry,
+    templates,
+};
+use actix::Actor;
+use actix_cors::Cors;
+use actix_files::Files;
+use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+use actix_web::dev::ServerHandle;
+use actix_web::{
+    cookie::{Key, SameSite},
+    http::KeepAlive,
+    middleware as actix_middleware, web, App, HttpResponse, HttpServer,
+};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use tracing_actix_web::TracingLogger;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
+
+/// How often accounts past their deletion grace period are looked for
+const ERASURE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+#[derive(OpenApi)]
+#[openapi(
+    components(
+        schemas(
+            handlers::oauth::TokenRequest,
+            handlers::token::IntrospectRequest,
+            handlers::token::RevokeRequest,
+            models::TokenResponse,
+            models::IntrospectionResponse,
+            models::UserInfo,
+            models::TokenDecodeRequest,
+            models::TokenInspection,
+            models::TokenCheck,
+            models::TokenFailure,
+            models::TokenRecordStatus,
+            models::Confirmation,
+            models::ClientRegistration,
+            models::ClientCredentials,
+            models::TokenEndpointAuthMethod,
+            models::ClientState,
+            models::ClientProvisioning,
+            models::ClientExport,
+            models::PortableClient,
+            models::ClientImportReport,
+            models::Organization,
+            models::OrganizationRegistration,
+            models::OrganizationAssignment,
+            models::SecretExpiryAssignment,
+            models::ExpiringSecret,
+            models::RegistrationToken,
+            models::RegistrationTokenRequest,
+            models::IssuedRegistrationToken,
+            models::AuditVerification,
+            models::Resource,
+            models::ResourceRegistration,
+            models::TokenFormat,
+            models::OAuth2Error,
+            models::AdminAuditEntry,
+            models::AuthorizationRequestEntry,
+            models::AccountDeletion,
+            models::ErasureReport,
+            models::RetentionReport,
+            models::StaleClient,
+            models::StatsBucket,
+            models::StatsGranularity,
+            models::UnusedToken,
+            models::SyncManifest,
+            models::DeclaredClient,
+            models::DeclaredResource,
+            models::DeclaredUser,
+            models::SyncReport,
+            models::SyncChange,
+            models::SyncKind,
+            models::SyncAction,
+            events::Anomaly,
+            services::SchemaViolation,
+        )
+    ),
+    tags(
+        (name = "OAuth2", description = "OAuth2 authentication and authorization endpoints"),
+        (name = "Client Management", description = "Client registration and management"),
+        (name = "Token Management", description = "Token introspection and revocation"),
+        (name = "Admin", description = "Administrative and monitoring endpoints"),
+        (name = "Observability", description = "Health checks and metrics"),
+    ),
+    info(
+        title = "OAuth2 Server API",
+        version = "0.1.0",
+        description = "A complete OAuth2 server implementation with Actix-web, featuring social logins and OIDC support",
+        contact(
+            name = "API Support",
+            email = "support@example.com"
+        ),
+        license(
+            name = "MIT OR Apache-2.0"
+        )
+    )
+)]
+pub struct ApiDoc;
+
+// Helper function to parse event types from configuration strings
+pub fn parse_event_types(event_type_strings: &[String]) -> Vec<events::EventType> {
+    use events::EventType;
+
+    event_type_strings
+        .iter()
+        .filter_map(|s| match s.as_str() {
+            "authorization_code_created" => Some(EventType::AuthorizationCodeCreated),
+            "authorization_code_validated" => Some(EventType::AuthorizationCodeValidated),
+            "authorization_code_expired" => Some(EventType::AuthorizationCodeExpired),
+            "authorization_code_replayed" => Some(EventType::AuthorizationCodeReplayed),
+            "token_created" => Some(EventType::TokenCreated),
+            "token_validated" => Some(EventType::TokenValidated),
+            "token_revoked" => Some(EventType::TokenRevoked),
+            "token_expired" => Some(EventType::TokenExpired),
+            "client_registered" => Some(EventType::ClientRegistered),
+            "client_validated" => Some(EventType::ClientValidated),
+            "client_updated" => Some(EventType::ClientUpdated),
+            "client_deleted" => Some(EventType::ClientDeleted),
+            "client_secret_expiring" => Some(EventType::ClientSecretExpiring),
+            "rate_limit_warning" => Some(EventType::RateLimitWarning),
+            "user_authenticated" => Some(EventType::UserAuthenticated),
+            "user_authentication_failed" => Some(EventType::UserAuthenticationFailed),
+            "user_logout" => Some(EventType::UserLogout),
+            "suspicious_login" => Some(EventType::SuspiciousLogin),
+            "user_deletion_requested" => Some(EventType::UserDeletionRequested),
+            "user_erased" => Some(EventType::UserErased),
+            "user_profile_updated" => Some(EventType::UserProfileUpdated),
+            "admin_action" => Some(EventType::AdminAction),
+            "impersonation_started" => Some(EventType::ImpersonationStarted),
+            _ => {
+                tracing::warn!("Unknown event type in config: {}", s);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Builds a ready-to-run OAuth2 server from a [`Config`]
+pub struct ServerBuilder {
+    config: Config,
+    listener: Option<TcpListener>,
+    internal_listener: Option<TcpListener>,
+    session_key: Option<Key>,
+    social_config: Option<models::SocialLoginConfig>,
+    clock: Option<SharedClock>,
+    geo_lookup: Option<Arc<dyn services::GeoLookup>>,
+    user_authenticator: Option<Arc<dyn services::UserAuthenticator>>,
+    event_plugins: Vec<Arc<dyn events::EventPlugin>>,
+    cache: Option<SharedCache>,
+    log_levels: Option<Arc<telemetry::LogLevels>>,
+    token_signer: Option<Arc<dyn keys::TokenSigner>>,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            listener: None,
+            internal_listener: None,
+            session_key: None,
+            social_config: None,
+            clock: None,
+            geo_lookup: None,
+            user_authenticator: None,
+            event_plugins: Vec::new(),
+            cache: None,
+            log_levels: None,
+            token_signer: None,
+        }
+    }
+
+    /// Serve on an already-bound listener instead of `server.host:server.port`.
+    /// Binding to port 0 lets tests run servers side by side.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Serve the admin API and metrics on an already-bound listener instead
+    /// of `server.internal_addr`
+    pub fn internal_listener(mut self, listener: TcpListener) -> Self {
+        self.internal_listener = Some(listener);
+        self
+    }
+
+    /// Key used to sign session cookies. Defaults to `OAUTH2_SESSION_KEY`,
+    /// or a random key when that is unset.
+    pub fn session_key(mut self, key: Key) -> Self {
+        self.session_key = Some(key);
+        self
+    }
+
+    /// Social login providers. Defaults to `SocialLoginConfig::from_env`.
+    pub fn social_login(mut self, config: models::SocialLoginConfig) -> Self {
+        self.social_config = Some(config);
+        self
+    }
+
+    /// Time source for token and code expiry. Defaults to the system clock.
+    pub fn clock(mut self, clock: SharedClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// IP geolocation for login and token events. Defaults to the database
+    /// at `risk.geoip_database`, or no lookup when that is unset.
+    pub fn geo_lookup(mut self, lookup: Arc<dyn services::GeoLookup>) -> Self {
+        self.geo_lookup = Some(lookup);
+        self
+    }
+
+    /// Directory the password grant checks passwords against. Defaults to
+    /// the LDAP server at `ldap.url`, or none when that is unset.
+    pub fn user_authenticator(
+        mut self,
+        authenticator: Arc<dyn services::UserAuthenticator>,
+    ) -> Self {
+        self.user_authenticator = Some(authenticator);
+        self
+    }
+
+    /// An external backend to deliver events to alongside `events.backend`,
+    /// besides those `events.redis` and `events.webhook` configure. Its
+    /// events are buffered while it is unhealthy and replayed once it
+    /// recovers.
+    pub fn event_plugin(mut self, plugin: Arc<dyn events::EventPlugin>) -> Self {
+        self.event_plugins.push(plugin);
+        self
+    }
+
+    /// Where short-lived shared state lives. Defaults to the Redis server at
+    /// `cache.redis_url`, or an in-process cache when that is unset.
+    pub fn cache(mut self, cache: SharedCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// The log filter the admin API can change. Without it the filter is
+    /// fixed at startup.
+    pub fn log_levels(mut self, log_levels: Arc<telemetry::LogLevels>) -> Self {
+        self.log_levels = Some(log_levels);
+        self
+    }
+
+    /// Signs tokens with a key held outside the process, such as in an HSM
+    /// or a cloud KMS. Its algorithm takes the place of `jwt.algorithm`,
+    /// and `jwt.private_key_file` is not read.
+    pub fn token_signer(mut self, signer: Arc<dyn keys::TokenSigner>) -> Self {
+        self.token_signer = Some(signer);
+        self
+    }
+
+    /// Connect to the database, start the actors and bind the HTTP server
+    pub async fn build(self) -> std::io::Result<Server> {
+        let config = self.config;
+
+        // Load social login configuration
+        let social_config = Arc::new(
+            self.social_config
+                .unwrap_or_else(models::SocialLoginConfig::from_env),
+        );
+        let login_config = Arc::new(config.login.clone());
+        let introspection_config = Arc::new(config.introspection.clone());
+        let admin_access = Arc::new(config.admin_access.clone());
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        tracing::info!("Social login configuration loaded");
+
+        if let Some(base) = &config.server.error_reference_url {
+            models::set_error_reference_base(base);
+        }
+
+        let geo_lookup = match (self.geo_lookup, &config.risk.geoip_database) {
+            (Some(lookup), _) => lookup,
+            (None, Some(path)) => services::open_geo_lookup(path).map_err(std::io::Error::other)?,
+            (None, None) => Arc::new(services::NoGeoLookup),
+        };
+        tracing::info!("Geolocation lookup: {}", geo_lookup.name());
+        let origin_resolver = Arc::new(services::OriginResolver::new(
+            geo_lookup,
+            config.risk.trust_forwarded_for,
+        ));
+        let risk_detector = Arc::new(services::LoginRiskDetector::new(
+            config.risk.max_travel_speed_kmh,
+        ));
+        let risk_config = Arc::new(config.risk.clone());
+        let session_config = config.session.clone();
+        let workload_identity = Arc::new(match &config.workload_identity.trust_bundle {
+            Some(path) => services::WorkloadIdentityVerifier::open(path, clock.clone())
+                .map_err(std::io::Error::other)?,
+            None => services::WorkloadIdentityVerifier::disabled(clock.clone()),
+        });
+        tracing::info!(
+            "Workload identity federation: {} trusted issuers",
+            workload_identity.issuer_count()
+        );
+
+        let directory = match self.user_authenticator {
+            Some(authenticator) => Some(authenticator),
+            None => {
+                services::open_user_authenticator(&config.ldap).map_err(std::io::Error::other)?
+            }
+        };
+        tracing::info!(
+            "User directory: {}",
+            directory
+                .as_ref()
+                .map_or("none", |directory| directory.name())
+        );
+
+        let cache = match self.cache {
+            Some(cache) => cache,
+            None => {
+                cache::open_cache(&config.cache, clock.clone()).map_err(std::io::Error::other)?
+            }
+        };
+        tracing::info!("Cache: {}", cache.name());
+        let origin_ttl = std::time::Duration::from_secs(config.cache.origin_ttl_secs);
+
+        let saml_idp = services::SamlIdp::open(&config.saml, clock.clone())
+            .map_err(std::io::Error::other)?
+            .map(Arc::new);
+        tracing::info!(
+            "SAML identity provider: {} service providers",
+            saml_idp
+                .as_ref()
+                .map_or(0, |idp| idp.service_provider_count())
+        );
+
+        let policy =
+            Arc::new(services::open_policy(&config.policy).map_err(std::io::Error::other)?);
+        tracing::info!("Authorization policy: {}", policy.engine_name());
+        let validator = Arc::new(services::RequestValidator::new(config.limits.clone()));
+
+        // Initialize metrics
+        let metrics = metrics::Metrics::new().map_err(std::io::Error::other)?;
+        metrics.watch_runtime("main");
+        tracing::info!("Metrics initialized");
+
+        // Initialize database
+        let db = db::Database::new(&config.database)
+            .await
+            .map_err(std::io::Error::other)?;
+
+        db.init().await.map_err(std::io::Error::other)?;
+        tracing::info!("Database initialized");
+
+        let db = Arc::new(db);
+        services::claim_database(&db, config.mode, clock.now())
+            .await
+            .map_err(std::io::Error::other)?;
+        if config.mode.is_demo() {
+            let port = match &self.listener {
+                Some(listener) => listener.local_addr()?.port(),
+                None => config.server.port,
+            };
+            let redirect_uris = services::demo_redirect_uris(&config.server.host, port);
+            services::seed_demo_data(&db, &redirect_uris, clock.now())
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            tracing::warn!(
+                "Demo mode: seeded user {:?} and clients {:?} with well-known credentials; never expose this server",
+                services::DEMO_USERNAME,
+                services::DEMO_CLIENTS
+                    .iter()
+                    .map(|client| client.client_id)
+                    .collect::<Vec<_>>()
+            );
+        }
+        let usage = Arc::new(services::UsageTracker::new(db.clone(), &config.usage));
+
+        // Replicas with a cache of their own apply each other's deletions
+        let invalidator = Arc::new(
+            cache::CacheInvalidator::new(
+                db.clone(),
+                cache.clone(),
+                clock.clone(),
+                config.cache.invalidation_poll_ms,
+            )
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?,
+        );
+        if let Some(poll_interval) = invalidator.poll_interval() {
+            actix_web::rt::spawn({
+                let invalidator = invalidator.clone();
+                async move {
+                    let mut interval = actix_web::rt::time::interval(poll_interval);
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = invalidator.apply().await {
+                            tracing::warn!("Failed to apply cache invalidations: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+
+        // Compile HTML templates up front so syntax errors fail startup
+        let templates = Arc::new(
+            templates::Templates::new(config.branding.clone()).map_err(std::io::Error::other)?,
+        );
+        tracing::info!("Templates loaded");
+
+        let jwt_secret = config.jwt.secret.clone();
+        let jwt_config = Arc::new(config.jwt.clone());
+        let signing_keys: keys::SharedKeys = Arc::new(
+            match self.token_signer {
+                Some(signer) => keys::SigningKeys::from_signer(signer),
+                None => keys::SigningKeys::from_config(&config.jwt),
+            }
+            .map_err(std::io::Error::other)?,
+        );
+        match signing_keys.external_signer() {
+            Some(name) => tracing::info!(
+                "Signing tokens with {:?} through {}",
+                signing_keys.algorithm(),
+                name
+            ),
+            None => tracing::info!("Signing tokens with {:?}", signing_keys.algorithm()),
+        }
+        let session_key = self.session_key.unwrap_or_else(session_key_from_env);
+        let mfa = Arc::new(services::MfaService::new(
+            db.clone(),
+            clock.clone(),
+            session_key.clone(),
+            config.branding.product_name.clone(),
+            &config.mfa,
+        ));
+
+        let anomaly_detector = Arc::new(events::AnomalyDetector::new(
+            config.anomaly.clone(),
+            clock.clone(),
+        ));
+
+        let stats = Arc::new(events::StatsAggregator::new(db.clone(), clock.clone()));
+        let rollup_interval =
+            std::time::Duration::from_secs(config.stats.rollup_interval_secs.max(1));
+        actix_web::rt::spawn({
+            let stats = stats.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(rollup_interval);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = stats.flush().await {
+                        tracing::warn!("Failed to roll up event stats: {}", e);
+                    }
+                }
+            }
+        });
+
+        let log_sampler = Arc::new(telemetry::LogSampler::new(
+            &config.log_sampling,
+            clock.clone(),
+        ));
+        let log_levels = self.log_levels;
+
+        // External backends degrade to a buffer instead of losing events
+        let event_backends = Arc::new(events::EventBackends::new(
+            config.events.buffer_dir.clone(),
+            config.events.buffer_max_events,
+            clock.clone(),
+        ));
+        if config.events.enabled {
+            let mut event_plugins = self.event_plugins;
+            if let Some(webhook) = &config.events.webhook {
+                event_plugins.push(Arc::new(
+                    events::WebhookEventPlugin::new(webhook).map_err(std::io::Error::other)?,
+                ));
+            }
+            if let Some(redis) = &config.events.redis {
+                event_plugins.push(Arc::new(
+                    events::RedisStreamEventPlugin::new(redis).map_err(std::io::Error::other)?,
+                ));
+            }
+            // Kafka needs a client library this crate does not bundle; the
+            // embedder registers a plugin that reads `events.kafka`
+            if config.events.kafka.is_some()
+                && !event_plugins.iter().any(|plugin| plugin.name() == "kafka")
+            {
+                tracing::warn!(
+                    "events.kafka is configured but no plugin named kafka was registered; events are not sent to Kafka"
+                );
+            }
+            for plugin in event_plugins {
+                tracing::info!("Event backend: {}", plugin.name());
+                event_backends.add(plugin)?;
+            }
+
+            // Runs even with no backends, for any attached later
+            let check_interval =
+                std::time::Duration::from_secs(config.events.health_check_interval_secs.max(1));
+            actix_web::rt::spawn({
+                let event_backends = event_backends.clone();
+                async move {
+                    let mut interval = actix_web::rt::time::interval(check_interval);
+                    loop {
+                        interval.tick().await;
+                        event_backends.check().await;
+                    }
+                }
+            });
+        }
+
+        // Production refuses to start on a critical problem; demo mode
+        // carries on with a report of it
+        let diagnostics = Arc::new(services::Diagnostics::new(
+            &config,
+            db.clone(),
+            signing_keys.clone(),
+            event_backends.clone(),
+            clock.clone(),
+        ));
+        let report = diagnostics.run().await;
+        report.log();
+        let critical = report.critical();
+        if !critical.is_empty() && !config.mode.is_demo() {
+            return Err(std::io::Error::other(format!(
+                "Refusing to start: {}",
+                critical.join("; ")
+            )));
+        }
+
+        // Initialize event system first
+        let event_actor = if config.events.enabled {
+            use events::{ConsoleEventLogger, EventFilter, InMemoryEventLogger};
+
+            // Parse event filter from config
+            let filter = match config.events.filter_mode.as_str() {
+                "include" => {
+                    let event_types = parse_event_types(&config.events.event_types);
+                    EventFilter::include_only(event_types)
+                }
+                "exclude" => {
+                    let event_types = parse_event_types(&config.events.event_types);
+                    EventFilter::exclude_events(event_types)
+                }
+                _ => EventFilter::allow_all(),
+            };
+
+            // Create plugins based on backend config
+            let mut plugins: Vec<Arc<dyn events::EventPlugin>> =
+                match config.events.backend.as_str() {
+                    "console" => vec![Arc::new(ConsoleEventLogger::with_sampler(
+                        log_sampler.clone(),
+                    ))],
+                    "in_memory" => vec![Arc::new(InMemoryEventLogger::new(1000))],
+                    "both" => vec![
+                        Arc::new(InMemoryEventLogger::new(1000)),
+                        Arc::new(ConsoleEventLogger::with_sampler(log_sampler.clone())),
+                    ],
+                    _ => {
+                        tracing::warn!(
+                            "Unknown event backend: {}, using in_memory",
+                            config.events.backend
+                        );
+                        vec![Arc::new(InMemoryEventLogger::new(1000))]
+                    }
+                };
+
+            // Derived metrics ride along with whichever backend is chosen
+            plugins.push(anomaly_detector.clone());
+            plugins.push(stats.clone());
+            plugins.extend(
+                event_backends
+                    .backends()
+                    .into_iter()
+                    .map(|plugin| plugin as Arc<dyn events::EventPlugin>),
+            );
+
+            let actor = events::event_actor::EventActor::new(plugins, filter).start();
+            tracing::info!("Event system initialized");
+            Some(actor)
+        } else {
+            tracing::info!("Event system disabled");
+            None
+        };
+
+        // Events about state changes wait in the outbox until the relay has
+        // handed them to the plugins
+        let outbox = event_actor.as_ref().map(|event_actor| {
+            Arc::new(services::EventOutbox::new(
+                db.clone(),
+                event_actor.clone(),
+                config.events.outbox_relay_interval_secs,
+            ))
+        });
+        if let Some(outbox) = outbox.clone() {
+            actix_web::rt::spawn(async move {
+                let mut interval = actix_web::rt::time::interval(outbox.interval());
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = outbox.woken() => {}
+                    }
+                    if let Err(e) = outbox.relay().await {
+                        tracing::warn!("Failed to relay outbox events: {}", e);
+                    }
+                }
+            });
+        }
+
+        // Build actors with event system
+        let token_actor = if let Some(ref event_actor) = event_actor {
+            actors::TokenActor::with_events(db.clone(), signing_keys.clone(), event_actor.clone())
+        } else {
+            actors::TokenActor::new(db.clone(), signing_keys.clone())
+        }
+        .with_clock(clock.clone())
+        .with_issuer(config.jwt.issuer.clone())
+        .with_refresh_policy(config.refresh_token.clone())
+        .with_grant_tokens(config.grant_tokens.clone())
+        .with_policy(policy.clone())
+        .with_usage(usage.clone())
+        .with_outbox(outbox.clone())
+        .with_denylist(config.jwt.stateless_validation.then(|| {
+            Arc::new(services::JtiDenylist::new(
+                db.clone(),
+                cache.clone(),
+                clock.clone(),
+                &config.jwt,
+            ))
+        }));
+
+        let client_actor = if let Some(ref event_actor) = event_actor {
+            actors::ClientActor::with_events(db.clone(), event_actor.clone())
+        } else {
+            actors::ClientActor::new(db.clone())
+        }
+        .with_clock(clock.clone())
+        .with_secret_lifetime(config.client_secrets.lifetime_days);
+
+        let auth_actor = if let Some(ref event_actor) = event_actor {
+            actors::AuthActor::with_events(db.clone(), event_actor.clone())
+        } else {
+            actors::AuthActor::new(db.clone())
+        }
+        .with_clock(clock.clone())
+        .with_pkce_required(config.pkce.required)
+        .with_security_profile(config.security_profile)
+        .with_invalidator(invalidator.clone());
+
+        // Supervised, so handlers keep a working address when one of them
+        // stops or panics
+        let restarts = metrics.actor_restarts_total.clone();
+        let token_actor = actors::supervise("token", token_actor, restarts.clone());
+        let client_actor = actors::supervise("client", client_actor, restarts.clone());
+        let auth_actor = actors::supervise("auth", auth_actor, restarts);
+
+        tracing::info!("Actors started");
+
+        let return_to = Arc::new(services::ReturnToValidator::new(&config.login));
+        let impersonator = Arc::new(
+            services::Impersonator::new(
+                db.clone(),
+                signing_keys.clone(),
+                clock.clone(),
+                config.impersonation.clone(),
+                event_actor.clone(),
+            )
+            .with_issuer(config.jwt.issuer.clone())
+            .with_session_admins(config.admin_access.admins.clone())
+            .with_invalidator(invalidator.clone()),
+        );
+
+        let upstream_tokens = Arc::new(services::UpstreamTokenVault::new(
+            db.clone(),
+            &config.upstream_tokens,
+            social_config.clone(),
+            clock.clone(),
+        ));
+        let profile_sync = Arc::new(services::ProfileSync::new(
+            db.clone(),
+            upstream_tokens.clone(),
+            social_config.clone(),
+            clock.clone(),
+            config.profile_sync.clone(),
+            event_actor.clone(),
+        ));
+        if profile_sync.enabled() {
+            actix_web::rt::spawn({
+                let profile_sync = profile_sync.clone();
+                async move {
+                    let mut interval = actix_web::rt::time::interval(profile_sync.interval());
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = profile_sync.run().await {
+                            tracing::warn!("Failed to sync connected profiles: {}", e);
+                        }
+                    }
+                }
+            });
+        }
+
+        let audit_chain = Arc::new(models::AuditChain::new(&jwt_secret));
+        let eraser = Arc::new(
+            services::AccountEraser::new(
+                db.clone(),
+                clock.clone(),
+                &config.account_deletion,
+                event_actor.clone(),
+                audit_chain.clone(),
+            )
+            .with_invalidator(invalidator.clone()),
+        );
+        actix_web::rt::spawn({
+            let eraser = eraser.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(ERASURE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    match eraser.erase_due().await {
+                        Ok(0) => {}
+                        Ok(erased) => tracing::info!("Erased {} deleted accounts", erased),
+                        Err(e) => tracing::warn!("Failed to erase deleted accounts: {}", e),
+                    }
+                }
+            }
+        });
+
+        let retention = Arc::new(services::RetentionEnforcer::new(
+            db.clone(),
+            clock.clone(),
+            config.retention.clone(),
+            metrics.clone(),
+            event_actor.clone(),
+        ));
+        if retention.dry_run() {
+            tracing::info!("Retention job in dry-run mode; nothing will be purged");
+        }
+        actix_web::rt::spawn({
+            let retention = retention.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(retention.interval());
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = retention.run(retention.dry_run()).await {
+                        tracing::warn!("Failed to purge records past retention: {}", e);
+                    }
+                }
+            }
+        });
+
+        let secret_expiry = Arc::new(services::SecretExpiryMonitor::new(
+            db.clone(),
+            clock.clone(),
+            config.client_secrets.clone(),
+            event_actor.clone(),
+        ));
+        actix_web::rt::spawn({
+            let secret_expiry = secret_expiry.clone();
+            async move {
+                let mut interval = actix_web::rt::time::interval(secret_expiry.interval());
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = secret_expiry.warn_expiring().await {
+                        tracing::warn!("Failed to check client secrets for expiry: {}", e);
+                    }
+                }
+            }
+        });
+
+        let registration_gate = Arc::new(services::RegistrationGate::new(
+            db.clone(),
+            clock.clone(),
+            config.registration.clone(),
+        ));
+
+        let admission = Arc::new(services::TokenAdmission::new(
+            config.token_concurrency.clone(),
+            metrics.clone(),
+        ));
+        let rate_limiter = Arc::new(services::RateLimiter::new(
+            config.rate_limit.clone(),
+            cache.clone(),
+            clock.clone(),
+            event_actor.clone(),
+        ));
+
+        // OpenAPI documentation
+        let openapi = ApiDoc::openapi();
+        let schema_validator = config
+            .limits
+            .validate_schemas
+            .then(|| Arc::new(services::SchemaValidator::new(&openapi)));
+
+        // Bound up front so its address is known to the listener split
+        let internal_listener = match self.internal_listener {
+            Some(listener) => Some(listener),
+            None => config
+                .server
+                .internal_addr
+                .as_deref()
+                .map(TcpListener::bind)
+                .transpose()?,
+        };
+        let internal_addr = internal_listener
+            .as_ref()
+            .map(TcpListener::local_addr)
+            .transpose()?;
+
+        let server_clock = clock.clone();
+        let mode = config.mode;
+        let security_profile = config.security_profile;
+        let http_server = HttpServer::new(move || {
+            metrics.watch_http_worker();
+
+            // Token and revocation endpoints only admit registered client
+            // origins; ClientCors handles those
+            let cors = Cors::default()
+                .allow_any_origin()
+                .allowed_origin_fn(|_, head| !middleware::ClientCors::covers(head.uri.path()))
+                .block_on_origin_mismatch(false)
+                .allow_any_method()
+                .allow_any_header()
+                .max_age(3600);
+
+            let mut app = App::new()
+                // Middleware; the listener split, rate limits and schema
+                // checks sit innermost so refused requests are still logged
+                // and counted, and the session timeout and browser state must
+                // sit inside the session middleware
+                .wrap(middleware::SchemaValidation::new(schema_validator.clone()))
+                .wrap(middleware::RateLimit::new(
+                    rate_limiter.clone(),
+                    origin_resolver.clone(),
+                ))
+                .wrap(middleware::ListenerSplit::new(internal_addr))
+                .wrap(middleware::BrowserState::new(&session_config))
+                .wrap(middleware::SessionTimeout::new(
+                    &session_config,
+                    clock.clone(),
+                    db.clone(),
+                ))
+                .wrap(session_middleware(&session_config, session_key.clone()))
+                .wrap(TracingLogger::<telemetry::OAuthRootSpanBuilder>::new())
+                .wrap(middleware::AccessLog::new(log_sampler.clone()))
+                .wrap(actix_middleware::Compress::default())
+                .wrap(middleware::MetricsMiddleware::new(metrics.clone()))
+                .wrap(cors)
+                .wrap(middleware::ClientCors::new(
+                    db.clone(),
+                    cache.clone(),
+                    origin_ttl,
+                ))
+                // Shared state
+                .app_data(web::Data::new(token_actor.clone()))
+                .app_data(web::Data::new(client_actor.clone()))
+                .app_data(web::Data::new(auth_actor.clone()))
+                .app_data(web::Data::new(jwt_config.clone()))
+                .app_data(web::Data::new(admin_access.clone()))
+                .app_data(web::Data::new(signing_keys.clone()))
+                .app_data(web::Data::new(db.clone()))
+                .app_data(web::Data::new(metrics.clone()))
+                .app_data(web::Data::new(social_config.clone()))
+                .app_data(web::Data::new(login_config.clone()))
+                .app_data(web::Data::new(introspection_config.clone()))
+                .app_data(web::Data::new(origin_resolver.clone()))
+                .app_data(web::Data::new(risk_detector.clone()))
+                .app_data(web::Data::new(risk_config.clone()))
+                .app_data(web::Data::new(mfa.clone()))
+                .app_data(web::Data::new(workload_identity.clone()))
+                .app_data(web::Data::new(policy.clone()))
+                .app_data(web::Data::new(directory.clone()))
+                .app_data(web::Data::new(saml_idp.clone()))
+                .app_data(web::Data::new(anomaly_detector.clone()))
+                .app_data(web::Data::new(event_backends.clone()))
+                .app_data(web::Data::new(diagnostics.clone()))
+                .app_data(web::Data::new(validator.clone()))
+                .app_data(web::Data::new(admission.clone()))
+                .app_data(web::Data::new(usage.clone()))
+                .app_data(web::Data::new(stats.clone()))
+                .app_data(web::Data::new(eraser.clone()))
+                .app_data(web::Data::new(return_to.clone()))
+                .app_data(web::Data::new(impersonator.clone()))
+                .app_data(web::Data::new(upstream_tokens.clone()))
+                .app_data(web::Data::new(profile_sync.clone()))
+                .app_data(web::Data::new(log_sampler.clone()))
+                .app_data(web::Data::new(log_levels.clone()))
+                .app_data(web::Data::new(retention.clone()))
+                .app_data(web::Data::new(secret_expiry.clone()))
+                .app_data(web::Data::new(registration_gate.clone()))
+                .app_data(web::Data::new(audit_chain.clone()))
+                .app_data(web::Data::new(invalidator.clone()))
+                .app_data(
+                    web::FormConfig::default()
+                        .limit(validator.max_body_bytes())
+                        .error_handler(models::form_error_handler),
+                )
+                .app_data(
+                    web::JsonConfig::default()
+                        .limit(validator.max_body_bytes())
+                        .error_handler(models::json_error_handler),
+                )
+                .app_data(web::Data::from(clock.clone()))
+                .app_data(web::Data::from(cache.clone()))
+                .app_data(web::Data::new(templates.clone()))
+                .app_data(web::Data::new(mode))
+                .app_data(web::Data::new(security_profile));
+
+            // Add event actor if enabled
+            if let Some(ref event_actor) = event_actor {
+                app = app.app_data(web::Data::new(event_actor.clone()));
+            }
+            if let Some(ref outbox) = outbox {
+                app = app.app_data(web::Data::new(outbox.clone()));
+            }
+            // Playground for the seeded demo clients
+            if mode.is_demo() {
+                app = app.service(
+                    web::scope("/demo")
+                        .wrap(middleware::CsrfProtection::new())
+                        .route("", web::get().to(handlers::demo::playground))
+                        .route("/login", web::post().to(handlers::demo::login))
+                        .route("/start", web::get().to(handlers::demo::start))
+                        .route("/callback", web::get().to(handlers::demo::callback)),
+                );
+            }
+
+            app
+                // Root route
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        HttpResponse::Found()
+                            .append_header(("Location", "/auth/login"))
+                            .finish()
+                    }),
+                )
+                // Authentication routes
+                .service(
+                    web::scope("/auth")
+                        .wrap(middleware::CsrfProtection::new())
+                        .route("/login", web::get().to(handlers::auth::login_page))
+                        .route("/logout", web::post().to(handlers::auth::logout))
+                        .route(
+                            "/logout-all",
+                            web::get().to(handlers::auth::logout_all_confirm),
+                        )
+                        .route("/logout-all", web::post().to(handlers::auth::logout_all))
+                        .route("/success", web::get().to(handlers::auth::auth_success))
+                        .route("/step-up", web::get().to(handlers::auth::step_up))
+                        .route("/step-up", web::post().to(handlers::auth::verify_step_up))
+                        .service(
+                            web::scope("/login")
+                                .route("/google", web::get().to(handlers::auth::google_login))
+                                .route("/microsoft", web::get().to(handlers::auth::microsoft_login))
+                                .route("/github", web::get().to(handlers::auth::github_login))
+                                .route("/azure", web::get().to(handlers::auth::microsoft_login)) // Azure uses Microsoft endpoint
+                                // NOTE: Okta and Auth0 handlers not yet implemented; the login page never links here
+                                .route(
+                                    "/okta",
+                                    web::get().to(|| async {
+                                        actix_web::HttpResponse::ServiceUnavailable()
+                                            .body("Okta login not yet implemented")
+                                    }),
+                                )
+                                .route(
+                                    "/auth0",
+                                    web::get().to(|| async {
+                                        actix_web::HttpResponse::ServiceUnavailable()
+                                            .body("Auth0 login not yet implemented")
+                                    }),
+                                ),
+                        )
+                        .route(
+                            "/callback/{provider}",
+                            web::get().to(handlers::auth::auth_callback),
+                        ),
+                )
+                // OAuth2 endpoints
+                .service(
+                    web::scope("/oauth")
+                        .service(
+                            web::resource("/authorize")
+                                .wrap(middleware::CsrfProtection::new())
+                                .wrap(middleware::AuthorizeAudit::new(
+                                    db.clone(),
+                                    clock.clone(),
+                                    origin_resolver.clone(),
+                                ))
+                                .route(web::get().to(handlers::oauth::authorize))
+                                .route(web::post().to(handlers::oauth::authorize_decision)),
+                        )
+                        .route("/token", web::post().to(handlers::oauth::token))
+                        .route("/introspect", web::post().to(handlers::token::introspect))
+                        .route("/revoke", web::post().to(handlers::token::revoke))
+                        .route(
+                            "/check_session",
+                            web::get().to(handlers::oauth::check_session),
+                        )
+                        .service(
+                            web::resource("/userinfo")
+                                .route(web::get().to(handlers::oauth::userinfo))
+                                .route(web::post().to(handlers::oauth::userinfo)),
+                        ),
+                )
+                // SAML identity provider
+                .service(
+                    web::scope("/saml")
+                        .route("/metadata", web::get().to(handlers::saml::metadata))
+                        .route("/sso", web::get().to(handlers::saml::sso)),
+                )
+                // Client management endpoints
+                .service(web::scope("/clients").route(
+                    "/register",
+                    web::post().to(handlers::client::register_client),
+                ))
+                // Well-known endpoints
+                .service(
+                    web::scope("/.well-known")
+                        .route(
+                            "/openid-configuration",
+                            web::get().to(handlers::wellknown::openid_configuration),
+                        )
+                        .route("/jwks.json", web::get().to(handlers::wellknown::jwks)),
+                )
+                // Signed-in user's security settings
+                .service(
+                    web::scope("/account")
+                        .wrap(middleware::CsrfProtection::new())
+                        .route("/security", web::get().to(handlers::account::security))
+                        .route(
+                            "/mfa/enroll",
+                            web::post().to(handlers::account::enroll_totp),
+                        )
+                        .route(
+                            "/mfa/disable",
+                            web::post().to(handlers::account::disable_totp),
+                        )
+                        .route(
+                            "/devices/{id}/revoke",
+                            web::post().to(handlers::account::revoke_device),
+                        )
+                        .route(
+                            "/devices/revoke-all",
+                            web::post().to(handlers::account::revoke_all_devices),
+                        )
+                        .route(
+                            "/delete",
+                            web::get().to(handlers::account::delete_account_confirm),
+                        )
+                        .route("/delete", web::post().to(handlers::account::delete_account))
+                        .route(
+                            "/connections",
+                            web::get().to(handlers::account::connections),
+                        )
+                        .route(
+                            "/connections/{provider}/disconnect",
+                            web::post().to(handlers::account::disconnect),
+                        ),
+                )
+                // Developer portal for client owners
+                .service(
+                    web::scope("/portal")
+                        .wrap(middleware::CsrfProtection::new())
+                        .route("", web::get().to(handlers::portal::index))
+                        .route("/clients", web::post().to(handlers::portal::create_client))
+                        .route(
+                            "/clients/{client_id}",
+                            web::get().to(handlers::portal::show_client),
+                        )
+                        .route(
+                            "/clients/{client_id}/rotate-secret",
+                            web::post().to(handlers::portal::rotate_secret),
+                        )
+                        .route(
+                            "/clients/{client_id}/redirect-uris",
+                            web::post().to(handlers::portal::update_redirect_uris),
+                        ),
+                )
+                // Admin endpoints
+                .service(
+                    web::scope("/admin")
+                        .wrap(middleware::AdminAudit::new(
+                            db.clone(),
+                            clock.clone(),
+                            origin_resolver.clone(),
+                            event_actor.clone(),
+                            audit_chain.clone(),
+                        ))
+                        .route("", web::get().to(admin_dashboard))
+                        .service(
+                            web::scope("/api")
+                                .wrap(middleware::AdminRoles::new(
+                                    admin_access.clone(),
+                                    db.clone(),
+                                    clock.clone(),
+                                ))
+                                .route("/dashboard", web::get().to(handlers::admin::dashboard))
+                                .route("/clients", web::get().to(handlers::admin::list_clients))
+                                .route(
+                                    "/clients/export",
+                                    web::get().to(handlers::admin::export_clients),
+                                )
+                                .route(
+                                    "/clients/import",
+                                    web::post().to(handlers::admin::import_clients),
+                                )
+                                .route(
+                                    "/clients/expiring-secrets",
+                                    web::get().to(handlers::admin::expiring_secrets),
+                                )
+                                .route("/tokens", web::get().to(handlers::admin::list_tokens))
+                                .route(
+                                    "/tokens/decode",
+                                    web::post().to(handlers::admin::decode_token),
+                                )
+                                .route(
+                                    "/tokens/{token}/revoke",
+                                    web::post().to(handlers::admin::admin_revoke_token),
+                                )
+                                .route(
+                                    "/clients/{id}",
+                                    web::delete().to(handlers::admin::delete_client),
+                                )
+                                .route(
+                                    "/clients/by-name/{name}",
+                                    web::put().to(handlers::admin::provision_client),
+                                )
+                                .route("/sync", web::post().to(handlers::admin::sync))
+                                .route(
+                                    "/clients/{id}/organization",
+                                    web::put().to(handlers::admin::set_client_organization),
+                                )
+                                .route(
+                                    "/clients/{id}/secret-expiry",
+                                    web::put().to(handlers::admin::set_client_secret_expiry),
+                                )
+                                .route(
+                                    "/organizations",
+                                    web::get().to(handlers::admin::list_organizations),
+                                )
+                                .route(
+                                    "/organizations",
+                                    web::post().to(handlers::admin::create_organization),
+                                )
+                                .route(
+                                    "/organizations/{id}/members/{subject}",
+                                    web::put().to(handlers::admin::add_organization_member),
+                                )
+                                .route(
+                                    "/organizations/{id}/members/{subject}",
+                                    web::delete().to(handlers::admin::remove_organization_member),
+                                )
+                                .route("/anomalies", web::get().to(handlers::admin::anomalies))
+                                .route("/audit", web::get().to(handlers::admin::audit_log))
+                                .route(
+                                    "/audit/verify",
+                                    web::get().to(handlers::admin::verify_audit_log),
+                                )
+                                .route(
+                                    "/audit/authorizations",
+                                    web::get().to(handlers::admin::authorization_log),
+                                )
+                                .route("/diagnostics", web::get().to(handlers::admin::diagnostics))
+                                .route(
+                                    "/events/plugins",
+                                    web::get().to(handlers::admin::list_event_plugins),
+                                )
+                                .route(
+                                    "/events/plugins",
+                                    web::post().to(handlers::admin::attach_event_plugin),
+                                )
+                                .route(
+                                    "/events/plugins/{name}",
+                                    web::delete().to(handlers::admin::detach_event_plugin),
+                                )
+                                .route("/stats", web::get().to(handlers::admin::stats))
+                                .route(
+                                    "/usage/stale-clients",
+                                    web::get().to(handlers::admin::stale_clients),
+                                )
+                                .route(
+                                    "/usage/unused-tokens",
+                                    web::get().to(handlers::admin::unused_tokens),
+                                )
+                                .route(
+                                    "/retention/run",
+                                    web::post().to(handlers::admin::run_retention),
+                                )
+                                .route(
+                                    "/profile-sync/run",
+                                    web::post().to(handlers::admin::run_profile_sync),
+                                )
+                                .route(
+                                    "/registration-tokens",
+                                    web::get().to(handlers::admin::list_registration_tokens),
+                                )
+                                .route(
+                                    "/registration-tokens",
+                                    web::post().to(handlers::admin::create_registration_token),
+                                )
+                                .route(
+                                    "/registration-tokens/{id}",
+                                    web::delete().to(handlers::admin::revoke_registration_token),
+                                )
+                                .route("/resources", web::get().to(handlers::admin::list_resources))
+                                .route(
+                                    "/resources",
+                                    web::post().to(handlers::admin::create_resource),
+                                )
+                                .route(
+                                    "/resources/{id}",
+                                    web::delete().to(handlers::admin::delete_resource),
+                                )
+                                .route(
+                                    "/users/{id}/disable",
+                                    web::post().to(handlers::admin::disable_user),
+                                )
+                                .route(
+                                    "/users/{id}/password",
+                                    web::post().to(handlers::admin::change_user_password),
+                                )
+                                .route(
+                                    "/users/{id}/erase",
+                                    web::post().to(handlers::admin::erase_user),
+                                )
+                                .route(
+                                    "/users/{id}/erase",
+                                    web::delete().to(handlers::admin::cancel_user_erasure),
+                                )
+                                .route(
+                                    "/clients/{client_id}/debug-logging",
+                                    web::put().to(handlers::admin::enable_client_debug_logging),
+                                )
+                                .route(
+                                    "/clients/{client_id}/debug-logging",
+                                    web::delete().to(handlers::admin::disable_client_debug_logging),
+                                )
+                                .route(
+                                    "/debug-logging",
+                                    web::get().to(handlers::admin::list_debug_logging),
+                                )
+                                .route("/logging", web::get().to(handlers::admin::log_levels))
+                                .route("/logging", web::put().to(handlers::admin::set_log_levels))
+                                .route(
+                                    "/logging",
+                                    web::delete().to(handlers::admin::reset_log_levels),
+                                )
+                                .route(
+                                    "/users/{id}/upstream-tokens",
+                                    web::get().to(handlers::admin::list_upstream_tokens),
+                                )
+                                .route(
+                                    "/users/{id}/upstream-tokens",
+                                    web::delete().to(handlers::admin::purge_upstream_tokens),
+                                )
+                                .route(
+                                    "/users/{id}/impersonate",
+                                    web::post().to(handlers::admin::impersonate_user),
+                                )
+                                .route(
+                                    "/impersonation",
+                                    web::get().to(handlers::admin::list_impersonation_tokens),
+                                )
+                                .route(
+                                    "/impersonation/revoke",
+                                    web::post().to(handlers::admin::revoke_impersonation_tokens),
+                                )
+                                .route(
+                                    "/conformance",
+                                    web::post().to(handlers::admin::conformance),
+                                ),
+                        ),
+                )
+                // Error page
+                .route("/error", web::get().to(error_page))
+                // Error code reference
+                .route("/errors", web::get().to(error_reference))
+                .route("/errors/{code}", web::get().to(error_reference_entry))
+                // Observability endpoints
+                .route("/health", web::get().to(handlers::admin::health))
+                .route("/ready", web::get().to(handlers::admin::readiness))
+                .route("/metrics", web::get().to(handlers::admin::system_metrics))
+                // Swagger UI
+                .service(
+                    SwaggerUi::new("/swagger-ui/{_:.*}")
+                        .url("/api-docs/openapi.json", openapi.clone()),
+                )
+                // Static files
+                .service(Files::new("/static", "./static"))
+        });
+
+        let server_config = &config.server;
+        let mut http_server = http_server
+            .backlog(server_config.backlog)
+            .keep_alive(match server_config.keep_alive_secs {
+                0 => KeepAlive::Disabled,
+                secs => KeepAlive::Timeout(std::time::Duration::from_secs(secs)),
+            })
+            .client_request_timeout(std::time::Duration::from_millis(
+                server_config.client_request_timeout_ms,
+            ));
+        if let Some(workers) = server_config.workers {
+            http_server = http_server.workers(workers);
+        }
+        tracing::info!(
+            "HTTP workers: {}",
+            server_config
+                .workers
+                .map_or("one per CPU".to_string(), |workers| workers.to_string())
+        );
+
+        let http_server = match self.listener {
+            Some(listener) => http_server.listen(listener)?,
+            None => {
+                let bind_addr = format!("{}:{}", server_config.host, server_config.port);
+                http_server.bind(&bind_addr)?
+            }
+        };
+        let http_server = match internal_listener {
+            Some(listener) => {
+                tracing::info!(
+                    "Serving the admin API and metrics on internal listener {}",
+                    listener.local_addr()?
+                );
+                http_server.listen(listener)?
+            }
+            None => http_server,
+        };
+        #[cfg(unix)]
+        let http_server = match &server_config.unix_socket {
+            Some(path) => {
+                remove_stale_socket(path)?;
+                tracing::info!("Listening on unix:{}", path);
+                http_server.bind_uds(path)?
+            }
+            None => http_server,
+        };
+        #[cfg(not(unix))]
+        if server_config.unix_socket.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix domain sockets are not supported on this platform",
+            ));
+        }
+        let local_addr = http_server.addrs()[0];
+
+        Ok(Server {
+            server: http_server.run(),
+            local_addr,
+            internal_addr,
+            clock: server_clock,
+        })
+    }
+}
+
+/// Remove a socket left behind at `path` by an earlier run, so it can be
+/// bound again
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// A bound server; await [`Server::run`] to serve requests
+pub struct Server {
+    server: actix_web::dev::Server,
+    local_addr: SocketAddr,
+    internal_addr: Option<SocketAddr>,
+    clock: SharedClock,
+}
+
+impl Server {
+    /// Address the server is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Address of the internal listener, if there is one
+    pub fn internal_addr(&self) -> Option<SocketAddr> {
+        self.internal_addr
+    }
+
+    /// Time source the server runs on
+    pub fn clock(&self) -> SharedClock {
+        self.clock.clone()
+    }
+
+    /// Handle for stopping the server from another task
+    pub fn handle(&self) -> ServerHandle {
+        self.server.handle()
+    }
+
+    /// Serve requests until the server is stopped
+    pub async fn run(self) -> std::io::Result<()> {
+        self.server.await
+    }
+}
+
+/// The cookie session, with its cookie's attributes taken from the config
+fn session_middleware(config: &SessionConfig, key: Key) -> SessionMiddleware<CookieSessionStore> {
+    let same_site = match config.cookie_same_site {
+        CookieSameSite::Strict => SameSite::Strict,
+        CookieSameSite::Lax => SameSite::Lax,
+        CookieSameSite::None => SameSite::None,
+    };
+    SessionMiddleware::builder(CookieSessionStore::default(), key)
+        .cookie_name(config.cookie_name.clone())
+        .cookie_secure(config.cookie_secure)
+        .cookie_same_site(same_site)
+        .cookie_domain(config.cookie_domain.clone())
+        .cookie_path(config.cookie_path.clone())
+        .build()
+}
+
+/// Load session key from environment or generate a new one.
+/// In production, OAUTH2_SESSION_KEY should be set to a persistent value.
+fn session_key_from_env() -> Key {
+    if let Ok(key_str) = std::env::var("OAUTH2_SESSION_KEY") {
+        if key_str.len() < 64 {
+            panic!("OAUTH2_SESSION_KEY must be at least 64 characters (128 hex digits)");
+        }
+        let key_bytes =
+            hex::decode(&key_str).expect("OAUTH2_SESSION_KEY must be valid hexadecimal");
+        Key::try_from(&key_bytes[..]).expect("OAUTH2_SESSION_KEY must be exactly 64 bytes")
+    } else {
+        tracing::warn!("OAUTH2_SESSION_KEY not set. Generating random key. Sessions will not persist across restarts!");
+        Key::generate()
+    }
+}
+
+// Admin dashboard HTML page
+async fn admin_dashboard(templates: web::Data<Arc<templates::Templates>>) -> HttpResponse {
+    templates.render_response("admin_dashboard.html", &templates::AdminDashboardPage {})
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorPageQuery {
+    error: Option<String>,
+    error_description: Option<String>,
+    error_code: Option<String>,
+}
+
+// Error page
+async fn error_page(
+    query: web::Query<ErrorPageQuery>,
+    templates: web::Data<Arc<templates::Templates>>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let page = templates::ErrorPage {
+        error: query.error,
+        error_description: query.error_description,
+        error_code: query.error_code,
+    };
+
+    templates.render_response("error.html", &page)
+}
+
+fn error_reference_entry_for(code: models::ErrorCode) -> templates::ErrorReferenceEntry {
+    templates::ErrorReferenceEntry {
+        code: code.as_str(),
+        status: code.status().as_u16(),
+        summary: code.summary(),
+        remedy: code.remedy(),
+    }
+}
+
+// Error code reference, the target of `error_uri`
+async fn error_reference(templates: web::Data<Arc<templates::Templates>>) -> HttpResponse {
+    let page = templates::ErrorReferencePage {
+        entries: models::ErrorCode::ALL
+            .into_iter()
+            .map(error_reference_entry_for)
+            .collect(),
+    };
+    templates.render_response("error_reference.html", &page)
+}
+
+async fn error_reference_entry(
+    code: web::Path<String>,
+    templates: web::Data<Arc<templates::Templates>>,
+) -> HttpResponse {
+    let Some(code) = models::ErrorCode::parse(&code) else {
+        return HttpResponse::NotFound().body("Unknown error code");
+    };
+    let page = templates::ErrorReferencePage {
+        entries: vec![error_reference_entry_for(code)],
+    };
+    templates.render_response("error_reference.html", &page)
+}
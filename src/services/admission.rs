@@ -0,0 +1,207 @@
+//! Admission control for the token endpoint.
+//!
+//! Every grant ends in a round trip through the token and auth actors. When
+//! one client floods `/oauth/token`, their mailboxes fill and every client's
+//! requests time out together. [`TokenAdmission`] caps the requests in flight
+//! overall and per client; requests over the overall cap wait in a FIFO queue
+//! for a slot. A client already at its own cap, a full queue, or a wait past
+//! the deadline sheds the request with `503` and `Retry-After` instead.
+
+use crate::config::TokenConcurrencyConfig;
+use crate::metrics::Metrics;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Why a token request was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shed {
+    /// The client already has its share of requests in flight
+    ClientBusy,
+    /// Too many requests are already waiting for a slot
+    QueueFull,
+    /// No slot came free before the deadline
+    Timeout,
+}
+
+impl Shed {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Shed::ClientBusy => "client_busy",
+            Shed::QueueFull => "queue_full",
+            Shed::Timeout => "timeout",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Shed::ClientBusy => "Too many token requests from this client are in progress",
+            Shed::QueueFull | Shed::Timeout => "The token endpoint is overloaded",
+        }
+    }
+}
+
+type InFlight = Arc<Mutex<HashMap<String, usize>>>;
+
+/// A token request's place, released when dropped
+pub struct Admitted {
+    _slot: Option<OwnedSemaphorePermit>,
+    client: Option<(InFlight, String)>,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        let Some((in_flight, client_id)) = self.client.take() else {
+            return;
+        };
+        let mut in_flight = in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&client_id) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&client_id);
+            }
+        }
+    }
+}
+
+pub struct TokenAdmission {
+    config: TokenConcurrencyConfig,
+    /// None when there is no overall limit
+    slots: Option<Arc<Semaphore>>,
+    queued: AtomicUsize,
+    /// Requests in flight or queued, by client
+    in_flight: InFlight,
+    metrics: Metrics,
+}
+
+impl TokenAdmission {
+    pub fn new(config: TokenConcurrencyConfig, metrics: Metrics) -> Self {
+        let slots =
+            (config.max_in_flight > 0).then(|| Arc::new(Semaphore::new(config.max_in_flight)));
+        Self {
+            config,
+            slots,
+            queued: AtomicUsize::new(0),
+            in_flight: InFlight::default(),
+            metrics,
+        }
+    }
+
+    /// Seconds a shed client is told to wait before retrying
+    pub fn retry_after_secs(&self) -> u64 {
+        self.config.retry_after_secs
+    }
+
+    /// Wait for a slot for a request from `client_id`
+    pub async fn admit(&self, client_id: &str) -> Result<Admitted, Shed> {
+        let result = self.try_admit(client_id).await;
+        if let Err(shed) = result {
+            tracing::info!(client_id = %client_id, reason = shed.as_str(), "Shed token request");
+            self.metrics
+                .token_requests_shed_total
+                .with_label_values(&[shed.as_str()])
+                .inc();
+        }
+        result
+    }
+
+    async fn try_admit(&self, client_id: &str) -> Result<Admitted, Shed> {
+        let mut admitted = Admitted {
+            _slot: None,
+            client: None,
+        };
+        if self.config.max_in_flight_per_client > 0 {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let count = in_flight.entry(client_id.to_string()).or_default();
+            if *count >= self.config.max_in_flight_per_client {
+                return Err(Shed::ClientBusy);
+            }
+            *count += 1;
+            admitted.client = Some((self.in_flight.clone(), client_id.to_string()));
+        }
+
+        let Some(slots) = &self.slots else {
+            return Ok(admitted);
+        };
+        if let Ok(slot) = slots.clone().try_acquire_owned() {
+            admitted._slot = Some(slot);
+            return Ok(admitted);
+        }
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.config.max_queued {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(Shed::QueueFull);
+        }
+        let waited = tokio::time::timeout(
+            Duration::from_millis(self.config.queue_timeout_ms),
+            slots.clone().acquire_owned(),
+        )
+        .await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        match waited {
+            Ok(Ok(slot)) => {
+                admitted._slot = Some(slot);
+                Ok(admitted)
+            }
+            // The semaphore is never closed
+            Ok(Err(_)) | Err(_) => Err(Shed::Timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(max_in_flight: usize, per_client: usize, max_queued: usize) -> TokenAdmission {
+        TokenAdmission::new(
+            TokenConcurrencyConfig {
+                max_in_flight,
+                max_in_flight_per_client: per_client,
+                max_queued,
+                queue_timeout_ms: 50,
+                retry_after_secs: 1,
+            },
+            Metrics::new().unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_busy_client_is_shed_without_starving_others() {
+        let admission = admission(10, 2, 10);
+        let first = admission.admit("noisy").await.unwrap();
+        let _second = admission.admit("noisy").await.unwrap();
+        assert_eq!(admission.admit("noisy").await.err(), Some(Shed::ClientBusy));
+        assert!(admission.admit("quiet").await.is_ok());
+
+        drop(first);
+        assert!(admission.admit("noisy").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queued_requests_wait_for_a_slot_until_the_deadline() {
+        let admission = Arc::new(admission(1, 0, 1));
+        let held = admission.admit("a").await.unwrap();
+
+        // Queued behind the held slot, then admitted when it is released
+        let waiting = tokio::spawn({
+            let admission = admission.clone();
+            async move { admission.admit("b").await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(admission.admit("c").await.err(), Some(Shed::QueueFull));
+        drop(held);
+        assert!(waiting.await.unwrap().is_ok());
+
+        let _held = admission.admit("a").await.unwrap();
+        assert_eq!(admission.admit("b").await.err(), Some(Shed::Timeout));
+        let shed = admission
+            .metrics
+            .token_requests_shed_total
+            .with_label_values(&["timeout"])
+            .get();
+        assert_eq!(shed, 1);
+    }
+}
@@ -0,0 +1,143 @@
+//! Local roles, groups and claims from what an upstream provider says.
+//!
+//! Each provider has its own rule set (`OAUTH2_{PROVIDER}_CLAIM_MAPPINGS`).
+//! At login, every rule whose claim has a matching value, such as a Google
+//! Workspace user's `hd` or an Azure AD user's `groups`, grants its role,
+//! group or claim. Values compare case-insensitively, as domains and group
+//! names do. Users no rule matches get nothing, so rules only ever add.
+
+use crate::models::{ClaimMappingRule, MappingTarget, SocialUserInfo};
+
+/// Claim holding the domain of the user's email, for every provider
+pub const EMAIL_DOMAIN_CLAIM: &str = "email_domain";
+
+/// Record the claims every provider has, from the parts of the profile
+/// they come from
+pub fn collect_common_claims(user: &mut SocialUserInfo) {
+    if let Some((_, domain)) = user.email.rsplit_once('@') {
+        user.upstream_claims
+            .insert(EMAIL_DOMAIN_CLAIM.to_string(), vec![domain.to_lowercase()]);
+    }
+}
+
+/// Grant `user` what the rules matching their upstream claims map to, in
+/// the order the rules list them
+pub fn apply_claim_mappings(rules: &[ClaimMappingRule], user: &mut SocialUserInfo) {
+    for rule in rules {
+        let matches = user.upstream_claims.get(&rule.claim).is_some_and(|values| {
+            values
+                .iter()
+                .any(|value| rule.value == "*" || value.trim().eq_ignore_ascii_case(&rule.value))
+        });
+        if !matches {
+            continue;
+        }
+        match &rule.target {
+            MappingTarget::Role(role) => {
+                if !user.roles.contains(role) {
+                    user.roles.push(role.clone());
+                }
+            }
+            MappingTarget::Group(group) => {
+                if !user.groups.contains(group) {
+                    user.groups.push(group.clone());
+                }
+            }
+            MappingTarget::Claim { name, value } => {
+                // The first rule to set a claim wins
+                user.claims
+                    .entry(name.clone())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn user(email: &str, upstream: &[(&str, &[&str])]) -> SocialUserInfo {
+        let mut user = SocialUserInfo {
+            provider: "microsoft".to_string(),
+            provider_user_id: "1".to_string(),
+            email: email.to_string(),
+            name: None,
+            picture: None,
+            upstream_claims: upstream
+                .iter()
+                .map(|(claim, values)| {
+                    (
+                        claim.to_string(),
+                        values.iter().map(|v| v.to_string()).collect(),
+                    )
+                })
+                .collect(),
+            roles: vec![],
+            groups: vec![],
+            claims: BTreeMap::new(),
+        };
+        collect_common_claims(&mut user);
+        user
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = ClaimMappingRule::parse_list(
+            "groups:Admins=role:admin; hd:example.com=group:staff;\
+             email_domain:*=claim:org=external; bogus; groups:x=team:y",
+        );
+        assert_eq!(
+            rules,
+            vec![
+                ClaimMappingRule {
+                    claim: "groups".to_string(),
+                    value: "Admins".to_string(),
+                    target: MappingTarget::Role("admin".to_string()),
+                },
+                ClaimMappingRule {
+                    claim: "hd".to_string(),
+                    value: "example.com".to_string(),
+                    target: MappingTarget::Group("staff".to_string()),
+                },
+                ClaimMappingRule {
+                    claim: "email_domain".to_string(),
+                    value: "*".to_string(),
+                    target: MappingTarget::Claim {
+                        name: "org".to_string(),
+                        value: "external".to_string(),
+                    },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_matching_rules_grant_roles_groups_and_claims() {
+        let rules = ClaimMappingRule::parse_list(
+            "groups:admins=role:admin;groups:Engineering=group:eng;\
+             groups:Engineering=role:admin;email_domain:contoso.com=claim:org=contoso;\
+             email_domain:*=claim:org=external;groups:Finance=role:auditor",
+        );
+        let mut user = user(
+            "Someone@Contoso.com",
+            &[("groups", &["Admins", "Engineering"])],
+        );
+        apply_claim_mappings(&rules, &mut user);
+
+        assert_eq!(user.roles, vec!["admin"]);
+        assert_eq!(user.groups, vec!["eng"]);
+        assert_eq!(user.claims.get("org").map(String::as_str), Some("contoso"));
+    }
+
+    #[test]
+    fn test_users_no_rule_matches_get_nothing() {
+        let rules = ClaimMappingRule::parse_list("hd:example.com=role:staff");
+        let mut user = user("someone@gmail.com", &[]);
+        apply_claim_mappings(&rules, &mut user);
+        assert!(user.roles.is_empty());
+        assert!(user.groups.is_empty());
+        assert!(user.claims.is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+//! Client secret expiry.
+//!
+//! With `lifetime_days` set, every secret issued at registration, import or
+//! rotation expires that long after it was issued; an admin can also set or
+//! clear a client's expiry, to force a rotation by a deadline. A client that
+//! authenticates with an expired secret is refused with `invalid_client`.
+//! Every `check_interval_secs`, each secret that has come within
+//! `warning_days` of expiring is announced once with a
+//! `client_secret_expiring` event.
+
+use crate::clock::SharedClock;
+use crate::config::ClientSecretConfig;
+use crate::db::Database;
+use crate::events::{
+    event_actor::{EmitEvent, EventActor},
+    AuthEvent, EventSeverity, EventType,
+};
+use crate::models::{ExpiringSecret, OAuth2Error};
+use actix::Addr;
+use chrono::Duration;
+use std::sync::Arc;
+
+pub struct SecretExpiryMonitor {
+    db: Arc<Database>,
+    clock: SharedClock,
+    config: ClientSecretConfig,
+    event_actor: Option<Addr<EventActor>>,
+}
+
+impl SecretExpiryMonitor {
+    pub fn new(
+        db: Arc<Database>,
+        clock: SharedClock,
+        config: ClientSecretConfig,
+        event_actor: Option<Addr<EventActor>>,
+    ) -> Self {
+        Self {
+            db,
+            clock,
+            config,
+            event_actor,
+        }
+    }
+
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.config.check_interval_secs.max(1))
+    }
+
+    pub fn warning_days(&self) -> u32 {
+        self.config.warning_days
+    }
+
+    /// Secrets expiring within `days`, soonest first, including those that
+    /// already have
+    pub async fn expiring(&self, days: u32) -> Result<Vec<ExpiringSecret>, OAuth2Error> {
+        let now = self.clock.now();
+        self.db
+            .list_expiring_client_secrets(now + Duration::days(i64::from(days)), now, false)
+            .await
+    }
+
+    /// Announce each secret newly within the warning window, returning how
+    /// many were announced
+    pub async fn warn_expiring(&self) -> Result<usize, OAuth2Error> {
+        let now = self.clock.now();
+        let due = self
+            .db
+            .list_expiring_client_secrets(
+                now + Duration::days(i64::from(self.config.warning_days)),
+                now,
+                true,
+            )
+            .await?;
+
+        let mut warned = 0;
+        for secret in due {
+            if !self
+                .db
+                .mark_secret_expiry_warned(&secret.client_id, now)
+                .await?
+            {
+                continue;
+            }
+            warned += 1;
+            tracing::warn!(
+                "Secret of client {} expires at {}",
+                secret.client_id,
+                secret.secret_expires_at
+            );
+            if let Some(event_actor) = &self.event_actor {
+                let event = AuthEvent::new(
+                    EventType::ClientSecretExpiring,
+                    EventSeverity::Warning,
+                    secret.owner_id.clone(),
+                    Some(secret.client_id.clone()),
+                )
+                .with_metadata("client_name", secret.name.clone())
+                .with_metadata("secret_expires_at", secret.secret_expires_at.to_rfc3339())
+                .with_metadata("expired", secret.expired.to_string());
+                event_actor.do_send(EmitEvent { event });
+            }
+        }
+        Ok(warned)
+    }
+}
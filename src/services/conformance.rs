@@ -0,0 +1,613 @@
+//! Protocol self-test that exercises the server's own HTTP endpoints.
+//!
+//! Each check drives the public surface the way a client would and records
+//! pass/fail with a short reason, so regressions in discovery, PKCE, error
+//! codes or token formats show up as a failing line rather than a bug report.
+
+use crate::actors::{AuthActor, CreateAuthorizationCode};
+use crate::cache::CacheInvalidator;
+use crate::db::Database;
+use actix::Addr;
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Redirect URI registered for the throwaway conformance client
+const REDIRECT_URI: &str = "http://localhost/conformance/callback";
+
+/// User the checker's authorization codes are issued to
+const CONFORMANCE_USER: &str = "conformance-self-test";
+
+/// Metadata OpenID Connect Discovery 1.0 marks as REQUIRED
+const REQUIRED_DISCOVERY_FIELDS: &[&str] = &[
+    "issuer",
+    "authorization_endpoint",
+    "token_endpoint",
+    "jwks_uri",
+    "response_types_supported",
+    "subject_types_supported",
+    "id_token_signing_alg_values_supported",
+];
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConformanceReport {
+    pub passed: usize,
+    pub failed: usize,
+    pub checks: Vec<CheckResult>,
+}
+
+type CheckOutcome = Result<(), String>;
+
+/// The throwaway client the flow checks run as
+struct ConformanceClient {
+    id: String,
+    secret: String,
+}
+
+pub struct ConformanceChecker {
+    base_url: String,
+    http: reqwest::Client,
+    db: Arc<Database>,
+    auth_actor: Addr<AuthActor>,
+    invalidator: Arc<CacheInvalidator>,
+    checks: Vec<CheckResult>,
+}
+
+impl ConformanceChecker {
+    /// `base_url` must reach this server, e.g. `http://127.0.0.1:8080`
+    pub fn new(
+        base_url: String,
+        db: Arc<Database>,
+        auth_actor: Addr<AuthActor>,
+        invalidator: Arc<CacheInvalidator>,
+    ) -> Self {
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("HTTP client with default TLS settings");
+
+        Self {
+            base_url,
+            http,
+            db,
+            auth_actor,
+            invalidator,
+            checks: Vec::new(),
+        }
+    }
+
+    /// Run every check. A temporary client is registered for the flow checks
+    /// and deleted again afterwards.
+    pub async fn run(mut self) -> ConformanceReport {
+        self.check_discovery().await;
+        self.check_client_authentication().await;
+
+        match self.register_client().await {
+            Ok(client) => {
+                self.record("client_registration", Ok(()));
+                self.check_token_errors(&client).await;
+                self.check_code_flow(&client).await;
+                match self.db.delete_client(&client.id, None).await {
+                    // Its introspected tokens are cached as active
+                    Ok(tokens) => self.invalidator.forget_tokens(&tokens).await,
+                    Err(e) => {
+                        tracing::warn!("Failed to remove conformance client {}: {:?}", client.id, e)
+                    }
+                }
+                match self.db.delete_user(CONFORMANCE_USER, Utc::now()).await {
+                    Ok(revoked) => {
+                        let tokens = revoked.map(|revoked| revoked.revoked_tokens);
+                        self.invalidator
+                            .forget_tokens(&tokens.unwrap_or_default())
+                            .await
+                    }
+                    Err(e) => tracing::warn!("Failed to remove conformance user: {:?}", e),
+                }
+            }
+            Err(e) => self.record("client_registration", Err(e)),
+        }
+
+        let passed = self.checks.iter().filter(|c| c.passed).count();
+        ConformanceReport {
+            passed,
+            failed: self.checks.len() - passed,
+            checks: self.checks,
+        }
+    }
+
+    fn record(&mut self, name: &'static str, outcome: CheckOutcome) {
+        self.checks.push(CheckResult {
+            name,
+            passed: outcome.is_ok(),
+            detail: outcome.err(),
+        });
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    async fn check_discovery(&mut self) {
+        let doc = match self.get_json("/.well-known/openid-configuration").await {
+            Ok(doc) => {
+                self.record("discovery_document", Ok(()));
+                doc
+            }
+            Err(e) => {
+                self.record("discovery_document", Err(e));
+                return;
+            }
+        };
+
+        let missing: Vec<&str> = REQUIRED_DISCOVERY_FIELDS
+            .iter()
+            .copied()
+            .filter(|field| doc.get(*field).is_none())
+            .collect();
+        let outcome = if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("missing {}", missing.join(", ")))
+        };
+        self.record("discovery_required_metadata", outcome);
+
+        let issuer = doc["issuer"].as_str().unwrap_or_default();
+        let outside: Vec<&str> = doc
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(key, _)| key.ends_with("_endpoint") || key.as_str() == "jwks_uri")
+            .filter_map(|(key, value)| {
+                let url = value.as_str()?;
+                (!url.starts_with(issuer)).then_some(key.as_str())
+            })
+            .collect();
+        let outcome = if issuer.is_empty() {
+            Err("no issuer".to_string())
+        } else if outside.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("not under issuer: {}", outside.join(", ")))
+        };
+        self.record("discovery_endpoints_under_issuer", outcome);
+
+        let s256 = doc["code_challenge_methods_supported"]
+            .as_array()
+            .is_some_and(|methods| methods.iter().any(|m| m == "S256"));
+        self.record(
+            "discovery_advertises_pkce_s256",
+            s256.then_some(())
+                .ok_or_else(|| "S256 not in code_challenge_methods_supported".to_string()),
+        );
+    }
+
+    async fn check_client_authentication(&mut self) {
+        let outcome = self.expect_token_error(&[], "invalid_request").await;
+        self.record("token_malformed_request", outcome);
+
+        let outcome = self
+            .expect_token_error(
+                &[
+                    ("grant_type", "client_credentials"),
+                    ("client_id", "conformance"),
+                    ("client_secret", "not-a-real-secret"),
+                ],
+                "invalid_client",
+            )
+            .await;
+        self.record("token_requires_client_authentication", outcome);
+    }
+
+    async fn check_token_errors(&mut self, client: &ConformanceClient) {
+        let outcome = self
+            .expect_token_error(
+                &[
+                    ("grant_type", "urn:example:unknown"),
+                    ("client_id", &client.id),
+                    ("client_secret", &client.secret),
+                ],
+                "unsupported_grant_type",
+            )
+            .await;
+        self.record("token_rejects_unsupported_grant_type", outcome);
+
+        let outcome = self
+            .expect_token_error(
+                &[
+                    ("grant_type", "authorization_code"),
+                    ("client_id", &client.id),
+                    ("client_secret", &client.secret),
+                    ("redirect_uri", REDIRECT_URI),
+                ],
+                "invalid_request",
+            )
+            .await;
+        self.record("token_requires_code", outcome);
+
+        let outcome = self
+            .expect_token_error(
+                &code_exchange(client, "not-a-real-code", None),
+                "invalid_grant",
+            )
+            .await;
+        self.record("token_rejects_unknown_code", outcome);
+    }
+
+    async fn check_code_flow(&mut self, client: &ConformanceClient) {
+        let client_id = client.id.as_str();
+        let (verifier, challenge) = pkce_pair();
+
+        let outcome = self
+            .check_authorize_requires_login(client_id, &challenge)
+            .await;
+        self.record("authorize_requires_login", outcome);
+
+        let outcome = match self.authorize(client_id, &challenge).await {
+            Ok(code) => {
+                self.expect_token_error(&code_exchange(client, &code, None), "invalid_grant")
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        self.record("pkce_rejects_missing_verifier", outcome);
+
+        let outcome = match self.authorize(client_id, &challenge).await {
+            Ok(code) => {
+                self.expect_token_error(
+                    &code_exchange(client, &code, Some("wrong-verifier")),
+                    "invalid_grant",
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        self.record("pkce_rejects_wrong_verifier", outcome);
+
+        let code = match self.authorize(client_id, &challenge).await {
+            Ok(code) => code,
+            Err(e) => {
+                self.record("pkce_accepts_valid_verifier", Err(e));
+                return;
+            }
+        };
+        let token = match self
+            .post_form(
+                "/oauth/token",
+                &code_exchange(client, &code, Some(&verifier)),
+            )
+            .await
+        {
+            Ok((200, body)) => {
+                self.record("pkce_accepts_valid_verifier", Ok(()));
+                body
+            }
+            Ok((status, body)) => {
+                self.record(
+                    "pkce_accepts_valid_verifier",
+                    Err(format!("HTTP {}: {}", status, body)),
+                );
+                return;
+            }
+            Err(e) => {
+                self.record("pkce_accepts_valid_verifier", Err(e));
+                return;
+            }
+        };
+
+        self.record("token_response_format", check_token_response(&token));
+        let access_token = token["access_token"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        self.record("access_token_is_jwt", check_jwt_shape(&access_token));
+
+        let outcome = self.expect_active(&access_token, true).await;
+        self.record("introspection_active_token", outcome);
+
+        let outcome = self.expect_active("not-a-real-token", false).await;
+        self.record("introspection_unknown_token", outcome);
+
+        let outcome = match self
+            .post_form("/oauth/revoke", &[("token", access_token.as_str())])
+            .await
+        {
+            Ok((200, _)) => self.expect_active(&access_token, false).await,
+            Ok((status, _)) => Err(format!("revocation returned HTTP {}", status)),
+            Err(e) => Err(e),
+        };
+        self.record("revocation_deactivates_token", outcome);
+
+        self.check_code_replay(client, &challenge, &verifier).await;
+    }
+
+    /// Redeem a fresh code twice: the second exchange is refused, and takes
+    /// the token the first one issued with it
+    async fn check_code_replay(
+        &mut self,
+        client: &ConformanceClient,
+        challenge: &str,
+        verifier: &str,
+    ) {
+        let exchanged = match self.authorize(&client.id, challenge).await {
+            Ok(code) => self
+                .post_form(
+                    "/oauth/token",
+                    &code_exchange(client, &code, Some(verifier)),
+                )
+                .await
+                .and_then(
+                    |(status, body)| match (status, body["access_token"].as_str()) {
+                        (200, Some(access_token)) => Ok((code, access_token.to_string())),
+                        _ => Err(format!("HTTP {}: {}", status, body)),
+                    },
+                ),
+            Err(e) => Err(e),
+        };
+        let (code, access_token) = match exchanged {
+            Ok(exchanged) => exchanged,
+            Err(e) => {
+                self.record("authorization_code_single_use", Err(e));
+                return;
+            }
+        };
+
+        let outcome = self
+            .expect_token_error(
+                &code_exchange(client, &code, Some(verifier)),
+                "invalid_grant",
+            )
+            .await;
+        self.record("authorization_code_single_use", outcome);
+
+        let outcome = self.expect_active(&access_token, false).await;
+        self.record("authorization_code_replay_revokes_tokens", outcome);
+    }
+
+    async fn register_client(&self) -> Result<ConformanceClient, String> {
+        let resp = self
+            .http
+            .post(self.url("/clients/register"))
+            .json(&serde_json::json!({
+                "client_name": "Conformance self-test",
+                "redirect_uris": [REDIRECT_URI],
+                "grant_types": ["authorization_code"],
+                "scope": "read",
+            }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = resp.status();
+        let body: Value = resp.json().await.map_err(|e| e.to_string())?;
+        if !status.is_success() {
+            return Err(format!("HTTP {}: {}", status.as_u16(), body));
+        }
+
+        let field = |name: &str| {
+            body[name]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("registration response has no {}", name))
+        };
+        Ok(ConformanceClient {
+            id: field("client_id")?,
+            secret: field("client_secret")?,
+        })
+    }
+
+    /// An authorization request without a session is sent to log in
+    async fn check_authorize_requires_login(
+        &self,
+        client_id: &str,
+        code_challenge: &str,
+    ) -> CheckOutcome {
+        let resp = self
+            .http
+            .get(self.url("/oauth/authorize"))
+            .query(&[
+                ("response_type", "code"),
+                ("client_id", client_id),
+                ("redirect_uri", REDIRECT_URI),
+                ("scope", "read"),
+                ("state", "conformance"),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ])
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let location = resp
+            .headers()
+            .get("Location")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !resp.status().is_redirection() || !location.starts_with("/auth/login") {
+            return Err(format!(
+                "authorize returned HTTP {} to {:?}",
+                resp.status().as_u16(),
+                location
+            ));
+        }
+        Ok(())
+    }
+
+    /// Issue a code as the authorize endpoint does once the user approves;
+    /// the checker has no user to sign in as
+    async fn authorize(&self, client_id: &str, code_challenge: &str) -> Result<String, String> {
+        self.db
+            .ensure_user(CONFORMANCE_USER, "conformance@localhost", Utc::now())
+            .await
+            .map_err(|e| e.to_string())?;
+        let code = self
+            .auth_actor
+            .send(CreateAuthorizationCode {
+                client_id: client_id.to_string(),
+                user_id: CONFORMANCE_USER.to_string(),
+                redirect_uri: REDIRECT_URI.to_string(),
+                scope: "read".to_string(),
+                code_challenge: Some(code_challenge.to_string()),
+                code_challenge_method: Some("S256".to_string()),
+                nonce: None,
+                auth_time: None,
+                flow_id: None,
+            })
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+        Ok(code.code)
+    }
+
+    async fn get_json(&self, path: &str) -> Result<Value, String> {
+        let resp = self
+            .http
+            .get(self.url(path))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("HTTP {}", resp.status().as_u16()));
+        }
+        resp.json().await.map_err(|e| e.to_string())
+    }
+
+    async fn post_form(&self, path: &str, form: &[(&str, &str)]) -> Result<(u16, Value), String> {
+        let resp = self
+            .http
+            .post(self.url(path))
+            .form(form)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = resp.status().as_u16();
+        let body = resp.json().await.unwrap_or(Value::Null);
+        Ok((status, body))
+    }
+
+    /// Error responses must be a 4xx JSON body with the expected `error` code
+    async fn expect_token_error(&self, form: &[(&str, &str)], expected: &str) -> CheckOutcome {
+        let (status, body) = self.post_form("/oauth/token", form).await?;
+        if !(400..500).contains(&status) {
+            return Err(format!("expected 4xx, got HTTP {}", status));
+        }
+        match body["error"].as_str() {
+            Some(error) if error == expected => Ok(()),
+            Some(error) => Err(format!("expected error {}, got {}", expected, error)),
+            None => Err("error response has no JSON error field".to_string()),
+        }
+    }
+
+    async fn expect_active(&self, token: &str, expected: bool) -> CheckOutcome {
+        let (status, body) = self
+            .post_form("/oauth/introspect", &[("token", token)])
+            .await?;
+        if status != 200 {
+            return Err(format!("introspection returned HTTP {}", status));
+        }
+        match body["active"].as_bool() {
+            Some(active) if active == expected => Ok(()),
+            Some(active) => Err(format!("expected active={}, got {}", expected, active)),
+            None => Err("introspection response has no active field".to_string()),
+        }
+    }
+}
+
+fn code_exchange<'a>(
+    client: &'a ConformanceClient,
+    code: &'a str,
+    code_verifier: Option<&'a str>,
+) -> Vec<(&'a str, &'a str)> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", REDIRECT_URI),
+        ("client_id", &client.id),
+        ("client_secret", &client.secret),
+    ];
+    if let Some(verifier) = code_verifier {
+        form.push(("code_verifier", verifier));
+    }
+    form
+}
+
+fn pkce_pair() -> (String, String) {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    let verifier = general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+    let challenge = general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    (verifier, challenge)
+}
+
+/// RFC 6749 section 5.1 fields
+fn check_token_response(body: &Value) -> CheckOutcome {
+    if body["access_token"].as_str().is_none_or(str::is_empty) {
+        return Err("missing access_token".to_string());
+    }
+    if !body["token_type"]
+        .as_str()
+        .is_some_and(|t| t.eq_ignore_ascii_case("bearer"))
+    {
+        return Err(format!(
+            "token_type is {}, expected Bearer",
+            body["token_type"]
+        ));
+    }
+    if body["expires_in"].as_i64().is_none_or(|e| e <= 0) {
+        return Err("expires_in missing or not positive".to_string());
+    }
+    Ok(())
+}
+
+fn check_jwt_shape(token: &str) -> CheckOutcome {
+    let segments: Vec<&str> = token.split('.').collect();
+    if segments.len() != 3 {
+        return Err(format!("expected 3 segments, got {}", segments.len()));
+    }
+    let header = general_purpose::URL_SAFE_NO_PAD
+        .decode(segments[0])
+        .map_err(|_| "header is not base64url".to_string())?;
+    let header: Value =
+        serde_json::from_slice(&header).map_err(|_| "header is not JSON".to_string())?;
+    if header["alg"].as_str().is_none_or(|alg| alg == "none") {
+        return Err("header has no signing alg".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_token_response() {
+        let ok =
+            serde_json::json!({"access_token": "a", "token_type": "Bearer", "expires_in": 3600});
+        assert!(check_token_response(&ok).is_ok());
+
+        let bad_type =
+            serde_json::json!({"access_token": "a", "token_type": "mac", "expires_in": 3600});
+        assert!(check_token_response(&bad_type).is_err());
+
+        let no_expiry = serde_json::json!({"access_token": "a", "token_type": "bearer"});
+        assert!(check_token_response(&no_expiry).is_err());
+    }
+
+    #[test]
+    fn test_check_jwt_shape() {
+        let header = general_purpose::URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#);
+        assert!(check_jwt_shape(&format!("{}.e30.sig", header)).is_ok());
+
+        let none = general_purpose::URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#);
+        assert!(check_jwt_shape(&format!("{}.e30.", none)).is_err());
+        assert!(check_jwt_shape("opaque-token").is_err());
+    }
+}
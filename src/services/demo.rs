@@ -0,0 +1,185 @@
+//! Demo mode (`OAUTH2_MODE=demo`): a server to try out in one command.
+//!
+//! On start a demo user and two clients with well-known credentials are
+//! seeded, consent is approved on the user's behalf and the playground at
+//! `/demo` walks through an authorization code flow with them. Because
+//! anyone can read those credentials here, a database is marked with the
+//! mode the server runs in, and demo mode refuses one marked production.
+
+use crate::config::DeploymentMode;
+use crate::db::Database;
+use crate::models::{Client, OAuth2Error, SocialUserInfo, User};
+use crate::services::hash_password;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Deployment setting holding the mode the database is used in
+const MODE_SETTING: &str = "mode";
+
+pub const DEMO_USERNAME: &str = "demo";
+pub const DEMO_PASSWORD: &str = "demo-password";
+pub const DEMO_EMAIL: &str = "demo@example.com";
+
+/// Where the playground's authorization code flow comes back to
+pub const DEMO_CALLBACK_PATH: &str = "/demo/callback";
+
+/// A client seeded in demo mode
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DemoClient {
+    pub client_id: &'static str,
+    pub client_secret: &'static str,
+    pub name: &'static str,
+    pub grant_types: &'static [&'static str],
+    pub scope: &'static str,
+    /// Redirected to the playground's callback
+    pub redirects: bool,
+}
+
+/// The web application the playground signs in to
+pub const DEMO_WEB_CLIENT: DemoClient = DemoClient {
+    client_id: "demo-web",
+    client_secret: "demo-web-secret",
+    name: "Demo Web App",
+    grant_types: &["authorization_code", "refresh_token", "password"],
+    scope: "openid profile email read write",
+    redirects: true,
+};
+
+pub const DEMO_CLIENTS: &[DemoClient] = &[
+    DEMO_WEB_CLIENT,
+    DemoClient {
+        client_id: "demo-service",
+        client_secret: "demo-service-secret",
+        name: "Demo Backend Service",
+        grant_types: &["client_credentials"],
+        scope: "read write",
+        redirects: false,
+    },
+];
+
+/// The demo user as a signed-in session sees them. The user row has the
+/// same id, so codes from the playground and tokens from the password grant
+/// belong to the one user.
+pub fn demo_user() -> SocialUserInfo {
+    SocialUserInfo {
+        provider: "demo".to_string(),
+        provider_user_id: DEMO_USERNAME.to_string(),
+        email: DEMO_EMAIL.to_string(),
+        name: Some("Demo User".to_string()),
+        picture: None,
+        upstream_claims: Default::default(),
+        roles: Vec::new(),
+        groups: Vec::new(),
+        claims: Default::default(),
+    }
+}
+
+/// Redirect URIs for the playground's callback on a server listening on
+/// `host:port`, as a browser on the same machine would reach it
+pub fn demo_redirect_uris(host: &str, port: u16) -> Vec<String> {
+    let mut hosts = vec!["localhost", "127.0.0.1"];
+    if !matches!(host, "0.0.0.0" | "::" | "[::]") && !hosts.contains(&host) {
+        hosts.push(host);
+    }
+    let mut uris: Vec<String> = hosts
+        .into_iter()
+        .map(|host| format!("http://{}:{}{}", host, port, DEMO_CALLBACK_PATH))
+        .collect();
+    // For a client app run next to the server
+    uris.push("http://localhost:3000/callback".to_string());
+    uris
+}
+
+/// Check the database may be used in `mode` and mark it as used so. Demo
+/// mode refuses a database a production server has used.
+pub async fn claim_database(
+    db: &Database,
+    mode: DeploymentMode,
+    now: DateTime<Utc>,
+) -> Result<(), String> {
+    let marked = db
+        .get_deployment_setting(MODE_SETTING)
+        .await
+        .map_err(|e| e.to_string())?;
+    if mode.is_demo() && marked.as_deref() == Some(DeploymentMode::Production.as_str()) {
+        return Err("OAUTH2_MODE=demo refuses to start on a production database: demo mode seeds well-known credentials. Point OAUTH2_DATABASE_URL at a database of its own.".to_string());
+    }
+    if marked.as_deref() != Some(mode.as_str()) {
+        if let Some(marked) = &marked {
+            tracing::warn!(
+                "Database was last used in {} mode; now running in {} mode",
+                marked,
+                mode.as_str()
+            );
+        }
+        db.set_deployment_setting(MODE_SETTING, mode.as_str(), now)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Create the demo user and clients unless they exist, pointing the web
+/// client at `redirect_uris`
+pub async fn seed_demo_data(
+    db: &Database,
+    redirect_uris: &[String],
+    now: DateTime<Utc>,
+) -> Result<(), OAuth2Error> {
+    if db.get_user_by_username(DEMO_USERNAME).await?.is_none() {
+        let mut user = User::new(
+            DEMO_USERNAME.to_string(),
+            hash_password(DEMO_PASSWORD)?,
+            DEMO_EMAIL.to_string(),
+        );
+        user.id = demo_user().subject();
+        user.created_at = now;
+        user.updated_at = now;
+        db.save_user(&user).await?;
+    }
+
+    for demo in DEMO_CLIENTS {
+        let redirect_uris = if demo.redirects {
+            redirect_uris.to_vec()
+        } else {
+            Vec::new()
+        };
+        if db.get_client(demo.client_id).await?.is_some() {
+            // The server may have moved to another port since
+            db.update_client_redirect_uris(demo.client_id, &redirect_uris)
+                .await?;
+            continue;
+        }
+        let mut client = Client::new(
+            demo.client_id.to_string(),
+            demo.client_secret.to_string(),
+            redirect_uris,
+            demo.grant_types.iter().map(|g| g.to_string()).collect(),
+            demo.scope.to_string(),
+            demo.name.to_string(),
+        );
+        client.created_at = now;
+        client.updated_at = now;
+        db.save_client(&client).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirect_uris_cover_local_hosts() {
+        assert_eq!(
+            demo_redirect_uris("0.0.0.0", 8080),
+            vec![
+                "http://localhost:8080/demo/callback",
+                "http://127.0.0.1:8080/demo/callback",
+                "http://localhost:3000/callback",
+            ]
+        );
+        assert!(demo_redirect_uris("demo.internal", 9000)
+            .contains(&"http://demo.internal:9000/demo/callback".to_string()));
+    }
+}
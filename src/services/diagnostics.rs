@@ -0,0 +1,332 @@
+//! Startup self-check of the environment the server runs in.
+//!
+//! The checks cover what the server cannot fix for itself: the database and
+//! its schema, the signing keys, the external event backends, the clock and
+//! the configuration. Each is `ok`, a `warning` worth an operator's look, or
+//! `critical`, which stops a production server from starting. The report is
+//! logged on boot and served, freshly run, at `/admin/api/diagnostics`.
+
+use crate::clock::SharedClock;
+use crate::config::{Config, DeploymentMode};
+use crate::db::Database;
+use crate::events::EventBackends;
+use crate::keys::SharedKeys;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Newest migration in `migrations/sql`; a database behind it lacks tables
+/// or columns this build uses
+pub const SCHEMA_VERSION: u32 = 42;
+
+/// Seconds the server's clock may differ from the system clock before it is
+/// reported
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// How each check came out, in increasing order of concern
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiagnosticCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiagnosticsReport {
+    pub mode: &'static str,
+    pub checked_at: DateTime<Utc>,
+    /// The worst status of any check
+    pub status: CheckStatus,
+    pub checks: Vec<DiagnosticCheck>,
+}
+
+impl DiagnosticsReport {
+    /// Log one line per check at the level its status calls for
+    pub fn log(&self) {
+        for check in &self.checks {
+            match check.status {
+                CheckStatus::Ok => {
+                    tracing::info!(check = check.name, status = "ok", "{}", check.detail)
+                }
+                CheckStatus::Warning => {
+                    tracing::warn!(check = check.name, status = "warning", "{}", check.detail)
+                }
+                CheckStatus::Critical => {
+                    tracing::error!(check = check.name, status = "critical", "{}", check.detail)
+                }
+            }
+        }
+    }
+
+    /// The critical checks, as `name: detail`
+    pub fn critical(&self) -> Vec<String> {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Critical)
+            .map(|check| format!("{}: {}", check.name, check.detail))
+            .collect()
+    }
+}
+
+pub struct Diagnostics {
+    mode: DeploymentMode,
+    db: Arc<Database>,
+    keys: SharedKeys,
+    event_backends: Arc<EventBackends>,
+    clock: SharedClock,
+    config_error: Option<String>,
+    config_warnings: Vec<String>,
+}
+
+impl Diagnostics {
+    pub fn new(
+        config: &Config,
+        db: Arc<Database>,
+        keys: SharedKeys,
+        event_backends: Arc<EventBackends>,
+        clock: SharedClock,
+    ) -> Self {
+        Self {
+            mode: config.mode,
+            db,
+            keys,
+            event_backends,
+            clock,
+            config_error: config.validate_for_production().err(),
+            config_warnings: config.warnings(),
+        }
+    }
+
+    /// Run every check. Event backends are health-checked as part of it.
+    pub async fn run(&self) -> DiagnosticsReport {
+        let checks = vec![
+            self.check_database().await,
+            self.check_schema().await,
+            self.check_signing_keys(),
+            self.check_event_backends().await,
+            self.check_clock(),
+            self.check_configuration(),
+        ];
+        DiagnosticsReport {
+            mode: self.mode.as_str(),
+            checked_at: self.clock.now(),
+            status: checks
+                .iter()
+                .map(|check| check.status)
+                .max()
+                .unwrap_or(CheckStatus::Ok),
+            checks,
+        }
+    }
+
+    async fn check_database(&self) -> DiagnosticCheck {
+        let started = Instant::now();
+        let (status, detail) = match self.db.init().await {
+            Ok(()) => (
+                CheckStatus::Ok,
+                format!("Connected in {} ms", started.elapsed().as_millis()),
+            ),
+            Err(e) => (CheckStatus::Critical, format!("Cannot query: {}", e)),
+        };
+        DiagnosticCheck {
+            name: "database",
+            status,
+            detail,
+        }
+    }
+
+    async fn check_schema(&self) -> DiagnosticCheck {
+        let (status, detail) = match self.db.schema_version().await {
+            Ok(Some(version)) if version < SCHEMA_VERSION => (
+                CheckStatus::Critical,
+                format!(
+                    "Schema is at V{}, this build needs V{}; run the migrations",
+                    version, SCHEMA_VERSION
+                ),
+            ),
+            Ok(Some(version)) if version > SCHEMA_VERSION => (
+                CheckStatus::Warning,
+                format!(
+                    "Schema is at V{}, newer than this build's V{}; was a newer version rolled back?",
+                    version, SCHEMA_VERSION
+                ),
+            ),
+            Ok(Some(version)) => (CheckStatus::Ok, format!("Schema is at V{}", version)),
+            Ok(None) => (
+                CheckStatus::Warning,
+                format!(
+                    "No Flyway history; cannot tell whether the schema is at V{}",
+                    SCHEMA_VERSION
+                ),
+            ),
+            Err(e) => (
+                CheckStatus::Critical,
+                format!("Cannot read the schema version: {}", e),
+            ),
+        };
+        DiagnosticCheck {
+            name: "schema",
+            status,
+            detail,
+        }
+    }
+
+    /// Sign a token and verify it again with the published key
+    fn check_signing_keys(&self) -> DiagnosticCheck {
+        let probe = json!({ "sub": "diagnostics" });
+        let mut validation = self.keys.validation();
+        validation.validate_exp = false;
+        validation.required_spec_claims.clear();
+        let outcome = self.keys.encode(&probe, None).and_then(|token| {
+            jsonwebtoken::decode::<serde_json::Value>(&token, self.keys.decoding_key(), &validation)
+        });
+        let (status, detail) = match outcome {
+            Ok(_) => (
+                CheckStatus::Ok,
+                format!(
+                    "{:?} key signs and verifies{}",
+                    self.keys.algorithm(),
+                    self.keys
+                        .kid()
+                        .map(|kid| format!(" (kid {})", kid))
+                        .unwrap_or_default()
+                ),
+            ),
+            Err(e) => (
+                CheckStatus::Critical,
+                format!(
+                    "{:?} key cannot sign and verify: {}",
+                    self.keys.algorithm(),
+                    e
+                ),
+            ),
+        };
+        DiagnosticCheck {
+            name: "signing_keys",
+            status,
+            detail,
+        }
+    }
+
+    /// A degraded backend buffers its events, so it is never critical
+    async fn check_event_backends(&self) -> DiagnosticCheck {
+        self.event_backends.check().await;
+        let statuses = self.event_backends.statuses();
+        let degraded: Vec<&str> = statuses
+            .iter()
+            .filter(|status| status.degraded)
+            .map(|status| status.plugin.as_str())
+            .collect();
+        let (status, detail) = if statuses.is_empty() {
+            (
+                CheckStatus::Ok,
+                "No external event backends configured".to_string(),
+            )
+        } else if degraded.is_empty() {
+            (
+                CheckStatus::Ok,
+                format!("{} external backends reachable", statuses.len()),
+            )
+        } else {
+            (
+                CheckStatus::Warning,
+                format!("Unreachable, buffering events: {}", degraded.join(", ")),
+            )
+        };
+        DiagnosticCheck {
+            name: "event_backends",
+            status,
+            detail,
+        }
+    }
+
+    /// A clock far in the past expires nothing that should expire; one that
+    /// disagrees with the system clock skews every token's lifetime
+    fn check_clock(&self) -> DiagnosticCheck {
+        let now = self.clock.now();
+        let floor = Utc
+            .with_ymd_and_hms(2024, 1, 1, 0, 0, 0)
+            .single()
+            .expect("valid date");
+        let skew = (now - Utc::now()).num_seconds();
+        let (status, detail) = if now < floor {
+            (
+                CheckStatus::Critical,
+                format!(
+                    "Clock reads {}, before this build existed",
+                    now.to_rfc3339()
+                ),
+            )
+        } else if skew.abs() > MAX_CLOCK_SKEW_SECS {
+            (
+                CheckStatus::Warning,
+                format!("Clock is {} s off the system clock", skew),
+            )
+        } else {
+            (CheckStatus::Ok, format!("Clock reads {}", now.to_rfc3339()))
+        };
+        DiagnosticCheck {
+            name: "clock",
+            status,
+            detail,
+        }
+    }
+
+    /// What production refuses is critical there and a warning in demo mode
+    fn check_configuration(&self) -> DiagnosticCheck {
+        let status = match &self.config_error {
+            Some(_) if self.mode.is_demo() => CheckStatus::Warning,
+            Some(_) => CheckStatus::Critical,
+            None if !self.config_warnings.is_empty() => CheckStatus::Warning,
+            None => CheckStatus::Ok,
+        };
+        let problems: Vec<&str> = self
+            .config_error
+            .iter()
+            .chain(&self.config_warnings)
+            .map(String::as_str)
+            .collect();
+        let detail = if problems.is_empty() {
+            "No problems found".to_string()
+        } else {
+            problems.join("; ")
+        };
+        DiagnosticCheck {
+            name: "configuration",
+            status,
+            detail,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_version_is_the_newest_migration() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations/sql");
+        let newest = std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| {
+                let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+                name.strip_prefix('V')?
+                    .split("__")
+                    .next()?
+                    .parse::<u32>()
+                    .ok()
+            })
+            .max();
+        assert_eq!(newest, Some(SCHEMA_VERSION));
+    }
+}
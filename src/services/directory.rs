@@ -0,0 +1,94 @@
+//! Checking users' passwords against an external user store.
+//!
+//! [`UserAuthenticator`] is the extension point: the bundled
+//! [`LdapAuthenticator`] binds to an LDAP server or Active Directory as the
+//! user. Whatever checks the password, the tokens are still ours; the
+//! directory only vouches for who the user is and which groups they are in.
+//! Groups map to roles through `OAUTH2_LDAP_GROUP_ROLES`, and roles reach the
+//! authorization policy alongside the rest of the request.
+
+use crate::config::LdapConfig;
+use crate::models::OAuth2Error;
+use crate::services::LdapAuthenticator;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// A user a directory signed in
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DirectoryUser {
+    /// Our subject for the user, e.g. `ldap:alice`
+    pub subject: String,
+    pub username: String,
+    pub email: Option<String>,
+    pub name: Option<String>,
+    /// DNs of the groups the user is in
+    pub groups: Vec<String>,
+    /// Roles the groups map to
+    pub roles: Vec<String>,
+}
+
+#[async_trait]
+pub trait UserAuthenticator: Send + Sync {
+    /// The user `username` signs in as, or `None` when there is no such user
+    /// or the password is wrong. `Err` means the directory could not answer.
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<DirectoryUser>, OAuth2Error>;
+
+    fn name(&self) -> &str;
+}
+
+/// Roles for members of `groups`, in the order the mapping lists them.
+/// Group DNs compare case-insensitively, as directories treat them.
+pub fn map_group_roles(mapping: &[(String, String)], groups: &[String]) -> Vec<String> {
+    let mut roles: Vec<String> = Vec::new();
+    for (group, role) in mapping {
+        let member = groups
+            .iter()
+            .any(|dn| dn.trim().eq_ignore_ascii_case(group));
+        if member && !roles.contains(role) {
+            roles.push(role.clone());
+        }
+    }
+    roles
+}
+
+/// The configured directory, if there is one
+pub fn open_user_authenticator(
+    config: &LdapConfig,
+) -> Result<Option<Arc<dyn UserAuthenticator>>, String> {
+    if config.url.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(Arc::new(LdapAuthenticator::new(config.clone())?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_groups_map_to_roles() {
+        let mapping = vec![
+            (
+                "cn=admins,dc=example,dc=com".to_string(),
+                "admin".to_string(),
+            ),
+            (
+                "cn=support,dc=example,dc=com".to_string(),
+                "support".to_string(),
+            ),
+            ("cn=ops,dc=example,dc=com".to_string(), "admin".to_string()),
+        ];
+        let groups = vec![
+            "CN=Ops,DC=example,DC=com".to_string(),
+            "cn=admins,dc=example,dc=com".to_string(),
+            "cn=other,dc=example,dc=com".to_string(),
+        ];
+        assert_eq!(map_group_roles(&mapping, &groups), vec!["admin"]);
+        assert!(map_group_roles(&mapping, &[]).is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+//! Account deletion and erasure of personal data.
+//!
+//! Deleting an account, by the user or an admin, disables it straight away
+//! and schedules its erasure after a grace period, during which an admin can
+//! still call it off. Erasure then anonymizes the subject everywhere it is
+//! recorded (see [`Database::erase_subject`]), keeping counters and the
+//! shape of the audit trail but not who it was about.
+
+use crate::cache::CacheInvalidator;
+use crate::clock::SharedClock;
+use crate::config::AccountDeletionConfig;
+use crate::db::Database;
+use crate::events::{
+    event_actor::{EmitEvent, EraseSubject, EventActor},
+    AuthEvent, EventSeverity, EventType,
+};
+use crate::models::{AccountDeletion, AuditChain, ErasureReport, OAuth2Error, RevokedCredentials};
+use actix::Addr;
+use chrono::Duration;
+use std::sync::Arc;
+
+/// Attached to the response of an erasure so the admin audit trail records
+/// the call under the pseudonym rather than the erased subject
+#[derive(Debug, Clone)]
+pub struct ErasedSubject {
+    pub subject: String,
+    pub pseudonym: String,
+}
+
+pub struct AccountEraser {
+    db: Arc<Database>,
+    clock: SharedClock,
+    grace_period: Duration,
+    event_actor: Option<Addr<EventActor>>,
+    audit_chain: Arc<AuditChain>,
+    invalidator: Option<Arc<CacheInvalidator>>,
+}
+
+impl AccountEraser {
+    pub fn new(
+        db: Arc<Database>,
+        clock: SharedClock,
+        config: &AccountDeletionConfig,
+        event_actor: Option<Addr<EventActor>>,
+        audit_chain: Arc<AuditChain>,
+    ) -> Self {
+        Self {
+            db,
+            clock,
+            grace_period: Duration::days(i64::from(config.grace_period_days)),
+            event_actor,
+            audit_chain,
+            invalidator: None,
+        }
+    }
+
+    /// Drop what every replica has cached about the tokens a deletion revokes
+    pub fn with_invalidator(mut self, invalidator: Arc<CacheInvalidator>) -> Self {
+        self.invalidator = Some(invalidator);
+        self
+    }
+
+    pub fn grace_period_days(&self) -> u32 {
+        self.grace_period.num_days() as u32
+    }
+
+    /// Whether the subject's account is disabled pending erasure
+    pub async fn is_pending(&self, subject: &str) -> Result<bool, OAuth2Error> {
+        Ok(self.db.get_account_deletion(subject).await?.is_some())
+    }
+
+    /// Disable the account, revoking everything it holds, and schedule its
+    /// erasure. Asking again leaves the original schedule in place.
+    pub async fn request_deletion(
+        &self,
+        subject: &str,
+        requested_by: &str,
+    ) -> Result<(AccountDeletion, RevokedCredentials), OAuth2Error> {
+        let now = self.clock.now();
+        let deletion = self
+            .db
+            .save_account_deletion(&AccountDeletion::new(
+                subject.to_string(),
+                requested_by,
+                now,
+                self.grace_period,
+            ))
+            .await?;
+
+        // Local users are disabled; federated ones have no account row, and
+        // are kept out by the login check instead
+        let revoked = match self.db.disable_user(subject, now).await? {
+            Some(revoked) => revoked,
+            None => {
+                self.db
+                    .revoke_user_credentials(subject, "account_deleted", now)
+                    .await?
+            }
+        };
+        if let Some(invalidator) = &self.invalidator {
+            invalidator.forget_tokens(&revoked.revoked_tokens).await;
+        }
+
+        tracing::info!(
+            "Account {} scheduled for erasure after {} (requested by {})",
+            subject,
+            deletion.erase_after,
+            requested_by
+        );
+        if let Some(event_actor) = &self.event_actor {
+            let event = AuthEvent::new(
+                EventType::UserDeletionRequested,
+                EventSeverity::Warning,
+                Some(subject.to_string()),
+                None,
+            )
+            .with_metadata("requested_by", deletion.requested_by.clone())
+            .with_metadata("erase_after", deletion.erase_after.to_rfc3339())
+            .with_metadata("tokens", revoked.tokens.to_string());
+            event_actor.do_send(EmitEvent { event });
+        }
+        Ok((deletion, revoked))
+    }
+
+    /// Call off a pending deletion. Returns false if none was pending.
+    pub async fn cancel(&self, subject: &str) -> Result<bool, OAuth2Error> {
+        let cancelled = self
+            .db
+            .cancel_account_deletion(subject, self.clock.now())
+            .await?;
+        if cancelled {
+            tracing::info!("Erasure of {} cancelled", subject);
+        }
+        Ok(cancelled)
+    }
+
+    /// Anonymize the subject now, whether or not a deletion is pending
+    pub async fn erase(&self, subject: &str) -> Result<ErasureReport, OAuth2Error> {
+        let pseudonym = format!("erased:{}", uuid::Uuid::new_v4().simple());
+        let report = self
+            .db
+            .erase_subject(subject, &pseudonym, self.clock.now(), &self.audit_chain)
+            .await?;
+        tracing::info!(
+            "Erased account as {}: {} tokens, {} audit entries",
+            pseudonym,
+            report.tokens,
+            report.audit_entries
+        );
+
+        if let Some(event_actor) = &self.event_actor {
+            event_actor.do_send(EraseSubject {
+                subject: subject.to_string(),
+                pseudonym: pseudonym.clone(),
+            });
+            // Downstream systems need the subject to erase their own copies,
+            // but not the pseudonym, which would tie the two back together
+            let event = AuthEvent::new(
+                EventType::UserErased,
+                EventSeverity::Warning,
+                Some(subject.to_string()),
+                None,
+            );
+            event_actor.do_send(EmitEvent { event });
+        }
+        Ok(report)
+    }
+
+    /// Erase every account whose grace period is over. Returns how many.
+    pub async fn erase_due(&self) -> Result<usize, OAuth2Error> {
+        let due = self.db.list_due_account_deletions(self.clock.now()).await?;
+        for subject in &due {
+            self.erase(subject).await?;
+        }
+        Ok(due.len())
+    }
+}
@@ -0,0 +1,258 @@
+//! Client IP resolution and IP-to-location lookup.
+//!
+//! [`GeoLookup`] is the extension point: the bundled [`StaticGeoLookup`] reads
+//! a CSV of networks, and with the `geoip` feature [`MaxMindGeoLookup`] reads a
+//! MaxMind City database. Anything else (a hosted API, a cache in front of
+//! one) only has to implement `lookup`.
+
+use crate::events::AuthEvent;
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GeoLocation {
+    /// ISO 3166-1 alpha-2 country code
+    pub country: String,
+    pub city: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+pub trait GeoLookup: Send + Sync {
+    fn lookup(&self, ip: IpAddr) -> Option<GeoLocation>;
+
+    fn name(&self) -> &str;
+}
+
+/// Resolves nothing; used when no database is configured
+pub struct NoGeoLookup;
+
+impl GeoLookup for NoGeoLookup {
+    fn lookup(&self, _ip: IpAddr) -> Option<GeoLocation> {
+        None
+    }
+
+    fn name(&self) -> &str {
+        "none"
+    }
+}
+
+/// Network-to-location table. The most specific matching network wins.
+pub struct StaticGeoLookup {
+    networks: Vec<(IpNet, GeoLocation)>,
+}
+
+impl StaticGeoLookup {
+    pub fn new(mut networks: Vec<(IpNet, GeoLocation)>) -> Self {
+        networks.sort_by_key(|(net, _)| std::cmp::Reverse(net.prefix_len()));
+        Self { networks }
+    }
+
+    /// Parse `network,country,city,latitude,longitude` lines. Blank lines and
+    /// lines starting with `#` are skipped; the city may be empty.
+    pub fn from_csv(csv: &str) -> Result<Self, String> {
+        let mut networks = Vec::new();
+        for (number, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let [network, country, city, latitude, longitude] = fields[..] else {
+                return Err(format!("line {}: expected 5 fields", number + 1));
+            };
+            let network: IpNet = network
+                .parse()
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+            let coordinate = |value: &str| {
+                value
+                    .parse::<f64>()
+                    .map_err(|e| format!("line {}: {}", number + 1, e))
+            };
+            networks.push((
+                network,
+                GeoLocation {
+                    country: country.to_uppercase(),
+                    city: (!city.is_empty()).then(|| city.to_string()),
+                    latitude: coordinate(latitude)?,
+                    longitude: coordinate(longitude)?,
+                },
+            ));
+        }
+        Ok(Self::new(networks))
+    }
+}
+
+impl GeoLookup for StaticGeoLookup {
+    fn lookup(&self, ip: IpAddr) -> Option<GeoLocation> {
+        self.networks
+            .iter()
+            .find(|(net, _)| net.contains(&ip))
+            .map(|(_, location)| location.clone())
+    }
+
+    fn name(&self) -> &str {
+        "static"
+    }
+}
+
+/// Reader for MaxMind GeoIP2/GeoLite2 City databases
+#[cfg(feature = "geoip")]
+pub struct MaxMindGeoLookup {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+#[cfg(feature = "geoip")]
+impl MaxMindGeoLookup {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let reader = maxminddb::Reader::open_readfile(path).map_err(|e| e.to_string())?;
+        Ok(Self { reader })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl GeoLookup for MaxMindGeoLookup {
+    fn lookup(&self, ip: IpAddr) -> Option<GeoLocation> {
+        let record: maxminddb::geoip2::City = self.reader.lookup(ip).ok()?;
+        let location = record.location?;
+        Some(GeoLocation {
+            country: record.country?.iso_code?.to_string(),
+            city: record
+                .city
+                .and_then(|city| city.names)
+                .and_then(|names| names.get("en").map(|name| name.to_string())),
+            latitude: location.latitude?,
+            longitude: location.longitude?,
+        })
+    }
+
+    fn name(&self) -> &str {
+        "maxmind"
+    }
+}
+
+/// Open the lookup for a configured database path: `.mmdb` files need the
+/// `geoip` feature, anything else is read as CSV
+pub fn open_geo_lookup(path: &str) -> Result<Arc<dyn GeoLookup>, String> {
+    if path.ends_with(".mmdb") {
+        #[cfg(feature = "geoip")]
+        return Ok(Arc::new(MaxMindGeoLookup::open(path)?));
+        #[cfg(not(feature = "geoip"))]
+        return Err("MaxMind databases require the `geoip` feature".to_string());
+    }
+
+    let csv = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(Arc::new(StaticGeoLookup::from_csv(&csv)?))
+}
+
+/// Where a request came from, as far as we can tell
+#[derive(Debug, Clone, Default)]
+pub struct RequestOrigin {
+    pub ip: Option<IpAddr>,
+    pub location: Option<GeoLocation>,
+}
+
+impl RequestOrigin {
+    /// Record the origin on an event as `client_ip`, `geo_country` and `geo_city`
+    pub fn annotate(&self, mut event: AuthEvent) -> AuthEvent {
+        if let Some(ip) = self.ip {
+            event = event.with_metadata("client_ip", ip.to_string());
+        }
+        if let Some(location) = &self.location {
+            event = event.with_metadata("geo_country", location.country.clone());
+            if let Some(city) = &location.city {
+                event = event.with_metadata("geo_city", city.clone());
+            }
+        }
+        event
+    }
+}
+
+pub struct OriginResolver {
+    lookup: Arc<dyn GeoLookup>,
+    trust_forwarded_for: bool,
+}
+
+impl OriginResolver {
+    pub fn new(lookup: Arc<dyn GeoLookup>, trust_forwarded_for: bool) -> Self {
+        Self {
+            lookup,
+            trust_forwarded_for,
+        }
+    }
+
+    /// The peer address, or the first `X-Forwarded-For`/`Forwarded` hop when
+    /// the server sits behind a trusted proxy
+    pub fn resolve(&self, req: &HttpRequest) -> RequestOrigin {
+        let ip = if self.trust_forwarded_for {
+            req.connection_info()
+                .realip_remote_addr()
+                .and_then(parse_ip)
+        } else {
+            req.peer_addr().map(|addr| addr.ip())
+        };
+
+        RequestOrigin {
+            ip,
+            location: ip.and_then(|ip| self.lookup.lookup(ip)),
+        }
+    }
+}
+
+/// Accept a bare address or one with a port (`1.2.3.4:5678`, `[::1]:80`)
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    value.parse().ok().or_else(|| {
+        value
+            .parse::<std::net::SocketAddr>()
+            .ok()
+            .map(|addr| addr.ip())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSV: &str = "\
+# network,country,city,latitude,longitude
+203.0.113.0/24,us,New York,40.71,-74.01
+203.0.113.128/25,us,Boston,42.36,-71.06
+2001:db8::/32,DE,,52.52,13.40
+";
+
+    #[test]
+    fn test_static_lookup_prefers_most_specific_network() {
+        let lookup = StaticGeoLookup::from_csv(CSV).unwrap();
+
+        let boston = lookup.lookup("203.0.113.200".parse().unwrap()).unwrap();
+        assert_eq!(boston.city.as_deref(), Some("Boston"));
+
+        let new_york = lookup.lookup("203.0.113.5".parse().unwrap()).unwrap();
+        assert_eq!(new_york.country, "US");
+        assert_eq!(new_york.city.as_deref(), Some("New York"));
+
+        let germany = lookup.lookup("2001:db8::1".parse().unwrap()).unwrap();
+        assert_eq!(germany.country, "DE");
+        assert_eq!(germany.city, None);
+
+        assert!(lookup.lookup("198.51.100.1".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_static_lookup_rejects_malformed_lines() {
+        assert!(StaticGeoLookup::from_csv("203.0.113.0/24,US,NYC").is_err());
+        assert!(StaticGeoLookup::from_csv("not-a-network,US,,1,2").is_err());
+        assert!(StaticGeoLookup::from_csv("203.0.113.0/24,US,,north,2").is_err());
+    }
+
+    #[test]
+    fn test_parse_ip_with_port() {
+        assert_eq!(parse_ip("203.0.113.5"), "203.0.113.5".parse().ok());
+        assert_eq!(parse_ip("203.0.113.5:443"), "203.0.113.5".parse().ok());
+        assert_eq!(parse_ip("[2001:db8::1]:80"), "2001:db8::1".parse().ok());
+        assert_eq!(parse_ip("unknown"), None);
+    }
+}
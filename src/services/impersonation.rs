@@ -0,0 +1,285 @@
+//! Admin impersonation tokens.
+//!
+//! An admin can mint a short-lived access token for a user, to reproduce a
+//! problem as that user sees it. The admin is either a signed-in full admin
+//! or holds a token with the [`IMPERSONATE_SCOPE`] permission and is listed
+//! in `impersonation.admins`. The token carries an `act` claim naming the
+//! admin, introspection reports it, and the admin's identity is recorded on
+//! the token row so every impersonation token (or every one an admin minted)
+//! can be revoked in one go. Minting one emits an `impersonation_started`
+//! event on top of the admin audit entry for the call.
+
+use crate::cache::CacheInvalidator;
+use crate::clock::SharedClock;
+use crate::config::ImpersonationConfig;
+use crate::db::Database;
+use crate::events::{
+    event_actor::{EmitEvent, EventActor},
+    AuthEvent, EventSeverity, EventType,
+};
+use crate::keys::SharedKeys;
+use crate::models::{
+    scope::{is_admin_scope, validate_scopes},
+    Claims, ErrorCode, OAuth2Error, SocialUserInfo, Token, DEFAULT_ISSUER,
+};
+use actix::Addr;
+use actix_session::SessionExt;
+use actix_web::{http::header, HttpRequest};
+use std::sync::Arc;
+
+/// Scope an admin's token needs to mint impersonation tokens
+pub const IMPERSONATE_SCOPE: &str = "admin:impersonate";
+
+pub struct Impersonator {
+    db: Arc<Database>,
+    keys: SharedKeys,
+    clock: SharedClock,
+    config: ImpersonationConfig,
+    event_actor: Option<Addr<EventActor>>,
+    issuer: String,
+    session_admins: Vec<String>,
+    invalidator: Option<Arc<CacheInvalidator>>,
+}
+
+impl Impersonator {
+    pub fn new(
+        db: Arc<Database>,
+        keys: SharedKeys,
+        clock: SharedClock,
+        config: ImpersonationConfig,
+        event_actor: Option<Addr<EventActor>>,
+    ) -> Self {
+        Self {
+            db,
+            keys,
+            clock,
+            config,
+            event_actor,
+            issuer: DEFAULT_ISSUER.to_string(),
+            session_admins: Vec::new(),
+            invalidator: None,
+        }
+    }
+
+    /// `iss` of the impersonation tokens issued
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = issuer.into();
+        self
+    }
+
+    /// Subjects of signed-in users who may impersonate without a token, the
+    /// full admins of `admin_access.admins`
+    pub fn with_session_admins(mut self, admins: Vec<String>) -> Self {
+        self.session_admins = admins;
+        self
+    }
+
+    /// Drop what every replica has cached about tokens revoked in bulk
+    pub fn with_invalidator(mut self, invalidator: Arc<CacheInvalidator>) -> Self {
+        self.invalidator = Some(invalidator);
+        self
+    }
+
+    /// The admin making the request, as `user:<id>` or `client:<id>`. With a
+    /// bearer token, it must carry the impersonation permission, its client
+    /// must still be registered for it and its principal must be listed;
+    /// impersonation tokens themselves never qualify. Without one, the
+    /// signed-in user must be a full admin.
+    pub async fn authorize(&self, req: &HttpRequest) -> Result<String, OAuth2Error> {
+        let denied = || {
+            OAuth2Error::access_denied(
+                "Impersonation requires a listed admin with the admin:impersonate scope, or a signed-in admin",
+            )
+        };
+        let bearer = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let Some(bearer) = bearer else {
+            let user_info: Option<String> = req.get_session().get("user_info").unwrap_or(None);
+            return user_info
+                .and_then(|json| serde_json::from_str::<SocialUserInfo>(&json).ok())
+                .map(|user| user.subject())
+                .filter(|subject| self.session_admins.contains(subject))
+                .map(|subject| format!("user:{}", subject))
+                .ok_or_else(denied);
+        };
+
+        let token = self
+            .db
+            .get_token_by_access_token(bearer)
+            .await?
+            .filter(|token| token.is_valid(self.clock.as_ref()) && token.impersonator.is_none())
+            .ok_or_else(denied)?;
+        let has_scope = |scope: &str| scope.split_whitespace().any(|s| s == IMPERSONATE_SCOPE);
+        if !has_scope(&token.scope) {
+            return Err(denied());
+        }
+        let client = self
+            .db
+            .get_client(&token.client_id)
+            .await?
+            .ok_or_else(denied)?;
+        let principal = token.principal();
+        if !has_scope(&client.scope) || !self.config.admins.contains(&principal) {
+            return Err(denied());
+        }
+
+        Ok(principal)
+    }
+
+    /// Mint an access token for `subject` at `client_id`, held by `admin`,
+    /// or `None` if there is no such user. Without a scope the token gets
+    /// every scope the client has, bar admin ones; it lives for `ttl_secs`,
+    /// at most the configured maximum.
+    pub async fn impersonate(
+        &self,
+        admin: &str,
+        subject: &str,
+        client_id: &str,
+        scope: Option<&str>,
+        ttl_secs: Option<u64>,
+    ) -> Result<Option<Token>, OAuth2Error> {
+        if admin == format!("user:{}", subject) {
+            return Err(OAuth2Error::invalid_request(
+                "Admins cannot impersonate themselves",
+            ));
+        }
+        // A client's own tokens name it as their subject, but it is no user
+        if !self.db.subject_exists(subject).await? || self.db.get_client(subject).await?.is_some() {
+            return Ok(None);
+        }
+        let client = self
+            .db
+            .get_client(client_id)
+            .await?
+            .ok_or_else(|| OAuth2Error::invalid_client("Client not found"))?;
+
+        // Never granted, so an impersonation token cannot be used to
+        // impersonate further or to administer the server as the user
+        let scope = match scope {
+            Some(scope) => {
+                if scope.split_whitespace().any(is_admin_scope) {
+                    return Err(OAuth2Error::invalid_scope(
+                        "Impersonation tokens cannot carry admin scopes",
+                    ));
+                }
+                if !validate_scopes(scope, &client.scope) {
+                    return Err(OAuth2Error::invalid_scope(
+                        "Scope exceeds what the client is registered for",
+                    ));
+                }
+                scope.to_string()
+            }
+            None => client
+                .scope
+                .split_whitespace()
+                .filter(|s| !is_admin_scope(s))
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        if scope.is_empty() {
+            return Err(OAuth2Error::invalid_scope("No scope left to grant"));
+        }
+
+        let max_ttl = self.config.max_ttl_secs.max(1);
+        let ttl = i64::try_from(ttl_secs.unwrap_or(max_ttl).clamp(1, max_ttl)).unwrap_or(i64::MAX);
+        let claims = Claims::new(
+            subject.to_string(),
+            client_id.to_string(),
+            scope.clone(),
+            ttl,
+            self.clock.as_ref(),
+        )
+        .with_issuer(&self.issuer)
+        .with_actor(admin);
+        let access_token = claims
+            .encode_access_token(&self.keys)
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+        let token = Token::new(
+            access_token,
+            None,
+            client_id.to_string(),
+            subject.to_string(),
+            scope,
+            ttl,
+            self.clock.as_ref(),
+        )
+        .with_impersonator(admin)
+        .with_jti(claims.jti);
+        self.db.save_token(&token).await?;
+
+        tracing::warn!(
+            "{} is impersonating {} at {} until {}",
+            admin,
+            subject,
+            client_id,
+            token.expires_at
+        );
+        if let Some(event_actor) = &self.event_actor {
+            let event = AuthEvent::new(
+                EventType::ImpersonationStarted,
+                EventSeverity::Warning,
+                Some(subject.to_string()),
+                Some(client_id.to_string()),
+            )
+            .with_metadata("impersonator", admin)
+            .with_metadata("scope", token.scope.clone())
+            .with_metadata("token_id", token.id.clone())
+            .with_metadata("expires_at", token.expires_at.to_rfc3339());
+            event_actor.do_send(EmitEvent { event });
+        }
+        Ok(Some(token))
+    }
+
+    /// Live impersonation tokens, newest first
+    pub async fn active(&self) -> Result<Vec<Token>, OAuth2Error> {
+        self.db.list_impersonation_tokens(self.clock.now()).await
+    }
+
+    /// Revoke every live impersonation token, or only those `impersonator`
+    /// minted. Returns how many.
+    pub async fn revoke_all(&self, impersonator: Option<&str>) -> Result<u64, OAuth2Error> {
+        let tokens = self
+            .db
+            .revoke_impersonation_tokens(impersonator, self.clock.now())
+            .await?;
+        if let Some(invalidator) = &self.invalidator {
+            invalidator.forget_tokens(&tokens).await;
+        }
+        let revoked = tokens.len() as u64;
+        tracing::warn!(
+            "Revoked {} impersonation tokens{}",
+            revoked,
+            impersonator
+                .map(|admin| format!(" minted by {}", admin))
+                .unwrap_or_default()
+        );
+
+        if let Some(event_actor) = &self.event_actor {
+            let mut event =
+                AuthEvent::new(EventType::TokenRevoked, EventSeverity::Warning, None, None)
+                    .with_metadata("reason", "impersonation_revoked")
+                    .with_metadata("tokens", revoked.to_string());
+            if let Some(impersonator) = impersonator {
+                event = event.with_metadata("impersonator", impersonator);
+            }
+            event_actor.do_send(EmitEvent { event });
+        }
+        Ok(revoked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admin_scopes() {
+        assert!(is_admin_scope("admin"));
+        assert!(is_admin_scope(IMPERSONATE_SCOPE));
+        assert!(!is_admin_scope("read"));
+        assert!(!is_admin_scope("administrator"));
+    }
+}
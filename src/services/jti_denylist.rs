@@ -0,0 +1,90 @@
+//! Revoked JWT access tokens, for accepting the rest without a lookup.
+//!
+//! With `OAUTH2_JWT_STATELESS_VALIDATION` on, a JWT access token is accepted
+//! on its signature and claims alone, as long as its `jti` is not on the
+//! denylist. Every revocation copies the jtis of the access tokens it
+//! revokes into the `revoked_jtis` table, where they stay until the tokens
+//! would have expired anyway; that keeps the list as short as the tokens
+//! are long-lived.
+//!
+//! Answers are cached: a revoked jti until the token expires, a live one for
+//! `OAUTH2_JWT_DENYLIST_CACHE_SECS`. Revocations, single or in bulk, drop
+//! the cached answer on every replica, within one poll interval when each
+//! keeps its own cache (see [`crate::cache::CacheInvalidator`]).
+
+use crate::cache::SharedCache;
+use crate::clock::SharedClock;
+use crate::config::JwtConfig;
+use crate::db::Database;
+use crate::keys::SigningKeys;
+use crate::models::{Claims, OAuth2Error};
+use std::sync::Arc;
+use std::time::Duration;
+
+const REVOKED: &str = "revoked";
+const LIVE: &str = "live";
+
+pub struct JtiDenylist {
+    db: Arc<Database>,
+    cache: SharedCache,
+    clock: SharedClock,
+    leeway_secs: u64,
+    cache_ttl: Duration,
+}
+
+impl JtiDenylist {
+    pub fn new(
+        db: Arc<Database>,
+        cache: SharedCache,
+        clock: SharedClock,
+        config: &JwtConfig,
+    ) -> Self {
+        Self {
+            db,
+            cache,
+            clock,
+            leeway_secs: config.leeway_secs,
+            cache_ttl: Duration::from_secs(config.denylist_cache_secs),
+        }
+    }
+
+    /// Cache key of the answer for `jti`
+    pub fn cache_key(jti: &str) -> String {
+        format!("jti_denylist:{}", jti)
+    }
+
+    /// The claims of `token` if it is a JWT access token with a valid
+    /// signature that is usable now. Anything else, opaque tokens and access
+    /// tokens from before they were typed included, has to be looked up.
+    pub fn decode(&self, token: &str, keys: &SigningKeys) -> Option<Claims> {
+        if !Claims::is_access_token(token) {
+            return None;
+        }
+        Claims::decode(token, keys, self.clock.as_ref(), self.leeway_secs).ok()
+    }
+
+    /// Whether the access token `claims` describe has been revoked
+    pub async fn is_revoked(&self, claims: &Claims) -> Result<bool, OAuth2Error> {
+        let key = Self::cache_key(&claims.jti);
+        match self.cache.get(&key).await {
+            Ok(Some(answer)) => return Ok(answer == REVOKED),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Failed to read cached jti denylist entry: {}", e),
+        }
+
+        let revoked = self.db.is_jti_revoked(&claims.jti).await?;
+        let ttl = if revoked {
+            let remaining = claims.exp - self.clock.now().timestamp();
+            Duration::from_secs(u64::try_from(remaining).unwrap_or(0).max(1))
+        } else {
+            self.cache_ttl
+        };
+        if !ttl.is_zero() {
+            let answer = if revoked { REVOKED } else { LIVE };
+            if let Err(e) = self.cache.set(&key, answer, ttl).await {
+                tracing::warn!("Failed to cache jti denylist entry: {}", e);
+            }
+        }
+        Ok(revoked)
+    }
+}
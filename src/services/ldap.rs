@@ -0,0 +1,585 @@
+//! Signing users in against an LDAP server or Active Directory.
+//!
+//! Only what a sign-in takes is spoken: LDAPv3 simple binds and a subtree
+//! search for one entry. [`LdapAuthenticator`] binds as the service account
+//! (or anonymously), searches `base_dn` for the entry whose `user_attribute`
+//! is the username, then binds as that entry with the password given. The
+//! search filter is sent BER-encoded, so usernames need no escaping.
+//! `ldaps://` URLs connect over TLS; StartTLS is not supported.
+
+use crate::config::LdapConfig;
+use crate::models::{ErrorCode, OAuth2Error};
+use crate::services::{map_group_roles, DirectoryUser, UserAuthenticator};
+use async_trait::async_trait;
+use oauth2::url::Url;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Result codes acted on (RFC 4511 section 4.1.9)
+pub(crate) const SUCCESS: i64 = 0;
+pub(crate) const SIZE_LIMIT_EXCEEDED: i64 = 4;
+pub(crate) const INVALID_CREDENTIALS: i64 = 49;
+
+/// Protocol operation tags
+pub(crate) const BIND_REQUEST: u8 = 0x60;
+pub(crate) const BIND_RESPONSE: u8 = 0x61;
+pub(crate) const UNBIND_REQUEST: u8 = 0x42;
+pub(crate) const SEARCH_REQUEST: u8 = 0x63;
+pub(crate) const SEARCH_RESULT_ENTRY: u8 = 0x64;
+pub(crate) const SEARCH_RESULT_DONE: u8 = 0x65;
+pub(crate) const SIMPLE_AUTH: u8 = 0x80;
+pub(crate) const EQUALITY_MATCH: u8 = 0xa3;
+
+/// Largest message read, so a misbehaving server cannot exhaust memory
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// The subset of BER that LDAP messages use: definite lengths and
+/// single-byte tags
+pub(crate) mod ber {
+    pub const BOOLEAN: u8 = 0x01;
+    pub const INTEGER: u8 = 0x02;
+    pub const OCTET_STRING: u8 = 0x04;
+    pub const ENUMERATED: u8 = 0x0a;
+    pub const SEQUENCE: u8 = 0x30;
+    pub const SET: u8 = 0x31;
+
+    /// A tag, its length and its contents
+    pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        let len = content.len().to_be_bytes();
+        let significant = len.iter().skip_while(|b| **b == 0).count();
+        if content.len() < 0x80 {
+            out.push(content.len() as u8);
+        } else {
+            out.push(0x80 | significant as u8);
+            out.extend(&len[len.len() - significant..]);
+        }
+        out.extend(content);
+        out
+    }
+
+    /// An integer or enumeration in as few bytes as two's complement allows
+    pub fn integer(tag: u8, value: i64) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let mut start = 0;
+        while start < bytes.len() - 1 {
+            let redundant = match bytes[start] {
+                0x00 => bytes[start + 1] & 0x80 == 0,
+                0xff => bytes[start + 1] & 0x80 != 0,
+                _ => false,
+            };
+            if !redundant {
+                break;
+            }
+            start += 1;
+        }
+        tlv(tag, &bytes[start..])
+    }
+
+    pub fn decode_integer(content: &[u8]) -> Option<i64> {
+        if content.is_empty() || content.len() > 8 {
+            return None;
+        }
+        let sign = if content[0] & 0x80 != 0 { -1 } else { 0 };
+        Some(
+            content
+                .iter()
+                .fold(sign, |value, byte| (value << 8) | i64::from(*byte)),
+        )
+    }
+
+    /// A length and what follows it; `None` if it is truncated or indefinite
+    pub fn split_length(data: &[u8]) -> Option<(usize, &[u8])> {
+        let (&first, rest) = data.split_first()?;
+        if first < 0x80 {
+            return Some((usize::from(first), rest));
+        }
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let (bytes, rest) = rest.split_at(count);
+        let len = bytes
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | usize::from(*byte));
+        Some((len, rest))
+    }
+
+    /// Reads the elements of a constructed value one by one
+    pub struct Reader<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Reader<'a> {
+        pub fn new(data: &'a [u8]) -> Self {
+            Self { data }
+        }
+
+        /// The next element's tag and contents
+        #[allow(clippy::should_implement_trait)]
+        pub fn next(&mut self) -> Option<(u8, &'a [u8])> {
+            let (&tag, rest) = self.data.split_first()?;
+            let (len, rest) = split_length(rest)?;
+            if rest.len() < len {
+                return None;
+            }
+            let (content, rest) = rest.split_at(len);
+            self.data = rest;
+            Some((tag, content))
+        }
+
+        /// The next element's contents, if it has tag `tag`
+        pub fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+            match self.next()? {
+                (found, content) if found == tag => Some(content),
+                _ => None,
+            }
+        }
+
+        pub fn string(&mut self) -> Option<String> {
+            self.expect(OCTET_STRING)
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+        }
+    }
+}
+
+/// Read one LDAPMessage and return what is inside its SEQUENCE
+pub(crate) async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    if header[0] != ber::SEQUENCE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an LDAP message",
+        ));
+    }
+    let mut length = vec![header[1]];
+    if header[1] & 0x80 != 0 {
+        let mut extra = vec![0u8; usize::from(header[1] & 0x7f).min(4)];
+        reader.read_exact(&mut extra).await?;
+        length.extend(extra);
+    }
+    let len = ber::split_length(&length)
+        .map(|(len, _)| len)
+        .filter(|len| *len <= MAX_MESSAGE_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad LDAP message length"))?;
+    let mut content = vec![0u8; len];
+    reader.read_exact(&mut content).await?;
+    Ok(content)
+}
+
+/// The message ID, operation tag and operation contents of a message
+pub(crate) fn parse_message(content: &[u8]) -> Option<(i64, u8, &[u8])> {
+    let mut reader = ber::Reader::new(content);
+    let id = ber::decode_integer(reader.expect(ber::INTEGER)?)?;
+    let (tag, op) = reader.next()?;
+    Some((id, tag, op))
+}
+
+/// An LDAPMessage wrapping operation `op`
+pub(crate) fn message(id: i64, op: &[u8]) -> Vec<u8> {
+    let mut content = ber::integer(ber::INTEGER, id);
+    content.extend(op);
+    ber::tlv(ber::SEQUENCE, &content)
+}
+
+/// The outcome of a bind or search
+#[derive(Debug, Clone, PartialEq)]
+struct LdapResult {
+    code: i64,
+    message: String,
+}
+
+impl LdapResult {
+    fn parse(content: &[u8]) -> Option<Self> {
+        let mut reader = ber::Reader::new(content);
+        let code = ber::decode_integer(reader.expect(ber::ENUMERATED)?)?;
+        let _matched_dn = reader.string()?;
+        let message = reader.string()?;
+        Some(Self { code, message })
+    }
+
+    fn error(&self, operation: &str) -> io::Error {
+        io::Error::other(format!(
+            "{} failed with result {}: {}",
+            operation, self.code, self.message
+        ))
+    }
+}
+
+/// A search result entry
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    dn: String,
+    attributes: Vec<(String, Vec<String>)>,
+}
+
+impl Entry {
+    fn parse(content: &[u8]) -> Option<Self> {
+        let mut reader = ber::Reader::new(content);
+        let dn = reader.string()?;
+        let mut list = ber::Reader::new(reader.expect(ber::SEQUENCE)?);
+        let mut attributes = Vec::new();
+        while let Some(attribute) = list.expect(ber::SEQUENCE) {
+            let mut attribute = ber::Reader::new(attribute);
+            let name = attribute.string()?;
+            let mut set = ber::Reader::new(attribute.expect(ber::SET)?);
+            let mut values = Vec::new();
+            while let Some(value) = set.string() {
+                values.push(value);
+            }
+            attributes.push((name, values));
+        }
+        Some(Self { dn, attributes })
+    }
+
+    /// Values of an attribute; attribute names are case-insensitive
+    fn values(&self, name: &str) -> &[String] {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+            .map(|(_, values)| values.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn first(&self, name: &str) -> Option<String> {
+        self.values(name).first().cloned()
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Connection {
+    stream: Box<dyn Stream>,
+    next_id: i64,
+}
+
+impl Connection {
+    async fn open(host: &str, port: u16, tls: bool) -> io::Result<Self> {
+        let tcp = TcpStream::connect((host, port)).await?;
+        let stream: Box<dyn Stream> = if tls {
+            let connector =
+                tokio_native_tls::native_tls::TlsConnector::new().map_err(io::Error::other)?;
+            let stream = tokio_native_tls::TlsConnector::from(connector)
+                .connect(host, tcp)
+                .await
+                .map_err(io::Error::other)?;
+            Box::new(stream)
+        } else {
+            Box::new(tcp)
+        };
+        Ok(Self { stream, next_id: 1 })
+    }
+
+    async fn send(&mut self, op: &[u8]) -> io::Result<i64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stream.write_all(&message(id, op)).await?;
+        self.stream.flush().await?;
+        Ok(id)
+    }
+
+    /// The next response to message `id`
+    async fn receive(&mut self, id: i64) -> io::Result<(u8, Vec<u8>)> {
+        loop {
+            let content = read_message(&mut self.stream).await?;
+            let (response_id, tag, op) = parse_message(&content)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad LDAP response"))?;
+            if response_id == id {
+                return Ok((tag, op.to_vec()));
+            }
+            // Message 0 is the server's notice that it is closing the session
+            if response_id == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "LDAP server ended the session",
+                ));
+            }
+        }
+    }
+
+    async fn bind(&mut self, dn: &str, password: &str) -> io::Result<LdapResult> {
+        let mut request = ber::integer(ber::INTEGER, 3);
+        request.extend(ber::tlv(ber::OCTET_STRING, dn.as_bytes()));
+        request.extend(ber::tlv(SIMPLE_AUTH, password.as_bytes()));
+        let id = self.send(&ber::tlv(BIND_REQUEST, &request)).await?;
+
+        match self.receive(id).await? {
+            (BIND_RESPONSE, op) => LdapResult::parse(&op)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad bind response")),
+            (tag, _) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected response {:#x} to a bind", tag),
+            )),
+        }
+    }
+
+    /// Entries under `base_dn` whose `attribute` equals `value`. At most two
+    /// are returned; two is enough to know the match is ambiguous.
+    async fn search(
+        &mut self,
+        base_dn: &str,
+        attribute: &str,
+        value: &str,
+        attributes: &[&str],
+    ) -> io::Result<(Vec<Entry>, LdapResult)> {
+        let mut filter = ber::tlv(ber::OCTET_STRING, attribute.as_bytes());
+        filter.extend(ber::tlv(ber::OCTET_STRING, value.as_bytes()));
+        let wanted: Vec<u8> = attributes
+            .iter()
+            .flat_map(|name| ber::tlv(ber::OCTET_STRING, name.as_bytes()))
+            .collect();
+
+        let mut request = ber::tlv(ber::OCTET_STRING, base_dn.as_bytes());
+        request.extend(ber::integer(ber::ENUMERATED, 2)); // wholeSubtree
+        request.extend(ber::integer(ber::ENUMERATED, 0)); // neverDerefAliases
+        request.extend(ber::integer(ber::INTEGER, 2)); // sizeLimit
+        request.extend(ber::integer(ber::INTEGER, 0)); // timeLimit
+        request.extend(ber::tlv(ber::BOOLEAN, &[0])); // typesOnly
+        request.extend(ber::tlv(EQUALITY_MATCH, &filter));
+        request.extend(ber::tlv(ber::SEQUENCE, &wanted));
+        let id = self.send(&ber::tlv(SEARCH_REQUEST, &request)).await?;
+
+        let mut entries = Vec::new();
+        loop {
+            match self.receive(id).await? {
+                (SEARCH_RESULT_ENTRY, op) => entries.push(Entry::parse(&op).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "bad search entry")
+                })?),
+                (SEARCH_RESULT_DONE, op) => {
+                    let result = LdapResult::parse(&op).ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "bad search result")
+                    })?;
+                    return Ok((entries, result));
+                }
+                // Referrals to other servers are not followed
+                _ => {}
+            }
+        }
+    }
+
+    async fn unbind(mut self) {
+        let _ = self.send(&ber::tlv(UNBIND_REQUEST, &[])).await;
+    }
+}
+
+pub struct LdapAuthenticator {
+    config: LdapConfig,
+    host: String,
+    port: u16,
+    tls: bool,
+}
+
+impl LdapAuthenticator {
+    pub fn new(config: LdapConfig) -> Result<Self, String> {
+        let raw = config.url.as_deref().ok_or("OAUTH2_LDAP_URL is not set")?;
+        let url = Url::parse(raw).map_err(|e| format!("OAUTH2_LDAP_URL: {}", e))?;
+        let (tls, default_port) = match url.scheme() {
+            "ldap" => (false, 389),
+            "ldaps" => (true, 636),
+            scheme => {
+                return Err(format!(
+                    "OAUTH2_LDAP_URL: expected ldap:// or ldaps://, got {}://",
+                    scheme
+                ))
+            }
+        };
+        let host = url
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .ok_or("OAUTH2_LDAP_URL has no host")?
+            .to_string();
+        Ok(Self {
+            port: url.port().unwrap_or(default_port),
+            host,
+            tls,
+            config,
+        })
+    }
+
+    async fn sign_in(&self, username: &str, password: &str) -> io::Result<Option<DirectoryUser>> {
+        let config = &self.config;
+        let mut connection = Connection::open(&self.host, self.port, self.tls).await?;
+
+        let bound = connection
+            .bind(
+                config.bind_dn.as_deref().unwrap_or_default(),
+                config.bind_password.as_deref().unwrap_or_default(),
+            )
+            .await?;
+        if bound.code != SUCCESS {
+            return Err(bound.error("Service account bind"));
+        }
+
+        let attributes = [
+            config.user_attribute.as_str(),
+            config.email_attribute.as_str(),
+            config.name_attribute.as_str(),
+            config.group_attribute.as_str(),
+        ];
+        let (entries, done) = connection
+            .search(
+                &config.base_dn,
+                &config.user_attribute,
+                username,
+                &attributes,
+            )
+            .await?;
+        if done.code != SUCCESS && done.code != SIZE_LIMIT_EXCEEDED {
+            return Err(done.error("User search"));
+        }
+        let [entry] = entries.as_slice() else {
+            tracing::debug!(
+                "{} LDAP entries match {}={}",
+                entries.len(),
+                config.user_attribute,
+                username
+            );
+            connection.unbind().await;
+            return Ok(None);
+        };
+
+        let result = connection.bind(&entry.dn, password).await?;
+        connection.unbind().await;
+        match result.code {
+            SUCCESS => Ok(Some(self.user(entry, username))),
+            // Active Directory also answers this for disabled or locked accounts
+            INVALID_CREDENTIALS => Ok(None),
+            _ => Err(result.error("User bind")),
+        }
+    }
+
+    fn user(&self, entry: &Entry, username: &str) -> DirectoryUser {
+        let config = &self.config;
+        // The directory's spelling, since lookups may be case-insensitive
+        let username = entry
+            .first(&config.user_attribute)
+            .unwrap_or_else(|| username.to_string());
+        let groups = entry.values(&config.group_attribute).to_vec();
+        DirectoryUser {
+            subject: format!("ldap:{}", username),
+            roles: map_group_roles(&config.group_roles, &groups),
+            email: entry.first(&config.email_attribute),
+            name: entry.first(&config.name_attribute),
+            username,
+            groups,
+        }
+    }
+}
+
+#[async_trait]
+impl UserAuthenticator for LdapAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<DirectoryUser>, OAuth2Error> {
+        // A simple bind with an empty password is an anonymous bind, which
+        // servers accept
+        if username.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        match tokio::time::timeout(timeout, self.sign_in(username, password)).await {
+            Ok(Ok(user)) => Ok(user),
+            Ok(Err(e)) => Err(OAuth2Error::internal(
+                ErrorCode::TemporarilyUnavailable,
+                format!("LDAP sign-in against {}: {}", self.host, e),
+            )),
+            Err(_) => Err(OAuth2Error::internal(
+                ErrorCode::TemporarilyUnavailable,
+                format!("LDAP sign-in against {} timed out", self.host),
+            )),
+        }
+    }
+
+    fn name(&self) -> &str {
+        "ldap"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ber_lengths_and_integers() {
+        assert_eq!(
+            ber::tlv(ber::OCTET_STRING, b"ab"),
+            vec![0x04, 2, b'a', b'b']
+        );
+        let long = ber::tlv(ber::OCTET_STRING, &[0u8; 300]);
+        assert_eq!(&long[..4], &[0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(ber::split_length(&long[1..]).unwrap().0, 300);
+
+        for value in [0, 3, 127, 128, 255, 256, 65_535, -1, -129, i64::MAX] {
+            let encoded = ber::integer(ber::INTEGER, value);
+            let mut reader = ber::Reader::new(&encoded);
+            let content = reader.expect(ber::INTEGER).unwrap();
+            assert_eq!(ber::decode_integer(content), Some(value));
+        }
+        assert_eq!(ber::integer(ber::INTEGER, 128), vec![0x02, 2, 0x00, 0x80]);
+        assert_eq!(ber::split_length(&[0x84, 1]), None);
+    }
+
+    #[test]
+    fn test_bind_request_encoding() {
+        let mut request = ber::integer(ber::INTEGER, 3);
+        request.extend(ber::tlv(ber::OCTET_STRING, b"cn=a"));
+        request.extend(ber::tlv(SIMPLE_AUTH, b"pw"));
+        let bytes = message(1, &ber::tlv(BIND_REQUEST, &request));
+        assert_eq!(
+            bytes,
+            vec![
+                0x30, 0x12, 0x02, 0x01, 0x01, 0x60, 0x0d, 0x02, 0x01, 0x03, 0x04, 0x04, b'c', b'n',
+                b'=', b'a', 0x80, 0x02, b'p', b'w'
+            ]
+        );
+        let (id, tag, _) = parse_message(&bytes[2..]).unwrap();
+        assert_eq!((id, tag), (1, BIND_REQUEST));
+    }
+
+    #[test]
+    fn test_search_entry_parsing() {
+        let attribute = |name: &str, values: &[&str]| {
+            let mut content = ber::tlv(ber::OCTET_STRING, name.as_bytes());
+            let set: Vec<u8> = values
+                .iter()
+                .flat_map(|value| ber::tlv(ber::OCTET_STRING, value.as_bytes()))
+                .collect();
+            content.extend(ber::tlv(ber::SET, &set));
+            ber::tlv(ber::SEQUENCE, &content)
+        };
+        let mut attributes = attribute("mail", &["alice@example.com"]);
+        attributes.extend(attribute("memberOf", &["cn=a,dc=x", "cn=b,dc=x"]));
+        let mut content = ber::tlv(ber::OCTET_STRING, b"uid=alice,dc=x");
+        content.extend(ber::tlv(ber::SEQUENCE, &attributes));
+
+        let entry = Entry::parse(&content).unwrap();
+        assert_eq!(entry.dn, "uid=alice,dc=x");
+        assert_eq!(entry.first("MAIL").as_deref(), Some("alice@example.com"));
+        assert_eq!(entry.values("memberof"), ["cn=a,dc=x", "cn=b,dc=x"]);
+        assert!(entry.values("cn").is_empty());
+    }
+
+    #[test]
+    fn test_urls() {
+        let config = |url: &str| LdapConfig {
+            url: Some(url.to_string()),
+            ..LdapConfig::default()
+        };
+        let ldap = LdapAuthenticator::new(config("ldap://dc1.corp.example")).unwrap();
+        assert_eq!(
+            (ldap.host.as_str(), ldap.port, ldap.tls),
+            ("dc1.corp.example", 389, false)
+        );
+        let ldaps = LdapAuthenticator::new(config("ldaps://dc1.corp.example:3269")).unwrap();
+        assert_eq!((ldaps.port, ldaps.tls), (3269, true));
+        assert!(LdapAuthenticator::new(config("https://dc1.corp.example")).is_err());
+    }
+}
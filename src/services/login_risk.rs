@@ -0,0 +1,177 @@
+//! Suspicious-login detection from successive login locations.
+//!
+//! History is kept in memory per user, so a restart forgets where users have
+//! been; the first login after that is never flagged.
+
+use crate::services::GeoLocation;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Location differences below this are treated as geolocation noise
+const MIN_TRAVEL_DISTANCE_KM: f64 = 100.0;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RiskSignal {
+    /// First login from a country this user has not logged in from before
+    NewCountry { country: String },
+    /// The previous login was too far away to have travelled here since
+    ImpossibleTravel {
+        from_country: String,
+        distance_km: f64,
+        elapsed_secs: i64,
+    },
+}
+
+impl RiskSignal {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RiskSignal::NewCountry { .. } => "new_country",
+            RiskSignal::ImpossibleTravel { .. } => "impossible_travel",
+        }
+    }
+
+    /// Short explanation suitable for an event or a user-facing page
+    pub fn describe(&self) -> String {
+        match self {
+            RiskSignal::NewCountry { country } => format!("First sign-in from {}", country),
+            RiskSignal::ImpossibleTravel {
+                from_country,
+                distance_km,
+                elapsed_secs,
+            } => format!(
+                "{:.0} km from the previous sign-in in {} only {} minutes earlier",
+                distance_km,
+                from_country,
+                elapsed_secs / 60
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+struct LoginHistory {
+    countries: HashSet<String>,
+    last: Option<(GeoLocation, DateTime<Utc>)>,
+}
+
+pub struct LoginRiskDetector {
+    max_travel_speed_kmh: f64,
+    history: Mutex<HashMap<String, LoginHistory>>,
+}
+
+impl LoginRiskDetector {
+    pub fn new(max_travel_speed_kmh: f64) -> Self {
+        Self {
+            max_travel_speed_kmh,
+            history: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Compare a login against the user's history, then record it
+    pub fn assess(
+        &self,
+        user_id: &str,
+        location: &GeoLocation,
+        at: DateTime<Utc>,
+    ) -> Vec<RiskSignal> {
+        let mut history = self.history.lock().unwrap();
+        let entry = history.entry(user_id.to_string()).or_default();
+        let mut signals = Vec::new();
+
+        if !entry.countries.is_empty() && !entry.countries.contains(&location.country) {
+            signals.push(RiskSignal::NewCountry {
+                country: location.country.clone(),
+            });
+        }
+
+        if let Some((previous, previous_at)) = &entry.last {
+            let distance_km = distance_km(previous, location);
+            let elapsed_secs = (at - *previous_at).num_seconds().max(0);
+            let hours = elapsed_secs as f64 / 3600.0;
+            if distance_km >= MIN_TRAVEL_DISTANCE_KM
+                && distance_km > self.max_travel_speed_kmh * hours
+            {
+                signals.push(RiskSignal::ImpossibleTravel {
+                    from_country: previous.country.clone(),
+                    distance_km,
+                    elapsed_secs,
+                });
+            }
+        }
+
+        entry.countries.insert(location.country.clone());
+        entry.last = Some((location.clone(), at));
+        signals
+    }
+}
+
+/// Great-circle distance (haversine)
+fn distance_km(a: &GeoLocation, b: &GeoLocation) -> f64 {
+    let (lat1, lat2) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.longitude - a.longitude).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn location(country: &str, latitude: f64, longitude: f64) -> GeoLocation {
+        GeoLocation {
+            country: country.to_string(),
+            city: None,
+            latitude,
+            longitude,
+        }
+    }
+
+    #[test]
+    fn test_distance_km() {
+        let new_york = location("US", 40.71, -74.01);
+        let london = location("GB", 51.51, -0.13);
+        let distance = distance_km(&new_york, &london);
+        assert!((5500.0..5600.0).contains(&distance), "{}", distance);
+    }
+
+    #[test]
+    fn test_new_country_and_impossible_travel() {
+        let detector = LoginRiskDetector::new(1000.0);
+        let start = Utc::now();
+        let new_york = location("US", 40.71, -74.01);
+        let boston = location("US", 42.36, -71.06);
+        let berlin = location("DE", 52.52, 13.40);
+
+        assert!(detector.assess("alice", &new_york, start).is_empty());
+        // ~300 km in two hours is plausible
+        assert!(detector
+            .assess("alice", &boston, start + Duration::hours(2))
+            .is_empty());
+
+        let signals = detector.assess("alice", &berlin, start + Duration::hours(3));
+        assert_eq!(signals.len(), 2);
+        assert_eq!(signals[0].as_str(), "new_country");
+        assert_eq!(signals[1].as_str(), "impossible_travel");
+
+        // Germany is known now, and a day is long enough to fly back
+        assert!(detector
+            .assess("alice", &new_york, start + Duration::hours(30))
+            .is_empty());
+        // Other users have their own history
+        assert!(detector.assess("bob", &berlin, start).is_empty());
+    }
+
+    #[test]
+    fn test_nearby_locations_are_not_travel() {
+        let detector = LoginRiskDetector::new(1000.0);
+        let now = Utc::now();
+        detector.assess("alice", &location("US", 40.71, -74.01), now);
+        let signals = detector.assess("alice", &location("US", 40.73, -73.93), now);
+        assert!(signals.is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+//! TOTP second factor and remembered ("trusted") devices.
+//!
+//! Passing the TOTP challenge with "remember this device" issues a signed
+//! `trusted_device` cookie holding the id of a `trusted_devices` row. The
+//! signature stops ids being forged; the row is what expires and what users
+//! revoke from their account page.
+
+use crate::clock::SharedClock;
+use crate::config::MfaConfig;
+use crate::db::Database;
+use crate::models::{ErrorCode, OAuth2Error, TotpEnrollment, TrustedDevice};
+use actix_web::cookie::{time, Cookie, CookieJar, Key, SameSite};
+use actix_web::HttpRequest;
+use chrono::Duration;
+use rand::Rng;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+pub const TRUSTED_DEVICE_COOKIE: &str = "trusted_device";
+
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP_SECS: i64 = 30;
+/// Codes from one step either side are accepted to allow for clock drift
+const TOTP_SKEW_STEPS: i64 = 1;
+
+/// Device labels come from the User-Agent, which can be arbitrarily long
+const MAX_DEVICE_LABEL_LEN: usize = 120;
+
+pub struct MfaService {
+    db: Arc<Database>,
+    clock: SharedClock,
+    cookie_key: Key,
+    issuer: String,
+    trusted_device_lifetime: Option<Duration>,
+}
+
+impl MfaService {
+    /// `cookie_key` signs the trusted-device cookie; `issuer` is the name
+    /// authenticator apps show next to the code
+    pub fn new(
+        db: Arc<Database>,
+        clock: SharedClock,
+        cookie_key: Key,
+        issuer: String,
+        config: &MfaConfig,
+    ) -> Self {
+        Self {
+            db,
+            clock,
+            cookie_key,
+            issuer: issuer.replace(':', ""),
+            trusted_device_lifetime: (config.trusted_device_days > 0)
+                .then(|| Duration::days(i64::from(config.trusted_device_days))),
+        }
+    }
+
+    /// How long "remember this device" lasts, or `None` if it is disabled
+    pub fn trusted_device_days(&self) -> Option<i64> {
+        self.trusted_device_lifetime
+            .map(|lifetime| lifetime.num_days())
+    }
+
+    pub async fn is_enrolled(&self, subject: &str) -> Result<bool, OAuth2Error> {
+        Ok(self.db.get_totp_enrollment(subject).await?.is_some())
+    }
+
+    /// A fresh base32 secret for enrollment
+    pub fn generate_secret() -> String {
+        let bytes: [u8; 20] = rand::thread_rng().gen();
+        Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+    }
+
+    /// `otpauth://` URI for adding `secret` to an authenticator app
+    pub fn provisioning_uri(&self, secret: &str, account: &str) -> Result<String, OAuth2Error> {
+        Ok(self.totp(secret, Some(account.replace(':', "")))?.get_url())
+    }
+
+    /// Save `secret` for the user if `code` proves their app has it
+    pub async fn enroll(
+        &self,
+        subject: &str,
+        secret: &str,
+        code: &str,
+    ) -> Result<bool, OAuth2Error> {
+        let Some(step) = self.matching_step(secret, code)? else {
+            return Ok(false);
+        };
+
+        self.db
+            .save_totp_enrollment(&TotpEnrollment {
+                subject: subject.to_string(),
+                secret: secret.to_string(),
+                last_used_step: Some(step),
+                created_at: self.clock.now(),
+            })
+            .await?;
+        Ok(true)
+    }
+
+    /// Check a code against the user's enrollment. Each time step is accepted
+    /// once, so an observed code cannot be replayed.
+    pub async fn verify(&self, subject: &str, code: &str) -> Result<bool, OAuth2Error> {
+        let Some(enrollment) = self.db.get_totp_enrollment(subject).await? else {
+            return Ok(false);
+        };
+        match self.matching_step(&enrollment.secret, code)? {
+            Some(step) => self.db.advance_totp_step(subject, step).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Remove the second factor, and with it every remembered device
+    pub async fn unenroll(&self, subject: &str) -> Result<(), OAuth2Error> {
+        self.db.delete_totp_enrollment(subject).await?;
+        self.db.revoke_all_trusted_devices(subject).await
+    }
+
+    /// Whether the request carries a valid trusted-device cookie for `subject`
+    pub async fn is_trusted_device(
+        &self,
+        req: &HttpRequest,
+        subject: &str,
+    ) -> Result<bool, OAuth2Error> {
+        let Some(cookie) = req.cookie(TRUSTED_DEVICE_COOKIE) else {
+            return Ok(false);
+        };
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie);
+        let Some(cookie) = jar.signed(&self.cookie_key).get(TRUSTED_DEVICE_COOKIE) else {
+            tracing::warn!("Ignoring trusted-device cookie with a bad signature");
+            return Ok(false);
+        };
+
+        let Some(device) = self.db.get_trusted_device(cookie.value()).await? else {
+            return Ok(false);
+        };
+        if device.subject != subject || !device.is_valid(self.clock.as_ref()) {
+            return Ok(false);
+        }
+
+        self.db
+            .touch_trusted_device(&device.id, self.clock.now())
+            .await?;
+        Ok(true)
+    }
+
+    /// Remember the requesting browser for `subject`. Returns the cookie to
+    /// set, or `None` when remembering devices is disabled.
+    pub async fn trust_device(
+        &self,
+        req: &HttpRequest,
+        subject: &str,
+    ) -> Result<Option<Cookie<'static>>, OAuth2Error> {
+        let Some(lifetime) = self.trusted_device_lifetime else {
+            return Ok(None);
+        };
+
+        let device = TrustedDevice::new(
+            subject.to_string(),
+            device_label(req),
+            lifetime,
+            self.clock.as_ref(),
+        );
+        self.db.save_trusted_device(&device).await?;
+
+        let cookie = Cookie::build(TRUSTED_DEVICE_COOKIE, device.id)
+            .path("/")
+            .http_only(true)
+            .secure(req.connection_info().scheme() == "https")
+            .same_site(SameSite::Lax)
+            .max_age(time::Duration::seconds(lifetime.num_seconds()))
+            .finish();
+        let mut jar = CookieJar::new();
+        jar.signed_mut(&self.cookie_key).add(cookie);
+        Ok(jar.get(TRUSTED_DEVICE_COOKIE).cloned())
+    }
+
+    /// The user's remembered devices that are still valid
+    pub async fn list_devices(&self, subject: &str) -> Result<Vec<TrustedDevice>, OAuth2Error> {
+        Ok(self
+            .db
+            .list_trusted_devices(subject)
+            .await?
+            .into_iter()
+            .filter(|device| device.is_valid(self.clock.as_ref()))
+            .collect())
+    }
+
+    pub async fn revoke_device(&self, subject: &str, id: &str) -> Result<bool, OAuth2Error> {
+        self.db.revoke_trusted_device(subject, id).await
+    }
+
+    pub async fn revoke_all_devices(&self, subject: &str) -> Result<(), OAuth2Error> {
+        self.db.revoke_all_trusted_devices(subject).await
+    }
+
+    fn totp(&self, secret: &str, account: Option<String>) -> Result<TOTP, OAuth2Error> {
+        let bytes = Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+        TOTP::new(
+            Algorithm::SHA1,
+            TOTP_DIGITS,
+            0,
+            TOTP_STEP_SECS as u64,
+            bytes,
+            Some(self.issuer.clone()),
+            account.unwrap_or_default(),
+        )
+        .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))
+    }
+
+    /// The time step `code` was generated for, if it is within the allowed skew
+    fn matching_step(&self, secret: &str, code: &str) -> Result<Option<i64>, OAuth2Error> {
+        let totp = self.totp(secret, None)?;
+        let current = self.clock.now().timestamp() / TOTP_STEP_SECS;
+        let code = code.trim();
+
+        Ok((current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+            .filter(|step| *step >= 0)
+            .find(|step| {
+                let expected = totp.generate((step * TOTP_STEP_SECS) as u64);
+                bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+            }))
+    }
+}
+
+/// Name a device after its User-Agent
+fn device_label(req: &HttpRequest) -> String {
+    let user_agent = req
+        .headers()
+        .get(actix_web::http::header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .unwrap_or_default();
+
+    if user_agent.is_empty() {
+        "Unknown browser".to_string()
+    } else {
+        user_agent.chars().take(MAX_DEVICE_LABEL_LEN).collect()
+    }
+}
@@ -0,0 +1,688 @@
+//! Moving clients, APIs and users over from other authorization servers.
+//!
+//! An adapter reads another server's export into a [`MigrationPlan`]:
+//! clients keep their ids but are issued new secrets, and users keep their
+//! password hashes when this server can verify them. Whatever has no
+//! equivalent here is listed in [`MigrationPlan::unmapped`] rather than
+//! guessed at. `oauth2ctl migrate-from` applies plans.
+
+use crate::actors::{ClientActor, ImportClient};
+use crate::db::Database;
+use crate::models::{
+    is_valid_redirect_uri, normalize_origin, DeclaredResource, DeclaredUser, OAuth2Error,
+    PortableClient, Resource, TokenEndpointAuthMethod, TokenFormat, User, SUPPORTED_GRANT_TYPES,
+    UNUSABLE_PASSWORD_HASH,
+};
+use actix::Addr;
+use argon2::password_hash::PasswordHash;
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+/// Clients Keycloak creates in every realm for its own consoles
+const KEYCLOAK_BUILT_IN_CLIENTS: [&str; 6] = [
+    "account",
+    "account-console",
+    "admin-cli",
+    "broker",
+    "realm-management",
+    "security-admin-console",
+];
+
+/// What to create here for another server's configuration
+#[derive(Debug, Default)]
+pub struct MigrationPlan {
+    pub clients: Vec<PortableClient>,
+    /// APIs that only accept tokens, e.g. Keycloak bearer-only clients
+    pub resources: Vec<DeclaredResource>,
+    pub users: Vec<DeclaredUser>,
+    /// Settings with no equivalent here, one line each
+    pub unmapped: Vec<String>,
+}
+
+/// Outcome of applying a [`MigrationPlan`]
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    pub clients: Vec<MigratedClient>,
+    /// Identifiers of the APIs registered
+    pub resources: Vec<String>,
+    /// Usernames of the users created
+    pub users: Vec<String>,
+    /// Objects that already existed and were left alone
+    pub skipped: Vec<String>,
+    pub unmapped: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigratedClient {
+    pub client_id: String,
+    /// Issued on import and not retrievable afterwards; absent on a dry run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+impl MigrationPlan {
+    /// Plan from a Keycloak realm export (`kc.sh export --realm <realm>`)
+    pub fn from_keycloak(export: &str) -> Result<Self, serde_json::Error> {
+        let realm: KeycloakRealm = serde_json::from_str(export)?;
+        let mut plan = Self::default();
+
+        for client in realm.clients {
+            if KEYCLOAK_BUILT_IN_CLIENTS.contains(&client.client_id.as_str()) {
+                continue;
+            }
+            plan.add_keycloak_client(client);
+        }
+        for user in realm.users {
+            // Service accounts are the client credentials grant here
+            if user.service_account_client_id.is_some() {
+                continue;
+            }
+            plan.add_keycloak_user(user);
+        }
+        Ok(plan)
+    }
+
+    /// Plan from a Hydra client list (`hydra list oauth2-clients --format json`
+    /// or the admin API's `GET /admin/clients`)
+    pub fn from_hydra(export: &str) -> Result<Self, serde_json::Error> {
+        let clients = match serde_json::from_str(export)? {
+            HydraClientList::Page { items } => items,
+            HydraClientList::Plain(clients) => clients,
+        };
+        let mut plan = Self::default();
+        for client in clients {
+            plan.add_hydra_client(client);
+        }
+        Ok(plan)
+    }
+
+    fn add_keycloak_client(&mut self, client: KeycloakClient) {
+        let id = client.client_id.clone();
+        if client
+            .protocol
+            .as_deref()
+            .is_some_and(|p| p != "openid-connect")
+        {
+            self.unmapped.push(format!(
+                "client {}: {} clients are not supported",
+                id,
+                client.protocol.unwrap_or_default()
+            ));
+            return;
+        }
+        if !client.enabled {
+            self.unmapped
+                .push(format!("client {}: disabled in Keycloak, not migrated", id));
+            return;
+        }
+
+        let scopes: Vec<String> = client
+            .default_client_scopes
+            .iter()
+            .chain(&client.optional_client_scopes)
+            .cloned()
+            .collect();
+        if client.bearer_only {
+            if scopes.is_empty() {
+                self.unmapped.push(format!(
+                    "bearer-only client {}: no scopes to register it with",
+                    id
+                ));
+            } else {
+                self.resources.push(DeclaredResource {
+                    identifier: id,
+                    name: client.name.unwrap_or(client.client_id),
+                    scopes,
+                    token_format: TokenFormat::Jwt,
+                });
+            }
+            return;
+        }
+
+        let mut grant_types = BTreeSet::new();
+        if client.standard_flow_enabled {
+            grant_types.extend(["authorization_code", "refresh_token"]);
+        }
+        if client.direct_access_grants_enabled {
+            grant_types.extend(["password", "refresh_token"]);
+        }
+        if client.service_accounts_enabled {
+            grant_types.insert("client_credentials");
+        }
+        if client.implicit_flow_enabled {
+            self.unmapped
+                .push(format!("client {}: the implicit flow is not supported", id));
+        }
+
+        let mut redirect_uris = Vec::new();
+        for uri in client.redirect_uris {
+            if uri.contains('*') || !is_valid_redirect_uri(&uri) {
+                self.unmapped.push(format!(
+                    "client {}: redirect URI {} is not an exact absolute URI",
+                    id, uri
+                ));
+            } else {
+                redirect_uris.push(uri);
+            }
+        }
+
+        let mut allowed_origins = BTreeSet::new();
+        for origin in client.web_origins {
+            match origin.as_str() {
+                // Keycloak's shorthand for the redirect URIs' origins
+                "+" => allowed_origins.extend(
+                    redirect_uris
+                        .iter()
+                        .filter_map(|uri| oauth2::url::Url::parse(uri).ok())
+                        .map(|url| url.origin().ascii_serialization()),
+                ),
+                _ => match normalize_origin(&origin) {
+                    Some(origin) => {
+                        allowed_origins.insert(origin);
+                    }
+                    None => self.unmapped.push(format!(
+                        "client {}: web origin {} is not a single origin",
+                        id, origin
+                    )),
+                },
+            }
+        }
+
+        self.push_client(PortableClient {
+            client_name: client
+                .name
+                .filter(|n| !n.trim().is_empty())
+                .unwrap_or(id.clone()),
+            client_id: id,
+            redirect_uris,
+            grant_types: grant_types.into_iter().map(str::to_string).collect(),
+            scope: scopes.join(" "),
+            allowed_origins: allowed_origins.into_iter().collect(),
+            redirect_uri_patterns: vec![],
+            token_endpoint_auth_method: if client.public_client {
+                TokenEndpointAuthMethod::None
+            } else {
+                TokenEndpointAuthMethod::default()
+            },
+            access_token_format: None,
+        });
+    }
+
+    fn add_keycloak_user(&mut self, user: KeycloakUser) {
+        let password = user
+            .credentials
+            .iter()
+            .find(|credential| credential.kind == "password");
+        let password_hash = match password.map(keycloak_password_hash) {
+            Some(Ok(hash)) => Some(hash),
+            Some(Err(reason)) => {
+                self.unmapped.push(format!(
+                    "user {}: {}; they must set a new password",
+                    user.username, reason
+                ));
+                None
+            }
+            None => None,
+        };
+
+        self.users.push(DeclaredUser {
+            username: user.username,
+            email: user.email.unwrap_or_default(),
+            enabled: user.enabled,
+            password_hash,
+        });
+    }
+
+    fn add_hydra_client(&mut self, client: HydraClient) {
+        let id = client.client_id.clone();
+        let (grant_types, unsupported): (Vec<String>, Vec<String>) = client
+            .grant_types
+            .into_iter()
+            .partition(|grant| SUPPORTED_GRANT_TYPES.contains(&grant.as_str()));
+        for grant in unsupported {
+            self.unmapped.push(format!(
+                "client {}: grant type {} is not supported",
+                id, grant
+            ));
+        }
+        if !client.audience.is_empty() {
+            self.unmapped.push(format!(
+                "client {}: audiences {} are APIs to register as resources",
+                id,
+                client.audience.join(", ")
+            ));
+        }
+
+        let mut allowed_origins = Vec::new();
+        for origin in client.allowed_cors_origins {
+            match normalize_origin(&origin) {
+                Some(origin) => allowed_origins.push(origin),
+                None => self.unmapped.push(format!(
+                    "client {}: CORS origin {} is not a single origin",
+                    id, origin
+                )),
+            }
+        }
+
+        self.push_client(PortableClient {
+            client_name: client
+                .client_name
+                .filter(|n| !n.trim().is_empty())
+                .unwrap_or(id.clone()),
+            client_id: id,
+            redirect_uris: client.redirect_uris,
+            grant_types,
+            scope: client.scope,
+            allowed_origins,
+            redirect_uri_patterns: vec![],
+            token_endpoint_auth_method: client
+                .token_endpoint_auth_method
+                .as_deref()
+                .and_then(TokenEndpointAuthMethod::parse)
+                .unwrap_or_default(),
+            access_token_format: None,
+        });
+    }
+
+    /// Keep the client if it is valid here, otherwise say why not
+    fn push_client(&mut self, client: PortableClient) {
+        match client.validate() {
+            Ok(()) => self.clients.push(client),
+            Err(reason) => self
+                .unmapped
+                .push(format!("client {}, not migrated", reason)),
+        }
+    }
+
+    /// Create what the plan describes, leaving alone clients, APIs and users
+    /// that already exist. With `dry_run` nothing is created.
+    pub async fn apply(
+        self,
+        db: &Database,
+        client_actor: &Addr<ClientActor>,
+        dry_run: bool,
+    ) -> Result<MigrationReport, OAuth2Error> {
+        let mut report = MigrationReport {
+            dry_run,
+            unmapped: self.unmapped,
+            ..Default::default()
+        };
+
+        for client in self.clients {
+            if db.get_client(&client.client_id).await?.is_some() {
+                report.skipped.push(format!("client {}", client.client_id));
+                continue;
+            }
+            let client_id = client.client_id.clone();
+            let client_secret = match dry_run {
+                true => None,
+                false => Some(
+                    client_actor
+                        .send(ImportClient { client })
+                        .await??
+                        .client_secret,
+                ),
+            };
+            report.clients.push(MigratedClient {
+                client_id,
+                client_secret,
+            });
+        }
+
+        for declared in self.resources {
+            if db
+                .get_resource_by_identifier(&declared.identifier)
+                .await?
+                .is_some()
+            {
+                report
+                    .skipped
+                    .push(format!("resource {}", declared.identifier));
+                continue;
+            }
+            if !dry_run {
+                db.save_resource(&Resource::new(
+                    declared.identifier.clone(),
+                    declared.name,
+                    declared.scopes,
+                    declared.token_format,
+                ))
+                .await?;
+            }
+            report.resources.push(declared.identifier);
+        }
+
+        for declared in self.users {
+            if db.get_user_by_username(&declared.username).await?.is_some() {
+                report.skipped.push(format!("user {}", declared.username));
+                continue;
+            }
+            if !dry_run {
+                let mut user = User::new(
+                    declared.username.clone(),
+                    declared
+                        .password_hash
+                        .unwrap_or_else(|| UNUSABLE_PASSWORD_HASH.to_string()),
+                    declared.email,
+                );
+                user.enabled = declared.enabled;
+                db.save_user(&user).await?;
+            }
+            report.users.push(declared.username);
+        }
+
+        Ok(report)
+    }
+}
+
+/// The PHC string for a Keycloak password credential, when it is an Argon2
+/// hash this server can verify
+fn keycloak_password_hash(credential: &KeycloakCredential) -> Result<String, String> {
+    let data: KeycloakCredentialData = serde_json::from_str(&credential.credential_data)
+        .map_err(|_| "unreadable password credential".to_string())?;
+    if data.algorithm != "argon2" {
+        return Err(format!(
+            "{} password hashes are not supported",
+            data.algorithm
+        ));
+    }
+    let secret: KeycloakSecretData = serde_json::from_str(&credential.secret_data)
+        .map_err(|_| "unreadable password credential".to_string())?;
+
+    let param = |name: &str, default: &str| {
+        data.additional_parameters
+            .get(name)
+            .and_then(|values| values.first())
+            .cloned()
+            .unwrap_or_else(|| default.to_string())
+    };
+    let version = match param("version", "1.3").as_str() {
+        "1.3" => 19,
+        "1.0" => 16,
+        other => return Err(format!("Argon2 version {} is not supported", other)),
+    };
+    let rebase = |value: &str| {
+        general_purpose::STANDARD
+            .decode(value)
+            .map(|bytes| general_purpose::STANDARD_NO_PAD.encode(bytes))
+            .map_err(|_| "unreadable password credential".to_string())
+    };
+
+    let hash = format!(
+        "$argon2{}$v={}$m={},t={},p={}${}${}",
+        param("type", "id"),
+        version,
+        param("memory", "7168"),
+        data.hash_iterations,
+        param("parallelism", "1"),
+        rebase(&secret.salt)?,
+        rebase(&secret.value)?,
+    );
+    PasswordHash::new(&hash).map_err(|e| format!("unusable Argon2 hash ({})", e))?;
+    Ok(hash)
+}
+
+#[derive(Debug, Deserialize)]
+struct KeycloakRealm {
+    #[serde(default)]
+    clients: Vec<KeycloakClient>,
+    #[serde(default)]
+    users: Vec<KeycloakUser>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeycloakClient {
+    client_id: String,
+    name: Option<String>,
+    protocol: Option<String>,
+    #[serde(default = "enabled")]
+    enabled: bool,
+    #[serde(default)]
+    bearer_only: bool,
+    #[serde(default)]
+    public_client: bool,
+    #[serde(default = "enabled")]
+    standard_flow_enabled: bool,
+    #[serde(default)]
+    implicit_flow_enabled: bool,
+    #[serde(default)]
+    direct_access_grants_enabled: bool,
+    #[serde(default)]
+    service_accounts_enabled: bool,
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+    #[serde(default)]
+    web_origins: Vec<String>,
+    #[serde(default)]
+    default_client_scopes: Vec<String>,
+    #[serde(default)]
+    optional_client_scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeycloakUser {
+    username: String,
+    email: Option<String>,
+    #[serde(default = "enabled")]
+    enabled: bool,
+    service_account_client_id: Option<String>,
+    #[serde(default)]
+    credentials: Vec<KeycloakCredential>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeycloakCredential {
+    #[serde(rename = "type")]
+    kind: String,
+    /// JSON holding the base64 `value` and `salt`
+    #[serde(default)]
+    secret_data: String,
+    /// JSON holding the algorithm and its parameters
+    #[serde(default)]
+    credential_data: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeycloakSecretData {
+    value: String,
+    salt: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeycloakCredentialData {
+    algorithm: String,
+    #[serde(default)]
+    hash_iterations: u32,
+    #[serde(default)]
+    additional_parameters: HashMap<String, Vec<String>>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum HydraClientList {
+    Page { items: Vec<HydraClient> },
+    Plain(Vec<HydraClient>),
+}
+
+#[derive(Debug, Deserialize)]
+struct HydraClient {
+    client_id: String,
+    client_name: Option<String>,
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+    #[serde(default)]
+    grant_types: Vec<String>,
+    #[serde(default)]
+    scope: String,
+    #[serde(default)]
+    allowed_cors_origins: Vec<String>,
+    token_endpoint_auth_method: Option<String>,
+    #[serde(default)]
+    audience: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, PasswordVerifier, SaltString};
+    use argon2::{Algorithm, Argon2, Params, Version};
+
+    #[test]
+    fn test_keycloak_realm_export() {
+        let plan = MigrationPlan::from_keycloak(
+            r#"{
+                "realm": "acme",
+                "clients": [
+                    { "clientId": "admin-cli", "publicClient": true },
+                    {
+                        "clientId": "web",
+                        "name": "Web app",
+                        "protocol": "openid-connect",
+                        "directAccessGrantsEnabled": true,
+                        "redirectUris": ["https://app.example.com/callback", "https://app.example.com/*"],
+                        "webOrigins": ["+"],
+                        "defaultClientScopes": ["profile", "email"],
+                        "optionalClientScopes": ["offline_access"]
+                    },
+                    { "clientId": "orders-api", "bearerOnly": true, "defaultClientScopes": ["orders"] },
+                    { "clientId": "legacy", "protocol": "saml" }
+                ],
+                "users": [
+                    { "username": "service-account-web", "serviceAccountClientId": "web" },
+                    {
+                        "username": "alice",
+                        "email": "alice@example.com",
+                        "credentials": [{
+                            "type": "password",
+                            "secretData": "{\"value\":\"abc=\",\"salt\":\"def=\"}",
+                            "credentialData": "{\"hashIterations\":27500,\"algorithm\":\"pbkdf2-sha256\"}"
+                        }]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(plan.clients.len(), 1);
+        let web = &plan.clients[0];
+        assert_eq!(web.client_id, "web");
+        assert_eq!(web.client_name, "Web app");
+        assert_eq!(web.redirect_uris, vec!["https://app.example.com/callback"]);
+        assert_eq!(
+            web.grant_types,
+            vec!["authorization_code", "password", "refresh_token"]
+        );
+        assert_eq!(web.scope, "profile email offline_access");
+        assert_eq!(web.allowed_origins, vec!["https://app.example.com"]);
+
+        assert_eq!(plan.resources.len(), 1);
+        assert_eq!(plan.resources[0].identifier, "orders-api");
+
+        assert_eq!(plan.users.len(), 1);
+        assert_eq!(plan.users[0].username, "alice");
+        assert!(plan.users[0].password_hash.is_none());
+
+        assert_eq!(
+            plan.unmapped,
+            vec![
+                "client web: redirect URI https://app.example.com/* is not an exact absolute URI",
+                "client legacy: saml clients are not supported",
+                "user alice: pbkdf2-sha256 password hashes are not supported; they must set a new password",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keycloak_argon2_hash_is_carried_over() {
+        let params = Params::new(7168, 5, 1, Some(32)).unwrap();
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let salt = SaltString::encode_b64(b"sixteen byte slt").unwrap();
+        let original = argon2.hash_password(b"correct horse", &salt).unwrap();
+
+        // Keycloak keeps the raw hash and salt as padded base64
+        let credential = KeycloakCredential {
+            kind: "password".to_string(),
+            secret_data: serde_json::json!({
+                "value": general_purpose::STANDARD.encode(original.hash.unwrap().as_bytes()),
+                "salt": general_purpose::STANDARD.encode(b"sixteen byte slt"),
+            })
+            .to_string(),
+            credential_data: serde_json::json!({
+                "hashIterations": 5,
+                "algorithm": "argon2",
+                "additionalParameters": {
+                    "hashLength": ["32"], "memory": ["7168"], "type": ["id"],
+                    "version": ["1.3"], "parallelism": ["1"]
+                }
+            })
+            .to_string(),
+        };
+
+        let hash = keycloak_password_hash(&credential).unwrap();
+        assert_eq!(hash, original.to_string());
+        let parsed = PasswordHash::new(&hash).unwrap();
+        assert!(Argon2::default()
+            .verify_password(b"correct horse", &parsed)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_hydra_client_list() {
+        let plan = MigrationPlan::from_hydra(
+            r#"{
+                "items": [
+                    {
+                        "client_id": "batch",
+                        "grant_types": ["client_credentials", "urn:ietf:params:oauth:grant-type:jwt-bearer"],
+                        "scope": "read write",
+                        "audience": ["https://api.example.com"]
+                    },
+                    {
+                        "client_id": "spa",
+                        "client_name": "SPA",
+                        "redirect_uris": ["https://spa.example.com/callback"],
+                        "grant_types": ["authorization_code"],
+                        "scope": "read",
+                        "token_endpoint_auth_method": "none",
+                        "allowed_cors_origins": ["https://spa.example.com/"]
+                    },
+                    { "client_id": "broken", "grant_types": ["implicit"], "scope": "read" }
+                ],
+                "next_page_token": ""
+            }"#,
+        )
+        .unwrap();
+
+        let ids: Vec<&str> = plan.clients.iter().map(|c| c.client_id.as_str()).collect();
+        assert_eq!(ids, vec!["batch", "spa"]);
+        assert_eq!(plan.clients[0].client_name, "batch");
+        assert_eq!(plan.clients[0].grant_types, vec!["client_credentials"]);
+        assert_eq!(
+            plan.clients[1].allowed_origins,
+            vec!["https://spa.example.com"]
+        );
+        assert_eq!(
+            plan.clients[1].token_endpoint_auth_method,
+            TokenEndpointAuthMethod::None
+        );
+        assert_eq!(plan.unmapped.len(), 4);
+        assert!(plan.unmapped[3].starts_with("client broken: grant_types must list"));
+
+        // A bare array, as the admin API returns it
+        let plan = MigrationPlan::from_hydra(
+            r#"[{ "client_id": "batch", "grant_types": ["client_credentials"], "scope": "read" }]"#,
+        )
+        .unwrap();
+        assert_eq!(plan.clients.len(), 1);
+    }
+}
@@ -1,3 +1,65 @@
+pub mod admission;
+pub mod claim_mapping;
+pub mod client_secrets;
+pub mod conformance;
+pub mod demo;
+pub mod diagnostics;
+pub mod directory;
+pub mod erasure;
+pub mod geoip;
+pub mod impersonation;
+pub mod jti_denylist;
+pub mod ldap;
+pub mod login_risk;
+pub mod mfa;
+pub mod migration;
+pub mod outbound;
+pub mod outbox;
+pub mod password;
+pub mod policy;
+pub mod profile_sync;
+pub mod rate_limit;
+pub mod registration;
+pub mod retention;
+pub mod return_to;
+pub mod saml;
+pub mod schema_validation;
 pub mod social_login;
+pub mod token_inspection;
+pub mod upstream_tokens;
+pub mod usage;
+pub mod validation;
+pub mod workload_identity;
 
+pub use admission::*;
+pub use claim_mapping::*;
+pub use client_secrets::*;
+pub use conformance::*;
+pub use demo::*;
+pub use diagnostics::*;
+pub use directory::*;
+pub use erasure::*;
+pub use geoip::*;
+pub use impersonation::*;
+pub use jti_denylist::*;
+pub use ldap::*;
+pub use login_risk::*;
+pub use mfa::*;
+pub use migration::*;
+pub use outbound::*;
+pub use outbox::*;
+pub use password::*;
+pub use policy::*;
+pub use profile_sync::*;
+pub use rate_limit::*;
+pub use registration::*;
+pub use retention::*;
+pub use return_to::*;
+pub use saml::*;
+pub use schema_validation::*;
 pub use social_login::*;
+pub use token_inspection::*;
+pub use upstream_tokens::*;
+pub use usage::*;
+pub use validation::*;
+pub use workload_identity::*;
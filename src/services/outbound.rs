@@ -0,0 +1,138 @@
+//! Calls to upstream identity providers.
+//!
+//! Token exchanges and profile fetches go through a [`ProviderClient`]. Each
+//! call runs in a `provider_request` span, a child of the request that made
+//! it, recording the provider, the operation, the response status and how
+//! long the provider took. The span is sent onward as a W3C `traceparent`
+//! header so a slow login can be followed into the provider's own traces.
+
+use crate::telemetry::{TraceContext, TRACEPARENT};
+use oauth2::AsyncHttpClient;
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Instant;
+use tracing::Instrument;
+
+/// HTTP client for one provider, on behalf of one trace
+pub struct ProviderClient {
+    provider: String,
+    trace: TraceContext,
+    http: reqwest::Client,
+}
+
+impl ProviderClient {
+    pub fn new(provider: &str, trace: TraceContext) -> Self {
+        Self {
+            provider: provider.to_string(),
+            trace,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn provider(&self) -> &str {
+        &self.provider
+    }
+
+    /// The client to build requests for [`ProviderClient::send`] on
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Send `request` to the provider, traced as `operation`
+    pub async fn send(
+        &self,
+        operation: &'static str,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        let span = self.trace.child();
+        let request = request.header(TRACEPARENT, span.traceparent());
+        self.traced(operation, &span, request.send(), |response| {
+            response.status().as_u16()
+        })
+        .await
+    }
+
+    /// An HTTP client for the `oauth2` crate's requests, traced as `operation`
+    pub fn oauth2(&self, operation: &'static str) -> OAuth2Call<'_> {
+        OAuth2Call {
+            client: self,
+            operation,
+        }
+    }
+
+    async fn traced<T, E: Display>(
+        &self,
+        operation: &'static str,
+        context: &TraceContext,
+        call: impl Future<Output = Result<T, E>>,
+        status: impl Fn(&T) -> u16,
+    ) -> Result<T, E> {
+        let span = tracing::info_span!(
+            "provider_request",
+            provider = %self.provider,
+            operation,
+            trace_id = %context.trace_id(),
+            span_id = %context.span_id(),
+            status = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+        );
+        let started = Instant::now();
+        let result = call.instrument(span.clone()).await;
+        let latency_ms = started.elapsed().as_millis() as u64;
+
+        span.record("latency_ms", latency_ms);
+        let _entered = span.enter();
+        match &result {
+            Ok(response) => {
+                let status = status(response);
+                span.record("status", status);
+                tracing::info!(
+                    "{} {} answered {} in {}ms",
+                    self.provider,
+                    operation,
+                    status,
+                    latency_ms
+                );
+            }
+            Err(e) => tracing::warn!(
+                "{} {} failed after {}ms: {}",
+                self.provider,
+                operation,
+                latency_ms,
+                e
+            ),
+        }
+        result
+    }
+}
+
+/// A [`ProviderClient`] as the `oauth2` crate's HTTP client
+pub struct OAuth2Call<'a> {
+    client: &'a ProviderClient,
+    operation: &'static str,
+}
+
+impl<'c> AsyncHttpClient<'c> for OAuth2Call<'_> {
+    type Error = <reqwest::Client as AsyncHttpClient<'c>>::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<oauth2::HttpResponse, Self::Error>> + 'c>>;
+
+    fn call(&'c self, mut request: oauth2::HttpRequest) -> Self::Future {
+        Box::pin(async move {
+            let span = self.client.trace.child();
+            request.headers_mut().insert(
+                TRACEPARENT,
+                oauth2::http::HeaderValue::from_str(&span.traceparent())
+                    .expect("traceparent is ASCII"),
+            );
+            self.client
+                .traced(
+                    self.operation,
+                    &span,
+                    self.client.http.call(request),
+                    |response| response.status().as_u16(),
+                )
+                .await
+        })
+    }
+}
@@ -0,0 +1,96 @@
+//! Transactional outbox for events about state changes.
+//!
+//! An event sent with `do_send` after a database write is lost if the process
+//! dies in between. Events for the changes that matter most for audit, such
+//! as issuing a token or deleting a client, are instead written to the
+//! `event_outbox` table in the same transaction as the change. The relay
+//! publishes them to the event plugins in order and only then deletes them,
+//! so each is delivered at least once: a crash between the two means it is
+//! published again on the next run. The relay is woken after every write and
+//! also runs every `outbox_relay_interval_secs`, which picks up anything left
+//! from before a restart.
+
+use crate::db::Database;
+use crate::events::{
+    event_actor::{EmitEvent, EventActor},
+    AuthEvent,
+};
+use crate::models::OAuth2Error;
+use actix::Addr;
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Events published per database round trip
+const RELAY_BATCH: i64 = 100;
+
+pub struct EventOutbox {
+    db: Arc<Database>,
+    event_actor: Addr<EventActor>,
+    interval: std::time::Duration,
+    wake: Notify,
+}
+
+impl EventOutbox {
+    pub fn new(db: Arc<Database>, event_actor: Addr<EventActor>, interval_secs: u64) -> Self {
+        Self {
+            db,
+            event_actor,
+            interval: std::time::Duration::from_secs(interval_secs.max(1)),
+            wake: Notify::new(),
+        }
+    }
+
+    pub fn interval(&self) -> std::time::Duration {
+        self.interval
+    }
+
+    /// Ask the relay to run now, after events were committed
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Completes when `wake` is called, or at once if it was called since
+    /// the last time this completed
+    pub async fn woken(&self) {
+        self.wake.notified().await
+    }
+
+    /// Publish everything waiting in the outbox, oldest first, returning how
+    /// many events were published. Stops at the first one the event actor
+    /// cannot take, leaving it and those after it for the next run.
+    pub async fn relay(&self) -> Result<usize, OAuth2Error> {
+        let mut published = 0;
+        loop {
+            let pending = self.db.list_outbox_events(RELAY_BATCH).await?;
+            if pending.is_empty() {
+                return Ok(published);
+            }
+            for (seq, json) in pending {
+                match serde_json::from_str::<AuthEvent>(&json) {
+                    Ok(event) => {
+                        self.event_actor.send(EmitEvent { event }).await?;
+                        published += 1;
+                    }
+                    // Nothing can ever publish it, so it must not hold up the rest
+                    Err(e) => tracing::error!("Dropping unreadable outbox event {}: {}", seq, e),
+                }
+                self.db.delete_outbox_event(seq).await?;
+            }
+        }
+    }
+}
+
+/// Deliver an event that was recorded with the change it describes: it is
+/// already in the outbox if there is one, so wake the relay, otherwise send
+/// it straight to the event actor
+pub fn publish_recorded_event(
+    outbox: Option<&EventOutbox>,
+    event_actor: Option<&Addr<EventActor>>,
+    event: AuthEvent,
+) {
+    match (outbox, event_actor) {
+        (Some(outbox), _) => outbox.wake(),
+        (None, Some(event_actor)) => event_actor.do_send(EmitEvent { event }),
+        (None, None) => {}
+    }
+}
@@ -0,0 +1,77 @@
+//! Password hashing for local user accounts.
+
+use crate::models::{ErrorCode, OAuth2Error};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::Rng;
+use std::sync::OnceLock;
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Hash a password as an Argon2id PHC string
+pub fn hash_password(password: &str) -> Result<String, OAuth2Error> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(OAuth2Error::invalid_request(&format!(
+            "Password must be at least {} characters",
+            MIN_PASSWORD_LEN
+        )));
+    }
+
+    let salt_bytes: [u8; 16] = rand::thread_rng().gen();
+    let salt = SaltString::encode_b64(&salt_bytes)
+        .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))
+}
+
+/// Check a password against a PHC string, from `hash_password` or imported
+/// with its own Argon2 parameters. Accounts without a usable hash, such as
+/// those created by social login, never match.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    })
+}
+
+/// A hash to check passwords for unknown usernames against, so that a
+/// lookup for an account that does not exist takes as long as a wrong
+/// password
+pub fn placeholder_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password("placeholder password").unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, hash_password("correct horse").unwrap());
+
+        let parsed = PasswordHash::new(&hash).unwrap();
+        assert!(Argon2::default()
+            .verify_password(b"correct horse", &parsed)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("", ""));
+        assert!(!verify_password("placeholder password", "not a hash"));
+    }
+
+    #[test]
+    fn test_short_password_is_rejected() {
+        assert_eq!(hash_password("short").unwrap_err().error, "invalid_request");
+    }
+}
@@ -0,0 +1,464 @@
+//! Authorization policy checks before codes and tokens are issued.
+//!
+//! [`PolicyEngine`] is the extension point. The bundled engines are
+//! [`OpaPolicyEngine`], which asks an Open Policy Agent endpoint, and
+//! [`RulePolicyEngine`], which evaluates a JSON rule file in-process:
+//!
+//! ```json
+//! {
+//!   "default": "permit",
+//!   "rules": [
+//!     { "effect": "deny", "clients": ["legacy-app"], "reason": "Retired client" },
+//!     { "effect": "permit", "networks": ["10.0.0.0/8"], "restrict_scopes": ["read"] }
+//!   ]
+//! }
+//! ```
+//!
+//! Rules are tried in order and the first whose conditions all match decides.
+//! An empty or missing condition matches anything. A permit may carry
+//! obligations; `restrict_scopes` narrows what is granted to those scopes.
+
+use crate::config::PolicyConfig;
+use crate::models::OAuth2Error;
+use crate::services::RequestOrigin;
+use async_trait::async_trait;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What is being asked for, as sent to the policy engine
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyInput {
+    /// `authorize` for authorization codes, `token` for tokens
+    pub action: &'static str,
+    /// Grant type for `token` requests
+    pub grant_type: Option<String>,
+    pub user_id: String,
+    /// Roles the user's directory groups map to
+    pub roles: Vec<String>,
+    pub client_id: String,
+    pub scopes: Vec<String>,
+    /// Identifier of the resource the token is for
+    pub resource: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub country: Option<String>,
+}
+
+impl PolicyInput {
+    pub fn new(action: &'static str, user_id: &str, client_id: &str, scope: &str) -> Self {
+        Self {
+            action,
+            grant_type: None,
+            user_id: user_id.to_string(),
+            roles: Vec::new(),
+            client_id: client_id.to_string(),
+            scopes: scope.split_whitespace().map(str::to_string).collect(),
+            resource: None,
+            ip: None,
+            country: None,
+        }
+    }
+
+    pub fn with_grant_type(mut self, grant_type: &str) -> Self {
+        self.grant_type = Some(grant_type.to_string());
+        self
+    }
+
+    pub fn with_roles(mut self, roles: &[String]) -> Self {
+        self.roles = roles.to_vec();
+        self
+    }
+
+    pub fn with_resource(mut self, resource: Option<&str>) -> Self {
+        self.resource = resource.map(str::to_string);
+        self
+    }
+
+    pub fn with_origin(mut self, origin: &RequestOrigin) -> Self {
+        self.ip = origin.ip;
+        self.country = origin.location.as_ref().map(|l| l.country.clone());
+        self
+    }
+}
+
+/// Conditions a permit comes with
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Obligations {
+    /// Grant only the requested scopes that are also in this list
+    pub restrict_scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PolicyDecision {
+    pub allow: bool,
+    /// Why the request was denied, shown to the client
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub obligations: Obligations,
+}
+
+impl PolicyDecision {
+    pub fn permit() -> Self {
+        Self {
+            allow: true,
+            reason: None,
+            obligations: Obligations::default(),
+        }
+    }
+
+    pub fn deny(reason: Option<String>) -> Self {
+        Self {
+            allow: false,
+            reason,
+            obligations: Obligations::default(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait PolicyEngine: Send + Sync {
+    async fn evaluate(&self, input: &PolicyInput) -> Result<PolicyDecision, String>;
+
+    fn name(&self) -> &str;
+}
+
+/// Permits everything; used when no policy is configured
+pub struct PermitAllPolicy;
+
+#[async_trait]
+impl PolicyEngine for PermitAllPolicy {
+    async fn evaluate(&self, _input: &PolicyInput) -> Result<PolicyDecision, String> {
+        Ok(PolicyDecision::permit())
+    }
+
+    fn name(&self) -> &str {
+        "none"
+    }
+}
+
+/// Asks an OPA decision endpoint such as
+/// `http://opa:8181/v1/data/oauth2/decision`. The policy's result may be a
+/// boolean or an object shaped like [`PolicyDecision`]; an undefined result
+/// denies.
+pub struct OpaPolicyEngine {
+    url: String,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct OpaResponse {
+    result: Option<OpaResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OpaResult {
+    Allow(bool),
+    Decision(PolicyDecision),
+}
+
+impl OpaPolicyEngine {
+    pub fn new(url: String, timeout: Duration) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self { url, http })
+    }
+}
+
+#[async_trait]
+impl PolicyEngine for OpaPolicyEngine {
+    async fn evaluate(&self, input: &PolicyInput) -> Result<PolicyDecision, String> {
+        let response = self
+            .http
+            .post(&self.url)
+            .json(&serde_json::json!({ "input": input }))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| e.to_string())?;
+        let body: OpaResponse = response.json().await.map_err(|e| e.to_string())?;
+
+        Ok(match body.result {
+            Some(OpaResult::Allow(true)) => PolicyDecision::permit(),
+            Some(OpaResult::Allow(false)) => PolicyDecision::deny(None),
+            Some(OpaResult::Decision(decision)) => decision,
+            None => PolicyDecision::deny(Some("No policy decision".to_string())),
+        })
+    }
+
+    fn name(&self) -> &str {
+        "opa"
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Effect {
+    Permit,
+    Deny,
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    #[serde(default = "default_effect")]
+    default: Effect,
+    rules: Vec<Rule>,
+}
+
+fn default_effect() -> Effect {
+    Effect::Permit
+}
+
+#[derive(Deserialize)]
+struct Rule {
+    effect: Effect,
+    reason: Option<String>,
+    #[serde(default)]
+    actions: Vec<String>,
+    #[serde(default)]
+    grant_types: Vec<String>,
+    #[serde(default)]
+    clients: Vec<String>,
+    #[serde(default)]
+    users: Vec<String>,
+    /// Matches when the user has any of these roles
+    #[serde(default)]
+    roles: Vec<String>,
+    /// Matches when any of these scopes is requested
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    resources: Vec<String>,
+    #[serde(default)]
+    countries: Vec<String>,
+    #[serde(default)]
+    networks: Vec<IpNet>,
+    restrict_scopes: Option<Vec<String>>,
+}
+
+impl Rule {
+    fn matches(&self, input: &PolicyInput) -> bool {
+        let any_of = |values: &[String], value: Option<&str>| {
+            values.is_empty() || value.is_some_and(|value| values.iter().any(|v| v == value))
+        };
+
+        any_of(&self.actions, Some(input.action))
+            && any_of(&self.grant_types, input.grant_type.as_deref())
+            && any_of(&self.clients, Some(&input.client_id))
+            && any_of(&self.users, Some(&input.user_id))
+            && (self.roles.is_empty() || input.roles.iter().any(|r| self.roles.contains(r)))
+            && (self.scopes.is_empty() || input.scopes.iter().any(|s| self.scopes.contains(s)))
+            && any_of(&self.resources, input.resource.as_deref())
+            && any_of(&self.countries, input.country.as_deref())
+            && (self.networks.is_empty()
+                || input
+                    .ip
+                    .is_some_and(|ip| self.networks.iter().any(|net| net.contains(&ip))))
+    }
+}
+
+/// In-process rules; see the module docs for the format
+pub struct RulePolicyEngine {
+    default: Effect,
+    rules: Vec<Rule>,
+}
+
+impl RulePolicyEngine {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let file: RuleFile = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Ok(Self {
+            default: file.default,
+            rules: file.rules,
+        })
+    }
+
+    pub fn open(path: &str) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::from_json(&json).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn decide(&self, input: &PolicyInput) -> PolicyDecision {
+        let Some(rule) = self.rules.iter().find(|rule| rule.matches(input)) else {
+            return match self.default {
+                Effect::Permit => PolicyDecision::permit(),
+                Effect::Deny => PolicyDecision::deny(None),
+            };
+        };
+        match rule.effect {
+            Effect::Permit => PolicyDecision {
+                allow: true,
+                reason: None,
+                obligations: Obligations {
+                    restrict_scopes: rule.restrict_scopes.clone(),
+                },
+            },
+            Effect::Deny => PolicyDecision::deny(rule.reason.clone()),
+        }
+    }
+}
+
+#[async_trait]
+impl PolicyEngine for RulePolicyEngine {
+    async fn evaluate(&self, input: &PolicyInput) -> Result<PolicyDecision, String> {
+        Ok(self.decide(input))
+    }
+
+    fn name(&self) -> &str {
+        "rules"
+    }
+}
+
+/// The policy evaluation point: asks the engine and applies its decision
+pub struct AuthorizationPolicy {
+    engine: Arc<dyn PolicyEngine>,
+    fail_open: bool,
+}
+
+impl AuthorizationPolicy {
+    /// With `fail_open`, requests are permitted when the engine cannot be
+    /// reached; otherwise they are denied
+    pub fn new(engine: Arc<dyn PolicyEngine>, fail_open: bool) -> Self {
+        Self { engine, fail_open }
+    }
+
+    pub fn permit_all() -> Self {
+        Self::new(Arc::new(PermitAllPolicy), false)
+    }
+
+    pub fn engine_name(&self) -> &str {
+        self.engine.name()
+    }
+
+    /// The scope to grant, or `access_denied` if the policy denies the request
+    pub async fn enforce(&self, input: &PolicyInput) -> Result<String, OAuth2Error> {
+        let decision = match self.engine.evaluate(input).await {
+            Ok(decision) => decision,
+            Err(e) if self.fail_open => {
+                tracing::warn!("Policy engine unavailable, permitting: {}", e);
+                PolicyDecision::permit()
+            }
+            Err(e) => {
+                tracing::error!("Policy engine unavailable, denying: {}", e);
+                PolicyDecision::deny(Some("Authorization policy unavailable".to_string()))
+            }
+        };
+
+        if !decision.allow {
+            tracing::info!(
+                "Policy denied {} for client {} and user {}",
+                input.action,
+                input.client_id,
+                input.user_id
+            );
+            return Err(OAuth2Error::access_denied(
+                decision
+                    .reason
+                    .as_deref()
+                    .unwrap_or("Denied by authorization policy"),
+            ));
+        }
+
+        let scopes: Vec<&str> = match &decision.obligations.restrict_scopes {
+            Some(allowed) => input
+                .scopes
+                .iter()
+                .filter(|scope| allowed.contains(scope))
+                .map(String::as_str)
+                .collect(),
+            None => input.scopes.iter().map(String::as_str).collect(),
+        };
+        if scopes.is_empty() && !input.scopes.is_empty() {
+            return Err(OAuth2Error::access_denied(
+                "None of the requested scopes are permitted",
+            ));
+        }
+        Ok(scopes.join(" "))
+    }
+}
+
+/// The configured policy: an OPA endpoint if set, then a rule file, and
+/// otherwise one that permits everything
+pub fn open_policy(config: &PolicyConfig) -> Result<AuthorizationPolicy, String> {
+    let engine: Arc<dyn PolicyEngine> = match (&config.opa_url, &config.rules_file) {
+        (Some(url), _) => Arc::new(OpaPolicyEngine::new(
+            url.clone(),
+            Duration::from_millis(config.timeout_ms),
+        )?),
+        (None, Some(path)) => Arc::new(RulePolicyEngine::open(path)?),
+        (None, None) => Arc::new(PermitAllPolicy),
+    };
+    Ok(AuthorizationPolicy::new(engine, config.fail_open))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"{
+        "default": "permit",
+        "rules": [
+            { "effect": "deny", "clients": ["legacy-app"], "reason": "Retired client" },
+            { "effect": "deny", "actions": ["token"], "scopes": ["admin"], "countries": ["XX"] },
+            { "effect": "permit", "networks": ["10.0.0.0/8"], "restrict_scopes": ["read"] }
+        ]
+    }"#;
+
+    fn input(client_id: &str, scope: &str) -> PolicyInput {
+        PolicyInput::new("token", "alice", client_id, scope).with_grant_type("password")
+    }
+
+    #[actix_web::test]
+    async fn test_rules_deny_and_restrict() {
+        let policy =
+            AuthorizationPolicy::new(Arc::new(RulePolicyEngine::from_json(RULES).unwrap()), false);
+
+        let err = policy
+            .enforce(&input("legacy-app", "read"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.error, "access_denied");
+        assert_eq!(err.error_description.as_deref(), Some("Retired client"));
+
+        let mut from_xx = input("app", "read admin");
+        from_xx.country = Some("XX".to_string());
+        assert!(policy.enforce(&from_xx).await.is_err());
+
+        let mut internal = input("app", "read write");
+        internal.ip = "10.1.2.3".parse().ok();
+        assert_eq!(policy.enforce(&internal).await.unwrap(), "read");
+
+        // Falls through to the default
+        assert_eq!(
+            policy.enforce(&input("app", "read write")).await.unwrap(),
+            "read write"
+        );
+    }
+
+    struct Unreachable;
+
+    #[async_trait]
+    impl PolicyEngine for Unreachable {
+        async fn evaluate(&self, _input: &PolicyInput) -> Result<PolicyDecision, String> {
+            Err("connection refused".to_string())
+        }
+
+        fn name(&self) -> &str {
+            "unreachable"
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_unreachable_engine_fails_closed_unless_configured() {
+        let closed = AuthorizationPolicy::new(Arc::new(Unreachable), false);
+        assert!(closed.enforce(&input("app", "read")).await.is_err());
+
+        let open = AuthorizationPolicy::new(Arc::new(Unreachable), true);
+        assert_eq!(open.enforce(&input("app", "read")).await.unwrap(), "read");
+    }
+}
@@ -0,0 +1,196 @@
+//! Re-reading connected users' profiles from their provider.
+//!
+//! Users who connected a provider (see [`UpstreamTokenVault`]) have their
+//! email, name and picture re-read every `interval_secs`, with the stored
+//! access token refreshed first once it has expired. A profile that changed
+//! is stored and announced with a `user_profile_updated` event. Connections
+//! that fail, e.g. because the user revoked access at the provider, are tried
+//! again the next interval.
+
+use crate::clock::SharedClock;
+use crate::config::ProfileSyncConfig;
+use crate::db::Database;
+use crate::events::{
+    event_actor::{EmitEvent, EventActor},
+    AuthEvent, EventSeverity, EventType,
+};
+use crate::models::{
+    ErrorCode, OAuth2Error, ProfileSyncReport, ProviderConfig, SocialLoginConfig, UpstreamToken,
+};
+use crate::services::{ProviderClient, SocialLoginService, UpstreamGrant, UpstreamTokenVault};
+use crate::telemetry::TraceContext;
+use actix::Addr;
+use chrono::{DateTime, Duration, Utc};
+use oauth2::RefreshToken;
+use std::sync::Arc;
+
+pub struct ProfileSync {
+    db: Arc<Database>,
+    vault: Arc<UpstreamTokenVault>,
+    social: Arc<SocialLoginConfig>,
+    clock: SharedClock,
+    config: ProfileSyncConfig,
+    event_actor: Option<Addr<EventActor>>,
+}
+
+fn sync_error(description: &str) -> OAuth2Error {
+    OAuth2Error::new(ErrorCode::ProviderError.as_str(), Some(description))
+}
+
+impl ProfileSync {
+    pub fn new(
+        db: Arc<Database>,
+        vault: Arc<UpstreamTokenVault>,
+        social: Arc<SocialLoginConfig>,
+        clock: SharedClock,
+        config: ProfileSyncConfig,
+        event_actor: Option<Addr<EventActor>>,
+    ) -> Self {
+        Self {
+            db,
+            vault,
+            social,
+            clock,
+            config,
+            event_actor,
+        }
+    }
+
+    /// Whether the background job runs at all
+    pub fn enabled(&self) -> bool {
+        self.config.interval_secs > 0
+    }
+
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.config.interval_secs.max(1))
+    }
+
+    /// Re-read every connected profile not synced within the interval, up to
+    /// the batch size
+    pub async fn run(&self) -> Result<ProfileSyncReport, OAuth2Error> {
+        let now = self.clock.now();
+        let interval = i64::try_from(self.config.interval_secs).unwrap_or(i64::MAX);
+        let cutoff = now - Duration::seconds(interval);
+        let due = self
+            .db
+            .list_upstream_profiles_due(cutoff, self.config.batch_size)
+            .await?;
+
+        let mut report = ProfileSyncReport::default();
+        for connection in due {
+            report.checked += 1;
+            match self.sync(&connection, now).await {
+                Ok(true) => report.updated += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    report.failed += 1;
+                    tracing::warn!(
+                        "Failed to sync the {} profile of {}: {}",
+                        connection.provider,
+                        connection.subject,
+                        e
+                    );
+                    self.db
+                        .touch_upstream_profile(&connection.subject, &connection.provider, now)
+                        .await?;
+                }
+            }
+        }
+
+        if report.checked > 0 {
+            tracing::info!(
+                checked = report.checked,
+                updated = report.updated,
+                failed = report.failed,
+                "Synced connected profiles"
+            );
+        }
+        Ok(report)
+    }
+
+    /// Re-read one profile, returning whether it changed
+    async fn sync(
+        &self,
+        connection: &UpstreamToken,
+        now: DateTime<Utc>,
+    ) -> Result<bool, OAuth2Error> {
+        let subject = connection.subject.as_str();
+        let provider = connection.provider.as_str();
+        let config = self
+            .social
+            .provider(provider)
+            .ok_or_else(|| sync_error("Provider is no longer configured"))?;
+        let grant = self
+            .vault
+            .get(subject, provider)
+            .await?
+            .ok_or_else(|| sync_error("Stored tokens cannot be read"))?;
+
+        // Each connection's calls are a trace of their own
+        let client = ProviderClient::new(provider, TraceContext::new_root());
+        let access_token = if grant.expires_at.is_some_and(|at| at <= now) {
+            self.refresh(&client, subject, config, &grant, now).await?
+        } else {
+            grant.access_token
+        };
+        let profile = SocialLoginService::fetch_user_info(&client, config, &access_token).await?;
+        if profile.subject() != subject {
+            return Err(sync_error("Provider returned a different user"));
+        }
+
+        let changed = connection.profile_changes(&profile);
+        self.db.save_upstream_profile(&profile, now).await?;
+        // A connection never synced before has nothing to compare with
+        if changed.is_empty() || connection.profile_synced_at.is_none() {
+            return Ok(false);
+        }
+
+        tracing::info!(
+            "The {} profile of {} changed: {}",
+            provider,
+            subject,
+            changed.join(", ")
+        );
+        if let Some(event_actor) = &self.event_actor {
+            let event = AuthEvent::new(
+                EventType::UserProfileUpdated,
+                EventSeverity::Info,
+                Some(subject.to_string()),
+                None,
+            )
+            .with_metadata("provider", provider)
+            .with_metadata("changed", changed.join(","));
+            event_actor.do_send(EmitEvent { event });
+        }
+        Ok(true)
+    }
+
+    /// Swap the stored refresh token for a new access token, storing what
+    /// the provider returns
+    async fn refresh(
+        &self,
+        client: &ProviderClient,
+        subject: &str,
+        config: &ProviderConfig,
+        grant: &UpstreamGrant,
+        now: DateTime<Utc>,
+    ) -> Result<String, OAuth2Error> {
+        let refresh_token = grant
+            .refresh_token
+            .clone()
+            .ok_or_else(|| sync_error("Access token expired and there is no refresh token"))?;
+        let provider = client.provider();
+        let oauth_client = SocialLoginService::get_client(provider, config)?;
+
+        let response = oauth_client
+            .exchange_refresh_token(&RefreshToken::new(refresh_token))
+            .request_async(&client.oauth2("token_refresh"))
+            .await
+            .map_err(|e| OAuth2Error::internal(ErrorCode::TokenExchangeFailed, e))?;
+
+        let scopes: Vec<String> = grant.scope.split_whitespace().map(str::to_string).collect();
+        let refreshed = UpstreamGrant::from_response(&response, &scopes, now);
+        self.vault.store(subject, provider, &refreshed).await?;
+        Ok(refreshed.access_token)
+    }
+}
@@ -0,0 +1,288 @@
+//! Request rate limits, with a warning before they bite.
+//!
+//! Requests are counted per caller in fixed windows of `window_secs`, in the
+//! [`Cache`](crate::cache::Cache), so replicas sharing Redis share the
+//! counts. Token and introspection requests are counted per client, the rest
+//! per IP address. Past `soft_limit_percent` of its limit a caller's
+//! responses carry `X-RateLimit-*` headers, and a `rate_limit_warning` event
+//! is emitted the first time in the window, so an integrator can slow their
+//! client down before it is cut off. Past the limit itself requests are
+//! refused with `429` until the window ends, announced once more with a
+//! `rate_limit_warning` event of severity `error`.
+//!
+//! A cache that cannot be reached lets requests through uncounted.
+
+use crate::cache::SharedCache;
+use crate::clock::SharedClock;
+use crate::config::RateLimitConfig;
+use crate::events::{
+    event_actor::{EmitEvent, EventActor},
+    AuthEvent, EventSeverity, EventType,
+};
+use crate::middleware::listener_middleware::under;
+use crate::services::RequestOrigin;
+use actix::Addr;
+use std::time::Duration;
+
+/// Paths never limited: probes, metrics, the admin surface and static assets
+const EXEMPT: &[&str] = &["/health", "/ready", "/metrics", "/admin", "/static"];
+
+/// The limit a request counts against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitBucket {
+    Authorize,
+    Token,
+    Introspect,
+    Default,
+}
+
+impl RateLimitBucket {
+    /// The bucket for a request to `path`, if it is limited at all
+    pub fn for_path(path: &str) -> Option<Self> {
+        if under(path, EXEMPT) {
+            None
+        } else if under(path, &["/oauth/authorize"]) {
+            Some(Self::Authorize)
+        } else if path == "/oauth/token" {
+            Some(Self::Token)
+        } else if path == "/oauth/introspect" {
+            Some(Self::Introspect)
+        } else {
+            Some(Self::Default)
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Authorize => "authorize",
+            Self::Token => "token",
+            Self::Introspect => "introspect",
+            Self::Default => "default",
+        }
+    }
+
+    /// Whether requests are counted per client rather than per IP address
+    pub fn per_client(&self) -> bool {
+        matches!(self, Self::Token | Self::Introspect)
+    }
+}
+
+/// Where a caller stands against its limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub limit: u64,
+    /// Requests left in the window
+    pub remaining: u64,
+    /// Seconds until the window ends
+    pub reset_secs: u64,
+    /// Past the soft limit: the caller should be told
+    pub warn: bool,
+    /// Past the limit: the request is refused
+    pub exceeded: bool,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    cache: SharedCache,
+    clock: SharedClock,
+    event_actor: Option<Addr<EventActor>>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        config: RateLimitConfig,
+        cache: SharedCache,
+        clock: SharedClock,
+        event_actor: Option<Addr<EventActor>>,
+    ) -> Self {
+        Self {
+            config,
+            cache,
+            clock,
+            event_actor,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Requests allowed per window in `bucket`; 0 for no limit
+    fn limit(&self, bucket: RateLimitBucket) -> u64 {
+        match bucket {
+            RateLimitBucket::Authorize => self.config.authorize_limit,
+            RateLimitBucket::Token => self.config.token_limit,
+            RateLimitBucket::Introspect => self.config.introspect_limit,
+            RateLimitBucket::Default => self.config.default_limit,
+        }
+    }
+
+    /// Count a request from `caller` (`client:<id>` or `ip:<address>`)
+    /// against `bucket`. `None` when the bucket is unlimited or the count
+    /// could not be kept.
+    pub async fn check(
+        &self,
+        bucket: RateLimitBucket,
+        caller: &str,
+        client_id: Option<&str>,
+        origin: &RequestOrigin,
+    ) -> Option<RateLimitStatus> {
+        let limit = self.limit(bucket);
+        if !self.config.enabled || limit == 0 {
+            return None;
+        }
+
+        let window = self.config.window_secs.max(1);
+        let now = self.clock.now().timestamp();
+        let index = now.div_euclid(window as i64);
+        let reset_secs = ((index + 1) * window as i64 - now) as u64;
+        let key = format!("rate_limit:{}:{}:{}", bucket.as_str(), caller, index);
+        let count = match self
+            .cache
+            .increment(&key, Duration::from_secs(window))
+            .await
+        {
+            Ok(count) => count,
+            Err(e) => {
+                tracing::warn!("Failed to count request against rate limit: {}", e);
+                return None;
+            }
+        };
+
+        let soft_limit = limit * self.config.soft_limit_percent.min(100) / 100;
+        if count == soft_limit.max(1) && count <= limit {
+            self.announce(bucket, caller, client_id, origin, limit, "soft");
+        } else if count == limit + 1 {
+            self.announce(bucket, caller, client_id, origin, limit, "hard");
+        }
+
+        Some(RateLimitStatus {
+            limit,
+            remaining: limit.saturating_sub(count),
+            reset_secs,
+            warn: count >= soft_limit,
+            exceeded: count > limit,
+        })
+    }
+
+    fn announce(
+        &self,
+        bucket: RateLimitBucket,
+        caller: &str,
+        client_id: Option<&str>,
+        origin: &RequestOrigin,
+        limit: u64,
+        threshold: &str,
+    ) {
+        tracing::warn!(
+            bucket = bucket.as_str(),
+            caller,
+            limit,
+            threshold,
+            "Caller reached the {} rate limit",
+            threshold
+        );
+        let Some(event_actor) = &self.event_actor else {
+            return;
+        };
+        let severity = if threshold == "hard" {
+            EventSeverity::Error
+        } else {
+            EventSeverity::Warning
+        };
+        let event = AuthEvent::new(
+            EventType::RateLimitWarning,
+            severity,
+            None,
+            client_id.map(str::to_string),
+        )
+        .with_metadata("bucket", bucket.as_str())
+        .with_metadata("caller", caller)
+        .with_metadata("limit", limit.to_string())
+        .with_metadata("window_secs", self.config.window_secs.to_string())
+        .with_metadata("threshold", threshold);
+        event_actor.do_send(EmitEvent {
+            event: origin.annotate(event),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::InMemoryCache;
+    use crate::clock::ManualClock;
+    use chrono::{TimeZone, Utc};
+    use std::sync::Arc;
+
+    fn limiter(clock: Arc<ManualClock>) -> RateLimiter {
+        let config = RateLimitConfig {
+            enabled: true,
+            window_secs: 60,
+            token_limit: 5,
+            soft_limit_percent: 60,
+            ..RateLimitConfig::default()
+        };
+        let cache = Arc::new(InMemoryCache::new(clock.clone()));
+        RateLimiter::new(config, cache, clock, None)
+    }
+
+    #[test]
+    fn test_paths_map_to_buckets() {
+        let bucket = RateLimitBucket::for_path;
+        assert_eq!(bucket("/oauth/authorize"), Some(RateLimitBucket::Authorize));
+        assert_eq!(bucket("/oauth/token"), Some(RateLimitBucket::Token));
+        assert_eq!(
+            bucket("/oauth/introspect"),
+            Some(RateLimitBucket::Introspect)
+        );
+        assert_eq!(bucket("/oauth/revoke"), Some(RateLimitBucket::Default));
+        assert_eq!(bucket("/health"), None);
+        assert_eq!(bucket("/admin/api/clients"), None);
+    }
+
+    #[tokio::test]
+    async fn test_callers_are_warned_then_refused_until_the_window_ends() {
+        let clock = Arc::new(ManualClock::new(
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 15).unwrap(),
+        ));
+        let limiter = limiter(clock.clone());
+        let origin = RequestOrigin::default();
+        let mut statuses = Vec::new();
+        for _ in 0..6 {
+            statuses.push(
+                limiter
+                    .check(RateLimitBucket::Token, "client:app", Some("app"), &origin)
+                    .await
+                    .unwrap(),
+            );
+        }
+
+        assert!(!statuses[1].warn);
+        assert!(statuses[2].warn && !statuses[2].exceeded);
+        assert_eq!(statuses[2].remaining, 2);
+        assert_eq!(statuses[2].reset_secs, 45);
+        assert!(!statuses[4].exceeded);
+        assert!(statuses[5].exceeded);
+        assert_eq!(statuses[5].remaining, 0);
+
+        // Other callers and buckets count separately
+        let other = limiter
+            .check(RateLimitBucket::Token, "client:other", None, &origin)
+            .await
+            .unwrap();
+        assert_eq!(other.remaining, 4);
+        assert!(limiter
+            .check(RateLimitBucket::Authorize, "client:app", None, &origin)
+            .await
+            .is_some_and(|status| !status.warn));
+
+        clock.advance(chrono::Duration::seconds(45));
+        let next = limiter
+            .check(RateLimitBucket::Token, "client:app", Some("app"), &origin)
+            .await
+            .unwrap();
+        assert_eq!(next.remaining, 4);
+        assert_eq!(next.reset_secs, 60);
+    }
+}
@@ -0,0 +1,139 @@
+//! Registration tokens for gated self-registration.
+//!
+//! An admin issues a token that may register a limited number of clients,
+//! optionally only within a scope and set of grant types, and hands it to
+//! whoever needs to register. They send it to `/clients/register` as a
+//! bearer token. With `require_token` set, registrations without one are
+//! refused; otherwise registration stays open, and a token sent anyway still
+//! has its limits applied. Only a SHA-256 of each token is stored.
+
+use crate::clock::SharedClock;
+use crate::config::RegistrationConfig;
+use crate::db::Database;
+use crate::models::{
+    ClientRegistration, IssuedRegistrationToken, OAuth2Error, RegistrationToken,
+    RegistrationTokenRequest, SUPPORTED_GRANT_TYPES,
+};
+use chrono::Duration;
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Length of an issued token, before its prefix
+const TOKEN_LENGTH: usize = 40;
+
+/// Marks registration tokens so they are recognizable in logs and scanners
+const TOKEN_PREFIX: &str = "reg_";
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub struct RegistrationGate {
+    db: Arc<Database>,
+    clock: SharedClock,
+    config: RegistrationConfig,
+}
+
+impl RegistrationGate {
+    pub fn new(db: Arc<Database>, clock: SharedClock, config: RegistrationConfig) -> Self {
+        Self { db, clock, config }
+    }
+
+    pub async fn issue(
+        &self,
+        request: RegistrationTokenRequest,
+    ) -> Result<IssuedRegistrationToken, OAuth2Error> {
+        if request.max_uses == Some(0) {
+            return Err(OAuth2Error::invalid_request("max_uses must be at least 1"));
+        }
+        if let Some(unsupported) = request
+            .grant_types
+            .iter()
+            .find(|grant| !SUPPORTED_GRANT_TYPES.contains(&grant.as_str()))
+        {
+            return Err(OAuth2Error::invalid_request(&format!(
+                "Unsupported grant type: {}",
+                unsupported
+            )));
+        }
+        let scope = request
+            .scope
+            .map(|scope| scope.split_whitespace().collect::<Vec<_>>().join(" "));
+        if scope.as_deref() == Some("") {
+            return Err(OAuth2Error::invalid_request(
+                "scope must name at least one scope, or be left out to allow any",
+            ));
+        }
+
+        let token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(TOKEN_LENGTH)
+            .map(char::from)
+            .collect();
+        let token = format!("{}{}", TOKEN_PREFIX, token);
+        let now = self.clock.now();
+        let expires_at = request
+            .expires_in_secs
+            .map(|secs| {
+                i64::try_from(secs)
+                    .ok()
+                    .and_then(Duration::try_seconds)
+                    .and_then(|ttl| now.checked_add_signed(ttl))
+                    .ok_or_else(|| OAuth2Error::invalid_request("expires_in_secs is too large"))
+            })
+            .transpose()?;
+        let registration_token = RegistrationToken {
+            id: uuid::Uuid::new_v4().to_string(),
+            token_hash: hash_token(&token),
+            description: request.description,
+            scope,
+            grant_types: serde_json::to_string(&request.grant_types)
+                .unwrap_or_else(|_| "[]".to_string()),
+            max_uses: i64::from(request.max_uses.unwrap_or(1)),
+            use_count: 0,
+            expires_at,
+            revoked_at: None,
+            created_at: now,
+        };
+        self.db.save_registration_token(&registration_token).await?;
+
+        Ok(IssuedRegistrationToken {
+            token,
+            registration_token,
+        })
+    }
+
+    /// Check a registration against the token sent with it, counting one
+    /// use of the token if it is allowed. Returns the token used, if any.
+    pub async fn admit(
+        &self,
+        bearer: Option<&str>,
+        registration: &ClientRegistration,
+    ) -> Result<Option<RegistrationToken>, OAuth2Error> {
+        let Some(bearer) = bearer else {
+            if self.config.require_token {
+                return Err(OAuth2Error::access_denied(
+                    "Registering a client needs a registration token",
+                ));
+            }
+            return Ok(None);
+        };
+
+        let invalid =
+            || OAuth2Error::access_denied("The registration token is invalid, used up or expired");
+        let token = self
+            .db
+            .get_registration_token_by_hash(&hash_token(bearer))
+            .await?
+            .filter(|token| token.is_usable(self.clock.now()))
+            .ok_or_else(invalid)?;
+        if let Some(reason) = token.refuses(&registration.scope, &registration.grant_types) {
+            return Err(OAuth2Error::access_denied(&reason));
+        }
+        if !self.db.use_registration_token(&token.id).await? {
+            return Err(invalid());
+        }
+        Ok(Some(token))
+    }
+}
@@ -0,0 +1,109 @@
+//! Purging records once they are past their retention window.
+//!
+//! Each data class (admin audit entries and authorization requests, tokens,
+//! authorization codes and stored events) has its own window in
+//! [`RetentionConfig`]. The retention job runs [`RetentionEnforcer::run`]
+//! every interval; on a dry run it only counts what would go, so a new policy
+//! can be checked against real data before anything is deleted.
+
+use crate::clock::SharedClock;
+use crate::config::RetentionConfig;
+use crate::db::Database;
+use crate::events::event_actor::{EventActor, PurgeEvents};
+use crate::metrics::Metrics;
+use crate::models::{OAuth2Error, RetentionReport};
+use actix::Addr;
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Arc;
+
+pub struct RetentionEnforcer {
+    db: Arc<Database>,
+    clock: SharedClock,
+    config: RetentionConfig,
+    metrics: Metrics,
+    event_actor: Option<Addr<EventActor>>,
+}
+
+impl RetentionEnforcer {
+    pub fn new(
+        db: Arc<Database>,
+        clock: SharedClock,
+        config: RetentionConfig,
+        metrics: Metrics,
+        event_actor: Option<Addr<EventActor>>,
+    ) -> Self {
+        Self {
+            db,
+            clock,
+            config,
+            metrics,
+            event_actor,
+        }
+    }
+
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.config.interval_secs.max(1))
+    }
+
+    /// Whether runs only count unless told otherwise
+    pub fn dry_run(&self) -> bool {
+        self.config.dry_run
+    }
+
+    /// Purge, or on a dry run count, every record past its window
+    pub async fn run(&self, dry_run: bool) -> Result<RetentionReport, OAuth2Error> {
+        let now = self.clock.now();
+        let cutoff = |days: u32| (days > 0).then(|| now - Duration::days(i64::from(days)));
+        let mut report = RetentionReport {
+            dry_run,
+            ..Default::default()
+        };
+
+        if let Some(cutoff) = cutoff(self.config.audit_days) {
+            report.audit_entries = self.db.purge_admin_audit_entries(cutoff, dry_run).await?;
+            report.authorization_requests = self
+                .db
+                .purge_authorization_requests(cutoff, dry_run)
+                .await?;
+        }
+        if let Some(cutoff) = cutoff(self.config.token_days) {
+            report.tokens = self.db.purge_tokens(cutoff, dry_run).await?;
+        }
+        if let Some(cutoff) = cutoff(self.config.authorization_code_days) {
+            report.authorization_codes = self.db.purge_authorization_codes(cutoff, dry_run).await?;
+        }
+        if let Some(cutoff) = cutoff(self.config.event_days) {
+            report.events = self.purge_events(cutoff, dry_run).await;
+        }
+
+        self.metrics.record_retention(&report);
+        let total: u64 = report.classes().iter().map(|(_, records)| records).sum();
+        if total > 0 {
+            tracing::info!(
+                dry_run,
+                audit_entries = report.audit_entries,
+                authorization_requests = report.authorization_requests,
+                tokens = report.tokens,
+                authorization_codes = report.authorization_codes,
+                events = report.events,
+                "{} {} records past retention",
+                if dry_run { "Found" } else { "Purged" },
+                total
+            );
+        }
+        Ok(report)
+    }
+
+    async fn purge_events(&self, cutoff: DateTime<Utc>, dry_run: bool) -> u64 {
+        let Some(event_actor) = &self.event_actor else {
+            return 0;
+        };
+        match event_actor.send(PurgeEvents { cutoff, dry_run }).await {
+            Ok(purged) => purged as u64,
+            Err(e) => {
+                tracing::warn!("Failed to purge stored events: {}", e);
+                0
+            }
+        }
+    }
+}
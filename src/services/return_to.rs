@@ -0,0 +1,116 @@
+//! Where to send the user once they have signed in.
+//!
+//! A login can be started with `return_to` naming the page that sent the
+//! user there, such as an authorization request or an application's own
+//! page. Anything else would make the login an open redirect, so a target
+//! is only kept if it is a path on this server or falls under a configured
+//! allowlist prefix. Client redirect URIs do not count, since anyone may
+//! register a client. Targets that are not kept are dropped and the user
+//! lands on the default success page.
+
+use crate::config::LoginConfig;
+use oauth2::url::Url;
+
+/// Where a login continues when it has no (acceptable) target
+pub const DEFAULT_RETURN_TO: &str = "/auth/success";
+
+pub struct ReturnToValidator {
+    allowlist: Vec<Url>,
+}
+
+impl ReturnToValidator {
+    pub fn new(config: &LoginConfig) -> Self {
+        let allowlist = config
+            .return_to_allowlist
+            .iter()
+            .filter_map(|prefix| match Url::parse(prefix) {
+                Ok(url) if is_web_url(&url) => Some(url),
+                _ => {
+                    tracing::warn!("Ignoring invalid return_to allowlist entry {}", prefix);
+                    None
+                }
+            })
+            .collect();
+        Self { allowlist }
+    }
+
+    /// Whether the user may be sent to `target` after signing in
+    pub fn is_allowed(&self, target: &str) -> bool {
+        if is_local_path(target) {
+            return true;
+        }
+        let Ok(url) = Url::parse(target) else {
+            return false;
+        };
+        is_web_url(&url)
+            && self
+                .allowlist
+                .iter()
+                .any(|prefix| is_under_prefix(&url, prefix))
+    }
+}
+
+/// An absolute path on this server. `//host` and `/\host` are excluded,
+/// since browsers read both as a different host.
+fn is_local_path(target: &str) -> bool {
+    target.starts_with('/')
+        && !target.starts_with("//")
+        && !target.contains('\\')
+        && !target.chars().any(char::is_control)
+}
+
+/// An http(s) URL without credentials, which browsers would otherwise
+/// show as part of a convincing-looking host
+fn is_web_url(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+        && url.host_str().is_some()
+        && url.username().is_empty()
+        && url.password().is_none()
+}
+
+/// Same origin as `prefix`, at or below its path
+fn is_under_prefix(url: &Url, prefix: &Url) -> bool {
+    if url.origin() != prefix.origin() {
+        return false;
+    }
+    let base = prefix.path().trim_end_matches('/');
+    let path = url.path();
+    path == base
+        || path
+            .strip_prefix(base)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_paths() {
+        assert!(is_local_path("/oauth/authorize?client_id=abc"));
+        assert!(is_local_path("/portal"));
+        assert!(!is_local_path("//evil.example.com/portal"));
+        assert!(!is_local_path("/\\evil.example.com"));
+        assert!(!is_local_path("https://evil.example.com"));
+        assert!(!is_local_path("portal"));
+    }
+
+    #[test]
+    fn test_allowlist_prefixes() {
+        let prefix = Url::parse("https://app.example.com/dashboard/").unwrap();
+        let allowed = |target: &str| is_under_prefix(&Url::parse(target).unwrap(), &prefix);
+        assert!(allowed("https://app.example.com/dashboard"));
+        assert!(allowed("https://app.example.com/dashboard/reports?id=1"));
+        assert!(!allowed("https://app.example.com/dashboard-admin"));
+        assert!(!allowed("https://app.example.com/"));
+        assert!(!allowed("http://app.example.com/dashboard"));
+        assert!(!allowed("https://app.example.com.evil.com/dashboard"));
+    }
+
+    #[test]
+    fn test_credentials_in_url_are_refused() {
+        let url = Url::parse("https://app.example.com@evil.example.com/").unwrap();
+        assert!(!is_web_url(&url));
+        assert!(!is_web_url(&Url::parse("javascript:alert(1)").unwrap()));
+    }
+}
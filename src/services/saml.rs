@@ -0,0 +1,490 @@
+//! A minimal SAML 2.0 identity provider for applications that cannot use OIDC.
+//!
+//! A service provider sends an AuthnRequest to `/saml/sso` with the
+//! HTTP-Redirect binding. Once the user has signed in, through the same login
+//! and session as everything else, the browser posts a Response carrying a
+//! signed assertion to the provider's assertion consumer service (ACS). Only
+//! providers listed in the service provider file are answered, and only at
+//! the ACS URLs listed for them:
+//!
+//! ```json
+//! {
+//!   "service_providers": [{
+//!     "entity_id": "https://legacy.example.com/saml",
+//!     "acs_urls": ["https://legacy.example.com/saml/acs"],
+//!     "name_id_format": "email"
+//!   }]
+//! }
+//! ```
+//!
+//! Assertions are signed with RSA-SHA256 using exclusive canonicalization.
+//! They are written out in canonical form to begin with, so what is digested
+//! is exactly what is sent. Requests do not have to be signed; the ACS
+//! allowlist keeps assertions from going anywhere else.
+
+use crate::clock::SharedClock;
+use crate::config::SamlConfig;
+use crate::models::{ErrorCode, OAuth2Error, SocialUserInfo};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use flate2::read::DeflateDecoder;
+use jsonwebtoken::{Algorithm, EncodingKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+const PROTOCOL_NS: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const ASSERTION_NS: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const REDIRECT_BINDING: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Redirect";
+
+/// Largest AuthnRequest inflated, so a small request cannot expand without bound
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+/// What a service provider gets as the user's NameID
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NameIdFormat {
+    /// The user's email address
+    #[default]
+    Email,
+    /// Our subject for the user, which does not change with their email
+    Persistent,
+}
+
+impl NameIdFormat {
+    fn urn(self) -> &'static str {
+        match self {
+            NameIdFormat::Email => "urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress",
+            NameIdFormat::Persistent => "urn:oasis:names:tc:SAML:2.0:nameid-format:persistent",
+        }
+    }
+}
+
+/// An application assertions may be issued to
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceProvider {
+    pub entity_id: String,
+    /// Where responses may be posted; the first is used when a request does
+    /// not name one
+    pub acs_urls: Vec<String>,
+    #[serde(default)]
+    pub name_id_format: NameIdFormat,
+}
+
+#[derive(Deserialize)]
+struct ServiceProviderFile {
+    service_providers: Vec<ServiceProvider>,
+}
+
+/// The parts of an AuthnRequest that are acted on
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthnRequest {
+    pub id: String,
+    /// The requesting service provider's entity ID
+    pub issuer: String,
+    pub acs_url: Option<String>,
+}
+
+impl AuthnRequest {
+    /// Decode the `SAMLRequest` parameter of the HTTP-Redirect binding:
+    /// base64 of the raw DEFLATE of the request
+    pub fn from_redirect(saml_request: &str) -> Result<Self, OAuth2Error> {
+        let deflated = general_purpose::STANDARD
+            .decode(saml_request.trim())
+            .map_err(|_| OAuth2Error::invalid_request("SAMLRequest is not base64"))?;
+        let mut xml = String::new();
+        DeflateDecoder::new(deflated.as_slice())
+            .take(MAX_REQUEST_LEN)
+            .read_to_string(&mut xml)
+            .map_err(|_| OAuth2Error::invalid_request("SAMLRequest is not DEFLATE-compressed"))?;
+        Self::parse(&xml)
+    }
+
+    /// Read an AuthnRequest document. Documents with a DTD are refused.
+    pub fn parse(xml: &str) -> Result<Self, OAuth2Error> {
+        let invalid = || OAuth2Error::invalid_request("SAMLRequest is not an AuthnRequest");
+        let document = roxmltree::Document::parse(xml).map_err(|_| invalid())?;
+        let root = document.root_element();
+        if !root.has_tag_name((PROTOCOL_NS, "AuthnRequest")) {
+            return Err(invalid());
+        }
+        let issuer = root
+            .children()
+            .find(|node| node.has_tag_name((ASSERTION_NS, "Issuer")))
+            .and_then(|node| node.text())
+            .map(str::trim)
+            .filter(|issuer| !issuer.is_empty())
+            .ok_or_else(|| OAuth2Error::invalid_request("AuthnRequest has no Issuer"))?;
+        Ok(Self {
+            id: root
+                .attribute("ID")
+                .ok_or_else(|| OAuth2Error::invalid_request("AuthnRequest has no ID"))?
+                .to_string(),
+            issuer: issuer.to_string(),
+            acs_url: root
+                .attribute("AssertionConsumerServiceURL")
+                .map(str::to_string),
+        })
+    }
+}
+
+/// Escape text content as exclusive canonicalization writes it
+fn escape_text(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Escape an attribute value as exclusive canonicalization writes it
+fn escape_attribute(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '"' => out.push_str("&quot;"),
+            '\t' => out.push_str("&#x9;"),
+            '\n' => out.push_str("&#xA;"),
+            '\r' => out.push_str("&#xD;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// An XML ID; these must not start with a digit
+fn new_id() -> String {
+    format!("_{}", uuid::Uuid::new_v4().simple())
+}
+
+/// The base64 DER of the first certificate in a PEM file
+fn pem_certificate(pem: &str) -> Option<String> {
+    let body: String = pem
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != "-----BEGIN CERTIFICATE-----")
+        .skip(1)
+        .take_while(|line| *line != "-----END CERTIFICATE-----")
+        .collect();
+    general_purpose::STANDARD.decode(&body).ok()?;
+    Some(body)
+}
+
+pub struct SamlIdp {
+    entity_id: Option<String>,
+    /// Base64 DER, as it goes into metadata and signatures
+    certificate: String,
+    key: EncodingKey,
+    service_providers: Vec<ServiceProvider>,
+    assertion_lifetime: Duration,
+    clock: SharedClock,
+}
+
+impl SamlIdp {
+    /// The configured identity provider, or `None` when SAML is not set up
+    pub fn open(config: &SamlConfig, clock: SharedClock) -> Result<Option<Self>, String> {
+        let (certificate, key, providers) = match (
+            &config.certificate,
+            &config.private_key,
+            &config.service_providers,
+        ) {
+            (None, None, None) => return Ok(None),
+            (Some(certificate), Some(key), Some(providers)) => (certificate, key, providers),
+            _ => {
+                return Err("OAUTH2_SAML_CERTIFICATE, OAUTH2_SAML_PRIVATE_KEY and \
+                     OAUTH2_SAML_SERVICE_PROVIDERS must be set together"
+                    .to_string())
+            }
+        };
+        let read =
+            |path: &str| std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e));
+
+        let certificate = pem_certificate(&read(certificate)?)
+            .ok_or_else(|| format!("{}: no PEM certificate", certificate))?;
+        let key = EncodingKey::from_rsa_pem(read(key)?.as_bytes())
+            .map_err(|e| format!("{}: {}", key, e))?;
+        let file: ServiceProviderFile =
+            serde_json::from_str(&read(providers)?).map_err(|e| format!("{}: {}", providers, e))?;
+
+        Ok(Some(Self {
+            entity_id: config.entity_id.clone(),
+            certificate,
+            key,
+            service_providers: file.service_providers,
+            assertion_lifetime: Duration::seconds(
+                i64::try_from(config.assertion_lifetime_secs).unwrap_or(i64::MAX),
+            ),
+            clock,
+        }))
+    }
+
+    pub fn service_provider_count(&self) -> usize {
+        self.service_providers.len()
+    }
+
+    /// Our entity ID, given the URL the server is reached at
+    pub fn entity_id(&self, base_url: &str) -> String {
+        self.entity_id
+            .clone()
+            .unwrap_or_else(|| format!("{}/saml/metadata", base_url))
+    }
+
+    /// The IdP metadata document service providers are configured from
+    pub fn metadata(&self, base_url: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<md:EntityDescriptor xmlns:md="urn:oasis:names:tc:SAML:2.0:metadata" xmlns:ds="{dsig}" entityID="{entity_id}">
+  <md:IDPSSODescriptor WantAuthnRequestsSigned="false" protocolSupportEnumeration="{protocol}">
+    <md:KeyDescriptor use="signing">
+      <ds:KeyInfo><ds:X509Data><ds:X509Certificate>{certificate}</ds:X509Certificate></ds:X509Data></ds:KeyInfo>
+    </md:KeyDescriptor>
+    <md:NameIDFormat>{email}</md:NameIDFormat>
+    <md:NameIDFormat>{persistent}</md:NameIDFormat>
+    <md:SingleSignOnService Binding="{binding}" Location="{sso}"/>
+  </md:IDPSSODescriptor>
+</md:EntityDescriptor>
+"#,
+            dsig = DSIG_NS,
+            entity_id = escape_attribute(&self.entity_id(base_url)),
+            protocol = PROTOCOL_NS,
+            certificate = self.certificate,
+            email = NameIdFormat::Email.urn(),
+            persistent = NameIdFormat::Persistent.urn(),
+            binding = REDIRECT_BINDING,
+            sso = escape_attribute(&format!("{}/saml/sso", base_url)),
+        )
+    }
+
+    /// The provider that sent `request`, and the ACS URL to answer at
+    pub fn service_provider(
+        &self,
+        request: &AuthnRequest,
+    ) -> Result<(&ServiceProvider, String), OAuth2Error> {
+        let provider = self
+            .service_providers
+            .iter()
+            .find(|provider| provider.entity_id == request.issuer)
+            .ok_or_else(|| {
+                OAuth2Error::unauthorized_client(&format!(
+                    "Unknown service provider '{}'",
+                    request.issuer
+                ))
+            })?;
+        let acs_url = match &request.acs_url {
+            Some(url) if provider.acs_urls.contains(url) => url.clone(),
+            Some(_) => {
+                return Err(OAuth2Error::invalid_request(
+                    "AssertionConsumerServiceURL is not registered for this service provider",
+                ))
+            }
+            None => provider.acs_urls.first().cloned().ok_or_else(|| {
+                OAuth2Error::invalid_request("Service provider has no registered ACS URL")
+            })?,
+        };
+        Ok((provider, acs_url))
+    }
+
+    /// A Response to `request` asserting that `user` is signed in, with the
+    /// assertion signed
+    pub fn response(
+        &self,
+        base_url: &str,
+        request: &AuthnRequest,
+        provider: &ServiceProvider,
+        acs_url: &str,
+        user: &SocialUserInfo,
+    ) -> Result<String, OAuth2Error> {
+        let now = self.clock.now();
+        let issuer = escape_text(&self.entity_id(base_url));
+        let assertion = self.signed_assertion(&issuer, request, provider, acs_url, user, now)?;
+
+        Ok(format!(
+            concat!(
+                r#"<samlp:Response xmlns:samlp="{protocol}" xmlns:saml="{assertion_ns}" Destination="{destination}" ID="{id}" InResponseTo="{in_response_to}" IssueInstant="{now}" Version="2.0">"#,
+                r#"<saml:Issuer>{issuer}</saml:Issuer>"#,
+                r#"<samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"></samlp:StatusCode></samlp:Status>"#,
+                "{assertion}",
+                "</samlp:Response>"
+            ),
+            protocol = PROTOCOL_NS,
+            assertion_ns = ASSERTION_NS,
+            destination = escape_attribute(acs_url),
+            id = new_id(),
+            in_response_to = escape_attribute(&request.id),
+            now = timestamp(now),
+            issuer = issuer,
+            assertion = assertion,
+        ))
+    }
+
+    fn signed_assertion(
+        &self,
+        issuer: &str,
+        request: &AuthnRequest,
+        provider: &ServiceProvider,
+        acs_url: &str,
+        user: &SocialUserInfo,
+        now: DateTime<Utc>,
+    ) -> Result<String, OAuth2Error> {
+        let id = new_id();
+        let expires = timestamp(now + self.assertion_lifetime);
+        let name_id = match provider.name_id_format {
+            NameIdFormat::Email => user.email.clone(),
+            NameIdFormat::Persistent => user.subject(),
+        };
+        let mut attributes = vec![("email", user.email.clone()), ("subject", user.subject())];
+        if let Some(name) = &user.name {
+            attributes.push(("name", name.clone()));
+        }
+        let attributes: String = attributes
+            .into_iter()
+            .map(|(name, value)| {
+                format!(
+                    r#"<saml:Attribute Name="{}" NameFormat="urn:oasis:names:tc:SAML:2.0:attrname-format:basic"><saml:AttributeValue>{}</saml:AttributeValue></saml:Attribute>"#,
+                    name,
+                    escape_text(&value)
+                )
+            })
+            .collect();
+
+        // Split around where the signature goes, right after the Issuer
+        let head = format!(
+            r#"<saml:Assertion xmlns:saml="{}" ID="{}" IssueInstant="{}" Version="2.0"><saml:Issuer>{}</saml:Issuer>"#,
+            ASSERTION_NS,
+            id,
+            timestamp(now),
+            issuer
+        );
+        let body = format!(
+            concat!(
+                r#"<saml:Subject><saml:NameID Format="{format}">{name_id}</saml:NameID>"#,
+                r#"<saml:SubjectConfirmation Method="urn:oasis:names:tc:SAML:2.0:cm:bearer">"#,
+                r#"<saml:SubjectConfirmationData InResponseTo="{in_response_to}" NotOnOrAfter="{expires}" Recipient="{recipient}"></saml:SubjectConfirmationData>"#,
+                r#"</saml:SubjectConfirmation></saml:Subject>"#,
+                r#"<saml:Conditions NotBefore="{now}" NotOnOrAfter="{expires}"><saml:AudienceRestriction><saml:Audience>{audience}</saml:Audience></saml:AudienceRestriction></saml:Conditions>"#,
+                r#"<saml:AuthnStatement AuthnInstant="{now}" SessionIndex="{session_index}"><saml:AuthnContext><saml:AuthnContextClassRef>urn:oasis:names:tc:SAML:2.0:ac:classes:unspecified</saml:AuthnContextClassRef></saml:AuthnContext></saml:AuthnStatement>"#,
+                r#"<saml:AttributeStatement>{attributes}</saml:AttributeStatement>"#,
+                "</saml:Assertion>"
+            ),
+            format = provider.name_id_format.urn(),
+            name_id = escape_text(&name_id),
+            in_response_to = escape_attribute(&request.id),
+            expires = expires,
+            recipient = escape_attribute(acs_url),
+            now = timestamp(now),
+            audience = escape_text(&provider.entity_id),
+            session_index = new_id(),
+            attributes = attributes,
+        );
+
+        let digest = general_purpose::STANDARD.encode(Sha256::digest(format!("{}{}", head, body)));
+        let signature = self.signature(&id, &digest)?;
+        Ok(format!("{}{}{}", head, signature, body))
+    }
+
+    /// An enveloped signature over the assertion `id` with `digest`
+    fn signature(&self, id: &str, digest: &str) -> Result<String, OAuth2Error> {
+        // SignedInfo is signed as it canonicalizes on its own, which brings
+        // along the namespace it inherits from Signature
+        let signed_info = |namespace: &str| {
+            format!(
+                concat!(
+                    r#"<ds:SignedInfo{namespace}>"#,
+                    r#"<ds:CanonicalizationMethod Algorithm="{c14n}"></ds:CanonicalizationMethod>"#,
+                    r#"<ds:SignatureMethod Algorithm="http://www.w3.org/2001/04/xmldsig-more#rsa-sha256"></ds:SignatureMethod>"#,
+                    r##"<ds:Reference URI="#{id}"><ds:Transforms>"##,
+                    r#"<ds:Transform Algorithm="http://www.w3.org/2000/09/xmldsig#enveloped-signature"></ds:Transform>"#,
+                    r#"<ds:Transform Algorithm="{c14n}"></ds:Transform></ds:Transforms>"#,
+                    r#"<ds:DigestMethod Algorithm="http://www.w3.org/2001/04/xmlenc#sha256"></ds:DigestMethod>"#,
+                    r#"<ds:DigestValue>{digest}</ds:DigestValue></ds:Reference></ds:SignedInfo>"#
+                ),
+                namespace = namespace,
+                c14n = EXC_C14N,
+                id = id,
+                digest = digest,
+            )
+        };
+        let canonical = signed_info(&format!(r#" xmlns:ds="{}""#, DSIG_NS));
+        let signature =
+            jsonwebtoken::crypto::sign(canonical.as_bytes(), &self.key, Algorithm::RS256)
+                .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+        // jsonwebtoken encodes for JWTs; XML signatures use standard base64
+        let signature = general_purpose::URL_SAFE_NO_PAD
+            .decode(signature)
+            .map(|bytes| general_purpose::STANDARD.encode(bytes))
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))?;
+
+        Ok(format!(
+            r#"<ds:Signature xmlns:ds="{}">{}<ds:SignatureValue>{}</ds:SignatureValue><ds:KeyInfo><ds:X509Data><ds:X509Certificate>{}</ds:X509Certificate></ds:X509Data></ds:KeyInfo></ds:Signature>"#,
+            DSIG_NS,
+            signed_info(""),
+            signature,
+            self.certificate
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::DeflateEncoder, Compression};
+    use std::io::Write;
+
+    const REQUEST: &str = r#"<samlp:AuthnRequest xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" ID="_req1" Version="2.0" IssueInstant="2024-01-01T00:00:00Z" AssertionConsumerServiceURL="https://sp.example.com/acs"><saml:Issuer> https://sp.example.com </saml:Issuer></samlp:AuthnRequest>"#;
+
+    fn redirect_encode(xml: &str) -> String {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(xml.as_bytes()).unwrap();
+        general_purpose::STANDARD.encode(encoder.finish().unwrap())
+    }
+
+    #[test]
+    fn test_authn_request_from_redirect_binding() {
+        let request = AuthnRequest::from_redirect(&redirect_encode(REQUEST)).unwrap();
+        assert_eq!(
+            request,
+            AuthnRequest {
+                id: "_req1".to_string(),
+                issuer: "https://sp.example.com".to_string(),
+                acs_url: Some("https://sp.example.com/acs".to_string()),
+            }
+        );
+
+        assert!(AuthnRequest::from_redirect("not base64!").is_err());
+        assert!(AuthnRequest::from_redirect(&general_purpose::STANDARD.encode(REQUEST)).is_err());
+        let logout = REQUEST.replace("AuthnRequest", "LogoutRequest");
+        assert!(AuthnRequest::parse(&logout).is_err());
+        let doctype = format!("<!DOCTYPE x [<!ENTITY e \"e\">]>{}", REQUEST);
+        assert!(AuthnRequest::parse(&doctype).is_err());
+    }
+
+    #[test]
+    fn test_canonical_escaping() {
+        assert_eq!(escape_text("a<b>&\"c\"\r"), "a&lt;b&gt;&amp;\"c\"&#xD;");
+        assert_eq!(
+            escape_attribute("a<b>&\"c\"\t\n"),
+            "a&lt;b>&amp;&quot;c&quot;&#x9;&#xA;"
+        );
+    }
+
+    #[test]
+    fn test_pem_certificate() {
+        let pem = "-----BEGIN CERTIFICATE-----\nTUlJ\nQw==\n-----END CERTIFICATE-----\n";
+        assert_eq!(pem_certificate(pem).as_deref(), Some("TUlJQw=="));
+        assert_eq!(pem_certificate("-----BEGIN CERTIFICATE-----\n!!\n"), None);
+    }
+}
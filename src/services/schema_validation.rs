@@ -0,0 +1,408 @@
+//! Checks request bodies against the schemas in the generated OpenAPI
+//! document.
+//!
+//! A body that does not deserialize into a handler's type otherwise fails
+//! with only serde's first complaint. Checked here first, a client gets
+//! every problem at once, each with a JSON pointer
+//! to the value at fault. The document carries schemas but not operations,
+//! so which schema a route takes is listed in [`REQUEST_BODIES`].
+
+use actix_web::http::Method;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use utoipa::openapi::OpenApi;
+use utoipa::ToSchema;
+
+/// Bodies checked: method, route pattern and the schema the body must match
+pub const REQUEST_BODIES: &[(Method, &str, &str)] = &[
+    (Method::POST, "/oauth/token", "TokenRequest"),
+    (Method::POST, "/oauth/introspect", "IntrospectRequest"),
+    (Method::POST, "/oauth/revoke", "RevokeRequest"),
+    (Method::POST, "/clients/register", "ClientRegistration"),
+    (Method::POST, "/admin/api/clients/import", "ClientExport"),
+    (
+        Method::POST,
+        "/admin/api/tokens/decode",
+        "TokenDecodeRequest",
+    ),
+    (
+        Method::PUT,
+        "/admin/api/clients/by-name/{name}",
+        "ClientState",
+    ),
+    (
+        Method::PUT,
+        "/admin/api/clients/{id}/organization",
+        "OrganizationAssignment",
+    ),
+    (
+        Method::PUT,
+        "/admin/api/clients/{id}/secret-expiry",
+        "SecretExpiryAssignment",
+    ),
+    (Method::POST, "/admin/api/sync", "SyncManifest"),
+    (
+        Method::POST,
+        "/admin/api/organizations",
+        "OrganizationRegistration",
+    ),
+    (
+        Method::POST,
+        "/admin/api/registration-tokens",
+        "RegistrationTokenRequest",
+    ),
+    (Method::POST, "/admin/api/resources", "ResourceRegistration"),
+];
+
+const SCHEMA_REF_PREFIX: &str = "#/components/schemas/";
+
+/// One way a body fails its schema
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct SchemaViolation {
+    /// JSON pointer to the value at fault; empty for the body as a whole
+    pub pointer: String,
+    pub message: String,
+}
+
+impl SchemaViolation {
+    fn new(pointer: &str, message: impl Into<String>) -> Self {
+        Self {
+            pointer: pointer.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+pub struct SchemaValidator {
+    schemas: Map<String, Value>,
+}
+
+impl SchemaValidator {
+    pub fn new(openapi: &OpenApi) -> Self {
+        let schemas = serde_json::to_value(openapi)
+            .ok()
+            .and_then(|mut doc| {
+                doc["components"]["schemas"]
+                    .as_object_mut()
+                    .map(std::mem::take)
+            })
+            .unwrap_or_default();
+        Self { schemas }
+    }
+
+    /// The schema a request to `pattern` must match, if it is checked
+    pub fn schema_for(method: &Method, pattern: &str) -> Option<&'static str> {
+        REQUEST_BODIES
+            .iter()
+            .find(|(m, p, _)| m == method && *p == pattern)
+            .map(|(_, _, schema)| *schema)
+    }
+
+    /// Everything wrong with `body` as an instance of the named schema
+    pub fn validate(&self, schema: &str, body: &Value) -> Vec<SchemaViolation> {
+        let mut violations = Vec::new();
+        match self.schemas.get(schema) {
+            Some(schema) => self.check(schema, body, "", &mut violations),
+            None => tracing::warn!("No OpenAPI schema named {}; body not checked", schema),
+        }
+        violations
+    }
+
+    /// Form fields as a JSON object of strings; a repeated field keeps its
+    /// last value, as the handlers' deserializer would reject it anyway
+    pub fn form_to_json(body: &[u8]) -> Result<Value, SchemaViolation> {
+        let fields: Vec<(String, String)> = serde_urlencoded::from_bytes(body)
+            .map_err(|e| SchemaViolation::new("", format!("is not a valid form: {}", e)))?;
+        Ok(Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name, Value::String(value)))
+                .collect(),
+        ))
+    }
+
+    fn resolve<'a>(&'a self, schema: &'a Value) -> Option<&'a Value> {
+        match schema.get("$ref").and_then(Value::as_str) {
+            Some(reference) => reference
+                .strip_prefix(SCHEMA_REF_PREFIX)
+                .and_then(|name| self.schemas.get(name)),
+            None => Some(schema),
+        }
+    }
+
+    fn check(&self, schema: &Value, value: &Value, pointer: &str, out: &mut Vec<SchemaViolation>) {
+        let Some(schema) = self.resolve(schema) else {
+            return;
+        };
+
+        if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+            for part in all {
+                self.check(part, value, pointer, out);
+            }
+        }
+        if let Some(alternatives) = schema
+            .get("oneOf")
+            .or_else(|| schema.get("anyOf"))
+            .and_then(Value::as_array)
+        {
+            self.check_alternatives(alternatives, value, pointer, out);
+        }
+
+        if let Some(types) = schema.get("type") {
+            let allowed: Vec<&str> = match types {
+                Value::String(t) => vec![t.as_str()],
+                Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !allowed.is_empty() && !allowed.iter().any(|t| is_type(value, t)) {
+                out.push(SchemaViolation::new(
+                    pointer,
+                    format!(
+                        "expected {}, found {}",
+                        allowed.join(" or "),
+                        type_name(value)
+                    ),
+                ));
+                return;
+            }
+        }
+
+        if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+            if !options.contains(value) {
+                let options: Vec<String> = options.iter().map(Value::to_string).collect();
+                out.push(SchemaViolation::new(
+                    pointer,
+                    format!("must be one of {}", options.join(", ")),
+                ));
+            }
+        }
+
+        if let Some(number) = value.as_f64() {
+            if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+                if number < minimum {
+                    out.push(SchemaViolation::new(
+                        pointer,
+                        format!("must be at least {}", minimum),
+                    ));
+                }
+            }
+            if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+                if number > maximum {
+                    out.push(SchemaViolation::new(
+                        pointer,
+                        format!("must be at most {}", maximum),
+                    ));
+                }
+            }
+        }
+
+        match value {
+            Value::Object(fields) => self.check_object(schema, fields, pointer, out),
+            Value::Array(items) => {
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        self.check(item_schema, item, &format!("{}/{}", pointer, i), out);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn check_object(
+        &self,
+        schema: &Value,
+        fields: &Map<String, Value>,
+        pointer: &str,
+        out: &mut Vec<SchemaViolation>,
+    ) {
+        let properties = schema.get("properties").and_then(Value::as_object);
+
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !fields.contains_key(name) {
+                    out.push(SchemaViolation::new(&child(pointer, name), "is required"));
+                }
+            }
+        }
+
+        for (name, field) in fields {
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(field_schema) => self.check(field_schema, field, &child(pointer, name), out),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => out.push(SchemaViolation::new(
+                        &child(pointer, name),
+                        "is not allowed",
+                    )),
+                    Some(extra @ Value::Object(_)) => {
+                        self.check(extra, field, &child(pointer, name), out)
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    /// `oneOf`/`anyOf`: matching any alternative will do. When only one
+    /// alternative besides `null` is left, its own complaints are more use
+    /// than "matches none".
+    fn check_alternatives(
+        &self,
+        alternatives: &[Value],
+        value: &Value,
+        pointer: &str,
+        out: &mut Vec<SchemaViolation>,
+    ) {
+        let mut failures = Vec::new();
+        for alternative in alternatives {
+            let mut violations = Vec::new();
+            self.check(alternative, value, pointer, &mut violations);
+            if violations.is_empty() {
+                return;
+            }
+            failures.push((alternative, violations));
+        }
+
+        let not_null: Vec<_> = failures
+            .into_iter()
+            .filter(|(alternative, _)| alternative.get("type") != Some(&Value::from("null")))
+            .collect();
+        match <[_; 1]>::try_from(not_null) {
+            Ok([(_, violations)]) => out.extend(violations),
+            Err(_) => out.push(SchemaViolation::new(
+                pointer,
+                "does not match any of the allowed forms",
+            )),
+        }
+    }
+}
+
+fn child(pointer: &str, name: &str) -> String {
+    format!("{}/{}", pointer, name.replace('~', "~0").replace('/', "~1"))
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ApiDoc;
+    use serde_json::json;
+    use utoipa::OpenApi as _;
+
+    fn validator() -> SchemaValidator {
+        SchemaValidator::new(&ApiDoc::openapi())
+    }
+
+    #[test]
+    fn test_every_checked_route_has_a_schema() {
+        let validator = validator();
+        for (_, pattern, schema) in REQUEST_BODIES {
+            assert!(
+                validator.schemas.contains_key(*schema),
+                "{} names missing schema {}",
+                pattern,
+                schema
+            );
+        }
+    }
+
+    #[test]
+    fn test_violations_point_at_each_problem() {
+        let violations = validator().validate(
+            "ClientRegistration",
+            &json!({
+                "client_name": 7,
+                "redirect_uris": "https://app.example.com/callback",
+                "grant_types": ["authorization_code", null],
+            }),
+        );
+        assert_eq!(
+            violations,
+            vec![
+                SchemaViolation::new("/scope", "is required"),
+                SchemaViolation::new("/client_name", "expected string, found integer"),
+                SchemaViolation::new("/grant_types/1", "expected string, found null"),
+                SchemaViolation::new("/redirect_uris", "expected array, found string"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_references_enums_and_nullable_fields() {
+        let validator = validator();
+        let resource = |token_format: Value| {
+            json!({
+                "identifier": "https://api.example.com",
+                "name": "API",
+                "scopes": ["read"],
+                "token_format": token_format,
+            })
+        };
+        assert!(validator
+            .validate("ResourceRegistration", &resource(json!("opaque")))
+            .is_empty());
+        assert!(validator
+            .validate("ResourceRegistration", &resource(Value::Null))
+            .is_empty());
+        assert_eq!(
+            validator.validate("ResourceRegistration", &resource(json!("saml"))),
+            vec![SchemaViolation::new(
+                "/token_format",
+                "must be one of \"jwt\", \"opaque\""
+            )]
+        );
+
+        // DeclaredClient is ClientState plus a name
+        let violations = validator.validate(
+            "SyncManifest",
+            &json!({"clients": [{"redirect_uris": [], "grant_types": [], "scope": ""}]}),
+        );
+        assert_eq!(
+            violations,
+            vec![SchemaViolation::new("/clients/0/name", "is required")]
+        );
+
+        assert_eq!(
+            validator.validate("ClientExport", &json!({"version": -1, "clients": []})),
+            vec![SchemaViolation::new("/version", "must be at least 0")]
+        );
+    }
+
+    #[test]
+    fn test_form_bodies() {
+        let validator = validator();
+        let form =
+            SchemaValidator::form_to_json(b"grant_type=client_credentials&client_id=app").unwrap();
+        assert!(validator.validate("TokenRequest", &form).is_empty());
+
+        let form = SchemaValidator::form_to_json(b"client_id=app").unwrap();
+        assert_eq!(
+            validator.validate("TokenRequest", &form),
+            vec![SchemaViolation::new("/grant_type", "is required")]
+        );
+    }
+}
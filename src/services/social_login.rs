@@ -1,11 +1,16 @@
 #![allow(dead_code)]
 
-use crate::models::{OAuth2Error, ProviderConfig, SocialUserInfo};
+use crate::models::{ErrorCode, OAuth2Error, ProviderConfig, SocialUserInfo};
+use crate::services::ProviderClient;
 use oauth2::{
-    basic::BasicClient, AuthUrl, ClientId, ClientSecret, EndpointNotSet, EndpointSet, RedirectUrl,
-    TokenUrl,
+    basic::BasicClient, url::Url, AuthUrl, ClientId, ClientSecret, EndpointNotSet, EndpointSet,
+    RedirectUrl, TokenUrl,
 };
 use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Most pages of a user's group memberships read from Microsoft Graph
+const MAX_GROUP_PAGES: usize = 10;
 
 // Type alias for a fully configured OAuth2 client with all required endpoints set.
 // This is necessary due to oauth2 5.0's typestate pattern which tracks endpoint
@@ -31,21 +36,32 @@ type ConfiguredClient = oauth2::Client<
 
 pub struct SocialLoginService;
 
+/// Use the configured override for a provider endpoint, or its public default
+fn endpoint(configured: &Option<String>, default: &str) -> String {
+    configured.clone().unwrap_or_else(|| default.to_string())
+}
+
 impl SocialLoginService {
     pub fn get_google_client(config: &ProviderConfig) -> Result<ConfiguredClient, OAuth2Error> {
         Ok(BasicClient::new(ClientId::new(config.client_id.clone()))
             .set_client_secret(ClientSecret::new(config.client_secret.clone()))
             .set_auth_uri(
-                AuthUrl::new("https://accounts.google.com/o/oauth2/v2/auth".to_string())
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                AuthUrl::new(endpoint(
+                    &config.auth_url,
+                    "https://accounts.google.com/o/oauth2/v2/auth",
+                ))
+                .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_token_uri(
-                TokenUrl::new("https://oauth2.googleapis.com/token".to_string())
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                TokenUrl::new(endpoint(
+                    &config.token_url,
+                    "https://oauth2.googleapis.com/token",
+                ))
+                .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_redirect_uri(
                 RedirectUrl::new(config.redirect_uri.clone())
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             ))
     }
 
@@ -54,22 +70,26 @@ impl SocialLoginService {
         Ok(BasicClient::new(ClientId::new(config.client_id.clone()))
             .set_client_secret(ClientSecret::new(config.client_secret.clone()))
             .set_auth_uri(
-                AuthUrl::new(format!(
-                    "https://login.microsoftonline.com/{}/oauth2/v2.0/authorize",
-                    tenant
-                ))
-                .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                AuthUrl::new(config.auth_url.clone().unwrap_or_else(|| {
+                    format!(
+                        "https://login.microsoftonline.com/{}/oauth2/v2.0/authorize",
+                        tenant
+                    )
+                }))
+                .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_token_uri(
-                TokenUrl::new(format!(
-                    "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
-                    tenant
-                ))
-                .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                TokenUrl::new(config.token_url.clone().unwrap_or_else(|| {
+                    format!(
+                        "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                        tenant
+                    )
+                }))
+                .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_redirect_uri(
                 RedirectUrl::new(config.redirect_uri.clone())
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             ))
     }
 
@@ -77,16 +97,22 @@ impl SocialLoginService {
         Ok(BasicClient::new(ClientId::new(config.client_id.clone()))
             .set_client_secret(ClientSecret::new(config.client_secret.clone()))
             .set_auth_uri(
-                AuthUrl::new("https://github.com/login/oauth/authorize".to_string())
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                AuthUrl::new(endpoint(
+                    &config.auth_url,
+                    "https://github.com/login/oauth/authorize",
+                ))
+                .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_token_uri(
-                TokenUrl::new("https://github.com/login/oauth/access_token".to_string())
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                TokenUrl::new(endpoint(
+                    &config.token_url,
+                    "https://github.com/login/oauth/access_token",
+                ))
+                .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_redirect_uri(
                 RedirectUrl::new(config.redirect_uri.clone())
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             ))
     }
 
@@ -99,15 +125,15 @@ impl SocialLoginService {
             .set_client_secret(ClientSecret::new(config.client_secret.clone()))
             .set_auth_uri(
                 AuthUrl::new(format!("https://{}/oauth2/default/v1/authorize", domain))
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_token_uri(
                 TokenUrl::new(format!("https://{}/oauth2/default/v1/token", domain))
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_redirect_uri(
                 RedirectUrl::new(config.redirect_uri.clone())
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             ))
     }
 
@@ -120,26 +146,154 @@ impl SocialLoginService {
             .set_client_secret(ClientSecret::new(config.client_secret.clone()))
             .set_auth_uri(
                 AuthUrl::new(format!("https://{}/authorize", domain))
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_token_uri(
                 TokenUrl::new(format!("https://{}/oauth/token", domain))
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             )
             .set_redirect_uri(
                 RedirectUrl::new(config.redirect_uri.clone())
-                    .map_err(|e| OAuth2Error::new("invalid_configuration", Some(&e.to_string())))?,
+                    .map_err(|e| OAuth2Error::internal(ErrorCode::InvalidConfiguration, e))?,
             ))
     }
 
-    pub async fn fetch_google_user_info(access_token: &str) -> Result<SocialUserInfo, OAuth2Error> {
-        let client = reqwest::Client::new();
+    /// The client for a provider with a login handler
+    pub fn get_client(
+        provider: &str,
+        config: &ProviderConfig,
+    ) -> Result<ConfiguredClient, OAuth2Error> {
+        match provider {
+            "google" => Self::get_google_client(config),
+            "microsoft" => Self::get_microsoft_client(config),
+            "github" => Self::get_github_client(config),
+            _ => Err(OAuth2Error::new(
+                ErrorCode::ProviderNotConfigured.as_str(),
+                Some("Unsupported provider"),
+            )),
+        }
+    }
+
+    /// The profile of the user `access_token` belongs to, from a provider
+    /// with a login handler
+    pub async fn fetch_user_info(
+        client: &ProviderClient,
+        config: &ProviderConfig,
+        access_token: &str,
+    ) -> Result<SocialUserInfo, OAuth2Error> {
+        match client.provider() {
+            "google" => Self::fetch_google_user_info(client, config, access_token).await,
+            "microsoft" => Self::fetch_microsoft_user_info(client, config, access_token).await,
+            "github" => Self::fetch_github_user_info(client, config, access_token).await,
+            _ => Err(OAuth2Error::new(
+                ErrorCode::ProviderNotConfigured.as_str(),
+                Some("Unsupported provider"),
+            )),
+        }
+    }
+
+    /// Scopes a login with `provider` asks for. Connecting the provider adds
+    /// its configured API scopes, and on Microsoft offline access; Google
+    /// asks for offline access with a parameter instead.
+    pub fn login_scopes(provider: &str, config: &ProviderConfig, connect: bool) -> Vec<String> {
+        let mut scopes: Vec<String> = match provider {
+            "github" => vec!["user:email".to_string()],
+            _ => vec![
+                "openid".to_string(),
+                "email".to_string(),
+                "profile".to_string(),
+            ],
+        };
+        if connect {
+            if provider == "microsoft" {
+                scopes.push("offline_access".to_string());
+            }
+            for scope in &config.api_scopes {
+                if !scopes.contains(scope) {
+                    scopes.push(scope.clone());
+                }
+            }
+        }
+        scopes
+    }
+
+    /// Where to send a user signing out so the provider ends its session
+    /// too, if the provider is set up for it
+    pub fn logout_url(provider: &str, config: &ProviderConfig) -> Option<String> {
+        if !config.upstream_logout {
+            return None;
+        }
+        let return_to = config.post_logout_redirect_uri.clone().or_else(|| {
+            let callback = Url::parse(&config.redirect_uri).ok()?;
+            Some(callback.join("/auth/login").ok()?.to_string())
+        })?;
+        let client_id = config.client_id.clone();
+        let domain = config.domain.as_deref();
+
+        let (default_url, params) = match provider {
+            // Google signs the browser out of every Google account and takes
+            // no return address
+            "google" => (
+                Some("https://accounts.google.com/Logout".to_string()),
+                vec![],
+            ),
+            "microsoft" | "azure" => (
+                Some(format!(
+                    "https://login.microsoftonline.com/{}/oauth2/v2.0/logout",
+                    config.tenant_id.as_deref().unwrap_or("common")
+                )),
+                vec![("post_logout_redirect_uri", return_to)],
+            ),
+            "okta" => (
+                domain.map(|domain| format!("https://{}/oauth2/default/v1/logout", domain)),
+                vec![
+                    ("client_id", client_id),
+                    ("post_logout_redirect_uri", return_to),
+                ],
+            ),
+            "auth0" => (
+                domain.map(|domain| format!("https://{}/v2/logout", domain)),
+                vec![("client_id", client_id), ("returnTo", return_to)],
+            ),
+            // OpenID Connect RP-initiated logout parameters
+            _ => (
+                None,
+                vec![
+                    ("client_id", client_id),
+                    ("post_logout_redirect_uri", return_to),
+                ],
+            ),
+        };
+
+        let Some(logout_url) = config.logout_url.clone().or(default_url) else {
+            tracing::warn!("{} has upstream logout on but no logout URL", provider);
+            return None;
+        };
+        let mut url = Url::parse(&logout_url)
+            .map_err(|e| tracing::warn!("Invalid {} logout URL: {}", provider, e))
+            .ok()?;
+        if !params.is_empty() {
+            url.query_pairs_mut().extend_pairs(params);
+        }
+        Some(url.to_string())
+    }
+
+    pub async fn fetch_google_user_info(
+        client: &ProviderClient,
+        config: &ProviderConfig,
+        access_token: &str,
+    ) -> Result<SocialUserInfo, OAuth2Error> {
+        let request = client
+            .http()
+            .get(endpoint(
+                &config.userinfo_url,
+                "https://www.googleapis.com/oauth2/v2/userinfo",
+            ))
+            .bearer_auth(access_token);
         let response = client
-            .get("https://www.googleapis.com/oauth2/v2/userinfo")
-            .bearer_auth(access_token)
-            .send()
+            .send("userinfo", request)
             .await
-            .map_err(|e| OAuth2Error::new("provider_error", Some(&e.to_string())))?;
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?;
 
         #[derive(Deserialize)]
         struct GoogleUser {
@@ -147,32 +301,43 @@ impl SocialLoginService {
             email: String,
             name: Option<String>,
             picture: Option<String>,
+            /// Google Workspace domain, absent for consumer accounts
+            hd: Option<String>,
         }
 
         let user: GoogleUser = response
             .json()
             .await
-            .map_err(|e| OAuth2Error::new("provider_error", Some(&e.to_string())))?;
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?;
 
+        let mut upstream_claims = BTreeMap::new();
+        if let Some(hd) = user.hd {
+            upstream_claims.insert("hd".to_string(), vec![hd]);
+        }
         Ok(SocialUserInfo {
             provider: "google".to_string(),
             provider_user_id: user.id,
             email: user.email,
             name: user.name,
             picture: user.picture,
+            upstream_claims,
+            roles: Vec::new(),
+            groups: Vec::new(),
+            claims: BTreeMap::new(),
         })
     }
 
     pub async fn fetch_microsoft_user_info(
+        client: &ProviderClient,
+        config: &ProviderConfig,
         access_token: &str,
     ) -> Result<SocialUserInfo, OAuth2Error> {
-        let client = reqwest::Client::new();
+        let me_url = endpoint(&config.userinfo_url, "https://graph.microsoft.com/v1.0/me");
+        let request = client.http().get(&me_url).bearer_auth(access_token);
         let response = client
-            .get("https://graph.microsoft.com/v1.0/me")
-            .bearer_auth(access_token)
-            .send()
+            .send("userinfo", request)
             .await
-            .map_err(|e| OAuth2Error::new("provider_error", Some(&e.to_string())))?;
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?;
 
         #[derive(Deserialize)]
         struct MicrosoftUser {
@@ -186,26 +351,87 @@ impl SocialLoginService {
         let user: MicrosoftUser = response
             .json()
             .await
-            .map_err(|e| OAuth2Error::new("provider_error", Some(&e.to_string())))?;
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?;
 
+        // Reading memberships needs the GroupMember.Read.All permission, so
+        // only ask when a mapping rule uses them
+        let mut upstream_claims = BTreeMap::new();
+        if config.maps_claim("groups") {
+            let groups =
+                Self::fetch_microsoft_groups(client, &format!("{}/memberOf", me_url), access_token)
+                    .await?;
+            upstream_claims.insert("groups".to_string(), groups);
+        }
         Ok(SocialUserInfo {
             provider: "microsoft".to_string(),
             provider_user_id: user.id,
             email: user.email,
             name: user.name,
             picture: None,
+            upstream_claims,
+            roles: Vec::new(),
+            groups: Vec::new(),
+            claims: BTreeMap::new(),
         })
     }
 
-    pub async fn fetch_github_user_info(access_token: &str) -> Result<SocialUserInfo, OAuth2Error> {
-        let client = reqwest::Client::new();
-        let response = client
-            .get("https://api.github.com/user")
+    /// Ids and display names of the groups a user is a member of, so rules
+    /// can name a group either way
+    async fn fetch_microsoft_groups(
+        client: &ProviderClient,
+        member_of_url: &str,
+        access_token: &str,
+    ) -> Result<Vec<String>, OAuth2Error> {
+        #[derive(Deserialize)]
+        struct Membership {
+            id: String,
+            #[serde(rename = "displayName")]
+            name: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        struct MembershipPage {
+            value: Vec<Membership>,
+            #[serde(rename = "@odata.nextLink")]
+            next_link: Option<String>,
+        }
+
+        let mut groups = Vec::new();
+        let mut next = Some(member_of_url.to_string());
+        for _ in 0..MAX_GROUP_PAGES {
+            let Some(url) = next.take() else { break };
+            let request = client.http().get(&url).bearer_auth(access_token);
+            let page: MembershipPage = client
+                .send("groups", request)
+                .await
+                .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?
+                .json()
+                .await
+                .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?;
+            for membership in page.value {
+                groups.push(membership.id);
+                groups.extend(membership.name);
+            }
+            next = page.next_link;
+        }
+        Ok(groups)
+    }
+
+    pub async fn fetch_github_user_info(
+        client: &ProviderClient,
+        config: &ProviderConfig,
+        access_token: &str,
+    ) -> Result<SocialUserInfo, OAuth2Error> {
+        let user_url = endpoint(&config.userinfo_url, "https://api.github.com/user");
+        let request = client
+            .http()
+            .get(&user_url)
             .bearer_auth(access_token)
-            .header("User-Agent", "rust_oauth2_server")
-            .send()
+            .header("User-Agent", "rust_oauth2_server");
+        let response = client
+            .send("userinfo", request)
             .await
-            .map_err(|e| OAuth2Error::new("provider_error", Some(&e.to_string())))?;
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?;
 
         #[derive(Deserialize)]
         struct GitHubUser {
@@ -218,20 +444,22 @@ impl SocialLoginService {
         let user: GitHubUser = response
             .json()
             .await
-            .map_err(|e| OAuth2Error::new("provider_error", Some(&e.to_string())))?;
+            .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?;
 
         // GitHub might not provide email in the main call
         let email = if let Some(email) = user.email {
             email
         } else {
             // Fetch primary email
-            let email_response = client
-                .get("https://api.github.com/user/emails")
+            let request = client
+                .http()
+                .get(format!("{}/emails", user_url))
                 .bearer_auth(access_token)
-                .header("User-Agent", "rust_oauth2_server")
-                .send()
+                .header("User-Agent", "rust_oauth2_server");
+            let email_response = client
+                .send("emails", request)
                 .await
-                .map_err(|e| OAuth2Error::new("provider_error", Some(&e.to_string())))?;
+                .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?;
 
             #[derive(Deserialize)]
             struct GitHubEmail {
@@ -242,7 +470,7 @@ impl SocialLoginService {
             let emails: Vec<GitHubEmail> = email_response
                 .json()
                 .await
-                .map_err(|e| OAuth2Error::new("provider_error", Some(&e.to_string())))?;
+                .map_err(|e| OAuth2Error::internal(ErrorCode::ProviderError, e))?;
 
             emails
                 .into_iter()
@@ -257,6 +485,92 @@ impl SocialLoginService {
             email,
             name: user.name,
             picture: user.avatar_url,
+            upstream_claims: BTreeMap::new(),
+            roles: Vec::new(),
+            groups: Vec::new(),
+            claims: BTreeMap::new(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(upstream_logout: bool) -> ProviderConfig {
+        ProviderConfig {
+            client_id: "our-client".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_uri: "https://auth.example.com/auth/callback/microsoft".to_string(),
+            tenant_id: Some("contoso".to_string()),
+            domain: Some("tenant.auth0.com".to_string()),
+            auth_url: None,
+            token_url: None,
+            userinfo_url: None,
+            upstream_logout,
+            logout_url: None,
+            post_logout_redirect_uri: None,
+            store_tokens: false,
+            api_scopes: vec![],
+            claim_mappings: vec![],
+        }
+    }
+
+    #[test]
+    fn test_upstream_logout_urls() {
+        assert_eq!(
+            SocialLoginService::logout_url("microsoft", &provider(false)),
+            None
+        );
+        assert_eq!(
+            SocialLoginService::logout_url("microsoft", &provider(true)).as_deref(),
+            Some(
+                "https://login.microsoftonline.com/contoso/oauth2/v2.0/logout\
+                 ?post_logout_redirect_uri=https%3A%2F%2Fauth.example.com%2Fauth%2Flogin"
+            )
+        );
+        assert_eq!(
+            SocialLoginService::logout_url("auth0", &provider(true)).as_deref(),
+            Some(
+                "https://tenant.auth0.com/v2/logout\
+                 ?client_id=our-client&returnTo=https%3A%2F%2Fauth.example.com%2Fauth%2Flogin"
+            )
+        );
+        assert_eq!(
+            SocialLoginService::logout_url("google", &provider(true)).as_deref(),
+            Some("https://accounts.google.com/Logout")
+        );
+        // GitHub has no logout endpoint of its own
+        assert_eq!(
+            SocialLoginService::logout_url("github", &provider(true)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_connecting_asks_for_api_scopes() {
+        let mut config = provider(false);
+        config.api_scopes = vec![
+            "https://graph.microsoft.com/Calendars.Read".to_string(),
+            "email".to_string(),
+        ];
+        assert_eq!(
+            SocialLoginService::login_scopes("microsoft", &config, false),
+            ["openid", "email", "profile"]
+        );
+        assert_eq!(
+            SocialLoginService::login_scopes("microsoft", &config, true),
+            [
+                "openid",
+                "email",
+                "profile",
+                "offline_access",
+                "https://graph.microsoft.com/Calendars.Read"
+            ]
+        );
+        assert_eq!(
+            SocialLoginService::login_scopes("github", &provider(false), true),
+            ["user:email"]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Decoding a token for support: what it says, what we stored for it, and
+//! every check it would fail.
+//!
+//! The checks are the ones the server makes when the token is presented:
+//! the signature, `exp` and `nbf` with the configured leeway, and the stored
+//! record's revocation and expiry. Unlike validation they do not stop at the
+//! first failure, and the claims are shown even when the signature is bad.
+
+use crate::clock::Clock;
+use crate::config::JwtConfig;
+use crate::db::Database;
+use crate::keys::SigningKeys;
+use crate::models::{
+    OAuth2Error, TokenCheck, TokenFailure, TokenFormat, TokenInspection, TokenRecordStatus,
+};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::DateTime;
+use jsonwebtoken::errors::ErrorKind;
+use serde_json::Value;
+
+pub async fn inspect_token(
+    db: &Database,
+    jwt: &JwtConfig,
+    keys: &SigningKeys,
+    clock: &dyn Clock,
+    token: &str,
+    audience: Option<&str>,
+) -> Result<TokenInspection, OAuth2Error> {
+    let mut failures = Vec::new();
+    let mut fail = |check, reason: String| failures.push(TokenFailure { check, reason });
+    let now = clock.now().timestamp();
+
+    let header = jsonwebtoken::decode_header(token).ok();
+    let format = if header.is_some() {
+        TokenFormat::Jwt
+    } else {
+        TokenFormat::Opaque
+    };
+
+    let mut claims = None;
+    if header.is_some() {
+        claims = unverified_claims(token);
+        if claims.is_none() {
+            fail(
+                TokenCheck::Malformed,
+                "the payload is not base64url-encoded JSON".to_string(),
+            );
+        } else if let Some(failure) = signature_failure(token, keys) {
+            fail(failure.check, failure.reason);
+        }
+    }
+
+    if let Some(claims) = &claims {
+        let leeway = i64::try_from(jwt.leeway_secs).unwrap_or(i64::MAX);
+        match claims["exp"].as_i64() {
+            Some(exp) if exp.saturating_add(leeway) < now => fail(
+                TokenCheck::Expired,
+                format!("expired at {} ({}s ago)", timestamp(exp), now - exp),
+            ),
+            Some(_) => {}
+            None => fail(TokenCheck::Malformed, "there is no exp claim".to_string()),
+        }
+        let iat = claims["iat"].as_i64();
+        let not_before = claims["nbf"].as_i64().max(iat);
+        if let Some(not_before) = not_before.filter(|nbf| nbf.saturating_sub(leeway) > now) {
+            fail(
+                TokenCheck::NotYetValid,
+                format!(
+                    "not valid until {} ({}s from now)",
+                    timestamp(not_before),
+                    not_before - now
+                ),
+            );
+        }
+        if let Some(expected) = audience {
+            let audiences: Vec<&str> = match &claims["aud"] {
+                Value::String(aud) => vec![aud.as_str()],
+                Value::Array(auds) => auds.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !audiences.contains(&expected) {
+                fail(
+                    TokenCheck::Audience,
+                    format!("issued for {:?}, not {}", audiences, expected),
+                );
+            }
+        }
+    }
+
+    let record = db.get_token_by_access_token(token).await?;
+    match &record {
+        None => fail(
+            TokenCheck::Unknown,
+            "no token with this value was issued by this server, or its record has been purged"
+                .to_string(),
+        ),
+        Some(record) => {
+            if record.revoked {
+                fail(
+                    TokenCheck::Revoked,
+                    "the token has been revoked".to_string(),
+                );
+            }
+            // Opaque tokens have only the record to go by
+            if claims.is_none() {
+                if record.is_expired(clock) {
+                    fail(
+                        TokenCheck::Expired,
+                        format!("expired at {}", record.expires_at.to_rfc3339()),
+                    );
+                }
+                let issued_for = record.audience.as_deref().unwrap_or(&record.client_id);
+                if let Some(expected) = audience.filter(|expected| *expected != issued_for) {
+                    fail(
+                        TokenCheck::Audience,
+                        format!("issued for {}, not {}", issued_for, expected),
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(TokenInspection {
+        format,
+        header: header.and_then(|header| serde_json::to_value(header).ok()),
+        claims,
+        record: record.as_ref().map(TokenRecordStatus::from),
+        valid: failures.is_empty(),
+        failures,
+    })
+}
+
+/// The claims segment as sent, without checking the signature
+fn unverified_claims(token: &str) -> Option<Value> {
+    let payload = token.split('.').nth(1)?;
+    let bytes = general_purpose::URL_SAFE_NO_PAD.decode(payload).ok()?;
+    serde_json::from_slice::<Value>(&bytes)
+        .ok()
+        .filter(Value::is_object)
+}
+
+/// Why the signature does not hold, if it does not. Times and audience are
+/// checked separately so each failure is reported on its own.
+fn signature_failure(token: &str, keys: &SigningKeys) -> Option<TokenFailure> {
+    let mut validation = keys.validation();
+    validation.validate_exp = false;
+    validation.validate_nbf = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+
+    let err = jsonwebtoken::decode::<Value>(token, keys.decoding_key(), &validation).err()?;
+    let (check, reason) = match err.kind() {
+        ErrorKind::InvalidSignature => (
+            TokenCheck::Signature,
+            "the signature does not match this server's signing key".to_string(),
+        ),
+        ErrorKind::InvalidAlgorithm => (
+            TokenCheck::Signature,
+            format!(
+                "signed with an algorithm other than {:?}",
+                validation.algorithms[0]
+            ),
+        ),
+        _ => (TokenCheck::Malformed, err.to_string()),
+    };
+    Some(TokenFailure { check, reason })
+}
+
+fn timestamp(secs: i64) -> String {
+    DateTime::from_timestamp(secs, 0).map_or_else(|| secs.to_string(), |time| time.to_rfc3339())
+}
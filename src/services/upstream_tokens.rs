@@ -0,0 +1,297 @@
+//! Provider tokens kept for users who connect a social login provider.
+//!
+//! A plain social login only uses the provider's access token to read the
+//! profile and then drops it. A user can instead *connect* a provider that is
+//! configured with `store_tokens`: the login asks for the provider's API
+//! scopes and offline access, and the tokens it returns are kept so the
+//! server can call the provider for them later. Tokens are encrypted with
+//! AES-256-GCM under `OAUTH2_UPSTREAM_TOKEN_KEY`, bound to the user and
+//! provider so a row copied to another user does not decrypt. Users
+//! disconnect from their account page; admins purge through the admin API.
+
+use crate::clock::SharedClock;
+use crate::config::UpstreamTokenConfig;
+use crate::db::Database;
+use crate::models::{
+    ErrorCode, OAuth2Error, SocialLoginConfig, SocialUserInfo, UpstreamConnection, UpstreamToken,
+};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use oauth2::TokenResponse;
+use rand::RngCore;
+use std::sync::Arc;
+
+const NONCE_LEN: usize = 12;
+
+/// Tokens a provider issued, in the clear
+#[derive(Debug, Clone)]
+pub struct UpstreamGrant {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// Scopes granted, space separated
+    pub scope: String,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl UpstreamGrant {
+    /// The grant in a token response. Providers that do not echo the scope
+    /// granted what was asked for.
+    pub fn from_response<T: TokenResponse>(
+        response: &T,
+        requested: &[String],
+        now: DateTime<Utc>,
+    ) -> Self {
+        let scope = match response.scopes() {
+            Some(scopes) if !scopes.is_empty() => scopes
+                .iter()
+                .map(|scope| scope.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+            _ => requested.join(" "),
+        };
+        Self {
+            access_token: response.access_token().secret().clone(),
+            refresh_token: response.refresh_token().map(|token| token.secret().clone()),
+            scope,
+            expires_at: response
+                .expires_in()
+                .and_then(|ttl| Duration::from_std(ttl).ok())
+                .map(|ttl| now + ttl),
+        }
+    }
+}
+
+pub struct UpstreamTokenVault {
+    db: Arc<Database>,
+    cipher: Option<Aes256Gcm>,
+    social: Arc<SocialLoginConfig>,
+    clock: SharedClock,
+}
+
+/// What a token is bound to, so it only decrypts for the row it was stored in
+fn associated_data(subject: &str, provider: &str) -> String {
+    format!("{}\n{}", subject, provider)
+}
+
+/// Encrypt `plaintext` as base64 of the nonce followed by the ciphertext
+fn seal(cipher: &Aes256Gcm, aad: &str, plaintext: &str) -> Result<String, aes_gcm::Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher.encrypt(
+        Nonce::from_slice(&nonce),
+        Payload {
+            msg: plaintext.as_bytes(),
+            aad: aad.as_bytes(),
+        },
+    )?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(general_purpose::STANDARD.encode(sealed))
+}
+
+/// Decrypt a value from [`seal`]; `None` if it was not sealed with this key
+/// and `aad`
+fn open(cipher: &Aes256Gcm, aad: &str, sealed: &str) -> Option<String> {
+    let sealed = general_purpose::STANDARD.decode(sealed).ok()?;
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: aad.as_bytes(),
+            },
+        )
+        .ok()?;
+    String::from_utf8(plaintext).ok()
+}
+
+impl UpstreamTokenVault {
+    pub fn new(
+        db: Arc<Database>,
+        config: &UpstreamTokenConfig,
+        social: Arc<SocialLoginConfig>,
+        clock: SharedClock,
+    ) -> Self {
+        let cipher = match (config.encryption_key.is_some(), config.key()) {
+            (_, Some(key)) => Some(Aes256Gcm::new(&key.into())),
+            (true, None) => {
+                tracing::warn!(
+                    "OAUTH2_UPSTREAM_TOKEN_KEY is not 32 bytes of base64; provider tokens will not be stored"
+                );
+                None
+            }
+            (false, None) => None,
+        };
+        Self {
+            db,
+            cipher,
+            social,
+            clock,
+        }
+    }
+
+    /// Whether users may connect `provider`: it is configured to store
+    /// tokens and there is a key to encrypt them with
+    pub fn can_store(&self, provider: &str) -> bool {
+        self.cipher.is_some()
+            && self
+                .social
+                .provider(provider)
+                .is_some_and(|config| config.store_tokens)
+    }
+
+    fn cipher_for(&self, provider: &str) -> Result<&Aes256Gcm, OAuth2Error> {
+        match &self.cipher {
+            Some(cipher) if self.can_store(provider) => Ok(cipher),
+            _ => Err(OAuth2Error::invalid_request(&format!(
+                "Connecting {} is not enabled",
+                provider
+            ))),
+        }
+    }
+
+    /// Keep the tokens `subject` was granted by `provider`, replacing any
+    /// stored earlier
+    pub async fn store(
+        &self,
+        subject: &str,
+        provider: &str,
+        grant: &UpstreamGrant,
+    ) -> Result<(), OAuth2Error> {
+        let cipher = self.cipher_for(provider)?;
+        let aad = associated_data(subject, provider);
+        let seal = |value: &str| {
+            seal(cipher, &aad, value).map_err(|e| OAuth2Error::internal(ErrorCode::ServerError, e))
+        };
+        let now = self.clock.now();
+        let token = UpstreamToken {
+            subject: subject.to_string(),
+            provider: provider.to_string(),
+            access_token: seal(&grant.access_token)?,
+            refresh_token: grant.refresh_token.as_deref().map(seal).transpose()?,
+            scope: grant.scope.clone(),
+            expires_at: grant.expires_at,
+            created_at: now,
+            updated_at: now,
+            email: None,
+            name: None,
+            picture: None,
+            profile_synced_at: None,
+        };
+        self.db.save_upstream_token(&token).await?;
+        tracing::info!(
+            "Stored {} tokens for {} with scope '{}'",
+            provider,
+            subject,
+            grant.scope
+        );
+        Ok(())
+    }
+
+    /// Keep the profile a connected provider returned, to compare later
+    /// syncs with
+    pub async fn record_profile(&self, profile: &SocialUserInfo) -> Result<(), OAuth2Error> {
+        self.db
+            .save_upstream_profile(profile, self.clock.now())
+            .await
+    }
+
+    /// The tokens `subject` connected `provider` with, decrypted. A row that
+    /// does not decrypt, e.g. after the key changed, counts as none.
+    pub async fn get(
+        &self,
+        subject: &str,
+        provider: &str,
+    ) -> Result<Option<UpstreamGrant>, OAuth2Error> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(None);
+        };
+        let Some(token) = self.db.get_upstream_token(subject, provider).await? else {
+            return Ok(None);
+        };
+        let aad = associated_data(subject, provider);
+        let Some(access_token) = open(cipher, &aad, &token.access_token) else {
+            tracing::warn!("Stored {} tokens for {} do not decrypt", provider, subject);
+            return Ok(None);
+        };
+        Ok(Some(UpstreamGrant {
+            access_token,
+            refresh_token: token
+                .refresh_token
+                .and_then(|refresh_token| open(cipher, &aad, &refresh_token)),
+            scope: token.scope,
+            expires_at: token.expires_at,
+        }))
+    }
+
+    /// The providers `subject` has connected, without their tokens
+    pub async fn connections(&self, subject: &str) -> Result<Vec<UpstreamConnection>, OAuth2Error> {
+        let tokens = self.db.list_upstream_tokens(subject).await?;
+        Ok(tokens.into_iter().map(UpstreamConnection::from).collect())
+    }
+
+    /// Delete the tokens stored for `subject`, for one provider or all of
+    /// them. Returns how many connections were removed.
+    pub async fn purge(&self, subject: &str, provider: Option<&str>) -> Result<u64, OAuth2Error> {
+        let purged = self.db.delete_upstream_tokens(subject, provider).await?;
+        if purged > 0 {
+            tracing::info!(
+                "Purged {} stored provider tokens for {}{}",
+                purged,
+                subject,
+                provider
+                    .map(|provider| format!(" from {}", provider))
+                    .unwrap_or_default()
+            );
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> Aes256Gcm {
+        Aes256Gcm::new(&[7u8; 32].into())
+    }
+
+    #[test]
+    fn test_sealed_tokens_round_trip() {
+        let aad = associated_data("google:1", "google");
+        let sealed = seal(&cipher(), &aad, "ya29.token").unwrap();
+        assert!(!sealed.contains("ya29"));
+        assert_eq!(
+            open(&cipher(), &aad, &sealed).as_deref(),
+            Some("ya29.token")
+        );
+        // A fresh nonce each time
+        assert_ne!(seal(&cipher(), &aad, "ya29.token").unwrap(), sealed);
+    }
+
+    #[test]
+    fn test_sealed_tokens_are_bound_to_their_row() {
+        let sealed = seal(&cipher(), &associated_data("google:1", "google"), "secret").unwrap();
+        assert_eq!(
+            open(&cipher(), &associated_data("google:2", "google"), &sealed),
+            None
+        );
+        assert_eq!(
+            open(
+                &Aes256Gcm::new(&[8u8; 32].into()),
+                &associated_data("google:1", "google"),
+                &sealed
+            ),
+            None
+        );
+        assert_eq!(open(&cipher(), "", "bm9wZQ=="), None);
+    }
+}
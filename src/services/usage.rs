@@ -0,0 +1,102 @@
+//! Last-used times and use counts for tokens and clients.
+//!
+//! Uses are buffered in memory and written in one batch every flush interval,
+//! so introspecting a token or issuing one costs no extra database write.
+//! Readers that need up-to-date figures either flush first (the admin
+//! reports) or merge in what is still buffered ([`UsageTracker::last_used`]).
+
+use crate::config::UsageConfig;
+use crate::db::Database;
+use crate::models::{OAuth2Error, Token, UsageCount};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Pending {
+    tokens: HashMap<String, UsageCount>,
+    clients: HashMap<String, UsageCount>,
+}
+
+fn add(counts: &mut HashMap<String, UsageCount>, key: &str, usage: UsageCount) {
+    counts
+        .entry(key.to_string())
+        .and_modify(|existing| {
+            existing.last_used_at = existing.last_used_at.max(usage.last_used_at);
+            existing.count += usage.count;
+        })
+        .or_insert(usage);
+}
+
+pub struct UsageTracker {
+    db: Arc<Database>,
+    flush_interval: Duration,
+    pending: Mutex<Pending>,
+}
+
+impl UsageTracker {
+    pub fn new(db: Arc<Database>, config: &UsageConfig) -> Self {
+        Self {
+            db,
+            flush_interval: Duration::from_secs(config.flush_interval_secs.max(1)),
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    /// A use of `token`, which also counts as a use of its client
+    pub fn record_token(&self, token: &Token, at: DateTime<Utc>) {
+        let usage = UsageCount {
+            last_used_at: at,
+            count: 1,
+        };
+        let mut pending = self.pending.lock().unwrap();
+        add(&mut pending.tokens, &token.id, usage);
+        add(&mut pending.clients, &token.client_id, usage);
+    }
+
+    pub fn record_client(&self, client_id: &str, at: DateTime<Utc>) {
+        let usage = UsageCount {
+            last_used_at: at,
+            count: 1,
+        };
+        add(&mut self.pending.lock().unwrap().clients, client_id, usage);
+    }
+
+    /// When `token` was last used, counting uses not yet flushed
+    pub fn last_used(&self, token: &Token) -> Option<DateTime<Utc>> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .tokens
+            .get(&token.id)
+            .map(|usage| usage.last_used_at);
+        pending.max(token.last_used_at)
+    }
+
+    /// Write everything buffered so far. If the write fails the uses are
+    /// kept for the next flush.
+    pub async fn flush(&self) -> Result<(), OAuth2Error> {
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+        if batch.tokens.is_empty() && batch.clients.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.db.save_usage(&batch.tokens, &batch.clients).await;
+        if result.is_err() {
+            let mut pending = self.pending.lock().unwrap();
+            for (id, usage) in batch.tokens {
+                add(&mut pending.tokens, &id, usage);
+            }
+            for (client_id, usage) in batch.clients {
+                add(&mut pending.clients, &client_id, usage);
+            }
+        }
+        result
+    }
+}
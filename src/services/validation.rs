@@ -0,0 +1,172 @@
+//! Up-front checks on OAuth2 request parameters.
+//!
+//! Oversized or malformed values are rejected with an `invalid_request` that
+//! names the parameter, rather than failing somewhere further down (a
+//! database constraint, a redirect the browser refuses) with a less useful
+//! error.
+
+use crate::config::RequestLimitsConfig;
+use crate::models::OAuth2Error;
+
+pub struct RequestValidator {
+    limits: RequestLimitsConfig,
+}
+
+impl RequestValidator {
+    pub fn new(limits: RequestLimitsConfig) -> Self {
+        Self { limits }
+    }
+
+    /// Largest form or JSON body accepted, in bytes
+    pub fn max_body_bytes(&self) -> usize {
+        self.limits.max_body_bytes
+    }
+
+    /// Client ids are short, printable and free of whitespace
+    pub fn client_id(&self, client_id: &str) -> Result<(), OAuth2Error> {
+        check_len("client_id", client_id, self.limits.max_client_id_len)?;
+        if client_id.is_empty() {
+            return Err(OAuth2Error::invalid_request("client_id is empty"));
+        }
+        if !client_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._~:@/+".contains(c))
+        {
+            return Err(OAuth2Error::invalid_request(
+                "client_id contains characters outside A-Z, a-z, 0-9 and -._~:@/+",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Space-delimited scope tokens, per RFC 6749 section 3.3
+    pub fn scope(&self, scope: Option<&str>) -> Result<(), OAuth2Error> {
+        let Some(scope) = scope else {
+            return Ok(());
+        };
+        check_len("scope", scope, self.limits.max_scope_len)?;
+        for token in scope.split(' ') {
+            if token.is_empty() {
+                return Err(OAuth2Error::invalid_request(
+                    "scope must be tokens separated by single spaces",
+                ));
+            }
+            if let Some(c) = token.chars().find(|c| !is_scope_char(*c)) {
+                return Err(OAuth2Error::invalid_request(&format!(
+                    "scope contains the invalid character {:?}",
+                    c
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn state(&self, state: Option<&str>) -> Result<(), OAuth2Error> {
+        let Some(state) = state else {
+            return Ok(());
+        };
+        check_len("state", state, self.limits.max_state_len)?;
+        check_printable("state", state)
+    }
+
+    /// An OpenID Connect nonce, held to the same limits as `state`
+    pub fn nonce(&self, nonce: Option<&str>) -> Result<(), OAuth2Error> {
+        let Some(nonce) = nonce else {
+            return Ok(());
+        };
+        check_len("nonce", nonce, self.limits.max_state_len)?;
+        check_printable("nonce", nonce)
+    }
+
+    pub fn redirect_uri(&self, redirect_uri: Option<&str>) -> Result<(), OAuth2Error> {
+        let Some(redirect_uri) = redirect_uri else {
+            return Ok(());
+        };
+        check_len(
+            "redirect_uri",
+            redirect_uri,
+            self.limits.max_redirect_uri_len,
+        )?;
+        if redirect_uri.contains(' ') {
+            return Err(OAuth2Error::invalid_request(
+                "redirect_uri must not contain spaces",
+            ));
+        }
+        check_printable("redirect_uri", redirect_uri)
+    }
+}
+
+fn check_len(name: &str, value: &str, max: usize) -> Result<(), OAuth2Error> {
+    if value.len() > max {
+        return Err(OAuth2Error::invalid_request(&format!(
+            "{} is longer than {} bytes",
+            name, max
+        )));
+    }
+    Ok(())
+}
+
+/// Printable ASCII (RFC 6749's VSCHAR)
+fn check_printable(name: &str, value: &str) -> Result<(), OAuth2Error> {
+    if !value.chars().all(|c| (' '..='~').contains(&c)) {
+        return Err(OAuth2Error::invalid_request(&format!(
+            "{} contains non-printable or non-ASCII characters",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// NQCHAR: printable ASCII except space, `"` and `\`
+fn is_scope_char(c: char) -> bool {
+    ('!'..='~').contains(&c) && c != '"' && c != '\\'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator() -> RequestValidator {
+        RequestValidator::new(RequestLimitsConfig {
+            max_scope_len: 16,
+            ..Default::default()
+        })
+    }
+
+    fn description(result: Result<(), OAuth2Error>) -> String {
+        let err = result.unwrap_err();
+        assert_eq!(err.error, "invalid_request");
+        err.error_description.unwrap()
+    }
+
+    #[test]
+    fn test_scope_syntax_and_length() {
+        let validator = validator();
+        assert!(validator.scope(Some("read write")).is_ok());
+        assert!(validator.scope(None).is_ok());
+
+        assert!(description(validator.scope(Some("read  write"))).contains("single spaces"));
+        assert!(description(validator.scope(Some("read\\"))).contains("'\\\\'"));
+        assert_eq!(
+            description(validator.scope(Some("read write admins"))),
+            "scope is longer than 16 bytes"
+        );
+    }
+
+    #[test]
+    fn test_client_id_state_and_redirect_uri() {
+        let validator = validator();
+        assert!(validator.client_id("client_4f0c-77").is_ok());
+        assert!(description(validator.client_id("")).contains("empty"));
+        assert!(description(validator.client_id("a b")).contains("characters"));
+
+        assert!(validator.state(Some("xyz=/+")).is_ok());
+        assert!(description(validator.state(Some("caf\u{e9}"))).contains("non-printable"));
+
+        assert!(validator
+            .redirect_uri(Some("https://app.example.com/cb"))
+            .is_ok());
+        let long = format!("https://app.example.com/{}", "a".repeat(2048));
+        assert!(description(validator.redirect_uri(Some(&long))).contains("longer than 2048"));
+    }
+}
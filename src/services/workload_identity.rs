@@ -0,0 +1,241 @@
+//! Client authentication with workload identity tokens.
+//!
+//! Instead of a client secret, a workload presents the JWT its platform issued
+//! it (a Kubernetes service account token, a SPIFFE JWT-SVID) as an RFC 7523
+//! `client_assertion`. The trust bundle lists the issuers we accept, their
+//! signing keys and audiences, and which workload subject acts as which
+//! registered client:
+//!
+//! ```json
+//! {
+//!   "issuers": [{
+//!     "issuer": "https://kubernetes.default.svc",
+//!     "audiences": ["https://auth.example.com"],
+//!     "jwks_file": "/etc/oauth2/k8s-jwks.json",
+//!     "clients": { "system:serviceaccount:payments:api": "payments-api" }
+//!   }]
+//! }
+//! ```
+//!
+//! Keys are given inline as `jwks` or read from `jwks_file`.
+
+use crate::clock::SharedClock;
+use crate::models::OAuth2Error;
+use base64::{engine::general_purpose, Engine as _};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+pub const JWT_BEARER_ASSERTION_TYPE: &str =
+    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Allowance for clock drift between us and the workload's issuer
+const CLOCK_SKEW_SECS: i64 = 60;
+
+#[derive(Deserialize)]
+struct TrustBundle {
+    issuers: Vec<IssuerEntry>,
+}
+
+#[derive(Deserialize)]
+struct IssuerEntry {
+    issuer: String,
+    audiences: Vec<String>,
+    jwks: Option<JwkSet>,
+    jwks_file: Option<String>,
+    /// Workload subject to client id
+    clients: HashMap<String, String>,
+}
+
+struct TrustedIssuer {
+    issuer: String,
+    audiences: Vec<String>,
+    keys: JwkSet,
+    clients: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct UnverifiedClaims {
+    iss: String,
+}
+
+#[derive(Deserialize)]
+struct WorkloadClaims {
+    sub: String,
+    exp: i64,
+    nbf: Option<i64>,
+}
+
+pub struct WorkloadIdentityVerifier {
+    issuers: Vec<TrustedIssuer>,
+    clock: SharedClock,
+}
+
+impl WorkloadIdentityVerifier {
+    /// A verifier that trusts nobody, for when no bundle is configured
+    pub fn disabled(clock: SharedClock) -> Self {
+        Self {
+            issuers: Vec::new(),
+            clock,
+        }
+    }
+
+    pub fn open(path: &str, clock: SharedClock) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Self::from_json(&json, clock)
+    }
+
+    pub fn from_json(json: &str, clock: SharedClock) -> Result<Self, String> {
+        let bundle: TrustBundle =
+            serde_json::from_str(json).map_err(|e| format!("invalid trust bundle: {}", e))?;
+
+        let issuers = bundle
+            .issuers
+            .into_iter()
+            .map(|entry| {
+                let keys = match (entry.jwks, entry.jwks_file) {
+                    (Some(keys), None) => keys,
+                    (None, Some(path)) => {
+                        let json = std::fs::read_to_string(&path)
+                            .map_err(|e| format!("{}: {}", path, e))?;
+                        serde_json::from_str(&json).map_err(|e| format!("{}: {}", path, e))?
+                    }
+                    _ => {
+                        return Err(format!(
+                            "issuer {} needs exactly one of jwks or jwks_file",
+                            entry.issuer
+                        ))
+                    }
+                };
+                if entry.audiences.is_empty() {
+                    return Err(format!("issuer {} has no audiences", entry.issuer));
+                }
+                Ok(TrustedIssuer {
+                    issuer: entry.issuer,
+                    audiences: entry.audiences,
+                    keys,
+                    clients: entry.clients,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self { issuers, clock })
+    }
+
+    pub fn issuer_count(&self) -> usize {
+        self.issuers.len()
+    }
+
+    /// Verify a workload assertion and return the client id it authenticates
+    pub fn verify(&self, assertion: &str) -> Result<String, OAuth2Error> {
+        let rejected = |reason: &str| {
+            tracing::warn!("Rejected workload assertion: {}", reason);
+            OAuth2Error::invalid_client("Invalid client assertion")
+        };
+
+        let header =
+            jsonwebtoken::decode_header(assertion).map_err(|e| rejected(&e.to_string()))?;
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(rejected("symmetric algorithms are not accepted"));
+        }
+
+        // The issuer picks the keys, so it has to be read before verifying
+        let iss = unverified_issuer(assertion).ok_or_else(|| rejected("no issuer"))?;
+        let issuer = self
+            .issuers
+            .iter()
+            .find(|issuer| issuer.issuer == iss)
+            .ok_or_else(|| rejected(&format!("untrusted issuer {}", iss)))?;
+
+        let jwk = match &header.kid {
+            Some(kid) => issuer.keys.find(kid),
+            None if issuer.keys.keys.len() == 1 => issuer.keys.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| rejected("no matching key"))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| rejected(&e.to_string()))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&issuer.issuer]);
+        validation.set_audience(&issuer.audiences);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        // Expiry is checked below against our clock
+        validation.validate_exp = false;
+        let claims = jsonwebtoken::decode::<WorkloadClaims>(assertion, &key, &validation)
+            .map_err(|e| rejected(&e.to_string()))?
+            .claims;
+
+        let now = self.clock.now().timestamp();
+        if claims.exp + CLOCK_SKEW_SECS < now {
+            return Err(rejected("expired"));
+        }
+        if claims.nbf.is_some_and(|nbf| nbf - CLOCK_SKEW_SECS > now) {
+            return Err(rejected("not yet valid"));
+        }
+
+        issuer
+            .clients
+            .get(&claims.sub)
+            .cloned()
+            .ok_or_else(|| rejected(&format!("{} is not mapped to a client", claims.sub)))
+    }
+}
+
+fn unverified_issuer(jwt: &str) -> Option<String> {
+    let payload = jwt.split('.').nth(1)?;
+    let bytes = general_purpose::URL_SAFE_NO_PAD.decode(payload).ok()?;
+    serde_json::from_slice::<UnverifiedClaims>(&bytes)
+        .ok()
+        .map(|claims| claims.iss)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use std::sync::Arc;
+
+    fn verifier(json: &str) -> Result<WorkloadIdentityVerifier, String> {
+        WorkloadIdentityVerifier::from_json(json, Arc::new(SystemClock))
+    }
+
+    #[test]
+    fn test_bundle_needs_keys_and_audiences() {
+        let no_keys =
+            r#"{"issuers": [{"issuer": "https://a", "audiences": ["x"], "clients": {}}]}"#;
+        assert!(verifier(no_keys).err().unwrap().contains("jwks"));
+
+        let no_audiences = r#"{"issuers": [{"issuer": "https://a", "audiences": [],
+            "jwks": {"keys": []}, "clients": {}}]}"#;
+        assert!(verifier(no_audiences).err().unwrap().contains("audiences"));
+
+        let ok = r#"{"issuers": [{"issuer": "https://a", "audiences": ["x"],
+            "jwks": {"keys": []}, "clients": {"sa": "client"}}]}"#;
+        assert_eq!(verifier(ok).unwrap().issuer_count(), 1);
+    }
+
+    #[test]
+    fn test_symmetric_assertions_are_rejected() {
+        let verifier = verifier(
+            r#"{"issuers": [{"issuer": "https://a", "audiences": ["x"],
+                "jwks": {"keys": []}, "clients": {"sa": "client"}}]}"#,
+        )
+        .unwrap();
+        let assertion = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &serde_json::json!({"iss": "https://a", "sub": "sa", "aud": "x", "exp": i64::MAX}),
+            &jsonwebtoken::EncodingKey::from_secret(b"guessable"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            verifier.verify(&assertion).unwrap_err().error,
+            "invalid_client"
+        );
+        assert!(verifier.verify("not-a-jwt").is_err());
+    }
+}
@@ -1,10 +1,34 @@
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use crate::clock::SharedClock;
+use crate::config::LogSamplingConfig;
+use crate::models::OAuth2Error;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::HttpRequest;
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::RwLock;
+use tracing::field::Empty;
+use tracing::level_filters::LevelFilter;
+use tracing::Span;
+use tracing_actix_web::{root_span, DefaultRootSpanBuilder, RootSpan, RootSpanBuilder};
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
 
-pub fn init_telemetry(_service_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// Install the global subscriber. The returned [`LogLevels`] changes its
+/// filter while the server runs.
+pub fn init_telemetry(_service_name: &str) -> Result<LogLevels, Box<dyn std::error::Error>> {
     // For now, use a simplified tracing setup without OTLP
     // In production, configure OTLP exporter with proper endpoint
 
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let directives = std::env::var(EnvFilter::DEFAULT_ENV)
+        .ok()
+        .filter(|directives| EnvFilter::try_new(directives).is_ok())
+        .unwrap_or_else(|| "info".to_string());
+    let (env_filter, log_levels) = LogLevels::layer(&directives)?;
 
     // JSON formatting for structured logging
     let formatting_layer = tracing_subscriber::fmt::layer()
@@ -17,7 +41,73 @@ pub fn init_telemetry(_service_name: &str) -> Result<(), Box<dyn std::error::Err
         .with(formatting_layer)
         .init();
 
-    Ok(())
+    Ok(log_levels)
+}
+
+/// The log filter, changeable without a restart.
+///
+/// Filters use `RUST_LOG` directive syntax, e.g. `info,sqlx=warn`. The one
+/// set at startup is kept so it can be restored. Like verbose client
+/// logging, a change only applies to the instance that made it.
+pub struct LogLevels {
+    handle: reload::Handle<EnvFilter, Registry>,
+    startup: String,
+    current: RwLock<String>,
+}
+
+impl LogLevels {
+    /// A filter layer starting from `directives`, and the levels that
+    /// control it
+    pub fn layer(directives: &str) -> Result<(reload::Layer<EnvFilter, Registry>, Self), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        let (layer, handle) = reload::Layer::new(filter);
+        Ok((
+            layer,
+            Self {
+                handle,
+                startup: directives.to_string(),
+                current: RwLock::new(directives.to_string()),
+            },
+        ))
+    }
+
+    /// Directives for `level` overall and other levels for some targets
+    pub fn directives(level: &str, targets: &BTreeMap<String, String>) -> Result<String, String> {
+        let check_level = |level: &str| {
+            LevelFilter::from_str(level)
+                .map(|_| level.to_ascii_lowercase())
+                .map_err(|_| format!("Unknown log level: {}", level))
+        };
+        let mut directives = vec![check_level(level)?];
+        for (target, level) in targets {
+            if target.is_empty() || target.contains([',', '=', '[', ']', ' ']) {
+                return Err(format!("Invalid log target: {}", target));
+            }
+            directives.push(format!("{}={}", target, check_level(level)?));
+        }
+        Ok(directives.join(","))
+    }
+
+    pub fn current(&self) -> String {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn startup(&self) -> &str {
+        &self.startup
+    }
+
+    /// Filter with `directives` from now on
+    pub fn set(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        self.handle.reload(filter).map_err(|e| e.to_string())?;
+        *self.current.write().unwrap() = directives.to_string();
+        Ok(())
+    }
+
+    /// Go back to the filter set at startup
+    pub fn reset(&self) -> Result<(), String> {
+        self.set(&self.startup)
+    }
 }
 
 #[allow(dead_code)]
@@ -25,3 +115,449 @@ pub fn shutdown_telemetry() {
     // Simplified telemetry shutdown
     // In production with OTLP, use: global::shutdown_tracer_provider();
 }
+
+/// Root span of every request, the default one plus the OAuth attributes
+/// token and authorize requests fill in through [`OAuthSpan`]
+pub struct OAuthRootSpanBuilder;
+
+impl RootSpanBuilder for OAuthRootSpanBuilder {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        root_span!(
+            request,
+            oauth.client_id = Empty,
+            oauth.grant_type = Empty,
+            oauth.scope.count = Empty,
+            oauth.outcome = Empty,
+            oauth.flow_id = Empty,
+            user.hash = Empty,
+        )
+    }
+
+    fn on_request_end<B: MessageBody>(
+        span: Span,
+        outcome: &Result<ServiceResponse<B>, actix_web::Error>,
+    ) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+/// Outcome of a token request that issued a token
+pub const TOKEN_ISSUED: &str = "token_issued";
+
+/// The OAuth attributes of a request's root span, so traces can be filtered
+/// by client and grant. The user is recorded as [`user_hash`], never as
+/// their id; `oauth.scope.count` is the granted scope once there is one, else
+/// the requested scope.
+pub struct OAuthSpan(Span);
+
+impl OAuthSpan {
+    pub fn new(root_span: RootSpan) -> Self {
+        Self(root_span.into())
+    }
+
+    pub fn client(&self, client_id: &str, grant_type: Option<&str>) {
+        self.0.record("oauth.client_id", client_id);
+        if let Some(grant_type) = grant_type {
+            self.0.record("oauth.grant_type", grant_type);
+        }
+    }
+
+    pub fn scope(&self, scope: &str) {
+        self.0
+            .record("oauth.scope.count", scope.split_whitespace().count());
+    }
+
+    pub fn user(&self, user_id: &str) {
+        self.0.record("user.hash", user_hash(user_id));
+    }
+
+    /// The authorization journey the request is part of
+    pub fn flow(&self, flow_id: &str) {
+        self.0.record("oauth.flow_id", flow_id);
+    }
+
+    /// `token_issued`, `code_issued`, or the error code the request was
+    /// refused with
+    pub fn outcome(&self, outcome: &str) {
+        self.0.record("oauth.outcome", outcome);
+    }
+
+    /// Record `error` as the outcome and pass it on
+    pub fn failed(&self, error: OAuth2Error) -> OAuth2Error {
+        self.outcome(&error.error);
+        error
+    }
+}
+
+/// Pseudonymous user id for traces: the first 16 hex digits of its SHA-256,
+/// enough to follow one user's requests without naming them
+pub fn user_hash(user_id: &str) -> String {
+    hex::encode(Sha256::digest(user_id.as_bytes()))[..16].to_string()
+}
+
+/// Decides which access log lines and events are written.
+///
+/// High-volume routes and event types can be logged at a sample rate. A
+/// client can also be put in verbose mode for a while from the admin API,
+/// logging every request and event about it in full regardless of sampling.
+/// Verbose mode is kept in memory, per instance.
+pub struct LogSampler {
+    routes: HashMap<String, f64>,
+    event_types: HashMap<String, f64>,
+    max_debug_ttl: Duration,
+    clock: SharedClock,
+    debug_clients: RwLock<HashMap<String, DateTime<Utc>>>,
+}
+
+impl LogSampler {
+    pub fn new(config: &LogSamplingConfig, clock: SharedClock) -> Self {
+        Self {
+            routes: config.routes.clone(),
+            event_types: config.event_types.clone(),
+            max_debug_ttl: Duration::seconds(
+                i64::try_from(config.max_debug_ttl_secs.max(1)).unwrap_or(i64::MAX),
+            ),
+            clock,
+            debug_clients: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn sample(rate: Option<&f64>) -> bool {
+        match rate {
+            None => true,
+            Some(rate) if *rate >= 1.0 => true,
+            Some(rate) if *rate <= 0.0 => false,
+            Some(rate) => rand::thread_rng().gen_bool(*rate),
+        }
+    }
+
+    /// Whether to log this request to `path`
+    pub fn sample_route(&self, path: &str) -> bool {
+        Self::sample(self.routes.get(path))
+    }
+
+    /// Whether to log an event of this type
+    pub fn sample_event(&self, event_type: &str) -> bool {
+        Self::sample(self.event_types.get(event_type))
+    }
+
+    /// Log everything about `client_id` for `ttl`, at most the configured
+    /// maximum. Returns when verbose mode ends.
+    pub fn enable_debug(&self, client_id: &str, ttl: Option<Duration>) -> DateTime<Utc> {
+        let ttl = ttl
+            .unwrap_or(self.max_debug_ttl)
+            .clamp(Duration::seconds(1), self.max_debug_ttl);
+        let until = self.clock.now() + ttl;
+        self.debug_clients
+            .write()
+            .unwrap()
+            .insert(client_id.to_string(), until);
+        until
+    }
+
+    /// End verbose mode for `client_id`. Returns false if it was not on.
+    pub fn disable_debug(&self, client_id: &str) -> bool {
+        let removed = self.debug_clients.write().unwrap().remove(client_id);
+        removed.is_some_and(|until| until > self.clock.now())
+    }
+
+    pub fn is_debug(&self, client_id: &str) -> bool {
+        self.debug_clients
+            .read()
+            .unwrap()
+            .get(client_id)
+            .is_some_and(|until| *until > self.clock.now())
+    }
+
+    /// Whether any client is in verbose mode, so callers can skip working
+    /// out which client a request is for
+    pub fn has_debug_clients(&self) -> bool {
+        let now = self.clock.now();
+        self.debug_clients
+            .read()
+            .unwrap()
+            .values()
+            .any(|until| *until > now)
+    }
+
+    /// Clients in verbose mode and when it ends, soonest first
+    pub fn debug_clients(&self) -> Vec<(String, DateTime<Utc>)> {
+        let now = self.clock.now();
+        let mut clients = self.debug_clients.write().unwrap();
+        clients.retain(|_, until| *until > now);
+        let mut clients: Vec<_> = clients
+            .iter()
+            .map(|(client_id, until)| (client_id.clone(), *until))
+            .collect();
+        clients.sort_by_key(|(_, until)| *until);
+        clients
+    }
+}
+
+/// Header carrying W3C trace context
+pub const TRACEPARENT: &str = "traceparent";
+
+/// W3C trace context, carried into calls this server makes so they show up
+/// in the caller's trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: String,
+    span_id: String,
+    sampled: bool,
+}
+
+fn random_hex_id<const N: usize>() -> String {
+    let mut id = [0u8; N];
+    rand::thread_rng().fill(&mut id[..]);
+    // All zeroes is not a valid ID
+    id[N - 1] |= 1;
+    hex::encode(id)
+}
+
+fn is_hex_id(id: &str, len: usize) -> bool {
+    id.len() == len
+        && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        && id.bytes().any(|b| b != b'0')
+}
+
+impl TraceContext {
+    /// A new trace, for work no request started
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: random_hex_id::<16>(),
+            span_id: random_hex_id::<8>(),
+            sampled: true,
+        }
+    }
+
+    /// The trace of the caller's `traceparent` header, or a new one
+    pub fn from_request(req: &HttpRequest) -> Self {
+        req.headers()
+            .get(TRACEPARENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Self::parse)
+            .unwrap_or_else(Self::new_root)
+    }
+
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        // Later versions may append fields; version 00 has exactly four
+        let valid_version = version.len() == 2
+            && version != "ff"
+            && u8::from_str_radix(version, 16).is_ok()
+            && (version != "00" || parts.next().is_none());
+        if !valid_version || !is_hex_id(trace_id, 32) || !is_hex_id(span_id, 16) {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16)
+            .ok()
+            .filter(|_| flags.len() == 2)?;
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            span_id: span_id.to_string(),
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    /// A new span in the same trace, whose parent is this one
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id.clone(),
+            span_id: random_hex_id::<8>(),
+            sampled: self.sampled,
+        }
+    }
+
+    pub fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    pub fn span_id(&self) -> &str {
+        &self.span_id
+    }
+
+    /// This span as a `traceparent` header value
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{}",
+            self.trace_id,
+            self.span_id,
+            if self.sampled { "01" } else { "00" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use std::sync::Arc;
+
+    fn sampler(clock: Arc<ManualClock>) -> LogSampler {
+        let config = LogSamplingConfig {
+            routes: HashMap::from([("/oauth/introspect".to_string(), 0.0)]),
+            event_types: HashMap::from([("token_validated".to_string(), 0.0)]),
+            max_debug_ttl_secs: 600,
+        };
+        LogSampler::new(&config, clock)
+    }
+
+    #[test]
+    fn test_sampling_rates() {
+        let sampler = sampler(Arc::new(ManualClock::new(Utc::now())));
+        assert!(!sampler.sample_route("/oauth/introspect"));
+        assert!(sampler.sample_route("/oauth/token"));
+        assert!(!sampler.sample_event("token_validated"));
+        assert!(sampler.sample_event("token_created"));
+    }
+
+    #[test]
+    fn test_debug_mode_expires() {
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let sampler = sampler(clock.clone());
+        assert!(!sampler.has_debug_clients());
+
+        // Capped at the configured maximum
+        let until = sampler.enable_debug("spa", Some(Duration::hours(2)));
+        assert_eq!(until, clock.now() + Duration::seconds(600));
+        assert!(sampler.is_debug("spa"));
+        assert!(!sampler.is_debug("other"));
+        assert_eq!(sampler.debug_clients().len(), 1);
+
+        clock.advance(Duration::seconds(601));
+        assert!(!sampler.is_debug("spa"));
+        assert!(!sampler.has_debug_clients());
+        assert!(sampler.debug_clients().is_empty());
+        assert!(!sampler.disable_debug("spa"));
+    }
+
+    #[test]
+    fn test_log_levels_change_at_runtime() {
+        let (layer, levels) = LogLevels::layer("info").unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let db_debug =
+            || tracing::enabled!(target: "rust_oauth2_server::db", tracing::Level::DEBUG);
+        let sqlx_info = || tracing::enabled!(target: "sqlx", tracing::Level::INFO);
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(!db_debug());
+            assert!(sqlx_info());
+
+            let targets =
+                BTreeMap::from([("rust_oauth2_server::db".to_string(), "DEBUG".to_string())]);
+            let directives = LogLevels::directives("warn", &targets).unwrap();
+            assert_eq!(directives, "warn,rust_oauth2_server::db=debug");
+            levels.set(&directives).unwrap();
+            assert_eq!(levels.current(), directives);
+            assert!(db_debug());
+            assert!(!sqlx_info());
+
+            levels.reset().unwrap();
+            assert_eq!(levels.current(), "info");
+            assert!(!db_debug());
+            assert!(sqlx_info());
+        });
+
+        assert!(LogLevels::directives("loud", &BTreeMap::new()).is_err());
+        let targets = BTreeMap::from([("a,b".to_string(), "info".to_string())]);
+        assert!(LogLevels::directives("info", &targets).is_err());
+    }
+
+    /// Log lines written through a `fmt` layer
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_oauth_attributes_land_on_the_root_span() {
+        use actix_web::{test, web, App, HttpResponse};
+        use tracing_actix_web::TracingLogger;
+
+        let captured = Captured::default();
+        let layer = tracing_subscriber::fmt::layer().json().with_writer({
+            let captured = captured.clone();
+            move || captured.clone()
+        });
+        let _subscriber =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+        let app = test::init_service(
+            App::new()
+                .wrap(TracingLogger::<OAuthRootSpanBuilder>::new())
+                .route(
+                    "/oauth/token",
+                    web::post().to(|root_span: RootSpan| async move {
+                        let span = OAuthSpan::new(root_span);
+                        span.client("app", Some("password"));
+                        span.scope("openid  profile email");
+                        span.user("alice");
+                        span.outcome(TOKEN_ISSUED);
+                        tracing::info!("issued");
+                        HttpResponse::Ok().finish()
+                    }),
+                ),
+        )
+        .await;
+        test::call_service(
+            &app,
+            test::TestRequest::post().uri("/oauth/token").to_request(),
+        )
+        .await;
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = logs
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .find(|line: &serde_json::Value| line["fields"]["message"] == "issued")
+            .unwrap();
+        let span = &line["span"];
+        assert_eq!(span["oauth.client_id"], "app");
+        assert_eq!(span["oauth.grant_type"], "password");
+        assert_eq!(span["oauth.scope.count"], 3);
+        assert_eq!(span["oauth.outcome"], "token_issued");
+        assert_eq!(span["user.hash"], user_hash("alice"));
+        assert_eq!(user_hash("alice").len(), 16);
+        assert!(!logs.contains("alice"));
+    }
+
+    #[test]
+    fn test_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(header).unwrap();
+        assert_eq!(context.traceparent(), header);
+
+        let child = context.child();
+        assert_eq!(child.trace_id(), context.trace_id());
+        assert_ne!(child.span_id(), context.span_id());
+        assert!(TraceContext::parse(&child.traceparent()).is_some());
+
+        for invalid in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{}", invalid);
+        }
+        // Fields a later version adds are ignored
+        assert!(TraceContext::parse(
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra"
+        )
+        .is_some_and(|context| !context.sampled));
+    }
+}
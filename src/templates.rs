@@ -0,0 +1,703 @@
+use actix_web::HttpResponse;
+use chrono::Datelike;
+use serde::Serialize;
+use tera::{Context, Tera};
+
+use crate::config::BrandingConfig;
+use crate::models::SocialUserInfo;
+use crate::services::DemoClient;
+
+/// HTML templates compiled into the binary.
+///
+/// Every page extends `base.html`; the end-user auth pages share
+/// `auth_layout.html` on top of that.
+const TEMPLATES: &[(&str, &str)] = &[
+    ("base.html", include_str!("../templates/base.html")),
+    (
+        "auth_layout.html",
+        include_str!("../templates/auth_layout.html"),
+    ),
+    (
+        "provider_icons.html",
+        include_str!("../templates/provider_icons.html"),
+    ),
+    (
+        "csrf_field.html",
+        include_str!("../templates/csrf_field.html"),
+    ),
+    ("login.html", include_str!("../templates/login.html")),
+    (
+        "auth_success.html",
+        include_str!("../templates/auth_success.html"),
+    ),
+    ("step_up.html", include_str!("../templates/step_up.html")),
+    ("consent.html", include_str!("../templates/consent.html")),
+    ("demo.html", include_str!("../templates/demo.html")),
+    (
+        "demo_callback.html",
+        include_str!("../templates/demo_callback.html"),
+    ),
+    (
+        "check_session.html",
+        include_str!("../templates/check_session.html"),
+    ),
+    (
+        "web_message.html",
+        include_str!("../templates/web_message.html"),
+    ),
+    (
+        "account_security.html",
+        include_str!("../templates/account_security.html"),
+    ),
+    (
+        "account_delete.html",
+        include_str!("../templates/account_delete.html"),
+    ),
+    (
+        "account_connections.html",
+        include_str!("../templates/account_connections.html"),
+    ),
+    (
+        "logout_all.html",
+        include_str!("../templates/logout_all.html"),
+    ),
+    ("error.html", include_str!("../templates/error.html")),
+    (
+        "error_reference.html",
+        include_str!("../templates/error_reference.html"),
+    ),
+    (
+        "admin_dashboard.html",
+        include_str!("../templates/admin_dashboard.html"),
+    ),
+    ("portal.html", include_str!("../templates/portal.html")),
+    (
+        "portal_client.html",
+        include_str!("../templates/portal_client.html"),
+    ),
+    (
+        "saml_post.html",
+        include_str!("../templates/saml_post.html"),
+    ),
+];
+
+/// Template renderer shared across handlers
+pub struct Templates {
+    tera: Tera,
+    branding: BrandingConfig,
+}
+
+impl Templates {
+    /// Parse all embedded templates. Fails on syntax errors so a broken
+    /// template is caught at startup rather than on first request.
+    pub fn new(branding: BrandingConfig) -> Result<Self, tera::Error> {
+        let mut tera = Tera::default();
+        tera.add_raw_templates(TEMPLATES.to_vec())?;
+        // Escape every interpolated value; templates must opt out explicitly with `| safe`
+        tera.autoescape_on(vec![".html"]);
+        Ok(Self { tera, branding })
+    }
+
+    /// Render a template with the given page context
+    pub fn render<T: Serialize>(&self, name: &str, page: &T) -> Result<String, tera::Error> {
+        self.render_context(name, Context::from_serialize(page)?)
+    }
+
+    /// Render a page with forms, which include `csrf_field.html` to post back
+    /// the session's CSRF token
+    pub fn render_form<T: Serialize>(
+        &self,
+        name: &str,
+        page: &T,
+        csrf_token: &str,
+    ) -> Result<String, tera::Error> {
+        let mut context = Context::from_serialize(page)?;
+        context.insert("csrf_token", csrf_token);
+        self.render_context(name, context)
+    }
+
+    fn render_context(&self, name: &str, mut context: Context) -> Result<String, tera::Error> {
+        context.insert("year", &chrono::Utc::now().year());
+        context.insert("brand", &self.branding);
+        self.tera.render(name, &context)
+    }
+
+    /// Render a template into an HTML response, logging and returning a
+    /// plain 500 if rendering fails
+    pub fn render_response<T: Serialize>(&self, name: &str, page: &T) -> HttpResponse {
+        Self::respond(name, self.render(name, page))
+    }
+
+    /// Like `render_response`, for a page with forms
+    pub fn render_form_response<T: Serialize>(
+        &self,
+        name: &str,
+        page: &T,
+        csrf_token: &str,
+    ) -> HttpResponse {
+        Self::respond(name, self.render_form(name, page, csrf_token))
+    }
+
+    fn respond(name: &str, rendered: Result<String, tera::Error>) -> HttpResponse {
+        match rendered {
+            Ok(html) => HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(html),
+            Err(e) => {
+                tracing::error!("Failed to render template {}: {:?}", name, e);
+                HttpResponse::InternalServerError()
+                    .content_type("text/plain; charset=utf-8")
+                    .body("Internal server error")
+            }
+        }
+    }
+}
+
+/// A social login button on the login page
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderButton {
+    pub id: &'static str,
+    pub label: &'static str,
+}
+
+/// Context for `login.html`
+#[derive(Debug, Serialize)]
+pub struct LoginPage {
+    pub providers: Vec<ProviderButton>,
+    pub local_login_enabled: bool,
+    /// Offer to sign in as the demo user
+    pub demo: bool,
+}
+
+/// Context for `auth_success.html`
+#[derive(Debug, Serialize)]
+pub struct AuthSuccessPage {
+    pub user: Option<SocialUserInfo>,
+}
+
+/// Context for `check_session.html`, the OIDC check session iframe
+#[derive(Debug, Serialize)]
+pub struct CheckSessionPage {
+    /// Cookie holding the browser state
+    pub cookie_name: &'static str,
+}
+
+/// Context for `web_message.html`, which posts an authorization response
+/// to the client's page that opened or framed the request
+#[derive(Debug, Serialize)]
+pub struct WebMessagePage {
+    /// Origin of the client's redirect URI; the message goes nowhere else
+    pub origin: String,
+    /// The response parameters as a JSON object
+    pub response: String,
+}
+
+/// Context for `saml_post.html`, which posts a SAML response to the
+/// service provider
+#[derive(Debug, Serialize)]
+pub struct SamlPostPage {
+    pub service_provider: String,
+    pub acs_url: String,
+    /// Base64 of the Response document
+    pub saml_response: String,
+    pub relay_state: Option<String>,
+}
+
+/// Context for `step_up.html`
+#[derive(Debug, Serialize)]
+pub struct StepUpPage {
+    /// Why the login was flagged; empty when it is held only for MFA
+    pub reasons: Vec<String>,
+    /// Offer the TOTP form; without it the user can only sign out
+    pub mfa_enrolled: bool,
+    /// Offer "remember this device" for this many days
+    pub trusted_device_days: Option<i64>,
+    pub error: Option<String>,
+}
+
+/// Context for `consent.html`, where the user approves or denies a client's
+/// authorization request
+#[derive(Debug, Serialize)]
+pub struct ConsentPage {
+    pub client_name: String,
+    pub user_email: String,
+    /// Scopes the client would be granted
+    pub scopes: Vec<String>,
+    /// Where the decision is posted: the authorize endpoint with the
+    /// request's parameters
+    pub action: String,
+}
+
+/// Context for `demo.html`, the demo mode playground
+#[derive(Debug, Serialize)]
+pub struct DemoPage {
+    /// The signed-in user, if any
+    pub user_email: Option<String>,
+    pub username: &'static str,
+    pub password: &'static str,
+    pub clients: &'static [DemoClient],
+}
+
+/// Context for `demo_callback.html`, where the playground's authorization
+/// code flow comes back to and exchanges its code in the browser
+#[derive(Debug, Serialize)]
+pub struct DemoCallbackPage {
+    /// Why the flow failed; nothing else is set then
+    pub error: Option<String>,
+    pub code: Option<String>,
+    pub code_verifier: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub client_id: &'static str,
+    pub client_secret: &'static str,
+}
+
+/// Context for `logout_all.html`
+#[derive(Debug, Serialize)]
+pub struct LogoutAllPage {
+    pub user_email: String,
+    /// Tokens revoked once the user has confirmed; `None` on the confirmation form
+    pub tokens_revoked: Option<u64>,
+}
+
+/// Context for `account_delete.html`
+#[derive(Debug, Serialize)]
+pub struct AccountDeletePage {
+    pub user_email: String,
+    /// Whether confirming needs a TOTP code
+    pub mfa_required: bool,
+    pub grace_period_days: u32,
+    /// When the data will be erased, once the user has confirmed; `None` on
+    /// the confirmation form
+    pub erase_after: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Context for `account_connections.html`
+#[derive(Debug, Serialize)]
+pub struct AccountConnectionsPage {
+    pub user_email: String,
+    /// Provider the user may connect, if it is not connected already
+    pub connectable: Option<String>,
+    pub connections: Vec<ConnectionView>,
+    pub notice: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionView {
+    pub provider: String,
+    pub scopes: Vec<String>,
+    pub connected_at: String,
+    /// Whether the server can keep calling the provider once the access
+    /// token expires
+    pub offline: bool,
+}
+
+/// Context for `account_security.html`
+#[derive(Debug, Serialize)]
+pub struct AccountSecurityPage {
+    pub user_email: String,
+    pub mfa_enrolled: bool,
+    /// Pending enrollment, shown until the user confirms a code
+    pub setup: Option<TotpSetupView>,
+    pub devices: Vec<TrustedDeviceView>,
+    pub trusted_device_days: Option<i64>,
+    pub notice: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpSetupView {
+    pub secret: String,
+    /// `otpauth://` URI for authenticator apps
+    pub uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrustedDeviceView {
+    pub id: String,
+    pub label: String,
+    pub last_used_at: Option<String>,
+    pub expires_at: String,
+}
+
+/// Context for `error.html`
+#[derive(Debug, Default, Serialize)]
+pub struct ErrorPage {
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+    pub error_code: Option<String>,
+}
+
+/// Context for `error_reference.html`
+#[derive(Debug, Serialize)]
+pub struct ErrorReferencePage {
+    /// Every code on the index, or just the one asked for
+    pub entries: Vec<ErrorReferenceEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorReferenceEntry {
+    pub code: &'static str,
+    pub status: u16,
+    pub summary: &'static str,
+    pub remedy: &'static str,
+}
+
+/// Context for `admin_dashboard.html`
+#[derive(Debug, Serialize)]
+pub struct AdminDashboardPage {}
+
+/// Context for `portal.html`
+#[derive(Debug, Serialize)]
+pub struct PortalPage {
+    pub user_email: String,
+    pub clients: Vec<PortalClientSummary>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PortalClientSummary {
+    pub client_id: String,
+    pub name: String,
+    pub created_at: String,
+}
+
+/// Context for `portal_client.html`
+#[derive(Debug, Serialize)]
+pub struct PortalClientPage {
+    pub client: PortalClientView,
+    /// Plaintext secret, only set directly after registration or rotation
+    pub new_secret: Option<String>,
+    pub tokens: Vec<PortalTokenView>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PortalClientView {
+    pub client_id: String,
+    pub name: String,
+    pub scope: String,
+    pub grant_types: Vec<String>,
+    pub redirect_uris: Vec<String>,
+    /// When the secret stops being accepted, if it expires
+    pub secret_expires_at: Option<String>,
+    pub secret_expired: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PortalTokenView {
+    pub created_at: String,
+    pub user_id: String,
+    pub scope: String,
+    pub status: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_templates_parse() {
+        assert!(Templates::new(BrandingConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn test_branding_is_applied() {
+        let branding = BrandingConfig {
+            product_name: "Acme ID".to_string(),
+            logo_url: Some("https://cdn.example.com/logo.svg".to_string()),
+            primary_color: "#ff0000".to_string(),
+            terms_url: None,
+            ..BrandingConfig::default()
+        };
+        let templates = Templates::new(branding).unwrap();
+
+        let html = templates
+            .render("error.html", &ErrorPage::default())
+            .unwrap();
+        assert!(html.contains("Acme ID"));
+        assert!(html.contains("cdn.example.com"));
+        assert!(html.contains("--brand-primary: #ff0000"));
+        assert!(!html.contains("Terms of Service"));
+    }
+
+    #[test]
+    fn test_login_page_renders_provider_buttons() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let page = LoginPage {
+            providers: vec![ProviderButton {
+                id: "github",
+                label: "GitHub",
+            }],
+            local_login_enabled: false,
+            demo: false,
+        };
+
+        let html = templates.render("login.html", &page).unwrap();
+        assert!(html.contains("/auth/login/github"));
+        assert!(html.contains("Continue with GitHub"));
+        assert!(!html.contains("/auth/login/google"));
+        assert!(!html.contains("loginForm"));
+        assert!(!html.contains("Or continue with"));
+    }
+
+    #[test]
+    fn test_login_page_local_form_only() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let page = LoginPage {
+            providers: vec![],
+            local_login_enabled: true,
+            demo: false,
+        };
+
+        let html = templates.render("login.html", &page).unwrap();
+        assert!(html.contains("loginForm"));
+        assert!(!html.contains("Or continue with"));
+        assert!(!html.contains("Continue with"));
+    }
+
+    #[test]
+    fn test_login_page_offers_the_demo_user() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let page = LoginPage {
+            providers: vec![],
+            local_login_enabled: false,
+            demo: true,
+        };
+
+        let html = templates.render_form("login.html", &page, "token").unwrap();
+        assert!(html.contains("action=\"/demo/login\""));
+        assert!(!html.contains("No sign-in methods are configured"));
+    }
+
+    #[test]
+    fn test_user_info_is_escaped() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let page = AuthSuccessPage {
+            user: Some(SocialUserInfo {
+                provider: "github".to_string(),
+                provider_user_id: "1".to_string(),
+                email: "a@example.com".to_string(),
+                name: Some("<script>alert(1)</script>".to_string()),
+                picture: None,
+                upstream_claims: Default::default(),
+                roles: vec![],
+                groups: vec![],
+                claims: Default::default(),
+            }),
+        };
+
+        let html = templates.render("auth_success.html", &page).unwrap();
+        assert!(!html.contains("<script>alert(1)</script>"));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+
+    #[test]
+    fn test_error_page_shows_description() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let page = ErrorPage {
+            error: Some("access_denied".to_string()),
+            error_description: Some("User denied <consent>".to_string()),
+            error_code: None,
+        };
+
+        let html = templates.render("error.html", &page).unwrap();
+        assert!(html.contains("User denied &lt;consent&gt;"));
+        assert!(html.contains("Error Code: UNKNOWN"));
+    }
+
+    #[test]
+    fn test_error_reference_page_lists_entries() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let page = ErrorReferencePage {
+            entries: vec![ErrorReferenceEntry {
+                code: "invalid_grant",
+                status: 400,
+                summary: "The grant is invalid",
+                remedy: "Start again",
+            }],
+        };
+
+        let html = templates.render("error_reference.html", &page).unwrap();
+        assert!(html.contains(r#"id="invalid_grant""#));
+        assert!(html.contains("HTTP 400"));
+        assert!(html.contains("Start again"));
+    }
+
+    #[test]
+    fn test_step_up_page_offers_code_form_when_enrolled() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let mut page = StepUpPage {
+            reasons: vec!["First sign-in from DE".to_string()],
+            mfa_enrolled: false,
+            trusted_device_days: Some(30),
+            error: None,
+        };
+
+        let html = templates
+            .render_form("step_up.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains("First sign-in from DE"));
+        assert!(!html.contains("one-time-code"));
+
+        page.mfa_enrolled = true;
+        let html = templates
+            .render_form("step_up.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains("one-time-code"));
+        assert!(html.contains("Remember this device for 30 days"));
+
+        page.trusted_device_days = None;
+        let html = templates
+            .render_form("step_up.html", &page, "t0ken")
+            .unwrap();
+        assert!(!html.contains("remember_device"));
+    }
+
+    #[test]
+    fn test_consent_page_lists_scopes_and_escapes_the_client() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let page = ConsentPage {
+            client_name: "<Evil> App".to_string(),
+            user_email: "ada@example.com".to_string(),
+            scopes: vec!["read".to_string(), "write".to_string()],
+            action: "/oauth/authorize?client_id=app&state=a%26b".to_string(),
+        };
+
+        let html = templates
+            .render_form("consent.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains("&lt;Evil&gt; App"));
+        assert!(!html.contains("<Evil>"));
+        assert!(html.contains("<code>read</code>"));
+        assert!(html.contains("<code>write</code>"));
+        assert!(html.contains(r#"name="decision" value="approve""#));
+        assert!(html.contains(r#"name="decision" value="deny""#));
+        assert!(html.contains(r#"<input type="hidden" name="csrf_token" value="t0ken">"#));
+    }
+
+    #[test]
+    fn test_logout_all_page_confirms_then_reports() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let mut page = LogoutAllPage {
+            user_email: "ada@example.com".to_string(),
+            tokens_revoked: None,
+        };
+
+        let html = templates
+            .render_form("logout_all.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains(r#"action="/auth/logout-all""#));
+        assert!(html.contains(r#"<input type="hidden" name="csrf_token" value="t0ken">"#));
+        // A form page rendered without a token fails rather than posting an empty one
+        assert!(templates.render("logout_all.html", &page).is_err());
+
+        page.tokens_revoked = Some(0);
+        let html = templates
+            .render_form("logout_all.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains("Signed out everywhere"));
+        assert!(html.contains("0 tokens"));
+        assert!(!html.contains(r#"action="/auth/logout-all""#));
+    }
+
+    #[test]
+    fn test_account_delete_page_confirms_then_reports() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let mut page = AccountDeletePage {
+            user_email: "ada@example.com".to_string(),
+            mfa_required: true,
+            grace_period_days: 30,
+            erase_after: None,
+            error: None,
+        };
+
+        let html = templates
+            .render_form("account_delete.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains(r#"action="/account/delete""#));
+        assert!(html.contains(r#"name="code""#));
+        assert!(html.contains("after 30 days"));
+
+        page.erase_after = Some("2024-02-01".to_string());
+        let html = templates
+            .render_form("account_delete.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains("erased on 2024-02-01"));
+        assert!(!html.contains(r#"action="/account/delete""#));
+    }
+
+    #[test]
+    fn test_account_connections_page_lists_and_offers_connections() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let mut page = AccountConnectionsPage {
+            user_email: "ada@example.com".to_string(),
+            connectable: Some("google".to_string()),
+            connections: vec![],
+            notice: None,
+        };
+
+        let html = templates
+            .render_form("account_connections.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains("/auth/login/google?connect=true&amp;return_to=/account/connections"));
+
+        page.connectable = None;
+        page.connections = vec![ConnectionView {
+            provider: "google".to_string(),
+            scopes: vec!["openid".to_string(), "calendar.readonly".to_string()],
+            connected_at: "2024-02-01".to_string(),
+            offline: true,
+        }];
+        let html = templates
+            .render_form("account_connections.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains("calendar.readonly"));
+        assert!(html.contains(r#"action="/account/connections/google/disconnect""#));
+        assert!(!html.contains("connect=true"));
+    }
+
+    #[test]
+    fn test_portal_pages_render() {
+        let templates = Templates::new(BrandingConfig::default()).unwrap();
+        let page = PortalPage {
+            user_email: "dev@example.com".to_string(),
+            clients: vec![PortalClientSummary {
+                client_id: "client_abc".to_string(),
+                name: "My App".to_string(),
+                created_at: "2024-01-01".to_string(),
+            }],
+            error: None,
+        };
+        let html = templates
+            .render_form("portal.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains("My App"));
+
+        let page = PortalClientPage {
+            client: PortalClientView {
+                client_id: "client_abc".to_string(),
+                name: "My App".to_string(),
+                scope: "read".to_string(),
+                grant_types: vec!["authorization_code".to_string()],
+                redirect_uris: vec!["https://app.example.com/cb".to_string()],
+                secret_expires_at: Some("2024-03-01 00:00:00 UTC".to_string()),
+                secret_expired: false,
+            },
+            new_secret: Some("s3cret".to_string()),
+            tokens: vec![PortalTokenView {
+                created_at: "2024-01-01 00:00:00 UTC".to_string(),
+                user_id: "user_123".to_string(),
+                scope: "read".to_string(),
+                status: "active",
+            }],
+            error: None,
+        };
+        let html = templates
+            .render_form("portal_client.html", &page, "t0ken")
+            .unwrap();
+        assert!(html.contains("client_abc"));
+        assert!(html.contains("s3cret"));
+        assert!(html.contains("Expires 2024-03-01 00:00:00 UTC"));
+    }
+}
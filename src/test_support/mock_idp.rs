@@ -0,0 +1,517 @@
+//! A minimal upstream identity provider for social-login tests.
+//!
+//! `MockIdp` serves authorize, token, userinfo and JWKS endpoints on a random
+//! local port and signs in a single configured [`MockUser`]. Point a provider
+//! at it with [`MockIdp::provider_config`] to run the real callback code
+//! without credentials for Google, Microsoft or GitHub.
+
+use crate::models::ProviderConfig;
+use actix_web::dev::ServerHandle;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use jsonwebtoken::{EncodingKey, Header};
+use oauth2::url::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::TcpListener;
+use std::sync::Mutex;
+
+/// Test-only signing key. Never use it for anything but the mock.
+const SIGNING_KEY_PEM: &str = include_str!("mock_idp_key.pem");
+/// Base64url modulus of [`SIGNING_KEY_PEM`], published through the JWKS endpoint
+pub(crate) const SIGNING_KEY_N: &str = "pU1keKOBfdb3cmQZSmmffjpR6p0kzqfOVMCizcy9VYsogNTjKUJfLT6Xr27gNnmO1CwyyZ10fksoRSiY-qkiLFZdXgNpuT-5Wz6B9V5QKmdJO_8KNZcAQSm4O6OnWKMHfWi5vDM4Ix8c09ASCqQrK4cRQ_ZYQ1iAnwjW7bF16qopbWES5oohLk7Gnshzbvq7HkeXN2HTqdDfdZ0Mx4aKvW6ybeSMwOmvHr5v_oxAk8-56ZiYUclnwGr7IKz5KngB9CfWy_taXuY9YueGarf4KoFJQ9U8e17gPwPFiw6iODleS3Yy-4tQKuOYBx3_3XyiOAOrGb8fSfz_nMjWCFpKaQ";
+pub(crate) const SIGNING_KEY_E: &str = "AQAB";
+const SIGNING_KEY_ID: &str = "mock-idp-key";
+
+/// The account the mock provider signs in
+#[derive(Debug, Clone)]
+pub struct MockUser {
+    pub id: String,
+    pub email: String,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+    /// Google Workspace domain, sent as `hd`
+    pub hosted_domain: Option<String>,
+    /// Display names of the groups Microsoft Graph lists the user in
+    pub groups: Vec<String>,
+}
+
+impl Default for MockUser {
+    fn default() -> Self {
+        Self {
+            id: "10001".to_string(),
+            email: "mock.user@example.com".to_string(),
+            name: Some("Mock User".to_string()),
+            picture: None,
+            hosted_domain: None,
+            groups: vec![],
+        }
+    }
+}
+
+struct IssuedCode {
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    nonce: Option<String>,
+    scope: Option<String>,
+    offline: bool,
+}
+
+struct MockState {
+    issuer: String,
+    user: Mutex<MockUser>,
+    codes: Mutex<HashMap<String, IssuedCode>>,
+    access_tokens: Mutex<HashSet<String>>,
+    refresh_tokens: Mutex<HashSet<String>>,
+    traceparents: Mutex<Vec<String>>,
+}
+
+/// A running mock identity provider
+pub struct MockIdp {
+    base_url: String,
+    handle: ServerHandle,
+    state: web::Data<MockState>,
+}
+
+impl MockIdp {
+    /// Start the provider on `127.0.0.1` with a random port
+    pub async fn start(user: MockUser) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let base_url = format!("http://{}", listener.local_addr()?);
+
+        let state = web::Data::new(MockState {
+            issuer: base_url.clone(),
+            user: Mutex::new(user),
+            codes: Mutex::new(HashMap::new()),
+            access_tokens: Mutex::new(HashSet::new()),
+            refresh_tokens: Mutex::new(HashSet::new()),
+            traceparents: Mutex::new(Vec::new()),
+        });
+
+        let server = HttpServer::new({
+            let state = state.clone();
+            move || {
+                App::new()
+                    .app_data(state.clone())
+                    .route("/authorize", web::get().to(authorize))
+                    .route("/token", web::post().to(token))
+                    .route("/userinfo/{provider}", web::get().to(userinfo))
+                    .route("/userinfo/github/emails", web::get().to(github_emails))
+                    .route(
+                        "/userinfo/microsoft/memberOf",
+                        web::get().to(microsoft_groups),
+                    )
+                    .route("/jwks", web::get().to(jwks))
+                    .route(
+                        "/.well-known/openid-configuration",
+                        web::get().to(discovery),
+                    )
+            }
+        })
+        .workers(1)
+        .listen(listener)?
+        .run();
+
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        Ok(Self {
+            base_url,
+            handle,
+            state,
+        })
+    }
+
+    /// Change the account the provider signs in and describes from now on,
+    /// e.g. to have its profile change between logins
+    pub fn set_user(&self, user: MockUser) {
+        *self.state.user.lock().unwrap() = user;
+    }
+
+    /// The `traceparent` headers of the back-channel requests received so
+    /// far, in order
+    pub fn traceparents(&self) -> Vec<String> {
+        self.state.traceparents.lock().unwrap().clone()
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Provider settings that send `provider`'s login flow to this mock
+    pub fn provider_config(&self, provider: &str, redirect_uri: &str) -> ProviderConfig {
+        ProviderConfig {
+            client_id: format!("mock-{}-client", provider),
+            client_secret: "mock-client-secret".to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            tenant_id: None,
+            domain: None,
+            auth_url: Some(format!("{}/authorize", self.base_url)),
+            token_url: Some(format!("{}/token", self.base_url)),
+            userinfo_url: Some(format!("{}/userinfo/{}", self.base_url, provider)),
+            upstream_logout: false,
+            logout_url: None,
+            post_logout_redirect_uri: None,
+            store_tokens: false,
+            api_scopes: vec![],
+            claim_mappings: vec![],
+        }
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(false).await;
+    }
+}
+
+fn oauth_error(error: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({ "error": error }))
+}
+
+fn record_traceparent(req: &HttpRequest, state: &MockState) {
+    if let Some(value) = req
+        .headers()
+        .get("traceparent")
+        .and_then(|value| value.to_str().ok())
+    {
+        state.traceparents.lock().unwrap().push(value.to_string());
+    }
+}
+
+fn random_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    response_type: String,
+    client_id: String,
+    redirect_uri: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    scope: Option<String>,
+    access_type: Option<String>,
+}
+
+/// Approve every request immediately and redirect back with a code
+async fn authorize(query: web::Query<AuthorizeQuery>, state: web::Data<MockState>) -> HttpResponse {
+    let query = query.into_inner();
+    if query.response_type != "code" {
+        return oauth_error("unsupported_response_type");
+    }
+    let Ok(mut redirect) = Url::parse(&query.redirect_uri) else {
+        return oauth_error("invalid_request");
+    };
+
+    // Google asks for offline access with a parameter, others with a scope
+    let offline = query.access_type.as_deref() == Some("offline")
+        || query
+            .scope
+            .as_deref()
+            .is_some_and(|scope| scope.split_whitespace().any(|s| s == "offline_access"));
+    let code = random_token();
+    state.codes.lock().unwrap().insert(
+        code.clone(),
+        IssuedCode {
+            client_id: query.client_id,
+            redirect_uri: query.redirect_uri,
+            code_challenge: query.code_challenge,
+            code_challenge_method: query.code_challenge_method,
+            nonce: query.nonce,
+            scope: query.scope,
+            offline,
+        },
+    );
+
+    {
+        let mut params = redirect.query_pairs_mut();
+        params.append_pair("code", &code);
+        if let Some(s) = &query.state {
+            params.append_pair("state", s);
+        }
+    }
+
+    HttpResponse::Found()
+        .append_header(("Location", redirect.to_string()))
+        .finish()
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    client_id: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+}
+
+/// Client ID from HTTP Basic auth, as sent by the `oauth2` crate by default
+fn basic_auth_client_id(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get("Authorization")?.to_str().ok()?;
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = general_purpose::STANDARD.decode(encoded).ok()?;
+    let (client_id, _) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some(client_id.to_string())
+}
+
+fn pkce_matches(challenge: &str, method: Option<&str>, verifier: &str) -> bool {
+    match method.unwrap_or("plain") {
+        "S256" => general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier)) == challenge,
+        "plain" => verifier == challenge,
+        _ => false,
+    }
+}
+
+#[derive(Serialize)]
+struct IdTokenClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+    email: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<&'a str>,
+}
+
+async fn token(
+    req: HttpRequest,
+    form: web::Form<TokenForm>,
+    state: web::Data<MockState>,
+) -> HttpResponse {
+    record_traceparent(&req, &state);
+    let form = form.into_inner();
+    let Some(client_id) = basic_auth_client_id(&req).or(form.client_id) else {
+        return HttpResponse::Unauthorized().json(serde_json::json!({ "error": "invalid_client" }));
+    };
+    match form.grant_type.as_str() {
+        "authorization_code" => {}
+        "refresh_token" => {
+            let known = form
+                .refresh_token
+                .is_some_and(|token| state.refresh_tokens.lock().unwrap().contains(&token));
+            if !known {
+                return oauth_error("invalid_grant");
+            }
+            let access_token = random_token();
+            state
+                .access_tokens
+                .lock()
+                .unwrap()
+                .insert(access_token.clone());
+            return HttpResponse::Ok().json(serde_json::json!({
+                "access_token": access_token,
+                "token_type": "Bearer",
+                "expires_in": 3600,
+            }));
+        }
+        _ => return oauth_error("unsupported_grant_type"),
+    }
+
+    // Codes are single use, even when the exchange fails
+    let Some(issued) = form
+        .code
+        .and_then(|code| state.codes.lock().unwrap().remove(&code))
+    else {
+        return oauth_error("invalid_grant");
+    };
+    if issued.client_id != client_id || Some(&issued.redirect_uri) != form.redirect_uri.as_ref() {
+        return oauth_error("invalid_grant");
+    }
+    if let Some(challenge) = &issued.code_challenge {
+        let verified = form.code_verifier.as_deref().is_some_and(|verifier| {
+            pkce_matches(challenge, issued.code_challenge_method.as_deref(), verifier)
+        });
+        if !verified {
+            return oauth_error("invalid_grant");
+        }
+    }
+
+    let access_token = random_token();
+    state
+        .access_tokens
+        .lock()
+        .unwrap()
+        .insert(access_token.clone());
+
+    let user = state.user.lock().unwrap().clone();
+    let now = Utc::now().timestamp();
+    let claims = IdTokenClaims {
+        iss: &state.issuer,
+        sub: &user.id,
+        aud: &client_id,
+        iat: now,
+        exp: now + 3600,
+        email: &user.email,
+        name: user.name.as_deref(),
+        nonce: issued.nonce.as_deref(),
+    };
+    let id_token = sign_jwt(&claims);
+
+    let mut body = serde_json::json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": 3600,
+        "id_token": id_token,
+    });
+    if let Some(scope) = &issued.scope {
+        body["scope"] = serde_json::json!(scope);
+    }
+    if issued.offline {
+        let refresh_token = random_token();
+        state
+            .refresh_tokens
+            .lock()
+            .unwrap()
+            .insert(refresh_token.clone());
+        body["refresh_token"] = serde_json::json!(refresh_token);
+    }
+    HttpResponse::Ok().json(body)
+}
+
+fn bearer_is_valid(req: &HttpRequest, state: &MockState) -> bool {
+    req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| state.access_tokens.lock().unwrap().contains(token))
+}
+
+/// Return the user in the shape each provider's API uses
+async fn userinfo(
+    req: HttpRequest,
+    provider: web::Path<String>,
+    state: web::Data<MockState>,
+) -> HttpResponse {
+    record_traceparent(&req, &state);
+    if !bearer_is_valid(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let user = state.user.lock().unwrap().clone();
+    let body = match provider.as_str() {
+        "google" => serde_json::json!({
+            "id": user.id,
+            "email": user.email,
+            "name": user.name,
+            "picture": user.picture,
+            "hd": user.hosted_domain,
+        }),
+        "microsoft" => serde_json::json!({
+            "id": user.id,
+            "userPrincipalName": user.email,
+            "displayName": user.name,
+        }),
+        // GitHub omits private emails here; clients fall back to /emails
+        "github" => serde_json::json!({
+            "id": user.id.parse::<i64>().unwrap_or_default(),
+            "email": null,
+            "name": user.name,
+            "avatar_url": user.picture,
+        }),
+        _ => serde_json::json!({
+            "sub": user.id,
+            "email": user.email,
+            "name": user.name,
+            "picture": user.picture,
+        }),
+    };
+
+    HttpResponse::Ok().json(body)
+}
+
+async fn github_emails(req: HttpRequest, state: web::Data<MockState>) -> HttpResponse {
+    record_traceparent(&req, &state);
+    if !bearer_is_valid(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let email = state.user.lock().unwrap().email.clone();
+    HttpResponse::Ok().json(serde_json::json!([
+        { "email": email, "primary": true, "verified": true }
+    ]))
+}
+
+/// One page per group, to exercise following `@odata.nextLink`
+async fn microsoft_groups(
+    req: HttpRequest,
+    query: web::Query<HashMap<String, usize>>,
+    state: web::Data<MockState>,
+) -> HttpResponse {
+    record_traceparent(&req, &state);
+    if !bearer_is_valid(&req, &state) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let groups = state.user.lock().unwrap().groups.clone();
+    let page = query.get("page").copied().unwrap_or(0);
+    let value: Vec<_> = groups
+        .get(page)
+        .map(|name| {
+            serde_json::json!({
+                "@odata.type": "#microsoft.graph.group",
+                "id": format!("group-{}", page),
+                "displayName": name,
+            })
+        })
+        .into_iter()
+        .collect();
+    let mut body = serde_json::json!({ "value": value });
+    if page + 1 < groups.len() {
+        body["@odata.nextLink"] = serde_json::json!(format!(
+            "{}/userinfo/microsoft/memberOf?page={}",
+            state.issuer,
+            page + 1
+        ));
+    }
+    HttpResponse::Ok().json(body)
+}
+
+/// Sign claims with the mock's RS256 key, as the mock signs its ID tokens
+pub fn sign_jwt<T: Serialize>(claims: &T) -> String {
+    let mut header = Header::new(jsonwebtoken::Algorithm::RS256);
+    header.kid = Some(SIGNING_KEY_ID.to_string());
+    let key = EncodingKey::from_rsa_pem(SIGNING_KEY_PEM.as_bytes())
+        .expect("mock IdP signing key is valid");
+    jsonwebtoken::encode(&header, claims, &key).expect("signing cannot fail")
+}
+
+/// The JWKS holding the public half of the key [`sign_jwt`] uses
+pub fn signing_jwks() -> serde_json::Value {
+    serde_json::json!({
+        "keys": [{
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": SIGNING_KEY_ID,
+            "n": SIGNING_KEY_N,
+            "e": SIGNING_KEY_E,
+        }]
+    })
+}
+
+async fn jwks() -> HttpResponse {
+    HttpResponse::Ok().json(signing_jwks())
+}
+
+async fn discovery(state: web::Data<MockState>) -> HttpResponse {
+    let issuer = &state.issuer;
+    HttpResponse::Ok().json(serde_json::json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "userinfo_endpoint": format!("{}/userinfo/oidc", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+    }))
+}
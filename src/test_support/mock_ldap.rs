@@ -0,0 +1,210 @@
+//! A minimal LDAP server for directory sign-in tests.
+//!
+//! `MockLdap` listens on a random local port and answers simple binds and
+//! equality searches from a fixed list of [`MockLdapEntry`]s. Searches are
+//! only answered once the connection has bound as the service account, as a
+//! locked-down directory would. Point the server at it with
+//! [`MockLdap::ldap_config`].
+
+use crate::config::LdapConfig;
+use crate::services::ldap::{
+    ber, message, parse_message, read_message, BIND_REQUEST, BIND_RESPONSE, EQUALITY_MATCH,
+    INVALID_CREDENTIALS, SEARCH_REQUEST, SEARCH_RESULT_DONE, SEARCH_RESULT_ENTRY, SIMPLE_AUTH,
+    SUCCESS, UNBIND_REQUEST,
+};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+pub const MOCK_LDAP_BASE_DN: &str = "ou=people,dc=example,dc=com";
+pub const MOCK_LDAP_BIND_DN: &str = "cn=oauth2,dc=example,dc=com";
+pub const MOCK_LDAP_BIND_PASSWORD: &str = "service-secret";
+
+/// insufficientAccessRights
+const INSUFFICIENT_ACCESS: i64 = 50;
+
+/// A user in the mock directory
+#[derive(Debug, Clone)]
+pub struct MockLdapEntry {
+    pub dn: String,
+    pub password: String,
+    pub attributes: Vec<(String, Vec<String>)>,
+}
+
+impl MockLdapEntry {
+    /// A `uid=...` entry under [`MOCK_LDAP_BASE_DN`] in `groups`
+    pub fn person(uid: &str, password: &str, groups: &[&str]) -> Self {
+        Self {
+            dn: format!("uid={},{}", uid, MOCK_LDAP_BASE_DN),
+            password: password.to_string(),
+            attributes: vec![
+                ("uid".to_string(), vec![uid.to_string()]),
+                ("mail".to_string(), vec![format!("{}@example.com", uid)]),
+                ("cn".to_string(), vec![uid.to_string()]),
+                (
+                    "memberOf".to_string(),
+                    groups.iter().map(|group| group.to_string()).collect(),
+                ),
+            ],
+        }
+    }
+
+    fn values(&self, name: &str) -> &[String] {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+            .map(|(_, values)| values.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// A running mock directory
+pub struct MockLdap {
+    url: String,
+    task: JoinHandle<()>,
+}
+
+impl MockLdap {
+    /// Start the directory on `127.0.0.1` with a random port
+    pub async fn start(entries: Vec<MockLdapEntry>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ldap://{}", listener.local_addr()?);
+        let entries = Arc::new(entries);
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, entries.clone()));
+            }
+        });
+        Ok(Self { url, task })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Settings that sign users in against this directory
+    pub fn ldap_config(&self) -> LdapConfig {
+        LdapConfig {
+            url: Some(self.url.clone()),
+            bind_dn: Some(MOCK_LDAP_BIND_DN.to_string()),
+            bind_password: Some(MOCK_LDAP_BIND_PASSWORD.to_string()),
+            base_dn: MOCK_LDAP_BASE_DN.to_string(),
+            ..LdapConfig::default()
+        }
+    }
+}
+
+impl Drop for MockLdap {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn result(tag: u8, code: i64) -> Vec<u8> {
+    let mut content = ber::integer(ber::ENUMERATED, code);
+    content.extend(ber::tlv(ber::OCTET_STRING, b""));
+    content.extend(ber::tlv(ber::OCTET_STRING, b""));
+    ber::tlv(tag, &content)
+}
+
+fn search_entry(entry: &MockLdapEntry, wanted: &[String]) -> Vec<u8> {
+    let attributes: Vec<u8> = entry
+        .attributes
+        .iter()
+        .filter(|(name, _)| {
+            wanted.is_empty() || wanted.iter().any(|w| w.eq_ignore_ascii_case(name))
+        })
+        .flat_map(|(name, values)| {
+            let set: Vec<u8> = values
+                .iter()
+                .flat_map(|value| ber::tlv(ber::OCTET_STRING, value.as_bytes()))
+                .collect();
+            let mut attribute = ber::tlv(ber::OCTET_STRING, name.as_bytes());
+            attribute.extend(ber::tlv(ber::SET, &set));
+            ber::tlv(ber::SEQUENCE, &attribute)
+        })
+        .collect();
+    let mut content = ber::tlv(ber::OCTET_STRING, entry.dn.as_bytes());
+    content.extend(ber::tlv(ber::SEQUENCE, &attributes));
+    ber::tlv(SEARCH_RESULT_ENTRY, &content)
+}
+
+/// The DN and password of a simple bind
+fn parse_bind(op: &[u8]) -> Option<(String, String)> {
+    let mut reader = ber::Reader::new(op);
+    let _version = reader.expect(ber::INTEGER)?;
+    let dn = reader.string()?;
+    let password = String::from_utf8_lossy(reader.expect(SIMPLE_AUTH)?).into_owned();
+    Some((dn, password))
+}
+
+/// The base DN, equality filter and attributes of a search
+fn parse_search(op: &[u8]) -> Option<(String, String, String, Vec<String>)> {
+    let mut reader = ber::Reader::new(op);
+    let base = reader.string()?;
+    for _ in 0..5 {
+        reader.next()?; // scope, deref, size and time limits, typesOnly
+    }
+    let mut filter = ber::Reader::new(reader.expect(EQUALITY_MATCH)?);
+    let (attribute, value) = (filter.string()?, filter.string()?);
+    let mut list = ber::Reader::new(reader.expect(ber::SEQUENCE)?);
+    let mut wanted = Vec::new();
+    while let Some(name) = list.string() {
+        wanted.push(name);
+    }
+    Some((base, attribute, value, wanted))
+}
+
+async fn serve(mut stream: TcpStream, entries: Arc<Vec<MockLdapEntry>>) {
+    let mut service_bound = false;
+    while let Ok(content) = read_message(&mut stream).await {
+        let Some((id, tag, op)) = parse_message(&content) else {
+            return;
+        };
+        let mut responses = Vec::new();
+        match tag {
+            BIND_REQUEST => {
+                let (dn, password) = parse_bind(op).unwrap_or_default();
+                service_bound = dn == MOCK_LDAP_BIND_DN && password == MOCK_LDAP_BIND_PASSWORD;
+                let user = entries
+                    .iter()
+                    .any(|entry| entry.dn.eq_ignore_ascii_case(&dn) && entry.password == password);
+                let code = if service_bound || user {
+                    SUCCESS
+                } else {
+                    INVALID_CREDENTIALS
+                };
+                responses.push(result(BIND_RESPONSE, code));
+            }
+            SEARCH_REQUEST if !service_bound => {
+                responses.push(result(SEARCH_RESULT_DONE, INSUFFICIENT_ACCESS));
+            }
+            SEARCH_REQUEST => {
+                let Some((base, attribute, value, wanted)) = parse_search(op) else {
+                    return;
+                };
+                let base = base.to_ascii_lowercase();
+                for entry in entries.iter() {
+                    let under_base = entry.dn.to_ascii_lowercase().ends_with(&base);
+                    let matches = entry
+                        .values(&attribute)
+                        .iter()
+                        .any(|v| v.eq_ignore_ascii_case(&value));
+                    if under_base && matches {
+                        responses.push(search_entry(entry, &wanted));
+                    }
+                }
+                responses.push(result(SEARCH_RESULT_DONE, SUCCESS));
+            }
+            UNBIND_REQUEST => return,
+            _ => return,
+        }
+        for response in responses {
+            if stream.write_all(&message(id, &response)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! A minimal Redis server for cache tests.
+//!
+//! `MockRedis` listens on a random local port and answers the commands
+//! [`RedisCache`](crate::cache::RedisCache) sends from one store shared by
+//! every connection, so several servers pointed at it see each other's
+//! entries as replicas would. Set `cache.redis_url` to [`MockRedis::url`].
+//! It also keeps the entries `XADD` appends to streams, for the Redis event
+//! backend.
+
+use crate::cache::redis::{encode_reply, read_command, Reply};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+type Store = Arc<Mutex<HashMap<Vec<u8>, (Vec<u8>, Option<Instant>)>>>;
+/// Each stream's entries, as their field-value pairs
+type Streams = Arc<Mutex<HashMap<Vec<u8>, Vec<Vec<(String, String)>>>>>;
+
+/// A running mock Redis
+pub struct MockRedis {
+    url: String,
+    store: Store,
+    streams: Streams,
+    task: JoinHandle<()>,
+}
+
+impl MockRedis {
+    /// Start the server on `127.0.0.1` with a random port
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("redis://{}", listener.local_addr()?);
+        let store = Store::default();
+        let streams = Streams::default();
+
+        let task = tokio::spawn({
+            let store = store.clone();
+            let streams = streams.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, store.clone(), streams.clone()));
+                }
+            }
+        });
+        Ok(Self {
+            url,
+            store,
+            streams,
+            task,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Keys currently stored, prefix included
+    pub fn keys(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.store
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (_, expires))| expires.is_none_or(|at| at > now))
+            .map(|(key, _)| String::from_utf8_lossy(key).into_owned())
+            .collect()
+    }
+
+    /// Entries added to `stream` with `XADD`, oldest first
+    pub fn stream(&self, stream: &str) -> Vec<Vec<(String, String)>> {
+        self.streams
+            .lock()
+            .unwrap()
+            .get(stream.as_bytes())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for MockRedis {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn expiry(millis: &[u8]) -> Option<Instant> {
+    let millis: u64 = std::str::from_utf8(millis).ok()?.parse().ok()?;
+    Some(Instant::now() + Duration::from_millis(millis))
+}
+
+fn execute(store: &Store, streams: &Streams, args: &[Vec<u8>]) -> Reply {
+    let Some((command, args)) = args.split_first() else {
+        return Reply::Error("ERR empty command".to_string());
+    };
+    if command.eq_ignore_ascii_case(b"XADD") {
+        return match args {
+            [stream, id, fields @ ..]
+                if id == b"*" && !fields.is_empty() && fields.len() % 2 == 0 =>
+            {
+                let mut streams = streams.lock().unwrap();
+                let entries = streams.entry(stream.clone()).or_default();
+                entries.push(
+                    fields
+                        .chunks(2)
+                        .map(|pair| {
+                            (
+                                String::from_utf8_lossy(&pair[0]).into_owned(),
+                                String::from_utf8_lossy(&pair[1]).into_owned(),
+                            )
+                        })
+                        .collect(),
+                );
+                Reply::Bulk(Some(format!("{}-0", entries.len()).into_bytes()))
+            }
+            _ => Reply::Error("ERR wrong number of arguments for 'xadd' command".to_string()),
+        };
+    }
+    let mut store = store.lock().unwrap();
+    let now = Instant::now();
+    store.retain(|_, (_, expires)| expires.is_none_or(|at| at > now));
+
+    match (command.to_ascii_uppercase().as_slice(), args) {
+        (b"AUTH" | b"SELECT", _) => Reply::Status("OK".to_string()),
+        (b"PING", []) => Reply::Status("PONG".to_string()),
+        (b"GET", [key]) => Reply::Bulk(store.get(key).map(|(value, _)| value.clone())),
+        (b"SET", [key, value, options @ ..]) => {
+            let options: Vec<Vec<u8>> = options.iter().map(|o| o.to_ascii_uppercase()).collect();
+            if options.iter().any(|o| o == b"NX") && store.contains_key(key) {
+                return Reply::Bulk(None);
+            }
+            let expires = options
+                .iter()
+                .position(|o| o == b"PX")
+                .and_then(|i| args.get(i + 3))
+                .and_then(|millis| expiry(millis));
+            store.insert(key.clone(), (value.clone(), expires));
+            Reply::Status("OK".to_string())
+        }
+        (b"DEL", keys) => {
+            let removed = keys
+                .iter()
+                .filter(|key| store.remove(*key).is_some())
+                .count();
+            Reply::Integer(removed as i64)
+        }
+        (b"INCR", [key]) => {
+            let entry = store.entry(key.clone()).or_insert((b"0".to_vec(), None));
+            match std::str::from_utf8(&entry.0)
+                .ok()
+                .and_then(|n| n.parse::<i64>().ok())
+            {
+                Some(n) => {
+                    entry.0 = (n + 1).to_string().into_bytes();
+                    Reply::Integer(n + 1)
+                }
+                None => Reply::Error("ERR value is not an integer".to_string()),
+            }
+        }
+        (b"PEXPIRE", [key, millis]) => match store.get_mut(key) {
+            Some(entry) => {
+                entry.1 = expiry(millis);
+                Reply::Integer(1)
+            }
+            None => Reply::Integer(0),
+        },
+        _ => Reply::Error("ERR unknown command".to_string()),
+    }
+}
+
+async fn serve(stream: TcpStream, store: Store, streams: Streams) {
+    let mut stream = BufReader::new(stream);
+    while let Ok(args) = read_command(&mut stream).await {
+        let reply = execute(&store, &streams, &args);
+        if stream.write_all(&encode_reply(&reply)).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Helpers for testing the SAML identity provider as a service provider
+//! would see it.
+//!
+//! [`saml_config`] signs with the mock IdP's key and trusts one service
+//! provider, [`authn_request`] builds the `SAMLRequest` it would send, and
+//! [`verify_assertion`] checks the signature on what comes back.
+
+use crate::config::SamlConfig;
+use crate::test_support::mock_idp::{SIGNING_KEY_E, SIGNING_KEY_N};
+use base64::{engine::general_purpose, Engine as _};
+use flate2::{write::DeflateEncoder, Compression};
+use jsonwebtoken::{Algorithm, DecodingKey};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+
+pub const MOCK_SP_ENTITY_ID: &str = "https://legacy.example.test/saml";
+pub const MOCK_SP_ACS_URL: &str = "https://legacy.example.test/saml/acs";
+
+const DSIG_NS: &str = "http://www.w3.org/2000/09/xmldsig#";
+
+/// Settings for an IdP that trusts the mock service provider, whose NameIDs
+/// are in `name_id_format`
+pub fn saml_config(name_id_format: &str) -> SamlConfig {
+    let providers = std::env::temp_dir().join(format!(
+        "oauth2-saml-providers-{}.json",
+        uuid::Uuid::new_v4().simple()
+    ));
+    let file = serde_json::json!({
+        "service_providers": [{
+            "entity_id": MOCK_SP_ENTITY_ID,
+            "acs_urls": [MOCK_SP_ACS_URL],
+            "name_id_format": name_id_format,
+        }]
+    });
+    std::fs::write(&providers, file.to_string()).expect("temp dir is writable");
+
+    let support = concat!(env!("CARGO_MANIFEST_DIR"), "/src/test_support");
+    SamlConfig {
+        certificate: Some(format!("{}/mock_saml_cert.pem", support)),
+        private_key: Some(format!("{}/mock_idp_key.pem", support)),
+        service_providers: Some(providers.to_string_lossy().into_owned()),
+        ..SamlConfig::default()
+    }
+}
+
+/// The `SAMLRequest` parameter for an AuthnRequest from `issuer`
+pub fn authn_request(issuer: &str, acs_url: Option<&str>) -> String {
+    let acs = acs_url
+        .map(|url| format!(r#" AssertionConsumerServiceURL="{}""#, url))
+        .unwrap_or_default();
+    let xml = format!(
+        r#"<samlp:AuthnRequest xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" ID="_mock-request" Version="2.0" IssueInstant="2024-01-01T00:00:00Z"{}><saml:Issuer>{}</saml:Issuer></samlp:AuthnRequest>"#,
+        acs, issuer
+    );
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(xml.as_bytes()).expect("in memory");
+    general_purpose::STANDARD.encode(encoder.finish().expect("in memory"))
+}
+
+fn between<'a>(xml: &'a str, start: &str, end: &str) -> Result<&'a str, String> {
+    let from = xml.find(start).ok_or_else(|| format!("no {}", start))?;
+    let to = xml[from + start.len()..]
+        .find(end)
+        .ok_or_else(|| format!("no {}", end))?;
+    Ok(&xml[from..from + start.len() + to + end.len()])
+}
+
+fn inner<'a>(xml: &'a str, tag: &str) -> Result<&'a str, String> {
+    let element = between(xml, &format!("<{}", tag), &format!("</{}>", tag))?;
+    let start = element.find('>').ok_or("unterminated tag")? + 1;
+    Ok(&element[start..element.len() - tag.len() - 3])
+}
+
+/// Check the assertion in a Response against the mock key, returning the
+/// assertion. The IdP writes assertions already canonicalized, so this only
+/// has to cut out the signature rather than implement XML canonicalization.
+pub fn verify_assertion(response: &str) -> Result<String, String> {
+    let assertion = between(response, "<saml:Assertion ", "</saml:Assertion>")?;
+    let signature = between(assertion, "<ds:Signature ", "</ds:Signature>")?;
+    let unsigned = assertion.replacen(signature, "", 1);
+
+    let signed_info = between(signature, "<ds:SignedInfo>", "</ds:SignedInfo>")?;
+    let digest = general_purpose::STANDARD.encode(Sha256::digest(&unsigned));
+    if inner(signed_info, "ds:DigestValue")? != digest {
+        return Err("digest does not match the assertion".to_string());
+    }
+    let id = between(assertion, r#"ID=""#, r#"""#)?;
+    if !signed_info.contains(&format!(r##"URI="#{}"##, &id[4..id.len() - 1])) {
+        return Err("signature does not reference the assertion".to_string());
+    }
+
+    let canonical = signed_info.replacen(
+        "<ds:SignedInfo>",
+        &format!(r#"<ds:SignedInfo xmlns:ds="{}">"#, DSIG_NS),
+        1,
+    );
+    let value = general_purpose::STANDARD
+        .decode(inner(signature, "ds:SignatureValue")?)
+        .map_err(|e| e.to_string())?;
+    let key = DecodingKey::from_rsa_components(SIGNING_KEY_N, SIGNING_KEY_E)
+        .map_err(|e| e.to_string())?;
+    let valid = jsonwebtoken::crypto::verify(
+        &general_purpose::URL_SAFE_NO_PAD.encode(value),
+        canonical.as_bytes(),
+        &key,
+        Algorithm::RS256,
+    )
+    .map_err(|e| e.to_string())?;
+    if !valid {
+        return Err("signature does not verify".to_string());
+    }
+    Ok(unsigned)
+}
@@ -0,0 +1,14 @@
+//! Helpers for exercising the server in tests. Only compiled with the
+//! `test-support` feature.
+
+pub mod mock_idp;
+pub mod mock_ldap;
+pub mod mock_redis;
+pub mod mock_saml;
+
+pub use mock_idp::{sign_jwt, signing_jwks, MockIdp, MockUser};
+pub use mock_ldap::{MockLdap, MockLdapEntry};
+pub use mock_redis::MockRedis;
+pub use mock_saml::{
+    authn_request, saml_config, verify_assertion, MOCK_SP_ACS_URL, MOCK_SP_ENTITY_ID,
+};
@@ -105,6 +105,31 @@ async function updateActivityTable() {
     }
 }
 
+// Fetch and update the clients whose secret expires soon
+async function updateExpiringSecrets() {
+    const tbody = document.querySelector('#expiring-secrets-table tbody');
+    try {
+        const response = await fetch('/admin/api/clients/expiring-secrets');
+        const secrets = await response.json();
+
+        if (secrets && secrets.length > 0) {
+            tbody.innerHTML = secrets.map(secret => `
+                <tr>
+                    <td>${secret.client_id}</td>
+                    <td>${secret.name}</td>
+                    <td>${new Date(secret.secret_expires_at).toLocaleString()}</td>
+                    <td><span class="status-badge status-${secret.expired ? 'error' : 'pending'}">${secret.expired ? 'expired' : 'expiring'}</span></td>
+                </tr>
+            `).join('');
+        } else {
+            tbody.innerHTML = '<tr><td colspan="4">No secrets expiring soon</td></tr>';
+        }
+    } catch (error) {
+        console.error('Failed to fetch expiring secrets:', error);
+        tbody.innerHTML = '<tr><td colspan="4">Failed to load expiring secrets</td></tr>';
+    }
+}
+
 // Auto-refresh dashboard
 function startAutoRefresh() {
     // Refresh stats every 30 seconds
@@ -112,6 +137,9 @@ function startAutoRefresh() {
     
     // Refresh activity every 10 seconds
     setInterval(updateActivityTable, 10000);
+
+    // Refresh expiring secrets every 5 minutes
+    setInterval(updateExpiringSecrets, 300000);
 }
 
 // Initialize dashboard
@@ -119,5 +147,6 @@ document.addEventListener('DOMContentLoaded', () => {
     updateDashboardStats();
     initCharts();
     updateActivityTable();
+    updateExpiringSecrets();
     startAutoRefresh();
 });
@@ -0,0 +1,204 @@
+// Replicas sharing state through Redis, and events appended to a Redis stream.
+// Run with `cargo test --features test-support`.
+
+mod common;
+
+use common::TestServer;
+use rust_oauth2_server::cache::{Cache, RedisCache};
+use rust_oauth2_server::config::{CacheConfig, RedisEventConfig};
+use rust_oauth2_server::test_support::MockRedis;
+use serde_json::Value;
+use std::time::Duration;
+
+#[actix_web::test]
+async fn test_redis_cache_round_trip() {
+    let redis = MockRedis::start().await.unwrap();
+    let cache = RedisCache::new(&CacheConfig {
+        redis_url: Some(redis.url().to_string()),
+        ..CacheConfig::default()
+    })
+    .unwrap();
+    let ttl = Duration::from_secs(60);
+
+    assert_eq!(cache.get("missing").await.unwrap(), None);
+    cache.set("a", "1", ttl).await.unwrap();
+    assert!(!cache.insert("a", "2", ttl).await.unwrap());
+    assert!(cache.insert("b", "2", ttl).await.unwrap());
+    assert_eq!(cache.get("a").await.unwrap().as_deref(), Some("1"));
+    assert_eq!(cache.increment("n", ttl).await.unwrap(), 1);
+    assert_eq!(cache.increment("n", ttl).await.unwrap(), 2);
+    cache.delete("a").await.unwrap();
+    assert_eq!(cache.get("a").await.unwrap(), None);
+
+    let mut keys = redis.keys();
+    keys.sort();
+    assert_eq!(keys, ["oauth2:b", "oauth2:n"]);
+
+    cache
+        .set("short", "1", Duration::from_millis(20))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(cache.get("short").await.unwrap(), None);
+}
+
+#[actix_web::test]
+async fn test_revoking_on_one_replica_clears_the_shared_introspection_cache() {
+    let redis = MockRedis::start().await.unwrap();
+    let url = redis.url().to_string();
+    let a = TestServer::spawn_with_config(|config| {
+        config.introspection.cache_max_age = 300;
+        config.cache.redis_url = Some(url);
+    })
+    .await;
+    let b = a.spawn_replica().await;
+    let client_id = a.register_client().await;
+
+    let code = a.authorize(&client_id, None).await;
+    let token: Value = a
+        .exchange_code(&client_id, &code, None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let access_token = token["access_token"].as_str().unwrap();
+
+    assert_eq!(a.introspect(access_token).await["active"], true);
+    assert!(redis
+        .keys()
+        .iter()
+        .any(|key| key.starts_with("oauth2:introspection:")));
+    // Served from the cache A filled
+    assert_eq!(b.introspect(access_token).await["active"], true);
+
+    let resp = b
+        .http
+        .post(b.url("/oauth/revoke"))
+        .form(&[("token", access_token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(!redis
+        .keys()
+        .iter()
+        .any(|key| key.starts_with("oauth2:introspection:")));
+    assert_eq!(a.introspect(access_token).await["active"], false);
+
+    b.stop().await;
+    a.stop().await;
+}
+
+#[actix_web::test]
+async fn test_events_are_appended_to_the_configured_redis_stream() {
+    let redis = MockRedis::start().await.unwrap();
+    let url = redis.url().to_string();
+    let server = TestServer::spawn_with_config(|config| {
+        config.events.enabled = true;
+        config.events.redis = Some(RedisEventConfig {
+            url,
+            stream: "auth-events".to_string(),
+        });
+    })
+    .await;
+    let client_id = server.register_client().await;
+    let code = server.authorize(&client_id, None).await;
+    server.exchange_code(&client_id, &code, None).await;
+
+    let mut entries = Vec::new();
+    for _ in 0..100 {
+        entries = redis.stream("auth-events");
+        if entries.iter().any(|fields| fields[0].1 == "token_created") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let token_created = entries
+        .iter()
+        .find(|fields| fields[0].1 == "token_created")
+        .expect("token_created was appended");
+    assert_eq!(token_created[1].0, "event");
+    let event: Value = serde_json::from_str(&token_created[1].1).unwrap();
+    assert_eq!(event["client_id"], client_id.as_str());
+}
+
+#[actix_web::test]
+async fn test_event_backends_are_attached_and_detached_at_runtime() {
+    let redis = MockRedis::start().await.unwrap();
+    let server = TestServer::spawn_with_config(|config| config.events.enabled = true).await;
+    let plugins = server.url("/admin/api/events/plugins");
+    let backend = serde_json::json!({
+        "type": "redis",
+        "name": "incident",
+        "url": redis.url(),
+        "stream": "incident-events",
+    });
+    let tokens_created = || {
+        redis
+            .stream("incident-events")
+            .iter()
+            .filter(|fields| fields[0].1 == "token_created")
+            .count()
+    };
+
+    let resp = server
+        .http
+        .post(&plugins)
+        .json(&backend)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let status: Value = resp.json().await.unwrap();
+    assert_eq!(status["plugin"], "incident");
+    let resp = server
+        .http
+        .post(&plugins)
+        .json(&backend)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 409);
+    let mut bad_name = backend.clone();
+    bad_name["name"] = "../incident".into();
+    let resp = server
+        .http
+        .post(&plugins)
+        .json(&bad_name)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let listed: Value = server
+        .http
+        .get(&plugins)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+
+    let client_id = server.register_client().await;
+    let code = server.authorize(&client_id, None).await;
+    server.exchange_code(&client_id, &code, None).await;
+    for _ in 0..100 {
+        if tokens_created() == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(tokens_created(), 1);
+
+    let detach = format!("{}/incident", plugins);
+    let resp = server.http.delete(&detach).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = server.http.delete(&detach).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    let code = server.authorize(&client_id, None).await;
+    server.exchange_code(&client_id, &code, None).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(tokens_created(), 1);
+}
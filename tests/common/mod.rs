@@ -0,0 +1,413 @@
+// Shared harness for the end-to-end tests: run the real server on a random
+// port against a throwaway SQLite database
+#![allow(dead_code)]
+
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use actix_web::dev::ServerHandle;
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Utc;
+use rust_oauth2_server::clock::SharedClock;
+use rust_oauth2_server::config::Config;
+use rust_oauth2_server::server::ServerBuilder;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::path::PathBuf;
+
+pub const REDIRECT_URI: &str = "http://localhost:3000/callback";
+
+/// User signed in by `session_cookie`, who approves every authorization
+/// request the harness makes. This is the subject of their social login.
+pub const MOCK_USER_ID: &str = "e2e:user_123";
+
+/// CSRF token stored in the session `session_cookie` forges
+pub const SESSION_CSRF_TOKEN: &str = "e2e-csrf-token";
+
+pub struct TestServer {
+    pub base_url: String,
+    pub http: reqwest::Client,
+    pub pool: SqlitePool,
+    handle: ServerHandle,
+    config: Config,
+    session_key: Key,
+    clock: SharedClock,
+    /// Removed on stop; replicas leave the shared database alone
+    db_path: Option<PathBuf>,
+}
+
+impl TestServer {
+    pub async fn spawn() -> Self {
+        Self::spawn_with(|builder, _| builder).await
+    }
+
+    /// Spawn a server, letting the caller adjust the builder. The closure also
+    /// gets the server's base URL, which is known before it starts.
+    pub async fn spawn_with(configure: impl FnOnce(ServerBuilder, &str) -> ServerBuilder) -> Self {
+        Self::spawn_custom(|_| {}, configure).await
+    }
+
+    /// Spawn a server with adjusted configuration
+    pub async fn spawn_with_config(configure: impl FnOnce(&mut Config)) -> Self {
+        Self::spawn_custom(configure, |builder, _| builder).await
+    }
+
+    /// Spawn a server adjusting both the configuration and the builder
+    pub async fn spawn_custom(
+        configure_config: impl FnOnce(&mut Config),
+        configure: impl FnOnce(ServerBuilder, &str) -> ServerBuilder,
+    ) -> Self {
+        let db_path = std::env::temp_dir().join(format!("oauth2_e2e_{}.db", uuid::Uuid::new_v4()));
+        let database_url = format!("sqlite://{}?mode=rwc", db_path.display());
+
+        let pool = SqlitePool::connect(&database_url).await.unwrap();
+        apply_migrations(&pool).await;
+        sqlx::query(
+            "INSERT INTO users (id, username, password_hash, email, enabled, created_at, updated_at) \
+             VALUES (?, 'e2e', 'unused', 'e2e@example.com', 1, ?, ?)",
+        )
+        .bind(MOCK_USER_ID)
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let mut config = Config::default();
+        config.database.url = database_url;
+        config.jwt.secret = "e2e-test-secret-that-is-long-enough-for-hs256".to_string();
+        config.events.enabled = false;
+        configure_config(&mut config);
+
+        Self::start(config, pool, Some(db_path), Key::generate(), configure).await
+    }
+
+    /// Another server on the same database, as a second replica behind a
+    /// load balancer would be
+    pub async fn spawn_replica(&self) -> Self {
+        self.spawn_replica_with_config(|_| {}).await
+    }
+
+    /// A replica with adjusted configuration, for settings that name
+    /// records only known once the first server has made them
+    pub async fn spawn_replica_with_config(&self, configure: impl FnOnce(&mut Config)) -> Self {
+        let pool = SqlitePool::connect(&self.config.database.url)
+            .await
+            .unwrap();
+        let mut config = self.config.clone();
+        configure(&mut config);
+        Self::start(
+            config,
+            pool,
+            None,
+            self.session_key.clone(),
+            |builder, _| builder,
+        )
+        .await
+    }
+
+    async fn start(
+        config: Config,
+        pool: SqlitePool,
+        db_path: Option<PathBuf>,
+        session_key: Key,
+        configure: impl FnOnce(ServerBuilder, &str) -> ServerBuilder,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let builder = ServerBuilder::new(config.clone())
+            .listener(listener)
+            .session_key(session_key.clone());
+        let server = configure(builder, &base_url).build().await.unwrap();
+
+        let handle = server.handle();
+        let clock = server.clock();
+        actix_web::rt::spawn(server.run());
+
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        Self {
+            base_url,
+            http,
+            pool,
+            handle,
+            config,
+            session_key,
+            clock,
+            db_path,
+        }
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(false).await;
+        self.pool.close().await;
+        if let Some(db_path) = self.db_path {
+            // With the write-ahead log and its index
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = db_path.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    pub async fn register_client(&self) -> String {
+        self.register_client_with_scope("read write").await
+    }
+
+    /// Register a client allowed to ask for `scope`
+    pub async fn register_client_with_scope(&self, scope: &str) -> String {
+        let resp = self
+            .http
+            .post(self.url("/clients/register"))
+            .json(&serde_json::json!({
+                "client_name": "E2E Client",
+                "redirect_uris": [REDIRECT_URI],
+                "grant_types": [
+                    "authorization_code",
+                    "refresh_token",
+                    "client_credentials",
+                    "password"
+                ],
+                "scope": scope,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 201);
+
+        let body: Value = resp.json().await.unwrap();
+        body["client_id"].as_str().unwrap().to_string()
+    }
+
+    /// Create a client through the admin API and return its id and secret.
+    /// Only admins may give a client admin scopes.
+    pub async fn provision_client(
+        &self,
+        name: &str,
+        grant_types: &[&str],
+        scope: &str,
+    ) -> (String, String) {
+        let resp = self
+            .http
+            .put(self.url(&format!("/admin/api/clients/by-name/{}", name)))
+            .json(&serde_json::json!({
+                "redirect_uris": [REDIRECT_URI],
+                "grant_types": grant_types,
+                "scope": scope,
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 201);
+
+        let body: Value = resp.json().await.unwrap();
+        (
+            body["client_id"].as_str().unwrap().to_string(),
+            body["client_secret"].as_str().unwrap().to_string(),
+        )
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Secret the server signs its tokens with
+    pub fn jwt_secret(&self) -> &str {
+        &self.config.jwt.secret
+    }
+
+    /// The secret a client authenticates to the token endpoint with
+    pub async fn client_secret(&self, client_id: &str) -> String {
+        sqlx::query_scalar("SELECT client_secret FROM clients WHERE client_id = ?")
+            .bind(client_id)
+            .fetch_one(&self.pool)
+            .await
+            .unwrap()
+    }
+
+    /// `name=value` of a session cookie for `MOCK_USER_ID`, signed in just
+    /// now by the server's clock, as the session middleware would have
+    /// encrypted it
+    pub fn session_cookie(&self) -> String {
+        let (provider, provider_user_id) = MOCK_USER_ID.split_once(':').unwrap();
+        let user_info = serde_json::json!({
+            "provider": provider,
+            "provider_user_id": provider_user_id,
+            "email": "e2e@example.com",
+            "name": "E2E User",
+            "picture": null,
+        });
+        let now = self.clock.now().timestamp();
+        // Session values are stored JSON-encoded
+        let state: HashMap<&str, String> = HashMap::from([
+            ("authenticated", "true".to_string()),
+            ("user_info", Value::from(user_info.to_string()).to_string()),
+            ("session_started_at", now.to_string()),
+            ("session_last_seen", now.to_string()),
+            (
+                "form_csrf_token",
+                Value::from(SESSION_CSRF_TOKEN).to_string(),
+            ),
+        ]);
+
+        let name = self.config.session.cookie_name.clone();
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.session_key).add(Cookie::new(
+            name.clone(),
+            serde_json::to_string(&state).unwrap(),
+        ));
+        format!("{}={}", name, jar.get(&name).unwrap().value())
+    }
+
+    /// Run `/oauth/authorize` and return the code from the redirect
+    pub async fn authorize(&self, client_id: &str, code_challenge: Option<&str>) -> String {
+        self.authorize_scope(client_id, "read", code_challenge)
+            .await
+    }
+
+    /// Like `authorize`, asking for `scope`
+    pub async fn authorize_scope(
+        &self,
+        client_id: &str,
+        scope: &str,
+        code_challenge: Option<&str>,
+    ) -> String {
+        let mut query = vec![
+            ("response_type", "code"),
+            ("client_id", client_id),
+            ("redirect_uri", REDIRECT_URI),
+            ("scope", scope),
+            ("state", "e2e-state"),
+        ];
+        if let Some(challenge) = code_challenge {
+            query.push(("code_challenge", challenge));
+            query.push(("code_challenge_method", "S256"));
+        }
+
+        // Approve the request as the signed-in user
+        let query = serde_urlencoded::to_string(&query).unwrap();
+        let resp = self
+            .http
+            .post(self.url(&format!("/oauth/authorize?{}", query)))
+            .header("Cookie", self.session_cookie())
+            .form(&[("decision", "approve"), ("csrf_token", SESSION_CSRF_TOKEN)])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 302);
+
+        let location = resp.headers()["location"].to_str().unwrap();
+        let location = reqwest::Url::parse(location).unwrap();
+        assert!(location.as_str().starts_with(REDIRECT_URI));
+
+        let param = |name: &str| {
+            location
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        assert_eq!(param("state").as_deref(), Some("e2e-state"));
+        param("code").expect("redirect should carry a code")
+    }
+
+    pub async fn exchange_code(
+        &self,
+        client_id: &str,
+        code: &str,
+        code_verifier: Option<&str>,
+    ) -> reqwest::Response {
+        let client_secret = self.client_secret(client_id).await;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", REDIRECT_URI),
+            ("client_id", client_id),
+            ("client_secret", &client_secret),
+        ];
+        if let Some(verifier) = code_verifier {
+            form.push(("code_verifier", verifier));
+        }
+
+        self.http
+            .post(self.url("/oauth/token"))
+            .form(&form)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    pub async fn refresh(&self, client_id: &str, refresh_token: &str) -> reqwest::Response {
+        let client_secret = self.client_secret(client_id).await;
+        self.http
+            .post(self.url("/oauth/token"))
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+                ("client_id", client_id),
+                ("client_secret", &client_secret),
+            ])
+            .send()
+            .await
+            .unwrap()
+    }
+
+    pub async fn introspect(&self, token: &str) -> Value {
+        let resp = self
+            .http
+            .post(self.url("/oauth/introspect"))
+            .form(&[("token", token)])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        resp.json().await.unwrap()
+    }
+}
+
+/// Apply the Flyway schema migrations in version order. The seed-data
+/// migration targets the production database dialect and is skipped.
+pub async fn apply_migrations(pool: &SqlitePool) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("migrations/sql");
+    let mut migrations: Vec<(u32, PathBuf)> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| !path.to_string_lossy().contains("insert_default_data"))
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let version = name
+                .trim_start_matches('V')
+                .split("__")
+                .next()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or_else(|| panic!("unexpected migration name: {}", name));
+            (version, path)
+        })
+        .collect();
+    migrations.sort();
+
+    for (_, path) in migrations {
+        let sql = std::fs::read_to_string(&path).unwrap();
+        sqlx::raw_sql(&sql).execute(pool).await.unwrap();
+    }
+}
+
+pub fn pkce_pair() -> (String, String) {
+    let verifier = format!("{}{}", uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+    let challenge = general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    (verifier, challenge)
+}
+
+pub async fn error_code(resp: reqwest::Response) -> String {
+    assert_eq!(resp.status(), 400);
+    let body: Value = resp.json().await.unwrap();
+    body["error"].as_str().unwrap().to_string()
+}
@@ -0,0 +1,115 @@
+// Password grant sign-ins against an LDAP directory.
+// Run with `cargo test --features test-support`.
+
+mod common;
+
+use common::{error_code, TestServer};
+use rust_oauth2_server::test_support::{MockLdap, MockLdapEntry};
+use serde_json::Value;
+
+const ADMINS: &str = "cn=admins,ou=groups,dc=example,dc=com";
+const SUPPORT: &str = "cn=support,ou=groups,dc=example,dc=com";
+
+async fn password_grant(
+    server: &TestServer,
+    client_id: &str,
+    username: &str,
+    password: &str,
+) -> reqwest::Response {
+    let client_secret = server.client_secret(client_id).await;
+    server
+        .http
+        .post(server.url("/oauth/token"))
+        .basic_auth(client_id, Some(client_secret))
+        .form(&[
+            ("grant_type", "password"),
+            ("username", username),
+            ("password", password),
+            ("scope", "read write"),
+        ])
+        .send()
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn test_password_grant_signs_in_against_ldap_with_group_roles() {
+    let ldap = MockLdap::start(vec![
+        MockLdapEntry::person("alice", "alice-password", &[ADMINS]),
+        MockLdapEntry::person("bob", "bob-password", &[SUPPORT]),
+    ])
+    .await
+    .unwrap();
+
+    // Support staff only get to read
+    let rules_path =
+        std::env::temp_dir().join(format!("oauth2_policy_{}.json", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &rules_path,
+        r#"{ "rules": [ { "effect": "permit", "roles": ["support"], "restrict_scopes": ["read"] } ] }"#,
+    )
+    .unwrap();
+    let path = rules_path.to_str().unwrap().to_string();
+
+    let mut ldap_config = ldap.ldap_config();
+    ldap_config.group_roles = vec![
+        (ADMINS.to_string(), "admin".to_string()),
+        (SUPPORT.to_string(), "support".to_string()),
+    ];
+    let server = TestServer::spawn_with_config(|config| {
+        config.ldap = ldap_config;
+        config.policy.rules_file = Some(path);
+    })
+    .await;
+    let client_id = server.register_client().await;
+
+    let resp = password_grant(&server, &client_id, "alice", "alice-password").await;
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    assert_eq!(token["scope"], "read write");
+    let introspection = server
+        .introspect(token["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["sub"], "ldap:alice");
+
+    let resp = password_grant(&server, &client_id, "BOB", "bob-password").await;
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    assert_eq!(token["scope"], "read");
+    let introspection = server
+        .introspect(token["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["sub"], "ldap:bob");
+
+    for (username, password) in [
+        ("alice", "wrong"),
+        ("alice", ""),
+        ("mallory", "alice-password"),
+        ("*", "alice-password"),
+    ] {
+        let resp = password_grant(&server, &client_id, username, password).await;
+        assert_eq!(error_code(resp).await, "invalid_grant", "{}", username);
+    }
+
+    server.stop().await;
+    let _ = std::fs::remove_file(rules_path);
+}
+
+#[actix_web::test]
+async fn test_unreachable_directory_is_temporarily_unavailable() {
+    let ldap = MockLdap::start(Vec::new()).await.unwrap();
+    let mut ldap_config = ldap.ldap_config();
+    drop(ldap);
+    // Nothing answers once the mock is gone
+    ldap_config.timeout_ms = 500;
+    let server = TestServer::spawn_with_config(|config| config.ldap = ldap_config).await;
+    let client_id = server.register_client().await;
+
+    let resp = password_grant(&server, &client_id, "alice", "alice-password").await;
+    assert_eq!(resp.status(), 503);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "temporarily_unavailable");
+
+    server.stop().await;
+}
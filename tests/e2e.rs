@@ -0,0 +1,5190 @@
+// End-to-end tests: drive the OAuth2 endpoints of a real server over HTTP
+
+mod common;
+
+use chrono::{Duration, Utc};
+use common::{error_code, pkce_pair, TestServer, MOCK_USER_ID, REDIRECT_URI, SESSION_CSRF_TOKEN};
+use rust_oauth2_server::clock::ManualClock;
+use rust_oauth2_server::config::{CookieSameSite, DeploymentMode, SecurityProfile};
+use rust_oauth2_server::events::{AuthEvent, EventPlugin, EventSeverity, EventType};
+use rust_oauth2_server::middleware::token_fingerprint;
+use rust_oauth2_server::models::TokenFormat;
+use rust_oauth2_server::server::ServerBuilder;
+use rust_oauth2_server::telemetry::LogLevels;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::layer::SubscriberExt;
+
+#[actix_web::test]
+async fn test_authorization_code_flow_with_pkce() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let (verifier, challenge) = pkce_pair();
+
+    let code = server.authorize(&client_id, Some(&challenge)).await;
+    let resp = server
+        .exchange_code(&client_id, &code, Some(&verifier))
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let token: Value = resp.json().await.unwrap();
+    assert_eq!(token["token_type"], "Bearer");
+    assert!(token["refresh_token"].is_string());
+    let access_token = token["access_token"].as_str().unwrap();
+
+    let introspection = server.introspect(access_token).await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["client_id"], client_id.as_str());
+    assert_eq!(introspection["sub"], MOCK_USER_ID);
+    assert_eq!(introspection["scope"], "read");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_pkce_verifier_is_enforced() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let (_, challenge) = pkce_pair();
+
+    let code = server.authorize(&client_id, Some(&challenge)).await;
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    let code = server.authorize(&client_id, Some(&challenge)).await;
+    let resp = server
+        .exchange_code(&client_id, &code, Some("not-the-verifier"))
+        .await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_public_clients_must_use_pkce() {
+    let server = TestServer::spawn().await;
+    let register = |grant_types: Vec<&str>| {
+        server
+            .http
+            .post(server.url("/clients/register"))
+            .json(&json!({
+                "client_name": "SPA",
+                "redirect_uris": [REDIRECT_URI],
+                "grant_types": grant_types,
+                "scope": "read",
+                "token_endpoint_auth_method": "none",
+            }))
+            .send()
+    };
+    let resp = register(vec!["client_credentials"]).await.unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    let resp = register(vec!["authorization_code", "refresh_token"])
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let registered: Value = resp.json().await.unwrap();
+    assert_eq!(registered["token_endpoint_auth_method"], "none");
+    assert!(registered.get("client_secret").is_none());
+    let client_id = registered["client_id"].as_str().unwrap();
+
+    // Refused before the user is asked anything
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", client_id),
+        ("redirect_uri", REDIRECT_URI),
+        ("scope", "read"),
+    ])
+    .unwrap();
+    let resp = server
+        .http
+        .get(server.url(&format!("/oauth/authorize?{}", query)))
+        .header("Cookie", server.session_cookie())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    // The verifier stands in for a secret
+    let (verifier, challenge) = pkce_pair();
+    let code = server.authorize(client_id, Some(&challenge)).await;
+    let resp = server
+        .exchange_code(client_id, &code, Some(&verifier))
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "unauthorized_client");
+
+    // A confidential client still needs its secret
+    let confidential = server.register_client().await;
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", confidential.as_str()),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_pkce_can_be_required_of_every_client() {
+    let server = TestServer::spawn_with_config(|config| config.pkce.required = true).await;
+    let client_id = server.register_client().await;
+
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", client_id.as_str()),
+        ("redirect_uri", REDIRECT_URI),
+        ("scope", "read"),
+    ])
+    .unwrap();
+    let resp = server
+        .http
+        .post(server.url(&format!("/oauth/authorize?{}", query)))
+        .header("Cookie", server.session_cookie())
+        .form(&[("decision", "approve"), ("csrf_token", SESSION_CSRF_TOKEN)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    let (verifier, challenge) = pkce_pair();
+    let code = server.authorize(&client_id, Some(&challenge)).await;
+    let resp = server
+        .exchange_code(&client_id, &code, Some(&verifier))
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_only_the_code_response_type_is_accepted() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let query = serde_urlencoded::to_string([
+        ("response_type", "token"),
+        ("client_id", client_id.as_str()),
+        ("redirect_uri", REDIRECT_URI),
+        ("scope", "read"),
+    ])
+    .unwrap();
+    let resp = server
+        .http
+        .post(server.url(&format!("/oauth/authorize?{}", query)))
+        .header("Cookie", server.session_cookie())
+        .form(&[("decision", "approve"), ("csrf_token", SESSION_CSRF_TOKEN)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "unsupported_response_type");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_strict_profile_refuses_password_plain_pkce_and_public_clients() {
+    let server =
+        TestServer::spawn_with_config(|config| config.security_profile = SecurityProfile::Strict)
+            .await;
+
+    let discovery: Value = server
+        .http
+        .get(server.url("/.well-known/openid-configuration"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(discovery["security_profile"], "strict");
+    assert_eq!(
+        discovery["code_challenge_methods_supported"],
+        json!(["S256"])
+    );
+    assert_eq!(discovery["response_types_supported"], json!(["code"]));
+    assert!(!discovery["grant_types_supported"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("password")));
+    assert!(!discovery["token_endpoint_auth_methods_supported"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("none")));
+
+    let resp = server
+        .http
+        .post(server.url("/clients/register"))
+        .json(&json!({
+            "client_name": "Public",
+            "redirect_uris": [REDIRECT_URI],
+            "grant_types": ["authorization_code"],
+            "scope": "read",
+            "token_endpoint_auth_method": "none",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+    // Nor may clients sign up for the password grant
+    let resp = server
+        .http
+        .post(server.url("/clients/register"))
+        .json(&json!({
+            "client_name": "Legacy",
+            "redirect_uris": [REDIRECT_URI],
+            "grant_types": ["authorization_code", "password"],
+            "scope": "read",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    let resp = server
+        .http
+        .post(server.url("/clients/register"))
+        .json(&json!({
+            "client_name": "Strict",
+            "redirect_uris": [REDIRECT_URI],
+            "grant_types": ["authorization_code", "refresh_token"],
+            "scope": "read",
+        }))
+        .send()
+        .await
+        .unwrap();
+    let registered: Value = resp.json().await.unwrap();
+    let client_id = registered["client_id"].as_str().unwrap().to_string();
+    let secret = server.client_secret(&client_id).await;
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "password"),
+            ("username", "e2e"),
+            ("password", "unused"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", secret.as_str()),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "unsupported_grant_type");
+
+    // Every code needs an S256 challenge
+    for challenge in [None, Some("plain")] {
+        let mut query = vec![
+            ("response_type", "code"),
+            ("client_id", client_id.as_str()),
+            ("redirect_uri", REDIRECT_URI),
+            ("scope", "read"),
+        ];
+        if let Some(method) = challenge {
+            query.push((
+                "code_challenge",
+                "a-plain-challenge-of-sufficient-length-0123456789",
+            ));
+            query.push(("code_challenge_method", method));
+        }
+        let query = serde_urlencoded::to_string(&query).unwrap();
+        let resp = server
+            .http
+            .post(server.url(&format!("/oauth/authorize?{}", query)))
+            .header("Cookie", server.session_cookie())
+            .form(&[("decision", "approve"), ("csrf_token", SESSION_CSRF_TOKEN)])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(error_code(resp).await, "invalid_request");
+    }
+
+    let (verifier, challenge) = pkce_pair();
+    let code = server.authorize(&client_id, Some(&challenge)).await;
+    let resp = server
+        .exchange_code(&client_id, &code, Some(&verifier))
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_token_requests_are_bound_by_the_client_registration() {
+    let server = TestServer::spawn().await;
+    let resp = server
+        .http
+        .post(server.url("/clients/register"))
+        .json(&json!({
+            "client_name": "Web App",
+            "redirect_uris": [REDIRECT_URI],
+            "grant_types": ["authorization_code"],
+            "scope": "read",
+        }))
+        .send()
+        .await
+        .unwrap();
+    let registered: Value = resp.json().await.unwrap();
+    let web_app = registered["client_id"].as_str().unwrap();
+    let secret = registered["client_secret"].as_str().unwrap();
+
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", web_app),
+            ("client_secret", secret),
+            ("scope", "read"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "unauthorized_client");
+
+    let client_id = server.register_client().await;
+    let secret = server.client_secret(&client_id).await;
+    for scope in ["admin:write", "read openid"] {
+        let resp = server
+            .http
+            .post(server.url("/oauth/token"))
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", client_id.as_str()),
+                ("client_secret", secret.as_str()),
+                ("scope", scope),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(error_code(resp).await, "invalid_scope");
+    }
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "password"),
+            ("username", "e2e"),
+            ("password", "unused"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", secret.as_str()),
+            ("scope", "read admin"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_scope");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_authorization_code_cannot_be_reused() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let code = server.authorize(&client_id, None).await;
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(resp.status(), 200);
+
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_replaying_a_code_revokes_the_tokens_issued_from_it() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let code = server.authorize(&client_id, None).await;
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    let access_token = token["access_token"].as_str().unwrap().to_string();
+    let resp = server.refresh(&client_id, &refresh_token(&token)).await;
+    assert_eq!(resp.status(), 200);
+    let rotated: Value = resp.json().await.unwrap();
+
+    // Tokens from another code are left alone
+    let other: Value = {
+        let code = server.authorize(&client_id, None).await;
+        let resp = server.exchange_code(&client_id, &code, None).await;
+        resp.json().await.unwrap()
+    };
+
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    assert_eq!(server.introspect(&access_token).await["active"], false);
+    assert_eq!(
+        server
+            .introspect(rotated["access_token"].as_str().unwrap())
+            .await["active"],
+        false
+    );
+    let resp = server.refresh(&client_id, &refresh_token(&rotated)).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+    assert_eq!(
+        server
+            .introspect(other["access_token"].as_str().unwrap())
+            .await["active"],
+        true
+    );
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_replicas_racing_on_a_code_issue_one_token() {
+    let server = TestServer::spawn().await;
+    let replica = server.spawn_replica().await;
+    let client_id = server.register_client().await;
+    let (verifier, challenge) = pkce_pair();
+
+    for _ in 0..5 {
+        // Issued by one replica, redeemed at both at once
+        let code = server.authorize(&client_id, Some(&challenge)).await;
+        let (first, second) = tokio::join!(
+            server.exchange_code(&client_id, &code, Some(&verifier)),
+            replica.exchange_code(&client_id, &code, Some(&verifier)),
+        );
+        let mut statuses = [first.status().as_u16(), second.status().as_u16()];
+        statuses.sort();
+        assert_eq!(statuses, [200, 400]);
+        let rejected = if first.status() == 200 { second } else { first };
+        assert_eq!(error_code(rejected).await, "invalid_grant");
+    }
+
+    let issued: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tokens WHERE client_id = ?")
+        .bind(&client_id)
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(issued, 5);
+
+    replica.stop().await;
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_concurrent_token_writes_wait_their_turn() {
+    let server = TestServer::spawn().await;
+    let replica = server.spawn_replica().await;
+    let client_id = server.register_client().await;
+
+    // Both replicas writing codes, tokens and usage at once
+    let flows = (0..20).map(|i| {
+        let server = if i % 2 == 0 { &server } else { &replica };
+        let client_id = client_id.as_str();
+        async move {
+            let token = issue_tokens(server, client_id).await;
+            let resp = server.refresh(client_id, &refresh_token(&token)).await;
+            assert_eq!(resp.status(), 200);
+        }
+    });
+    futures::future::join_all(flows).await;
+
+    let issued: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tokens WHERE client_id = ?")
+        .bind(&client_id)
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(issued, 40);
+    let (journal_mode,): (String,) = sqlx::query_as("PRAGMA journal_mode")
+        .fetch_one(&replica.pool)
+        .await
+        .unwrap();
+    assert_eq!(journal_mode, "wal");
+
+    replica.stop().await;
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_revocations_reach_other_replicas_caches() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.introspection.cache_max_age = 300;
+        config.cache.invalidation_poll_ms = 50;
+    })
+    .await;
+    let replica = server.spawn_replica().await;
+    let client_id = server.register_client().await;
+    let token = issue_tokens(&server, &client_id).await;
+    let access_token = token["access_token"].as_str().unwrap();
+    let eventually_inactive = |token: String| {
+        let replica = &replica;
+        async move {
+            let mut active = Value::Null;
+            for _ in 0..50 {
+                active = replica.introspect(&token).await["active"].clone();
+                if active == false {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            active == false
+        }
+    };
+
+    // Cached in the replica's own memory
+    assert_eq!(replica.introspect(access_token).await["active"], true);
+
+    let resp = server
+        .http
+        .post(server.url("/oauth/revoke"))
+        .form(&[("token", access_token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // The replica drops its copy once it polls
+    assert!(eventually_inactive(access_token.to_string()).await);
+
+    // So it does for tokens revoked in bulk, here by replaying their code
+    let code = server.authorize(&client_id, None).await;
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    let token: Value = resp.json().await.unwrap();
+    let access_token = token["access_token"].as_str().unwrap().to_string();
+    assert_eq!(replica.introspect(&access_token).await["active"], true);
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+    assert!(eventually_inactive(access_token).await);
+
+    replica.stop().await;
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_stateless_validation_checks_the_jti_denylist() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.jwt.stateless_validation = true;
+        config.jwt.denylist_cache_secs = 300;
+    })
+    .await;
+    let client_id = server.register_client().await;
+    let revoked = issue_tokens(&server, &client_id).await;
+    let revoked_token = revoked["access_token"].as_str().unwrap();
+    let kept = issue_tokens(&server, &client_id).await;
+    let kept_token = kept["access_token"].as_str().unwrap();
+
+    assert_eq!(server.introspect(revoked_token).await["active"], true);
+    let resp = server
+        .http
+        .post(server.url("/oauth/revoke"))
+        .form(&[("token", revoked_token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    // The cached answer went with the revocation
+    assert_eq!(server.introspect(revoked_token).await["active"], false);
+    let denied: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM revoked_jtis")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(denied, 1);
+
+    // Accepted on its claims, without the stored row
+    sqlx::query("DELETE FROM tokens WHERE access_token = ?")
+        .bind(kept_token)
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    let introspection = server.introspect(kept_token).await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["client_id"], client_id);
+    assert_eq!(introspection["sub"], MOCK_USER_ID);
+
+    // Refresh tokens carry the same claims but are not access tokens
+    assert_eq!(
+        server.introspect(&refresh_token(&kept)).await["active"],
+        false
+    );
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_es256_tokens_verify_against_the_jwks() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.jwt.algorithm = jsonwebtoken::Algorithm::ES256;
+        config.jwt.stateless_validation = true;
+    })
+    .await;
+    let client_id = server.register_client().await;
+    let tokens = issue_tokens(&server, &client_id).await;
+    let access_token = tokens["access_token"].as_str().unwrap();
+
+    let jwks: jsonwebtoken::jwk::JwkSet = server
+        .http
+        .get(server.url("/.well-known/jwks.json"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(jwks.keys.len(), 1);
+
+    // A resource server needs nothing but the published key
+    let header = jsonwebtoken::decode_header(access_token).unwrap();
+    assert_eq!(header.alg, jsonwebtoken::Algorithm::ES256);
+    let jwk = jwks.find(header.kid.as_deref().unwrap()).unwrap();
+    let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256);
+    validation.validate_aud = false;
+    let claims = jsonwebtoken::decode::<Value>(
+        access_token,
+        &jsonwebtoken::DecodingKey::from_jwk(jwk).unwrap(),
+        &validation,
+    )
+    .unwrap()
+    .claims;
+    assert_eq!(claims["sub"], MOCK_USER_ID);
+    assert_eq!(server.introspect(access_token).await["active"], true);
+
+    let discovery: Value = server
+        .http
+        .get(server.url("/.well-known/openid-configuration"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        discovery["id_token_signing_alg_values_supported"],
+        json!(["ES256"])
+    );
+
+    // Nor is one signed with the shared secret, even on its claims alone
+    let forged = rust_oauth2_server::models::Claims::new(
+        MOCK_USER_ID.to_string(),
+        client_id.clone(),
+        "read".to_string(),
+        3600,
+        &rust_oauth2_server::clock::SystemClock,
+    )
+    .encode_access_token(&rust_oauth2_server::keys::SigningKeys::hmac(
+        server.jwt_secret(),
+    ))
+    .unwrap();
+    assert_eq!(server.introspect(&forged).await["active"], false);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_errors_link_to_their_reference_page() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let resp = server.exchange_code(&client_id, "not-a-code", None).await;
+    assert_eq!(resp.status(), 400);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_grant");
+    assert_eq!(body["error_uri"], "/errors/invalid_grant");
+
+    let resp = server
+        .http
+        .get(server.url("/errors/invalid_grant"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(resp.text().await.unwrap().contains("invalid_grant"));
+
+    let resp = server
+        .http
+        .get(server.url("/errors/made_up"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_rate_limits_warn_before_refusing() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.rate_limit.enabled = true;
+        // Long enough that the test never straddles two windows
+        config.rate_limit.window_secs = 3600;
+        config.rate_limit.token_limit = 4;
+        config.rate_limit.soft_limit_percent = 50;
+    })
+    .await;
+    let client_id = server.register_client().await;
+    let other_client_id = server.register_client().await;
+    let remaining = |resp: &reqwest::Response| {
+        resp.headers()
+            .get("x-ratelimit-remaining")
+            .map(|value| value.to_str().unwrap().to_string())
+    };
+
+    let resp = server.exchange_code(&client_id, "not-a-code", None).await;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(remaining(&resp), None);
+    for left in ["2", "1", "0"] {
+        let resp = server.exchange_code(&client_id, "not-a-code", None).await;
+        assert_eq!(resp.status(), 400);
+        assert_eq!(remaining(&resp).as_deref(), Some(left));
+        assert_eq!(resp.headers()["x-ratelimit-limit"], "4");
+    }
+
+    let resp = server.exchange_code(&client_id, "not-a-code", None).await;
+    assert_eq!(resp.status(), 429);
+    assert!(resp.headers().contains_key("retry-after"));
+    assert!(resp.headers().contains_key("x-ratelimit-reset"));
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "rate_limit_exceeded");
+
+    // Counted per client
+    let resp = server
+        .exchange_code(&other_client_id, "not-a-code", None)
+        .await;
+    assert_eq!(resp.status(), 400);
+    assert_eq!(remaining(&resp), None);
+
+    // Probes are never limited
+    let resp = server.http.get(server.url("/health")).send().await.unwrap();
+    assert!(!resp.headers().contains_key("x-ratelimit-limit"));
+
+    server.stop().await;
+}
+
+/// The session cookie a response sets, or `cookie` if it sets none
+fn next_cookie(resp: &reqwest::Response, cookie: &str) -> String {
+    resp.headers()
+        .get_all("set-cookie")
+        .iter()
+        .map(|value| value.to_str().unwrap().split(';').next().unwrap())
+        .find(|pair| pair.starts_with("id="))
+        .map_or_else(|| cookie.to_string(), str::to_string)
+}
+
+#[actix_web::test]
+async fn test_demo_mode_runs_the_playground_flow() {
+    let server = TestServer::spawn_with_config(|config| config.mode = DeploymentMode::Demo).await;
+
+    let resp = server.http.get(server.url("/demo")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(resp.text().await.unwrap().contains("demo-web-secret"));
+
+    // Sign in as the demo user, then run the flow the playground starts
+    let resp = server
+        .http
+        .post(server.url("/demo/login"))
+        .header("Cookie", server.session_cookie())
+        .form(&[("csrf_token", SESSION_CSRF_TOKEN)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 302);
+    assert_eq!(resp.headers()["location"], "/demo");
+    let mut cookie = next_cookie(&resp, "");
+
+    let mut location = "/demo/start".to_string();
+    for _ in 0..2 {
+        let resp = server
+            .http
+            .get(server.url(&location))
+            .header("Cookie", &cookie)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 302);
+        cookie = next_cookie(&resp, &cookie);
+        location = resp.headers()["location"].to_str().unwrap().to_string();
+    }
+    // Consent was approved without asking
+    let callback = location
+        .strip_prefix(&server.base_url)
+        .unwrap_or_else(|| panic!("the code comes back to the playground, not {}", location));
+    assert!(callback.starts_with("/demo/callback?code="));
+
+    let resp = server
+        .http
+        .get(server.url(callback))
+        .header("Cookie", &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let page = resp.text().await.unwrap();
+    // As the page's script reads them, unescaped
+    let attribute = |name: &str| {
+        let start = page.find(&format!("{}=\"", name)).unwrap() + name.len() + 2;
+        page[start..start + page[start..].find('"').unwrap()].replace("&#x2F;", "/")
+    };
+
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", &attribute("data-code")),
+            ("code_verifier", &attribute("data-code-verifier")),
+            ("redirect_uri", &attribute("data-redirect-uri")),
+            ("client_id", "demo-web"),
+            ("client_secret", "demo-web-secret"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let tokens: Value = resp.json().await.unwrap();
+    let introspection = server
+        .introspect(tokens["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["sub"], "demo:demo");
+
+    // The seeded password works too, for the same user
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "password"),
+            ("username", "demo"),
+            ("password", "demo-password"),
+            ("client_id", "demo-web"),
+            ("client_secret", "demo-web-secret"),
+            ("scope", "read"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let tokens: Value = resp.json().await.unwrap();
+    let introspection = server
+        .introspect(tokens["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["sub"], "demo:demo");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_demo_mode_refuses_a_production_database() {
+    let server = TestServer::spawn().await;
+    let resp = server.http.get(server.url("/demo")).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    let mut config = server.config().clone();
+    config.mode = DeploymentMode::Demo;
+    let result = ServerBuilder::new(config)
+        .listener(std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+        .build()
+        .await;
+    match result {
+        Ok(_) => panic!("demo mode started on a production database"),
+        Err(e) => assert!(e.to_string().contains("production database")),
+    }
+    let client: Option<String> =
+        sqlx::query_scalar("SELECT client_id FROM clients WHERE client_id = 'demo-web'")
+            .fetch_optional(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(client, None);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_startup_diagnostics_refuse_an_old_schema() {
+    let server = TestServer::spawn().await;
+    let diagnostics = || async {
+        let report: Value = server
+            .http
+            .get(server.url("/admin/api/diagnostics"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        report
+    };
+    let status = |report: &Value, name: &str| {
+        report["checks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|check| check["name"] == name)
+            .map(|check| check["status"].as_str().unwrap().to_string())
+            .unwrap()
+    };
+
+    // The test schema is applied without Flyway
+    let report = diagnostics().await;
+    assert_eq!(report["mode"], "production");
+    assert_eq!(status(&report, "database"), "ok");
+    assert_eq!(status(&report, "signing_keys"), "ok");
+    assert_eq!(status(&report, "event_backends"), "ok");
+    assert_eq!(status(&report, "clock"), "ok");
+    assert_eq!(status(&report, "schema"), "warning");
+    assert_eq!(report["status"], "warning");
+
+    sqlx::raw_sql(
+        "CREATE TABLE flyway_schema_history (version TEXT, success INTEGER);
+         INSERT INTO flyway_schema_history VALUES ('1', 1), ('37', 1), ('38', 0);",
+    )
+    .execute(&server.pool)
+    .await
+    .unwrap();
+    let report = diagnostics().await;
+    assert_eq!(status(&report, "schema"), "critical");
+    assert_eq!(report["status"], "critical");
+
+    let result = ServerBuilder::new(server.config().clone())
+        .listener(std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+        .build()
+        .await;
+    match result {
+        Ok(_) => panic!("started on a schema behind the build"),
+        Err(e) => assert!(e.to_string().contains("Schema is at V37"), "{}", e),
+    }
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_event_backends_cannot_be_attached_with_events_disabled() {
+    let server = TestServer::spawn().await;
+    let resp = server
+        .http
+        .post(server.url("/admin/api/events/plugins"))
+        .json(&json!({
+            "type": "webhook",
+            "urls": ["http://127.0.0.1:9/hook"],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 503);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_database_errors_do_not_reach_responses() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let code = server.authorize(&client_id, None).await;
+
+    sqlx::query("DROP TABLE tokens")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(resp.status(), 500);
+    let body = resp.text().await.unwrap();
+    assert!(!body.contains("no such table"), "{}", body);
+    assert!(!body.contains("tokens"), "{}", body);
+
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error"], "server_error");
+    assert!(body["error_description"]
+        .as_str()
+        .unwrap()
+        .contains("(reference "));
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_authorize_checks_the_client_before_redirecting() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let authorize = |client_id: &str, redirect_uri: &str, scope: &str| {
+        server
+            .http
+            .get(server.url("/oauth/authorize"))
+            .query(&[
+                ("response_type", "code"),
+                ("client_id", client_id),
+                ("redirect_uri", redirect_uri),
+                ("scope", scope),
+                ("state", "xyz"),
+            ])
+            .header("Cookie", server.session_cookie())
+            .send()
+    };
+
+    // Answered here rather than sent to a redirect URI nobody registered
+    let resp = authorize("no-such-client", REDIRECT_URI, "read")
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+    assert!(resp.headers().get("location").is_none());
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_client");
+
+    let resp = authorize(&client_id, "https://evil.example.com/callback", "read")
+        .await
+        .unwrap();
+    assert!(resp.headers().get("location").is_none());
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_request");
+    assert_eq!(
+        body["error_description"],
+        "redirect_uri is not registered for this client"
+    );
+
+    // The client was registered for `read write`
+    let resp = authorize(&client_id, REDIRECT_URI, "read admin")
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_scope");
+
+    let resp = authorize(&client_id, REDIRECT_URI, "read write")
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_authorization_responses_carry_session_state() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", client_id.as_str()),
+        ("redirect_uri", REDIRECT_URI),
+        ("state", "xyz"),
+    ])
+    .unwrap();
+    let resp = server
+        .http
+        .post(server.url(&format!("/oauth/authorize?{}", query)))
+        .header("Cookie", server.session_cookie())
+        .form(&[("decision", "approve"), ("csrf_token", SESSION_CSRF_TOKEN)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 302);
+
+    // The browser state the check session iframe will read
+    let browser_state = resp
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|header| header.to_str().ok()?.split(';').next())
+        .find_map(|pair| pair.strip_prefix("op_browser_state="))
+        .expect("browser state cookie")
+        .to_string();
+
+    let location = reqwest::Url::parse(resp.headers()["location"].to_str().unwrap()).unwrap();
+    let session_state = location
+        .query_pairs()
+        .find(|(name, _)| name == "session_state")
+        .map(|(_, value)| value.into_owned())
+        .expect("session_state");
+    let (hash, salt) = session_state.split_once('.').unwrap();
+    let input = format!(
+        "{} http://localhost:3000 {} {}",
+        client_id, browser_state, salt
+    );
+    assert_eq!(hash, hex::encode(Sha256::digest(input.as_bytes())));
+
+    let discovery: Value = server
+        .http
+        .get(server.url("/.well-known/openid-configuration"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(
+        discovery["check_session_iframe"],
+        "http://localhost:8080/oauth/check_session"
+    );
+
+    let resp = server
+        .http
+        .get(server.url("/oauth/check_session"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let html = resp.text().await.unwrap();
+    assert!(html.contains("var cookieName = \"op_browser_state\";"));
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_userinfo_returns_the_claims_the_scope_allows() {
+    let server = TestServer::spawn().await;
+    let client_id = server
+        .register_client_with_scope("openid profile email read")
+        .await;
+    sqlx::query(
+        "INSERT INTO upstream_tokens (subject, provider, access_token, scope, created_at, updated_at, name, picture) \
+         VALUES (?, 'google', 'encrypted', 'profile', ?, ?, 'E2E User', 'https://example.com/e2e.png')",
+    )
+    .bind(MOCK_USER_ID)
+    .bind(Utc::now())
+    .bind(Utc::now())
+    .execute(&server.pool)
+    .await
+    .unwrap();
+
+    let access_token = |scope: &'static str| {
+        let server = &server;
+        let client_id = &client_id;
+        async move {
+            let code = server.authorize_scope(client_id, scope, None).await;
+            let resp = server.exchange_code(client_id, &code, None).await;
+            assert_eq!(resp.status(), 200);
+            let body: Value = resp.json().await.unwrap();
+            body["access_token"].as_str().unwrap().to_string()
+        }
+    };
+    let userinfo = |token: String| {
+        server
+            .http
+            .get(server.url("/oauth/userinfo"))
+            .bearer_auth(token)
+            .send()
+    };
+
+    let resp = userinfo(access_token("openid profile email").await)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(
+        body,
+        json!({
+            "sub": MOCK_USER_ID,
+            "name": "E2E User",
+            "preferred_username": "e2e",
+            "picture": "https://example.com/e2e.png",
+            "email": "e2e@example.com",
+        })
+    );
+
+    // Also over POST, and only the subject without profile or email
+    let resp = server
+        .http
+        .post(server.url("/oauth/userinfo"))
+        .bearer_auth(access_token("openid").await)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.json::<Value>().await.unwrap(),
+        json!({"sub": MOCK_USER_ID})
+    );
+
+    let resp = userinfo(access_token("read").await).await.unwrap();
+    assert_eq!(resp.status(), 403);
+    assert_eq!(
+        resp.headers()["www-authenticate"],
+        r#"Bearer error="insufficient_scope", scope="openid""#
+    );
+
+    let resp = userinfo("not-a-token".to_string()).await.unwrap();
+    assert_eq!(resp.status(), 401);
+    assert_eq!(
+        resp.headers()["www-authenticate"],
+        r#"Bearer error="invalid_token""#
+    );
+    let resp = server
+        .http
+        .get(server.url("/oauth/userinfo"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers()["www-authenticate"], "Bearer");
+
+    let discovery: Value = server
+        .http
+        .get(server.url("/.well-known/openid-configuration"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(discovery["userinfo_endpoint"]
+        .as_str()
+        .unwrap()
+        .ends_with("/oauth/userinfo"));
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_openid_scope_issues_an_id_token() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client_with_scope("openid read").await;
+
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", client_id.as_str()),
+        ("redirect_uri", REDIRECT_URI),
+        ("scope", "openid read"),
+        ("nonce", "n-0S6_WzA2Mj"),
+    ])
+    .unwrap();
+    let resp = server
+        .http
+        .post(server.url(&format!("/oauth/authorize?{}", query)))
+        .header("Cookie", server.session_cookie())
+        .form(&[("decision", "approve"), ("csrf_token", SESSION_CSRF_TOKEN)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 302);
+    let location = reqwest::Url::parse(resp.headers()["location"].to_str().unwrap()).unwrap();
+    let code = location
+        .query_pairs()
+        .find(|(name, _)| name == "code")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    let id_token = token["id_token"].as_str().expect("id_token");
+
+    let mut validation = jsonwebtoken::Validation::default();
+    validation.set_audience(&[client_id.as_str()]);
+    let claims = jsonwebtoken::decode::<Value>(
+        id_token,
+        &jsonwebtoken::DecodingKey::from_secret(server.jwt_secret().as_bytes()),
+        &validation,
+    )
+    .unwrap()
+    .claims;
+    assert_eq!(claims["iss"], "http://localhost:8080");
+    assert_eq!(claims["sub"], MOCK_USER_ID);
+    assert_eq!(claims["nonce"], "n-0S6_WzA2Mj");
+    let iat = claims["iat"].as_i64().unwrap();
+    assert_eq!(
+        claims["exp"].as_i64().unwrap() - iat,
+        token["expires_in"].as_i64().unwrap()
+    );
+    assert!(claims["auth_time"].as_i64().unwrap() <= iat);
+    // Not an access token
+    assert!(claims.get("scope").is_none());
+    assert_eq!(server.introspect(id_token).await["active"], false);
+
+    // Without `openid` there is no ID token
+    let code = server.authorize(&client_id, None).await;
+    let token: Value = server
+        .exchange_code(&client_id, &code, None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert!(token.get("id_token").is_none());
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_prompt_none_answers_without_showing_a_page() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let silent = |scope: &str, response_mode: Option<&str>, signed_in: bool| {
+        let mut query = vec![
+            ("response_type", "code"),
+            ("client_id", client_id.as_str()),
+            ("redirect_uri", REDIRECT_URI),
+            ("scope", scope),
+            ("state", "xyz"),
+            ("prompt", "none"),
+        ];
+        if let Some(response_mode) = response_mode {
+            query.push(("response_mode", response_mode));
+        }
+        let request = server
+            .http
+            .get(server.url("/oauth/authorize"))
+            .query(&query);
+        let request = if signed_in {
+            request.header("Cookie", server.session_cookie())
+        } else {
+            request
+        };
+        request.send()
+    };
+    let redirect_params = |resp: &reqwest::Response| -> HashMap<String, String> {
+        assert_eq!(resp.status(), 302);
+        let location = resp.headers()["location"].to_str().unwrap();
+        assert!(location.starts_with(REDIRECT_URI));
+        reqwest::Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    };
+
+    let resp = silent("read", None, false).await.unwrap();
+    let params = redirect_params(&resp);
+    assert_eq!(params["error"], "login_required");
+    assert_eq!(params["state"], "xyz");
+
+    // Signed in, but the user has never approved the client
+    let resp = silent("read", None, true).await.unwrap();
+    assert_eq!(redirect_params(&resp)["error"], "consent_required");
+
+    // Once approved on the consent page, the same request goes through
+    server.authorize(&client_id, None).await;
+    let resp = silent("read", None, true).await.unwrap();
+    let params = redirect_params(&resp);
+    assert_eq!(params["state"], "xyz");
+    let resp = server
+        .exchange_code(&client_id, &params["code"], None)
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    // Nothing beyond what was approved
+    let resp = silent("read write", None, true).await.unwrap();
+    assert_eq!(redirect_params(&resp)["error"], "consent_required");
+
+    // Posted to the client's page for iframe-based renewal
+    let resp = silent("read", Some("web_message"), true).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let html = resp.text().await.unwrap();
+    assert!(html.contains("data-origin=\"http:&#x2F;&#x2F;localhost:3000\""));
+    assert!(html.contains("&quot;code&quot;:&quot;"));
+    assert!(html.contains("&quot;state&quot;:&quot;xyz&quot;"));
+    let resp = silent("read", Some("web_message"), false).await.unwrap();
+    let html = resp.text().await.unwrap();
+    assert!(html.contains("&quot;error&quot;:&quot;login_required&quot;"));
+
+    let resp = silent("read", Some("fragment"), true).await.unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_oversized_and_malformed_parameters_are_rejected() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let long_state = "s".repeat(513);
+    let resp = server
+        .http
+        .get(server.url("/oauth/authorize"))
+        .query(&[
+            ("response_type", "code"),
+            ("client_id", client_id.as_str()),
+            ("redirect_uri", REDIRECT_URI),
+            ("state", long_state.as_str()),
+        ])
+        .send()
+        .await
+        .unwrap();
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_request");
+    assert_eq!(body["error_description"], "state is longer than 512 bytes");
+
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id.as_str()),
+            ("scope", "read\"write"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    // Bodies over the limit are refused before they are parsed
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "password"),
+            ("password", &"p".repeat(70_000)),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 413);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_undecodable_bodies_get_oauth_errors() {
+    let server = TestServer::spawn().await;
+
+    let register = |body: &'static str| {
+        server
+            .http
+            .post(server.url("/clients/register"))
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+    };
+    let resp = register("{\"client_name\": ").await.unwrap();
+    assert_eq!(resp.status(), 400);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_request");
+    assert!(body["error_description"]
+        .as_str()
+        .unwrap()
+        .starts_with("Request body is not valid JSON"));
+
+    let resp = register(r#"{"client_name": "App", "redirect_uris": [], "grant_types": []}"#)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let body: Value = resp.json().await.unwrap();
+    assert!(body["error_description"]
+        .as_str()
+        .unwrap()
+        .contains("missing field `scope`"));
+
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[("client_id", "app")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(
+        body["error_description"],
+        "Invalid form body: missing field `grant_type`"
+    );
+
+    // Statuses other than 400 are kept
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .json(&serde_json::json!({"grant_type": "client_credentials"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 415);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_request");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_authorization_code_bound_to_client_and_redirect_uri() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let other_client_id = server.register_client().await;
+
+    let code = server.authorize(&client_id, None).await;
+    let resp = server.exchange_code(&other_client_id, &code, None).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    let code = server.authorize(&client_id, None).await;
+    let client_secret = server.client_secret(&client_id).await;
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", "http://localhost:3000/other"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_token_endpoint_authenticates_the_client() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let client_secret = server.client_secret(&client_id).await;
+    let other_client_id = server.register_client().await;
+
+    let exchange = |code: String, credentials: Vec<(&'static str, String)>| {
+        let server = &server;
+        let mut form = vec![
+            ("grant_type", "authorization_code".to_string()),
+            ("code", code),
+            ("redirect_uri", REDIRECT_URI.to_string()),
+        ];
+        form.extend(credentials);
+        server.http.post(server.url("/oauth/token")).form(&form)
+    };
+    let assert_challenged = |resp: &reqwest::Response| {
+        assert_eq!(resp.status(), 401);
+        assert_eq!(resp.headers()["www-authenticate"], "Basic realm=\"oauth2\"");
+    };
+
+    // A client_id alone, or with the wrong secret, is not enough
+    let code = server.authorize(&client_id, None).await;
+    let resp = exchange(code.clone(), vec![("client_id", client_id.clone())])
+        .send()
+        .await
+        .unwrap();
+    assert_challenged(&resp);
+    let resp = exchange(
+        code.clone(),
+        vec![
+            ("client_id", client_id.clone()),
+            ("client_secret", "wrong".to_string()),
+        ],
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_challenged(&resp);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_client");
+
+    // Another client's credentials in the header
+    let resp = exchange(code.clone(), vec![("client_id", client_id.clone())])
+        .basic_auth(&other_client_id, Some(client_secret.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_challenged(&resp);
+
+    // One authentication method per request
+    let resp = exchange(code.clone(), vec![("client_secret", client_secret.clone())])
+        .basic_auth(&client_id, Some(client_secret.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    // HTTP Basic, with client_id taken from the header; the refused attempts
+    // above left the code unspent
+    let resp = exchange(code, Vec::new())
+        .basic_auth(&client_id, Some(client_secret.clone()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+
+    // client_secret_post works as well
+    let resp = server.refresh(&client_id, &refresh_token(&token)).await;
+    assert_eq!(resp.status(), 200);
+
+    // Client credentials no longer accept any secret
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", "secret"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_challenged(&resp);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_redirect_uri_patterns_are_opt_in_and_strict() {
+    let server = TestServer::spawn().await;
+    let register = |redirect_uris: Vec<&str>, patterns: Vec<&str>| {
+        server
+            .http
+            .post(server.url("/clients/register"))
+            .json(&serde_json::json!({
+                "client_name": "Previews",
+                "redirect_uris": redirect_uris,
+                "redirect_uri_patterns": patterns,
+                "grant_types": ["authorization_code"],
+                "scope": "read",
+            }))
+            .send()
+    };
+
+    // A wildcard among the exact URIs is refused rather than matched literally
+    let resp = register(vec!["https://*.preview.example.com/callback"], vec![])
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    let resp = register(vec![REDIRECT_URI], vec!["https://*.com/callback"])
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    let resp = register(
+        vec![REDIRECT_URI],
+        vec!["https://*.preview.example.com/callback"],
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 201);
+    let body: Value = resp.json().await.unwrap();
+    assert!(body.get("warnings").is_none());
+
+    let resp = register(vec![REDIRECT_URI], vec!["https://*.example.com/callback"])
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let body: Value = resp.json().await.unwrap();
+    assert!(body["warnings"][0]
+        .as_str()
+        .unwrap()
+        .contains("every subdomain of example.com"));
+
+    let export: Value = server
+        .http
+        .get(server.url("/admin/api/clients/export"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let client = export["clients"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|client| client["client_id"] == body["client_id"])
+        .unwrap();
+    assert_eq!(
+        client["redirect_uri_patterns"],
+        serde_json::json!(["https://*.example.com/callback"])
+    );
+
+    server.stop().await;
+}
+
+/// Spawn a server whose expiry decisions follow `clock`
+async fn spawn_with_clock(clock: &Arc<ManualClock>) -> TestServer {
+    let clock = clock.clone();
+    TestServer::spawn_with(|builder, _| builder.clock(clock)).await
+}
+
+#[actix_web::test]
+async fn test_authorization_code_expires_after_ten_minutes() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let server = spawn_with_clock(&clock).await;
+    let client_id = server.register_client().await;
+
+    let fresh = server.authorize(&client_id, None).await;
+    let stale = server.authorize(&client_id, None).await;
+
+    clock.advance(Duration::minutes(9));
+    let resp = server.exchange_code(&client_id, &fresh, None).await;
+    assert_eq!(resp.status(), 200);
+
+    clock.advance(Duration::minutes(2));
+    let resp = server.exchange_code(&client_id, &stale, None).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_access_token_expires_after_an_hour() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let server = spawn_with_clock(&clock).await;
+    let client_id = server.register_client().await;
+
+    let code = server.authorize(&client_id, None).await;
+    let token: Value = server
+        .exchange_code(&client_id, &code, None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let access_token = token["access_token"].as_str().unwrap();
+
+    clock.advance(Duration::minutes(59));
+    assert_eq!(server.introspect(access_token).await["active"], true);
+
+    clock.advance(Duration::minutes(2));
+    assert_eq!(server.introspect(access_token).await["active"], false);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_token_format_and_lifetime_per_grant() {
+    let server = TestServer::spawn_with_config(|config| {
+        let grants = &mut config.grant_tokens;
+        grants
+            .formats
+            .insert("authorization_code".to_string(), TokenFormat::Opaque);
+        grants
+            .access_token_lifetimes
+            .insert("authorization_code".to_string(), 300);
+        grants
+            .refresh_token_lifetimes
+            .insert("authorization_code".to_string(), 0);
+        grants
+            .access_token_lifetimes
+            .insert("client_credentials".to_string(), 60);
+    })
+    .await;
+    let client_id = server.register_client().await;
+
+    // Short opaque tokens and no refresh token for the code grant
+    let token = issue_tokens(&server, &client_id).await;
+    let access_token = token["access_token"].as_str().unwrap();
+    assert!(!access_token.contains('.'), "expected an opaque token");
+    assert_eq!(token["expires_in"], 300);
+    assert!(token.get("refresh_token").is_none());
+    assert_eq!(server.introspect(access_token).await["active"], true);
+
+    // JWTs as usual for client credentials, with their own lifetime
+    let client_secret = server.client_secret(&client_id).await;
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    assert_eq!(
+        token["access_token"].as_str().unwrap().split('.').count(),
+        3
+    );
+    assert_eq!(token["expires_in"], 60);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_opaque_tokens_are_stored_hashed_and_resolved_by_introspection() {
+    let server =
+        TestServer::spawn_with_config(|config| config.grant_tokens.format = TokenFormat::Opaque)
+            .await;
+    let client_id = server.register_client().await;
+
+    let token = issue_tokens(&server, &client_id).await;
+    let access_token = token["access_token"].as_str().unwrap();
+    assert!(!access_token.contains('.'), "expected an opaque token");
+    assert_eq!(server.introspect(access_token).await["active"], true);
+
+    // The table holds only a digest of it
+    let stored = |value: String| {
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM tokens WHERE access_token = ?")
+            .bind(value)
+            .fetch_one(&server.pool)
+    };
+    assert_eq!(stored(access_token.to_string()).await.unwrap(), 0);
+    let digest = hex::encode(Sha256::digest(access_token.as_bytes()));
+    assert_eq!(stored(digest.clone()).await.unwrap(), 1);
+    assert_eq!(server.introspect(&digest).await["active"], false);
+
+    // Refreshed tokens are opaque too, and revoking the refresh token takes
+    // its access token with it
+    let resp = server.refresh(&client_id, &refresh_token(&token)).await;
+    assert_eq!(resp.status(), 200);
+    let refreshed: Value = resp.json().await.unwrap();
+    let access_token = refreshed["access_token"].as_str().unwrap();
+    assert!(!access_token.contains('.'), "expected an opaque token");
+    assert_eq!(server.introspect(access_token).await["active"], true);
+    let resp = server
+        .http
+        .post(server.url("/oauth/revoke"))
+        .form(&[("token", refresh_token(&refreshed))])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(server.introspect(access_token).await["active"], false);
+
+    // A client can ask for JWTs anyway
+    let resp = server
+        .http
+        .post(server.url("/clients/register"))
+        .json(&json!({
+            "client_name": "JWT Client",
+            "redirect_uris": [REDIRECT_URI],
+            "grant_types": ["authorization_code"],
+            "scope": "read",
+            "access_token_format": "jwt",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let body: Value = resp.json().await.unwrap();
+    let jwt_client = body["client_id"].as_str().unwrap();
+    let token = issue_tokens(&server, jwt_client).await;
+    assert_eq!(
+        token["access_token"].as_str().unwrap().split('.').count(),
+        3
+    );
+
+    server.stop().await;
+}
+
+/// Exchange a fresh authorization code and return the token response
+async fn issue_tokens(server: &TestServer, client_id: &str) -> Value {
+    let code = server.authorize(client_id, None).await;
+    let resp = server.exchange_code(client_id, &code, None).await;
+    assert_eq!(resp.status(), 200);
+    resp.json().await.unwrap()
+}
+
+fn refresh_token(token: &Value) -> String {
+    token["refresh_token"].as_str().unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn test_refresh_token_rotates() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let other_client_id = server.register_client().await;
+    let first = issue_tokens(&server, &client_id).await;
+
+    let resp = server
+        .refresh(&other_client_id, &refresh_token(&first))
+        .await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    let resp = server.refresh(&client_id, &refresh_token(&first)).await;
+    assert_eq!(resp.status(), 200);
+    let second: Value = resp.json().await.unwrap();
+    assert_ne!(second["access_token"], first["access_token"]);
+    assert_ne!(second["refresh_token"], first["refresh_token"]);
+    let introspection = server
+        .introspect(second["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["sub"], MOCK_USER_ID);
+
+    // The rotated-out refresh token is spent
+    let resp = server.refresh(&client_id, &refresh_token(&first)).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+    let resp = server.refresh(&client_id, &refresh_token(&second)).await;
+    assert_eq!(resp.status(), 200);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_introspecting_refresh_tokens_needs_the_hint() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let token = issue_tokens(&server, &client_id).await;
+    let introspect = |token: String, hint: &'static str| {
+        let server = &server;
+        async move {
+            server
+                .http
+                .post(server.url("/oauth/introspect"))
+                .form(&[("token", token.as_str()), ("token_type_hint", hint)])
+                .send()
+                .await
+                .unwrap()
+                .json::<Value>()
+                .await
+                .unwrap()
+        }
+    };
+
+    // Never active where an access token is expected
+    let introspection = server.introspect(&refresh_token(&token)).await;
+    assert_eq!(introspection["active"], false);
+
+    let introspection = introspect(refresh_token(&token), "refresh_token").await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["token_type"], "refresh_token");
+    assert_eq!(introspection["client_id"], client_id.as_str());
+    assert_eq!(introspection["sub"], MOCK_USER_ID);
+    assert_eq!(introspection["scope"], "read");
+    assert!(introspection["exp"].as_i64().unwrap() > introspection["iat"].as_i64().unwrap());
+
+    // A wrong hint still finds the access token
+    let access_token = token["access_token"].as_str().unwrap().to_string();
+    let introspection = introspect(access_token, "refresh_token").await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["token_type"], "Bearer");
+
+    let resp = server.refresh(&client_id, &refresh_token(&token)).await;
+    assert_eq!(resp.status(), 200);
+    let introspection = introspect(refresh_token(&token), "refresh_token").await;
+    assert_eq!(introspection["active"], false);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_refresh_can_narrow_but_not_broaden_scope() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let code = server.authorize_scope(&client_id, "read write", None).await;
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(resp.status(), 200);
+    let granted: Value = resp.json().await.unwrap();
+    assert_eq!(granted["scope"], "read write");
+
+    let client_secret = server.client_secret(&client_id).await;
+    let refresh_with_scope = |refresh_token: String, scope: &'static str| {
+        let server = &server;
+        let client_id = client_id.clone();
+        let client_secret = client_secret.clone();
+        async move {
+            server
+                .http
+                .post(server.url("/oauth/token"))
+                .form(&[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", refresh_token.as_str()),
+                    ("client_id", client_id.as_str()),
+                    ("client_secret", client_secret.as_str()),
+                    ("scope", scope),
+                ])
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    // Asking for more than was granted fails, and spends nothing
+    let resp = refresh_with_scope(refresh_token(&granted), "read write admin").await;
+    assert_eq!(error_code(resp).await, "invalid_scope");
+
+    let resp = refresh_with_scope(refresh_token(&granted), "read").await;
+    assert_eq!(resp.status(), 200);
+    let narrowed: Value = resp.json().await.unwrap();
+    assert_eq!(narrowed["scope"], "read");
+    let introspection = server
+        .introspect(narrowed["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["scope"], "read");
+
+    // The rotated refresh token still carries the original grant
+    let resp = refresh_with_scope(refresh_token(&narrowed), "write").await;
+    assert_eq!(resp.status(), 200);
+    let widened: Value = resp.json().await.unwrap();
+    assert_eq!(widened["scope"], "write");
+    let resp = server.refresh(&client_id, &refresh_token(&widened)).await;
+    assert_eq!(resp.status(), 200);
+    let restored: Value = resp.json().await.unwrap();
+    assert_eq!(restored["scope"], "read write");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_refresh_token_slides_up_to_max_lifetime() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let server = TestServer::spawn_custom(
+        |config| {
+            config.refresh_token.lifetime = 86400;
+            config.refresh_token.max_lifetime = 3 * 86400;
+        },
+        |builder, _| builder.clock(clock.clone()),
+    )
+    .await;
+    let client_id = server.register_client().await;
+    let mut token = issue_tokens(&server, &client_id).await;
+
+    // Refreshing every 20 hours outlives the one-day lifetime...
+    for _ in 0..3 {
+        clock.advance(Duration::hours(20));
+        let resp = server.refresh(&client_id, &refresh_token(&token)).await;
+        assert_eq!(resp.status(), 200);
+        token = resp.json().await.unwrap();
+    }
+
+    // ...but not the three-day limit from the original grant
+    clock.advance(Duration::hours(13));
+    let resp = server.refresh(&client_id, &refresh_token(&token)).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    // A token left alone past its lifetime is dead
+    let dormant = issue_tokens(&server, &client_id).await;
+    clock.advance(Duration::hours(25));
+    let resp = server.refresh(&client_id, &refresh_token(&dormant)).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_refresh_token_idle_timeout_counts_access_token_use() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let server = TestServer::spawn_custom(
+        |config| config.refresh_token.idle_timeout = 3600,
+        |builder, _| builder.clock(clock.clone()),
+    )
+    .await;
+    let client_id = server.register_client().await;
+    let active = issue_tokens(&server, &client_id).await;
+    let idle = issue_tokens(&server, &client_id).await;
+
+    clock.advance(Duration::minutes(45));
+    let introspection = server
+        .introspect(active["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], true);
+
+    clock.advance(Duration::minutes(45));
+    let resp = server.refresh(&client_id, &refresh_token(&active)).await;
+    assert_eq!(resp.status(), 200);
+    let resp = server.refresh(&client_id, &refresh_token(&idle)).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_usage_reports_find_stale_credentials() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let server = TestServer::spawn_with(|builder, _| builder.clock(clock.clone())).await;
+    let busy_client = server.register_client().await;
+    let idle_client = server.register_client().await;
+    let used = issue_tokens(&server, &busy_client).await;
+    issue_tokens(&server, &busy_client).await;
+    issue_tokens(&server, &idle_client).await;
+
+    clock.advance(Duration::days(10));
+    let resp = server.refresh(&busy_client, &refresh_token(&used)).await;
+    assert_eq!(resp.status(), 200);
+    let rotated: Value = resp.json().await.unwrap();
+    let introspection = server
+        .introspect(rotated["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], true);
+
+    let report = |path: &str| {
+        server
+            .http
+            .get(server.url(path))
+            .query(&[("days", "5")])
+            .send()
+    };
+
+    let stale: Vec<Value> = report("/admin/api/usage/stale-clients")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let stale_ids: Vec<_> = stale
+        .iter()
+        .map(|c| c["client_id"].as_str().unwrap())
+        .collect();
+    assert!(stale_ids.contains(&idle_client.as_str()));
+    assert!(!stale_ids.contains(&busy_client.as_str()));
+
+    // The refreshed token is spent and its replacement is in use; the
+    // untouched ones are still refreshable
+    let unused: Vec<Value> = report("/admin/api/usage/unused-tokens")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let mut unused_clients: Vec<_> = unused
+        .iter()
+        .map(|t| t["client_id"].as_str().unwrap())
+        .collect();
+    unused_clients.sort();
+    let mut expected = vec![busy_client.as_str(), idle_client.as_str()];
+    expected.sort();
+    assert_eq!(unused_clients, expected);
+    assert!(unused
+        .iter()
+        .all(|t| t["refreshable"] == true && t["use_count"] == 0));
+    assert!(unused.iter().all(|t| t.get("access_token").is_none()));
+
+    // Reports flush buffered usage: two issuances, a refresh and an introspection
+    let (use_count, last_used_at): (i64, Option<String>) =
+        sqlx::query_as("SELECT use_count, last_used_at FROM clients WHERE client_id = ?")
+            .bind(&busy_client)
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(use_count, 4);
+    assert!(last_used_at.is_some());
+
+    server.stop().await;
+}
+
+async fn count_rows(server: &TestServer, table: &str) -> i64 {
+    sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+        .fetch_one(&server.pool)
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn test_retention_purges_records_past_their_window() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let server = TestServer::spawn_custom(
+        |config| config.retention.audit_days = 30,
+        |builder, _| builder.clock(clock.clone()),
+    )
+    .await;
+    let client_id = server.register_client().await;
+    let revoked = issue_tokens(&server, &client_id).await;
+    let expired = issue_tokens(&server, &client_id).await;
+    server.authorize(&client_id, None).await;
+    let resp = server
+        .http
+        .post(server.url(&format!(
+            "/admin/api/tokens/{}/revoke",
+            revoked["access_token"].as_str().unwrap()
+        )))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    clock.advance(Duration::days(20));
+    let live = issue_tokens(&server, &client_id).await;
+    clock.advance(Duration::days(20));
+
+    let run = |dry_run: &'static str| {
+        server
+            .http
+            .post(server.url("/admin/api/retention/run"))
+            .query(&[("dry_run", dry_run)])
+            .send()
+    };
+
+    // The revoked token and every code are past their window; the expired
+    // token can still be refreshed for another ten days
+    let report: Value = run("true").await.unwrap().json().await.unwrap();
+    assert_eq!(report["dry_run"], true);
+    assert_eq!(report["tokens"], 1);
+    assert_eq!(report["authorization_codes"], 4);
+    assert_eq!(report["audit_entries"], 1);
+    assert_eq!(report["authorization_requests"], 3);
+    assert_eq!(count_rows(&server, "tokens").await, 3);
+    assert_eq!(count_rows(&server, "authorization_codes").await, 4);
+
+    let report: Value = run("false").await.unwrap().json().await.unwrap();
+    assert_eq!(report["dry_run"], false);
+    assert_eq!(report["tokens"], 1);
+    assert_eq!(count_rows(&server, "tokens").await, 2);
+    assert_eq!(count_rows(&server, "authorization_codes").await, 0);
+    // Only the two runs themselves are left in the audit log
+    assert_eq!(count_rows(&server, "admin_audit_log").await, 2);
+
+    clock.advance(Duration::days(30));
+    let report: Value = run("false").await.unwrap().json().await.unwrap();
+    assert_eq!(report["tokens"], 1);
+    // Now the expired token's refresh token has run out too
+    let remaining: Option<String> = sqlx::query_scalar("SELECT refresh_token FROM tokens")
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining, Some(refresh_token(&live)));
+    assert_ne!(remaining, Some(refresh_token(&expired)));
+
+    let metrics = server
+        .http
+        .get(server.url("/metrics"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains(r#"oauth2_server_retention_purged_total{class="tokens"} 2"#));
+    assert!(metrics.contains(r#"oauth2_server_retention_dry_run_records{class="tokens"} 1"#));
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_metrics_show_process_and_runtime_saturation() {
+    let server = TestServer::spawn().await;
+
+    let metrics = server
+        .http
+        .get(server.url("/metrics"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let value = |name: &str| -> f64 {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("{} missing", name))
+            .parse()
+            .unwrap()
+    };
+    if cfg!(target_os = "linux") {
+        assert!(value("process_open_fds") > 0.0);
+        assert!(value("process_resident_memory_bytes") > 0.0);
+    }
+    assert!(value("oauth2_server_http_workers") >= 1.0);
+    assert_eq!(
+        value(r#"oauth2_server_runtime_worker_threads{runtime="http-worker-0"}"#),
+        1.0
+    );
+    assert!(value(r#"oauth2_server_runtime_alive_tasks{runtime="main"}"#) >= 1.0);
+    assert!(metrics.contains(r#"oauth2_server_runtime_queued_tasks{runtime="main"}"#));
+
+    server.stop().await;
+}
+
+#[cfg(unix)]
+#[actix_web::test]
+async fn test_server_tuning_and_unix_socket() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket = std::env::temp_dir().join(format!("oauth2_e2e_{}.sock", uuid::Uuid::new_v4()));
+    let server = TestServer::spawn_with_config(|config| {
+        config.server.workers = Some(1);
+        config.server.unix_socket = Some(socket.display().to_string());
+    })
+    .await;
+
+    let mut stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("healthy"));
+
+    // TCP is still served, by the one worker asked for
+    let metrics = server
+        .http
+        .get(server.url("/metrics"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics
+        .lines()
+        .any(|line| line == "oauth2_server_http_workers 1"));
+
+    server.stop().await;
+    let _ = std::fs::remove_file(socket);
+}
+
+#[actix_web::test]
+async fn test_admin_surface_only_on_internal_listener() {
+    let internal = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let internal_url = format!("http://{}", internal.local_addr().unwrap());
+    let server = TestServer::spawn_with(|builder, _| builder.internal_listener(internal)).await;
+
+    for path in ["/admin/api/clients", "/metrics"] {
+        let resp = server.http.get(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 404, "public {}", path);
+        let resp = server
+            .http
+            .get(format!("{}{}", internal_url, path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200, "internal {}", path);
+    }
+
+    // Health checks answer on both; OAuth endpoints only on the public one
+    for base in [server.base_url.as_str(), internal_url.as_str()] {
+        let resp = server
+            .http
+            .get(format!("{}/health", base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200, "{}", base);
+    }
+    server.register_client().await;
+    let resp = server
+        .http
+        .post(format!("{}/clients/register", internal_url))
+        .json(&serde_json::json!({
+            "client_name": "Internal",
+            "redirect_uris": [REDIRECT_URI],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_schema_validation_lists_every_violation() {
+    let server =
+        TestServer::spawn_with_config(|config| config.limits.validate_schemas = true).await;
+
+    let resp = server
+        .http
+        .post(server.url("/clients/register"))
+        .json(&serde_json::json!({
+            "client_name": "E2E Client",
+            "redirect_uris": REDIRECT_URI,
+            "grant_types": ["authorization_code"],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_request");
+    assert_eq!(
+        body["violations"],
+        serde_json::json!([
+            {"pointer": "/scope", "message": "is required"},
+            {"pointer": "/redirect_uris", "message": "expected array, found string"},
+        ])
+    );
+
+    // Forms are checked too, and valid bodies reach the handlers intact
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[("client_id", "app")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["violations"][0]["pointer"], "/grant_type");
+
+    let client_id = server.register_client().await;
+    let code = server.authorize(&client_id, None).await;
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(resp.status(), 200);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_revoked_token_is_inactive() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let code = server.authorize(&client_id, None).await;
+    let token: Value = server
+        .exchange_code(&client_id, &code, None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let access_token = token["access_token"].as_str().unwrap();
+
+    let resp = server
+        .http
+        .post(server.url("/oauth/revoke"))
+        .form(&[("token", access_token)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let introspection = server.introspect(access_token).await;
+    assert_eq!(introspection["active"], false);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_decoding_a_token_names_each_failed_check() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let server = spawn_with_clock(&clock).await;
+    let client_id = server.register_client().await;
+
+    let code = server.authorize(&client_id, None).await;
+    let token: Value = server
+        .exchange_code(&client_id, &code, None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let access_token = token["access_token"].as_str().unwrap();
+
+    let decode = |token: String, audience: Option<&str>| {
+        let body = serde_json::json!({"token": token, "audience": audience});
+        let request = server
+            .http
+            .post(server.url("/admin/api/tokens/decode"))
+            .json(&body);
+        async move {
+            let resp = request.send().await.unwrap();
+            assert_eq!(resp.status(), 200);
+            resp.json::<Value>().await.unwrap()
+        }
+    };
+    let checks = |inspection: &Value| -> Vec<String> {
+        inspection["failures"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|failure| failure["check"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let inspection = decode(access_token.to_string(), None).await;
+    assert_eq!(inspection["format"], "jwt");
+    assert_eq!(inspection["valid"], true);
+    assert_eq!(inspection["header"]["alg"], "HS256");
+    assert_eq!(inspection["claims"]["client_id"], client_id);
+    assert_eq!(inspection["record"]["client_id"], client_id);
+    assert_eq!(inspection["record"]["revoked"], false);
+    assert!(inspection["record"].get("access_token").is_none());
+
+    let inspection = decode(access_token.to_string(), Some("https://other.example.com")).await;
+    assert_eq!(checks(&inspection), ["audience"]);
+
+    // Claims still show when the signature does not hold
+    let mut tampered = access_token.to_string();
+    let at = tampered.len() - 10;
+    let swapped = if &tampered[at..at + 1] == "A" {
+        "B"
+    } else {
+        "A"
+    };
+    tampered.replace_range(at..at + 1, swapped);
+    let inspection = decode(tampered, None).await;
+    assert_eq!(inspection["claims"]["client_id"], client_id);
+    assert_eq!(checks(&inspection), ["signature", "unknown"]);
+
+    let inspection = decode("not-a-token".to_string(), None).await;
+    assert_eq!(inspection["format"], "opaque");
+    assert!(inspection["record"].is_null());
+    assert_eq!(checks(&inspection), ["unknown"]);
+
+    server
+        .http
+        .post(server.url("/oauth/revoke"))
+        .form(&[("token", access_token)])
+        .send()
+        .await
+        .unwrap();
+    clock.advance(Duration::hours(2));
+    let inspection = decode(access_token.to_string(), None).await;
+    assert_eq!(inspection["valid"], false);
+    assert_eq!(inspection["record"]["revoked"], true);
+    assert_eq!(checks(&inspection), ["expired", "revoked"]);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_disabling_user_revokes_tokens_and_codes() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let token = issue_tokens(&server, &client_id).await;
+    let pending_code = server.authorize(&client_id, None).await;
+
+    let resp = server
+        .http
+        .post(server.url(&format!("/admin/api/users/{}/disable", MOCK_USER_ID)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["revoked"]["tokens"], 1);
+    assert_eq!(body["revoked"]["authorization_codes"], 1);
+
+    let introspection = server
+        .introspect(token["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], false);
+    let resp = server.refresh(&client_id, &refresh_token(&token)).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+    let resp = server.exchange_code(&client_id, &pending_code, None).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    let enabled: bool = sqlx::query_scalar("SELECT enabled FROM users WHERE id = ?")
+        .bind(MOCK_USER_ID)
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert!(!enabled);
+
+    let resp = server
+        .http
+        .post(server.url("/admin/api/users/nobody/disable"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_disabling_user_drops_cached_introspections() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.introspection.cache_max_age = 300;
+    })
+    .await;
+    let client_id = server.register_client().await;
+    let token = issue_tokens(&server, &client_id).await;
+    let access_token = token["access_token"].as_str().unwrap();
+
+    // Cached as active until the user is disabled
+    assert_eq!(server.introspect(access_token).await["active"], true);
+
+    let resp = server
+        .http
+        .post(server.url(&format!("/admin/api/users/{}/disable", MOCK_USER_ID)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    assert_eq!(server.introspect(access_token).await["active"], false);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_password_change_revokes_tokens() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let token = issue_tokens(&server, &client_id).await;
+    let url = server.url(&format!("/admin/api/users/{}/password", MOCK_USER_ID));
+
+    let resp = server
+        .http
+        .post(&url)
+        .json(&serde_json::json!({ "password": "short" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+    let introspection = server
+        .introspect(token["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], true);
+
+    let resp = server
+        .http
+        .post(&url)
+        .json(&serde_json::json!({ "password": "a much better password" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let introspection = server
+        .introspect(token["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], false);
+    let hash: String = sqlx::query_scalar("SELECT password_hash FROM users WHERE id = ?")
+        .bind(MOCK_USER_ID)
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_password_grant_checks_the_users_password() {
+    let backend = Arc::new(RecordingBackend::default());
+    let server = TestServer::spawn_custom(|config| config.events.enabled = true, {
+        let backend = backend.clone();
+        |builder, _| builder.event_plugin(backend)
+    })
+    .await;
+    let client_id = server.register_client().await;
+    let client_secret = server.client_secret(&client_id).await;
+    let resp = server
+        .http
+        .post(server.url(&format!("/admin/api/users/{}/password", MOCK_USER_ID)))
+        .json(&serde_json::json!({ "password": "a much better password" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // The user the test server seeds, whose username is `e2e`
+    let password_grant = |username: &str, password: &str| {
+        server
+            .http
+            .post(server.url("/oauth/token"))
+            .basic_auth(&client_id, Some(&client_secret))
+            .form(&[
+                ("grant_type", "password"),
+                ("username", username),
+                ("password", password),
+            ])
+            .send()
+    };
+
+    let resp = password_grant("e2e", "a much better password")
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    let introspection = server
+        .introspect(token["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["sub"], MOCK_USER_ID);
+
+    let resp = password_grant("e2e", "not the password").await.unwrap();
+    assert_eq!(error_code(resp).await, "invalid_grant");
+    let resp = password_grant("nobody", "a much better password")
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    let resp = server
+        .http
+        .post(server.url(&format!("/admin/api/users/{}/disable", MOCK_USER_ID)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = password_grant("e2e", "a much better password")
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    let outcomes = || {
+        backend
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.metadata.get("provider").map(String::as_str) == Some("local"))
+            .map(|event| {
+                (
+                    event.event_type.clone(),
+                    event.metadata.get("reason").cloned(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    for _ in 0..150 {
+        if outcomes().len() == 4 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(
+        outcomes(),
+        vec![
+            (EventType::UserAuthenticated, None),
+            (
+                EventType::UserAuthenticationFailed,
+                Some("invalid_password".to_string())
+            ),
+            (
+                EventType::UserAuthenticationFailed,
+                Some("unknown_user".to_string())
+            ),
+            (
+                EventType::UserAuthenticationFailed,
+                Some("user_disabled".to_string())
+            ),
+        ]
+    );
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_admin_mutations_are_audited() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let token = issue_tokens(&server, &client_id).await;
+    let access_token = token["access_token"].as_str().unwrap();
+
+    let resp = server
+        .http
+        .post(server.url(&format!("/admin/api/tokens/{}/revoke", access_token)))
+        .bearer_auth(access_token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let resp = server
+        .http
+        .post(server.url(&format!("/admin/api/users/{}/password", MOCK_USER_ID)))
+        .json(&serde_json::json!({ "password": "a much better password" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Reads are not audited
+    let entries: Value = server
+        .http
+        .get(server.url("/admin/api/audit"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    let body = serde_json::to_string(entries).unwrap();
+    assert!(!body.contains("a much better password"));
+    assert!(!body.contains(access_token));
+
+    let fingerprint = token_fingerprint(access_token);
+    let revoked: Value = server
+        .http
+        .get(server.url("/admin/api/audit"))
+        .query(&[("path", format!("/admin/api/tokens/{}", fingerprint))])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(revoked.as_array().unwrap().len(), 1);
+    assert_eq!(revoked[0]["actor"], format!("user:{}", MOCK_USER_ID));
+    assert_eq!(revoked[0]["method"], "POST");
+    assert_eq!(revoked[0]["status"], 200);
+
+    let password_change = entries
+        .iter()
+        .find(|entry| entry["path"].as_str().unwrap().ends_with("/password"))
+        .unwrap();
+    assert_eq!(password_change["actor"], "anonymous");
+    let params: Value = serde_json::from_str(password_change["params"].as_str().unwrap()).unwrap();
+    assert_eq!(params["id"], MOCK_USER_ID);
+    assert_eq!(params["body"]["password"], "[REDACTED]");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_authorization_requests_are_logged_with_their_outcome() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let code = server.authorize(&client_id, None).await;
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(resp.status(), 200);
+
+    // Refused by the handler, and before it for a missing parameter
+    let resp = server
+        .http
+        .get(server.url("/oauth/authorize"))
+        .query(&[
+            ("response_type", "code"),
+            ("client_id", client_id.as_str()),
+            ("redirect_uri", REDIRECT_URI),
+            ("state", "bad\u{1}state"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    let resp = server
+        .http
+        .get(server.url("/oauth/authorize"))
+        .query(&[("response_type", "code"), ("client_id", client_id.as_str())])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+
+    let entries: Value = server
+        .http
+        .get(server.url("/admin/api/audit/authorizations"))
+        .query(&[("client_id", client_id.as_str())])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), 3);
+    let outcome = |entry: &Value| entry["outcome"].as_str().unwrap().to_string();
+    let mut outcomes: Vec<String> = entries.iter().map(outcome).collect();
+    outcomes.sort();
+    assert_eq!(
+        outcomes,
+        ["code_issued", "invalid_request", "invalid_request"]
+    );
+
+    let issued = entries
+        .iter()
+        .find(|entry| entry["outcome"] == "code_issued")
+        .unwrap();
+    assert_eq!(issued["response_type"], "code");
+    assert_eq!(issued["redirect_uri"], REDIRECT_URI);
+    assert_eq!(issued["scope"], "read");
+    assert_eq!(issued["granted_scope"], "read");
+    assert_eq!(issued["user_id"], MOCK_USER_ID);
+    assert_eq!(issued["status"], 302);
+    assert!(issued["code_id"].is_string());
+    assert!(issued["token_id"].is_string());
+    let params: Value = serde_json::from_str(issued["params"].as_str().unwrap()).unwrap();
+    assert!(params["state"].as_str().unwrap().starts_with("sha256:"));
+    assert!(!issued.to_string().contains(&code));
+
+    for refused in entries
+        .iter()
+        .filter(|entry| entry["outcome"] != "code_issued")
+    {
+        assert_eq!(refused["status"], 400);
+        assert!(refused["code_id"].is_null());
+        assert!(refused["error_description"].is_string());
+    }
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_authorize_asks_the_signed_in_user_to_approve() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", client_id.as_str()),
+        ("redirect_uri", REDIRECT_URI),
+        ("scope", "read write"),
+        ("state", "xyz"),
+    ])
+    .unwrap();
+    let authorize_url = server.url(&format!("/oauth/authorize?{}", query));
+
+    // Signed-out users log in first and come back to the request
+    let resp = server.http.get(&authorize_url).send().await.unwrap();
+    assert_eq!(resp.status(), 302);
+    let location = resp.headers()["location"].to_str().unwrap();
+    assert!(location.starts_with("/auth/login?return_to=%2Foauth%2Fauthorize"));
+
+    let resp = server
+        .http
+        .get(&authorize_url)
+        .header("Cookie", server.session_cookie())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let page = resp.text().await.unwrap();
+    assert!(page.contains("E2E Client"));
+    assert!(page.contains("<code>read</code>"));
+    assert!(page.contains("<code>write</code>"));
+    assert!(page.contains("e2e@example.com"));
+
+    let decide = |decision: &'static str, csrf_token: &'static str| {
+        server
+            .http
+            .post(&authorize_url)
+            .header("Cookie", server.session_cookie())
+            .form(&[("decision", decision), ("csrf_token", csrf_token)])
+            .send()
+    };
+
+    // Approval has to come from the page
+    let resp = decide("approve", "forged").await.unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let resp = decide("deny", SESSION_CSRF_TOKEN).await.unwrap();
+    assert_eq!(resp.status(), 302);
+    let location = resp.headers()["location"].to_str().unwrap();
+    assert_eq!(
+        location,
+        format!(
+            "{}?error=access_denied&error_description=The+user+denied+the+request&state=xyz",
+            REDIRECT_URI
+        )
+    );
+
+    let resp = decide("approve", SESSION_CSRF_TOKEN).await.unwrap();
+    assert_eq!(resp.status(), 302);
+    let location = reqwest::Url::parse(resp.headers()["location"].to_str().unwrap()).unwrap();
+    let code = location
+        .query_pairs()
+        .find(|(key, _)| key == "code")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(resp.status(), 200);
+
+    let entries: Value = server
+        .http
+        .get(server.url("/admin/api/audit/authorizations"))
+        .query(&[("client_id", client_id.as_str())])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let denied = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["outcome"] == "access_denied")
+        .unwrap();
+    assert_eq!(denied["user_id"], MOCK_USER_ID);
+    assert_eq!(denied["status"], 302);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_a_flow_id_ties_the_authorization_journey_together() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", client_id.as_str()),
+        ("redirect_uri", REDIRECT_URI),
+        ("scope", "read"),
+    ])
+    .unwrap();
+
+    // The flow id rides along through the login redirect
+    let resp = server
+        .http
+        .get(server.url(&format!("/oauth/authorize?{}", query)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 302);
+    let login =
+        reqwest::Url::parse(&server.url(resp.headers()["location"].to_str().unwrap())).unwrap();
+    let return_to = login
+        .query_pairs()
+        .find(|(key, _)| key == "return_to")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+    let flow_id = reqwest::Url::parse(&server.url(&return_to))
+        .unwrap()
+        .query_pairs()
+        .find(|(key, _)| key == "flow_id")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+
+    let resp = server
+        .http
+        .get(server.url(&return_to))
+        .header("Cookie", server.session_cookie())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(resp.text().await.unwrap().contains(&flow_id));
+    let resp = server
+        .http
+        .post(server.url(&return_to))
+        .header("Cookie", server.session_cookie())
+        .form(&[("decision", "approve"), ("csrf_token", SESSION_CSRF_TOKEN)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 302);
+    let location = reqwest::Url::parse(resp.headers()["location"].to_str().unwrap()).unwrap();
+    let code = location
+        .query_pairs()
+        .find(|(key, _)| key == "code")
+        .map(|(_, value)| value.into_owned())
+        .unwrap();
+
+    let tokens: Value = server
+        .exchange_code(&client_id, &code, None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let resp = server
+        .refresh(&client_id, tokens["refresh_token"].as_str().unwrap())
+        .await;
+    assert_eq!(resp.status(), 200);
+
+    let entries: Value = server
+        .http
+        .get(server.url("/admin/api/audit/authorizations"))
+        .query(&[("flow_id", flow_id.as_str())])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let outcomes: Vec<&str> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["outcome"].as_str().unwrap())
+        .collect();
+    assert_eq!(outcomes.len(), 3);
+    assert!(outcomes.contains(&"code_issued"));
+
+    let code_flow: Option<String> =
+        sqlx::query_scalar("SELECT flow_id FROM authorization_codes WHERE code = ?")
+            .bind(&code)
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(code_flow.as_deref(), Some(flow_id.as_str()));
+    let token_flows: Vec<Option<String>> =
+        sqlx::query_scalar("SELECT flow_id FROM tokens WHERE client_id = ?")
+            .bind(&client_id)
+            .fetch_all(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(token_flows.len(), 2);
+    assert!(token_flows
+        .iter()
+        .all(|flow| flow.as_deref() == Some(flow_id.as_str())));
+
+    // Each new request starts a flow of its own
+    let other = server.authorize(&client_id, None).await;
+    let other_flow: Option<String> =
+        sqlx::query_scalar("SELECT flow_id FROM authorization_codes WHERE code = ?")
+            .bind(&other)
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert!(other_flow.is_some_and(|other_flow| other_flow != flow_id));
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_read_only_admins_may_view_but_not_change() {
+    // Provisioned before roles are enforced, on a replica that does not
+    let unenforced = TestServer::spawn().await;
+    let (client_id, client_secret) = unenforced
+        .provision_client(
+            "admin-tooling",
+            &["client_credentials"],
+            "admin:read admin:write",
+        )
+        .await;
+    let server = unenforced
+        .spawn_replica_with_config(|config| config.admin_access.enforce_roles = true)
+        .await;
+
+    // Nobody can sign themselves up for an admin scope
+    let resp = server
+        .http
+        .post(server.url("/clients/register"))
+        .json(&serde_json::json!({
+            "client_name": "Admin Tooling",
+            "redirect_uris": [],
+            "grant_types": ["client_credentials"],
+            "scope": "admin:write",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_scope");
+
+    let admin_token = |scope: &'static str| {
+        let request = server.http.post(server.url("/oauth/token")).form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+            ("scope", scope),
+        ]);
+        async move {
+            let token: Value = request.send().await.unwrap().json().await.unwrap();
+            token["access_token"].as_str().unwrap().to_string()
+        }
+    };
+    let reader = admin_token("admin:read").await;
+    let admin = admin_token("admin:write").await;
+    let disable = |token: Option<&str>| {
+        let mut request = server
+            .http
+            .post(server.url(&format!("/admin/api/users/{}/disable", MOCK_USER_ID)));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        request.send()
+    };
+
+    let resp = server
+        .http
+        .get(server.url("/admin/api/clients"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "access_denied");
+    for path in ["/admin/api/clients", "/admin/api/clients/export"] {
+        let resp = server
+            .http
+            .get(server.url(path))
+            .bearer_auth(&reader)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200, "{}", path);
+    }
+
+    let resp = disable(Some(&reader)).await.unwrap();
+    assert_eq!(resp.status(), 403);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "access_denied");
+    assert_eq!(disable(None).await.unwrap().status(), 401);
+    assert_eq!(disable(Some(&admin)).await.unwrap().status(), 200);
+
+    // Refused changes are still on the audit trail
+    let audit: Value = server
+        .http
+        .get(server.url("/admin/api/audit?path=/admin/api/users"))
+        .bearer_auth(&admin)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let statuses: Vec<i64> = audit
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["status"].as_i64().unwrap())
+        .collect();
+    assert_eq!(statuses.len(), 3);
+    assert!(statuses.contains(&403) && statuses.contains(&401) && statuses.contains(&200));
+
+    // Narrowing the client takes the role away from tokens it already has
+    let resp = server
+        .http
+        .put(server.url("/admin/api/clients/by-name/admin-tooling"))
+        .bearer_auth(&admin)
+        .json(&serde_json::json!({
+            "redirect_uris": [REDIRECT_URI],
+            "grant_types": ["client_credentials"],
+            "scope": "admin:read",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(disable(Some(&admin)).await.unwrap().status(), 401);
+
+    server.stop().await;
+    unenforced.stop().await;
+}
+
+#[actix_web::test]
+async fn test_erasing_user_anonymizes_tokens_and_audit_trail() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let token = issue_tokens(&server, &client_id).await;
+    let access_token = token["access_token"].as_str().unwrap();
+    let erase_url = server.url(&format!("/admin/api/users/{}/erase", MOCK_USER_ID));
+
+    // An earlier admin action by the user, and one about them
+    let resp = server
+        .http
+        .post(server.url(&format!("/admin/api/users/{}/password", MOCK_USER_ID)))
+        .bearer_auth(access_token)
+        .json(&serde_json::json!({ "password": "a much better password" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let resp = server
+        .http
+        .post(&erase_url)
+        .query(&[("immediate", "true")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let body: Value = resp.json().await.unwrap();
+    let pseudonym = body["erased"]["pseudonym"].as_str().unwrap().to_string();
+    assert!(pseudonym.starts_with("erased:"));
+    assert_eq!(body["erased"]["users"], 1);
+    assert_eq!(body["erased"]["tokens"], 1);
+
+    // Tokens are kept for the counts, but no longer say whose they were
+    let (user_id, stored_token): (String, String) =
+        sqlx::query_as("SELECT user_id, access_token FROM tokens WHERE client_id = ?")
+            .bind(&client_id)
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(user_id, pseudonym);
+    assert!(stored_token.starts_with("erased:"));
+    let introspection = server.introspect(access_token).await;
+    assert_eq!(introspection["active"], false);
+
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE id = ?")
+        .bind(MOCK_USER_ID)
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(users, 0);
+
+    // Including the record of the erasure itself
+    let entries: Value = server
+        .http
+        .get(server.url("/admin/api/audit"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(entries.as_array().unwrap().len(), 2);
+    let body = serde_json::to_string(&entries).unwrap();
+    assert!(!body.contains(MOCK_USER_ID));
+    assert!(entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|entry| entry["path"].as_str().unwrap().contains(&pseudonym)));
+
+    // The rewritten entries were sealed into the audit chain again
+    let verification: Value = server
+        .http
+        .get(server.url("/admin/api/audit/verify"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(verification["valid"], true);
+    assert_eq!(verification["entries"], 2);
+
+    let resp = server
+        .http
+        .post(&erase_url)
+        .query(&[("immediate", "true")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_scheduled_erasure_can_be_cancelled() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let token = issue_tokens(&server, &client_id).await;
+    let erase_url = server.url(&format!("/admin/api/users/{}/erase", MOCK_USER_ID));
+
+    let resp = server.http.post(&erase_url).send().await.unwrap();
+    assert_eq!(resp.status(), 202);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["deletion"]["subject"], MOCK_USER_ID);
+    assert_eq!(body["revoked"]["tokens"], 1);
+    let introspection = server
+        .introspect(token["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], false);
+
+    let resp = server.http.delete(&erase_url).send().await.unwrap();
+    assert_eq!(resp.status(), 204);
+    let enabled: bool = sqlx::query_scalar("SELECT enabled FROM users WHERE id = ?")
+        .bind(MOCK_USER_ID)
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert!(enabled);
+
+    let resp = server.http.delete(&erase_url).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_clients_provisioned_declaratively_by_name() {
+    let server = TestServer::spawn().await;
+    let url = server.url("/admin/api/clients/by-name/reports");
+    let put = |state: Value| {
+        let request = server.http.put(&url).json(&state);
+        async move { request.send().await.unwrap() }
+    };
+    let state = serde_json::json!({
+        "redirect_uris": [REDIRECT_URI],
+        "grant_types": ["authorization_code", "refresh_token"],
+        "scope": "read write",
+    });
+
+    let resp = put(state.clone()).await;
+    assert_eq!(resp.status(), 201);
+    let created: Value = resp.json().await.unwrap();
+    assert_eq!(created["created"], true);
+    assert!(created["client_secret"].is_string());
+    let client_id = created["client_id"].as_str().unwrap().to_string();
+
+    // Applying the same state again is a no-op
+    let resp = put(state.clone()).await;
+    assert_eq!(resp.status(), 200);
+    let unchanged: Value = resp.json().await.unwrap();
+    assert_eq!(unchanged["client_id"], client_id.as_str());
+    assert_eq!(unchanged["drift"], serde_json::json!([]));
+    assert!(unchanged.get("client_secret").is_none());
+
+    // Scopes compare as sets; a changed grant type is drift
+    let resp = put(serde_json::json!({
+        "redirect_uris": [REDIRECT_URI],
+        "grant_types": ["authorization_code"],
+        "scope": "write read",
+    }))
+    .await;
+    let updated: Value = resp.json().await.unwrap();
+    assert_eq!(updated["drift"], serde_json::json!(["grant_types"]));
+    let grant_types: String =
+        sqlx::query_scalar("SELECT grant_types FROM clients WHERE client_id = ?")
+            .bind(&client_id)
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(grant_types, r#"["authorization_code"]"#);
+
+    let resp = put(serde_json::json!({
+        "redirect_uris": [REDIRECT_URI],
+        "grant_types": ["implicit"],
+        "scope": "read",
+    }))
+    .await;
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    // A name shared by several clients cannot be managed declaratively
+    server.register_client().await;
+    server.register_client().await;
+    let resp = server
+        .http
+        .put(server.url("/admin/api/clients/by-name/E2E%20Client"))
+        .json(&state)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 409);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_clients_move_between_servers_with_new_secrets() {
+    let source = TestServer::spawn().await;
+    let target = TestServer::spawn().await;
+    let client_id = source.register_client().await;
+
+    let resp = source
+        .http
+        .get(source.url("/admin/api/clients/export"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let mut export: Value = resp.json().await.unwrap();
+    assert_eq!(export["version"], 1);
+    assert_eq!(export["clients"][0]["client_id"], client_id.as_str());
+    assert_eq!(export["clients"][0]["redirect_uris"][0], REDIRECT_URI);
+    assert!(export["clients"][0].get("client_secret").is_none());
+
+    // Clients from elsewhere use the same format
+    export["clients"]
+        .as_array_mut()
+        .unwrap()
+        .push(serde_json::json!({
+            "client_id": "keycloak-batch-job",
+            "client_name": "Batch job",
+            "redirect_uris": [],
+            "grant_types": ["client_credentials"],
+            "scope": "read",
+        }));
+    let import = |body: Value| {
+        let request = target
+            .http
+            .post(target.url("/admin/api/clients/import"))
+            .json(&body);
+        async move { request.send().await.unwrap() }
+    };
+
+    let resp = import(export.clone()).await;
+    assert_eq!(resp.status(), 200);
+    let report: Value = resp.json().await.unwrap();
+    assert_eq!(report["imported"].as_array().unwrap().len(), 2);
+    assert_eq!(report["imported"][0]["client_id"], client_id.as_str());
+    let secret = report["imported"][1]["client_secret"].as_str().unwrap();
+    let resp = target
+        .http
+        .post(target.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", "keycloak-batch-job"),
+            ("client_secret", secret),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Importing again leaves the registered clients and their secrets alone
+    let report: Value = import(export.clone()).await.json().await.unwrap();
+    assert_eq!(report["imported"], serde_json::json!([]));
+    assert_eq!(report["skipped"].as_array().unwrap().len(), 2);
+
+    // One invalid client stops the whole import
+    let resp = import(serde_json::json!({
+        "version": 1,
+        "clients": [
+            { "client_id": "good", "client_name": "Good", "redirect_uris": [REDIRECT_URI],
+              "grant_types": ["authorization_code"], "scope": "read" },
+            { "client_id": "bad", "client_name": "Bad", "redirect_uris": ["not a uri"],
+              "grant_types": ["authorization_code"], "scope": "read" },
+        ],
+    }))
+    .await;
+    assert_eq!(error_code(resp).await, "invalid_request");
+    let count =
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM clients WHERE client_id = 'good'")
+            .fetch_one(&target.pool)
+            .await
+            .unwrap();
+    assert_eq!(count, 0);
+
+    let resp = import(serde_json::json!({ "version": 2, "clients": [] })).await;
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    source.stop().await;
+    target.stop().await;
+}
+
+#[actix_web::test]
+async fn test_sync_converges_on_manifest() {
+    let server = TestServer::spawn().await;
+    let manual_client = server.register_client().await;
+    let sync = |manifest: Value, dry_run: bool| {
+        let request = server
+            .http
+            .post(server.url(&format!("/admin/api/sync?dry_run={}", dry_run)))
+            .json(&manifest);
+        async move { request.send().await.unwrap() }
+    };
+    let summary = |report: &Value| -> Vec<String> {
+        report["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| format!("{} {} {}", c["action"], c["kind"], c["name"]).replace('"', ""))
+            .collect()
+    };
+    let count = |sql: &'static str| {
+        let pool = server.pool.clone();
+        async move {
+            sqlx::query_scalar::<_, i64>(sql)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        }
+    };
+    let manifest = serde_json::json!({
+        "clients": [{
+            "name": "reports",
+            "redirect_uris": [REDIRECT_URI],
+            "grant_types": ["authorization_code"],
+            "scope": "read",
+        }],
+        "resources": [{
+            "identifier": "https://api.example.com",
+            "name": "API",
+            "scopes": ["read", "write"],
+        }],
+        "users": [{ "username": "operator", "email": "operator@example.com" }],
+    });
+
+    // A dry run reports the plan and changes nothing
+    let report: Value = sync(manifest.clone(), true).await.json().await.unwrap();
+    assert_eq!(report["dry_run"], true);
+    assert_eq!(
+        summary(&report),
+        vec![
+            "create resource https://api.example.com",
+            "create client reports",
+            "create user operator",
+        ]
+    );
+    assert_eq!(
+        count("SELECT COUNT(*) FROM clients WHERE name = 'reports'").await,
+        0
+    );
+
+    let resp = sync(manifest.clone(), false).await;
+    assert_eq!(resp.status(), 200);
+    let report: Value = resp.json().await.unwrap();
+    assert_eq!(summary(&report).len(), 3);
+    assert!(report["changes"][1]["client_secret"].is_string());
+    let reports_id = report["changes"][1]["id"].as_str().unwrap().to_string();
+    assert_eq!(
+        count("SELECT COUNT(*) FROM users WHERE username = 'operator'").await,
+        1
+    );
+
+    // Applying it again changes nothing
+    let report: Value = sync(manifest.clone(), false).await.json().await.unwrap();
+    assert_eq!(report["changes"], serde_json::json!([]));
+
+    // Drift is corrected, and managed objects no longer declared are
+    // deleted; the client registered by hand is left alone
+    let mut changed = manifest.clone();
+    changed["clients"] = serde_json::json!([]);
+    changed["users"][0]["email"] = "ops@example.com".into();
+    let report: Value = sync(changed, false).await.json().await.unwrap();
+    assert_eq!(
+        summary(&report),
+        vec!["update user operator", "delete client reports"]
+    );
+    assert_eq!(report["changes"][0]["fields"], serde_json::json!(["email"]));
+    let clients: Vec<String> = sqlx::query_scalar("SELECT client_id FROM clients")
+        .fetch_all(&server.pool)
+        .await
+        .unwrap();
+    assert!(!clients.contains(&reports_id));
+    assert!(clients.contains(&manual_client));
+    assert_eq!(
+        count("SELECT COUNT(*) FROM users WHERE email = 'ops@example.com'").await,
+        1
+    );
+
+    // Declaring a hand-made object adopts it
+    let mut adopt = manifest.clone();
+    adopt["clients"][0]["name"] = "E2E Client".into();
+    adopt["users"][0]["email"] = "ops@example.com".into();
+    let report: Value = sync(adopt, true).await.json().await.unwrap();
+    assert_eq!(summary(&report), vec!["adopt client E2E Client"]);
+    assert_eq!(report["changes"][0]["id"], manual_client.as_str());
+
+    let mut twice = manifest.clone();
+    twice["users"] = serde_json::json!([manifest["users"][0], manifest["users"][0]]);
+    assert_eq!(
+        error_code(sync(twice, false).await).await,
+        "invalid_request"
+    );
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_unknown_token_is_inactive() {
+    let server = TestServer::spawn().await;
+
+    let introspection = server.introspect("not-a-real-token").await;
+    assert_eq!(introspection["active"], false);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_introspection_cache_headers() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.introspection.cache_max_age = 7200;
+    })
+    .await;
+    let client_id = server.register_client().await;
+
+    let code = server.authorize(&client_id, None).await;
+    let token: Value = server
+        .exchange_code(&client_id, &code, None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let access_token = token["access_token"].as_str().unwrap();
+
+    let introspect = |etag: Option<&str>| {
+        let mut req = server
+            .http
+            .post(server.url("/oauth/introspect"))
+            .form(&[("token", access_token)]);
+        if let Some(etag) = etag {
+            req = req.header("If-None-Match", etag);
+        }
+        req.send()
+    };
+
+    let resp = introspect(None).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let cache_control = resp.headers()["cache-control"].to_str().unwrap();
+    // Capped by the one-hour token lifetime rather than the configured two hours
+    let max_age: u64 = cache_control
+        .split("max-age=")
+        .nth(1)
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(cache_control.starts_with("private"));
+    assert!((3590..=3600).contains(&max_age));
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+
+    let resp = introspect(Some(&etag)).await.unwrap();
+    assert_eq!(resp.status(), 304);
+    assert_eq!(resp.headers()["etag"].to_str().unwrap(), etag);
+
+    // Revocation changes the body, so the old ETag no longer matches
+    server
+        .http
+        .post(server.url("/oauth/revoke"))
+        .form(&[("token", access_token)])
+        .send()
+        .await
+        .unwrap();
+    let resp = introspect(Some(&etag)).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["cache-control"], "no-store");
+    assert!(resp.headers().get("etag").is_none());
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_introspection_reports_binding_and_authorization_details() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+
+    let code = server.authorize(&client_id, None).await;
+    let token: Value = server
+        .exchange_code(&client_id, &code, None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let access_token = token["access_token"].as_str().unwrap();
+    let refresh_token = token["refresh_token"].as_str().unwrap();
+
+    let introspection = server.introspect(access_token).await;
+    assert!(introspection["jti"].is_string());
+    assert!(introspection.get("cnf").is_none());
+    assert!(introspection.get("authorization_details").is_none());
+
+    // Nothing issues bound tokens yet, so bind this one as DPoP would
+    let details = r#"[{"type":"payment_initiation","actions":["initiate"]}]"#;
+    sqlx::query(
+        "UPDATE tokens SET authorization_details = ?, cnf_jkt = 'key-thumbprint' \
+         WHERE access_token = ?",
+    )
+    .bind(details)
+    .bind(access_token)
+    .execute(&server.pool)
+    .await
+    .unwrap();
+
+    let introspection = server.introspect(access_token).await;
+    assert_eq!(introspection["cnf"]["jkt"], "key-thumbprint");
+    assert!(introspection["cnf"].get("x5t#S256").is_none());
+    assert_eq!(
+        introspection["authorization_details"][0]["type"],
+        "payment_initiation"
+    );
+
+    // The refreshed token stays bound to the same key
+    let refreshed: Value = server
+        .refresh(&client_id, refresh_token)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let introspection = server
+        .introspect(refreshed["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["cnf"]["jkt"], "key-thumbprint");
+    assert_eq!(
+        introspection["authorization_details"][0]["actions"][0],
+        "initiate"
+    );
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_conformance_self_test() {
+    let server = TestServer::spawn().await;
+
+    let resp = server
+        .http
+        .post(server.url("/admin/api/conformance"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let report: Value = resp.json().await.unwrap();
+
+    let passed = |name: &str| {
+        report["checks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|check| check["name"] == name)
+            .unwrap_or_else(|| panic!("missing check {}", name))["passed"]
+            == true
+    };
+    for name in [
+        "discovery_document",
+        "token_requires_client_authentication",
+        "token_rejects_unsupported_grant_type",
+        "token_rejects_unknown_code",
+        "client_registration",
+        "authorize_requires_login",
+        "pkce_rejects_missing_verifier",
+        "pkce_rejects_wrong_verifier",
+        "pkce_accepts_valid_verifier",
+        "token_response_format",
+        "introspection_active_token",
+        "revocation_deactivates_token",
+        "authorization_code_single_use",
+        "authorization_code_replay_revokes_tokens",
+    ] {
+        assert!(passed(name), "{} failed: {}", name, report);
+    }
+
+    // The temporary client is cleaned up afterwards
+    let (remaining,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM clients WHERE name = 'Conformance self-test'")
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+    assert_eq!(remaining, 0);
+
+    server.stop().await;
+}
+
+async fn register_resource(server: &TestServer, identifier: &str, token_format: &str) -> Value {
+    let resp = server
+        .http
+        .post(server.url("/admin/api/resources"))
+        .json(&serde_json::json!({
+            "identifier": identifier,
+            "name": "Orders API",
+            "scopes": ["orders:read", "orders:write"],
+            "token_format": token_format,
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    resp.json().await.unwrap()
+}
+
+async fn client_credentials(
+    server: &TestServer,
+    client_id: &str,
+    resource: &str,
+    scope: Option<&str>,
+) -> reqwest::Response {
+    let client_secret = server.client_secret(client_id).await;
+    let mut form = vec![
+        ("grant_type", "client_credentials"),
+        ("client_id", client_id),
+        ("client_secret", &client_secret),
+        ("resource", resource),
+    ];
+    if let Some(scope) = scope {
+        form.push(("scope", scope));
+    }
+    server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&form)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn test_tokens_for_a_resource_carry_its_audience() {
+    let server = TestServer::spawn().await;
+    let client_id = server
+        .register_client_with_scope("read write orders:read orders:write")
+        .await;
+    register_resource(&server, "https://orders.example.com", "jwt").await;
+
+    let resp = server
+        .http
+        .post(server.url("/admin/api/resources"))
+        .json(&serde_json::json!({
+            "identifier": "https://orders.example.com",
+            "name": "Duplicate",
+            "scopes": ["orders:read"],
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 409);
+
+    let resp = client_credentials(&server, &client_id, "https://orders.example.com", None).await;
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    assert_eq!(token["scope"], "orders:read orders:write");
+    let introspection = server
+        .introspect(token["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["aud"], "https://orders.example.com");
+
+    let resp = client_credentials(
+        &server,
+        &client_id,
+        "https://orders.example.com",
+        Some("orders:read admin"),
+    )
+    .await;
+    assert_eq!(error_code(resp).await, "invalid_scope");
+
+    let resp = client_credentials(&server, &client_id, "https://unknown.example.com", None).await;
+    assert_eq!(error_code(resp).await, "invalid_target");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_access_tokens_follow_the_rfc9068_profile() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.jwt.issuer = "https://auth.example.com".to_string();
+    })
+    .await;
+    let client_id = server
+        .register_client_with_scope("read write orders:read orders:write")
+        .await;
+    register_resource(&server, "https://orders.example.com", "jwt").await;
+
+    let discovery: Value = server
+        .http
+        .get(server.url("/.well-known/openid-configuration"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(discovery["issuer"], "https://auth.example.com");
+    assert_eq!(
+        discovery["token_endpoint"],
+        "https://auth.example.com/oauth/token"
+    );
+
+    let resp = client_credentials(
+        &server,
+        &client_id,
+        "https://orders.example.com",
+        Some("orders:read"),
+    )
+    .await;
+    let token: Value = resp.json().await.unwrap();
+    let access_token = token["access_token"].as_str().unwrap();
+    let header = jsonwebtoken::decode_header(access_token).unwrap();
+    assert_eq!(header.typ.as_deref(), Some("at+jwt"));
+
+    let mut validation = jsonwebtoken::Validation::default();
+    validation.set_issuer(&["https://auth.example.com"]);
+    validation.set_audience(&["https://orders.example.com"]);
+    let claims = jsonwebtoken::decode::<Value>(
+        access_token,
+        &jsonwebtoken::DecodingKey::from_secret(server.jwt_secret().as_bytes()),
+        &validation,
+    )
+    .unwrap()
+    .claims;
+    assert_eq!(claims["client_id"], client_id);
+    assert_eq!(claims["sub"], client_id);
+    assert_eq!(claims["scope"], "orders:read");
+    for claim in ["exp", "iat", "jti"] {
+        assert!(claims.get(claim).is_some(), "missing {}", claim);
+    }
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_refused_code_exchange_leaves_the_code_unspent() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    register_resource(&server, "https://orders.example.com", "jwt").await;
+    let code = server.authorize(&client_id, None).await;
+
+    // `read` is not one of the resource's scopes
+    let client_secret = server.client_secret(&client_id).await;
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", REDIRECT_URI),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+            ("resource", "https://orders.example.com"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_scope");
+
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(resp.status(), 200);
+    let resp = server.exchange_code(&client_id, &code, None).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_opaque_resource_tokens() {
+    let server = TestServer::spawn().await;
+    let client_id = server
+        .register_client_with_scope("read write orders:read orders:write")
+        .await;
+    let resource = register_resource(&server, "https://ledger.example.com", "opaque").await;
+
+    let resp = client_credentials(
+        &server,
+        &client_id,
+        "https://ledger.example.com",
+        Some("orders:read"),
+    )
+    .await;
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    let access_token = token["access_token"].as_str().unwrap();
+    assert!(!access_token.contains('.'), "expected an opaque token");
+
+    let introspection = server.introspect(access_token).await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["aud"], "https://ledger.example.com");
+    assert_eq!(introspection["scope"], "orders:read");
+    assert!(introspection["exp"].is_i64());
+
+    let resources: Value = server
+        .http
+        .get(server.url("/admin/api/resources"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(resources.as_array().unwrap().len(), 1);
+    assert_eq!(resources[0]["token_format"], "opaque");
+
+    let url = server.url(&format!(
+        "/admin/api/resources/{}",
+        resource["id"].as_str().unwrap()
+    ));
+    let resp = server.http.delete(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = server.http.delete(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_authorization_policy_denies_and_restricts() {
+    let rules_path =
+        std::env::temp_dir().join(format!("oauth2_policy_{}.json", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &rules_path,
+        r#"{
+            "rules": [
+                { "effect": "deny", "actions": ["authorize"], "scopes": ["payments"],
+                  "reason": "Payments access needs approval" },
+                { "effect": "permit", "grant_types": ["client_credentials"],
+                  "restrict_scopes": ["read"] }
+            ]
+        }"#,
+    )
+    .unwrap();
+    let path = rules_path.to_str().unwrap().to_string();
+    let server =
+        TestServer::spawn_with_config(|config| config.policy.rules_file = Some(path)).await;
+    let client_id = server
+        .register_client_with_scope("read write payments")
+        .await;
+
+    // Refused before the user is asked to approve
+    let resp = server
+        .http
+        .get(server.url("/oauth/authorize"))
+        .query(&[
+            ("response_type", "code"),
+            ("client_id", client_id.as_str()),
+            ("redirect_uri", REDIRECT_URI),
+            ("scope", "read payments"),
+        ])
+        .header("Cookie", server.session_cookie())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "access_denied");
+    assert_eq!(body["error_description"], "Payments access needs approval");
+
+    // Other requests fall through to the default permit
+    issue_tokens(&server, &client_id).await;
+
+    let client_secret = server.client_secret(&client_id).await;
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+            ("scope", "read write"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    assert_eq!(token["scope"], "read");
+
+    server.stop().await;
+    let _ = std::fs::remove_file(rules_path);
+}
+
+#[actix_web::test]
+async fn test_failed_token_requests_surface_as_anomalies() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.events.enabled = true;
+        config.anomaly.min_samples = 3;
+    })
+    .await;
+    let client_id = server.register_client().await;
+
+    for _ in 0..3 {
+        let resp = server.exchange_code(&client_id, "not-a-code", None).await;
+        assert_eq!(error_code(resp).await, "invalid_grant");
+    }
+    server.introspect("not-a-token").await;
+
+    // Events reach the detector asynchronously
+    let mut anomalies = Value::Null;
+    for _ in 0..50 {
+        anomalies = server
+            .http
+            .get(server.url("/admin/api/anomalies"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if !anomalies.as_array().unwrap().is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(anomalies[0]["kind"], "failed_auth_ratio");
+    assert_eq!(anomalies[0]["client_id"], client_id.as_str());
+    assert_eq!(anomalies[0]["value"], 1.0);
+
+    let metrics = server
+        .http
+        .get(server.url("/metrics"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains(&format!(
+        "oauth2_server_client_failed_auth_ratio{{client_id=\"{}\"}} 1",
+        client_id
+    )));
+    assert!(metrics.contains("oauth2_server_introspection_miss_rate 1"));
+    assert!(metrics.contains("oauth2_server_anomalies_active{kind=\"failed_auth_ratio\"} 1"));
+
+    server.stop().await;
+}
+
+/// An external event backend that can be taken down
+#[derive(Default)]
+struct FlakyBackend {
+    down: AtomicBool,
+    delivered: Mutex<Vec<String>>,
+}
+
+#[async_trait::async_trait]
+impl EventPlugin for FlakyBackend {
+    async fn emit(&self, event: &AuthEvent) -> Result<(), String> {
+        if self.down.load(Ordering::SeqCst) {
+            return Err("connection refused".to_string());
+        }
+        self.delivered.lock().unwrap().push(event.id.clone());
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "flaky"
+    }
+
+    async fn health_check(&self) -> bool {
+        !self.down.load(Ordering::SeqCst)
+    }
+}
+
+#[actix_web::test]
+async fn test_events_buffer_while_backend_is_down() {
+    let backend = Arc::new(FlakyBackend::default());
+    backend.down.store(true, Ordering::SeqCst);
+    let server = TestServer::spawn_custom(
+        |config| {
+            config.events.enabled = true;
+            config.events.health_check_interval_secs = 1;
+        },
+        |builder, _| builder.event_plugin(backend.clone()),
+    )
+    .await;
+    let client_id = server.register_client().await;
+
+    for _ in 0..3 {
+        let resp = server.exchange_code(&client_id, "not-a-code", None).await;
+        assert_eq!(error_code(resp).await, "invalid_grant");
+    }
+
+    let ready = |server: &TestServer| {
+        let request = server.http.get(server.url("/ready")).send();
+        async move {
+            let resp = request.await.unwrap();
+            assert_eq!(resp.status(), 200);
+            resp.json::<Value>().await.unwrap()
+        }
+    };
+    let mut status = Value::Null;
+    for _ in 0..50 {
+        status = ready(&server).await;
+        if status["checks"]["event_backends"][0]["buffered"].as_u64() >= Some(3) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(status["status"], "degraded");
+    assert_eq!(status["checks"]["event_backends"][0]["plugin"], "flaky");
+    assert_eq!(status["checks"]["event_backends"][0]["degraded"], true);
+    let buffered = status["checks"]["event_backends"][0]["buffered"]
+        .as_u64()
+        .unwrap();
+    assert!(buffered >= 3);
+
+    let metrics = server
+        .http
+        .get(server.url("/metrics"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(metrics.contains("oauth2_server_event_backend_degraded{plugin=\"flaky\"} 1"));
+    assert!(metrics.contains(&format!(
+        "oauth2_server_event_backend_buffered_events{{plugin=\"flaky\"}} {}",
+        buffered
+    )));
+    assert!(backend.delivered.lock().unwrap().is_empty());
+
+    // The next health check replays the buffer
+    backend.down.store(false, Ordering::SeqCst);
+    for _ in 0..150 {
+        status = ready(&server).await;
+        if status["status"] == "ready" {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(status["status"], "ready");
+    assert_eq!(status["checks"]["event_backends"][0]["buffered"], 0);
+    assert_eq!(backend.delivered.lock().unwrap().len() as u64, buffered);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_events_roll_up_into_hourly_and_daily_stats() {
+    let server = TestServer::spawn_with_config(|config| config.events.enabled = true).await;
+    let client_id = server.register_client().await;
+    issue_tokens(&server, &client_id).await;
+    issue_tokens(&server, &client_id).await;
+    let resp = server.exchange_code(&client_id, "not-a-code", None).await;
+    assert_eq!(error_code(resp).await, "invalid_grant");
+
+    let stats = |granularity: &'static str| {
+        let request = server
+            .http
+            .get(server.url("/admin/api/stats"))
+            .query(&[("granularity", granularity)]);
+        async move {
+            let buckets: Vec<Value> = request.send().await.unwrap().json().await.unwrap();
+            let count = |metric: &str, dimension: &str| -> i64 {
+                buckets
+                    .iter()
+                    .filter(|b| b["metric"] == metric && b["dimension"] == dimension)
+                    .map(|b| b["count"].as_i64().unwrap())
+                    .sum()
+            };
+            (
+                count("tokens_issued", "authorization_code"),
+                count("auth_failures", "client"),
+            )
+        }
+    };
+
+    // Events reach the aggregator asynchronously
+    let mut hourly = (0, 0);
+    for _ in 0..50 {
+        hourly = stats("hour").await;
+        if hourly == (2, 1) {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(hourly, (2, 1));
+    assert_eq!(stats("day").await, (2, 1));
+
+    let resp = server
+        .http
+        .get(server.url("/admin/api/stats?metric=bogus"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_session_cookie_attributes_are_configurable() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.session.cookie_name = "oauth_session".to_string();
+        config.session.cookie_same_site = CookieSameSite::None;
+        config.session.cookie_domain = Some("example.com".to_string());
+        config.session.cookie_path = "/auth".to_string();
+    })
+    .await;
+
+    // The sign-out confirmation page starts a session for its CSRF token
+    let resp = server
+        .http
+        .get(server.url("/auth/logout-all"))
+        .send()
+        .await
+        .unwrap();
+    let cookie = resp
+        .headers()
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .find(|header| header.starts_with("oauth_session="))
+        .expect("session cookie")
+        .to_string();
+    for attribute in [
+        "Domain=example.com",
+        "Path=/auth",
+        "SameSite=None",
+        "Secure",
+        "HttpOnly",
+    ] {
+        assert!(cookie.contains(attribute), "{} in {}", attribute, cookie);
+    }
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_admins_impersonate_users_with_audited_tokens() {
+    let unlisted = TestServer::spawn_with_config(|config| {
+        config.admin_access.admins = vec![MOCK_USER_ID.to_string()]
+    })
+    .await;
+    let client_id = unlisted.register_client().await;
+    let (console_id, console_secret) = unlisted
+        .provision_client(
+            "support-console",
+            &["client_credentials"],
+            "read admin:impersonate",
+        )
+        .await;
+    let actor = format!("client:{}", console_id);
+    let server = unlisted
+        .spawn_replica_with_config(|config| config.impersonation.admins = vec![actor.clone()])
+        .await;
+    let (console_id, console_secret) = (console_id.as_str(), console_secret.as_str());
+    let admin_token = |scope: &'static str| {
+        let server = &server;
+        async move {
+            let resp = server
+                .http
+                .post(server.url("/oauth/token"))
+                .form(&[
+                    ("grant_type", "client_credentials"),
+                    ("client_id", console_id),
+                    ("client_secret", console_secret),
+                    ("scope", scope),
+                ])
+                .send()
+                .await
+                .unwrap();
+            let token: Value = resp.json().await.unwrap();
+            token["access_token"].as_str().unwrap().to_string()
+        }
+    };
+    let url = server.url(&format!("/admin/api/users/{}/impersonate", MOCK_USER_ID));
+    let body = serde_json::json!({ "client_id": client_id, "scope": "read", "ttl_secs": 3600 });
+
+    // The permission is separate from holding any other token
+    let resp = server.http.post(&url).json(&body).send().await.unwrap();
+    assert_eq!(resp.status(), 403);
+    let read_only = admin_token("read").await;
+    let resp = server
+        .http
+        .post(&url)
+        .bearer_auth(&read_only)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let admin = admin_token("admin:impersonate").await;
+    // Nor is the scope enough for a principal that is not listed
+    let resp = unlisted
+        .http
+        .post(unlisted.url(&format!("/admin/api/users/{}/impersonate", MOCK_USER_ID)))
+        .bearer_auth(&admin)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let resp = server
+        .http
+        .post(&url)
+        .bearer_auth(&admin)
+        .json(&serde_json::json!({ "client_id": client_id, "scope": "read admin" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_scope");
+    let resp = server
+        .http
+        .post(server.url("/admin/api/users/nobody/impersonate"))
+        .bearer_auth(&admin)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    // Clients are the subject of their own tokens, but are not users
+    let resp = server
+        .http
+        .post(server.url(&format!("/admin/api/users/{}/impersonate", console_id)))
+        .bearer_auth(&admin)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    let resp = server
+        .http
+        .post(&url)
+        .bearer_auth(&admin)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let minted: Value = resp.json().await.unwrap();
+    assert_eq!(minted["act"]["sub"], actor);
+    // Capped at the configured maximum
+    assert_eq!(minted["expires_in"], 900);
+    let impersonation_token = minted["access_token"].as_str().unwrap();
+
+    let introspection = server.introspect(impersonation_token).await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["sub"], MOCK_USER_ID);
+    assert_eq!(introspection["scope"], "read");
+    assert_eq!(introspection["act"]["sub"], actor);
+    assert!(server.introspect(&admin).await.get("act").is_none());
+
+    // An impersonation token cannot be used to impersonate further
+    let resp = server
+        .http
+        .post(&url)
+        .bearer_auth(impersonation_token)
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let entries: Value = server
+        .http
+        .get(server.url("/admin/api/audit"))
+        .query(&[(
+            "path",
+            format!("/admin/api/users/{}/impersonate", MOCK_USER_ID),
+        )])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert!(entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|entry| entry["actor"] == actor && entry["status"] == 201));
+
+    let active: Value = server
+        .http
+        .get(server.url("/admin/api/impersonation"))
+        .bearer_auth(&admin)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(active.as_array().unwrap().len(), 1);
+    assert_eq!(active[0]["impersonator"], actor);
+    assert!(active[0].get("access_token").is_none());
+
+    let resp = server
+        .http
+        .post(server.url("/admin/api/impersonation/revoke"))
+        .bearer_auth(&admin)
+        .json(&serde_json::json!({ "impersonator": actor }))
+        .send()
+        .await
+        .unwrap();
+    let revoked: Value = resp.json().await.unwrap();
+    assert_eq!(revoked["revoked"], 1);
+    assert_eq!(
+        server.introspect(impersonation_token).await["active"],
+        false
+    );
+    assert_eq!(server.introspect(&admin).await["active"], true);
+
+    // A signed-in admin needs no token, but cannot impersonate themselves
+    sqlx::query(
+        "INSERT INTO users (id, username, password_hash, email, enabled, created_at, updated_at) \
+         VALUES ('e2e:customer', 'customer', 'unused', 'customer@example.com', 1, ?, ?)",
+    )
+    .bind(chrono::Utc::now())
+    .bind(chrono::Utc::now())
+    .execute(&server.pool)
+    .await
+    .unwrap();
+    let resp = server
+        .http
+        .post(server.url("/admin/api/users/e2e:customer/impersonate"))
+        .header("Cookie", server.session_cookie())
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let minted: Value = resp.json().await.unwrap();
+    assert_eq!(minted["act"]["sub"], format!("user:{}", MOCK_USER_ID));
+    let resp = server
+        .http
+        .post(&url)
+        .header("Cookie", server.session_cookie())
+        .json(&body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    server.stop().await;
+    unlisted.stop().await;
+}
+
+#[actix_web::test]
+async fn test_token_endpoint_cors_admits_registered_origins() {
+    let server = TestServer::spawn().await;
+    let register = |origins: Value| {
+        server
+            .http
+            .post(server.url("/clients/register"))
+            .json(&serde_json::json!({
+                "client_name": "SPA",
+                "redirect_uris": ["https://spa.example.com/callback"],
+                "grant_types": ["authorization_code", "refresh_token"],
+                "scope": "read",
+                "allowed_origins": origins,
+            }))
+            .send()
+    };
+    let resp = register(serde_json::json!(["https://spa.example.com/app"]))
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+    let resp = register(serde_json::json!(["https://spa.example.com/"]))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+
+    let preflight = |path: &'static str, origin: &'static str| {
+        server
+            .http
+            .request(reqwest::Method::OPTIONS, server.url(path))
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "POST")
+            .header("Access-Control-Request-Headers", "content-type")
+            .send()
+    };
+    let allow_origin = |resp: &reqwest::Response| {
+        resp.headers()
+            .get("access-control-allow-origin")
+            .map(|value| value.to_str().unwrap().to_string())
+    };
+
+    for path in ["/oauth/token", "/oauth/revoke"] {
+        let resp = preflight(path, "https://spa.example.com").await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            allow_origin(&resp).as_deref(),
+            Some("https://spa.example.com")
+        );
+        assert_eq!(resp.headers()["access-control-allow-methods"], "POST");
+
+        let resp = preflight(path, "https://evil.example.com").await.unwrap();
+        assert_eq!(resp.status(), 403);
+        assert_eq!(allow_origin(&resp), None);
+    }
+
+    let token = |origin: &'static str| {
+        server
+            .http
+            .post(server.url("/oauth/token"))
+            .header("Origin", origin)
+            .form(&[("grant_type", "authorization_code"), ("code", "unknown")])
+            .send()
+    };
+    let resp = token("https://spa.example.com").await.unwrap();
+    assert_eq!(
+        allow_origin(&resp).as_deref(),
+        Some("https://spa.example.com")
+    );
+    let resp = token("https://evil.example.com").await.unwrap();
+    assert_eq!(allow_origin(&resp), None);
+
+    // Everything else keeps the general policy
+    let resp = server
+        .http
+        .get(server.url("/health"))
+        .header("Origin", "https://evil.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        allow_origin(&resp).as_deref(),
+        Some("https://evil.example.com")
+    );
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_log_levels_change_from_admin_api() {
+    let (layer, levels) = LogLevels::layer("info").unwrap();
+    let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+    let server = TestServer::spawn_with(|builder, _| builder.log_levels(Arc::new(levels))).await;
+    let url = server.url("/admin/api/logging");
+
+    let current: Value = server
+        .http
+        .get(&url)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(current["filter"], "info");
+
+    let resp = server
+        .http
+        .put(&url)
+        .json(&serde_json::json!({
+            "level": "warn",
+            "targets": { "rust_oauth2_server::db": "debug" },
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let changed: Value = resp.json().await.unwrap();
+    assert_eq!(changed["filter"], "warn,rust_oauth2_server::db=debug");
+    assert_eq!(changed["startup_filter"], "info");
+
+    let resp = server
+        .http
+        .put(&url)
+        .json(&serde_json::json!({ "level": "loud" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(error_code(resp).await, "invalid_request");
+
+    // Changes are audited like any other admin action
+    let audit: Value = server
+        .http
+        .get(server.url("/admin/api/audit"))
+        .query(&[("path", "/admin/api/logging")])
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(audit.as_array().unwrap().len(), 2);
+
+    let reset: Value = server
+        .http
+        .delete(&url)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(reset["filter"], "info");
+
+    server.stop().await;
+
+    // Without a reloadable filter there is nothing to change
+    let server = TestServer::spawn().await;
+    let resp = server
+        .http
+        .get(server.url("/admin/api/logging"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 503);
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_client_verbose_logging_toggles_from_admin_api() {
+    let server = TestServer::spawn().await;
+    let client_id = server.register_client().await;
+    let url = server.url(&format!("/admin/api/clients/{}/debug-logging", client_id));
+
+    let resp = server
+        .http
+        .put(server.url("/admin/api/clients/unknown/debug-logging"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    let resp = server
+        .http
+        .put(&url)
+        .json(&serde_json::json!({ "ttl_secs": 300 }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let enabled: Value = resp.json().await.unwrap();
+    assert_eq!(enabled["client_id"], client_id.as_str());
+
+    let listed: Value = server
+        .http
+        .get(server.url("/admin/api/debug-logging"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["until"], enabled["until"]);
+
+    // Requests are read for their client but still reach the handlers whole
+    let token = issue_tokens(&server, &client_id).await;
+    assert!(token["access_token"].is_string());
+
+    let resp = server.http.delete(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 204);
+    let resp = server.http.delete(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.stop().await;
+}
+
+/// An event backend that keeps the type of everything it is sent
+#[derive(Default)]
+struct RecordingBackend {
+    events: Mutex<Vec<AuthEvent>>,
+}
+
+#[async_trait::async_trait]
+impl EventPlugin for RecordingBackend {
+    async fn emit(&self, event: &AuthEvent) -> Result<(), String> {
+        self.events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "recording"
+    }
+}
+
+#[actix_web::test]
+async fn test_client_secrets_expire_after_a_warning() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let backend = Arc::new(RecordingBackend::default());
+    let server = TestServer::spawn_custom(
+        |config| {
+            config.events.enabled = true;
+            config.client_secrets.lifetime_days = 30;
+            config.client_secrets.warning_days = 7;
+            config.client_secrets.check_interval_secs = 1;
+        },
+        {
+            let (clock, backend) = (clock.clone(), backend.clone());
+            |builder, _| builder.clock(clock).event_plugin(backend)
+        },
+    )
+    .await;
+
+    let client: Value = server
+        .http
+        .post(server.url("/clients/register"))
+        .json(&serde_json::json!({
+            "client_name": "Batch Job",
+            "redirect_uris": [],
+            "grant_types": ["client_credentials"],
+            "scope": "read",
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let client_id = client["client_id"].as_str().unwrap();
+    assert!(client["secret_expires_at"].is_string());
+    let token = || {
+        server.http.post(server.url("/oauth/token")).form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id),
+            ("client_secret", client["client_secret"].as_str().unwrap()),
+        ])
+    };
+    let expiring = || {
+        let request = server
+            .http
+            .get(server.url("/admin/api/clients/expiring-secrets"))
+            .send();
+        async move { request.await.unwrap().json::<Value>().await.unwrap() }
+    };
+    assert_eq!(token().send().await.unwrap().status(), 200);
+    assert_eq!(expiring().await, serde_json::json!([]));
+
+    // Inside the warning window: listed, and announced once
+    clock.advance(Duration::days(24));
+    let listed = expiring().await;
+    assert_eq!(listed[0]["client_id"], client_id);
+    assert_eq!(listed[0]["expired"], false);
+    let warnings = || {
+        backend
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.event_type.as_str() == "client_secret_expiring")
+            .count()
+    };
+    for _ in 0..150 {
+        if warnings() > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(warnings(), 1);
+    assert_eq!(token().send().await.unwrap().status(), 200);
+
+    clock.advance(Duration::days(7));
+    let resp = token().send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_client");
+    assert!(body["error_description"]
+        .as_str()
+        .unwrap()
+        .contains("expired"));
+    assert_eq!(expiring().await[0]["expired"], true);
+
+    // An admin can extend the deadline
+    let resp = server
+        .http
+        .put(server.url(&format!("/admin/api/clients/{}/secret-expiry", client_id)))
+        .json(&serde_json::json!({ "expires_at": null }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(token().send().await.unwrap().status(), 200);
+    assert_eq!(expiring().await, serde_json::json!([]));
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_registration_requires_a_usable_token() {
+    let server = TestServer::spawn_with_config(|config| {
+        config.registration.require_token = true;
+    })
+    .await;
+    let register = |token: Option<&str>, scope: &str| {
+        let request = server
+            .http
+            .post(server.url("/clients/register"))
+            .json(&serde_json::json!({
+                "client_name": "Partner App",
+                "redirect_uris": [],
+                "grant_types": ["client_credentials"],
+                "scope": scope,
+            }));
+        match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    };
+
+    let resp = register(None, "read").send().await.unwrap();
+    assert_eq!(resp.status(), 403);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "access_denied");
+
+    let issue = || {
+        server
+            .http
+            .post(server.url("/admin/api/registration-tokens"))
+            .json(&serde_json::json!({
+                "description": "Partner onboarding",
+                "max_uses": 1,
+                "scope": "read",
+                "grant_types": ["client_credentials"],
+            }))
+            .send()
+    };
+    let issued: Value = issue().await.unwrap().json().await.unwrap();
+    let token = issued["token"].as_str().unwrap();
+    assert!(token.starts_with("reg_"));
+    assert!(issued.get("token_hash").is_none());
+
+    let resp = register(Some(token), "read write").send().await.unwrap();
+    assert_eq!(resp.status(), 403);
+    let resp = register(Some(token), "read").send().await.unwrap();
+    assert_eq!(resp.status(), 201);
+    let resp = register(Some(token), "read").send().await.unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let tokens: Value = server
+        .http
+        .get(server.url("/admin/api/registration-tokens"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(tokens[0]["use_count"], 1);
+
+    let issued: Value = issue().await.unwrap().json().await.unwrap();
+    let resp = server
+        .http
+        .delete(server.url(&format!(
+            "/admin/api/registration-tokens/{}",
+            issued["id"].as_str().unwrap()
+        )))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = register(Some(issued["token"].as_str().unwrap()), "read")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+}
+
+#[actix_web::test]
+async fn test_outbox_events_are_published_and_survive_a_crash() {
+    let backend = Arc::new(RecordingBackend::default());
+    let server = TestServer::spawn_custom(
+        |config| {
+            config.events.enabled = true;
+            config.events.outbox_relay_interval_secs = 1;
+        },
+        {
+            let backend = backend.clone();
+            |builder, _| builder.event_plugin(backend)
+        },
+    )
+    .await;
+
+    // Left behind by a process that died between committing and publishing
+    let stranded = AuthEvent::new(
+        EventType::ClientDeleted,
+        EventSeverity::Info,
+        None,
+        Some("deleted-before-crash".to_string()),
+    );
+    sqlx::query("INSERT INTO event_outbox (event_id, event, created_at) VALUES (?, ?, ?)")
+        .bind(&stranded.id)
+        .bind(serde_json::to_string(&stranded).unwrap())
+        .bind(stranded.timestamp)
+        .execute(&server.pool)
+        .await
+        .unwrap();
+
+    let client: Value = server
+        .http
+        .post(server.url("/clients/register"))
+        .json(&serde_json::json!({
+            "client_name": "Batch Job",
+            "redirect_uris": [],
+            "grant_types": ["client_credentials"],
+            "scope": "read",
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let resp = server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client["client_id"].as_str().unwrap()),
+            ("client_secret", client["client_secret"].as_str().unwrap()),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let published = |event_type: &str| {
+        backend
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.event_type.as_str() == event_type)
+            .map(|event| event.id.clone())
+            .collect::<Vec<_>>()
+    };
+    for _ in 0..150 {
+        if !published("token_created").is_empty() && !published("client_deleted").is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(published("client_deleted"), vec![stranded.id]);
+    assert_eq!(published("token_created").len(), 1);
+    // Numbered among the events about the client, for consumers to order by
+    let sequence = backend
+        .events
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|event| event.event_type.as_str() == "token_created")
+        .and_then(|event| event.client_sequence);
+    assert_eq!(sequence, Some(1));
+
+    // The relay clears a row once its event is published, so allow it a moment
+    let mut pending: i64 = -1;
+    for _ in 0..150 {
+        pending = sqlx::query_scalar("SELECT COUNT(*) FROM event_outbox")
+            .fetch_one(&server.pool)
+            .await
+            .unwrap();
+        if pending == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(pending, 0);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_revocations_are_published_with_jti_hashes() {
+    let backend = Arc::new(RecordingBackend::default());
+    let server = TestServer::spawn_custom(
+        |config| {
+            config.events.enabled = true;
+            config.events.outbox_relay_interval_secs = 1;
+        },
+        {
+            let backend = backend.clone();
+            |builder, _| builder.event_plugin(backend)
+        },
+    )
+    .await;
+    let client_id = server.register_client().await;
+    let code = server.authorize(&client_id, None).await;
+    let token: Value = server
+        .exchange_code(&client_id, &code, None)
+        .await
+        .json()
+        .await
+        .unwrap();
+    let access_token = token["access_token"].as_str().unwrap();
+    let introspection = server.introspect(access_token).await;
+    let jti = introspection["jti"].as_str().unwrap();
+
+    // Revoking the refresh token takes the access token with it, so
+    // resource servers must hear about it too
+    let revoke = || {
+        server
+            .http
+            .post(server.url("/oauth/revoke"))
+            .form(&[("token", refresh_token(&token))])
+            .send()
+    };
+    assert_eq!(revoke().await.unwrap().status(), 200);
+    // Nothing new to tell anyone the second time
+    assert_eq!(revoke().await.unwrap().status(), 200);
+
+    let revoked = || {
+        backend
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.event_type == EventType::TokenRevoked)
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    for _ in 0..150 {
+        if !revoked().is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    let events = revoked();
+    assert_eq!(events.len(), 1);
+    let event = &events[0];
+    assert_eq!(event.client_id.as_deref(), Some(client_id.as_str()));
+    assert_eq!(
+        event.metadata["jti_sha256"],
+        hex::encode(Sha256::digest(jti.as_bytes()))
+    );
+    assert!(event.metadata.contains_key("expires_at"));
+    assert!(event.client_sequence.is_some());
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_tampering_with_the_audit_log_is_detected() {
+    let server = TestServer::spawn().await;
+    for password in ["first new password", "second new password", "third one"] {
+        let resp = server
+            .http
+            .post(server.url(&format!("/admin/api/users/{}/password", MOCK_USER_ID)))
+            .json(&serde_json::json!({ "password": password }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+    }
+    let verify = || async {
+        server
+            .http
+            .get(server.url("/admin/api/audit/verify"))
+            .send()
+            .await
+            .unwrap()
+            .json::<Value>()
+            .await
+            .unwrap()
+    };
+
+    let verification = verify().await;
+    assert_eq!(verification["valid"], true);
+    assert_eq!(verification["entries"], 3);
+    assert_eq!(verification["first_seq"], 1);
+    assert_eq!(verification["last_seq"], 3);
+
+    // Retention purging the oldest entries leaves the rest verifiable
+    sqlx::query("DELETE FROM admin_audit_log WHERE seq = 1")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(verify().await["valid"], true);
+
+    sqlx::query("UPDATE admin_audit_log SET actor = 'user:someone-else' WHERE seq = 3")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    let verification = verify().await;
+    assert_eq!(verification["valid"], false);
+    assert_eq!(verification["broken_at"], 3);
+
+    server.stop().await;
+}
@@ -0,0 +1,200 @@
+// Property-based tests for the security-critical string handling: PKCE,
+// scopes, redirect URIs and JWT claims
+
+use base64::{engine::general_purpose, Engine as _};
+use proptest::prelude::*;
+use rust_oauth2_server::actors::validate_pkce;
+use rust_oauth2_server::clock::SystemClock;
+use rust_oauth2_server::keys::SigningKeys;
+use rust_oauth2_server::models::scope::{intersect_scopes, validate_scopes};
+use rust_oauth2_server::models::{is_valid_redirect_uri, Claims, Client};
+use sha2::{Digest, Sha256};
+
+fn s256(verifier: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// RFC 7636 verifier alphabet, 43-128 characters
+fn verifier() -> impl Strategy<Value = String> {
+    "[A-Za-z0-9._~-]{43,128}"
+}
+
+fn scope_token() -> impl Strategy<Value = String> {
+    "[a-z:._]{1,12}"
+}
+
+fn scope_string() -> impl Strategy<Value = String> {
+    prop::collection::vec(scope_token(), 0..6).prop_map(|tokens| tokens.join(" "))
+}
+
+proptest! {
+    #[test]
+    fn pkce_s256_accepts_matching_verifier(v in verifier()) {
+        prop_assert!(validate_pkce(&s256(&v), &v, "S256"));
+    }
+
+    #[test]
+    fn pkce_s256_rejects_other_verifiers(v in verifier(), other in verifier()) {
+        prop_assume!(v != other);
+        prop_assert!(!validate_pkce(&s256(&v), &other, "S256"));
+    }
+
+    #[test]
+    fn pkce_s256_rejects_verifier_as_challenge(v in verifier()) {
+        // Sending the verifier itself must not pass as an S256 challenge
+        prop_assert!(!validate_pkce(&v, &v, "S256"));
+    }
+
+    #[test]
+    fn pkce_plain_is_exact_match(challenge in ".*", v in ".*") {
+        prop_assert_eq!(validate_pkce(&challenge, &v, "plain"), challenge == v);
+    }
+
+    #[test]
+    fn pkce_unknown_method_never_validates(method in "[A-Za-z0-9]{0,8}", v in ".*") {
+        prop_assume!(method != "plain" && method != "S256");
+        prop_assert!(!validate_pkce(&v, &v, &method));
+    }
+
+    #[test]
+    fn scopes_validate_against_themselves(scope in scope_string()) {
+        prop_assert!(validate_scopes(&scope, &scope));
+    }
+
+    #[test]
+    fn scope_intersection_is_within_both(requested in scope_string(), available in scope_string()) {
+        let granted = intersect_scopes(&requested, &available);
+        prop_assert!(validate_scopes(&granted, &available));
+        prop_assert!(validate_scopes(&granted, &requested));
+    }
+
+    #[test]
+    fn scope_validation_ignores_extra_whitespace(scope in scope_string(), pad in "[ \t\n]{1,3}") {
+        let padded = format!("{pad}{}{pad}", scope.replace(' ', &pad));
+        prop_assert!(validate_scopes(&padded, &scope));
+        prop_assert!(validate_scopes(&scope, &padded));
+    }
+
+    #[test]
+    fn scope_validation_rejects_unavailable_scope(available in scope_string(), extra in scope_token()) {
+        prop_assume!(!available.split_whitespace().any(|s| s == extra));
+        let requested = format!("{} {}", available, extra);
+        prop_assert!(!validate_scopes(&requested, &available));
+    }
+
+    #[test]
+    fn redirect_uri_validation_never_panics(uri in ".*") {
+        let _ = is_valid_redirect_uri(&uri);
+    }
+
+    #[test]
+    fn valid_redirect_uris_are_http_without_fragment(uri in "[a-z]{2,6}://[a-z0-9.:@/?#=&%-]{0,40}") {
+        if is_valid_redirect_uri(&uri) {
+            prop_assert!(uri.starts_with("http://") || uri.starts_with("https://"));
+            prop_assert!(!uri.contains('#'));
+        }
+    }
+
+    #[test]
+    fn registered_redirect_uri_must_match_exactly(
+        host in "[a-z]{1,10}\\.example\\.com",
+        path in "(/[a-z0-9]{1,8}){0,3}",
+        suffix in "[a-z0-9/.?=&-]{1,10}",
+    ) {
+        let registered = format!("https://{}{}", host, path);
+        let client = Client::new(
+            "client".to_string(),
+            "secret".to_string(),
+            vec![registered.clone()],
+            vec!["authorization_code".to_string()],
+            "read".to_string(),
+            "Client".to_string(),
+        );
+
+        let extended = format!("{}{}", registered, suffix);
+        let downgraded = registered.replacen("https", "http", 1);
+        prop_assert!(client.validate_redirect_uri(&registered));
+        prop_assert!(!client.validate_redirect_uri(&extended));
+        prop_assert!(!client.validate_redirect_uri(&downgraded));
+    }
+
+    #[test]
+    fn redirect_uri_pattern_matches_one_label(
+        label in "[a-z0-9]([a-z0-9-]{0,20}[a-z0-9])?",
+        other in "[a-z0-9]{1,10}",
+        suffix in "[a-z0-9/.?=&-]{1,10}",
+    ) {
+        let client = Client::new(
+            "client".to_string(),
+            "secret".to_string(),
+            vec![],
+            vec!["authorization_code".to_string()],
+            "read".to_string(),
+            "Client".to_string(),
+        )
+        .with_redirect_uri_patterns(vec![
+            "https://*.preview.example.com/callback".to_string(),
+        ]);
+
+        let preview = format!("https://{}.preview.example.com/callback", label);
+        let nested = format!("https://{}.{}.preview.example.com/callback", other, label);
+        let elsewhere = format!("https://{}.example.com/callback", label);
+        let smuggled = format!("https://{}@{}.preview.example.com/callback", other, label);
+        let extended = format!("{}{}", preview, suffix);
+        prop_assert!(client.validate_redirect_uri(&preview));
+        prop_assert!(!client.validate_redirect_uri(&extended));
+        prop_assert!(!client.validate_redirect_uri(&nested));
+        prop_assert!(!client.validate_redirect_uri(&elsewhere));
+        prop_assert!(!client.validate_redirect_uri(&smuggled));
+    }
+
+    #[test]
+    fn jwt_claims_round_trip(
+        sub in "[ -~]{1,40}",
+        client_id in "[A-Za-z0-9_-]{1,40}",
+        scope in scope_string(),
+        secret in "[ -~]{32,64}",
+    ) {
+        let keys = SigningKeys::hmac(&secret);
+        let claims = Claims::new(sub, client_id, scope, 3600, &SystemClock);
+        let token = claims.encode(&keys).unwrap();
+        let decoded = Claims::decode(&token, &keys, &SystemClock, 0).unwrap();
+
+        prop_assert_eq!(decoded.sub, claims.sub);
+        prop_assert_eq!(decoded.aud, claims.aud);
+        prop_assert_eq!(decoded.scope, claims.scope);
+        prop_assert_eq!(decoded.jti, claims.jti);
+        prop_assert_eq!(decoded.exp, claims.exp);
+    }
+
+    #[test]
+    fn jwt_rejects_wrong_secret(secret in "[ -~]{32,64}", other in "[ -~]{32,64}") {
+        prop_assume!(secret != other);
+        let token = Claims::new("user".into(), "client".into(), "read".into(), 3600, &SystemClock)
+            .encode(&SigningKeys::hmac(&secret))
+            .unwrap();
+        prop_assert!(Claims::decode(&token, &SigningKeys::hmac(&other), &SystemClock, 0).is_err());
+    }
+
+    #[test]
+    fn jwt_rejects_tampered_payload(scope in scope_string()) {
+        let secret = &SigningKeys::hmac("property-test-secret-that-is-long-enough");
+        let token = Claims::new("user".into(), "client".into(), "read".into(), 3600, &SystemClock)
+            .encode(secret)
+            .unwrap();
+        let forged = Claims::new("admin".into(), "client".into(), scope, 3600, &SystemClock)
+            .encode(&SigningKeys::hmac("attacker-controlled-secret"))
+            .unwrap();
+
+        // Header and signature from the real token, payload from the forged one
+        let parts: Vec<&str> = token.split('.').collect();
+        let forged_payload = forged.split('.').nth(1).unwrap();
+        let spliced = format!("{}.{}.{}", parts[0], forged_payload, parts[2]);
+        prop_assert!(Claims::decode(&spliced, secret, &SystemClock, 0).is_err());
+    }
+
+    #[test]
+    fn jwt_decode_never_panics(token in ".*") {
+        let _ = Claims::decode(&token, &SigningKeys::hmac("secret"), &SystemClock, 0);
+    }
+}
@@ -321,6 +321,79 @@ mod client_validation_tests {
             "Redirect URI validation should be cautious of fragments that could enable open redirects"
         );
     }
+
+    #[test]
+    fn test_redirect_uri_pattern_rules() {
+        use rust_oauth2_server::models::validate_redirect_uri_pattern;
+
+        assert_eq!(
+            validate_redirect_uri_pattern("https://*.preview.example.com/callback"),
+            Ok(vec![])
+        );
+        let warnings = validate_redirect_uri_pattern("https://*.example.com/callback").unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("every subdomain of example.com"));
+
+        for (invalid, reason) in [
+            (
+                "http://*.preview.example.com/callback",
+                "leftmost label of an https host",
+            ),
+            (
+                "https://pr-*.preview.example.com/callback",
+                "leftmost label",
+            ),
+            ("https://app.*.example.com/callback", "leftmost label"),
+            ("https://*.*.example.com/callback", "only one wildcard"),
+            ("https://*.preview.example.com/*", "only one wildcard"),
+            ("https://*.com/callback", "at least two labels"),
+            (
+                "https://*.preview.example.com/callback#done",
+                "not a valid redirect URI",
+            ),
+            (
+                "https://*.Preview.example.com/callback",
+                "write it as https://*.preview.example.com/callback",
+            ),
+            (
+                "https://*.preview.example.com",
+                "write it as https://*.preview.example.com/",
+            ),
+        ] {
+            let err = validate_redirect_uri_pattern(invalid).unwrap_err();
+            assert!(err.contains(reason), "{}: {}", invalid, err);
+        }
+    }
+
+    #[test]
+    fn test_allowed_origin_normalization() {
+        use rust_oauth2_server::models::normalize_origin;
+
+        assert_eq!(
+            normalize_origin("https://App.Example.com/").as_deref(),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            normalize_origin("http://localhost:5173").as_deref(),
+            Some("http://localhost:5173")
+        );
+        // Default ports are dropped, as browsers do in the Origin header
+        assert_eq!(
+            normalize_origin("https://app.example.com:443").as_deref(),
+            Some("https://app.example.com")
+        );
+
+        for invalid in [
+            "https://app.example.com/callback",
+            "https://app.example.com?x=1",
+            "https://user@app.example.com",
+            "myapp://callback",
+            "app.example.com",
+            "*",
+        ] {
+            assert_eq!(normalize_origin(invalid), None, "{}", invalid);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,151 @@
+// Client credentials with workload identity tokens instead of secrets.
+// Run with `cargo test --features test-support`.
+
+mod common;
+
+use chrono::{Duration, Utc};
+use common::TestServer;
+use rust_oauth2_server::clock::{Clock, ManualClock};
+use rust_oauth2_server::test_support::{sign_jwt, signing_jwks};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const ISSUER: &str = "https://kubernetes.example.test";
+const AUDIENCE: &str = "https://auth.example.test";
+const SUBJECT: &str = "system:serviceaccount:payments:api";
+const CLIENT_ID: &str = "payments-api";
+const JWT_BEARER: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+async fn spawn_with_bundle(clock: &Arc<ManualClock>) -> TestServer {
+    let bundle = json!({
+        "issuers": [{
+            "issuer": ISSUER,
+            "audiences": [AUDIENCE],
+            "jwks": signing_jwks(),
+            "clients": { SUBJECT: CLIENT_ID },
+        }]
+    });
+    let path = std::env::temp_dir().join(format!("trust_bundle_{}.json", uuid::Uuid::new_v4()));
+    std::fs::write(&path, bundle.to_string()).unwrap();
+
+    let server = TestServer::spawn_custom(
+        |config| {
+            config.workload_identity.trust_bundle = Some(path.display().to_string());
+        },
+        |builder, _| builder.clock(clock.clone()),
+    )
+    .await;
+
+    // The client the workload is mapped to; it never gets its secret
+    sqlx::query(
+        "INSERT INTO clients (id, client_id, client_secret, redirect_uris, grant_types, scope, name, created_at, updated_at) \
+         VALUES ('payments-api-id', ?, 'never-distributed', '[]', '[\"client_credentials\"]', 'read', 'Payments API', ?, ?)",
+    )
+    .bind(CLIENT_ID)
+    .bind(Utc::now())
+    .bind(Utc::now())
+    .execute(&server.pool)
+    .await
+    .unwrap();
+
+    server
+}
+
+fn assertion(clock: &ManualClock, claims: Value) -> String {
+    let now = clock.now().timestamp();
+    let mut token = json!({
+        "iss": ISSUER,
+        "sub": SUBJECT,
+        "aud": AUDIENCE,
+        "iat": now,
+        "exp": now + 600,
+    });
+    token
+        .as_object_mut()
+        .unwrap()
+        .extend(claims.as_object().unwrap().clone());
+    sign_jwt(&token)
+}
+
+async fn client_credentials(
+    server: &TestServer,
+    client_id: &str,
+    assertion_type: &str,
+    assertion: &str,
+) -> reqwest::Response {
+    server
+        .http
+        .post(server.url("/oauth/token"))
+        .form(&[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id),
+            ("client_assertion_type", assertion_type),
+            ("client_assertion", assertion),
+        ])
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn assert_invalid_client(resp: reqwest::Response) {
+    assert_eq!(resp.status(), 401);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_client");
+}
+
+#[actix_web::test]
+async fn test_workload_token_authenticates_mapped_client() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let server = spawn_with_bundle(&clock).await;
+
+    let jwt = assertion(&clock, json!({}));
+    let resp = client_credentials(&server, CLIENT_ID, JWT_BEARER, &jwt).await;
+    assert_eq!(resp.status(), 200);
+    let token: Value = resp.json().await.unwrap();
+    assert!(token.get("refresh_token").is_none());
+
+    let introspection = server
+        .introspect(token["access_token"].as_str().unwrap())
+        .await;
+    assert_eq!(introspection["active"], true);
+    assert_eq!(introspection["client_id"], CLIENT_ID);
+
+    server.stop().await;
+}
+
+#[actix_web::test]
+async fn test_workload_token_is_rejected() {
+    let clock = Arc::new(ManualClock::starting_now());
+    let server = spawn_with_bundle(&clock).await;
+    let other_client = server.register_client().await;
+
+    // Valid token, but presented for a client it is not mapped to
+    let jwt = assertion(&clock, json!({}));
+    assert_invalid_client(client_credentials(&server, &other_client, JWT_BEARER, &jwt).await).await;
+
+    for claims in [
+        json!({ "aud": "https://someone-else.example.test" }),
+        json!({ "iss": "https://untrusted.example.test" }),
+        json!({ "sub": "system:serviceaccount:default:default" }),
+    ] {
+        let jwt = assertion(&clock, claims);
+        assert_invalid_client(client_credentials(&server, CLIENT_ID, JWT_BEARER, &jwt).await).await;
+    }
+
+    // Tampered signature
+    let mut jwt = assertion(&clock, json!({}));
+    jwt.push('A');
+    assert_invalid_client(client_credentials(&server, CLIENT_ID, JWT_BEARER, &jwt).await).await;
+
+    let jwt = assertion(&clock, json!({}));
+    assert_invalid_client(
+        client_credentials(&server, CLIENT_ID, "urn:example:unknown", &jwt).await,
+    )
+    .await;
+
+    // Expired, allowing for clock skew
+    clock.advance(Duration::minutes(12));
+    assert_invalid_client(client_credentials(&server, CLIENT_ID, JWT_BEARER, &jwt).await).await;
+
+    server.stop().await;
+}